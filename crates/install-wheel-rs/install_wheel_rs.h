@@ -0,0 +1,36 @@
+/* The C api of install-wheel-rs, built with `cargo rustc --release --features capi --crate-type cdylib` */
+
+#ifndef INSTALL_WHEEL_RS_H
+#define INSTALL_WHEEL_RS_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Installs a wheel file into the venv at `venv`. `python` is the python of the venv, used for
+ * compiling to bytecode, and `major`.`minor` its version. Returns 0 on success and -1 on failure */
+int install_wheel_rs_install(const char *wheel, const char *venv, const char *python, uint8_t major,
+                             uint8_t minor);
+
+/* The compatible wheel tags for python `major`.`minor` on this platform, most specific first, as
+ * `python-abi-platform` lines. NULL on failure, otherwise free with install_wheel_rs_free_string */
+char *install_wheel_rs_compatible_tags(uint8_t major, uint8_t minor);
+
+/* The message of the last failure on the calling thread, NULL if there was none. Every other call
+ * clears it, so it's valid until the next call on the same thread, don't free it */
+const char *install_wheel_rs_last_error(void);
+
+/* The stable code of the last failure, e.g. "incompatible-wheel" or "panic" for an internal error,
+ * same lifetime as the message */
+const char *install_wheel_rs_last_error_code(void);
+
+/* Releases a string returned by this library, NULL is ignored */
+void install_wheel_rs_free_string(char *value);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* INSTALL_WHEEL_RS_H */
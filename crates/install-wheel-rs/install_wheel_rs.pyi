@@ -1,3 +1,10 @@
+from typing import List, Optional, Tuple
+
 class LockedVenv:
     def __init__(self, venv: str): ...
     def install_wheel(self, wheel: str): ...
+    def install_many(self, wheels: List[str]) -> List[str]: ...
+    def uninstall(self, name: str) -> Optional[str]: ...
+    def compatible_tags(self) -> List[Tuple[str, str, str]]: ...
+
+def read_metadata(wheel: str) -> str: ...
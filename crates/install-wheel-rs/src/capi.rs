@@ -0,0 +1,251 @@
+//! A small C API for embedding the installer in tools that are neither rust nor python, see
+//! `install_wheel_rs.h`. Build it as shared or static library with
+//! `cargo rustc --release --features capi --crate-type cdylib` (or `staticlib`).
+//!
+//! Functions return 0 on success and -1 on failure, after which
+//! [install_wheel_rs_last_error] has the message. Each call clears the previous error. Strings
+//! returned by us must be released with [install_wheel_rs_free_string].
+
+use crate::{install_wheel_in_venv, CompatibleTags, Error};
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+thread_local! {
+    /// The message and code of the last failure on this thread
+    static LAST_ERROR: RefCell<Option<(CString, CString)>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: &str, code: &str) {
+    // Messages and codes don't contain nul bytes, but paths in messages could
+    let message = CString::new(message.replace('\0', "\\0")).unwrap_or_default();
+    let code = CString::new(code).unwrap_or_default();
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some((message, code)));
+}
+
+fn set_error(err: &Error) {
+    let mut message = err.to_string();
+    let mut current: &dyn std::error::Error = err;
+    while let Some(source) = current.source() {
+        message.push_str(&format!("\n  Caused by: {}", source));
+        current = source;
+    }
+    set_last_error(&message, err.code());
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Runs the body of an exported function: Clears the last error and turns a panic, which must not
+/// unwind into the caller, into the `failure` return value with a `panic` error
+fn ffi_call<R>(failure: R, body: impl FnOnce() -> R) -> R {
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = None);
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(result) => result,
+        Err(payload) => {
+            set_last_error(
+                &format!("Internal error: {}", panic_message(payload.as_ref())),
+                "panic",
+            );
+            failure
+        }
+    }
+}
+
+/// Reads a utf-8 string argument, recording an error for null and invalid utf-8
+///
+/// # Safety
+///
+/// `value` must be null or a valid nul-terminated string
+unsafe fn read_str<'a>(value: *const c_char, name: &str) -> Option<&'a str> {
+    if value.is_null() {
+        set_last_error(&format!("{} must not be null", name), "invalid-argument");
+        return None;
+    }
+    match CStr::from_ptr(value).to_str() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            set_last_error(&format!("{} must be utf-8", name), "invalid-argument");
+            None
+        }
+    }
+}
+
+/// Only python 3 is supported
+fn check_major(major: u8) -> bool {
+    if major != 3 {
+        set_last_error(
+            &format!("Unsupported python major version {}", major),
+            "invalid-argument",
+        );
+    }
+    major == 3
+}
+
+/// Installs a wheel file into the venv at `venv`. `python` is the python of the venv, used for
+/// compiling to bytecode, and `major`.`minor` its version
+///
+/// # Safety
+///
+/// All strings must be valid nul-terminated utf-8 strings
+#[no_mangle]
+pub unsafe extern "C" fn install_wheel_rs_install(
+    wheel: *const c_char,
+    venv: *const c_char,
+    python: *const c_char,
+    major: u8,
+    minor: u8,
+) -> c_int {
+    ffi_call(-1, || {
+        let (Some(wheel), Some(venv), Some(python)) = (
+            read_str(wheel, "wheel"),
+            read_str(venv, "venv"),
+            read_str(python, "python"),
+        ) else {
+            return -1;
+        };
+        if !check_major(major) {
+            return -1;
+        }
+        match install_wheel_in_venv(wheel, venv, python, (major, minor)) {
+            Ok(_tag) => 0,
+            Err(err) => {
+                set_error(&err);
+                -1
+            }
+        }
+    })
+}
+
+/// The tags of the wheels that can be installed for python `major`.`minor` on this platform,
+/// most specific first, as `python-abi-platform` lines. Null on failure, otherwise the string
+/// must be released with [install_wheel_rs_free_string]
+#[no_mangle]
+pub extern "C" fn install_wheel_rs_compatible_tags(major: u8, minor: u8) -> *mut c_char {
+    ffi_call(ptr::null_mut(), || {
+        if !check_major(major) {
+            return ptr::null_mut();
+        }
+        match CompatibleTags::current((major, minor)) {
+            Ok(tags) => {
+                let lines: Vec<String> = tags
+                    .iter()
+                    .map(|(python, abi, platform)| format!("{}-{}-{}", python, abi, platform))
+                    .collect();
+                // Tags never contain nul bytes
+                CString::new(lines.join("\n")).unwrap().into_raw()
+            }
+            Err(err) => {
+                set_error(&err);
+                ptr::null_mut()
+            }
+        }
+    })
+}
+
+/// The message of the last failure on the calling thread, null if there was none. Valid until
+/// the next call of this library on the same thread, don't free it. This and
+/// [install_wheel_rs_last_error_code] don't clear the error
+#[no_mangle]
+pub extern "C" fn install_wheel_rs_last_error() -> *const c_char {
+    panic::catch_unwind(|| {
+        LAST_ERROR.with(|last_error| match &*last_error.borrow() {
+            Some((message, _code)) => message.as_ptr(),
+            None => ptr::null(),
+        })
+    })
+    .unwrap_or(ptr::null())
+}
+
+/// The stable code of the last failure on the calling thread, e.g. `incompatible-wheel`, see
+/// [Error::code]. Same lifetime as [install_wheel_rs_last_error]
+#[no_mangle]
+pub extern "C" fn install_wheel_rs_last_error_code() -> *const c_char {
+    panic::catch_unwind(|| {
+        LAST_ERROR.with(|last_error| match &*last_error.borrow() {
+            Some((_message, code)) => code.as_ptr(),
+            None => ptr::null(),
+        })
+    })
+    .unwrap_or(ptr::null())
+}
+
+/// Releases a string returned by this library. Null is ignored
+///
+/// # Safety
+///
+/// `value` must be null or a string returned by this library that wasn't freed yet
+#[no_mangle]
+pub unsafe extern "C" fn install_wheel_rs_free_string(value: *mut c_char) {
+    ffi_call((), || {
+        if !value.is_null() {
+            drop(CString::from_raw(value));
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::capi::{
+        ffi_call, install_wheel_rs_compatible_tags, install_wheel_rs_free_string,
+        install_wheel_rs_install, install_wheel_rs_last_error, install_wheel_rs_last_error_code,
+    };
+    use std::ffi::{CStr, CString};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_capi() {
+        let tags = install_wheel_rs_compatible_tags(3, 12);
+        assert!(!tags.is_null());
+        let tags_str = unsafe { CStr::from_ptr(tags) }.to_str().unwrap();
+        assert!(tags_str.lines().any(|tag| tag == "py3-none-any"));
+        unsafe { install_wheel_rs_free_string(tags) };
+
+        let wheel = CString::new("not-a-wheel.txt").unwrap();
+        let temp_dir = TempDir::new().unwrap();
+        let venv = CString::new(temp_dir.path().to_str().unwrap()).unwrap();
+        let python = CString::new("python3").unwrap();
+        let status = unsafe {
+            install_wheel_rs_install(wheel.as_ptr(), venv.as_ptr(), python.as_ptr(), 3, 12)
+        };
+        assert_eq!(status, -1);
+        let code = unsafe { CStr::from_ptr(install_wheel_rs_last_error_code()) };
+        assert_eq!(code.to_str().unwrap(), "invalid-wheel-filename");
+        assert!(!install_wheel_rs_last_error().is_null());
+
+        let status = unsafe {
+            install_wheel_rs_install(std::ptr::null(), venv.as_ptr(), python.as_ptr(), 3, 12)
+        };
+        assert_eq!(status, -1);
+        let code = unsafe { CStr::from_ptr(install_wheel_rs_last_error_code()) };
+        assert_eq!(code.to_str().unwrap(), "invalid-argument");
+
+        assert!(install_wheel_rs_compatible_tags(2, 7).is_null());
+        // A successful call clears the error of the previous one
+        let tags = install_wheel_rs_compatible_tags(3, 12);
+        assert!(install_wheel_rs_last_error().is_null());
+        unsafe { install_wheel_rs_free_string(tags) };
+    }
+
+    #[test]
+    fn test_capi_panic() {
+        let status = ffi_call(-1, || panic!("broken invariant"));
+        assert_eq!(status, -1);
+        let code = unsafe { CStr::from_ptr(install_wheel_rs_last_error_code()) };
+        assert_eq!(code.to_str().unwrap(), "panic");
+        let message = unsafe { CStr::from_ptr(install_wheel_rs_last_error()) };
+        assert_eq!(
+            message.to_str().unwrap(),
+            "Internal error: broken invariant"
+        );
+    }
+}
@@ -0,0 +1,77 @@
+//! `direct_url.json` for packages that weren't installed from an index, e.g. a git checkout
+//!
+//! <https://packaging.python.org/en/latest/specifications/direct-url/>
+//! <https://peps.python.org/pep-0610/>
+
+use serde::Serialize;
+
+/// The contents of `direct_url.json`, one variant per kind of url
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum DirectUrl {
+    /// A local directory, `url` is a `file://` url
+    LocalDirectory { url: String, dir_info: DirInfo },
+    /// A wheel or source distribution at `url`
+    ArchiveUrl {
+        url: String,
+        archive_info: ArchiveInfo,
+    },
+    /// A checkout of a repository, `url` is the repository url without the `git+` prefix
+    VcsUrl { url: String, vcs_info: VcsInfo },
+}
+
+impl DirectUrl {
+    /// A git checkout at the exact commit `commit_id`, `requested_revision` is e.g. the branch or
+    /// tag the user asked for
+    pub fn git(url: &str, commit_id: &str, requested_revision: Option<&str>) -> Self {
+        Self::VcsUrl {
+            url: url.strip_prefix("git+").unwrap_or(url).to_string(),
+            vcs_info: VcsInfo {
+                vcs: "git".to_string(),
+                commit_id: commit_id.to_string(),
+                requested_revision: requested_revision.map(ToString::to_string),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct DirInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub editable: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct ArchiveInfo {
+    /// `<algorithm>=<hex>`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct VcsInfo {
+    /// Always `git` for now
+    pub vcs: String,
+    /// The full commit hash
+    pub commit_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested_revision: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use crate::DirectUrl;
+
+    #[test]
+    fn test_git() {
+        let direct_url = DirectUrl::git(
+            "git+https://github.com/pallets/flask",
+            "b0bab0b0bab0b0bab0b0bab0b0bab0b0bab0b0ba",
+            Some("main"),
+        );
+        assert_eq!(
+            serde_json::to_string(&direct_url).unwrap(),
+            r#"{"url":"https://github.com/pallets/flask","vcs_info":{"vcs":"git","commit_id":"b0bab0b0bab0b0bab0b0bab0b0bab0b0bab0b0ba","requested_revision":"main"}}"#
+        );
+    }
+}
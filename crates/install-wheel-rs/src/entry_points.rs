@@ -0,0 +1,203 @@
+//! All entry points of a distribution, i.e. the console and gui scripts and the plugin groups such
+//! as `pytest11`, from the `entry_points.txt` of a wheel or of an installed `.dist-info`
+//!
+//! <https://packaging.python.org/en/latest/specifications/entry-points/>
+
+use crate::metadata::find_dist_info;
+use crate::{Error, WheelFilename};
+use configparser::ini::Ini;
+use fs_err as fs;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Serialize;
+use std::io;
+use std::io::{Read, Seek};
+use std::path::Path;
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// An entry like `pytest_cov = pytest_cov.plugin` or `main = foo.cli:App.run [color]`
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Serialize)]
+pub struct EntryPoint {
+    /// The section, e.g. `console_scripts` or `pytest11`
+    pub group: String,
+    pub name: String,
+    pub module: String,
+    /// The dotted path of the object in the module, `None` if the entry point is the module
+    pub attr: Option<String>,
+    /// Extras that need to be installed for the entry point to work
+    pub extras: Vec<String>,
+}
+
+/// The entry points of a distribution, sorted by group and name
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct EntryPoints(pub Vec<EntryPoint>);
+
+impl EntryPoints {
+    /// Parses the contents of an `entry_points.txt`
+    pub fn parse(entry_points_txt: &str) -> Result<Self, Error> {
+        static VALUE: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(
+                r"^(?P<module>[\w.]+)\s*(?::\s*(?P<attr>[\w.]+))?\s*(?:\[(?P<extras>[^\]]*)\])?$",
+            )
+            .unwrap()
+        });
+
+        let sections = Ini::new_cs()
+            .read(entry_points_txt.to_string())
+            .map_err(|err| Error::InvalidWheel(format!("entry_points.txt is invalid: {}", err)))?;
+        let mut entry_points = Vec::new();
+        for (group, entries) in sections {
+            for (name, value) in entries {
+                let value = value.ok_or_else(|| {
+                    Error::InvalidWheel(format!("[{}] key {} must have a value", group, name))
+                })?;
+                let captures = VALUE.captures(value.trim()).ok_or_else(|| {
+                    Error::InvalidWheel(format!("invalid entry point {} = {}", name, value))
+                })?;
+                let extras = captures
+                    .name("extras")
+                    .map(|extras| {
+                        extras
+                            .as_str()
+                            .split(',')
+                            .map(|extra| extra.trim().to_string())
+                            .filter(|extra| !extra.is_empty())
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                entry_points.push(EntryPoint {
+                    group: group.clone(),
+                    name,
+                    module: captures["module"].to_string(),
+                    attr: captures.name("attr").map(|attr| attr.as_str().to_string()),
+                    extras,
+                });
+            }
+        }
+        // The ini parser doesn't keep the order
+        entry_points.sort();
+        Ok(Self(entry_points))
+    }
+
+    /// Reads `entry_points.txt` from a wheel, empty if there is none
+    pub fn from_wheel(filename: &WheelFilename, reader: impl Read + Seek) -> Result<Self, Error> {
+        let mut archive = ZipArchive::new(reader)
+            .map_err(|err| Error::from_zip_error("(index)".to_string(), err))?;
+        let dist_info_prefix = find_dist_info(filename, &mut archive)?;
+        let entry_points_path = format!("{dist_info_prefix}.dist-info/entry_points.txt");
+        let mut entry_points_txt = String::new();
+        match archive.by_name(&entry_points_path) {
+            Ok(mut file) => file.read_to_string(&mut entry_points_txt)?,
+            Err(ZipError::FileNotFound) => return Ok(Self::default()),
+            Err(err) => return Err(Error::from_zip_error(entry_points_path, err)),
+        };
+        Self::parse(&entry_points_txt)
+    }
+
+    /// Reads `entry_points.txt` from an installed `.dist-info` directory, empty if there is none
+    pub fn from_dist_info(dist_info: &Path) -> Result<Self, Error> {
+        match fs::read_to_string(dist_info.join("entry_points.txt")) {
+            Ok(entry_points_txt) => Self::parse(&entry_points_txt),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The entry points of one group, e.g. all `pytest11` plugins
+    pub fn group<'a>(&'a self, group: &'a str) -> impl Iterator<Item = &'a EntryPoint> {
+        self.0
+            .iter()
+            .filter(move |entry_point| entry_point.group == group)
+    }
+
+    /// The names of all groups
+    pub fn groups(&self) -> Vec<&str> {
+        let mut groups: Vec<&str> = self
+            .0
+            .iter()
+            .map(|entry_point| entry_point.group.as_str())
+            .collect();
+        groups.dedup();
+        groups
+    }
+
+    /// A single entry point
+    pub fn get(&self, group: &str, name: &str) -> Option<&EntryPoint> {
+        self.0
+            .iter()
+            .find(|entry_point| entry_point.group == group && entry_point.name == name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{EntryPoint, EntryPoints, WheelFilename};
+    use fs_err as fs;
+    use fs_err::File;
+    use indoc::indoc;
+    use std::path::Path;
+    use std::str::FromStr;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse() {
+        let entry_points = EntryPoints::parse(indoc! {"
+            [console_scripts]
+            foo = foo.cli:main
+            foo-color = foo.cli:App.run [color, fancy]
+
+            [pytest11]
+            foo_plugin = foo.pytest_plugin
+        "})
+        .unwrap();
+        assert_eq!(entry_points.groups(), ["console_scripts", "pytest11"]);
+        assert_eq!(
+            entry_points.get("console_scripts", "foo-color").unwrap(),
+            &EntryPoint {
+                group: "console_scripts".to_string(),
+                name: "foo-color".to_string(),
+                module: "foo.cli".to_string(),
+                attr: Some("App.run".to_string()),
+                extras: vec!["color".to_string(), "fancy".to_string()],
+            }
+        );
+        let plugins: Vec<_> = entry_points.group("pytest11").collect();
+        assert_eq!(plugins.len(), 1);
+        assert_eq!(plugins[0].module, "foo.pytest_plugin");
+        assert_eq!(plugins[0].attr, None);
+
+        assert!(EntryPoints::parse("[console_scripts]\nfoo = foo:main:other\n").is_err());
+    }
+
+    #[test]
+    fn test_from_wheel_and_dist_info() {
+        let filename = "setuptools-0.9.8-py2.py3-none-any.whl";
+        let wheel =
+            File::open(Path::new("../../test-data/pip-test-packages").join(filename)).unwrap();
+        let entry_points =
+            EntryPoints::from_wheel(&WheelFilename::from_str(filename).unwrap(), wheel).unwrap();
+        assert!(entry_points.groups().contains(&"egg_info.writers"));
+        let writer = entry_points.get("egg_info.writers", "PKG-INFO").unwrap();
+        assert_eq!(
+            (writer.module.as_str(), writer.attr.as_deref()),
+            ("setuptools.command.egg_info", Some("write_pkg_info"))
+        );
+
+        let temp_dir = TempDir::new().unwrap();
+        assert_eq!(
+            EntryPoints::from_dist_info(temp_dir.path()).unwrap(),
+            EntryPoints::default()
+        );
+        fs::write(
+            temp_dir.path().join("entry_points.txt"),
+            "[console_scripts]\nfoo = foo:main\n",
+        )
+        .unwrap();
+        let entry_points = EntryPoints::from_dist_info(temp_dir.path()).unwrap();
+        assert_eq!(
+            entry_points.get("console_scripts", "foo").unwrap().module,
+            "foo"
+        );
+    }
+}
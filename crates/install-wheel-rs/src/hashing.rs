@@ -0,0 +1,200 @@
+//! The hash functions we use, split by what the hash is for: Cache keys and change detection only
+//! need to be fast and stable, so they use xxh3, while verifying and recording distributions
+//! needs a cryptographic hash, which is sha256 since that's what the packaging standards use.
+//!
+//! Both are behind [HashBackend], so e.g. FIPS-constrained builds can use a validated sha256
+//! implementation or sha256 for the cache keys too with [set_hash_backend]. Everything that
+//! hashes, including RECORD files and checksums of downloads, goes through here.
+
+use data_encoding::BASE64URL_NOPAD;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read, Write};
+use std::sync::RwLock;
+use xxhash_rust::xxh3::Xxh3;
+
+/// What the hash is used for
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HashPurpose {
+    /// Cache directory names and change detection, xxh3 by default
+    CacheKey,
+    /// Hashes of distributions and installed files that are checked against the index, a lock
+    /// or a RECORD, sha256 by default. Swapped implementations must still compute sha256
+    Integrity,
+}
+
+/// A hash function
+pub trait HashBackend: Send + Sync {
+    /// The name in hash strings such as `sha256:<hex>`
+    fn name(&self) -> &'static str;
+
+    /// Starts a new incremental hash
+    fn hasher(&self) -> Box<dyn IncrementalHash>;
+}
+
+/// The state of one hash computation, feed it with [Write] or [IncrementalHash::update]
+pub trait IncrementalHash: Write {
+    /// Adds `data` to the hash
+    fn update(&mut self, data: &[u8]);
+
+    /// The raw digest
+    fn finalize(self: Box<Self>) -> Vec<u8>;
+}
+
+/// sha256 from the sha2 crate
+pub struct Sha256Backend;
+
+impl HashBackend for Sha256Backend {
+    fn name(&self) -> &'static str {
+        "sha256"
+    }
+
+    fn hasher(&self) -> Box<dyn IncrementalHash> {
+        Box::new(Sha256Hash(Sha256::new()))
+    }
+}
+
+struct Sha256Hash(Sha256);
+
+impl Write for Sha256Hash {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl IncrementalHash for Sha256Hash {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.finalize().to_vec()
+    }
+}
+
+/// The 128 bit variant of xxh3, which is not cryptographic but much faster than sha256
+pub struct Xxh3Backend;
+
+impl HashBackend for Xxh3Backend {
+    fn name(&self) -> &'static str {
+        "xxh3-128"
+    }
+
+    fn hasher(&self) -> Box<dyn IncrementalHash> {
+        Box::new(Xxh3Hash(Xxh3::new()))
+    }
+}
+
+struct Xxh3Hash(Xxh3);
+
+impl Write for Xxh3Hash {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl IncrementalHash for Xxh3Hash {
+    fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        self.0.digest128().to_be_bytes().to_vec()
+    }
+}
+
+/// `(cache key, integrity)`, changed with [set_hash_backend]
+static BACKENDS: RwLock<(&dyn HashBackend, &dyn HashBackend)> =
+    RwLock::new((&Xxh3Backend, &Sha256Backend));
+
+/// Replaces the hash function for `purpose`. Call this before anything is hashed, otherwise the
+/// cache keys of one run aren't consistent
+pub fn set_hash_backend(purpose: HashPurpose, backend: &'static dyn HashBackend) {
+    let mut backends = BACKENDS.write().unwrap();
+    match purpose {
+        HashPurpose::CacheKey => backends.0 = backend,
+        HashPurpose::Integrity => backends.1 = backend,
+    }
+}
+
+/// The current hash function for `purpose`
+pub fn hash_backend(purpose: HashPurpose) -> &'static dyn HashBackend {
+    let backends = BACKENDS.read().unwrap();
+    match purpose {
+        HashPurpose::CacheKey => backends.0,
+        HashPurpose::Integrity => backends.1,
+    }
+}
+
+/// Lowercase hex, as in `sha256:<hex>` or cache directory names
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The hex cache key for `data`
+pub fn cache_key(data: impl AsRef<[u8]>) -> String {
+    let mut hasher = hash_backend(HashPurpose::CacheKey).hasher();
+    hasher.update(data.as_ref());
+    to_hex(&hasher.finalize())
+}
+
+/// Returns the name of the hash function and the raw digest of everything read from `reader`
+pub fn integrity_hash(reader: &mut impl Read) -> io::Result<(&'static str, Vec<u8>)> {
+    let backend = hash_backend(HashPurpose::Integrity);
+    let mut hasher = backend.hasher();
+    io::copy(reader, &mut hasher)?;
+    Ok((backend.name(), hasher.finalize()))
+}
+
+/// The raw integrity digest of `data`
+pub fn integrity_digest(data: &[u8]) -> Vec<u8> {
+    let mut hasher = hash_backend(HashPurpose::Integrity).hasher();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+/// The hash of `data` as a RECORD entry wants it, e.g. `sha256=<urlsafe base64 without padding>`
+pub fn record_hash(data: &[u8]) -> String {
+    format!(
+        "{}={}",
+        hash_backend(HashPurpose::Integrity).name(),
+        BASE64URL_NOPAD.encode(&integrity_digest(data))
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hashing::{
+        cache_key, integrity_hash, record_hash, to_hex, HashBackend, Sha256Backend,
+    };
+
+    #[test]
+    fn test_backends() {
+        // Stable across runs and platforms, since it names directories in shared caches
+        assert_eq!(cache_key("abc"), "06b05ab6733a618578af5f94892f3950");
+        let (name, digest) = integrity_hash(&mut "abc".as_bytes()).unwrap();
+        assert_eq!(name, "sha256");
+        assert_eq!(
+            to_hex(&digest),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // Streaming gives the same result
+        let mut hasher = Sha256Backend.hasher();
+        hasher.update(b"a");
+        hasher.update(b"bc");
+        assert_eq!(to_hex(&hasher.finalize()), to_hex(&digest));
+        assert_eq!(
+            record_hash(b"abc"),
+            "sha256=ungWv48Bz-pBQUDeXa4iI7ADYaOWF3qctBD_YfIAFa0"
+        );
+    }
+}
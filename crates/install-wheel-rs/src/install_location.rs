@@ -1,5 +1,6 @@
 //! Multiplexing between venv install and monotrail install
 
+use crate::{CompatibleTags, Error};
 use fs2::FileExt;
 use fs_err as fs;
 use fs_err::File;
@@ -10,14 +11,24 @@ use tracing::{error, warn};
 
 const INSTALL_LOCKFILE: &str = "install-wheel-rs.lock";
 
-/// I'm not sure that's the right way to normalize here, but it's a single place to change
-/// everything.
-///
-/// For displaying to the user, `-` is better, and it's also what poetry lockfile 2.0 does
-///
-/// Keep in sync with `find_distributions`
-pub fn normalize_name(dep_name: &str) -> String {
-    dep_name.to_lowercase().replace(['.', '_'], "-")
+/// Whether the venv was created by a free-threaded python (PEP 703) such as `python3.13t`. Those
+/// venvs have their packages in `lib/python3.13t/site-packages`, on windows there's a
+/// `python3.13t.exe` next to `python.exe`
+pub fn is_free_threaded_venv(venv_base: &Path, python_version: (u8, u8)) -> bool {
+    if cfg!(windows) {
+        venv_base
+            .join("Scripts")
+            .join(format!(
+                "python{}.{}t.exe",
+                python_version.0, python_version.1
+            ))
+            .is_file()
+    } else {
+        venv_base
+            .join("lib")
+            .join(format!("python{}.{}t", python_version.0, python_version.1))
+            .is_dir()
+    }
 }
 
 /// A directory for which we acquired a install-wheel-rs.lock lockfile
@@ -43,6 +54,7 @@ impl LockedDir {
     }
 
     /// Locks the directory, if necessary blocking until the lock becomes free
+    #[cfg_attr(feature = "instrument", tracing::instrument(level = "debug"))]
     pub fn acquire(path: &Path) -> io::Result<Self> {
         let lockfile = File::create(path.join(INSTALL_LOCKFILE))?;
         lockfile.file().lock_exclusive()?;
@@ -73,6 +85,60 @@ impl Deref for LockedDir {
     }
 }
 
+/// Where the parts of a wheel go, relative to the venv base (or the package directory for
+/// monotrail), named after the `.data` subdirectories. `include` is the directory that gets a
+/// subdirectory per distribution for the headers.
+///
+/// The defaults are the venv layout pip uses, override them for e.g. debian's
+/// `lib/python3/dist-packages` or a conda prefix. The paths must be relative since we stage the
+/// installation inside the venv base before moving it into place
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemePaths {
+    pub purelib: PathBuf,
+    pub platlib: PathBuf,
+    pub scripts: PathBuf,
+    pub data: PathBuf,
+    pub include: PathBuf,
+}
+
+impl SchemePaths {
+    /// `lib/python3.8/site-packages` or `Lib/site-packages`, `bin` or `Scripts`, `include/site/python3.8`
+    ///
+    /// `python_dir_name` is the directory in `lib`, e.g. `python3.13t` for free-threaded python,
+    /// pip doesn't use the abiflags for the include dir
+    pub fn venv(python_dir_name: &str, python_version: (u8, u8)) -> Self {
+        let site_packages = if cfg!(windows) {
+            Path::new("Lib").join("site-packages")
+        } else {
+            Path::new("lib").join(python_dir_name).join("site-packages")
+        };
+        Self {
+            purelib: site_packages.clone(),
+            platlib: site_packages,
+            scripts: PathBuf::from(if cfg!(windows) { "Scripts" } else { "bin" }),
+            data: PathBuf::new(),
+            include: Path::new("include")
+                .join("site")
+                .join(format!("python{}.{}", python_version.0, python_version.1)),
+        }
+    }
+
+    /// All absolute paths, which we can't stage
+    pub(crate) fn absolute_paths(&self) -> Vec<&Path> {
+        [
+            &self.purelib,
+            &self.platlib,
+            &self.scripts,
+            &self.data,
+            &self.include,
+        ]
+        .into_iter()
+        .map(PathBuf::as_path)
+        .filter(|path| path.is_absolute())
+        .collect()
+    }
+}
+
 /// Multiplexing between venv install and monotrail install
 ///
 /// For monotrail, we have a structure that is {monotrail}/{normalized(name)}/{version}/tag
@@ -86,11 +152,17 @@ impl Deref for LockedDir {
 /// atomicity (we need to add lots of different file without a top level directory / key-turn
 /// file we could rename) and the locking would also need to happen in the import mechanism
 /// itself to ensure
+///
+/// Construct a venv location with [InstallLocation::venv], it may get more settings like
+/// [InstallLocation::with_scheme]
 pub enum InstallLocation<T: Deref<Target = Path>> {
+    #[non_exhaustive]
     Venv {
         /// absolute path
         venv_base: T,
         python_version: (u8, u8),
+        /// Overrides the venv layout
+        scheme: Option<SchemePaths>,
     },
     Monotrail {
         monotrail_root: T,
@@ -100,15 +172,33 @@ pub enum InstallLocation<T: Deref<Target = Path>> {
 }
 
 impl<T: Deref<Target = Path>> InstallLocation<T> {
+    /// A venv with the default layout
+    pub fn venv(venv_base: T, python_version: (u8, u8)) -> Self {
+        InstallLocation::Venv {
+            venv_base,
+            python_version,
+            scheme: None,
+        }
+    }
+
+    /// Overrides the layout of a venv, ignored for monotrail
+    pub fn with_scheme(mut self, scheme_paths: SchemePaths) -> Self {
+        if let InstallLocation::Venv { scheme, .. } = &mut self {
+            *scheme = Some(scheme_paths);
+        }
+        self
+    }
+
     /// Returns the location of the python interpreter
     pub fn get_python(&self) -> PathBuf {
         match self {
             InstallLocation::Venv { venv_base, .. } => {
+                let scripts = venv_base.join(self.scheme_paths().scripts);
                 if cfg!(windows) {
-                    venv_base.join("Scripts").join("python.exe")
+                    scripts.join("python.exe")
                 } else {
                     // canonicalize on python would resolve the symlink
-                    venv_base.join("bin").join("python")
+                    scripts.join("python")
                 }
             }
             // TODO: For monotrail use the monotrail launcher
@@ -123,25 +213,61 @@ impl<T: Deref<Target = Path>> InstallLocation<T> {
         }
     }
 
-    /// TODO: This function is unused?
-    pub fn is_installed(&self, normalized_name: &str, version: &str) -> bool {
+    /// Whether we install for a free-threaded python, which has its own abi. Monotrail always uses
+    /// the default build
+    pub fn is_free_threaded(&self) -> bool {
         match self {
             InstallLocation::Venv {
                 venv_base,
                 python_version,
-            } => {
-                let site_packages = if cfg!(target_os = "windows") {
-                    venv_base.join("Lib").join("site-packages")
-                } else {
-                    venv_base
-                        .join("lib")
-                        .join(format!("python{}.{}", python_version.0, python_version.1))
-                        .join("site-packages")
-                };
-                site_packages
-                    .join(format!("{}-{}.dist-info", normalized_name, version))
-                    .is_dir()
+                ..
+            } => is_free_threaded_venv(venv_base, *python_version),
+            InstallLocation::Monotrail { .. } => false,
+        }
+    }
+
+    /// The directory name in `lib`, e.g. `python3.8` or `python3.13t`
+    pub fn python_dir_name(&self) -> String {
+        let (major, minor) = self.get_python_version();
+        let abiflags = if self.is_free_threaded() { "t" } else { "" };
+        format!("python{}.{}{}", major, minor, abiflags)
+    }
+
+    /// Where the parts of the wheel go. Monotrail uses `python` instead of `python3.8` since a
+    /// package directory is shared between python versions
+    pub fn scheme_paths(&self) -> SchemePaths {
+        match self {
+            InstallLocation::Venv {
+                scheme: Some(scheme),
+                ..
+            } => scheme.clone(),
+            InstallLocation::Venv { scheme: None, .. } => {
+                SchemePaths::venv(&self.python_dir_name(), self.get_python_version())
             }
+            InstallLocation::Monotrail { .. } => {
+                let mut scheme = SchemePaths::venv("python", self.get_python_version());
+                scheme.include = Path::new("include").join("site").join("python");
+                scheme
+            }
+        }
+    }
+
+    /// The wheel tags this location can install
+    pub fn compatible_tags(&self) -> Result<CompatibleTags, Error> {
+        if self.is_free_threaded() {
+            CompatibleTags::current_free_threaded(self.get_python_version())
+        } else {
+            CompatibleTags::current(self.get_python_version())
+        }
+    }
+
+    /// TODO: This function is unused?
+    pub fn is_installed(&self, normalized_name: &str, version: &str) -> bool {
+        match self {
+            InstallLocation::Venv { venv_base, .. } => venv_base
+                .join(self.scheme_paths().purelib)
+                .join(format!("{}-{}.dist-info", normalized_name, version))
+                .is_dir(),
             InstallLocation::Monotrail { monotrail_root, .. } => monotrail_root
                 .join(format!("{}-{}", normalized_name, version))
                 .is_dir(),
@@ -170,9 +296,14 @@ impl InstallLocation<PathBuf> {
         };
 
         Ok(match self {
-            Self::Venv { python_version, .. } => InstallLocation::Venv {
+            Self::Venv {
+                python_version,
+                scheme,
+                ..
+            } => InstallLocation::Venv {
                 venv_base: locked_dir,
                 python_version: *python_version,
+                scheme: scheme.clone(),
             },
             Self::Monotrail {
                 python_version,
@@ -0,0 +1,141 @@
+//! The stable entrypoint for installing wheels: An [Installer] is configured once with builder
+//! methods and then installs any number of wheels into its location, also from multiple threads.
+//!
+//! ```no_run
+//! use install_wheel_rs::{InstallLocation, Installer, LauncherPolicy};
+//! use std::path::PathBuf;
+//!
+//! let location = InstallLocation::venv(PathBuf::from(".venv"), (3, 12))
+//!     .acquire_lock()
+//!     .unwrap();
+//! let installer = Installer::new(&location, ".venv/bin/python")
+//!     .compile(true)
+//!     .launcher_policy(LauncherPolicy::EnvPython);
+//! installer
+//!     .install_file("Django-4.2.6-py3-none-any.whl")
+//!     .unwrap();
+//! ```
+
+use crate::install_location::{InstallLocation, LockedDir};
+use crate::progress::{NoProgress, ProgressReporter};
+use crate::wheel::{install_wheel_with, LauncherPolicy};
+use crate::{DirectUrl, Error, WheelFilename};
+use fs_err::File;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Installs wheels into a locked [InstallLocation]. The defaults are those of pip: Check the
+/// hashes, don't compile, skip wheels whose version is already installed
+pub struct Installer<'a> {
+    pub(crate) location: &'a InstallLocation<LockedDir>,
+    pub(crate) sys_executable: PathBuf,
+    pub(crate) compile: bool,
+    pub(crate) check_hashes: bool,
+    pub(crate) launcher_policy: LauncherPolicy,
+    pub(crate) unique_version: String,
+    pub(crate) direct_url: Option<DirectUrl>,
+    pub(crate) source_date_epoch: Option<u64>,
+    pub(crate) force: bool,
+    pub(crate) dry_run: bool,
+    pub(crate) reporter: &'a dyn ProgressReporter,
+}
+
+impl<'a> Installer<'a> {
+    /// `sys_executable` is used for compiling to bytecode
+    pub fn new(location: &'a InstallLocation<LockedDir>, sys_executable: impl AsRef<Path>) -> Self {
+        Self {
+            location,
+            sys_executable: sys_executable.as_ref().to_path_buf(),
+            compile: false,
+            check_hashes: true,
+            launcher_policy: LauncherPolicy::default(),
+            unique_version: String::new(),
+            direct_url: None,
+            source_date_epoch: None,
+            force: false,
+            dry_run: false,
+            reporter: &NoProgress,
+        }
+    }
+
+    /// Compile the python files to bytecode, errors are ignored like in pip
+    pub fn compile(mut self, compile: bool) -> Self {
+        self.compile = compile;
+        self
+    }
+
+    /// Check the files against the hashes in RECORD
+    pub fn check_hashes(mut self, check_hashes: bool) -> Self {
+        self.check_hashes = check_hashes;
+        self
+    }
+
+    /// The shebang of the entrypoint launchers and of `#!python` scripts
+    pub fn launcher_policy(mut self, launcher_policy: LauncherPolicy) -> Self {
+        self.launcher_policy = launcher_policy;
+        self
+    }
+
+    /// The version directory for monotrail installations, e.g. the commit of a git dependency.
+    /// Ignored for venvs
+    pub fn unique_version(mut self, unique_version: impl Into<String>) -> Self {
+        self.unique_version = unique_version.into();
+        self
+    }
+
+    /// Written to `direct_url.json`, only for packages that weren't installed from an index
+    pub fn direct_url(mut self, direct_url: Option<DirectUrl>) -> Self {
+        self.direct_url = direct_url;
+        self
+    }
+
+    /// The mtime of all installed files, also makes the pyc files hash based. Defaults to
+    /// `SOURCE_DATE_EPOCH`
+    pub fn source_date_epoch(mut self, source_date_epoch: Option<u64>) -> Self {
+        self.source_date_epoch = source_date_epoch;
+        self
+    }
+
+    /// Install even if the same version is already installed, replacing its files
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Only read and validate the wheel, without writing anything. The disk space is checked for
+    /// all wheels together with [crate::check_disk_space_for]
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Receives the progress events of all wheels installed with this installer
+    pub fn reporter(mut self, reporter: &'a dyn ProgressReporter) -> Self {
+        self.reporter = reporter;
+        self
+    }
+
+    /// Installs the wheel and returns its tag. The caller must ensure that the wheel is
+    /// compatible with the environment
+    pub fn install(
+        &self,
+        reader: impl Read + Seek,
+        filename: WheelFilename,
+    ) -> Result<String, Error> {
+        install_wheel_with(self, reader, filename)
+    }
+
+    /// Installs a wheel file after checking that its tags are compatible with the location.
+    /// Returns the tag
+    pub fn install_file(&self, wheel: impl AsRef<Path>) -> Result<String, Error> {
+        let filename = wheel
+            .as_ref()
+            .file_name()
+            .ok_or_else(|| Error::InvalidWheel("Expected a file".to_string()))?
+            .to_string_lossy();
+        let filename = WheelFilename::from_str(&filename)?;
+        filename.compatibility(&self.location.compatible_tags()?)?;
+        self.install(File::open(wheel.as_ref())?, filename)
+    }
+}
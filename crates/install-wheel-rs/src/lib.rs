@@ -1,7 +1,7 @@
 //! Takes a wheel and installs it, either in a venv or for monotrail
 //!
 //! ```no_run
-//! use std::path::Path;
+//! use std::path::{Path, PathBuf};
 //! use install_wheel_rs::install_wheel_in_venv;
 //!
 //! install_wheel_in_venv(
@@ -12,25 +12,70 @@
 //! ).unwrap();
 //! ```
 
+#[cfg(not(target_arch = "wasm32"))]
 use platform_info::PlatformInfoError;
-use std::fs::File;
+use serde::ser::SerializeStruct;
+use serde::Serialize;
 use std::io;
+#[cfg(not(target_arch = "wasm32"))]
 use std::path::Path;
-use std::str::FromStr;
+use std::path::PathBuf;
 use thiserror::Error;
 use zip::result::ZipError;
 
-pub use install_location::{normalize_name, InstallLocation, LockedDir};
+pub use direct_url::{ArchiveInfo, DirInfo, DirectUrl, VcsInfo};
+pub use entry_points::{EntryPoint, EntryPoints};
+pub use hashing::{
+    cache_key, hash_backend, integrity_digest, integrity_hash, record_hash, set_hash_backend,
+    to_hex, HashBackend, HashPurpose, IncrementalHash, Sha256Backend, Xxh3Backend,
+};
+#[cfg(not(target_arch = "wasm32"))]
+pub use install_location::{is_free_threaded_venv, InstallLocation, LockedDir, SchemePaths};
+#[cfg(not(target_arch = "wasm32"))]
+pub use installer::Installer;
+pub use metadata::{
+    normalize_name, parse_key_value_file, read_record_file, read_wheel_metadata, verify_wheel,
+    wheel_install_paths, write_record, RecordEntry, Script,
+};
+pub use progress::{LogProgress, NoProgress, ProgressReporter};
+#[cfg(not(target_arch = "wasm32"))]
+pub use repair::{library_search_path, repair_wheel, RepairReport};
+#[cfg(not(target_arch = "wasm32"))]
+pub use uninstall::{installed_dist_infos, remove_distribution, uninstall};
+#[cfg(not(target_arch = "wasm32"))]
+#[allow(deprecated)]
+pub use wheel::install_wheel;
+#[cfg(not(target_arch = "wasm32"))]
 pub use wheel::{
-    get_script_launcher, install_wheel, parse_key_value_file, read_record_file, relative_to,
-    Script, SHEBANG_PYTHON,
+    check_disk_space_for, get_script_launcher, relative_to, write_record_file, LauncherPolicy,
+    SHEBANG_PYTHON,
 };
-pub use wheel_tags::{Arch, CompatibleTags, Os, WheelFilename};
+#[cfg(not(target_arch = "wasm32"))]
+pub use wheel_builder::{build_wheel, wheel_file};
+pub use wheel_tags::{Arch, CompatibleTags, Os, PyodideAbi, WheelFilename, PYODIDE_ABIS};
 
+#[cfg(all(feature = "capi", not(target_arch = "wasm32")))]
+mod capi;
+mod direct_url;
+mod entry_points;
+mod hashing;
+// Installing needs a filesystem, file locks and subprocesses, on wasm only the parsing is available
+#[cfg(not(target_arch = "wasm32"))]
 mod install_location;
+#[cfg(not(target_arch = "wasm32"))]
+mod installer;
+mod metadata;
+mod progress;
 #[cfg(feature = "python_bindings")]
 mod python_bindings;
+#[cfg(not(target_arch = "wasm32"))]
+mod repair;
+#[cfg(not(target_arch = "wasm32"))]
+mod uninstall;
+#[cfg(not(target_arch = "wasm32"))]
 mod wheel;
+#[cfg(not(target_arch = "wasm32"))]
+mod wheel_builder;
 mod wheel_tags;
 
 #[derive(Error, Debug)]
@@ -62,17 +107,132 @@ pub enum Error {
     RecordFile(String),
     #[error("RECORD file is invalid")]
     RecordCsv(#[from] csv::Error),
+    /// A file in the wheel doesn't match its RECORD hash
+    #[error("Hash mismatch for {path}. Recorded: {recorded}, Actual: {actual}")]
+    HashMismatch {
+        path: String,
+        recorded: String,
+        actual: String,
+    },
     #[error("Broken virtualenv: {0}")]
     BrokenVenv(String),
     #[error("Failed to detect the operating system version: {0}")]
     OsVersionDetection(String),
+    #[cfg(not(target_arch = "wasm32"))]
     #[error("Failed to detect the current platform")]
     PlatformInfo(#[source] PlatformInfoError),
-    #[error("Invalid version specification, only none or == is supported")]
-    Pep440,
+    #[error("Invalid requirement: {0}")]
+    Pep440(String),
+    /// Moving the staged files into place failed, the already placed files have been removed
+    #[error("Failed to install the wheel, the installation has been rolled back")]
+    RolledBack(#[source] Box<Error>),
+    /// Checked before installing, or the disk or quota ran full during the installation, which
+    /// has been rolled back
+    #[error(
+        "Not enough disk space in {}: The installation needs {} but only {} are available",
+        path.display(),
+        format_size(*needed),
+        format_size(*available)
+    )]
+    InsufficientSpace {
+        path: PathBuf,
+        needed: u64,
+        available: u64,
+    },
+    /// Copying the shared libraries into a locally built wheel failed
+    #[error("Failed to repair the wheel: {0}")]
+    Repair(String),
+    /// Not in [wheel_tags::PYODIDE_ABIS]
+    #[error("Unknown pyodide abi {0}, known are {1}")]
+    UnknownPyodideAbi(String, String),
+    /// The [SchemePaths] can't be used for installing
+    #[error("Invalid install scheme: {0}")]
+    InvalidScheme(String),
+    /// A [crate::LauncherPolicy::Custom] shebang that isn't a single line
+    #[error("Invalid shebang {0:?}, it must be a single line")]
+    InvalidShebang(String),
+}
+
+/// Coarse grouping of the [Error] codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCategory {
+    /// The wheel, its filename or its metadata is broken
+    InvalidInput,
+    /// The wheel doesn't match the platform or the python
+    Incompatible,
+    /// The target environment is broken or can't be detected
+    Environment,
+    /// Reading, writing or running something failed, e.g. the disk is full
+    Io,
+    /// Shouldn't happen
+    Internal,
+}
+
+/// Human readable size for error messages
+fn format_size(bytes: u64) -> String {
+    format!("{:.1}MB", bytes as f64 / 1_000_000.0)
 }
 
 impl Error {
+    /// A stable identifier of the kind of error for tools that wrap us, so they don't have to
+    /// match on the message, e.g. `incompatible-wheel` or `hash-mismatch`
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::IO(_) => "io",
+            Error::DirectUrlSerdeJson(_) => "direct-url-json",
+            Error::IncompatibleWheel { .. } => "incompatible-wheel",
+            Error::InvalidWheel(_) => "invalid-wheel",
+            Error::InvalidPoetry(_) => "invalid-poetry",
+            Error::InvalidWheelFileName(..) => "invalid-wheel-filename",
+            Error::Zip(..) => "invalid-zip",
+            Error::PythonSubcommand(_) => "python-subcommand",
+            Error::WalkDir(_) => "walk-dir",
+            Error::RecordFile(_) | Error::RecordCsv(_) => "invalid-record",
+            Error::HashMismatch { .. } => "hash-mismatch",
+            Error::BrokenVenv(_) => "broken-venv",
+            Error::OsVersionDetection(_) => "os-version-detection",
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::PlatformInfo(_) => "platform-detection",
+            Error::Pep440(_) => "invalid-requirement",
+            Error::RolledBack(_) => "rolled-back",
+            Error::InsufficientSpace { .. } => "insufficient-space",
+            Error::Repair(_) => "repair-failed",
+            Error::UnknownPyodideAbi(..) => "unknown-pyodide-abi",
+            Error::InvalidScheme(_) => "invalid-scheme",
+            Error::InvalidShebang(_) => "invalid-shebang",
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Error::InvalidWheel(_)
+            | Error::InvalidPoetry(_)
+            | Error::InvalidWheelFileName(..)
+            | Error::Zip(..)
+            | Error::RecordFile(_)
+            | Error::RecordCsv(_)
+            | Error::HashMismatch { .. }
+            | Error::Pep440(_)
+            | Error::InvalidShebang(_) => ErrorCategory::InvalidInput,
+            Error::IncompatibleWheel { .. } | Error::UnknownPyodideAbi(..) => {
+                ErrorCategory::Incompatible
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::PlatformInfo(_) => ErrorCategory::Environment,
+            Error::BrokenVenv(_)
+            | Error::OsVersionDetection(_)
+            | Error::Repair(_)
+            | Error::InvalidScheme(_) => ErrorCategory::Environment,
+            Error::IO(_)
+            | Error::PythonSubcommand(_)
+            | Error::WalkDir(_)
+            | Error::RolledBack(_)
+            | Error::InsufficientSpace { .. } => ErrorCategory::Io,
+            Error::DirectUrlSerdeJson(_) => ErrorCategory::Internal,
+        }
+    }
+
     pub(crate) fn from_zip_error(file: String, value: ZipError) -> Self {
         match value {
             ZipError::Io(io_error) => Self::IO(io_error),
@@ -81,12 +241,32 @@ impl Error {
     }
 }
 
+/// `{"code": ..., "category": ..., "message": ..., "causes": [...]}`, with the messages of the
+/// source errors in `causes`
+impl Serialize for Error {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut causes = Vec::new();
+        let mut current: &dyn std::error::Error = self;
+        while let Some(source) = current.source() {
+            causes.push(source.to_string());
+            current = source;
+        }
+        let mut state = serializer.serialize_struct("Error", 4)?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("category", &self.category())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.serialize_field("causes", &causes)?;
+        state.end()
+    }
+}
+
 /// High level API: Install a wheel in a virtualenv
 ///
 /// The python interpreter is used for compiling to byte code, the python version for computing
 /// the site packages path on unix.
 ///
 /// Returns the tag of the wheel
+#[cfg(not(target_arch = "wasm32"))]
 pub fn install_wheel_in_venv(
     wheel: impl AsRef<Path>,
     venv: impl AsRef<Path>,
@@ -94,30 +274,38 @@ pub fn install_wheel_in_venv(
     major_minor: (u8, u8),
 ) -> Result<String, Error> {
     let venv_base = venv.as_ref().canonicalize()?;
-    let location = InstallLocation::Venv {
-        venv_base,
-        python_version: major_minor,
-    };
+    let location = InstallLocation::venv(venv_base, major_minor);
     let locked_dir = location.acquire_lock()?;
 
-    let filename = wheel
-        .as_ref()
-        .file_name()
-        .ok_or_else(|| Error::InvalidWheel("Expected a file".to_string()))?
-        .to_string_lossy();
-    let filename = WheelFilename::from_str(&filename)?;
-    let compatible_tags = CompatibleTags::current(location.get_python_version())?;
-    filename.compatibility(&compatible_tags)?;
+    Installer::new(&locked_dir, interpreter).install_file(wheel)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Arch, Error, ErrorCategory, Os};
 
-    install_wheel(
-        &locked_dir,
-        File::open(wheel)?,
-        filename,
-        false,
-        true,
-        &[],
-        // Only relevant for monotrail style installation
-        "",
-        interpreter,
-    )
+    #[test]
+    fn test_error_json() {
+        let err = Error::IncompatibleWheel {
+            os: Os::Windows,
+            arch: Arch::X86_64,
+        };
+        assert_eq!(err.category(), ErrorCategory::Incompatible);
+        let json = serde_json::to_value(&err).unwrap();
+        assert_eq!(json["code"], "incompatible-wheel");
+        assert_eq!(json["category"], "incompatible");
+
+        let err = Error::RolledBack(Box::new(Error::HashMismatch {
+            path: "tqdm/__init__.py".to_string(),
+            recorded: "abc".to_string(),
+            actual: "def".to_string(),
+        }));
+        let json = serde_json::to_value(&err).unwrap();
+        assert_eq!(json["code"], "rolled-back");
+        assert_eq!(json["category"], "io");
+        assert_eq!(
+            json["causes"],
+            serde_json::json!(["Hash mismatch for tqdm/__init__.py. Recorded: abc, Actual: def"])
+        );
+    }
 }
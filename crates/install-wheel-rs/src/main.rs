@@ -1,6 +1,6 @@
 use clap::Parser;
 use fs_err::File;
-use install_wheel_rs::{install_wheel, CompatibleTags, Error, InstallLocation, WheelFilename};
+use install_wheel_rs::{check_disk_space_for, Error, InstallLocation, Installer, WheelFilename};
 #[cfg(feature = "rayon")]
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use std::path::PathBuf;
@@ -25,15 +25,18 @@ struct Args {
     /// Don't check the hashes in RECORD
     #[clap(long)]
     skip_hashes: bool,
+    /// Reinstall wheels whose version is already installed
+    #[clap(long)]
+    force: bool,
+    /// Only validate the wheels and check the disk space
+    #[clap(long)]
+    dry_run: bool,
 }
 
 fn main() -> Result<(), Error> {
     let args = Args::parse();
     let venv_base = args.venv.canonicalize()?;
-    let location = InstallLocation::Venv {
-        venv_base,
-        python_version: (args.major, args.minor),
-    };
+    let location = InstallLocation::venv(venv_base, (args.major, args.minor));
     let locked_dir = location.acquire_lock()?;
 
     let wheels: Vec<(PathBuf, WheelFilename)> = args
@@ -45,11 +48,12 @@ fn main() -> Result<(), Error> {
                 .ok_or_else(|| Error::InvalidWheel("Expected a file".to_string()))?
                 .to_string_lossy();
             let filename = WheelFilename::from_str(&filename)?;
-            let compatible_tags = CompatibleTags::current(location.get_python_version())?;
+            let compatible_tags = location.compatible_tags()?;
             filename.compatibility(&compatible_tags)?;
             Ok((wheel, filename))
         })
         .collect::<Result<_, Error>>()?;
+    check_disk_space_for(&locked_dir, wheels.iter().map(|(wheel, _filename)| wheel))?;
 
     let wheels = {
         #[cfg(feature = "rayon")]
@@ -61,21 +65,16 @@ fn main() -> Result<(), Error> {
             wheels.into_iter()
         }
     };
+    let installer = Installer::new(&locked_dir, location.get_python())
+        .compile(args.compile)
+        .check_hashes(!args.skip_hashes)
+        .force(args.force)
+        .dry_run(args.dry_run);
     wheels
         .map(|(wheel, filename)| {
-            install_wheel(
-                &locked_dir,
-                File::open(wheel)?,
-                filename,
-                args.compile,
-                !args.skip_hashes,
-                &[],
-                // Only relevant for monotrail style installation
-                "",
-                location.get_python(),
-            )?;
+            installer.install(File::open(wheel)?, filename)?;
             Ok(())
         })
-        .collect::<Result<_, Error>>()?;
+        .collect::<Result<(), Error>>()?;
     Ok(())
 }
@@ -0,0 +1,568 @@
+//! Reads the metadata of a wheel, i.e. RECORD, WHEEL, METADATA and the entrypoints, without
+//! installing it. Nothing here touches the filesystem or runs a subprocess, so this part also
+//! compiles to wasm, e.g. for web-based dependency viewers
+
+use crate::hashing::{hash_backend, HashPurpose};
+use crate::{Error, WheelFilename};
+use configparser::ini::Ini;
+use data_encoding::BASE64URL_NOPAD;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::io::{BufRead, BufReader, Read, Seek, Write};
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+/// I'm not sure that's the right way to normalize here, but it's a single place to change
+/// everything.
+///
+/// For displaying to the user, `-` is better, and it's also what poetry lockfile 2.0 does
+///
+/// Keep in sync with `find_distributions`
+pub fn normalize_name(dep_name: &str) -> String {
+    dep_name.to_lowercase().replace(['.', '_'], "-")
+}
+
+/// Line in a RECORD file
+/// <https://www.python.org/dev/peps/pep-0376/#record>
+///
+/// ```csv
+/// tqdm/cli.py,sha256=x_c8nmc4Huc-lKEsAXj78ZiyqSJ9hJ71j7vltY67icw,10509
+/// tqdm-4.62.3.dist-info/RECORD,,
+/// ```
+#[derive(Deserialize, Serialize, PartialOrd, PartialEq, Ord, Eq)]
+pub struct RecordEntry {
+    pub path: String,
+    pub hash: Option<String>,
+    #[allow(dead_code)]
+    pub size: Option<usize>,
+}
+
+/// A script defining the name of the runnable entrypoint and the module and function that should be
+/// run.
+#[cfg(feature = "python_bindings")]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[pyo3::pyclass(dict)]
+pub struct Script {
+    #[pyo3(get)]
+    pub script_name: String,
+    #[pyo3(get)]
+    pub module: String,
+    #[pyo3(get)]
+    pub function: String,
+}
+
+/// A script defining the name of the runnable entrypoint and the module and function that should be
+/// run.
+#[cfg(not(feature = "python_bindings"))]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Script {
+    pub script_name: String,
+    pub module: String,
+    pub function: String,
+}
+
+impl Script {
+    /// Parses a script definition like `foo.bar:main` or `foomod:main_bar [bar,baz]`
+    ///
+    /// <https://packaging.python.org/en/latest/specifications/entry-points/>
+    ///
+    /// Extras are supposed to be ignored, which happens if you pass None for extras
+    pub fn from_value(
+        script_name: &str,
+        value: &str,
+        extras: Option<&[String]>,
+    ) -> Result<Option<Script>, Error> {
+        let script_regex = Regex::new(r"^(?P<module>[\w\d_\-.]+):(?P<function>[\w\d_\-.]+)(?:\s+\[(?P<extras>(?:[^,]+,?\s*)+)\])?$").unwrap();
+
+        let captures = script_regex
+            .captures(value)
+            .ok_or_else(|| Error::InvalidWheel(format!("invalid console script: '{}'", value)))?;
+        if let Some(script_extras) = captures.name("extras") {
+            let script_extras = script_extras
+                .as_str()
+                .split(',')
+                .map(|extra| extra.trim().to_string())
+                .collect::<HashSet<String>>();
+            if let Some(extras) = extras {
+                if !script_extras.is_subset(&extras.iter().cloned().collect()) {
+                    return Ok(None);
+                }
+            }
+        }
+
+        Ok(Some(Script {
+            script_name: script_name.to_string(),
+            module: captures.name("module").unwrap().as_str().to_string(),
+            function: captures.name("function").unwrap().as_str().to_string(),
+        }))
+    }
+}
+
+/// Part of entrypoints parsing
+fn read_scripts_from_section(
+    scripts_section: &HashMap<String, Option<String>>,
+    section_name: &str,
+    extras: Option<&[String]>,
+) -> Result<Vec<Script>, Error> {
+    let mut scripts = Vec::new();
+    for (script_name, python_location) in scripts_section.iter() {
+        match python_location {
+            Some(value) => {
+                if let Some(script) = Script::from_value(script_name, value, extras)? {
+                    scripts.push(script);
+                }
+            }
+            None => {
+                return Err(Error::InvalidWheel(format!(
+                    "[{}] key {} must have a value",
+                    section_name, script_name
+                )));
+            }
+        }
+    }
+    Ok(scripts)
+}
+
+/// Parses the entry_points.txt entry in the wheel for console scripts
+///
+/// Returns (script_name, module, function)
+///
+/// Extras are supposed to be ignored, which happens if you pass None for extras
+pub(crate) fn parse_scripts<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    dist_info_prefix: &str,
+    extras: Option<&[String]>,
+) -> Result<(Vec<Script>, Vec<Script>), Error> {
+    let entry_points_path = format!("{dist_info_prefix}.dist-info/entry_points.txt");
+    let entry_points_mapping = match archive.by_name(&entry_points_path) {
+        Ok(mut file) => {
+            let mut ini_text = String::new();
+            file.read_to_string(&mut ini_text)?;
+            Ini::new_cs().read(ini_text).map_err(|err| {
+                Error::InvalidWheel(format!("entry_points.txt is invalid: {}", err))
+            })?
+        }
+        Err(ZipError::FileNotFound) => return Ok((Vec::new(), Vec::new())),
+        Err(err) => return Err(Error::from_zip_error(entry_points_path, err)),
+    };
+
+    // TODO: handle extras
+    let console_scripts = match entry_points_mapping.get("console_scripts") {
+        Some(console_scripts) => {
+            read_scripts_from_section(console_scripts, "console_scripts", extras)?
+        }
+        None => Vec::new(),
+    };
+    let gui_scripts = match entry_points_mapping.get("gui_scripts") {
+        Some(gui_scripts) => read_scripts_from_section(gui_scripts, "gui_scripts", extras)?,
+        None => Vec::new(),
+    };
+
+    Ok((console_scripts, gui_scripts))
+}
+
+/// Shamelessly stolen (and updated for the hash backends)
+/// <https://github.com/richo/hashing-copy/blob/d8dd2fdb63c6faf198de0c9e5713d6249cbb5323/src/lib.rs#L10-L52>
+/// which in turn got it from std
+/// <https://doc.rust-lang.org/1.58.0/src/std/io/copy.rs.html#128-156>
+pub fn copy_and_hash(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<(u64, String)> {
+    let backend = hash_backend(HashPurpose::Integrity);
+    let mut hasher = backend.hasher();
+    // Same buf size as std. Note that this number is important for performance
+    let mut buf = vec![0; 8 * 1024];
+
+    let mut written = 0;
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hasher.update(&buf[..len]);
+        writer.write_all(&buf[..len])?;
+        written += len as u64;
+    }
+    Ok((
+        written,
+        format!(
+            "{}={}",
+            backend.name(),
+            BASE64URL_NOPAD.encode(&hasher.finalize())
+        ),
+    ))
+}
+
+/// Reads the record file
+/// <https://www.python.org/dev/peps/pep-0376/#record>
+pub fn read_record_file(record: &mut impl Read) -> Result<Vec<RecordEntry>, Error> {
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .escape(Some(b'"'))
+        .from_reader(record)
+        .deserialize()
+        .map(|entry| {
+            let entry: RecordEntry = entry?;
+            Ok(RecordEntry {
+                // selenium uses absolute paths for some reason
+                path: entry.path.trim_start_matches('/').to_string(),
+                ..entry
+            })
+        })
+        .collect()
+}
+
+/// Writes RECORD entries sorted by path, with `/` as separator, `\n` line endings and urlsafe
+/// base64 digests without padding, so the same installation gives byte-identical RECORD files on
+/// windows and unix
+pub fn write_record(writer: impl Write, record: Vec<RecordEntry>) -> Result<(), Error> {
+    let mut record_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .escape(b'"')
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_writer(writer);
+    let mut record: Vec<RecordEntry> = record
+        .into_iter()
+        .map(|entry| RecordEntry {
+            path: entry.path.replace('\\', "/"),
+            hash: entry.hash.map(|hash| normalize_hash(&hash)),
+            size: entry.size,
+        })
+        .collect();
+    record.sort();
+    for entry in record {
+        record_writer.serialize(entry)?;
+    }
+    record_writer.flush()?;
+    Ok(())
+}
+
+/// `sha256=ab+/cd==` to `sha256=ab-_cd`, the RECORD spec wants the urlsafe alphabet without
+/// padding but some build backends write standard base64
+fn normalize_hash(hash: &str) -> String {
+    match hash.split_once('=') {
+        Some((algorithm, digest)) => {
+            let digest = digest
+                .trim_end_matches('=')
+                .replace('+', "-")
+                .replace('/', "_");
+            format!("{}={}", algorithm, digest)
+        }
+        None => hash.to_string(),
+    }
+}
+
+/// Parse a file with `Key: value` entries such as WHEEL and METADATA
+pub fn parse_key_value_file(
+    file: &mut impl Read,
+    debug_filename: &str,
+) -> Result<HashMap<String, Vec<String>>, Error> {
+    let mut data = HashMap::new();
+
+    let file = BufReader::new(file);
+    for (line_no, line) in file.lines().enumerate() {
+        let line = line?.trim().to_string();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line.split_once(": ").ok_or_else(|| {
+            Error::InvalidWheel(format!(
+                "Line {} of the {} file is invalid",
+                line_no, debug_filename
+            ))
+        })?;
+        data.entry(key.to_string())
+            .or_insert_with(Vec::new)
+            .push(value.to_string())
+    }
+    Ok(data)
+}
+
+/// From https://github.com/PyO3/python-pkginfo-rs
+///
+/// The metadata name may be uppercase, while the wheel and dist info names are lowercase, or
+/// the metadata name and the dist info name are lowercase, while the wheel name is uppercase.
+/// Either way, we just search the wheel for the name
+pub(crate) fn find_dist_info(
+    filename: &WheelFilename,
+    archive: &mut ZipArchive<impl Read + Seek + Sized>,
+) -> Result<String, Error> {
+    let dist_info_matcher =
+        format!("{}-{}", filename.distribution, filename.version).to_lowercase();
+    let dist_infos: Vec<_> = archive
+        .file_names()
+        .filter_map(|name| name.split_once('/'))
+        .filter_map(|(dir, file)| Some((dir.strip_suffix(".dist-info")?, file)))
+        .filter(|(dir, file)| dir.to_lowercase() == dist_info_matcher && *file == "METADATA")
+        .map(|(dir, _file)| dir)
+        .collect();
+    let dist_info = match dist_infos.as_slice() {
+        [] => {
+            return Err(Error::InvalidWheel(
+                "Missing .dist-info directory".to_string(),
+            ))
+        }
+        [dist_info] => dist_info.to_string(),
+        _ => {
+            return Err(Error::InvalidWheel(format!(
+                "Multiple .dist-info directories: {}",
+                dist_infos.join(", ")
+            )));
+        }
+    };
+    Ok(dist_info)
+}
+
+/// The raw `.dist-info/METADATA` of a wheel, e.g. to read the dependencies without installing it
+pub fn read_wheel_metadata(
+    filename: &WheelFilename,
+    reader: impl Read + Seek,
+) -> Result<String, Error> {
+    let mut archive =
+        ZipArchive::new(reader).map_err(|err| Error::from_zip_error("(index)".to_string(), err))?;
+    let dist_info_prefix = find_dist_info(filename, &mut archive)?;
+    let metadata_file = format!("{dist_info_prefix}.dist-info/METADATA");
+    let mut content = String::new();
+    archive
+        .by_name(&metadata_file)
+        .map_err(|err| Error::from_zip_error(metadata_file.to_string(), err))?
+        .read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Checks the files of a wheel against the sha256 hashes in its RECORD without extracting it,
+/// e.g. to find corrupted wheels in a cache. Returns the files that don't match, that aren't
+/// recorded or that are recorded but missing
+pub fn verify_wheel(
+    filename: &WheelFilename,
+    reader: impl Read + Seek,
+) -> Result<Vec<String>, Error> {
+    let mut archive =
+        ZipArchive::new(reader).map_err(|err| Error::from_zip_error("(index)".to_string(), err))?;
+    let dist_info_prefix = find_dist_info(filename, &mut archive)?;
+    let record_path = format!("{dist_info_prefix}.dist-info/RECORD");
+    let record = read_record_file(
+        &mut archive
+            .by_name(&record_path)
+            .map_err(|err| Error::from_zip_error(record_path.clone(), err))?,
+    )?;
+    let recorded: HashMap<&str, Option<&str>> = record
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry.hash.as_deref()))
+        .collect();
+    let mut failing = Vec::new();
+    let mut seen = HashSet::new();
+    for i in 0..archive.len() {
+        let mut file = archive
+            .by_index(i)
+            .map_err(|err| Error::from_zip_error(format!("(index {i})"), err))?;
+        let name = file.name().to_string();
+        if name.ends_with('/') {
+            continue;
+        }
+        seen.insert(name.clone());
+        match recorded.get(name.as_str()) {
+            Some(Some(recorded_hash))
+                if recorded_hash
+                    .starts_with(&format!("{}=", hash_backend(HashPurpose::Integrity).name())) =>
+            {
+                let (_size, encoded_hash) = copy_and_hash(&mut file, &mut io::sink())?;
+                if *recorded_hash != encoded_hash {
+                    failing.push(name);
+                }
+            }
+            // RECORD itself and its signatures, or another hash algorithm
+            Some(_) => {}
+            None => {
+                // > RECORD.jws is used for digital signatures. It is not mentioned in RECORD.
+                if name != format!("{record_path}.jws") && name != format!("{record_path}.p7s") {
+                    failing.push(name);
+                }
+            }
+        }
+    }
+    for entry in &record {
+        if entry.hash.is_some() && !seen.contains(&entry.path) {
+            failing.push(entry.path.clone());
+        }
+    }
+    Ok(failing)
+}
+
+/// The paths a wheel installs to with their hash from RECORD, without extracting it, to find
+/// files that two wheels would both write. Files that go into site-packages are relative to it,
+/// the `.data` directories and the entrypoint scripts are prefixed with their scheme, e.g.
+/// `scripts/black` or `headers/foo.h`. Entrypoint scripts have `entrypoint=<module>:<function>`
+/// as hash, since we generate them. The `.dist-info` directory is skipped, since it's unique to
+/// the distribution
+pub fn wheel_install_paths(
+    filename: &WheelFilename,
+    reader: impl Read + Seek,
+) -> Result<Vec<(String, Option<String>)>, Error> {
+    let mut archive =
+        ZipArchive::new(reader).map_err(|err| Error::from_zip_error("(index)".to_string(), err))?;
+    let dist_info_prefix = find_dist_info(filename, &mut archive)?;
+    let record_path = format!("{dist_info_prefix}.dist-info/RECORD");
+    let record = read_record_file(
+        &mut archive
+            .by_name(&record_path)
+            .map_err(|err| Error::from_zip_error(record_path.clone(), err))?,
+    )?;
+    let hashes: HashMap<String, Option<String>> = record
+        .into_iter()
+        .map(|entry| (entry.path, entry.hash))
+        .collect();
+    let dist_info_dir = format!("{dist_info_prefix}.dist-info/");
+    let data_dir = format!("{dist_info_prefix}.data/");
+    let mut paths = Vec::new();
+    for name in archive.file_names() {
+        if name.ends_with('/') || name.starts_with(&dist_info_dir) {
+            continue;
+        }
+        let path = match name.strip_prefix(&data_dir) {
+            Some(data_path) => match data_path.split_once('/') {
+                Some(("purelib" | "platlib", path)) => path.to_string(),
+                Some(_) => data_path.to_string(),
+                None => continue,
+            },
+            None => name.to_string(),
+        };
+        paths.push((path, hashes.get(name).cloned().flatten()));
+    }
+    let (console_scripts, gui_scripts) = parse_scripts(&mut archive, &dist_info_prefix, None)?;
+    for script in console_scripts.iter().chain(&gui_scripts) {
+        paths.push((
+            format!("scripts/{}", script.script_name),
+            Some(format!("entrypoint={}:{}", script.module, script.function)),
+        ));
+    }
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::metadata::{
+        parse_key_value_file, wheel_install_paths, write_record, RecordEntry, Script,
+    };
+    use crate::WheelFilename;
+    use indoc::indoc;
+    use std::fs::File;
+    use std::path::Path;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_parse_key_value_file() {
+        let text = indoc! {"
+            Wheel-Version: 1.0
+            Generator: bdist_wheel (0.37.1)
+            Root-Is-Purelib: false
+            Tag: cp38-cp38-manylinux_2_17_x86_64
+            Tag: cp38-cp38-manylinux2014_x86_64
+        "};
+
+        parse_key_value_file(&mut text.as_bytes(), "WHEEL").unwrap();
+    }
+
+    #[test]
+    fn test_write_record() {
+        let record = vec![
+            RecordEntry {
+                path: "tqdm-4.62.3.dist-info\\RECORD".to_string(),
+                hash: None,
+                size: None,
+            },
+            RecordEntry {
+                path: "..\\..\\Scripts\\tqdm.exe".to_string(),
+                hash: Some("sha256=ab+/cd==".to_string()),
+                size: Some(4),
+            },
+            RecordEntry {
+                path: "tqdm/cli.py".to_string(),
+                hash: Some("sha256=x_c8nmc4Huc-lKEsAXj78ZiyqSJ9hJ71j7vltY67icw".to_string()),
+                size: Some(10509),
+            },
+        ];
+        let mut written = Vec::new();
+        write_record(&mut written, record).unwrap();
+        assert_eq!(
+            String::from_utf8(written).unwrap(),
+            indoc! {"
+                ../../Scripts/tqdm.exe,sha256=ab-_cd,4
+                tqdm-4.62.3.dist-info/RECORD,,
+                tqdm/cli.py,sha256=x_c8nmc4Huc-lKEsAXj78ZiyqSJ9hJ71j7vltY67icw,10509
+            "}
+        );
+    }
+
+    #[test]
+    fn test_script_from_value() {
+        assert_eq!(
+            Script::from_value("launcher", "foo.bar:main", None).unwrap(),
+            Some(Script {
+                script_name: "launcher".to_string(),
+                module: "foo.bar".to_string(),
+                function: "main".to_string(),
+            })
+        );
+        assert_eq!(
+            Script::from_value(
+                "launcher",
+                "foo.bar:main",
+                Some(&["bar".to_string(), "baz".to_string()])
+            )
+            .unwrap(),
+            Some(Script {
+                script_name: "launcher".to_string(),
+                module: "foo.bar".to_string(),
+                function: "main".to_string(),
+            })
+        );
+        assert_eq!(
+            Script::from_value("launcher", "foomod:main_bar [bar,baz]", Some(&[])).unwrap(),
+            None
+        );
+        assert_eq!(
+            Script::from_value(
+                "launcher",
+                "foomod:main_bar [bar,baz]",
+                Some(&["bar".to_string(), "baz".to_string()])
+            )
+            .unwrap(),
+            Some(Script {
+                script_name: "launcher".to_string(),
+                module: "foomod".to_string(),
+                function: "main_bar".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_wheel_install_paths() {
+        let packages = Path::new("../../test-data/pip-test-packages");
+        let mut paths = Vec::new();
+        for filename in [
+            "has.script-1.0-py2.py3-none-any.whl",
+            "console_scripts_uppercase-1.0-py2.py3-none-any.whl",
+        ] {
+            let wheel = File::open(packages.join(filename)).unwrap();
+            let filename = WheelFilename::from_str(filename).unwrap();
+            paths.extend(wheel_install_paths(&filename, wheel).unwrap());
+        }
+        let paths: Vec<String> = paths
+            .into_iter()
+            .map(|(path, hash)| format!("{} {}", path, hash.unwrap_or_default()))
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "scripts/script.py sha256=enKSGMk4N5M_w_Xd35Gqn9dzWaQXe5vcwo10_5KJHiE",
+                "cmdmod/__init__.py sha256=2RYza_zqmdRYNL8hpt9PsZimNSpvwcxAIEjHhu9HfpQ",
+                "scripts/cmdName entrypoint=cmdmod:cmd"
+            ]
+        );
+    }
+}
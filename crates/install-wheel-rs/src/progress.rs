@@ -0,0 +1,84 @@
+//! Progress events during installation, so CLIs can render progress bars and CI logs can show
+//! what's happening in long installs
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::debug;
+
+/// Receives progress events from [crate::install_wheel]. All methods default to doing nothing.
+///
+/// Wheels may be installed in parallel, so events of different wheels can interleave; `name` is
+/// the distribution name from the wheel filename.
+pub trait ProgressReporter: Sync {
+    /// We started installing a wheel
+    fn wheel_started(&self, _name: &str) {}
+    /// A file from the wheel was extracted with `bytes` size
+    fn bytes_extracted(&self, _name: &str, _bytes: u64) {}
+    /// A file was written, `path` is relative to site-packages
+    fn file_written(&self, _name: &str, _path: &Path) {}
+    /// The wheel was installed successfully
+    fn wheel_finished(&self, _name: &str) {}
+}
+
+/// Doesn't report anything
+pub struct NoProgress;
+
+impl ProgressReporter for NoProgress {}
+
+/// Emits a structured `debug!` line when a wheel is started and finished, with the number of
+/// files and bytes written
+#[derive(Default)]
+pub struct LogProgress {
+    /// name -> (files, bytes)
+    counts: Mutex<HashMap<String, (usize, u64)>>,
+}
+
+impl ProgressReporter for LogProgress {
+    fn wheel_started(&self, name: &str) {
+        self.counts.lock().unwrap().insert(name.to_string(), (0, 0));
+        debug!(wheel = name, "Started installing");
+    }
+
+    fn bytes_extracted(&self, name: &str, bytes: u64) {
+        self.counts
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .1 += bytes;
+    }
+
+    fn file_written(&self, name: &str, _path: &Path) {
+        self.counts
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .0 += 1;
+    }
+
+    fn wheel_finished(&self, name: &str) {
+        let (files, bytes) = self.counts.lock().unwrap().remove(name).unwrap_or_default();
+        debug!(wheel = name, files, bytes, "Finished installing");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LogProgress, ProgressReporter};
+    use std::path::Path;
+
+    #[test]
+    fn test_log_progress_counts() {
+        let progress = LogProgress::default();
+        progress.wheel_started("black");
+        progress.bytes_extracted("black", 100);
+        progress.file_written("black", Path::new("black/__init__.py"));
+        progress.bytes_extracted("black", 20);
+        progress.file_written("black", Path::new("black/__main__.py"));
+        assert_eq!(progress.counts.lock().unwrap()["black"], (2, 120));
+        progress.wheel_finished("black");
+        assert!(progress.counts.lock().unwrap().is_empty());
+    }
+}
@@ -1,11 +1,15 @@
 #![allow(clippy::format_push_string)] // I will not replace clear and infallible with fallible, io looking code
 
-use crate::{install_wheel, CompatibleTags, Error, InstallLocation, LockedDir, WheelFilename};
+use crate::{
+    read_wheel_metadata, uninstall, Error, InstallLocation, Installer, LockedDir, WheelFilename,
+};
+use fs_err::File;
 use pyo3::create_exception;
 use pyo3::types::PyModule;
-use pyo3::{pyclass, pymethods, pymodule, PyErr, PyResult, Python};
+use pyo3::{pyclass, pyfunction, pymethods, pymodule, wrap_pyfunction, PyErr, PyResult, Python};
+#[cfg(feature = "rayon")]
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use std::env;
-use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
@@ -38,10 +42,10 @@ impl LockedVenv {
     #[new]
     pub fn new(py: Python, venv: PathBuf) -> PyResult<Self> {
         Ok(Self {
-            location: InstallLocation::Venv {
-                venv_base: LockedDir::acquire(&venv)?,
-                python_version: (py.version_info().major, py.version_info().minor),
-            },
+            location: InstallLocation::venv(
+                LockedDir::acquire(&venv)?,
+                (py.version_info().major, py.version_info().minor),
+            ),
         })
     }
 
@@ -51,28 +55,57 @@ impl LockedVenv {
 
         // TODO: Pass those options on to the user
         py.allow_threads(|| {
-            let filename = wheel
-                .file_name()
-                .ok_or_else(|| Error::InvalidWheel("Expected a file".to_string()))?
-                .to_string_lossy();
-            let filename = WheelFilename::from_str(&filename)?;
-            let compatible_tags = CompatibleTags::current(self.location.get_python_version())?;
-            filename.compatibility(&compatible_tags)?;
-
-            install_wheel(
-                &self.location,
-                File::open(wheel)?,
-                filename,
-                true,
-                true,
-                &[],
-                // unique_version can be anything since it's only used to monotrail
-                "",
-                Path::new(&sys_executable),
-            )
+            Installer::new(&self.location, Path::new(&sys_executable))
+                .compile(true)
+                .force(true)
+                .install_file(wheel)
         })?;
         Ok(())
     }
+
+    /// Installs all wheels without holding the GIL, in parallel if built with rayon. Returns
+    /// the tags in the order of the wheels
+    pub fn install_many(&self, py: Python, wheels: Vec<PathBuf>) -> PyResult<Vec<String>> {
+        let sys_executable: String = py.import("sys")?.getattr("executable")?.extract()?;
+
+        let tags = py.allow_threads(|| {
+            let installer = Installer::new(&self.location, Path::new(&sys_executable))
+                .compile(true)
+                .force(true);
+            #[cfg(feature = "rayon")]
+            let wheels = wheels.into_par_iter();
+            #[cfg(not(feature = "rayon"))]
+            let wheels = wheels.into_iter();
+            wheels
+                .map(|wheel| installer.install_file(wheel))
+                .collect::<Result<Vec<_>, Error>>()
+        })?;
+        Ok(tags)
+    }
+
+    /// Removes the distribution and returns the removed version, `None` if it wasn't installed
+    pub fn uninstall(&self, py: Python, name: &str) -> PyResult<Option<String>> {
+        Ok(py.allow_threads(|| uninstall(&self.location, name))?)
+    }
+
+    /// The `(python, abi, platform)` tags of wheels that can be installed into this venv, most
+    /// specific first
+    pub fn compatible_tags(&self) -> PyResult<Vec<(String, String, String)>> {
+        Ok(self.location.compatible_tags()?.tags)
+    }
+}
+
+/// The `METADATA` of a wheel as string, e.g. to parse it with `email.parser`
+#[pyfunction]
+fn read_metadata(py: Python, wheel: PathBuf) -> PyResult<String> {
+    py.allow_threads(|| {
+        let filename = wheel
+            .file_name()
+            .ok_or_else(|| Error::InvalidWheel("Expected a file".to_string()))?
+            .to_string_lossy();
+        let filename = WheelFilename::from_str(&filename)?;
+        Ok(read_wheel_metadata(&filename, File::open(&wheel)?)?)
+    })
 }
 
 #[pymodule]
@@ -89,5 +122,6 @@ pub fn install_wheel_rs(_py: Python, m: &PyModule) -> PyResult<()> {
         tracing_subscriber::fmt().event_format(format).init();
     }
     m.add_class::<LockedVenv>()?;
+    m.add_function(wrap_pyfunction!(read_metadata, m)?)?;
     Ok(())
 }
@@ -0,0 +1,322 @@
+//! Basic auditwheel: A wheel built locally from a source distribution may link against shared
+//! libraries that only exist on this machine. We copy all libraries outside the manylinux policy
+//! into `<distribution>.libs` in the wheel and point the rpath of the extension modules there,
+//! so the wheel can be shared through the store.
+//!
+//! Unlike auditwheel, we don't rename the copied libraries and don't retag the wheel. Setting the
+//! rpath requires `patchelf`.
+
+use crate::hashing::record_hash;
+use crate::metadata::{find_dist_info, RecordEntry};
+use crate::wheel::relative_to;
+use crate::{Error, WheelFilename};
+use fs_err as fs;
+use goblin::elf::Elf;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::env;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use tempfile::{tempdir, NamedTempFile};
+use tracing::debug;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+/// The libraries that manylinux wheels may link against, which every linux system has
+///
+/// <https://peps.python.org/pep-0599/#the-manylinux2014-policy>
+const MANYLINUX_LIBS: &[&str] = &[
+    "libgcc_s.so.1",
+    "libstdc++.so.6",
+    "libm.so.6",
+    "libdl.so.2",
+    "librt.so.1",
+    "libc.so.6",
+    "libnsl.so.1",
+    "libutil.so.1",
+    "libpthread.so.0",
+    "libresolv.so.2",
+    "libX11.so.6",
+    "libXext.so.6",
+    "libXrender.so.1",
+    "libICE.so.6",
+    "libSM.so.6",
+    "libGL.so.1",
+    "libgobject-2.0.so.0",
+    "libgthread-2.0.so.0",
+    "libglib-2.0.so.0",
+    "libcrypt.so.1",
+];
+
+/// The libraries copied into the wheel by [repair_wheel], by soname
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct RepairReport {
+    pub grafted: BTreeMap<String, PathBuf>,
+}
+
+/// Whether a library is provided by the system or the python interpreter
+fn is_allowed_by_policy(soname: &str) -> bool {
+    MANYLINUX_LIBS.contains(&soname)
+        || soname.starts_with("ld-linux")
+        || soname.starts_with("ld64.so")
+        || soname.starts_with("libpython")
+}
+
+/// `LD_LIBRARY_PATH` and the default directories of the dynamic linker
+pub fn library_search_path() -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = env::var_os("LD_LIBRARY_PATH")
+        .map(|paths| env::split_paths(&paths).collect())
+        .unwrap_or_default();
+    dirs.extend(
+        [
+            "/lib64",
+            "/usr/lib64",
+            "/lib",
+            "/usr/lib",
+            "/usr/local/lib",
+            "/lib/x86_64-linux-gnu",
+            "/usr/lib/x86_64-linux-gnu",
+            "/lib/aarch64-linux-gnu",
+            "/usr/lib/aarch64-linux-gnu",
+        ]
+        .map(PathBuf::from),
+    );
+    dirs
+}
+
+/// The libraries a shared library or executable links against, `None` if it's not an ELF file
+fn needed_libraries(content: &[u8]) -> Result<Option<Vec<String>>, Error> {
+    if !content.starts_with(b"\x7fELF") {
+        return Ok(None);
+    }
+    let elf = Elf::parse(content)
+        .map_err(|err| Error::Repair(format!("Invalid shared library: {}", err)))?;
+    Ok(Some(
+        elf.libraries.iter().map(ToString::to_string).collect(),
+    ))
+}
+
+/// Copies the libraries the extension modules need that aren't covered by the manylinux policy
+/// into the wheel and rewrites the wheel in place. Libraries are searched in `lib_dirs`.
+///
+/// Returns which libraries were copied, if none, the wheel remains untouched
+pub fn repair_wheel(wheel: &Path, lib_dirs: &[PathBuf]) -> Result<RepairReport, Error> {
+    let filename = wheel
+        .file_name()
+        .ok_or_else(|| Error::InvalidWheel("Expected a file".to_string()))?
+        .to_string_lossy()
+        .to_string();
+    let wheel_filename = WheelFilename::from_str(&filename)?;
+    let mut archive = ZipArchive::new(fs::File::open(wheel)?)
+        .map_err(|err| Error::from_zip_error(filename.clone(), err))?;
+    let dist_info_prefix = find_dist_info(&wheel_filename, &mut archive)?;
+    let record_path = format!("{}.dist-info/RECORD", dist_info_prefix);
+    let libs_dir = format!("{}.libs", wheel_filename.distribution);
+
+    // (name, content, unix mode)
+    let mut entries: Vec<(String, Vec<u8>, Option<u32>)> = Vec::new();
+    for index in 0..archive.len() {
+        let mut file = archive
+            .by_index(index)
+            .map_err(|err| Error::from_zip_error(filename.clone(), err))?;
+        if file.is_dir() {
+            continue;
+        }
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        entries.push((file.name().to_string(), content, file.unix_mode()));
+    }
+
+    let in_wheel: HashSet<String> = entries
+        .iter()
+        .filter_map(|(name, _, _)| Some(name.rsplit_once('/')?.1.to_string()))
+        .collect();
+    // The extension modules and the grafted libraries that need a grafted library
+    let mut needs_rpath: Vec<String> = Vec::new();
+    let mut report = RepairReport::default();
+    let mut grafted_content: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+    // (name in the wheel or soname of a grafted library, content)
+    let mut queue: VecDeque<(String, Vec<u8>)> = entries
+        .iter()
+        .filter(|(_, content, _)| content.starts_with(b"\x7fELF"))
+        .map(|(name, content, _)| (name.clone(), content.clone()))
+        .collect();
+    while let Some((name, content)) = queue.pop_front() {
+        let Some(needed) = needed_libraries(&content)? else {
+            continue;
+        };
+        let mut needs_grafted = false;
+        for soname in needed {
+            if is_allowed_by_policy(&soname) || in_wheel.contains(&soname) {
+                continue;
+            }
+            needs_grafted = true;
+            if report.grafted.contains_key(&soname) {
+                continue;
+            }
+            let source = lib_dirs
+                .iter()
+                .map(|dir| dir.join(&soname))
+                .find(|path| path.is_file())
+                .ok_or_else(|| {
+                    Error::Repair(format!(
+                        "{} needs {}, which isn't allowed by the manylinux policy, \
+                        but it's not in any of the library directories",
+                        name, soname
+                    ))
+                })?;
+            debug!("Grafting {} from {}", soname, source.display());
+            let lib_content = fs::read(&source)?;
+            queue.push_back((soname.clone(), lib_content.clone()));
+            grafted_content.insert(soname.clone(), lib_content);
+            report.grafted.insert(soname, source);
+        }
+        if needs_grafted {
+            needs_rpath.push(name);
+        }
+    }
+
+    if report.grafted.is_empty() {
+        return Ok(report);
+    }
+
+    // Extension modules find the libraries relative to their own location
+    for (name, content, _) in &mut entries {
+        if needs_rpath.contains(name) {
+            let parent = Path::new(name.as_str()).parent().unwrap_or(Path::new(""));
+            let libs_relative = relative_to(Path::new(&libs_dir), parent)?;
+            let rpath = format!("$ORIGIN/{}", libs_relative.display());
+            *content = set_rpath(content, &rpath)?;
+        }
+    }
+    for (soname, content) in &mut grafted_content {
+        if needs_rpath.contains(soname) {
+            *content = set_rpath(content, "$ORIGIN")?;
+        }
+    }
+
+    let grafted_entries = grafted_content
+        .into_iter()
+        .map(|(soname, content)| (format!("{}/{}", libs_dir, soname), content, Some(0o755)));
+    let entries: Vec<_> = entries
+        .into_iter()
+        .filter(|(name, _, _)| *name != record_path)
+        .chain(grafted_entries)
+        .collect();
+    write_wheel(wheel, &entries, &record_path)?;
+    Ok(report)
+}
+
+/// Runs `patchelf --set-rpath` on a copy of the library
+fn set_rpath(content: &[u8], rpath: &str) -> Result<Vec<u8>, Error> {
+    let temp_dir = tempdir()?;
+    let library = temp_dir.path().join("library.so");
+    fs::write(&library, content)?;
+    let status = Command::new("patchelf")
+        .arg("--set-rpath")
+        .arg(rpath)
+        .arg(&library)
+        .status()
+        .map_err(|err| {
+            Error::Repair(format!(
+                "Failed to run patchelf, which is required to repair wheels: {}",
+                err
+            ))
+        })?;
+    if !status.success() {
+        return Err(Error::Repair(format!(
+            "patchelf --set-rpath failed with {}",
+            status
+        )));
+    }
+    Ok(fs::read(&library)?)
+}
+
+/// Writes all entries plus a new RECORD to a temporary file and replaces `wheel` with it
+fn write_wheel(
+    wheel: &Path,
+    entries: &[(String, Vec<u8>, Option<u32>)],
+    record_path: &str,
+) -> Result<(), Error> {
+    let parent = wheel.parent().unwrap_or(Path::new("."));
+    let temp_file = NamedTempFile::new_in(parent)?;
+    let mut writer = ZipWriter::new(temp_file.reopen()?);
+    let mut record = Vec::new();
+    for (name, content, mode) in entries {
+        let mut options =
+            FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        if let Some(mode) = mode {
+            options = options.unix_permissions(*mode);
+        }
+        writer
+            .start_file(name, options)
+            .map_err(|err| Error::from_zip_error(wheel.display().to_string(), err))?;
+        writer.write_all(content)?;
+        record.push(RecordEntry {
+            path: name.clone(),
+            hash: Some(record_hash(content)),
+            size: Some(content.len()),
+        });
+    }
+    record.push(RecordEntry {
+        path: record_path.to_string(),
+        hash: None,
+        size: None,
+    });
+    let mut record_writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .escape(b'"')
+        .from_writer(Vec::new());
+    for entry in record {
+        record_writer.serialize(entry)?;
+    }
+    let record_content = record_writer
+        .into_inner()
+        .map_err(|err| Error::Repair(format!("Failed to write RECORD: {}", err)))?;
+    writer
+        .start_file(record_path, FileOptions::default())
+        .map_err(|err| Error::from_zip_error(wheel.display().to_string(), err))?;
+    writer.write_all(&record_content)?;
+    writer
+        .finish()
+        .map_err(|err| Error::from_zip_error(wheel.display().to_string(), err))?;
+    temp_file
+        .persist(wheel)
+        .map_err(|err| Error::IO(err.error))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::repair::{is_allowed_by_policy, needed_libraries, repair_wheel, RepairReport};
+    use fs_err as fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_policy() {
+        assert!(is_allowed_by_policy("libc.so.6"));
+        assert!(is_allowed_by_policy("ld-linux-x86-64.so.2"));
+        assert!(is_allowed_by_policy("libpython3.8.so.1.0"));
+        assert!(!is_allowed_by_policy("libz.so.1"));
+        assert!(!is_allowed_by_policy("libssl.so.3"));
+    }
+
+    #[test]
+    fn test_not_elf() {
+        assert_eq!(needed_libraries(b"print('hello')").unwrap(), None);
+    }
+
+    /// Without extension modules, there is nothing to repair
+    #[test]
+    fn test_pure_python_unchanged() {
+        let temp_dir = tempdir().unwrap();
+        let filename = "purelib_and_platlib-1.0.0-cp38-cp38-linux_x86_64.whl";
+        let wheel = temp_dir.path().join(filename);
+        fs::copy(format!("../../test-data/wheels/{}", filename), &wheel).unwrap();
+        let before = fs::read(&wheel).unwrap();
+        let report = repair_wheel(&wheel, &[]).unwrap();
+        assert_eq!(report, RepairReport::default());
+        assert_eq!(fs::read(&wheel).unwrap(), before);
+    }
+}
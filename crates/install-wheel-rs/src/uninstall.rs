@@ -0,0 +1,309 @@
+//! Removing installed distributions by their RECORD, the inverse of [crate::Installer]
+
+use crate::install_location::{InstallLocation, LockedDir};
+use crate::metadata::{normalize_name, read_record_file};
+use crate::Error;
+use fs_err as fs;
+use std::collections::{BTreeSet, HashSet};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use tracing::debug;
+
+/// The .dist-info directories in site-packages with the parsed name and version
+pub fn installed_dist_infos(site_packages: &Path) -> Result<Vec<(String, String, String)>, Error> {
+    let entries = match fs::read_dir(site_packages) {
+        Ok(entries) => entries.collect::<io::Result<Vec<_>>>()?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
+    let mut dist_infos: Vec<_> = entries
+        .iter()
+        .filter_map(|entry| {
+            let dist_info = entry.file_name().to_string_lossy().to_string();
+            let (name, version) = dist_info.strip_suffix(".dist-info")?.split_once('-')?;
+            Some((normalize_name(name), version.to_string(), dist_info.clone()))
+        })
+        .collect();
+    dist_infos.sort();
+    Ok(dist_infos)
+}
+
+/// The paths in the RECORD files of all distributions except `dist_info`
+fn paths_of_others(site_packages: &Path, dist_info: &str) -> Result<HashSet<PathBuf>, Error> {
+    let mut paths = HashSet::new();
+    for (_, _, other) in installed_dist_infos(site_packages)? {
+        if other == dist_info {
+            continue;
+        }
+        let record = match fs::read(site_packages.join(&other).join("RECORD")) {
+            Ok(record) => record,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let record = read_record_file(&mut record.as_slice())?;
+        let root = install_root(site_packages);
+        paths.extend(
+            record
+                .into_iter()
+                .filter_map(|entry| record_path(site_packages, root, &entry.path)),
+        );
+    }
+    Ok(paths)
+}
+
+/// The venv or prefix of `site_packages`, i.e. where a RECORD entry such as `../../../bin/foo` may
+/// point to. Without a `lib/pythonX.Y/site-packages` or `Lib/site-packages` layout, it's
+/// `site_packages` itself
+fn install_root(site_packages: &Path) -> &Path {
+    site_packages
+        .ancestors()
+        .take(3)
+        .find(|ancestor| {
+            ancestor
+                .file_name()
+                .is_some_and(|name| name == "lib" || name == "Lib")
+        })
+        .and_then(Path::parent)
+        .unwrap_or(site_packages)
+}
+
+/// The location of a RECORD entry, `None` if it's absolute or `..` leaves `root`
+fn record_path(site_packages: &Path, root: &Path, path: &str) -> Option<PathBuf> {
+    let mut resolved = site_packages.to_path_buf();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => resolved.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    resolved.starts_with(root).then_some(resolved)
+}
+
+/// Removes the files of a distribution and the directories that became empty.
+///
+/// Files that another distribution also installed stay, such as the `__init__.py` that all
+/// `pkg_resources`-style namespace packages (e.g. `zope.*`) share, and so do directories with
+/// files of other distributions, such as PEP 420 namespace packages (e.g. `google.*`)
+pub fn remove_distribution(site_packages: &Path, dist_info: &str) -> Result<(), Error> {
+    let dist_info_dir = site_packages.join(dist_info);
+    let record = match fs::read(dist_info_dir.join("RECORD")) {
+        Ok(record) => record,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::RecordFile(format!(
+                "{} has no RECORD, can't remove it",
+                dist_info
+            )))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let record = read_record_file(&mut record.as_slice())?;
+    // A broken or malicious RECORD must not make us delete files elsewhere, so we check all paths
+    // before removing anything
+    let root = install_root(site_packages);
+    let paths = record
+        .iter()
+        .map(|entry| {
+            record_path(site_packages, root, &entry.path).ok_or_else(|| {
+                Error::RecordFile(format!(
+                    "{} lists {}, which is outside of {}, refusing to remove it",
+                    dist_info,
+                    entry.path,
+                    root.display()
+                ))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let shared = paths_of_others(site_packages, dist_info)?;
+    let mut parents = BTreeSet::new();
+    for path in paths {
+        if shared.contains(&path) {
+            debug!(
+                "Keeping {}, another package installed it too",
+                path.display()
+            );
+            continue;
+        }
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        // All the way up, e.g. google/cloud/storage/ can leave an empty google/cloud/
+        parents.extend(path.ancestors().skip(1).map(Path::to_path_buf));
+    }
+    if dist_info_dir.is_dir() {
+        fs::remove_dir_all(&dist_info_dir)?;
+    }
+    // Deepest first, so a package dir is empty once its subpackages are gone
+    let mut parents: Vec<PathBuf> = parents.into_iter().collect();
+    parents.sort_by_key(|parent| std::cmp::Reverse(parent.components().count()));
+    for parent in parents {
+        if !parent.starts_with(site_packages) || parent == site_packages {
+            continue;
+        }
+        let pycache = parent.join("__pycache__");
+        let entries: Vec<_> = fs::read_dir(&parent)
+            .map(|entries| entries.filter_map(Result::ok).collect())
+            .unwrap_or_default();
+        if entries.len() == 1 && entries[0].path() == pycache {
+            fs::remove_dir_all(&pycache)?;
+        }
+        // Fails for non-empty dirs, which is what we want
+        let _ = std::fs::remove_dir(&parent);
+    }
+    Ok(())
+}
+
+/// Removes the installed version of `name` from a venv and returns that version, `None` if it
+/// isn't installed
+pub fn uninstall(
+    location: &InstallLocation<LockedDir>,
+    name: &str,
+) -> Result<Option<String>, Error> {
+    let name = normalize_name(name);
+    match location {
+        InstallLocation::Venv { venv_base, .. } => {
+            let scheme = location.scheme_paths();
+            let mut site_packages_dirs = vec![venv_base.join(&scheme.purelib)];
+            if scheme.platlib != scheme.purelib {
+                site_packages_dirs.push(venv_base.join(&scheme.platlib));
+            }
+            for site_packages in site_packages_dirs {
+                for (installed, version, dist_info) in installed_dist_infos(&site_packages)? {
+                    if installed == name {
+                        debug!("Removing {} {}", name, version);
+                        remove_distribution(&site_packages, &dist_info)?;
+                        return Ok(Some(version));
+                    }
+                }
+            }
+            Ok(None)
+        }
+        // A version may be used by many projects, removing it would break the others
+        InstallLocation::Monotrail { .. } => Err(Error::InvalidScheme(
+            "Only venv installations can be uninstalled, monotrail packages are shared between \
+            projects"
+                .to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::uninstall::{remove_distribution, uninstall};
+    use crate::InstallLocation;
+    use fs_err as fs;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    /// Writes the files and a RECORD listing them
+    fn install_files(site_packages: &Path, dist_info: &str, files: &[&str]) {
+        let mut record = String::new();
+        for file in files {
+            let path = site_packages.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, "").unwrap();
+            record += &format!("{},,\n", file);
+        }
+        fs::create_dir_all(site_packages.join(dist_info)).unwrap();
+        record += &format!("{}/RECORD,,\n", dist_info);
+        fs::write(site_packages.join(dist_info).join("RECORD"), record).unwrap();
+    }
+
+    #[test]
+    fn test_remove_namespace_packages() {
+        let temp_dir = TempDir::new().unwrap();
+        let site_packages = temp_dir.path();
+        // pkg_resources-style, both install zope/__init__.py
+        install_files(
+            site_packages,
+            "zope.interface-6.0.dist-info",
+            &["zope/__init__.py", "zope/interface/__init__.py"],
+        );
+        install_files(
+            site_packages,
+            "zope.event-5.0.dist-info",
+            &["zope/__init__.py", "zope/event/__init__.py"],
+        );
+        // PEP 420, google/ and google/cloud/ have no __init__.py
+        install_files(
+            site_packages,
+            "google_cloud_storage-2.10.0.dist-info",
+            &["google/cloud/storage/__init__.py"],
+        );
+        install_files(
+            site_packages,
+            "protobuf-4.24.0.dist-info",
+            &["google/protobuf/__init__.py"],
+        );
+
+        remove_distribution(site_packages, "zope.event-5.0.dist-info").unwrap();
+        assert!(site_packages.join("zope/__init__.py").is_file());
+        assert!(site_packages.join("zope/interface/__init__.py").is_file());
+        assert!(!site_packages.join("zope/event").exists());
+
+        remove_distribution(site_packages, "google_cloud_storage-2.10.0.dist-info").unwrap();
+        assert!(!site_packages.join("google/cloud").exists());
+        assert!(site_packages.join("google/protobuf/__init__.py").is_file());
+
+        remove_distribution(site_packages, "zope.interface-6.0.dist-info").unwrap();
+        remove_distribution(site_packages, "protobuf-4.24.0.dist-info").unwrap();
+        assert_eq!(fs::read_dir(site_packages).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_uninstall() {
+        let venv = TempDir::new().unwrap();
+        let location = InstallLocation::venv(venv.path().to_path_buf(), (3, 12))
+            .acquire_lock()
+            .unwrap();
+        let site_packages = venv.path().join(location.scheme_paths().purelib);
+        install_files(
+            &site_packages,
+            "Zope.Event-5.0.dist-info",
+            &["zope/event/__init__.py", "../../../bin/zope-event"],
+        );
+
+        assert_eq!(uninstall(&location, "tqdm").unwrap(), None);
+        assert_eq!(
+            uninstall(&location, "zope-event").unwrap(),
+            Some("5.0".to_string())
+        );
+        assert_eq!(fs::read_dir(&site_packages).unwrap().count(), 0);
+        assert!(!venv.path().join("bin/zope-event").exists());
+    }
+
+    #[test]
+    fn test_remove_outside_of_venv() {
+        let temp_dir = TempDir::new().unwrap();
+        let venv = temp_dir.path().join("venv");
+        let site_packages = venv.join("lib/python3.12/site-packages");
+        fs::create_dir_all(&site_packages).unwrap();
+        let outside = temp_dir.path().join("outside.txt");
+        fs::write(&outside, "keep me").unwrap();
+        let dist_info = site_packages.join("evil-1.0.dist-info");
+        let write_record = |path: &str| {
+            fs::create_dir_all(&dist_info).unwrap();
+            fs::write(
+                dist_info.join("RECORD"),
+                format!("evil/__init__.py,,\n{},,\n", path),
+            )
+            .unwrap();
+        };
+        write_record("../../../../outside.txt");
+        let err = remove_distribution(&site_packages, "evil-1.0.dist-info").unwrap_err();
+        assert!(err.to_string().contains("refusing to remove it"), "{}", err);
+        assert!(outside.is_file());
+        assert!(dist_info.is_dir());
+        // Absolute paths are read as relative to site-packages
+        write_record(outside.to_str().unwrap());
+        remove_distribution(&site_packages, "evil-1.0.dist-info").unwrap();
+        assert!(outside.is_file());
+    }
+}
@@ -1,26 +1,31 @@
 #![allow(clippy::needless_borrow)]
 
-use crate::install_location::{InstallLocation, LockedDir};
+use crate::hashing::record_hash;
+use crate::install_location::{InstallLocation, LockedDir, SchemePaths};
+use crate::installer::Installer;
+use crate::metadata::{
+    copy_and_hash, find_dist_info, normalize_name, parse_key_value_file, parse_scripts,
+    read_record_file, write_record, RecordEntry, Script,
+};
+use crate::progress::ProgressReporter;
+use crate::uninstall::installed_dist_infos;
 use crate::wheel_tags::WheelFilename;
-use crate::{normalize_name, Error};
-use configparser::ini::Ini;
-use data_encoding::BASE64URL_NOPAD;
+use crate::{DirectUrl, Error};
+use filetime::FileTime;
 use fs_err as fs;
 use fs_err::{DirEntry, File};
 use mailparse::MailHeaderMap;
-use regex::Regex;
-use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 use std::ffi::OsString;
 use std::io::{BufRead, BufReader, BufWriter, Cursor, Read, Seek, Write};
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus, Stdio};
+use std::time::Instant;
 use std::{env, io, iter};
 use tempfile::{tempdir, TempDir};
 use tracing::{debug, error, span, warn, Level};
 use walkdir::WalkDir;
-use zip::result::ZipError;
 use zip::write::FileOptions;
 use zip::{ZipArchive, ZipWriter};
 
@@ -31,92 +36,6 @@ pub const LAUNCHER_T32: &[u8] = include_bytes!("../windows-launcher/t32.exe");
 pub const LAUNCHER_T64: &[u8] = include_bytes!("../windows-launcher/t64.exe");
 pub const LAUNCHER_T64_ARM: &[u8] = include_bytes!("../windows-launcher/t64-arm.exe");
 
-/// Line in a RECORD file
-/// <https://www.python.org/dev/peps/pep-0376/#record>
-///
-/// ```csv
-/// tqdm/cli.py,sha256=x_c8nmc4Huc-lKEsAXj78ZiyqSJ9hJ71j7vltY67icw,10509
-/// tqdm-4.62.3.dist-info/RECORD,,
-/// ```
-#[derive(Deserialize, Serialize, PartialOrd, PartialEq, Ord, Eq)]
-pub struct RecordEntry {
-    pub path: String,
-    pub hash: Option<String>,
-    #[allow(dead_code)]
-    pub size: Option<usize>,
-}
-
-/// Minimal direct_url.json schema
-///
-/// <https://packaging.python.org/en/latest/specifications/direct-url/>
-/// <https://www.python.org/dev/peps/pep-0610/>
-#[derive(Serialize)]
-struct DirectUrl {
-    archive_info: HashMap<(), ()>,
-    url: String,
-}
-
-/// A script defining the name of the runnable entrypoint and the module and function that should be
-/// run.
-#[cfg(feature = "python_bindings")]
-#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
-#[pyo3::pyclass(dict)]
-pub struct Script {
-    #[pyo3(get)]
-    pub script_name: String,
-    #[pyo3(get)]
-    pub module: String,
-    #[pyo3(get)]
-    pub function: String,
-}
-
-/// A script defining the name of the runnable entrypoint and the module and function that should be
-/// run.
-#[cfg(not(feature = "python_bindings"))]
-#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
-pub struct Script {
-    pub script_name: String,
-    pub module: String,
-    pub function: String,
-}
-
-impl Script {
-    /// Parses a script definition like `foo.bar:main` or `foomod:main_bar [bar,baz]`
-    ///
-    /// <https://packaging.python.org/en/latest/specifications/entry-points/>
-    ///
-    /// Extras are supposed to be ignored, which happens if you pass None for extras
-    pub fn from_value(
-        script_name: &str,
-        value: &str,
-        extras: Option<&[String]>,
-    ) -> Result<Option<Script>, Error> {
-        let script_regex = Regex::new(r"^(?P<module>[\w\d_\-.]+):(?P<function>[\w\d_\-.]+)(?:\s+\[(?P<extras>(?:[^,]+,?\s*)+)\])?$").unwrap();
-
-        let captures = script_regex
-            .captures(value)
-            .ok_or_else(|| Error::InvalidWheel(format!("invalid console script: '{}'", value)))?;
-        if let Some(script_extras) = captures.name("extras") {
-            let script_extras = script_extras
-                .as_str()
-                .split(',')
-                .map(|extra| extra.trim().to_string())
-                .collect::<HashSet<String>>();
-            if let Some(extras) = extras {
-                if !script_extras.is_subset(&extras.iter().cloned().collect()) {
-                    return Ok(None);
-                }
-            }
-        }
-
-        Ok(Some(Script {
-            script_name: script_name.to_string(),
-            module: captures.name("module").unwrap().as_str().to_string(),
-            function: captures.name("function").unwrap().as_str().to_string(),
-        }))
-    }
-}
-
 /// Wrapper script template function
 ///
 /// <https://github.com/pypa/pip/blob/7f8a6844037fb7255cfd0d34ff8e8cf44f2598d4/src/pip/_vendor/distlib/scripts.py#L41-L48>
@@ -137,97 +56,6 @@ if __name__ == "__main__":
     )
 }
 
-/// Part of entrypoints parsing
-fn read_scripts_from_section(
-    scripts_section: &HashMap<String, Option<String>>,
-    section_name: &str,
-    extras: Option<&[String]>,
-) -> Result<Vec<Script>, Error> {
-    let mut scripts = Vec::new();
-    for (script_name, python_location) in scripts_section.iter() {
-        match python_location {
-            Some(value) => {
-                if let Some(script) = Script::from_value(script_name, value, extras)? {
-                    scripts.push(script);
-                }
-            }
-            None => {
-                return Err(Error::InvalidWheel(format!(
-                    "[{}] key {} must have a value",
-                    section_name, script_name
-                )));
-            }
-        }
-    }
-    Ok(scripts)
-}
-
-/// Parses the entry_points.txt entry in the wheel for console scripts
-///
-/// Returns (script_name, module, function)
-///
-/// Extras are supposed to be ignored, which happens if you pass None for extras
-fn parse_scripts<R: Read + Seek>(
-    archive: &mut ZipArchive<R>,
-    dist_info_prefix: &str,
-    extras: Option<&[String]>,
-) -> Result<(Vec<Script>, Vec<Script>), Error> {
-    let entry_points_path = format!("{dist_info_prefix}.dist-info/entry_points.txt");
-    let entry_points_mapping = match archive.by_name(&entry_points_path) {
-        Ok(mut file) => {
-            let mut ini_text = String::new();
-            file.read_to_string(&mut ini_text)?;
-            Ini::new_cs().read(ini_text).map_err(|err| {
-                Error::InvalidWheel(format!("entry_points.txt is invalid: {}", err))
-            })?
-        }
-        Err(ZipError::FileNotFound) => return Ok((Vec::new(), Vec::new())),
-        Err(err) => return Err(Error::from_zip_error(entry_points_path, err)),
-    };
-
-    // TODO: handle extras
-    let console_scripts = match entry_points_mapping.get("console_scripts") {
-        Some(console_scripts) => {
-            read_scripts_from_section(console_scripts, "console_scripts", extras)?
-        }
-        None => Vec::new(),
-    };
-    let gui_scripts = match entry_points_mapping.get("gui_scripts") {
-        Some(gui_scripts) => read_scripts_from_section(gui_scripts, "gui_scripts", extras)?,
-        None => Vec::new(),
-    };
-
-    Ok((console_scripts, gui_scripts))
-}
-
-/// Shamelessly stolen (and updated for recent sha2)
-/// <https://github.com/richo/hashing-copy/blob/d8dd2fdb63c6faf198de0c9e5713d6249cbb5323/src/lib.rs#L10-L52>
-/// which in turn got it from std
-/// <https://doc.rust-lang.org/1.58.0/src/std/io/copy.rs.html#128-156>
-pub fn copy_and_hash(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<(u64, String)> {
-    // TODO: Do we need to support anything besides sha256?
-    let mut hasher = Sha256::new();
-    // Same buf size as std. Note that this number is important for performance
-    let mut buf = vec![0; 8 * 1024];
-
-    let mut written = 0;
-    loop {
-        let len = match reader.read(&mut buf) {
-            Ok(0) => break,
-            Ok(len) => len,
-            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-            Err(e) => return Err(e),
-        };
-        hasher.update(&buf[..len]);
-        writer.write_all(&buf[..len])?;
-        written += len as u64;
-    }
-    Ok((
-        written,
-        format!("sha256={}", BASE64URL_NOPAD.encode(&hasher.finalize())),
-    ))
-}
-
 /// Extract all files from the wheel into the site packages
 ///
 /// Matches with the RECORD entries
@@ -239,6 +67,8 @@ fn unpack_wheel_files<R: Read + Seek>(
     archive: &mut ZipArchive<R>,
     record: &[RecordEntry],
     check_hashes: bool,
+    name: &str,
+    reporter: &dyn ProgressReporter,
 ) -> Result<Vec<PathBuf>, Error> {
     let mut extracted_paths = Vec::new();
     // Cache the created parent dirs to avoid io calls
@@ -270,13 +100,14 @@ fn unpack_wheel_files<R: Read + Seek>(
             }
         }
         let mut outfile = BufWriter::new(File::create(&out_path)?);
-        let encoded_hash = if check_hashes {
-            let (_size, encoded_hash) = copy_and_hash(&mut file, &mut outfile)?;
-            Some(encoded_hash)
+        let (size, encoded_hash) = if check_hashes {
+            let (size, encoded_hash) = copy_and_hash(&mut file, &mut outfile)?;
+            (size, Some(encoded_hash))
         } else {
-            io::copy(&mut file, &mut outfile)?;
-            None
+            (io::copy(&mut file, &mut outfile)?, None)
         };
+        reporter.bytes_extracted(name, size);
+        reporter.file_written(name, &relative);
 
         extracted_paths.push(relative.clone());
 
@@ -335,37 +166,76 @@ fn unpack_wheel_files<R: Read + Seek>(
                     );
                     continue;
                 }
-                return Err(Error::RecordFile(format!(
-                    "Hash mismatch for {}. Recorded: {}, Actual: {}",
-                    relative.display(),
-                    recorded_hash,
-                    encoded_hash,
-                )));
+                return Err(Error::HashMismatch {
+                    path: relative.display().to_string(),
+                    recorded: recorded_hash.clone(),
+                    actual: encoded_hash,
+                });
             }
         }
     }
     Ok(extracted_paths)
 }
 
-fn get_shebang(location: &InstallLocation<LockedDir>) -> String {
-    if matches!(location, InstallLocation::Venv { .. }) {
-        let path = location.get_python().display().to_string();
-        let path = if cfg!(windows) {
-            // https://stackoverflow.com/a/50323079
-            const VERBATIM_PREFIX: &str = r"\\?\";
-            if let Some(stripped) = path.strip_prefix(VERBATIM_PREFIX) {
-                stripped.to_string()
-            } else {
-                path
+/// How the entrypoint launchers and the `#!python` scripts from `.data/scripts` find their python,
+/// i.e. what we write as their shebang. Monotrail installations always get [SHEBANG_PYTHON]
+/// since monotrail recognizes python scripts by it and launches them with its own python
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum LauncherPolicy {
+    /// [LauncherPolicy::AbsolutePython] for venvs, [LauncherPolicy::EnvPython] for monotrail
+    #[default]
+    Auto,
+    /// The absolute path of the venv python, like pip does, so scripts work without activating
+    /// the venv
+    AbsolutePython,
+    /// `#!/usr/bin/env python`, i.e. whatever python is first in PATH. This is how monotrail
+    /// launches scripts: It puts itself into PATH moonlighting as python
+    EnvPython,
+    /// Any first line, e.g. `#!/opt/python3.12/bin/python3 -I`. `#!` is added if missing
+    Custom(String),
+}
+
+impl LauncherPolicy {
+    /// The shebang line without the newline
+    pub fn shebang<T: Deref<Target = Path>>(
+        &self,
+        location: &InstallLocation<T>,
+    ) -> Result<String, Error> {
+        if let LauncherPolicy::Custom(shebang) = self {
+            if shebang.contains(['\n', '\r']) {
+                return Err(Error::InvalidShebang(shebang.clone()));
+            }
+        }
+        if matches!(location, InstallLocation::Monotrail { .. }) {
+            return Ok(SHEBANG_PYTHON.to_string());
+        }
+        let shebang = match self {
+            LauncherPolicy::Auto | LauncherPolicy::AbsolutePython => {
+                let path = location.get_python().display().to_string();
+                let path = if cfg!(windows) {
+                    // https://stackoverflow.com/a/50323079
+                    const VERBATIM_PREFIX: &str = r"\\?\";
+                    if let Some(stripped) = path.strip_prefix(VERBATIM_PREFIX) {
+                        stripped.to_string()
+                    } else {
+                        path
+                    }
+                } else {
+                    path
+                };
+                format!("#!{}", path)
+            }
+            // `python` alone doesn't work, we need env to find the python link in PATH
+            LauncherPolicy::EnvPython => SHEBANG_PYTHON.to_string(),
+            LauncherPolicy::Custom(shebang) => {
+                if shebang.starts_with("#!") {
+                    shebang.clone()
+                } else {
+                    format!("#!{}", shebang)
+                }
             }
-        } else {
-            path
         };
-        format!("#!{}", path)
-    } else {
-        // This will use the monotrail binary moonlighting as python. `python` alone doesn't,
-        // we need env to find the python link we put in PATH
-        SHEBANG_PYTHON.to_string()
+        Ok(shebang)
     }
 }
 
@@ -414,17 +284,19 @@ fn windows_script_launcher(launcher_python_script: &str) -> Result<Vec<u8>, Erro
 
 /// Create the wrapper scripts in the bin folder of the venv for launching console scripts
 ///
-/// We also pass venv_base so we can write the same path as pip does
+/// `bin_rel` is the scripts directory relative to site-packages, so we can write the same path as
+/// pip does
 ///
 /// TODO: Test for this launcher directly in install-wheel-rs
 fn write_script_entrypoints(
     site_packages: &Path,
-    location: &InstallLocation<LockedDir>,
+    bin_rel: &Path,
+    shebang: &str,
     entrypoints: &[Script],
     record: &mut Vec<RecordEntry>,
 ) -> Result<(), Error> {
     // for monotrail
-    fs::create_dir_all(site_packages.join(bin_rel()))?;
+    fs::create_dir_all(site_packages.join(bin_rel))?;
     for entrypoint in entrypoints {
         let entrypoint_relative = if cfg!(windows) {
             // On windows we actually build an .exe wrapper
@@ -435,15 +307,12 @@ fn write_script_entrypoints(
                 .unwrap_or(&entrypoint.script_name)
                 .to_string()
                 + ".exe";
-            bin_rel().join(script_name)
+            bin_rel.join(script_name)
         } else {
-            bin_rel().join(&entrypoint.script_name)
+            bin_rel.join(&entrypoint.script_name)
         };
-        let launcher_python_script = get_script_launcher(
-            &entrypoint.module,
-            &entrypoint.function,
-            &get_shebang(&location),
-        );
+        let launcher_python_script =
+            get_script_launcher(&entrypoint.module, &entrypoint.function, shebang);
         if cfg!(windows) {
             let launcher = windows_script_launcher(&launcher_python_script)?;
             write_file_recorded(site_packages, &entrypoint_relative, &launcher, record)?;
@@ -468,16 +337,6 @@ fn write_script_entrypoints(
     Ok(())
 }
 
-fn bin_rel() -> PathBuf {
-    if cfg!(windows) {
-        // windows doesn't have the python part, only Lib/site-packages
-        Path::new("..").join("..").join("Scripts")
-    } else {
-        // linux/mac has lib/python/site-packages
-        Path::new("..").join("..").join("..").join("bin")
-    }
-}
-
 /// Parse WHEEL file
 ///
 /// > {distribution}-{version}.dist-info/WHEEL is metadata about the archive itself in the same
@@ -527,11 +386,16 @@ fn parse_wheel_version(wheel_text: &str) -> Result<(), Error> {
 ///
 /// 2.f Compile any installed .py to .pyc. (Uninstallers should be smart enough to remove .pyc
 /// even if it is not mentioned in RECORD.)
+#[cfg_attr(
+    feature = "instrument",
+    tracing::instrument(level = "debug", skip_all, fields(name = name))
+)]
 fn bytecode_compile(
     site_packages: &Path,
     unpacked_paths: Vec<PathBuf>,
     python_version: (u8, u8),
     sys_executable: &Path,
+    source_date_epoch: Option<u64>,
     // Only for logging
     name: &str,
     record: &mut Vec<RecordEntry>,
@@ -548,8 +412,12 @@ fn bytecode_compile(
     // to cpython segmentation faults, so we add a simple retry loop
     let mut retries = 3;
     let (status, lines) = loop {
-        let (status, lines) =
-            bytecode_compile_inner(site_packages, &py_source_paths, &sys_executable)?;
+        let (status, lines) = bytecode_compile_inner(
+            site_packages,
+            &py_source_paths,
+            &sys_executable,
+            source_date_epoch,
+        )?;
         retries -= 1;
         if status.success() || retries == 0 {
             break (status, lines);
@@ -617,13 +485,20 @@ fn bytecode_compile_inner(
     site_packages: &Path,
     py_source_paths: &[PathBuf],
     sys_executable: &Path,
+    source_date_epoch: Option<u64>,
 ) -> Result<(ExitStatus, Vec<String>), Error> {
     let tempdir = tempdir()?;
     // Running python with an actual file will produce better error messages
     let pip_compileall_py = tempdir.path().join("pip_compileall.py");
     fs::write(&pip_compileall_py, include_str!("pip_compileall.py"))?;
     // We input the paths through stdin and get the successful paths returned through stdout
-    let mut bytecode_compiler = Command::new(sys_executable)
+    let mut command = Command::new(sys_executable);
+    // With SOURCE_DATE_EPOCH, py_compile defaults to hash based pycs instead of embedding the
+    // mtime of the source
+    if let Some(source_date_epoch) = source_date_epoch {
+        command.env("SOURCE_DATE_EPOCH", source_date_epoch.to_string());
+    }
+    let mut bytecode_compiler = command
         .arg(&pip_compileall_py)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
@@ -749,9 +624,10 @@ fn move_folder_recorded(
 /// Has to deal with both binaries files (just move) and scripts (rewrite the shebang if applicable)
 fn install_script(
     site_packages: &Path,
+    bin_rel: &Path,
     record: &mut [RecordEntry],
     file: DirEntry,
-    location: &InstallLocation<LockedDir>,
+    shebang: &str,
 ) -> Result<(), Error> {
     let path = file.path();
     if !path.is_file() {
@@ -761,7 +637,7 @@ fn install_script(
         )));
     }
 
-    let target_path = bin_rel().join(file.file_name());
+    let target_path = bin_rel.join(file.file_name());
     let mut script = File::open(&path)?;
     // https://sphinx-locales.github.io/peps/pep-0427/#recommended-installer-features
     // > In wheel, scripts are packaged in {distribution}-{version}.data/scripts/.
@@ -779,7 +655,7 @@ fn install_script(
     let mut start = vec![0; placeholder_python.len()];
     script.read_exact(&mut start)?;
     let size_and_encoded_hash = if start == placeholder_python {
-        let start = get_shebang(&location).as_bytes().to_vec();
+        let start = shebang.as_bytes().to_vec();
         let mut target = File::create(site_packages.join(&target_path))?;
         let size_and_encoded_hash = copy_and_hash(&mut start.chain(script), &mut target)?;
         fs::remove_file(&path)?;
@@ -826,11 +702,13 @@ fn install_script(
 /// Move the files from the .data directory to the right location in the venv
 #[allow(clippy::too_many_arguments)]
 fn install_data(
-    venv_base: &Path,
+    base_location: &Path,
     site_packages: &Path,
     data_dir: &Path,
     dist_name: &str,
-    location: &InstallLocation<LockedDir>,
+    scheme: &SchemePaths,
+    bin_rel: &Path,
+    shebang: &str,
     console_scripts: &[Script],
     gui_scripts: &[Script],
     record: &mut [RecordEntry],
@@ -840,7 +718,8 @@ fn install_data(
         match data_entry.file_name().as_os_str().to_str() {
             Some("data") => {
                 // Move the content of the folder to the root of the venv
-                move_folder_recorded(&data_entry.path(), venv_base, site_packages, record)?;
+                let target_path = base_location.join(&scheme.data);
+                move_folder_recorded(&data_entry.path(), &target_path, site_packages, record)?;
             }
             Some("scripts") => {
                 for file in fs::read_dir(data_entry.path())? {
@@ -862,26 +741,23 @@ fn install_data(
                         continue;
                     }
 
-                    install_script(site_packages, record, file, &location)?;
+                    install_script(site_packages, bin_rel, record, file, shebang)?;
                 }
             }
             Some("headers") => {
-                let target_path = venv_base
-                    .join("include")
-                    .join("site")
-                    // TODO: Also use just python here in monotrail
-                    .join(format!(
-                        "python{}.{}",
-                        location.get_python_version().0,
-                        location.get_python_version().1
-                    ))
-                    .join(dist_name);
+                let target_path = base_location.join(&scheme.include).join(dist_name);
                 move_folder_recorded(&data_entry.path(), &target_path, site_packages, record)?;
             }
-            Some("purelib" | "platlib") => {
-                // purelib and platlib locations are not relevant when using venvs
+            Some(lib @ ("purelib" | "platlib")) => {
+                // Those are the same in venvs, but e.g. debian splits them
                 // https://stackoverflow.com/a/27882460/3549270
-                move_folder_recorded(&data_entry.path(), site_packages, site_packages, record)?;
+                let lib_dir = if lib == "purelib" {
+                    &scheme.purelib
+                } else {
+                    &scheme.platlib
+                };
+                let target_path = base_location.join(lib_dir);
+                move_folder_recorded(&data_entry.path(), &target_path, site_packages, record)?;
             }
             _ => {
                 return Err(Error::InvalidWheel(format!(
@@ -905,11 +781,9 @@ fn write_file_recorded(
     record: &mut Vec<RecordEntry>,
 ) -> Result<(), Error> {
     File::create(site_packages.join(relative_path))?.write_all(content.as_ref())?;
-    let hash = Sha256::new().chain_update(content.as_ref()).finalize();
-    let encoded_hash = format!("sha256={}", BASE64URL_NOPAD.encode(&hash));
     record.push(RecordEntry {
         path: relative_path.display().to_string(),
-        hash: Some(encoded_hash),
+        hash: Some(record_hash(content.as_ref())),
         size: Some(content.as_ref().len()),
     });
     Ok(())
@@ -920,6 +794,7 @@ fn extra_dist_info(
     site_packages: &Path,
     dist_info_prefix: &str,
     requested: bool,
+    direct_url: Option<&DirectUrl>,
     record: &mut Vec<RecordEntry>,
 ) -> Result<(), Error> {
     write_file_recorded(
@@ -937,79 +812,36 @@ fn extra_dist_info(
         )?;
     }
 
-    // https://github.com/python-poetry/poetry/issues/6356
-    /*
-    let wheel_path_url = format!("file://{}", wheel_path.canonicalize()?.display());
-    let direct_url = DirectUrl {
-        archive_info: HashMap::new(),
-        url: wheel_path_url,
-    };
-
-    // Map explicitly because we special cased that error
-    let direct_url_json =
-        serde_json::to_string(&direct_url).map_err(WheelInstallerError::DirectUrlSerdeJsonError)?;
-    write_file_recorded(
-        site_packages,
-        &dist_info.join("direct_url.json"),
-        &direct_url_json,
-        record,
-    )?;
-    */
+    // Only for packages not from an index, poetry considers everything with a direct_url.json
+    // a url dependency: https://github.com/python-poetry/poetry/issues/6356
+    if let Some(direct_url) = direct_url {
+        // Map explicitly because we special cased that error
+        let direct_url_json =
+            serde_json::to_string(direct_url).map_err(Error::DirectUrlSerdeJson)?;
+        write_file_recorded(
+            site_packages,
+            &PathBuf::from(format!("{dist_info_prefix}.dist-info")).join("direct_url.json"),
+            &direct_url_json,
+            record,
+        )?;
+    }
     Ok(())
 }
 
-/// Reads the record file
-/// <https://www.python.org/dev/peps/pep-0376/#record>
-pub fn read_record_file(record: &mut impl Read) -> Result<Vec<RecordEntry>, Error> {
-    csv::ReaderBuilder::new()
-        .has_headers(false)
-        .escape(Some(b'"'))
-        .from_reader(record)
-        .deserialize()
-        .map(|entry| {
-            let entry: RecordEntry = entry?;
-            Ok(RecordEntry {
-                // selenium uses absolute paths for some reason
-                path: entry.path.trim_start_matches('/').to_string(),
-                ..entry
-            })
-        })
-        .collect()
-}
-
-/// Parse a file with `Key: value` entries such as WHEEL and METADATA
-pub fn parse_key_value_file(
-    file: &mut impl Read,
-    debug_filename: &str,
-) -> Result<HashMap<String, Vec<String>>, Error> {
-    let mut data = HashMap::new();
-
-    let file = BufReader::new(file);
-    for (line_no, line) in file.lines().enumerate() {
-        let line = line?.trim().to_string();
-        if line.is_empty() {
-            continue;
-        }
-        let (key, value) = line.split_once(": ").ok_or_else(|| {
-            Error::InvalidWheel(format!(
-                "Line {} of the {} file is invalid",
-                line_no, debug_filename
-            ))
-        })?;
-        data.entry(key.to_string())
-            .or_insert_with(Vec::new)
-            .push(value.to_string())
-    }
-    Ok(data)
+/// Writes the record file, see [write_record]
+pub fn write_record_file(path: &Path, record: Vec<RecordEntry>) -> Result<(), Error> {
+    write_record(File::create(path)?, record)
 }
 
 /// Install the given wheel to the given venv
 ///
 /// The caller must ensure that the wheel is compatible to the environment.
 ///
-/// <https://packaging.python.org/en/latest/specifications/binary-distribution-format/#installing-a-wheel-distribution-1-0-py32-none-any-whl>
-///
-/// Wheel 1.0: <https://www.python.org/dev/peps/pep-0427/>
+/// Replaces an installation of the same version, like [Installer::force]
+#[deprecated(
+    since = "0.0.1",
+    note = "use `Installer`, e.g. `Installer::new(location, sys_executable).compile(true).install(reader, filename)`"
+)]
 #[allow(clippy::too_many_arguments)]
 pub fn install_wheel(
     location: &InstallLocation<LockedDir>,
@@ -1017,17 +849,161 @@ pub fn install_wheel(
     filename: WheelFilename,
     compile: bool,
     check_hashes: bool,
-    // initially used to the console scripts, currently unused. Keeping it because we likely need
-    // it for validation later
     _extras: &[String],
     unique_version: &str,
     sys_executable: impl AsRef<Path>,
+    launcher_policy: &LauncherPolicy,
+    direct_url: Option<&DirectUrl>,
+    source_date_epoch: Option<u64>,
+    reporter: &dyn ProgressReporter,
+) -> Result<String, Error> {
+    Installer::new(location, sys_executable)
+        .compile(compile)
+        .check_hashes(check_hashes)
+        .unique_version(unique_version)
+        .launcher_policy(launcher_policy.clone())
+        .direct_url(direct_url.cloned())
+        .source_date_epoch(source_date_epoch)
+        .force(true)
+        .reporter(reporter)
+        .install(reader, filename)
+}
+
+/// Whether this version of the wheel's distribution is already installed: The
+/// `<name>-<version>.dist-info` in the venv or the tag directory for monotrail
+fn is_installed(
+    location: &InstallLocation<LockedDir>,
+    scheme: &SchemePaths,
+    filename: &WheelFilename,
+    unique_version: &str,
+) -> Result<bool, Error> {
+    match location {
+        InstallLocation::Venv { venv_base, .. } => {
+            for site_packages in [&scheme.purelib, &scheme.platlib] {
+                let installed = installed_dist_infos(&venv_base.join(site_packages))?;
+                if installed.iter().any(|(name, version, _)| {
+                    *name == normalize_name(&filename.distribution) && *version == filename.version
+                }) {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        }
+        InstallLocation::Monotrail { monotrail_root, .. } => Ok(monotrail_root
+            .join(normalize_name(&filename.distribution))
+            .join(unique_version)
+            .join(filename.get_tag())
+            .is_dir()),
+    }
+}
+
+/// Reads everything we need from the wheel, but doesn't write. The disk space is checked for
+/// all wheels together with [check_disk_space_for]
+fn dry_run(reader: impl Read + Seek, filename: &WheelFilename) -> Result<(), Error> {
+    let mut archive =
+        ZipArchive::new(reader).map_err(|err| Error::from_zip_error("(index)".to_string(), err))?;
+    let dist_info_prefix = find_dist_info(filename, &mut archive)?;
+    read_metadata(&dist_info_prefix, &mut archive)?;
+    let record_path = format!("{dist_info_prefix}.dist-info/RECORD");
+    read_record_file(
+        &mut archive
+            .by_name(&record_path)
+            .map_err(|err| Error::from_zip_error(record_path.clone(), err))?,
+    )?;
+    let wheel_file_path = format!("{dist_info_prefix}.dist-info/WHEEL");
+    let mut wheel_text = String::new();
+    archive
+        .by_name(&wheel_file_path)
+        .map_err(|err| Error::from_zip_error(wheel_file_path, err))?
+        .read_to_string(&mut wheel_text)?;
+    parse_wheel_version(&wheel_text)?;
+    Ok(())
+}
+
+/// <https://packaging.python.org/en/latest/specifications/binary-distribution-format/#installing-a-wheel-distribution-1-0-py32-none-any-whl>
+///
+/// Wheel 1.0: <https://www.python.org/dev/peps/pep-0427/>
+///
+/// With `source_date_epoch` or the `SOURCE_DATE_EPOCH` environment variable, all installed files
+/// get that modification time and the pyc files are hash based instead of embedding the mtime of
+/// the source, so installing the same wheels gives bit-identical trees
+pub(crate) fn install_wheel_with(
+    installer: &Installer,
+    reader: impl Read + Seek,
+    filename: WheelFilename,
 ) -> Result<String, Error> {
+    let Installer {
+        location,
+        sys_executable,
+        compile,
+        check_hashes,
+        launcher_policy,
+        unique_version,
+        direct_url,
+        source_date_epoch,
+        force,
+        dry_run: only_check,
+        reporter,
+    } = installer;
     let name = &filename.distribution;
     let _my_span = span!(Level::DEBUG, "install_wheel", name = name.as_str());
+    reporter.wheel_started(name);
+    let mut install_start = Instant::now();
+    let mut phase_start = Instant::now();
+    let source_date_epoch = source_date_epoch.or_else(read_source_date_epoch);
+    let reporter = *reporter;
+
+    // Monotrail installation is for multiple python versions (depending on the wheel tag), so it
+    // uses lib/python/site-packages. Potentially needs to be changed to creating pythonx.y
+    // symlinks for each python version we use it with (on install in that python version)
+    let scheme = location.scheme_paths();
+    if let [absolute, ..] = scheme.absolute_paths().as_slice() {
+        return Err(Error::InvalidScheme(format!(
+            "{} must be relative to the venv",
+            absolute.display()
+        )));
+    }
 
-    let (temp_dir_final_location, base_location) = match location {
-        InstallLocation::Venv { venv_base, .. } => (None, venv_base.to_path_buf()),
+    if is_installed(location, &scheme, &filename, unique_version)? {
+        if !force {
+            debug!(
+                name = name.as_str(),
+                "{} is already installed", filename.version
+            );
+            reporter.wheel_finished(name);
+            return Ok(filename.get_tag());
+        }
+        if let InstallLocation::Monotrail { monotrail_root, .. } = location {
+            if !only_check {
+                fs::remove_dir_all(
+                    monotrail_root
+                        .join(normalize_name(name))
+                        .join(unique_version)
+                        .join(filename.get_tag()),
+                )?;
+            }
+        }
+    }
+    if *only_check {
+        dry_run(reader, &filename)?;
+        reporter.wheel_finished(name);
+        return Ok(filename.get_tag());
+    }
+
+    let (staging, base_location) = match location {
+        InstallLocation::Venv { venv_base, .. } => {
+            // Unpack into a temp dir in the venv and only move the files into place once
+            // everything succeeded, so a failed install doesn't leave a half-written venv
+            let temp_dir = TempDir::new_in(&**venv_base)?;
+            let base_location = temp_dir.path().to_path_buf();
+            (
+                Staging::Venv {
+                    temp_dir,
+                    venv_base: venv_base.to_path_buf(),
+                },
+                base_location,
+            )
+        }
         InstallLocation::Monotrail { monotrail_root, .. } => {
             let name_version_dir = monotrail_root
                 .join(normalize_name(name))
@@ -1040,44 +1016,106 @@ pub fn install_wheel(
             if cfg!(not(windows)) {
                 let temp_dir = TempDir::new_in(&name_version_dir)?;
                 let base_location = temp_dir.path().to_path_buf();
-                (Some((temp_dir, final_location)), base_location)
+                (
+                    Staging::Monotrail {
+                        temp_dir,
+                        final_location,
+                    },
+                    base_location,
+                )
             } else {
                 fs::create_dir(&final_location)?;
-                (None, final_location)
+                (Staging::None, final_location)
             }
         }
     };
 
-    let site_packages_python = match location {
-        InstallLocation::Venv { .. } => {
-            format!(
-                "python{}.{}",
-                location.get_python_version().0,
-                location.get_python_version().1
-            )
-        }
-        // Monotrail installation is for multiple python versions (depending on the wheel tag)
-        // Potentially needs to be changed to creating pythonx.y symlinks for each python version
-        // we use it with (on install in that python version)
-        InstallLocation::Monotrail { .. } => "python".to_string(),
-    };
-    let site_packages = if cfg!(target_os = "windows") {
-        base_location.join("Lib").join("site-packages")
-    } else {
-        base_location
-            .join("lib")
-            .join(site_packages_python)
-            .join("site-packages")
-    };
-
     debug!(name = name.as_str(), "Opening zip");
     // No BufReader: https://github.com/zip-rs/zip/issues/381
     let mut archive =
         ZipArchive::new(reader).map_err(|err| Error::from_zip_error("(index)".to_string(), err))?;
+    // Only for the error message, the space was checked for all wheels before installing
+    let needed = unpacked_size(&mut archive)?;
+
+    let record_len = match install_staged(
+        &mut archive,
+        &filename,
+        location,
+        &base_location,
+        &scheme,
+        *compile,
+        *check_hashes,
+        sys_executable,
+        launcher_policy,
+        direct_url.as_ref(),
+        source_date_epoch,
+        reporter,
+        &mut phase_start,
+    ) {
+        Ok(record_len) => record_len,
+        Err(err) => {
+            // The staging temp dirs are removed on drop, only the unstaged install needs cleanup
+            if let Staging::None = staging {
+                if let Err(err) = fs::remove_dir_all(&base_location) {
+                    warn!(
+                        "Failed to remove partial installation {}: {}",
+                        base_location.display(),
+                        err
+                    );
+                }
+            }
+            return Err(out_of_space(err, &base_location, needed));
+        }
+    };
+
+    match staging {
+        Staging::Venv {
+            temp_dir,
+            venv_base,
+        } => {
+            debug!(name = name.as_str(), "Moving staged files into the venv");
+            commit_staged(temp_dir.path(), &venv_base)
+                .map_err(|err| out_of_space(err, &venv_base, needed))?;
+        }
+        // rename for atomicity
+        // well, except for windows, see comment above
+        Staging::Monotrail {
+            temp_dir: _temp_dir,
+            final_location,
+        } => {
+            fs::rename(base_location, final_location)?;
+        }
+        Staging::None => {}
+    }
+    phase_finished(name, "commit", record_len, &mut phase_start);
+    phase_finished(name, "total", record_len, &mut install_start);
+    reporter.wheel_finished(&filename.distribution);
 
+    Ok(filename.get_tag())
+}
+
+/// Unpacks the wheel into `base_location` and writes entrypoints, data, bytecode and RECORD,
+/// returning the number of RECORD entries
+#[allow(clippy::too_many_arguments)]
+fn install_staged<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    filename: &WheelFilename,
+    location: &InstallLocation<LockedDir>,
+    base_location: &Path,
+    scheme: &SchemePaths,
+    compile: bool,
+    check_hashes: bool,
+    sys_executable: &Path,
+    launcher_policy: &LauncherPolicy,
+    direct_url: Option<&DirectUrl>,
+    source_date_epoch: Option<u64>,
+    reporter: &dyn ProgressReporter,
+    phase_start: &mut Instant,
+) -> Result<usize, Error> {
+    let name = &filename.distribution;
     debug!(name = name.as_str(), "Getting wheel metadata");
-    let dist_info_prefix = find_dist_info(&filename, &mut archive)?;
-    let (name, _version) = read_metadata(&dist_info_prefix, &mut archive)?;
+    let dist_info_prefix = find_dist_info(&filename, archive)?;
+    let (name, _version) = read_metadata(&dist_info_prefix, archive)?;
     // TODO: Check that name and version match
 
     let record_path = format!("{dist_info_prefix}.dist-info/RECORD");
@@ -1100,25 +1138,60 @@ pub fn install_wheel(
     parse_wheel_version(&wheel_text)?;
     // > 1.c If Root-Is-Purelib == ‘true’, unpack archive into purelib (site-packages).
     // > 1.d Else unpack archive into platlib (site-packages).
-    // We always install in the same virtualenv site packages
+    // Those are the same in venvs and for monotrail
+    let root_is_purelib = parse_key_value_file(&mut wheel_text.as_bytes(), "WHEEL")?
+        .get("Root-Is-Purelib")
+        .is_some_and(|values| {
+            values
+                .iter()
+                .any(|value| value.eq_ignore_ascii_case("true"))
+        });
+    let site_packages = if root_is_purelib {
+        base_location.join(&scheme.purelib)
+    } else {
+        base_location.join(&scheme.platlib)
+    };
+    let bin_rel = relative_to(&base_location.join(&scheme.scripts), &site_packages)?;
     debug!(name = name.as_str(), "Extracting file");
     let unpacked_paths = unpack_wheel_files(
         &site_packages,
         &record_path,
-        &mut archive,
+        archive,
         &record,
         check_hashes,
+        &filename.distribution,
+        reporter,
     )?;
     debug!(
         name = name.as_str(),
         "Extracted {} files",
         unpacked_paths.len()
     );
+    phase_finished(&name, "unpack", unpacked_paths.len(), phase_start);
 
     debug!(name = name.as_str(), "Writing entrypoints");
-    let (console_scripts, gui_scripts) = parse_scripts(&mut archive, &dist_info_prefix, None)?;
-    write_script_entrypoints(&site_packages, &location, &console_scripts, &mut record)?;
-    write_script_entrypoints(&site_packages, &location, &gui_scripts, &mut record)?;
+    let (console_scripts, gui_scripts) = parse_scripts(archive, &dist_info_prefix, None)?;
+    let shebang = launcher_policy.shebang(location)?;
+    write_script_entrypoints(
+        &site_packages,
+        &bin_rel,
+        &shebang,
+        &console_scripts,
+        &mut record,
+    )?;
+    write_script_entrypoints(
+        &site_packages,
+        &bin_rel,
+        &shebang,
+        &gui_scripts,
+        &mut record,
+    )?;
+    phase_finished(
+        &name,
+        "entrypoints",
+        console_scripts.len() + gui_scripts.len(),
+        phase_start,
+    );
 
     let data_dir = site_packages.join(format!("{dist_info_prefix}.data"));
     // 2.a Unpacked archive includes distribution-1.0.dist-info/ and (if there is data) distribution-1.0.data/.
@@ -1130,17 +1203,20 @@ pub fn install_wheel(
             &site_packages,
             &data_dir,
             &name,
-            &location,
+            scheme,
+            &bin_rel,
+            // For the monotrail install, we want to keep the fake shebang for our own
+            // later replacement logic
+            &shebang,
             &console_scripts,
             &gui_scripts,
             &mut record,
-            // For the monotrail install, we want to keep the fake shebang for our own
-            // later replacement logic
         )?;
         // 2.c If applicable, update scripts starting with #!python to point to the correct interpreter.
         // Script are unsupported through data
         // 2.e Remove empty distribution-1.0.data directory.
         fs::remove_dir_all(data_dir)?;
+        phase_finished(&name, "data", record.len(), phase_start);
     } else {
         debug!(name = name.as_str(), "No data");
     }
@@ -1152,68 +1228,257 @@ pub fn install_wheel(
             &site_packages,
             unpacked_paths,
             location.get_python_version(),
-            sys_executable.as_ref(),
+            sys_executable,
+            source_date_epoch,
             name.as_str(),
             &mut record,
         )?;
+        phase_finished(&name, "compile", record.len(), phase_start);
     }
 
     debug!(name = name.as_str(), "Writing extra metadata");
 
-    extra_dist_info(&site_packages, &dist_info_prefix, true, &mut record)?;
+    extra_dist_info(
+        &site_packages,
+        &dist_info_prefix,
+        true,
+        direct_url,
+        &mut record,
+    )?;
 
     debug!(name = name.as_str(), "Writing record");
-    let mut record_writer = csv::WriterBuilder::new()
-        .has_headers(false)
-        .escape(b'"')
-        .from_path(site_packages.join(record_path))?;
-    record.sort();
-    for entry in record {
-        record_writer.serialize(entry)?;
-    }
+    let record_len = record.len();
+    write_record_file(&site_packages.join(record_path), record)?;
+    phase_finished(&name, "record", record_len, phase_start);
 
-    // rename for atomicity
-    // well, except for windows, see comment above
-    if let Some((_temp_dir, final_location)) = temp_dir_final_location {
-        fs::rename(base_location, final_location)?;
+    if let Some(source_date_epoch) = source_date_epoch {
+        set_mtimes(base_location, source_date_epoch)?;
     }
 
-    Ok(filename.get_tag())
+    Ok(record_len)
 }
 
-/// From https://github.com/PyO3/python-pkginfo-rs
+/// `SOURCE_DATE_EPOCH` as used by reproducible builds, ignored with a warning if it isn't a
+/// unix timestamp
 ///
-/// The metadata name may be uppercase, while the wheel and dist info names are lowercase, or
-/// the metadata name and the dist info name are lowercase, while the wheel name is uppercase.
-/// Either way, we just search the wheel for the name
-fn find_dist_info(
-    filename: &WheelFilename,
-    archive: &mut ZipArchive<impl Read + Seek + Sized>,
-) -> Result<String, Error> {
-    let dist_info_matcher =
-        format!("{}-{}", filename.distribution, filename.version).to_lowercase();
-    let dist_infos: Vec<_> = archive
-        .file_names()
-        .filter_map(|name| name.split_once('/'))
-        .filter_map(|(dir, file)| Some((dir.strip_suffix(".dist-info")?, file)))
-        .filter(|(dir, file)| dir.to_lowercase() == dist_info_matcher && *file == "METADATA")
-        .map(|(dir, _file)| dir)
-        .collect();
-    let dist_info = match dist_infos.as_slice() {
-        [] => {
-            return Err(Error::InvalidWheel(
-                "Missing .dist-info directory".to_string(),
-            ))
-        }
-        [dist_info] => dist_info.to_string(),
-        _ => {
-            return Err(Error::InvalidWheel(format!(
-                "Multiple .dist-info directories: {}",
-                dist_infos.join(", ")
-            )));
+/// <https://reproducible-builds.org/specs/source-date-epoch/>
+pub fn read_source_date_epoch() -> Option<u64> {
+    let value = env::var("SOURCE_DATE_EPOCH").ok()?;
+    match value.trim().parse() {
+        Ok(source_date_epoch) => Some(source_date_epoch),
+        Err(_) => {
+            warn!("Ignoring invalid SOURCE_DATE_EPOCH {:?}", value);
+            None
         }
+    }
+}
+
+/// Sets the modification time of everything in the staging directory before we move it into
+/// place
+fn set_mtimes(base_location: &Path, source_date_epoch: u64) -> Result<(), Error> {
+    let mtime = FileTime::from_unix_time(source_date_epoch as i64, 0);
+    for entry in WalkDir::new(base_location) {
+        let entry = entry?;
+        filetime::set_file_times(entry.path(), mtime, mtime)?;
+    }
+    Ok(())
+}
+
+/// The size of all files in the wheel once unpacked
+fn unpacked_size<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<u64, Error> {
+    let mut size = 0;
+    for index in 0..archive.len() {
+        size += archive
+            .by_index_raw(index)
+            .map_err(|err| Error::from_zip_error("(index)".to_string(), err))?
+            .size();
+    }
+    Ok(size)
+}
+
+/// Fails before we install anything if the location has less free space than all `wheels` need
+/// once unpacked, so we don't run out of space halfway through the installation
+pub fn check_disk_space_for<T: Deref<Target = Path>>(
+    location: &InstallLocation<T>,
+    wheels: impl IntoIterator<Item = impl AsRef<Path>>,
+) -> Result<(), Error> {
+    let mut needed = 0;
+    for wheel in wheels {
+        let wheel = wheel.as_ref();
+        let mut archive = ZipArchive::new(File::open(wheel)?)
+            .map_err(|err| Error::from_zip_error(wheel.display().to_string(), err))?;
+        needed += unpacked_size(&mut archive)?;
+    }
+    let target = match location {
+        InstallLocation::Venv { venv_base, .. } => venv_base,
+        InstallLocation::Monotrail { monotrail_root, .. } => monotrail_root,
     };
-    Ok(dist_info)
+    check_disk_space(target, needed)
+}
+
+/// Fails if `dir` has less than `needed` bytes left. If we can't determine the free space we try
+/// to install anyway
+fn check_disk_space(dir: &Path, needed: u64) -> Result<(), Error> {
+    match fs2::available_space(dir) {
+        Ok(available) if available < needed => Err(Error::InsufficientSpace {
+            path: dir.to_path_buf(),
+            needed,
+            available,
+        }),
+        Ok(_) => Ok(()),
+        Err(err) => {
+            debug!(
+                "Failed to determine the free disk space in {}: {}",
+                dir.display(),
+                err
+            );
+            Ok(())
+        }
+    }
+}
+
+/// If the disk or the quota ran full during the installation, report how much space we need
+/// instead of a bare io error. The partial installation has already been removed at this point
+fn out_of_space(err: Error, dir: &Path, needed: u64) -> Error {
+    fn is_out_of_space(err: &Error) -> bool {
+        match err {
+            Error::IO(err) => matches!(
+                err.kind(),
+                io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded
+            ),
+            Error::RolledBack(err) => is_out_of_space(err),
+            _ => false,
+        }
+    }
+
+    if !is_out_of_space(&err) {
+        return err;
+    }
+    Error::InsufficientSpace {
+        path: dir.to_path_buf(),
+        needed,
+        available: fs2::available_space(dir).unwrap_or_default(),
+    }
+}
+
+/// With the `instrument` feature, emits a structured event with the duration of the install phase
+/// that began at `start`, then resets `start` for the next phase
+#[cfg_attr(not(feature = "instrument"), allow(unused_variables))]
+fn phase_finished(name: &str, phase: &str, files: usize, start: &mut Instant) {
+    #[cfg(feature = "instrument")]
+    debug!(
+        wheel = name,
+        phase,
+        files,
+        duration_ms = start.elapsed().as_millis() as u64,
+        "Finished phase"
+    );
+    *start = Instant::now();
+}
+
+/// Where we unpack the wheel to before it ends up in its final location
+enum Staging {
+    /// A temp dir inside the venv, the files are moved into the venv one by one
+    Venv {
+        temp_dir: TempDir,
+        venv_base: PathBuf,
+    },
+    /// A temp dir next to the final location, which is renamed as a whole
+    Monotrail {
+        temp_dir: TempDir,
+        final_location: PathBuf,
+    },
+    /// We unpack directly into the final location (monotrail on windows)
+    None,
+}
+
+/// The changes we made to the venv while moving the staged files, so we can undo them
+#[derive(Default)]
+struct Placed {
+    /// Files we moved into the venv
+    files: Vec<PathBuf>,
+    /// Directories that didn't exist before
+    dirs: Vec<PathBuf>,
+    /// Files we replaced, as (original location, backup location)
+    replaced: Vec<(PathBuf, PathBuf)>,
+}
+
+/// Moves all files from the staging dir into the venv, rolling back the already placed files if
+/// any move fails
+#[cfg_attr(feature = "instrument", tracing::instrument(level = "debug", skip_all))]
+fn commit_staged(staging: &Path, venv_base: &Path) -> Result<(), Error> {
+    // Replaced files are kept until we're done so we can restore them
+    let backup_dir = TempDir::new_in(venv_base)?;
+    let mut placed = Placed::default();
+    if let Err(err) = move_staged(staging, venv_base, backup_dir.path(), &mut placed) {
+        warn!(
+            "Failed to move the staged files into place, rolling back {} files",
+            placed.files.len()
+        );
+        rollback(placed);
+        return Err(Error::RolledBack(Box::new(err)));
+    }
+    Ok(())
+}
+
+/// Moves each staged file to the same relative location in the venv, recording every change in
+/// `placed`
+fn move_staged(
+    staging: &Path,
+    venv_base: &Path,
+    backup_dir: &Path,
+    placed: &mut Placed,
+) -> Result<(), Error> {
+    // Collect first, we don't want to modify the directories we're iterating over
+    let entries = WalkDir::new(staging)
+        .min_depth(1)
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+    for entry in entries {
+        let relative = entry
+            .path()
+            .strip_prefix(staging)
+            .expect("walkdir starts with root");
+        let target = venv_base.join(relative);
+        if entry.file_type().is_dir() {
+            // Another install into the same venv may create the directory concurrently, and it's
+            // not ours to roll back then
+            match fs::create_dir(&target) {
+                Ok(()) => placed.dirs.push(target),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists && target.is_dir() => {}
+                Err(err) => return Err(err.into()),
+            }
+            continue;
+        }
+        if target.symlink_metadata().is_ok() {
+            let backup = backup_dir.join(placed.replaced.len().to_string());
+            fs::rename(&target, &backup)?;
+            placed.replaced.push((target.clone(), backup));
+        }
+        fs::rename(entry.path(), &target)?;
+        placed.files.push(target);
+    }
+    Ok(())
+}
+
+/// Best effort undo of [move_staged]: Removes the placed files, restores the replaced ones and
+/// removes the directories we created
+fn rollback(placed: Placed) {
+    for file in placed.files.iter().rev() {
+        if let Err(err) = fs::remove_file(file) {
+            error!("Failed to roll back {}: {}", file.display(), err);
+        }
+    }
+    for (original, backup) in placed.replaced.iter().rev() {
+        if let Err(err) = fs::rename(backup, original) {
+            error!("Failed to restore {}: {}", original.display(), err);
+        }
+    }
+    for dir in placed.dirs.iter().rev() {
+        if let Err(err) = fs::remove_dir(dir) {
+            error!("Failed to roll back {}: {}", dir.display(), err);
+        }
+    }
 }
 
 /// Adapted from https://github.com/PyO3/python-pkginfo-rs
@@ -1267,26 +1532,63 @@ fn read_metadata(
 #[cfg(test)]
 mod test {
     use super::parse_wheel_version;
-    use crate::wheel::{read_record_file, relative_to};
-    use crate::{install_wheel, parse_key_value_file, InstallLocation, Script, WheelFilename};
+    use crate::wheel::{
+        check_disk_space, check_disk_space_for, move_staged, out_of_space, read_record_file,
+        relative_to, rollback, Placed,
+    };
+    use crate::{
+        DirectUrl, Error, InstallLocation, Installer, LauncherPolicy, LockedDir, SchemePaths,
+        WheelFilename, SHEBANG_PYTHON,
+    };
     use fs_err as fs;
     use indoc::{formatdoc, indoc};
     use std::fs::File;
+    use std::io;
+    use std::io::{Cursor, Write};
     use std::path::{Path, PathBuf};
+    use std::process::Command;
     use std::str::FromStr;
+    use std::time::{Duration, UNIX_EPOCH};
     use tempfile::TempDir;
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
 
     #[test]
-    fn test_parse_key_value_file() {
-        let text = indoc! {"
-            Wheel-Version: 1.0
-            Generator: bdist_wheel (0.37.1)
-            Root-Is-Purelib: false
-            Tag: cp38-cp38-manylinux_2_17_x86_64
-            Tag: cp38-cp38-manylinux2014_x86_64
-        "};
-
-        parse_key_value_file(&mut text.as_bytes(), "WHEEL").unwrap();
+    fn test_launcher_policy() {
+        let venv = InstallLocation::venv(PathBuf::from("/home/ferris/project/.venv"), (3, 8));
+        let monotrail = InstallLocation::Monotrail {
+            monotrail_root: PathBuf::from("/home/ferris/.cache/monotrail"),
+            python: PathBuf::from("/usr/bin/python3.8"),
+            python_version: (3, 8),
+        };
+        let venv_python = if cfg!(windows) {
+            r"#!/home/ferris/project/.venv\Scripts\python.exe"
+        } else {
+            "#!/home/ferris/project/.venv/bin/python"
+        };
+        assert_eq!(LauncherPolicy::Auto.shebang(&venv).unwrap(), venv_python);
+        assert_eq!(
+            LauncherPolicy::Auto.shebang(&monotrail).unwrap(),
+            "#!/usr/bin/env python"
+        );
+        assert_eq!(
+            LauncherPolicy::EnvPython.shebang(&venv).unwrap(),
+            "#!/usr/bin/env python"
+        );
+        let custom = LauncherPolicy::Custom("/opt/python3.8/bin/python3 -I".to_string());
+        assert_eq!(
+            custom.shebang(&venv).unwrap(),
+            "#!/opt/python3.8/bin/python3 -I"
+        );
+        // Monotrail finds the python scripts by their placeholder shebang
+        for policy in [LauncherPolicy::AbsolutePython, custom] {
+            assert_eq!(policy.shebang(&monotrail).unwrap(), SHEBANG_PYTHON);
+        }
+        let injected = LauncherPolicy::Custom("/usr/bin/python3\nimport os".to_string());
+        assert!(matches!(
+            injected.shebang(&venv),
+            Err(Error::InvalidShebang(_))
+        ));
     }
 
     #[test]
@@ -1351,17 +1653,14 @@ mod test {
         }
         .acquire_lock()
         .unwrap();
-        install_wheel(
-            &install_location,
-            File::open(wheel).unwrap(),
-            WheelFilename::from_str(&filename).unwrap(),
-            true,
-            true,
-            &[],
-            "0.9.9",
-            &python,
-        )
-        .unwrap();
+        Installer::new(&install_location, &python)
+            .compile(true)
+            .unique_version("0.9.9")
+            .install(
+                File::open(wheel).unwrap(),
+                WheelFilename::from_str(&filename).unwrap(),
+            )
+            .unwrap();
 
         let base = temp_dir
             .path()
@@ -1383,6 +1682,343 @@ mod test {
         }
     }
 
+    /// A platlib wheel with headers, a script and a purelib file
+    fn greenlet_wheel() -> Vec<u8> {
+        let mut wheel = Vec::new();
+        let mut archive = ZipWriter::new(Cursor::new(&mut wheel));
+        for (path, content) in [
+            ("greenlet/__init__.py", ""),
+            (
+                "greenlet-3.0.0.data/headers/greenlet.h",
+                "#define GREENLET 1\n",
+            ),
+            ("greenlet-3.0.0.data/purelib/greenlet_pure.py", ""),
+            ("greenlet-3.0.0.data/scripts/greenlet-tool", "#!python\n"),
+            (
+                "greenlet-3.0.0.dist-info/METADATA",
+                "Metadata-Version: 2.1\nName: greenlet\nVersion: 3.0.0\n",
+            ),
+            (
+                "greenlet-3.0.0.dist-info/WHEEL",
+                "Wheel-Version: 1.0\nRoot-Is-Purelib: false\nTag: py3-none-any\n",
+            ),
+            (
+                "greenlet-3.0.0.dist-info/RECORD",
+                indoc! {"
+                    greenlet/__init__.py,,
+                    greenlet-3.0.0.data/headers/greenlet.h,,
+                    greenlet-3.0.0.data/purelib/greenlet_pure.py,,
+                    greenlet-3.0.0.data/scripts/greenlet-tool,,
+                    greenlet-3.0.0.dist-info/METADATA,,
+                    greenlet-3.0.0.dist-info/WHEEL,,
+                    greenlet-3.0.0.dist-info/RECORD,,
+                "},
+            ),
+        ] {
+            archive.start_file(path, FileOptions::default()).unwrap();
+            archive.write_all(content.as_bytes()).unwrap();
+        }
+        archive.finish().unwrap();
+        drop(archive);
+        wheel
+    }
+
+    fn greenlet_installer(location: &InstallLocation<LockedDir>) -> Installer<'_> {
+        Installer::new(location, "python")
+            .check_hashes(false)
+            .unique_version("3.0.0")
+    }
+
+    fn install_greenlet(location: &InstallLocation<LockedDir>) -> Result<String, Error> {
+        greenlet_installer(location).install(
+            Cursor::new(greenlet_wheel()),
+            WheelFilename::from_str("greenlet-3.0.0-py3-none-any.whl").unwrap(),
+        )
+    }
+
+    /// Headers go to `include/site/pythonX.Y/<name>` like with pip, where e.g. numpy extensions
+    /// look for them
+    #[test]
+    fn install_headers() {
+        let venv = TempDir::new().unwrap();
+        let location = InstallLocation::venv(venv.path().to_path_buf(), (3, 12))
+            .acquire_lock()
+            .unwrap();
+        install_greenlet(&location).unwrap();
+
+        let header = Path::new("include/site/python3.12/greenlet/greenlet.h");
+        assert_eq!(
+            fs::read_to_string(venv.path().join(header)).unwrap(),
+            "#define GREENLET 1\n"
+        );
+        let site_packages = if cfg!(windows) {
+            venv.path().join("Lib").join("site-packages")
+        } else {
+            venv.path().join("lib/python3.12/site-packages")
+        };
+        let record =
+            fs::read_to_string(site_packages.join("greenlet-3.0.0.dist-info/RECORD")).unwrap();
+        let recorded = relative_to(&venv.path().join(header), &site_packages).unwrap();
+        assert!(
+            record.contains(&recorded.display().to_string()),
+            "{}",
+            record
+        );
+        assert!(!site_packages.join("greenlet-3.0.0.data").exists());
+    }
+
+    /// An installed version is skipped unless forced, a dry run doesn't write anything
+    #[test]
+    fn install_force_and_dry_run() {
+        let venv = TempDir::new().unwrap();
+        let location = InstallLocation::venv(venv.path().to_path_buf(), (3, 12))
+            .acquire_lock()
+            .unwrap();
+        let install = |installer: Installer| {
+            installer
+                .install(
+                    Cursor::new(greenlet_wheel()),
+                    WheelFilename::from_str("greenlet-3.0.0-py3-none-any.whl").unwrap(),
+                )
+                .unwrap()
+        };
+
+        install(greenlet_installer(&location).dry_run(true));
+        let site_packages = venv.path().join(location_site_packages(12));
+        assert!(!site_packages.exists(), "the dry run wrote files");
+
+        assert_eq!(install(greenlet_installer(&location)), "py3-none-any");
+        let init = location_site_packages(12).join("greenlet/__init__.py");
+        fs::write(venv.path().join(&init), "edited").unwrap();
+        install(greenlet_installer(&location));
+        assert_eq!(
+            fs::read_to_string(venv.path().join(&init)).unwrap(),
+            "edited"
+        );
+        install(greenlet_installer(&location).force(true));
+        assert_eq!(fs::read_to_string(venv.path().join(&init)).unwrap(), "");
+    }
+
+    /// Packages from a git checkout record where they came from, index packages don't
+    #[test]
+    fn install_direct_url() {
+        let venv = TempDir::new().unwrap();
+        let location = InstallLocation::venv(venv.path().to_path_buf(), (3, 12))
+            .acquire_lock()
+            .unwrap();
+        let direct_url = DirectUrl::git(
+            "git+https://github.com/python-greenlet/greenlet",
+            "b0bab0b0bab0b0bab0b0bab0b0bab0b0bab0b0ba",
+            Some("3.0.0"),
+        );
+        greenlet_installer(&location)
+            .direct_url(Some(direct_url))
+            .install(
+                Cursor::new(greenlet_wheel()),
+                WheelFilename::from_str("greenlet-3.0.0-py3-none-any.whl").unwrap(),
+            )
+            .unwrap();
+
+        let site_packages = if cfg!(windows) {
+            venv.path().join("Lib").join("site-packages")
+        } else {
+            venv.path().join("lib/python3.12/site-packages")
+        };
+        let dist_info = site_packages.join("greenlet-3.0.0.dist-info");
+        let direct_url_json = fs::read_to_string(dist_info.join("direct_url.json")).unwrap();
+        assert!(
+            direct_url_json.contains(r#""commit_id":"b0bab0b0bab0b0bab0b0bab0b0bab0b0bab0b0ba""#)
+        );
+        let record = fs::read_to_string(dist_info.join("RECORD")).unwrap();
+        assert!(record.contains("greenlet-3.0.0.dist-info/direct_url.json,sha256="));
+    }
+
+    /// Same mtimes and hash based pycs with SOURCE_DATE_EPOCH
+    #[test]
+    fn install_reproducible() {
+        let output = Command::new("python3")
+            .args(["-c", "import sys; print(sys.version_info[1])"])
+            .output()
+            .unwrap();
+        let minor: u8 = String::from_utf8(output.stdout)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        let source_date_epoch = 1_700_000_000;
+
+        let install = || {
+            let venv = TempDir::new().unwrap();
+            let location = InstallLocation::venv(venv.path().to_path_buf(), (3, minor))
+                .acquire_lock()
+                .unwrap();
+            Installer::new(&location, "python3")
+                .compile(true)
+                .check_hashes(false)
+                // The default would write the absolute path of each venv
+                .launcher_policy(LauncherPolicy::EnvPython)
+                .source_date_epoch(Some(source_date_epoch))
+                .install(
+                    Cursor::new(greenlet_wheel()),
+                    WheelFilename::from_str("greenlet-3.0.0-py3-none-any.whl").unwrap(),
+                )
+                .unwrap();
+            drop(location);
+            venv
+        };
+        let (venv1, venv2) = (install(), install());
+
+        let site_packages = location_site_packages(minor);
+        let pyc = site_packages
+            .join("greenlet/__pycache__")
+            .join(format!("__init__.cpython-3{}.pyc", minor));
+        let pyc1 = fs::read(venv1.path().join(&pyc)).unwrap();
+        // The flags field of the header, 0b11 is checked-hash
+        assert_eq!(pyc1[4..8], [3, 0, 0, 0]);
+        assert_eq!(pyc1, fs::read(venv2.path().join(&pyc)).unwrap());
+        let record = site_packages.join("greenlet-3.0.0.dist-info/RECORD");
+        assert_eq!(
+            fs::read(venv1.path().join(&record)).unwrap(),
+            fs::read(venv2.path().join(&record)).unwrap()
+        );
+        for path in [&pyc, &record, &site_packages.join("greenlet/__init__.py")] {
+            let mtime = fs::metadata(venv1.path().join(path))
+                .unwrap()
+                .modified()
+                .unwrap();
+            assert_eq!(
+                mtime,
+                UNIX_EPOCH + Duration::from_secs(source_date_epoch),
+                "{}",
+                path.display()
+            );
+        }
+    }
+
+    fn location_site_packages(minor: u8) -> PathBuf {
+        if cfg!(windows) {
+            Path::new("Lib").join("site-packages")
+        } else {
+            Path::new("lib")
+                .join(format!("python3.{}", minor))
+                .join("site-packages")
+        }
+    }
+
+    #[test]
+    fn install_custom_scheme() {
+        let prefix = TempDir::new().unwrap();
+        let scheme = SchemePaths {
+            purelib: Path::new("lib").join("python3").join("dist-packages"),
+            platlib: Path::new("lib").join("python3.12").join("dist-packages"),
+            scripts: PathBuf::from("tools"),
+            data: PathBuf::from("share"),
+            include: Path::new("include").join("python3.12"),
+        };
+        let location = InstallLocation::venv(prefix.path().to_path_buf(), (3, 12))
+            .with_scheme(scheme.clone())
+            .acquire_lock()
+            .unwrap();
+        install_greenlet(&location).unwrap();
+
+        let platlib = prefix.path().join(&scheme.platlib);
+        assert!(platlib.join("greenlet").join("__init__.py").is_file());
+        assert!(prefix
+            .path()
+            .join(&scheme.purelib)
+            .join("greenlet_pure.py")
+            .is_file());
+        assert!(prefix
+            .path()
+            .join("include/python3.12/greenlet/greenlet.h")
+            .is_file());
+        let script = fs::read_to_string(prefix.path().join("tools").join("greenlet-tool")).unwrap();
+        assert!(script.starts_with("#!"), "{}", script);
+        let record = fs::read_to_string(platlib.join("greenlet-3.0.0.dist-info/RECORD")).unwrap();
+        assert!(
+            record.contains("../../../tools/greenlet-tool,"),
+            "{}",
+            record
+        );
+        assert!(
+            record.contains("../../python3/dist-packages/greenlet_pure.py,"),
+            "{}",
+            record
+        );
+        drop(location);
+
+        let location = InstallLocation::venv(prefix.path().to_path_buf(), (3, 12))
+            .with_scheme(SchemePaths {
+                purelib: PathBuf::from("/usr/lib/python3/dist-packages"),
+                ..scheme
+            })
+            .acquire_lock()
+            .unwrap();
+        assert!(matches!(
+            install_greenlet(&location),
+            Err(Error::InvalidScheme(_))
+        ));
+    }
+
+    #[test]
+    fn rollback_restores_venv() {
+        let venv = TempDir::new().unwrap();
+        let staging = TempDir::new_in(venv.path()).unwrap();
+        let backup_dir = TempDir::new_in(venv.path()).unwrap();
+        fs::create_dir_all(venv.path().join("bin")).unwrap();
+        fs::write(venv.path().join("bin").join("black"), "old").unwrap();
+        fs::create_dir_all(staging.path().join("bin")).unwrap();
+        fs::write(staging.path().join("bin").join("black"), "new").unwrap();
+        fs::create_dir_all(staging.path().join("black")).unwrap();
+        fs::write(staging.path().join("black").join("__init__.py"), "").unwrap();
+
+        let mut placed = Placed::default();
+        move_staged(staging.path(), venv.path(), backup_dir.path(), &mut placed).unwrap();
+        assert_eq!(
+            fs::read_to_string(venv.path().join("bin").join("black")).unwrap(),
+            "new"
+        );
+        rollback(placed);
+        assert_eq!(
+            fs::read_to_string(venv.path().join("bin").join("black")).unwrap(),
+            "old"
+        );
+        assert!(!venv.path().join("black").exists());
+    }
+
+    #[test]
+    fn disk_space_errors() {
+        let dir = TempDir::new().unwrap();
+        check_disk_space(dir.path(), 1).unwrap();
+        let err = check_disk_space(dir.path(), u64::MAX).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::InsufficientSpace {
+                needed: u64::MAX,
+                ..
+            }
+        ));
+
+        // The unpacked size of all wheels together is checked against the location
+        let wheel = Path::new("../../test-data/wheels/colander-0.9.9-py2.py3-none-any.whl");
+        let location = InstallLocation::venv(dir.path(), (3, 8));
+        check_disk_space_for(&location, [wheel, wheel]).unwrap();
+
+        // ENOSPC during the commit is reported with the size of the wheel
+        let full = Error::RolledBack(Box::new(Error::IO(io::ErrorKind::StorageFull.into())));
+        let err = out_of_space(full, dir.path(), 2_500_000);
+        assert!(err.to_string().starts_with(&format!(
+            "Not enough disk space in {}: The installation needs 2.5MB but only",
+            dir.path().display()
+        )));
+        // Other errors are passed through
+        let other = Error::IO(io::ErrorKind::PermissionDenied.into());
+        assert!(matches!(
+            out_of_space(other, dir.path(), 2_500_000),
+            Error::IO(_)
+        ));
+    }
+
     #[test]
     fn test_relative_to() {
         assert_eq!(
@@ -1410,46 +2046,4 @@ mod test {
             Path::new("../../../bin/foo_launcher")
         );
     }
-
-    #[test]
-    fn test_script_from_value() {
-        assert_eq!(
-            Script::from_value("launcher", "foo.bar:main", None).unwrap(),
-            Some(Script {
-                script_name: "launcher".to_string(),
-                module: "foo.bar".to_string(),
-                function: "main".to_string(),
-            })
-        );
-        assert_eq!(
-            Script::from_value(
-                "launcher",
-                "foo.bar:main",
-                Some(&["bar".to_string(), "baz".to_string()])
-            )
-            .unwrap(),
-            Some(Script {
-                script_name: "launcher".to_string(),
-                module: "foo.bar".to_string(),
-                function: "main".to_string(),
-            })
-        );
-        assert_eq!(
-            Script::from_value("launcher", "foomod:main_bar [bar,baz]", Some(&[])).unwrap(),
-            None
-        );
-        assert_eq!(
-            Script::from_value(
-                "launcher",
-                "foomod:main_bar [bar,baz]",
-                Some(&["bar".to_string(), "baz".to_string()])
-            )
-            .unwrap(),
-            Some(Script {
-                script_name: "launcher".to_string(),
-                module: "foomod".to_string(),
-                function: "main_bar".to_string(),
-            })
-        );
-    }
 }
@@ -0,0 +1,249 @@
+//! Packs a directory into a wheel, the inverse of installing. The directory contains the files as
+//! they go into the wheel including `<distribution>-<version>.dist-info/METADATA`, we add WHEEL
+//! (unless it exists already) and RECORD.
+//!
+//! As recommended by PEP 427, the `.dist-info` files come last with RECORD as the very last entry.
+//! All entries are sorted and have the same timestamp, so the same directory always gives the
+//! same wheel.
+
+use crate::hashing::record_hash;
+use crate::metadata::write_record;
+use crate::{Error, RecordEntry, WheelFilename};
+use fs_err as fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+use walkdir::WalkDir;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// The WHEEL file for a wheel with the tags of `filename`
+///
+/// <https://packaging.python.org/en/latest/specifications/binary-distribution-format/#the-dist-info-directory>
+pub fn wheel_file(filename: &WheelFilename) -> String {
+    // A wheel without abi and platform is pure python
+    let root_is_purelib = filename.abi_tag == ["none"] && filename.platform_tag == ["any"];
+    let mut wheel = format!(
+        "Wheel-Version: 1.0\nGenerator: install-wheel-rs {}\nRoot-Is-Purelib: {}\n",
+        env!("CARGO_PKG_VERSION"),
+        root_is_purelib
+    );
+    for python_tag in &filename.python_tag {
+        for abi_tag in &filename.abi_tag {
+            for platform_tag in &filename.platform_tag {
+                wheel.push_str(&format!("Tag: {python_tag}-{abi_tag}-{platform_tag}\n"));
+            }
+        }
+    }
+    wheel
+}
+
+/// Packs `layout` into `<out_dir>/<filename>` and returns the path of the wheel
+pub fn build_wheel(
+    layout: &Path,
+    filename: &WheelFilename,
+    out_dir: &Path,
+) -> Result<PathBuf, Error> {
+    let dist_info = format!("{}-{}.dist-info", filename.distribution, filename.version);
+    if !layout.join(&dist_info).join("METADATA").is_file() {
+        return Err(Error::InvalidWheel(format!(
+            "Missing {}/METADATA in {}",
+            dist_info,
+            layout.display()
+        )));
+    }
+    let record_path = format!("{dist_info}/RECORD");
+    let wheel_path = format!("{dist_info}/WHEEL");
+
+    // (path in the wheel, path on disk)
+    let mut files: Vec<(String, Option<PathBuf>)> = Vec::new();
+    for entry in WalkDir::new(layout) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry
+            .path()
+            .strip_prefix(layout)
+            .expect("walkdir starts with root");
+        let name = relative
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        // We write our own RECORD
+        if name == record_path {
+            continue;
+        }
+        files.push((name, Some(entry.path().to_path_buf())));
+    }
+    if !files.iter().any(|(name, _)| *name == wheel_path) {
+        files.push((wheel_path, None));
+    }
+    let dist_info_prefix = format!("{dist_info}/");
+    files.sort_by(|(a, _), (b, _)| {
+        (a.starts_with(&dist_info_prefix), a).cmp(&(b.starts_with(&dist_info_prefix), b))
+    });
+
+    let wheel_name = format!(
+        "{}-{}-{}.whl",
+        filename.distribution,
+        filename.version,
+        filename.get_tag()
+    );
+    let temp_file = NamedTempFile::new_in(out_dir)?;
+    let mut writer = ZipWriter::new(temp_file.reopen()?);
+    let zip_error = |err| Error::from_zip_error(wheel_name.clone(), err);
+    let mut record = Vec::new();
+    for (name, source) in files {
+        let (content, mode) = match source {
+            Some(source) => (fs::read(&source)?, file_mode(&source)?),
+            None => (wheel_file(filename).into_bytes(), 0o644),
+        };
+        // The default timestamp is 1980-01-01, the earliest zip can represent
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .unix_permissions(mode);
+        writer.start_file(&name, options).map_err(zip_error)?;
+        writer.write_all(&content)?;
+        record.push(RecordEntry {
+            path: name,
+            hash: Some(record_hash(&content)),
+            size: Some(content.len()),
+        });
+    }
+    record.push(RecordEntry {
+        path: record_path.clone(),
+        hash: None,
+        size: None,
+    });
+    let mut record_content = Vec::new();
+    write_record(&mut record_content, record)?;
+    let options = FileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .unix_permissions(0o644);
+    writer
+        .start_file(&record_path, options)
+        .map_err(zip_error)?;
+    writer.write_all(&record_content)?;
+    writer.finish().map_err(zip_error)?;
+
+    let target = out_dir.join(wheel_name);
+    temp_file
+        .persist(&target)
+        .map_err(|err| Error::IO(err.error))?;
+    Ok(target)
+}
+
+/// Keeps the executable bit, e.g. for scripts in `.data/scripts`
+#[cfg(unix)]
+fn file_mode(path: &Path) -> Result<u32, Error> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = fs::metadata(path)?.permissions().mode();
+    Ok(if mode & 0o111 != 0 { 0o755 } else { 0o644 })
+}
+
+#[cfg(not(unix))]
+fn file_mode(_path: &Path) -> Result<u32, Error> {
+    Ok(0o644)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::wheel_builder::{build_wheel, wheel_file};
+    use crate::{verify_wheel, wheel_install_paths, WheelFilename};
+    use fs_err as fs;
+    use fs_err::File;
+    use indoc::indoc;
+    use std::str::FromStr;
+    use tempfile::TempDir;
+    use zip::ZipArchive;
+
+    #[test]
+    fn test_wheel_file() {
+        let filename = WheelFilename::from_str("foo-1.0-py2.py3-none-any.whl").unwrap();
+        assert_eq!(
+            wheel_file(&filename).replace(env!("CARGO_PKG_VERSION"), "x"),
+            indoc! {"
+                Wheel-Version: 1.0
+                Generator: install-wheel-rs x
+                Root-Is-Purelib: true
+                Tag: py2-none-any
+                Tag: py3-none-any
+            "}
+        );
+        let filename =
+            WheelFilename::from_str("foo-1.0-cp312-cp312-manylinux_2_17_x86_64.whl").unwrap();
+        assert!(wheel_file(&filename).contains("Root-Is-Purelib: false\n"));
+    }
+
+    #[test]
+    fn test_build_wheel() {
+        let layout = TempDir::new().unwrap();
+        let files = [
+            (
+                "foo-1.0.dist-info/METADATA",
+                "Metadata-Version: 2.1\nName: foo\nVersion: 1.0\n",
+            ),
+            (
+                "foo-1.0.dist-info/entry_points.txt",
+                "[console_scripts]\nfoo = foo:main\n",
+            ),
+            // Replaced by ours
+            ("foo-1.0.dist-info/RECORD", "outdated"),
+            ("foo/__init__.py", "def main(): pass\n"),
+            ("foo/sub/data.json", "{}\n"),
+            ("foo-1.0.data/scripts/foo-tool", "#!python\n"),
+        ];
+        for (path, content) in files {
+            let path = layout.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        let out_dir = TempDir::new().unwrap();
+        let filename = WheelFilename::from_str("foo-1.0-py3-none-any.whl").unwrap();
+        let wheel = build_wheel(layout.path(), &filename, out_dir.path()).unwrap();
+        assert_eq!(wheel, out_dir.path().join("foo-1.0-py3-none-any.whl"));
+
+        let mut archive = ZipArchive::new(File::open(&wheel).unwrap()).unwrap();
+        let names: Vec<String> = (0..archive.len())
+            .map(|index| archive.by_index(index).unwrap().name().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "foo-1.0.data/scripts/foo-tool",
+                "foo/__init__.py",
+                "foo/sub/data.json",
+                "foo-1.0.dist-info/METADATA",
+                "foo-1.0.dist-info/WHEEL",
+                "foo-1.0.dist-info/entry_points.txt",
+                "foo-1.0.dist-info/RECORD",
+            ]
+        );
+        // The RECORD matches
+        assert!(verify_wheel(&filename, File::open(&wheel).unwrap())
+            .unwrap()
+            .is_empty());
+        let paths = wheel_install_paths(&filename, File::open(&wheel).unwrap()).unwrap();
+        assert!(paths
+            .iter()
+            .any(|(path, hash)| path == "scripts/foo"
+                && hash.as_deref() == Some("entrypoint=foo:main")));
+
+        // Reproducible
+        let first = fs::read(&wheel).unwrap();
+        build_wheel(layout.path(), &filename, out_dir.path()).unwrap();
+        assert_eq!(first, fs::read(&wheel).unwrap());
+
+        let err = build_wheel(
+            layout.path(),
+            &WheelFilename::from_str("bar-1.0-py3-none-any.whl").unwrap(),
+            out_dir.path(),
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Missing bar-1.0.dist-info/METADATA"));
+    }
+}
@@ -1,17 +1,26 @@
 //! Parses the wheel filename, the current host os/arch and checks wheels for compatibility
 
 use crate::Error;
+#[cfg(not(target_arch = "wasm32"))]
 use fs_err as fs;
+#[cfg(not(target_arch = "wasm32"))]
 use goblin::elf::Elf;
+#[cfg(not(target_arch = "wasm32"))]
 use once_cell::sync::Lazy;
+#[cfg(not(target_arch = "wasm32"))]
 use platform_info::{PlatformInfo, PlatformInfoAPI, UNameAPI};
+#[cfg(not(target_arch = "wasm32"))]
 use regex::Regex;
+#[cfg(not(target_arch = "wasm32"))]
 use serde::Deserialize;
 use std::fmt;
 use std::ops::Deref;
+#[cfg(not(target_arch = "wasm32"))]
 use std::path::{Path, PathBuf};
+#[cfg(not(target_arch = "wasm32"))]
 use std::process::{Command, Stdio};
 use std::str::FromStr;
+#[cfg(not(target_arch = "wasm32"))]
 use tracing::trace;
 
 /// The name of a wheel split into its parts ([PEP 491](https://peps.python.org/pep-0491/))
@@ -134,25 +143,55 @@ impl Deref for CompatibleTags {
 /// highest precedence to lowest precedence
 impl CompatibleTags {
     /// Compatible tags for the current operating system and architecture
+    #[cfg(not(target_arch = "wasm32"))]
+    #[cfg_attr(feature = "instrument", tracing::instrument(level = "debug"))]
     pub fn current(python_version: (u8, u8)) -> Result<CompatibleTags, Error> {
         Self::new(python_version, Os::current()?, Arch::current()?)
     }
 
+    /// Compatible tags for the free-threaded build (PEP 703) of the current operating system and
+    /// architecture
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn current_free_threaded(python_version: (u8, u8)) -> Result<CompatibleTags, Error> {
+        Self::new_free_threaded(python_version, Os::current()?, Arch::current()?)
+    }
+
     pub fn new(python_version: (u8, u8), os: Os, arch: Arch) -> Result<CompatibleTags, Error> {
+        Self::build(python_version, false, os, arch)
+    }
+
+    /// The free-threaded build, e.g. `python3.13t`, has its own abi (`cp313t`) and can't load
+    /// abi3 extension modules
+    pub fn new_free_threaded(
+        python_version: (u8, u8),
+        os: Os,
+        arch: Arch,
+    ) -> Result<CompatibleTags, Error> {
+        Self::build(python_version, true, os, arch)
+    }
+
+    fn build(
+        python_version: (u8, u8),
+        free_threaded: bool,
+        os: Os,
+        arch: Arch,
+    ) -> Result<CompatibleTags, Error> {
         assert_eq!(python_version.0, 3);
         let mut tags = Vec::new();
         let platform_tags = compatible_platform_tags(&os, &arch)?;
+        let abiflags = if free_threaded {
+            "t"
+        } else if python_version.1 <= 7 {
+            // hacky but that's legacy anyways
+            "m"
+        } else {
+            ""
+        };
         // 1. This exact c api version
         for platform_tag in &platform_tags {
             tags.push((
                 format!("cp{}{}", python_version.0, python_version.1),
-                format!(
-                    "cp{}{}{}",
-                    python_version.0,
-                    python_version.1,
-                    // hacky but that's legacy anyways
-                    if python_version.1 <= 7 { "m" } else { "" }
-                ),
+                format!("cp{}{}{}", python_version.0, python_version.1, abiflags),
                 platform_tag.clone(),
             ));
             tags.push((
@@ -161,15 +200,19 @@ impl CompatibleTags {
                 platform_tag.clone(),
             ));
         }
-        // 2. abi3 and no abi (e.g. executable binary)
-        // For some reason 3.2 is the minimum python for the cp abi
-        for minor in 2..=python_version.1 {
-            for platform_tag in &platform_tags {
-                tags.push((
-                    format!("cp{}{}", python_version.0, minor),
-                    "abi3".to_string(),
-                    platform_tag.clone(),
-                ));
+        // 2. abi3, which works for all pythons since the minor version it was built for. The
+        // newest build first, it may use newer parts of the stable api.
+        // For some reason 3.2 is the minimum python for the cp abi. The free-threaded build
+        // doesn't support the limited api
+        if !free_threaded {
+            for minor in (2..=python_version.1).rev() {
+                for platform_tag in &platform_tags {
+                    tags.push((
+                        format!("cp{}{}", python_version.0, minor),
+                        "abi3".to_string(),
+                        platform_tag.clone(),
+                    ));
+                }
             }
         }
         // 3. no abi (e.g. executable binary)
@@ -205,23 +248,113 @@ impl CompatibleTags {
         ));
         Ok(CompatibleTags { os, arch, tags })
     }
+
+    /// The tags for a pyodide abi such as `2024.0`, which fixes both the python and the
+    /// emscripten version. Detection isn't meaningful for pyodide, we always build the
+    /// environment from the outside
+    pub fn pyodide(abi: &str) -> Result<CompatibleTags, Error> {
+        let pyodide_abi = PYODIDE_ABIS
+            .iter()
+            .find(|known| known.abi == abi)
+            .ok_or_else(|| {
+                let known: Vec<&str> = PYODIDE_ABIS.iter().map(|known| known.abi).collect();
+                Error::UnknownPyodideAbi(abi.to_string(), known.join(", "))
+            })?;
+        let (major, minor, patch) = pyodide_abi.emscripten_version;
+        Self::new(
+            pyodide_abi.python_version,
+            Os::Emscripten {
+                major,
+                minor,
+                patch,
+            },
+            Arch::Wasm32,
+        )
+    }
+
+    /// The abi tags in order of precedence without duplicates, e.g. `cp38`, `none`, `abi3` for
+    /// CPython 3.8
+    pub fn abi_tags(&self) -> Vec<&str> {
+        let mut abi_tags: Vec<&str> = Vec::new();
+        for (_, abi_tag, _) in &self.tags {
+            if !abi_tags.contains(&abi_tag.as_str()) {
+                abi_tags.push(abi_tag);
+            }
+        }
+        abi_tags
+    }
 }
 
+/// A pyodide abi (<https://pyodide.org/en/stable/development/abi.html>)
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PyodideAbi {
+    /// e.g. `2024.0`
+    pub abi: &'static str,
+    pub python_version: (u8, u8),
+    /// major, minor and patch
+    pub emscripten_version: (u16, u16, u16),
+}
+
+/// The known pyodide abis
+pub const PYODIDE_ABIS: &[PyodideAbi] = &[
+    PyodideAbi {
+        abi: "2024.0",
+        python_version: (3, 12),
+        emscripten_version: (3, 1, 58),
+    },
+    PyodideAbi {
+        abi: "2025.0",
+        python_version: (3, 13),
+        emscripten_version: (4, 0, 9),
+    },
+];
+
 /// All supported operating system
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Os {
-    Manylinux { major: u16, minor: u16 },
-    Musllinux { major: u16, minor: u16 },
+    Manylinux {
+        major: u16,
+        minor: u16,
+    },
+    Musllinux {
+        major: u16,
+        minor: u16,
+    },
     Windows,
-    Macos { major: u16, minor: u16 },
-    FreeBsd { release: String },
-    NetBsd { release: String },
-    OpenBsd { release: String },
-    Dragonfly { release: String },
-    Illumos { release: String, arch: String },
-    Haiku { release: String },
+    Macos {
+        major: u16,
+        minor: u16,
+    },
+    FreeBsd {
+        release: String,
+    },
+    NetBsd {
+        release: String,
+    },
+    OpenBsd {
+        release: String,
+    },
+    Dragonfly {
+        release: String,
+    },
+    Illumos {
+        release: String,
+        arch: String,
+    },
+    Haiku {
+        release: String,
+    },
+    /// The emscripten version, e.g. 3.1.58 for pyodide 2024.0. Never detected, see
+    /// [CompatibleTags::pyodide]
+    Emscripten {
+        major: u16,
+        minor: u16,
+        patch: u16,
+    },
 }
 
+// Detecting the host needs the filesystem and subprocesses, on wasm the os and arch must be given
+#[cfg(not(target_arch = "wasm32"))]
 impl Os {
     fn detect_linux_libc() -> Result<Self, Error> {
         let libc = find_libc()?;
@@ -343,6 +476,7 @@ impl fmt::Display for Os {
             Os::Dragonfly { .. } => write!(f, "DragonFly"),
             Os::Illumos { .. } => write!(f, "Illumos"),
             Os::Haiku { .. } => write!(f, "Haiku"),
+            Os::Emscripten { .. } => write!(f, "Emscripten"),
         }
     }
 }
@@ -357,6 +491,7 @@ pub enum Arch {
     X86,
     X86_64,
     S390X,
+    Wasm32,
 }
 
 impl fmt::Display for Arch {
@@ -369,11 +504,13 @@ impl fmt::Display for Arch {
             Arch::X86 => write!(f, "i686"),
             Arch::X86_64 => write!(f, "x86_64"),
             Arch::S390X => write!(f, "s390x"),
+            Arch::Wasm32 => write!(f, "wasm32"),
         }
     }
 }
 
 impl Arch {
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn current() -> Result<Arch, Error> {
         let target_triple = target_lexicon::HOST;
         let arch = match target_triple.architecture {
@@ -384,6 +521,7 @@ impl Arch {
             target_lexicon::Architecture::Powerpc64 => Arch::Powerpc64,
             target_lexicon::Architecture::Powerpc64le => Arch::Powerpc64Le,
             target_lexicon::Architecture::S390x => Arch::S390X,
+            target_lexicon::Architecture::Wasm32 => Arch::Wasm32,
             unsupported => {
                 return Err(Error::OsVersionDetection(format!(
                     "The architecture {} is not supported",
@@ -398,13 +536,20 @@ impl Arch {
     pub fn get_minimum_manylinux_minor(&self) -> u16 {
         match self {
             // manylinux 2014
-            Arch::Aarch64 | Arch::Armv7L | Arch::Powerpc64 | Arch::Powerpc64Le | Arch::S390X => 17,
+            // There is no manylinux for wasm
+            Arch::Aarch64
+            | Arch::Armv7L
+            | Arch::Powerpc64
+            | Arch::Powerpc64Le
+            | Arch::S390X
+            | Arch::Wasm32 => 17,
             // manylinux 1
             Arch::X86 | Arch::X86_64 => 5,
         }
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 fn get_mac_os_version() -> Result<(u16, u16), Error> {
     // This is actually what python does
     // https://github.com/python/cpython/blob/cb2b3c8d3566ae46b3b8d0718019e1c98484589e/Lib/platform.py#L409-L428
@@ -469,6 +614,7 @@ fn get_mac_binary_formats(major: u16, minor: u16, arch: &Arch) -> Vec<String> {
 }
 
 /// Find musl libc path from executable's ELF header
+#[cfg(not(target_arch = "wasm32"))]
 pub fn find_libc() -> Result<PathBuf, Error> {
     let buffer = fs::read("/bin/ls")?;
     let error_str = "Couldn't parse /bin/ls for detecting the ld version";
@@ -488,6 +634,7 @@ pub fn find_libc() -> Result<PathBuf, Error> {
 /// musl libc (x86_64)
 /// Version 1.2.2
 /// Dynamic Program Loader
+#[cfg(not(target_arch = "wasm32"))]
 pub fn get_musl_version(ld_path: impl AsRef<Path>) -> std::io::Result<Option<(u16, u16)>> {
     let output = Command::new(ld_path.as_ref())
         .stdout(Stdio::null())
@@ -514,6 +661,7 @@ pub fn get_musl_version(ld_path: impl AsRef<Path>) -> std::io::Result<Option<(u1
 ///
 /// Bit of a mess, needs to be cleaned up. The order also isn't exactly matching that of pip yet,
 /// but works good enough in practice
+#[cfg_attr(feature = "instrument", tracing::instrument(level = "debug"))]
 pub fn compatible_platform_tags(os: &Os, arch: &Arch) -> Result<Vec<String>, Error> {
     let platform_tags = match (os.clone(), *arch) {
         (Os::Manylinux { major, minor }, _) => {
@@ -612,15 +760,38 @@ pub fn compatible_platform_tags(os: &Os, arch: &Arch) -> Result<Vec<String>, Err
         }
         (Os::Windows, Arch::Aarch64) => vec!["win_arm64".to_string()],
         (
-            Os::FreeBsd { release: _ }
-            | Os::NetBsd { release: _ }
-            | Os::OpenBsd { release: _ }
-            | Os::Dragonfly { release: _ }
-            | Os::Haiku { release: _ },
+            Os::Emscripten {
+                major,
+                minor,
+                patch,
+            },
+            Arch::Wasm32,
+        ) => {
+            // Pyodide's own tag (PEP 783) first, then the emscripten tag of older builds
+            let mut platform_tags = Vec::new();
+            if let Some(pyodide_abi) = PYODIDE_ABIS
+                .iter()
+                .find(|known| known.emscripten_version == (major, minor, patch))
+            {
+                platform_tags.push(format!(
+                    "pyodide_{}_{}",
+                    pyodide_abi.abi.replace('.', "_"),
+                    arch
+                ));
+            }
+            platform_tags.push(format!("emscripten_{}_{}_{}_{}", major, minor, patch, arch));
+            platform_tags
+        }
+        (
+            Os::FreeBsd { release }
+            | Os::NetBsd { release }
+            | Os::OpenBsd { release }
+            | Os::Dragonfly { release }
+            | Os::Haiku { release },
             _,
         ) => {
-            let info = PlatformInfo::new().map_err(Error::PlatformInfo)?;
-            let release = info.release().to_string_lossy().replace(['.', '-'], "_");
+            // `Os::current` reads the release from uname, so we don't need to ask again
+            let release = release.replace(['.', '-'], "_");
             vec![format!(
                 "{}_{}_{}",
                 os.to_string().to_lowercase(),
@@ -926,6 +1097,123 @@ mod test {
         }
     }
 
+    /// abi3 wheels work on all pythons since the one they were built for, and the newest build
+    /// wins
+    #[test]
+    fn test_abi3() {
+        let os = Os::Manylinux {
+            major: 2,
+            minor: 31,
+        };
+        let cp37 =
+            WheelFilename::from_str("cryptography-41.0.3-cp37-abi3-manylinux_2_28_x86_64.whl")
+                .unwrap();
+        let cp39 =
+            WheelFilename::from_str("cryptography-41.0.3-cp39-abi3-manylinux_2_28_x86_64.whl")
+                .unwrap();
+        for minor in 7..=12 {
+            let tags = CompatibleTags::new((3, minor), os.clone(), Arch::X86_64).unwrap();
+            assert!(cp37.compatibility(&tags).is_ok(), "3.{}", minor);
+            assert_eq!(cp39.compatibility(&tags).is_ok(), minor >= 9, "3.{}", minor);
+        }
+        let tags = CompatibleTags::new((3, 6), os.clone(), Arch::X86_64).unwrap();
+        assert!(cp37.compatibility(&tags).is_err());
+
+        let tags = CompatibleTags::new((3, 12), os, Arch::X86_64).unwrap();
+        assert!(cp39.compatibility(&tags).unwrap() < cp37.compatibility(&tags).unwrap());
+        assert_eq!(tags.abi_tags(), ["cp312", "none", "abi3"]);
+    }
+
+    #[test]
+    fn test_free_threaded() {
+        let os = Os::Manylinux {
+            major: 2,
+            minor: 31,
+        };
+        let tags = CompatibleTags::new_free_threaded((3, 13), os.clone(), Arch::X86_64).unwrap();
+        assert_eq!(tags.abi_tags(), ["cp313t", "none"]);
+        let compatible = |filename: &str| {
+            WheelFilename::from_str(filename)
+                .unwrap()
+                .compatibility(&tags)
+                .is_ok()
+        };
+        assert!(compatible(
+            "numpy-2.1.0-cp313-cp313t-manylinux_2_17_x86_64.manylinux2014_x86_64.whl"
+        ));
+        assert!(!compatible(
+            "numpy-2.1.0-cp313-cp313-manylinux_2_17_x86_64.manylinux2014_x86_64.whl"
+        ));
+        assert!(!compatible(
+            "cryptography-43.0.0-cp39-abi3-manylinux_2_28_x86_64.whl"
+        ));
+        assert!(compatible("tqdm-4.66.5-py3-none-any.whl"));
+
+        // And the other way round
+        let tags = CompatibleTags::new((3, 13), os, Arch::X86_64).unwrap();
+        assert!(WheelFilename::from_str(
+            "numpy-2.1.0-cp313-cp313t-manylinux_2_17_x86_64.manylinux2014_x86_64.whl"
+        )
+        .unwrap()
+        .compatibility(&tags)
+        .is_err());
+    }
+
+    #[test]
+    fn test_pyodide() {
+        let tags = CompatibleTags::pyodide("2024.0").unwrap();
+        let compatible = |filename: &str| {
+            WheelFilename::from_str(filename)
+                .unwrap()
+                .compatibility(&tags)
+                .is_ok()
+        };
+        assert!(compatible(
+            "numpy-2.0.2-cp312-cp312-pyodide_2024_0_wasm32.whl"
+        ));
+        assert!(compatible(
+            "numpy-1.26.4-cp312-cp312-emscripten_3_1_58_wasm32.whl"
+        ));
+        assert!(compatible("tqdm-4.66.5-py3-none-any.whl"));
+        assert!(!compatible(
+            "numpy-1.26.4-cp312-cp312-emscripten_3_1_46_wasm32.whl"
+        ));
+        assert!(!compatible(
+            "numpy-2.2.5-cp313-cp313-pyodide_2025_0_wasm32.whl"
+        ));
+        assert!(!compatible(
+            "numpy-2.0.2-cp312-cp312-manylinux_2_17_x86_64.manylinux2014_x86_64.whl"
+        ));
+
+        // Without a pyodide abi only the emscripten tag
+        let platform_tags = compatible_platform_tags(
+            &Os::Emscripten {
+                major: 3,
+                minor: 1,
+                patch: 46,
+            },
+            &Arch::Wasm32,
+        )
+        .unwrap();
+        assert_eq!(platform_tags, ["emscripten_3_1_46_wasm32"]);
+
+        assert_eq!(
+            CompatibleTags::pyodide("0.23").err().unwrap().to_string(),
+            "Unknown pyodide abi 0.23, known are 2024.0, 2025.0"
+        );
+    }
+
+    #[test]
+    fn test_bsd_release() {
+        let os = Os::FreeBsd {
+            release: "13.2-RELEASE".to_string(),
+        };
+        assert_eq!(
+            compatible_platform_tags(&os, &Arch::X86_64).unwrap(),
+            ["freebsd_13_2_RELEASE_x86_64"]
+        );
+    }
+
     /// Basic does-it-work test
     #[test]
     fn host_arch() -> Result<(), Error> {
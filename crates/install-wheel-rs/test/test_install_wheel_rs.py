@@ -33,3 +33,22 @@ def test_install_wheel_rs(pytestconfig, tmp_path):
     )
     locked_venv.install_wheel(wheel)
     assert check_installed(venv)
+
+
+def test_metadata_and_uninstall(pytestconfig, tmp_path):
+    from install_wheel_rs import LockedVenv, read_metadata
+
+    venv = tmp_path.joinpath("venv_test_uninstall")
+    check_call(["virtualenv", venv])
+    locked_venv = LockedVenv(venv)
+    wheel = pytestconfig.rootpath.joinpath(
+        "install-wheel-rs/test-data/upsidedown-0.4-py2.py3-none-any.whl"
+    )
+    assert "Name: upsidedown" in read_metadata(wheel)
+    assert ("py3", "none", "any") in locked_venv.compatible_tags()
+
+    assert locked_venv.install_many([wheel]) == ["py2.py3-none-any"]
+    assert check_installed(venv)
+    assert locked_venv.uninstall("upsidedown") == "0.4"
+    assert not check_installed(venv)
+    assert locked_venv.uninstall("upsidedown") is None
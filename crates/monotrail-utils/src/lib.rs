@@ -1,7 +1,17 @@
 //! Implements stand-alone utilities used by `monotrail`
 
-pub use requirements_txt::RequirementsTxt;
+pub use requirements_txt::{
+    EntrySpan, IndexOptions, RequirementEntry, RequirementSource, RequirementsTxt,
+    RequirementsTxtFileError,
+};
 
+#[cfg(not(target_arch = "wasm32"))]
+pub mod auth;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod network;
 pub mod parse_cpython_args;
 mod requirements_txt;
+#[cfg(not(target_arch = "wasm32"))]
 pub mod standalone_python;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod transport;
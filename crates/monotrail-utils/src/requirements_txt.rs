@@ -8,40 +8,60 @@
 //!  * `-c`
 //!  * `--hash` (postfix)
 //!  * `-e`
+//!  * `<path>` and `<archive_url>` to a wheel or source distribution, the name is taken from
+//!    the filename
+//!  * `--index-url`/`-i`, `--extra-index-url`, `--find-links`/`-f`, `--no-index`,
+//!    `--trusted-host` and `--pre`, collected in [IndexOptions]
+//!  * `--global-option` and `--config-settings` (postfix)
+//!
+//! `${VAR}` is replaced with the environment variable `VAR` as in pip, e.g. for
+//! `--index-url https://${TOKEN}@pypi.internal/simple`.
+//!
+//! Passing `-` as top level file reads the requirements from stdin, while
+//! [RequirementsTxt::parse_str] and [RequirementsTxt::parse_reader] take in-memory content.
+//!
+//! [RequirementsTxt::parse_lossless] additionally records an [EntrySpan] with position, original
+//! text and comments for each entry. Positions and text refer to the file as written, since
+//! `${VAR}` is only expanded in the parsed values.
 //!
 //! Unsupported:
 //!  * `-e <path>`. TBD
-//!  * `<path>`. TBD
-//!  * `<archive_url>`. TBD
-//!  * Options without a requirement, such as `--find-links` or `--index-url`
+//!  * `<path>` to a directory, since we can't know the name without building it
+//!  * Other options such as `--prefer-binary` or `--only-binary`
 //!
 //! Grammar as implemented:
 //!
 //! ```text
 //! file = (statement | empty ('#' any*)? '\n')*
 //! empty = whitespace*
-//! statement = constraint_include | requirements_include | editable_requirement | requirement
+//! statement = constraint_include | requirements_include | index_option | editable_requirement | path_or_url | requirement
 //! constraint_include = '-c' ('=' | wrappable_whitespaces) filepath
 //! requirements_include = '-r' ('=' | wrappable_whitespaces) filepath
+//! index_option = (('--index-url' | '-i' | '--extra-index-url' | '--find-links' | '-f' | '--trusted-host') ('=' | wrappable_whitespaces) value) | '--no-index' | '--pre'
 //! editable_requirement = '-e' ('=' | wrappable_whitespaces) requirement
+//! # Urls start with a scheme, paths with `.`, `/` or a drive letter or they end with an archive
+//! # extension
+//! path_or_url = (url_scheme | '.' | '/' | [a-zA-Z] ':') [^whitespace]* wrappable_whitespaces options
 //! # We check whether the line starts with a letter or a number, in that case we assume it's a
 //! # PEP 508 requirement
 //! # https://packaging.python.org/en/latest/specifications/name-normalization/#valid-non-normalized-names
 //! # This does not (yet?) support plain files or urls, we use a letter or a number as first
 //! # character to assume a PEP 508 requirement
-//! requirement = [a-zA-Z0-9] pep508_grammar_tail wrappable_whitespaces hashes
-//! hashes = ('--hash' ('=' | wrappable_whitespaces) [a-zA-Z0-9-_]+ ':' [a-zA-Z0-9-_] wrappable_whitespaces+)*
+//! requirement = [a-zA-Z0-9] pep508_grammar_tail wrappable_whitespaces options
+//! options = (('--hash' | '--global-option' | '--config-settings') ('=' | wrappable_whitespaces) [^whitespace]+ wrappable_whitespaces+)*
 //! # This should indicate a single backslash before a newline
 //! wrappable_whitespaces = whitespace ('\\\n' | whitespace)*
 //! ```
 
 use fs_err as fs;
-use pep508_rs::{Pep508Error, Requirement};
+use pep508_rs::{Pep508Error, Requirement, VersionOrUrl};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fmt::{Display, Formatter};
-use std::io;
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::{env, io};
 use tracing::warn;
 use unscanny::{Pattern, Scanner};
 
@@ -59,36 +79,135 @@ enum RequirementsTxtStatement {
         start: usize,
         end: usize,
     },
+    /// `--index-url`, `--find-links` and similar
+    IndexOption(IndexOption),
     /// PEP 508 requirement plus metadata
-    RequirementEntry(RequirementEntry),
+    RequirementEntry(Box<RequirementEntry>),
+}
+
+/// A single option line that changes where we get packages from
+enum IndexOption {
+    IndexUrl(String),
+    ExtraIndexUrl(String),
+    FindLinks(String),
+    NoIndex,
+    TrustedHost(String),
+    Pre,
 }
 
-/// A [Requirement] with additional metadata from the requirements.txt, currently only hashes but in
-/// the future also editable an similar information
+/// Url schemes that we accept for bare urls
+const URL_SCHEMES: &[&str] = &["http://", "https://", "file://"];
+
+/// The file extensions of wheels and source distributions
+const ARCHIVE_EXTENSIONS: &[&str] = &[".whl", ".tar.gz", ".zip"];
+
+/// Where a requirements.txt entry comes from
+#[derive(Debug, Deserialize, Clone, Eq, PartialEq, Serialize)]
+pub enum RequirementSource {
+    /// A PEP 508 requirement with a name and version specifiers, to be installed from the index
+    Registry,
+    /// A bare url or a PEP 508 `<name> @ <url>` requirement
+    Url(String),
+    /// A local wheel or source distribution, relative to the working directory
+    Path(PathBuf),
+    /// Editable installation, see e.g. <https://stackoverflow.com/q/35064426/3549270>
+    Editable,
+}
+
+/// A [Requirement] with additional metadata from the requirements.txt, currently the hashes and
+/// the source
 #[derive(Debug, Deserialize, Clone, Eq, PartialEq, Serialize)]
 pub struct RequirementEntry {
-    /// The actual PEP 508 requirement
+    /// The actual PEP 508 requirement. For bare urls and paths, this is `<name> @ <url>` with the
+    /// name from the filename
     pub requirement: Requirement,
     /// Hashes of the downloadable packages
     pub hashes: Vec<String>,
-    /// Editable installation, see e.g. <https://stackoverflow.com/q/35064426/3549270>
-    pub editable: bool,
+    /// Whether this is from an index, a url, a path or editable
+    pub source: RequirementSource,
+    /// `--global-option` values, passed to `setup.py` when building from source
+    pub global_options: Vec<String>,
+    /// `--config-settings` values as `key=value`, passed to the PEP 517 build backend
+    pub config_settings: Vec<String>,
+    /// Only recorded by [RequirementsTxt::parse_lossless]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub span: Option<EntrySpan>,
+}
+
+/// Where an entry is in its requirements file, with the original text and attached comments
+#[derive(Debug, Deserialize, Clone, Eq, PartialEq, Serialize)]
+pub struct EntrySpan {
+    /// The file the entry is from, which differs from the parsed file for `-r` includes
+    pub file: PathBuf,
+    /// 1-based line of the start of the entry
+    pub line: usize,
+    /// 1-based column of the start of the entry, in characters
+    pub column: usize,
+    /// Byte offset of the start of the entry
+    pub start: usize,
+    /// Byte offset of the end of the entry, excluding the line break
+    pub end: usize,
+    /// The entry as written, including escaped line breaks, options and a trailing comment
+    pub text: String,
+    /// The comment lines directly above the entry and a trailing comment, without the `#`
+    pub comments: Vec<String>,
 }
 
 impl Display for RequirementEntry {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        if self.editable {
-            write!(f, "-e ")?;
+        match &self.source {
+            RequirementSource::Editable => write!(f, "-e {}", self.requirement)?,
+            RequirementSource::Path(path) => write!(f, "{}", path.display())?,
+            RequirementSource::Registry | RequirementSource::Url(_) => {
+                write!(f, "{}", self.requirement)?
+            }
         }
-        write!(f, "{}", self.requirement)?;
         for hash in &self.hashes {
             write!(f, " --hash {}", hash)?
         }
+        for global_option in &self.global_options {
+            write!(f, " --global-option {}", global_option)?
+        }
+        for config_setting in &self.config_settings {
+            write!(f, " --config-settings {}", config_setting)?
+        }
 
         Ok(())
     }
 }
 
+/// The options that change where packages are found, merged over all included files like pip
+/// does
+#[derive(Debug, Deserialize, Clone, Default, Eq, PartialEq, Serialize)]
+pub struct IndexOptions {
+    /// `--index-url`, replaces pypi. If given multiple times, the last one wins
+    pub index_url: Option<String>,
+    /// `--extra-index-url`
+    pub extra_index_urls: Vec<String>,
+    /// `--find-links`, a directory or an url to an html page with links to distributions
+    pub find_links: Vec<String>,
+    /// `--no-index`, ignore all indexes and only use `--find-links`
+    pub no_index: bool,
+    /// `--trusted-host`, hosts for which we don't verify https
+    pub trusted_hosts: Vec<String>,
+    /// `--pre`, also consider pre-release versions
+    pub pre: bool,
+}
+
+impl IndexOptions {
+    /// Merges other into self
+    pub fn update_from(&mut self, other: IndexOptions) {
+        if other.index_url.is_some() {
+            self.index_url = other.index_url;
+        }
+        self.extra_index_urls.extend(other.extra_index_urls);
+        self.find_links.extend(other.find_links);
+        self.no_index |= other.no_index;
+        self.trusted_hosts.extend(other.trusted_hosts);
+        self.pre |= other.pre;
+    }
+}
+
 /// Parsed and flattened requirements.txt with requirements and constraints
 #[derive(Debug, Deserialize, Clone, Default, Eq, PartialEq, Serialize)]
 pub struct RequirementsTxt {
@@ -96,28 +215,102 @@ pub struct RequirementsTxt {
     pub requirements: Vec<RequirementEntry>,
     /// Constraints included with `-c`
     pub constraints: Vec<Requirement>,
+    /// Index urls, find links and related options from all included files
+    pub index_options: IndexOptions,
 }
 
 impl RequirementsTxt {
-    /// See module level documentation
+    /// See module level documentation. Use `-` as filename to read from stdin.
     pub fn parse(
         requirements_txt: impl AsRef<Path>,
         working_dir: impl AsRef<Path>,
     ) -> Result<Self, RequirementsTxtFileError> {
-        let content =
+        Self::parse_with_variables(requirements_txt, working_dir, None)
+    }
+
+    /// Like [RequirementsTxt::parse], but `${VAR}` is looked up in `variables` instead of the
+    /// environment if given, e.g. for testing
+    pub fn parse_with_variables(
+        requirements_txt: impl AsRef<Path>,
+        working_dir: impl AsRef<Path>,
+        variables: Option<&HashMap<String, String>>,
+    ) -> Result<Self, RequirementsTxtFileError> {
+        Self::parse_file(requirements_txt, working_dir, variables, false, &[])
+    }
+
+    /// Like [RequirementsTxt::parse], but also records the location, original text and comments
+    /// of each entry in [RequirementEntry::span], for formatters, linters and diagnostics
+    pub fn parse_lossless(
+        requirements_txt: impl AsRef<Path>,
+        working_dir: impl AsRef<Path>,
+    ) -> Result<Self, RequirementsTxtFileError> {
+        Self::parse_file(requirements_txt, working_dir, None, true, &[])
+    }
+
+    fn parse_file(
+        requirements_txt: impl AsRef<Path>,
+        working_dir: impl AsRef<Path>,
+        variables: Option<&HashMap<String, String>>,
+        lossless: bool,
+        includes: &[PathBuf],
+    ) -> Result<Self, RequirementsTxtFileError> {
+        let content = if requirements_txt.as_ref() == Path::new("-") {
+            read_content(io::stdin().lock(), &requirements_txt)?
+        } else {
             fs::read_to_string(&requirements_txt).map_err(|err| RequirementsTxtFileError {
                 file: requirements_txt.as_ref().to_path_buf(),
                 error: RequirementsTxtParserError::IO(err),
-            })?;
-        let data =
-            Self::parse_inner(&content, working_dir).map_err(|err| RequirementsTxtFileError {
-                file: requirements_txt.as_ref().to_path_buf(),
+            })?
+        };
+        Self::parse_content(
+            &content,
+            requirements_txt,
+            working_dir,
+            variables,
+            lossless,
+            includes,
+        )
+    }
+
+    /// Reads all of `reader` and parses it, e.g. for requirements piped in by a wrapper.
+    /// `name` is only used for error messages
+    pub fn parse_reader(
+        reader: impl Read,
+        name: impl AsRef<Path>,
+        working_dir: impl AsRef<Path>,
+    ) -> Result<Self, RequirementsTxtFileError> {
+        let content = read_content(reader, &name)?;
+        Self::parse_content(&content, name, working_dir, None, false, &[])
+    }
+
+    /// Parses requirements that are already in memory. `name` is only used for error messages
+    pub fn parse_str(
+        content: &str,
+        name: impl AsRef<Path>,
+        working_dir: impl AsRef<Path>,
+    ) -> Result<Self, RequirementsTxtFileError> {
+        Self::parse_content(content, name, working_dir, None, false, &[])
+    }
+
+    fn parse_content(
+        content: &str,
+        name: impl AsRef<Path>,
+        working_dir: impl AsRef<Path>,
+        variables: Option<&HashMap<String, String>>,
+        lossless: bool,
+        includes: &[PathBuf],
+    ) -> Result<Self, RequirementsTxtFileError> {
+        let span_file = lossless.then(|| name.as_ref());
+        let includes = [includes, &[name.as_ref().to_path_buf()]].concat();
+        let data = Self::parse_impl(content, working_dir, variables, span_file, &includes)
+            .map_err(|err| RequirementsTxtFileError {
+                file: name.as_ref().to_path_buf(),
                 error: err,
             })?;
         if data == Self::default() {
             warn!(
                 "Requirements file {} does not contain any dependencies",
-                requirements_txt.as_ref().display()
+                name.as_ref().display()
             );
         }
         Ok(data)
@@ -126,15 +319,30 @@ impl RequirementsTxt {
     /// See module level documentation
     ///
     /// Note that all relative paths are dependent on the current working dir, not on the location
-    /// of the file
+    /// of the file. `${VAR}` is expanded from `variables` or, if `None`, from the environment.
     pub fn parse_inner(
         content: &str,
         working_dir: impl AsRef<Path>,
+        variables: Option<&HashMap<String, String>>,
+    ) -> Result<Self, RequirementsTxtParserError> {
+        Self::parse_impl(content, working_dir, variables, None, &[])
+    }
+
+    /// If `span_file` is given, we record spans with that file name for all entries. `includes` is
+    /// the chain of files that included this one, ending with this file, to detect cycles
+    fn parse_impl(
+        content: &str,
+        working_dir: impl AsRef<Path>,
+        variables: Option<&HashMap<String, String>>,
+        span_file: Option<&Path>,
+        includes: &[PathBuf],
     ) -> Result<Self, RequirementsTxtParserError> {
         let mut s = Scanner::new(content);
 
         let mut data = Self::default();
-        while let Some(statement) = parse_entry(&mut s, content)? {
+        while let Some(statement) =
+            parse_entry(&mut s, content, working_dir.as_ref(), variables, span_file)?
+        {
             match statement {
                 RequirementsTxtStatement::Requirements {
                     filename,
@@ -142,14 +350,19 @@ impl RequirementsTxt {
                     end,
                 } => {
                     let sub_file = working_dir.as_ref().join(filename);
-                    let sub_requirements =
-                        Self::parse(&sub_file, working_dir.as_ref()).map_err(|err| {
-                            RequirementsTxtParserError::Subfile {
-                                source: Box::new(err),
-                                start,
-                                end,
-                            }
-                        })?;
+                    check_circular_include(includes, &sub_file, start, end)?;
+                    let sub_requirements = Self::parse_file(
+                        &sub_file,
+                        working_dir.as_ref(),
+                        variables,
+                        span_file.is_some(),
+                        includes,
+                    )
+                    .map_err(|err| RequirementsTxtParserError::Subfile {
+                        source: Box::new(err),
+                        start,
+                        end,
+                    })?;
                     // Add each to the correct category
                     data.update_from(sub_requirements);
                 }
@@ -159,14 +372,19 @@ impl RequirementsTxt {
                     end,
                 } => {
                     let sub_file = working_dir.as_ref().join(filename);
-                    let sub_constraints =
-                        Self::parse(&sub_file, working_dir.as_ref()).map_err(|err| {
-                            RequirementsTxtParserError::Subfile {
-                                source: Box::new(err),
-                                start,
-                                end,
-                            }
-                        })?;
+                    check_circular_include(includes, &sub_file, start, end)?;
+                    let sub_constraints = Self::parse_file(
+                        &sub_file,
+                        working_dir.as_ref(),
+                        variables,
+                        span_file.is_some(),
+                        includes,
+                    )
+                    .map_err(|err| RequirementsTxtParserError::Subfile {
+                        source: Box::new(err),
+                        start,
+                        end,
+                    })?;
                     // Here we add both to constraints
                     data.constraints.extend(
                         sub_constraints
@@ -175,9 +393,22 @@ impl RequirementsTxt {
                             .map(|requirement_entry| requirement_entry.requirement),
                     );
                     data.constraints.extend(sub_constraints.constraints);
+                    data.index_options
+                        .update_from(sub_constraints.index_options);
+                }
+                RequirementsTxtStatement::IndexOption(option) => {
+                    let index_options = &mut data.index_options;
+                    match option {
+                        IndexOption::IndexUrl(url) => index_options.index_url = Some(url),
+                        IndexOption::ExtraIndexUrl(url) => index_options.extra_index_urls.push(url),
+                        IndexOption::FindLinks(url) => index_options.find_links.push(url),
+                        IndexOption::NoIndex => index_options.no_index = true,
+                        IndexOption::TrustedHost(host) => index_options.trusted_hosts.push(host),
+                        IndexOption::Pre => index_options.pre = true,
+                    }
                 }
                 RequirementsTxtStatement::RequirementEntry(requirement_entry) => {
-                    data.requirements.push(requirement_entry);
+                    data.requirements.push(*requirement_entry);
                 }
             }
         }
@@ -188,9 +419,58 @@ impl RequirementsTxt {
     pub fn update_from(&mut self, other: RequirementsTxt) {
         self.requirements.extend(other.requirements);
         self.constraints.extend(other.constraints);
+        self.index_options.update_from(other.index_options);
     }
 }
 
+/// Reads requirements from e.g. stdin
+fn read_content(
+    mut reader: impl Read,
+    name: impl AsRef<Path>,
+) -> Result<String, RequirementsTxtFileError> {
+    let mut content = String::new();
+    reader
+        .read_to_string(&mut content)
+        .map_err(|err| RequirementsTxtFileError {
+            file: name.as_ref().to_path_buf(),
+            error: RequirementsTxtParserError::IO(err),
+        })?;
+    Ok(content)
+}
+
+/// Replaces `${VAR}` like pip does: Only uppercase letters, digits and underscores are allowed in
+/// the name and unset variables are kept verbatim. We only expand the parsed values, so the
+/// positions always refer to the file as written
+fn expand_variables(content: &str, variables: Option<&HashMap<String, String>>) -> String {
+    let mut expanded = String::with_capacity(content.len());
+    let mut rest = content;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let name_len = after
+            .find(|c: char| !(c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'))
+            .unwrap_or(after.len());
+        let name = &after[..name_len];
+        let value = if !name.is_empty() && after[name_len..].starts_with('}') {
+            match variables {
+                Some(variables) => variables.get(name).cloned(),
+                None => env::var(name).ok(),
+            }
+        } else {
+            None
+        };
+        if let Some(value) = value {
+            expanded.push_str(&value);
+            rest = &after[name_len + 1..];
+        } else {
+            expanded.push_str("${");
+            rest = after;
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
 /// Parse a single entry, that is a requirement, an inclusion or a comment line
 ///
 /// Consumes all preceding trivia (whitespace and comments). If it returns None, we've reached
@@ -198,22 +478,49 @@ impl RequirementsTxt {
 fn parse_entry(
     s: &mut Scanner,
     content: &str,
+    working_dir: &Path,
+    variables: Option<&HashMap<String, String>>,
+    span_file: Option<&Path>,
 ) -> Result<Option<RequirementsTxtStatement>, RequirementsTxtParserError> {
     // Eat all preceding whitespace, this may run us to the end of file
     eat_wrappable_whitespace(s);
+    // Comment lines directly above the entry, a blank line detaches them
+    let mut comments = Vec::new();
     while s.at(['\n', '\r', '#']) {
+        if s.at('#') {
+            comments.push(comment_text(s.eat_until(['\r', '\n'])));
+        } else {
+            comments.clear();
+        }
         // skip comments
         eat_trailing_line(s)?;
         eat_wrappable_whitespace(s);
     }
 
     let start = s.cursor();
+    let mut statement = parse_statement(s, content, working_dir, variables, start)?;
+    if let (Some(span_file), Some(RequirementsTxtStatement::RequirementEntry(entry))) =
+        (span_file, &mut statement)
+    {
+        entry.span = Some(entry_span(span_file, content, start, comments));
+    }
+    Ok(statement)
+}
+
+/// Parse the statement starting at `start`, after the trivia
+fn parse_statement(
+    s: &mut Scanner,
+    content: &str,
+    working_dir: &Path,
+    variables: Option<&HashMap<String, String>>,
+    start: usize,
+) -> Result<Option<RequirementsTxtStatement>, RequirementsTxtParserError> {
     Ok(Some(if s.eat_if("-r") {
         let requirements_file = parse_value(s, |c: char| !['\n', '\r', '#'].contains(&c))?;
         let end = s.cursor();
         eat_trailing_line(s)?;
         RequirementsTxtStatement::Requirements {
-            filename: requirements_file.to_string(),
+            filename: expand_variables(requirements_file, variables),
             start,
             end,
         }
@@ -222,28 +529,50 @@ fn parse_entry(
         let end = s.cursor();
         eat_trailing_line(s)?;
         RequirementsTxtStatement::Constraint {
-            filename: constraints_file.to_string(),
+            filename: expand_variables(constraints_file, variables),
             start,
             end,
         }
+    } else if let Some(option) = parse_index_option(s, variables)? {
+        eat_trailing_line(s)?;
+        RequirementsTxtStatement::IndexOption(option)
     } else if s.eat_if("-e") {
-        let (requirement, hashes) = parse_requirement_and_hashes(s, content)?;
-        RequirementsTxtStatement::RequirementEntry(RequirementEntry {
-            requirement,
-            hashes,
-            editable: true,
-        })
-    } else if s.at(char::is_ascii_alphanumeric) {
-        let (requirement, hashes) = parse_requirement_and_hashes(s, content)?;
-        RequirementsTxtStatement::RequirementEntry(RequirementEntry {
-            requirement,
-            hashes,
-            editable: false,
-        })
+        let (requirement, options) = parse_requirement_and_options(s, content, variables)?;
+        RequirementsTxtStatement::RequirementEntry(Box::new(
+            options.into_entry(requirement, RequirementSource::Editable),
+        ))
+    } else if is_path_or_url(&expand_variables(
+        s.after()
+            .split(char::is_whitespace)
+            .next()
+            .unwrap_or_default(),
+        variables,
+    )) {
+        let location = expand_variables(s.eat_until(char::is_whitespace), variables);
+        let end = s.cursor();
+        let (requirement, source) = parse_path_or_url(&location, working_dir, start, end)?;
+        let options = if !eat_wrappable_whitespace(s).is_empty() && s.after().starts_with("--") {
+            parse_requirement_options(s, variables)?
+        } else {
+            RequirementOptions::default()
+        };
+        eat_trailing_line(s)?;
+        RequirementsTxtStatement::RequirementEntry(Box::new(
+            options.into_entry(requirement, source),
+        ))
+    } else if s.at(char::is_ascii_alphanumeric) || s.at("${") {
+        let (requirement, options) = parse_requirement_and_options(s, content, variables)?;
+        let source = match &requirement.version_or_url {
+            Some(VersionOrUrl::Url(url)) => RequirementSource::Url(url.to_string()),
+            _ => RequirementSource::Registry,
+        };
+        RequirementsTxtStatement::RequirementEntry(Box::new(
+            options.into_entry(requirement, source),
+        ))
     } else if let Some(char) = s.peek() {
         return Err(RequirementsTxtParserError::Parser {
             message: format!(
-                "Unexpected '{}', expected '-c', '-e', '-r' or the start of a requirement",
+                "Unexpected '{}', expected '-c', '-e', '-r', an index option or the start of a requirement",
                 char
             ),
             location: s.cursor(),
@@ -254,6 +583,195 @@ fn parse_entry(
     }))
 }
 
+/// Errors if `sub_file` is already in the chain of `includes`, since we would recurse forever
+fn check_circular_include(
+    includes: &[PathBuf],
+    sub_file: &Path,
+    start: usize,
+    end: usize,
+) -> Result<(), RequirementsTxtParserError> {
+    let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let sub_file_canonical = canonical(sub_file);
+    if let Some(position) = includes
+        .iter()
+        .position(|include| canonical(include) == sub_file_canonical)
+    {
+        let mut cycle = includes[position..].to_vec();
+        cycle.push(sub_file.to_path_buf());
+        return Err(RequirementsTxtParserError::CircularInclude { cycle, start, end });
+    }
+    Ok(())
+}
+
+/// `# some comment` -> `some comment`
+fn comment_text(comment: &str) -> String {
+    comment.trim_start_matches('#').trim().to_string()
+}
+
+/// Computes the span of the entry at `start`, which ends at the end of its (possibly continued)
+/// line
+fn entry_span(file: &Path, content: &str, start: usize, mut comments: Vec<String>) -> EntrySpan {
+    let mut end = start;
+    let rest = &content[start..];
+    let mut lines = rest.split_inclusive('\n');
+    for line in lines.by_ref() {
+        end += line.len();
+        if !line.trim_end_matches(['\r', '\n']).ends_with('\\') {
+            break;
+        }
+    }
+    let text = content[start..end]
+        .trim_end_matches(['\r', '\n'])
+        .to_string();
+    let end = start + text.len();
+    // Like pip, `#` only starts a comment at the start of a line or after whitespace, so
+    // `#sha256=...` in urls stays intact
+    let trailing_comment = text
+        .char_indices()
+        .find(|&(index, c)| c == '#' && text[..index].ends_with(|c: char| c.is_whitespace()))
+        .map(|(index, _)| comment_text(&text[index..]));
+    comments.extend(trailing_comment);
+    let before = &content[..start];
+    let line_start = before.rfind('\n').map(|index| index + 1).unwrap_or(0);
+    EntrySpan {
+        file: file.to_path_buf(),
+        line: before.matches('\n').count() + 1,
+        column: before[line_start..].chars().count() + 1,
+        start,
+        end,
+        text,
+        comments,
+    }
+}
+
+/// Parses `--index-url` and the other [IndexOptions] if the line starts with one
+fn parse_index_option(
+    s: &mut Scanner,
+    variables: Option<&HashMap<String, String>>,
+) -> Result<Option<IndexOption>, RequirementsTxtParserError> {
+    let not_end = |c: char| !['\n', '\r', '#'].contains(&c);
+    let option = if eat_option(s, "--index-url") || eat_option(s, "-i") {
+        IndexOption::IndexUrl(expand_variables(parse_value(s, not_end)?, variables))
+    } else if eat_option(s, "--extra-index-url") {
+        IndexOption::ExtraIndexUrl(expand_variables(parse_value(s, not_end)?, variables))
+    } else if eat_option(s, "--find-links") || eat_option(s, "-f") {
+        IndexOption::FindLinks(expand_variables(parse_value(s, not_end)?, variables))
+    } else if eat_option(s, "--trusted-host") {
+        IndexOption::TrustedHost(expand_variables(parse_value(s, not_end)?, variables))
+    } else if eat_option(s, "--no-index") {
+        IndexOption::NoIndex
+    } else if eat_option(s, "--pre") {
+        IndexOption::Pre
+    } else {
+        return Ok(None);
+    };
+    Ok(Some(option))
+}
+
+/// Eats `name` if it's followed by `=`, whitespace or the end of the line, so that e.g. `--pre`
+/// doesn't match `--prefer-binary`
+fn eat_option(s: &mut Scanner, name: &str) -> bool {
+    let rest = match s.after().strip_prefix(name) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    if rest.is_empty() || rest.starts_with(|c: char| c == '=' || c == '#' || c.is_whitespace()) {
+        s.jump(s.cursor() + name.len());
+        true
+    } else {
+        false
+    }
+}
+
+/// Whether the line starts with a bare url or path instead of a PEP 508 requirement
+fn is_path_or_url(line: &str) -> bool {
+    let token = line.split(char::is_whitespace).next().unwrap_or_default();
+    let mut chars = token.chars();
+    URL_SCHEMES.iter().any(|scheme| token.starts_with(scheme))
+        || token.starts_with(['.', '/', '\\'])
+        // Windows drive letter
+        || (chars.next().is_some_and(|c| c.is_ascii_alphabetic()) && chars.next() == Some(':'))
+        // `pkgs/foo-1.0-py3-none-any.whl`, but not `foo @ https://.../foo-1.0.tar.gz`
+        || (ARCHIVE_EXTENSIONS.iter().any(|ext| token.ends_with(ext))
+            && !token.contains(['@', '=', '<', '>', ';', '[']))
+}
+
+/// Turns a bare url or path into a `<name> @ <url>` requirement, taking the name from the filename
+fn parse_path_or_url(
+    location: &str,
+    working_dir: &Path,
+    start: usize,
+    end: usize,
+) -> Result<(Requirement, RequirementSource), RequirementsTxtParserError> {
+    let (url, source) = if location.starts_with("http://") || location.starts_with("https://") {
+        (
+            location.to_string(),
+            RequirementSource::Url(location.to_string()),
+        )
+    } else {
+        let path = match location.strip_prefix("file://") {
+            Some(path) => PathBuf::from(path),
+            None => working_dir.join(location),
+        };
+        let absolute = if path.is_absolute() {
+            path.clone()
+        } else {
+            env::current_dir()
+                .map_err(RequirementsTxtParserError::IO)?
+                .join(&path)
+        };
+        let absolute = absolute.to_string_lossy().replace('\\', "/");
+        let url = if absolute.starts_with('/') {
+            format!("file://{}", absolute)
+        } else {
+            // Windows paths such as `C:/...`
+            format!("file:///{}", absolute)
+        };
+        (url, RequirementSource::Path(path))
+    };
+    // Strip e.g. `#sha256=...` or `?query` from the url
+    let filename = url
+        .split(['#', '?'])
+        .next()
+        .unwrap_or_default()
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+    let name = distribution_name(filename).ok_or_else(|| RequirementsTxtParserError::Parser {
+        message: format!(
+            "Can't determine the package name of '{}', expected a wheel or source distribution \
+            filename. Use `<name> @ {}` instead",
+            location, location
+        ),
+        location: start,
+    })?;
+    let requirement = Requirement::from_str(&format!("{} @ {}", name, url)).map_err(|err| {
+        RequirementsTxtParserError::Pep508 {
+            source: err,
+            start,
+            end,
+        }
+    })?;
+    Ok((requirement, source))
+}
+
+/// `foo-1.0-py3-none-any.whl` and `foo-1.0.tar.gz` -> `foo`
+fn distribution_name(filename: &str) -> Option<&str> {
+    let name = if let Some(stem) = filename.strip_suffix(".whl") {
+        stem.split('-').next()?
+    } else {
+        let stem = filename
+            .strip_suffix(".tar.gz")
+            .or_else(|| filename.strip_suffix(".zip"))?;
+        stem.rsplit_once('-')?.0
+    };
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
 /// Eat whitespace and ignore newlines escaped with a backslash
 fn eat_wrappable_whitespace<'a>(s: &mut Scanner<'a>) -> &'a str {
     let start = s.cursor();
@@ -290,15 +808,16 @@ fn eat_trailing_line(s: &mut Scanner) -> Result<(), RequirementsTxtParserError>
     Ok(())
 }
 
-/// Parse a PEP 508 requirement with optional trailing hashes
-fn parse_requirement_and_hashes(
+/// Parse a PEP 508 requirement with optional trailing hashes and build options
+fn parse_requirement_and_options(
     s: &mut Scanner,
     content: &str,
-) -> Result<(Requirement, Vec<String>), RequirementsTxtParserError> {
+    variables: Option<&HashMap<String, String>>,
+) -> Result<(Requirement, RequirementOptions), RequirementsTxtParserError> {
     // PEP 508 requirement
     let start = s.cursor();
     // Termination: s.eat() eventually becomes None
-    let (end, has_hashes) = loop {
+    let (end, has_options) = loop {
         let end = s.cursor();
 
         //  We look for the end of the line ...
@@ -309,7 +828,7 @@ fn parse_requirement_and_hashes(
             s.eat_if('\n'); // Support `\r\n` but also accept stray `\r`
             break (end, false);
         }
-        // ... or `--hash`, an escaped newline or a comment separated by whitespace ...
+        // ... or an option like `--hash`, an escaped newline or a comment separated by whitespace ...
         if !eat_wrappable_whitespace(s).is_empty() {
             if s.after().starts_with("--") {
                 break (end, true);
@@ -328,46 +847,79 @@ fn parse_requirement_and_hashes(
             break (end, false);
         }
     };
-    let requirement = Requirement::from_str(&content[start..end]).map_err(|err| {
-        RequirementsTxtParserError::Pep508 {
+    let requirement = Requirement::from_str(&expand_variables(&content[start..end], variables))
+        .map_err(|err| RequirementsTxtParserError::Pep508 {
             source: err,
             start,
             end,
-        }
-    })?;
-    let hashes = if has_hashes {
-        let hashes = parse_hashes(s)?;
+        })?;
+    let options = if has_options {
+        let options = parse_requirement_options(s, variables)?;
         eat_trailing_line(s)?;
-        hashes
+        options
     } else {
-        Vec::new()
+        RequirementOptions::default()
     };
-    Ok((requirement, hashes))
+    Ok((requirement, options))
 }
 
-/// Parse `--hash=... --hash ...` after a requirement
-fn parse_hashes(s: &mut Scanner) -> Result<Vec<String>, RequirementsTxtParserError> {
-    let mut hashes = Vec::new();
-    if s.eat_while("--hash").is_empty() {
-        return Err(RequirementsTxtParserError::Parser {
-            message: format!(
-                "Expected '--hash', found '{:?}'",
-                s.eat_while(|c: char| !c.is_whitespace())
-            ),
-            location: s.cursor(),
-        });
+/// The options that can follow a requirement on the same line
+#[derive(Default)]
+struct RequirementOptions {
+    hashes: Vec<String>,
+    global_options: Vec<String>,
+    config_settings: Vec<String>,
+}
+
+impl RequirementOptions {
+    fn into_entry(self, requirement: Requirement, source: RequirementSource) -> RequirementEntry {
+        RequirementEntry {
+            requirement,
+            hashes: self.hashes,
+            source,
+            global_options: self.global_options,
+            config_settings: self.config_settings,
+            span: None,
+        }
     }
-    let hash = parse_value(s, |c: char| !c.is_whitespace())?;
-    hashes.push(hash.to_string());
+}
+
+/// Parse `--hash=... --global-option ... --config-settings ...` after a requirement
+fn parse_requirement_options(
+    s: &mut Scanner,
+    variables: Option<&HashMap<String, String>>,
+) -> Result<RequirementOptions, RequirementsTxtParserError> {
+    let mut options = RequirementOptions::default();
+    let mut first = true;
     loop {
-        eat_wrappable_whitespace(s);
-        if !s.eat_if("--hash") {
+        let values = if eat_option(s, "--hash") {
+            &mut options.hashes
+        } else if eat_option(s, "--global-option") {
+            &mut options.global_options
+        } else if eat_option(s, "--config-settings") {
+            &mut options.config_settings
+        } else if first {
+            return Err(RequirementsTxtParserError::Parser {
+                message: format!(
+                    "Expected '--hash', '--global-option' or '--config-settings', found '{:?}'",
+                    s.eat_while(|c: char| !c.is_whitespace())
+                ),
+                location: s.cursor(),
+            });
+        } else {
             break;
-        }
-        let hash = parse_value(s, |c: char| !c.is_whitespace())?;
-        hashes.push(hash.to_string());
+        };
+        let value = parse_value(s, |c: char| !c.is_whitespace())?;
+        // `--global-option="--no-user-cfg"`
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+        values.push(expand_variables(value, variables));
+        first = false;
+        eat_wrappable_whitespace(s);
     }
-    Ok(hashes)
+    Ok(options)
 }
 
 /// In `-<key>=<value>` or `-<key> value`, this parses the part after the key
@@ -415,6 +967,12 @@ pub enum RequirementsTxtParserError {
         start: usize,
         end: usize,
     },
+    /// `-r`/`-c` of a file that is already being parsed, `cycle` starts and ends with that file
+    CircularInclude {
+        cycle: Vec<PathBuf>,
+        start: usize,
+        end: usize,
+    },
 }
 
 impl Display for RequirementsTxtFileError {
@@ -448,10 +1006,84 @@ impl Display for RequirementsTxtFileError {
                     end
                 )
             }
+            RequirementsTxtParserError::CircularInclude { cycle, start, end } => {
+                write!(
+                    f,
+                    "Circular include in {} at position {} to {}: {}",
+                    self.file.display(),
+                    start,
+                    end,
+                    cycle
+                        .iter()
+                        .map(|path| path.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(" -> ")
+                )
+            }
+        }
+    }
+}
+
+impl RequirementsTxtFileError {
+    /// The error in the file where it happened, following the `-r`/`-c` includes
+    fn innermost(&self) -> &Self {
+        match &self.error {
+            RequirementsTxtParserError::Subfile { source, .. } => source.innermost(),
+            _ => self,
+        }
+    }
+
+    /// A stable identifier of the kind of error for tools that wrap us, e.g.
+    /// `invalid-requirement`. Errors in included files have the code of the error in that file
+    pub fn code(&self) -> &'static str {
+        match &self.innermost().error {
+            RequirementsTxtParserError::IO(_) => "io",
+            RequirementsTxtParserError::Parser { .. } => "parse-error",
+            RequirementsTxtParserError::Pep508 { .. } => "invalid-requirement",
+            RequirementsTxtParserError::CircularInclude { .. } => "circular-include",
+            RequirementsTxtParserError::Subfile { .. } => unreachable!("resolved by innermost"),
+        }
+    }
+
+    /// The byte range in the file of [Self::innermost], if the error has a position
+    fn span(&self) -> Option<(usize, usize)> {
+        match &self.error {
+            RequirementsTxtParserError::IO(_) => None,
+            RequirementsTxtParserError::Parser { location, .. } => Some((*location, *location)),
+            RequirementsTxtParserError::Pep508 { start, end, .. }
+            | RequirementsTxtParserError::Subfile { start, end, .. }
+            | RequirementsTxtParserError::CircularInclude { start, end, .. } => {
+                Some((*start, *end))
+            }
         }
     }
 }
 
+/// `{"code": ..., "message": ..., "causes": [...], "file": ..., "start": ..., "end": ...}`, where
+/// file and position are those of the innermost error
+impl Serialize for RequirementsTxtFileError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let innermost = self.innermost();
+        let mut causes = Vec::new();
+        let mut current: &dyn std::error::Error = self;
+        while let Some(source) = current.source() {
+            causes.push(source.to_string());
+            current = source;
+        }
+        let (start, end) = innermost.span().unzip();
+        let mut state = serializer.serialize_struct("RequirementsTxtFileError", 6)?;
+        state.serialize_field("code", self.code())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.serialize_field("causes", &causes)?;
+        state.serialize_field("file", &innermost.file)?;
+        state.serialize_field("start", &start)?;
+        state.serialize_field("end", &end)?;
+        state.end()
+    }
+}
+
 impl std::error::Error for RequirementsTxtFileError {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match &self.error {
@@ -465,10 +1097,13 @@ impl std::error::Error for RequirementsTxtFileError {
 
 #[cfg(test)]
 mod test {
-    use crate::requirements_txt::RequirementsTxt;
+    use crate::requirements_txt::{
+        EntrySpan, IndexOptions, RequirementSource, RequirementsTxt, RequirementsTxtFileError,
+    };
     use fs_err as fs;
     use indoc::indoc;
-    use std::path::PathBuf;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
     use tempfile::tempdir;
 
     #[test]
@@ -564,6 +1199,51 @@ mod test {
         // The last error message is os specific
     }
 
+    #[test]
+    fn test_circular_include() {
+        let working_dir = workspace_test_data_dir().join("requirements-txt");
+        let a = working_dir.join("circular-include-a");
+        let b = working_dir.join("circular-include-b");
+        let err = RequirementsTxt::parse(&a, &working_dir).unwrap_err();
+        let errors = anyhow::Error::new(err)
+            .chain()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            [
+                format!(
+                    "Error parsing file included into {} at position 0 to 21",
+                    a.display()
+                ),
+                format!(
+                    "Circular include in {} at position 5 to 26: {} -> {} -> {}",
+                    b.display(),
+                    a.display(),
+                    b.display(),
+                    a.display()
+                )
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_json() {
+        let working_dir = workspace_test_data_dir().join("requirements-txt");
+        let a = working_dir.join("circular-include-a");
+        let b = working_dir.join("circular-include-b");
+        let err = RequirementsTxt::parse(&a, &working_dir).unwrap_err();
+        assert_eq!(err.code(), "circular-include");
+        let json = serde_json::to_value(&err).unwrap();
+        assert_eq!(json["code"], "circular-include");
+        assert_eq!(json["file"], b.display().to_string());
+        assert_eq!(
+            (json["start"].clone(), json["end"].clone()),
+            (5.into(), 26.into())
+        );
+        assert_eq!(json["causes"].as_array().unwrap().len(), 1);
+    }
+
     #[test]
     fn test_invalid_requirement() {
         let working_dir = workspace_test_data_dir().join("requirements-txt");
@@ -588,7 +1268,211 @@ mod test {
         assert_eq!(errors, expected)
     }
 
+    #[test]
+    fn test_bare_paths() {
+        let working_dir = workspace_test_data_dir().join("requirements-txt");
+        let content = indoc! {"
+            ./pkgs/foo_bar-1.0-py3-none-any.whl
+            pkgs/baz-qux-2.0.tar.gz --hash=sha256:abc
+            file:///opt/wheels/tqdm-4.65.0-py3-none-any.whl
+        "};
+        let requirements = RequirementsTxt::parse_inner(content, &working_dir, None).unwrap();
+        let actual: Vec<_> = requirements
+            .requirements
+            .iter()
+            .map(|entry| {
+                (
+                    entry.requirement.name.as_str(),
+                    entry.source.clone(),
+                    entry.hashes.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            actual,
+            [
+                (
+                    "foo_bar",
+                    RequirementSource::Path(
+                        working_dir.join("./pkgs/foo_bar-1.0-py3-none-any.whl")
+                    ),
+                    vec![]
+                ),
+                (
+                    "baz-qux",
+                    RequirementSource::Path(working_dir.join("pkgs/baz-qux-2.0.tar.gz")),
+                    vec!["sha256:abc".to_string()]
+                ),
+                (
+                    "tqdm",
+                    RequirementSource::Path(PathBuf::from(
+                        "/opt/wheels/tqdm-4.65.0-py3-none-any.whl"
+                    )),
+                    vec![]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bare_path_directory() {
+        let err = RequirementsTxt::parse_inner("./pkgs/foo\n", Path::new("."), None).unwrap_err();
+        let err = RequirementsTxtFileError {
+            file: PathBuf::from("requirements.txt"),
+            error: err,
+        };
+        assert_eq!(
+            err.to_string(),
+            "Can't determine the package name of './pkgs/foo', expected a wheel or source \
+            distribution filename. Use `<name> @ ./pkgs/foo` instead in requirements.txt position 0"
+        );
+    }
+
     fn workspace_test_data_dir() -> PathBuf {
         PathBuf::from("../../test-data")
     }
+
+    #[test]
+    fn test_index_options() {
+        let content = indoc! {"
+            --no-index
+            --find-links=./wheels
+            -i https://pypi.internal/simple
+        "};
+        let requirements = RequirementsTxt::parse_inner(content, Path::new("."), None).unwrap();
+        let expected = IndexOptions {
+            index_url: Some("https://pypi.internal/simple".to_string()),
+            find_links: vec!["./wheels".to_string()],
+            no_index: true,
+            ..IndexOptions::default()
+        };
+        assert_eq!(requirements.index_options, expected);
+
+        // `--pre` must not match a prefix of an unsupported option
+        let err = RequirementsTxt::parse_str("--prefer-binary\n", "<generated>", Path::new("."))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unexpected '-', expected '-c', '-e', '-r', an index option or the start of a requirement in <generated> position 0"
+        );
+    }
+
+    #[test]
+    fn test_variables() {
+        let content = indoc! {"
+            --index-url https://${TOKEN}@pypi.internal/simple
+            --extra-index-url https://${UNSET}@pypi.internal/${lowercase}
+            tqdm==${TQDM_VERSION}
+        "};
+        let variables = HashMap::from([
+            ("TOKEN".to_string(), "secret".to_string()),
+            ("TQDM_VERSION".to_string(), "4.65.0".to_string()),
+        ]);
+        let requirements =
+            RequirementsTxt::parse_inner(content, Path::new("."), Some(&variables)).unwrap();
+        assert_eq!(
+            requirements.index_options.index_url.as_deref(),
+            Some("https://secret@pypi.internal/simple")
+        );
+        // Unset and invalid variables are kept as is
+        assert_eq!(
+            requirements.index_options.extra_index_urls,
+            ["https://${UNSET}@pypi.internal/${lowercase}"]
+        );
+        assert_eq!(requirements.requirements[0].to_string(), "tqdm ==4.65.0");
+
+        // The lossless spans keep the text as written
+        let requirements = RequirementsTxt::parse_impl(
+            content,
+            Path::new("."),
+            Some(&variables),
+            Some(Path::new("requirements.txt")),
+            &[],
+        )
+        .unwrap();
+        let entry = &requirements.requirements[0];
+        assert_eq!(entry.to_string(), "tqdm ==4.65.0");
+        let span = entry.span.as_ref().unwrap();
+        assert_eq!(span.text, "tqdm==${TQDM_VERSION}");
+        assert_eq!(&content[span.start..span.end], span.text);
+    }
+
+    #[test]
+    fn test_parse_reader() {
+        let working_dir = Path::new("../../test-data").join("requirements-txt");
+        let content = "numpy==1.24.2\n-r basic.txt\n";
+        let from_reader =
+            RequirementsTxt::parse_reader(content.as_bytes(), "<generated>", &working_dir).unwrap();
+        let from_str = RequirementsTxt::parse_str(content, "<generated>", &working_dir).unwrap();
+        assert_eq!(from_reader, from_str);
+        assert_eq!(from_reader.requirements[0].requirement.name, "numpy");
+        assert!(from_reader.requirements.len() > 1);
+
+        let err = RequirementsTxt::parse_str("-e\n", "<generated>", &working_dir).unwrap_err();
+        assert_eq!(err.file, Path::new("<generated>"));
+    }
+
+    #[test]
+    fn test_parse_lossless() {
+        let temp_dir = tempdir().unwrap();
+        let requirements_txt = temp_dir.path().join("requirements.txt");
+        let included = temp_dir.path().join("included.txt");
+        fs::write(
+            &requirements_txt,
+            indoc! {"
+                # detached comment
+
+                # the numpy comment
+                numpy==1.24.2 # pinned for abi
+                  tqdm==4.65.0 \\
+                    --hash=sha256:abc
+                -r included.txt
+            "},
+        )
+        .unwrap();
+        fs::write(&included, "six==1.16.0\n").unwrap();
+
+        let requirements =
+            RequirementsTxt::parse_lossless(&requirements_txt, temp_dir.path()).unwrap();
+        let spans: Vec<EntrySpan> = requirements
+            .requirements
+            .iter()
+            .map(|entry| entry.span.clone().unwrap())
+            .collect();
+        assert_eq!(
+            spans[0],
+            EntrySpan {
+                file: requirements_txt.clone(),
+                line: 4,
+                column: 1,
+                start: 40,
+                end: 70,
+                text: "numpy==1.24.2 # pinned for abi".to_string(),
+                comments: vec![
+                    "the numpy comment".to_string(),
+                    "pinned for abi".to_string()
+                ],
+            }
+        );
+        assert_eq!(
+            spans[1],
+            EntrySpan {
+                file: requirements_txt,
+                line: 5,
+                column: 3,
+                start: 73,
+                end: 109,
+                text: "tqdm==4.65.0 \\\n    --hash=sha256:abc".to_string(),
+                comments: vec![],
+            }
+        );
+        assert_eq!(
+            (spans[2].file.as_path(), spans[2].line),
+            (included.as_path(), 1)
+        );
+
+        // The regular parse doesn't record spans
+        let requirements = RequirementsTxt::parse(&included, temp_dir.path()).unwrap();
+        assert_eq!(requirements.requirements[0].span, None);
+    }
 }
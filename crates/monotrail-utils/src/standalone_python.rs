@@ -1,17 +1,20 @@
 //! Download and install standalone python builds (PyOxy) from
 //! <https://github.com/indygreg/python-build-standalone>
 
+use crate::transport::{get_json, Transport};
 use anyhow::{bail, Context};
+use data_encoding::HEXLOWER;
 use fs2::FileExt;
 use fs_err as fs;
 use fs_err::File;
+use install_wheel_rs::{hash_backend, HashPurpose, IncrementalHash};
 use regex::Regex;
 use serde::Deserialize;
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 use tempfile::tempdir_in;
 use tracing::{debug, info, warn};
 
-#[cfg_attr(test, allow(dead_code))]
 const GITHUB_API: &str = "https://api.github.com";
 
 const PYTHON_STANDALONE_LATEST_RELEASE: (&str, &str) = (
@@ -42,28 +45,59 @@ struct GitHubAsset {
     browser_download_url: String,
 }
 
-/// Returns the url of the matching pgo+lto prebuilt python. We first try to find one in the latest
+/// A python-build-standalone archive of a release
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct PythonDownload {
+    name: String,
+    url: String,
+    /// Either `<name>.sha256` with only the digest or, in newer releases, `SHA256SUMS` with a
+    /// `<digest>  <name>` line per archive
+    checksum_url: Option<String>,
+}
+
+impl GitHubRelease {
+    /// The archive matching `version_re` and its checksum file
+    fn python_download(&self, version_re: &Regex) -> Option<PythonDownload> {
+        // TODO: Proper name parsing
+        // https://github.com/indygreg/python-build-standalone/issues/127
+        let asset = self
+            .assets
+            .iter()
+            .find(|asset| version_re.is_match(&asset.name))?;
+        let checksum_name = format!("{}.sha256", asset.name);
+        let checksum_url = self
+            .assets
+            .iter()
+            .find(|checksum| checksum.name == checksum_name || checksum.name == "SHA256SUMS")
+            .map(|checksum| checksum.browser_download_url.clone());
+        Some(PythonDownload {
+            name: asset.name.clone(),
+            url: asset.browser_download_url.clone(),
+            checksum_url,
+        })
+    }
+}
+
+/// Returns the matching pgo+lto prebuilt python. We first try to find one in the latest
 /// indygreg/python-build-standalone, then fall back to a known good release in case a more recent
 /// release broke compatibility
-fn find_python(host: &str, major: u8, minor: u8) -> anyhow::Result<String> {
+fn find_python(
+    host: &str,
+    transport: &dyn Transport,
+    major: u8,
+    minor: u8,
+) -> anyhow::Result<PythonDownload> {
     let version_re = filename_regex(major, minor);
 
-    let latest_release: anyhow::Result<GitHubRelease> =
-        ureq::get(&format!("{}{}", host, PYTHON_STANDALONE_LATEST_RELEASE.0))
-            .set("User-Agent", "monotrail (konstin@mailbox.org)")
-            .call()
-            .map_err(anyhow::Error::new)
-            .and_then(|x| x.into_json().map_err(anyhow::Error::new));
+    let latest_release: anyhow::Result<GitHubRelease> = get_json(
+        transport,
+        &format!("{}{}", host, PYTHON_STANDALONE_LATEST_RELEASE.0),
+    );
 
     match latest_release {
         Ok(latest_release) => {
-            let asset = latest_release.assets.into_iter().find(|asset| {
-                // TODO: Proper name parsing
-                // https://github.com/indygreg/python-build-standalone/issues/127
-                version_re.is_match(&asset.name)
-            });
-            if let Some(asset) = asset {
-                return Ok(asset.browser_download_url);
+            if let Some(download) = latest_release.python_download(&version_re) {
+                return Ok(download);
             }
         }
         Err(err) => {
@@ -77,7 +111,7 @@ fn find_python(host: &str, major: u8, minor: u8) -> anyhow::Result<String> {
     get_known_good_release(major, minor)
 }
 
-fn get_known_good_release(major: u8, minor: u8) -> anyhow::Result<String> {
+fn get_known_good_release(major: u8, minor: u8) -> anyhow::Result<PythonDownload> {
     let version_re = filename_regex(major, minor);
 
     // unwrap because we know the content
@@ -85,14 +119,8 @@ fn get_known_good_release(major: u8, minor: u8) -> anyhow::Result<String> {
         serde_json::from_slice(&zstd::decode_all(PYTHON_STANDALONE_KNOWN_GOOD_RELEASE).unwrap())
             .unwrap();
 
-    let asset = good_release
-        .assets
-        .into_iter()
-        .find(|asset| {
-            // TODO: Proper name parsing
-            // https://github.com/indygreg/python-build-standalone/issues/127
-            version_re.is_match(&asset.name)
-        })
+    good_release
+        .python_download(&version_re)
         .with_context(|| {
             format!(
                 "Failed to find a matching python-build-standalone download: /{}/. \
@@ -100,22 +128,83 @@ fn get_known_good_release(major: u8, minor: u8) -> anyhow::Result<String> {
                 version_re,
                 PYTHON_STANDALONE_LATEST_RELEASE.1,
             )
-        })?;
-    Ok(asset.browser_download_url)
+        })
+}
+
+/// The digest for `name` from a `.sha256` or a `SHA256SUMS` file
+fn parse_checksum(content: &str, name: &str) -> Option<String> {
+    content.lines().find_map(|line| {
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [digest] => Some(digest.to_lowercase()),
+            // `*` is the binary mode marker of sha256sum
+            [digest, file] if file.trim_start_matches('*') == name => Some(digest.to_lowercase()),
+            _ => None,
+        }
+    })
+}
+
+/// Hashes everything that is read through it
+struct HashingReader<R> {
+    inner: R,
+    hasher: Box<dyn IncrementalHash>,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
 }
 
-/// Download the prebuilt python .tar.zstd and unpacks it into the the target dir
-fn download_and_unpack_python(url: &str, target_dir: &Path) -> anyhow::Result<()> {
+/// Download the prebuilt python .tar.zstd and unpacks it into the the target dir, failing if it
+/// doesn't match the checksum of the release. The caller unpacks into a temporary directory, so
+/// a broken download never ends up in the cache
+fn download_and_unpack_python(
+    transport: &dyn Transport,
+    download: &PythonDownload,
+    target_dir: &Path,
+) -> anyhow::Result<()> {
+    let expected = if let Some(checksum_url) = &download.checksum_url {
+        let mut content = String::new();
+        transport
+            .get(checksum_url)?
+            .read_to_string(&mut content)
+            .with_context(|| format!("Failed to download {}", checksum_url))?;
+        let digest = parse_checksum(&content, &download.name)
+            .with_context(|| format!("No checksum for {} in {}", download.name, checksum_url))?;
+        Some(digest)
+    } else {
+        warn!("The release has no checksum for {}", download.name);
+        None
+    };
+
     // TODO: Add MB from API
-    info!("Downloading {}", url);
-    let tar_zstd = ureq::get(url)
-        .set("User-Agent", "monotrail (konstin@mailbox.org)")
-        .call()?
-        .into_reader();
-    let tar = zstd::Decoder::new(tar_zstd)?;
+    info!("Downloading {}", download.url);
+    // The release checksums are sha256, like the integrity backend
+    let mut tar_zstd = HashingReader {
+        inner: transport.get(&download.url)?,
+        hasher: hash_backend(HashPurpose::Integrity).hasher(),
+    };
+    let tar = zstd::Decoder::new(&mut tar_zstd)?;
     let mut archive = tar::Archive::new(tar);
     fs::create_dir_all(target_dir)?;
     archive.unpack(target_dir)?;
+    // Hash whatever the decoder didn't need
+    io::copy(&mut tar_zstd, &mut io::sink())?;
+
+    if let Some(expected) = expected {
+        let actual = HEXLOWER.encode(&tar_zstd.hasher.finalize());
+        if actual != expected {
+            bail!(
+                "Checksum mismatch for {}: expected sha256 {}, got {}",
+                download.url,
+                expected,
+                actual
+            );
+        }
+        debug!("Verified sha256 of {}", download.name);
+    }
     Ok(())
 }
 
@@ -147,6 +236,7 @@ fn check_installed_python(unpack_dir: &Path, python_version: (u8, u8)) -> anyhow
 
 /// Actual download and move into place logic
 fn provision_python_inner(
+    transport: &dyn Transport,
     python_version: (u8, u8),
     python_parent_dir: &PathBuf,
     unpack_dir: &PathBuf,
@@ -155,31 +245,35 @@ fn provision_python_inner(
         "Installing python {}.{}",
         python_version.0, python_version.1
     );
-    let url = find_python(GITHUB_API, python_version.0, python_version.1).with_context(|| {
-        format!(
-            "Couldn't find a matching python {}.{} to download",
-            python_version.0, python_version.1,
-        )
-    })?;
+    let download = find_python(GITHUB_API, transport, python_version.0, python_version.1)
+        .with_context(|| {
+            format!(
+                "Couldn't find a matching python {}.{} to download",
+                python_version.0, python_version.1,
+            )
+        })?;
     // atomic installation by tempdir & rename
-    let temp_dir = tempdir_in(python_parent_dir)
+    let mut temp_dir = tempdir_in(python_parent_dir)
         .context("Failed to create temporary directory for unpacking")?;
-    match download_and_unpack_python(&url, temp_dir.path()) {
+    match download_and_unpack_python(transport, &download, temp_dir.path()) {
         Ok(()) => {}
         Err(err) => {
             warn!(
                 "Failed to download and unpack latest python-build-standalone from {}, \
-                using known good release instead. Error: {}",
-                url, err
+                using known good release instead. Error: {:#}",
+                download.url, err
             );
-            let url =
-                get_known_good_release(python_version.0, python_version.1).with_context(|| {
+            let download = get_known_good_release(python_version.0, python_version.1)
+                .with_context(|| {
                     format!(
                         "Couldn't find a matching python {}.{} to download",
                         python_version.0, python_version.1,
                     )
                 })?;
-            download_and_unpack_python(&url, temp_dir.path())
+            // Don't mix with what the failed download left behind
+            temp_dir = tempdir_in(python_parent_dir)
+                .context("Failed to create temporary directory for unpacking")?;
+            download_and_unpack_python(transport, &download, temp_dir.path())
                 .context("Failed to download and unpack python-build-standalone")?;
         }
     }
@@ -191,6 +285,7 @@ fn provision_python_inner(
 
 /// Returns `(python_binary, python_home)`
 pub fn provision_python(
+    transport: &dyn Transport,
     python_version: (u8, u8),
     cache_dir: &Path,
 ) -> anyhow::Result<(PathBuf, PathBuf)> {
@@ -212,7 +307,7 @@ pub fn provision_python(
         ));
         let lockfile = File::create(install_lock)?;
         if lockfile.file().try_lock_exclusive().is_ok() {
-            provision_python_inner(python_version, &python_parent_dir, &unpack_dir)?;
+            provision_python_inner(transport, python_version, &python_parent_dir, &unpack_dir)?;
         } else {
             info!("Waiting for other process to finish installing");
             lockfile.file().lock_exclusive()?;
@@ -223,7 +318,7 @@ pub fn provision_python(
                 check_installed_python(&unpack_dir, python_version)
             } else {
                 info!("The other process seems to have failed, installing");
-                provision_python_inner(python_version, &python_parent_dir, &unpack_dir)
+                provision_python_inner(transport, python_version, &python_parent_dir, &unpack_dir)
             };
             // Make sure we unlock the file before returning. This would be nicer if it would
             // work through drop on a file lock object
@@ -292,11 +387,20 @@ pub fn filename_regex(major: u8, minor: u8) -> Regex {
 
 #[cfg(test)]
 mod test {
+    use crate::standalone_python::{
+        download_and_unpack_python, find_python, parse_checksum, PythonDownload,
+        PYTHON_STANDALONE_LATEST_RELEASE,
+    };
     #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
-    use crate::standalone_python::provision_python;
-    use crate::standalone_python::{find_python, PYTHON_STANDALONE_LATEST_RELEASE};
+    use crate::standalone_python::{provision_python, GITHUB_API};
+    use crate::transport::MockTransport;
+    use crate::transport::UreqTransport;
+    use data_encoding::HEXLOWER;
+    use install_wheel_rs::integrity_digest;
     use mockito::{Mock, ServerGuard};
     use std::path::PathBuf;
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    use tempfile::tempdir;
 
     pub fn zstd_json_mock(url: &str, fixture: impl Into<PathBuf>) -> (ServerGuard, Mock) {
         use fs_err::File;
@@ -322,23 +426,32 @@ mod test {
     fn test_download_url_from_release_20220502() {
         let (server, _mocks) = mock();
 
-        let url = find_python(&server.url(), 3, 9).unwrap();
-        assert_eq!(url, "https://github.com/indygreg/python-build-standalone/releases/download/20220502/cpython-3.9.12%2B20220502-x86_64_v3-unknown-linux-gnu-pgo%2Blto-full.tar.zst")
+        let download = find_python(&server.url(), &UreqTransport, 3, 9).unwrap();
+        assert_eq!(download.url, "https://github.com/indygreg/python-build-standalone/releases/download/20220502/cpython-3.9.12%2B20220502-x86_64_v3-unknown-linux-gnu-pgo%2Blto-full.tar.zst");
+        assert_eq!(download.checksum_url.unwrap(), "https://github.com/indygreg/python-build-standalone/releases/download/20220502/cpython-3.9.12%2B20220502-x86_64_v3-unknown-linux-gnu-pgo%2Blto-full.tar.zst.sha256");
     }
 
     #[test]
     fn test_download_url_from_release_20220502_any() {
         let (server, _mocks) = mock();
 
-        assert!(find_python(&server.url(), 3, 9).is_ok());
+        assert!(find_python(&server.url(), &UreqTransport, 3, 9).is_ok());
     }
 
     #[test]
     #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
     fn test_provision_nonexistent_version() {
-        let _mocks = mock();
+        let release = zstd::stream::decode_all(
+            fs_err::File::open("../../test-data/standalone_python_github_release.json.zstd")
+                .unwrap(),
+        )
+        .unwrap();
+        let transport = MockTransport::default().with_response(
+            format!("{}{}", GITHUB_API, PYTHON_STANDALONE_LATEST_RELEASE.0),
+            release,
+        );
         let tempdir = tempdir().unwrap();
-        let err = provision_python((3, 0), tempdir.path()).unwrap_err();
+        let err = provision_python(&transport, (3, 0), tempdir.path()).unwrap_err();
         let expected = vec![
             r"Couldn't find a matching python 3.0 to download",
             r"Failed to find a matching python-build-standalone download: /^cpython-3\.0\.(\d+)\+(\d+)-x86_64\-unknown\-linux\-gnu-pgo\+lto-full\.tar\.zst$/. Searched in https://github.com/indygreg/python-build-standalone/releases/latest and https://github.com/indygreg/python-build-standalone/releases/tag/20220502",
@@ -346,4 +459,54 @@ mod test {
         let actual = err.chain().map(|e| e.to_string()).collect::<Vec<_>>();
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn test_parse_checksum() {
+        let name = "cpython-3.12.1+20240107-x86_64-unknown-linux-gnu-pgo+lto-full.tar.zst";
+        assert_eq!(parse_checksum("ABC123\n", name).unwrap(), "abc123");
+        let sums = format!("def456  other.tar.zst\nabc123  {}\n", name);
+        assert_eq!(parse_checksum(&sums, name).unwrap(), "abc123");
+        assert_eq!(parse_checksum("def456  other.tar.zst\n", name), None);
+    }
+
+    #[test]
+    fn test_download_and_unpack_checksum() {
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, "python/install/hello.txt", "hello".as_bytes())
+            .unwrap();
+        let archive = zstd::encode_all(tar.into_inner().unwrap().as_slice(), 0).unwrap();
+        let digest = HEXLOWER.encode(&integrity_digest(&archive));
+
+        let download = PythonDownload {
+            name: "cpython.tar.zst".to_string(),
+            url: "https://example.org/cpython.tar.zst".to_string(),
+            checksum_url: Some("https://example.org/SHA256SUMS".to_string()),
+        };
+        let transport = MockTransport::default()
+            .with_response(&download.url, archive.clone())
+            .with_response(
+                "https://example.org/SHA256SUMS",
+                format!("{}  cpython.tar.zst\n", digest),
+            );
+        let target_dir = tempfile::tempdir().unwrap();
+        download_and_unpack_python(&transport, &download, target_dir.path()).unwrap();
+        let hello = target_dir.path().join("python/install/hello.txt");
+        assert_eq!(fs_err::read_to_string(hello).unwrap(), "hello");
+
+        let transport = MockTransport::default()
+            .with_response(&download.url, archive)
+            .with_response(
+                "https://example.org/SHA256SUMS",
+                format!("{}  cpython.tar.zst\n", "0".repeat(64)),
+            );
+        let target_dir = tempfile::tempdir().unwrap();
+        let err = download_and_unpack_python(&transport, &download, target_dir.path()).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Checksum mismatch for https://example.org/cpython.tar.zst"));
+    }
 }
@@ -0,0 +1,704 @@
+//! All HTTP requests go through [Transport], so resolution and download logic can be tested
+//! without network access by passing a [MockTransport]
+
+use crate::auth;
+use crate::network::{current_network_settings, NetworkSettings};
+use anyhow::{bail, Context};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::io::{self, Cursor, Read};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::{env, error};
+use tracing::warn;
+
+/// The User-Agent we send with all requests
+pub const USER_AGENT: &str = "monotrail (konstin@mailbox.org)";
+
+/// Performs HTTP requests. Downloads may run in parallel, so implementations must be [Sync]
+pub trait Transport: Sync {
+    /// Sends a GET request to `url` and returns the body of a successful response
+    fn get(&self, url: &str) -> anyhow::Result<Box<dyn Read + Send>>;
+
+    /// Like [Transport::get], but only the bytes from `offset` on, to resume an interrupted
+    /// download. The default downloads everything and skips the first `offset` bytes
+    fn get_range(&self, url: &str, offset: u64) -> anyhow::Result<Box<dyn Read + Send>> {
+        let mut reader = self.get(url)?;
+        io::copy(&mut (&mut reader).take(offset), &mut io::sink())
+            .with_context(|| format!("Failed to skip to byte {} of {}", offset, url))?;
+        Ok(reader)
+    }
+
+    /// GET with additional request headers such as `If-None-Match`, returning the status and the
+    /// response headers for caching. The default ignores the request headers and returns no
+    /// response headers
+    fn get_response(&self, url: &str, headers: &[(&str, &str)]) -> anyhow::Result<Response> {
+        let _ = headers;
+        Ok(Response {
+            status: 200,
+            headers: HashMap::new(),
+            body: self.get(url)?,
+        })
+    }
+
+    /// Uploads `body` to `url` with a PUT request, e.g. to a remote build cache. The default
+    /// doesn't support uploads
+    fn put(&self, url: &str, _body: &[u8]) -> anyhow::Result<()> {
+        bail!(
+            "Can't upload to {}, the transport doesn't support uploads",
+            url
+        )
+    }
+}
+
+/// A successful response from [Transport::get_response]
+pub struct Response {
+    /// 200, or 304 if the response to a conditional request is unchanged
+    pub status: u16,
+    /// The header names are lowercase
+    pub headers: HashMap<String, String>,
+    /// Empty for 304
+    pub body: Box<dyn Read + Send>,
+}
+
+/// GET request that deserializes the json response
+pub fn get_json<T: DeserializeOwned>(transport: &dyn Transport, url: &str) -> anyhow::Result<T> {
+    let reader = transport.get(url)?;
+    serde_json::from_reader(reader).with_context(|| format!("Invalid json response from {}", url))
+}
+
+/// The part of a request that took too long
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum TimeoutPhase {
+    /// Establishing the connection, see [Timeouts::connect]
+    Connect,
+    /// Waiting for the next bytes from the server, see [Timeouts::read]
+    Read,
+    /// The whole request including the body, see [Timeouts::total]
+    Total,
+}
+
+impl Display for TimeoutPhase {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let phase = match self {
+            TimeoutPhase::Connect => "connect",
+            TimeoutPhase::Read => "read",
+            TimeoutPhase::Total => "total",
+        };
+        f.write_str(phase)
+    }
+}
+
+/// The errors of [UreqTransport] that callers may want to handle, wrapped in [anyhow::Error] or,
+/// while reading a body, [io::Error]
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A hung or very slow server
+    #[error("The request to {url} timed out ({phase} timeout)")]
+    Timeout { url: String, phase: TimeoutPhase },
+    /// The server doesn't have this url, e.g. an index without the project
+    #[error("{url}: status code 404")]
+    NotFound { url: String },
+}
+
+/// Whether the request failed with [Error::NotFound]
+pub fn is_not_found(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<Error>(), Some(Error::NotFound { .. }))
+}
+
+/// Timeouts for all requests, so a hung index server can't stall an installation forever.
+///
+/// Set them with `MONOTRAIL_CONNECT_TIMEOUT`, `MONOTRAIL_READ_TIMEOUT` and `MONOTRAIL_TIMEOUT` in
+/// seconds or the command line options of the same name, `0` disables a timeout
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Timeouts {
+    /// Establishing the connection, including TLS
+    pub connect: Option<Duration>,
+    /// Between two reads, catches servers that stop sending
+    pub read: Option<Duration>,
+    /// The whole request including downloading the body. Large wheels on slow connections
+    /// take a while
+    pub total: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Some(Duration::from_secs(10)),
+            read: Some(Duration::from_secs(30)),
+            total: Some(Duration::from_secs(30 * 60)),
+        }
+    }
+}
+
+/// Set by [set_timeouts], otherwise the timeouts are read from the environment
+static TIMEOUTS: Mutex<Option<Timeouts>> = Mutex::new(None);
+
+/// What an agent was built for, see [Timeouts::agent]
+#[derive(PartialEq)]
+struct AgentKey {
+    timeouts: Timeouts,
+    settings: NetworkSettings,
+    proxy: Option<String>,
+}
+
+/// The agents keep a pool of connections, so we reuse them as long as the timeouts and network
+/// settings don't change. There is one agent per proxy, since `NO_PROXY` hosts don't use one
+static AGENTS: Mutex<Vec<(AgentKey, ureq::Agent)>> = Mutex::new(Vec::new());
+
+impl Timeouts {
+    /// The defaults, overridden by the environment variables
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut timeouts = Self::default();
+        for (name, timeout) in [
+            ("CONNECT_TIMEOUT", &mut timeouts.connect),
+            ("READ_TIMEOUT", &mut timeouts.read),
+            ("TIMEOUT", &mut timeouts.total),
+        ] {
+            let name = format!("{}_{}", env!("CARGO_PKG_NAME").to_uppercase(), name);
+            if let Ok(value) = env::var(&name) {
+                *timeout = parse_timeout(&value).with_context(|| format!("Invalid {}", name))?;
+            }
+        }
+        Ok(timeouts)
+    }
+
+    /// The shared agent for these timeouts and the current network settings for `url`
+    fn agent(&self, url: &str) -> anyhow::Result<ureq::Agent> {
+        let settings = current_network_settings()?;
+        let key = AgentKey {
+            timeouts: *self,
+            proxy: settings.proxy_for(url).map(ToString::to_string),
+            settings,
+        };
+        let mut agents = AGENTS.lock().unwrap();
+        if let Some((_, agent)) = agents.iter().find(|(known, _)| known == &key) {
+            return Ok(agent.clone());
+        }
+        let agent = self.build_agent(&key)?;
+        agents.push((key, agent.clone()));
+        Ok(agent)
+    }
+
+    fn build_agent(&self, key: &AgentKey) -> anyhow::Result<ureq::Agent> {
+        let mut builder = ureq::AgentBuilder::new().user_agent(USER_AGENT);
+        if let Some(connect) = self.connect {
+            builder = builder.timeout_connect(connect);
+        }
+        if let Some(read) = self.read {
+            builder = builder.timeout_read(read).timeout_write(read);
+        }
+        if let Some(total) = self.total {
+            builder = builder.timeout(total);
+        }
+        if let Some(proxy) = &key.proxy {
+            let proxy =
+                ureq::Proxy::new(proxy).with_context(|| format!("Invalid proxy {}", proxy))?;
+            builder = builder.proxy(proxy);
+        }
+        if let Some(tls_config) = key.settings.tls_config()? {
+            builder = builder.tls_config(tls_config);
+        }
+        Ok(builder.build())
+    }
+
+    /// ureq only tells us that a read timed out, the total timeout is implemented through the
+    /// read timeouts too. `started` is when the request was started
+    fn read_phase(&self, started: Instant) -> TimeoutPhase {
+        if self.total.is_some_and(|total| started.elapsed() >= total) {
+            TimeoutPhase::Total
+        } else {
+            TimeoutPhase::Read
+        }
+    }
+
+    /// Turns timeouts while reading the body into [Error::Timeout]
+    fn check(&self, err: io::Error, url: &str, started: Instant) -> io::Error {
+        if !is_timeout(&err) {
+            return err;
+        }
+        io::Error::new(
+            io::ErrorKind::TimedOut,
+            Error::Timeout {
+                url: url.to_string(),
+                phase: self.read_phase(started),
+            },
+        )
+    }
+
+    /// GET with these timeouts
+    fn get(&self, url: &str) -> anyhow::Result<Box<dyn Read + Send>> {
+        self.get_range(url, 0)
+    }
+
+    /// GET from `offset` on with these timeouts. Servers that don't support range requests send
+    /// the whole file, then we skip the start ourselves
+    fn get_range(&self, url: &str, offset: u64) -> anyhow::Result<Box<dyn Read + Send>> {
+        let started = Instant::now();
+        let mut request = authenticate(self.agent(url)?.get(url), url);
+        if offset > 0 {
+            request = request.set("Range", &format!("bytes={}-", offset));
+        }
+        let response = request
+            .call()
+            .map_err(|err| self.request_error(err, url, started))?;
+        let skip = if response.status() == 206 { 0 } else { offset };
+        let mut reader: Box<dyn Read + Send> = Box::new(TimeoutReader {
+            reader: response.into_reader(),
+            url: url.to_string(),
+            timeouts: *self,
+            started,
+        });
+        io::copy(&mut (&mut reader).take(skip), &mut io::sink())
+            .with_context(|| format!("Failed to skip to byte {} of {}", offset, url))?;
+        Ok(reader)
+    }
+
+    /// GET with extra request headers with these timeouts
+    fn get_response(&self, url: &str, headers: &[(&str, &str)]) -> anyhow::Result<Response> {
+        let started = Instant::now();
+        let mut request = authenticate(self.agent(url)?.get(url), url);
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        let response = request
+            .call()
+            .map_err(|err| self.request_error(err, url, started))?;
+        let response_headers = response
+            .headers_names()
+            .into_iter()
+            .filter_map(|name| {
+                let value = response.header(&name)?.to_string();
+                Some((name.to_lowercase(), value))
+            })
+            .collect();
+        Ok(Response {
+            status: response.status(),
+            headers: response_headers,
+            body: Box::new(TimeoutReader {
+                reader: response.into_reader(),
+                url: url.to_string(),
+                timeouts: *self,
+                started,
+            }),
+        })
+    }
+
+    /// PUT with these timeouts
+    fn put(&self, url: &str, body: &[u8]) -> anyhow::Result<()> {
+        let started = Instant::now();
+        authenticate(self.agent(url)?.put(url), url)
+            .send_bytes(body)
+            .map_err(|err| self.request_error(err, url, started))?;
+        Ok(())
+    }
+
+    /// Turns timeouts while connecting or waiting for the response into [Error::Timeout] and
+    /// 404 responses into [Error::NotFound]
+    fn request_error(&self, err: ureq::Error, url: &str, started: Instant) -> anyhow::Error {
+        if let ureq::Error::Status(404, _) = &err {
+            return Error::NotFound {
+                url: url.to_string(),
+            }
+            .into();
+        }
+        if let ureq::Error::Transport(transport) = &err {
+            if find_io_error(transport).is_some_and(is_timeout) {
+                let phase = if transport.kind() == ureq::ErrorKind::ConnectionFailed {
+                    TimeoutPhase::Connect
+                } else {
+                    self.read_phase(started)
+                };
+                return Error::Timeout {
+                    url: url.to_string(),
+                    phase,
+                }
+                .into();
+            }
+            if is_certificate_error(transport) {
+                return anyhow::Error::from(err).context(format!(
+                    "Failed to verify the TLS certificate of {}. If a proxy intercepts TLS, add its \
+                    root certificate with `{}_CA_BUNDLE` or `--ca-bundle`",
+                    url,
+                    env!("CARGO_PKG_NAME").to_uppercase()
+                ));
+            }
+        }
+        err.into()
+    }
+}
+
+/// Adds the credentials for the host of `url`, if we have any
+fn authenticate(request: ureq::Request, url: &str) -> ureq::Request {
+    match auth::authorization(url) {
+        Some(authorization) => request.set("Authorization", &authorization),
+        None => request,
+    }
+}
+
+/// rustls rejected the server certificate, e.g. because it's signed by an unknown authority
+fn is_certificate_error(err: &(dyn error::Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(err) = current {
+        if err.to_string().contains("invalid peer certificate") {
+            return true;
+        }
+        current = err.source();
+    }
+    false
+}
+
+/// Depending on the platform, socket timeouts are `TimedOut` or `WouldBlock`
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+/// Parses seconds, `0` means no timeout
+pub fn parse_timeout(value: &str) -> anyhow::Result<Option<Duration>> {
+    let seconds = f64::from_str(value.trim())
+        .ok()
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .with_context(|| format!("Expected a number of seconds, got `{}`", value))?;
+    Ok((seconds > 0.0).then(|| Duration::from_secs_f64(seconds)))
+}
+
+/// The io error somewhere in the chain of `err`
+fn find_io_error<'a>(err: &'a (dyn error::Error + 'static)) -> Option<&'a io::Error> {
+    let mut current = Some(err);
+    while let Some(err) = current {
+        if let Some(io_err) = err.downcast_ref::<io::Error>() {
+            return Some(io_err);
+        }
+        current = err.source();
+    }
+    None
+}
+
+/// Overrides the timeouts from the environment, e.g. with command line options
+pub fn set_timeouts(timeouts: Timeouts) {
+    *TIMEOUTS.lock().unwrap() = Some(timeouts);
+}
+
+/// The timeouts from [set_timeouts] or the environment
+pub fn current_timeouts() -> Timeouts {
+    if let Some(timeouts) = *TIMEOUTS.lock().unwrap() {
+        return timeouts;
+    }
+    Timeouts::from_env().unwrap_or_else(|err| {
+        warn!("{:#}, using the default timeouts", err);
+        Timeouts::default()
+    })
+}
+
+/// Marks timeouts while reading the body as [Error::Timeout]
+struct TimeoutReader {
+    reader: Box<dyn Read + Send + Sync>,
+    url: String,
+    timeouts: Timeouts,
+    started: Instant,
+}
+
+impl Read for TimeoutReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader
+            .read(buf)
+            .map_err(|err| self.timeouts.check(err, &self.url, self.started))
+    }
+}
+
+/// The real network, through ureq, with the [current_timeouts]
+pub struct UreqTransport;
+
+impl Transport for UreqTransport {
+    fn get(&self, url: &str) -> anyhow::Result<Box<dyn Read + Send>> {
+        current_timeouts().get(url)
+    }
+
+    fn get_range(&self, url: &str, offset: u64) -> anyhow::Result<Box<dyn Read + Send>> {
+        current_timeouts().get_range(url, offset)
+    }
+
+    fn get_response(&self, url: &str, headers: &[(&str, &str)]) -> anyhow::Result<Response> {
+        current_timeouts().get_response(url, headers)
+    }
+
+    fn put(&self, url: &str, body: &[u8]) -> anyhow::Result<()> {
+        current_timeouts().put(url, body)
+    }
+}
+
+/// Serves canned responses for tests. Requests to urls without a response fail like a 404.
+/// Uploads are served to later GET requests. Range requests are recorded as `<url> bytes=<offset>-`
+/// and with [Transport::get_response] answered with a 206 like a server that supports them
+#[derive(Default)]
+pub struct MockTransport {
+    responses: HashMap<String, Vec<u8>>,
+    uploads: Mutex<HashMap<String, Vec<u8>>>,
+    requests: Mutex<Vec<String>>,
+    /// url -> number of bytes after which the connection breaks, once
+    interruptions: Mutex<HashMap<String, usize>>,
+    /// url -> response headers, lowercase
+    headers: HashMap<String, HashMap<String, String>>,
+}
+
+impl MockTransport {
+    /// Respond to GET requests to `url` with `body`
+    pub fn with_response(mut self, url: impl Into<String>, body: impl Into<Vec<u8>>) -> Self {
+        self.responses.insert(url.into(), body.into());
+        self
+    }
+
+    /// Send these headers with the response to `url`. With an `etag`, requests with a matching
+    /// `If-None-Match` get a 304
+    pub fn with_headers(mut self, url: impl Into<String>, headers: &[(&str, &str)]) -> Self {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_lowercase(), value.to_string()))
+            .collect();
+        self.headers.insert(url.into(), headers);
+        self
+    }
+
+    /// The next GET request to `url` fails after `after` bytes of the body, like a dropped
+    /// connection
+    pub fn with_interruption(self, url: impl Into<String>, after: usize) -> Self {
+        self.interruptions.lock().unwrap().insert(url.into(), after);
+        self
+    }
+
+    /// All requested urls in order, including those that failed
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// All uploaded urls, sorted
+    pub fn uploads(&self) -> Vec<String> {
+        let mut uploads: Vec<String> = self.uploads.lock().unwrap().keys().cloned().collect();
+        uploads.sort();
+        uploads
+    }
+}
+
+impl MockTransport {
+    /// A 206 response to `Range: bytes=<start>-<end>` or `bytes=-<suffix length>`, recorded as
+    /// `<url> bytes=<range>`
+    fn get_partial(&self, url: &str, range: &str) -> anyhow::Result<Response> {
+        self.requests
+            .lock()
+            .unwrap()
+            .push(format!("{} bytes={}", url, range));
+        let Some(body) = self.responses.get(url) else {
+            return Err(Error::NotFound {
+                url: url.to_string(),
+            }
+            .into());
+        };
+        let len = body.len() as u64;
+        let (start, end) = match range.split_once('-') {
+            Some(("", suffix)) => (len.saturating_sub(suffix.parse()?), len),
+            Some((start, "")) => (start.parse()?, len),
+            Some((start, end)) => (start.parse()?, (end.parse::<u64>()? + 1).min(len)),
+            None => anyhow::bail!("Invalid range {}", range),
+        };
+        let content_range = format!("bytes {}-{}/{}", start, end.saturating_sub(1), len);
+        Ok(Response {
+            status: 206,
+            headers: HashMap::from([("content-range".to_string(), content_range)]),
+            body: Box::new(Cursor::new(
+                body.get(start as usize..end as usize)
+                    .unwrap_or_default()
+                    .to_vec(),
+            )),
+        })
+    }
+}
+
+impl Transport for MockTransport {
+    fn get(&self, url: &str) -> anyhow::Result<Box<dyn Read + Send>> {
+        self.get_range(url, 0)
+    }
+
+    fn get_range(&self, url: &str, offset: u64) -> anyhow::Result<Box<dyn Read + Send>> {
+        if offset == 0 {
+            self.requests.lock().unwrap().push(url.to_string());
+        } else {
+            self.requests
+                .lock()
+                .unwrap()
+                .push(format!("{} bytes={}-", url, offset));
+        }
+        let uploaded = self.uploads.lock().unwrap().get(url).cloned();
+        let Some(body) = self.responses.get(url).cloned().or(uploaded) else {
+            return Err(anyhow::Error::from(Error::NotFound {
+                url: url.to_string(),
+            })
+            .context(format!("{}: status code 404 (no mock response)", url)));
+        };
+        let body = body.get(offset as usize..).unwrap_or_default().to_vec();
+        match self.interruptions.lock().unwrap().remove(url) {
+            Some(after) => Ok(Box::new(
+                Cursor::new(body).take(after as u64).chain(BrokenConnection),
+            )),
+            None => Ok(Box::new(Cursor::new(body))),
+        }
+    }
+
+    fn get_response(&self, url: &str, headers: &[(&str, &str)]) -> anyhow::Result<Response> {
+        let range = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Range"))
+            .and_then(|(_, value)| value.strip_prefix("bytes="));
+        if let Some(range) = range {
+            return self.get_partial(url, range);
+        }
+        let response_headers = self.headers.get(url).cloned().unwrap_or_default();
+        let etag = response_headers.get("etag");
+        let unchanged = headers.iter().any(|(name, value)| {
+            name.eq_ignore_ascii_case("If-None-Match") && Some(&value.to_string()) == etag
+        });
+        if unchanged {
+            self.requests.lock().unwrap().push(url.to_string());
+            return Ok(Response {
+                status: 304,
+                headers: response_headers,
+                body: Box::new(io::empty()),
+            });
+        }
+        Ok(Response {
+            status: 200,
+            body: self.get(url)?,
+            headers: response_headers,
+        })
+    }
+
+    fn put(&self, url: &str, body: &[u8]) -> anyhow::Result<()> {
+        self.requests.lock().unwrap().push(url.to_string());
+        self.uploads
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), body.to_vec());
+        Ok(())
+    }
+}
+
+/// Always fails like a connection the server dropped
+struct BrokenConnection;
+
+impl Read for BrokenConnection {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::ConnectionReset,
+            "connection reset (mock interruption)",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::transport::{
+        get_json, is_not_found, parse_timeout, Error, MockTransport, TimeoutPhase, Timeouts,
+        Transport,
+    };
+    use std::collections::HashMap;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[test]
+    fn test_mock_transport() {
+        let transport = MockTransport::default()
+            .with_response("https://example.org/a.json", r#"{"a": 1}"#)
+            .with_response("https://example.org/b.txt", "b");
+
+        let json: HashMap<String, u32> =
+            get_json(&transport, "https://example.org/a.json").unwrap();
+        assert_eq!(json["a"], 1);
+        let mut body = String::new();
+        transport
+            .get("https://example.org/b.txt")
+            .unwrap()
+            .read_to_string(&mut body)
+            .unwrap();
+        assert_eq!(body, "b");
+        let err = transport.get("https://example.org/c").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "https://example.org/c: status code 404 (no mock response)"
+        );
+        assert!(is_not_found(&err));
+        assert_eq!(
+            transport.requests(),
+            [
+                "https://example.org/a.json",
+                "https://example.org/b.txt",
+                "https://example.org/c"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mock_interruption() {
+        let url = "https://example.org/a.whl";
+        let transport = MockTransport::default()
+            .with_response(url, "0123456789")
+            .with_interruption(url, 4);
+        let mut body = Vec::new();
+        let err = transport
+            .get(url)
+            .unwrap()
+            .read_to_end(&mut body)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        assert_eq!(body, b"0123");
+        let mut rest = String::new();
+        transport
+            .get_range(url, 4)
+            .unwrap()
+            .read_to_string(&mut rest)
+            .unwrap();
+        assert_eq!(rest, "456789");
+        assert_eq!(transport.requests(), [url, &format!("{} bytes=4-", url)]);
+    }
+
+    #[test]
+    fn test_parse_timeout() {
+        assert_eq!(parse_timeout("0").unwrap(), None);
+        assert_eq!(
+            parse_timeout(" 2.5").unwrap(),
+            Some(Duration::from_millis(2500))
+        );
+        assert_eq!(
+            parse_timeout("-1").unwrap_err().to_string(),
+            "Expected a number of seconds, got `-1`"
+        );
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        // Accepts the connection but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/simple/tqdm/", listener.local_addr().unwrap());
+        let timeouts = Timeouts {
+            connect: Some(Duration::from_secs(10)),
+            read: Some(Duration::from_millis(100)),
+            total: None,
+        };
+        let err = timeouts.get(&url).err().unwrap();
+        match err.downcast_ref::<Error>() {
+            Some(Error::Timeout {
+                url: timed_out,
+                phase,
+            }) => {
+                assert_eq!(timed_out, &url);
+                assert_eq!(*phase, TimeoutPhase::Read);
+            }
+            _ => panic!("Expected a timeout, got {:?}", err),
+        }
+        drop(listener);
+    }
+}
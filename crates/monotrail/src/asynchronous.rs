@@ -0,0 +1,320 @@
+//! Async versions of resolving, downloading and installing, for servers that drive many
+//! environment builds at once such as a build farm. Requires the `async` feature.
+//!
+//! The index client, the resolver and the installer are blocking, so every operation runs on
+//! tokio's blocking pool. An [AsyncRunner] caps how many of them run at the same time, the jobs
+//! beyond that wait on a semaphore as plain futures, so a few hundred queued environment builds
+//! don't need a few hundred threads.
+//!
+//! ```no_run
+//! # async fn build() -> anyhow::Result<()> {
+//! use monotrail::{query_interpreter, AsyncRunner};
+//! use std::path::{Path, PathBuf};
+//!
+//! let runner = AsyncRunner::new(16);
+//! let interpreter = query_interpreter(Path::new(".venv/bin/python"))?;
+//! let python_version = interpreter.python_version();
+//! let resolution = runner
+//!     .resolve(vec!["tqdm>=4,<5".to_string()], interpreter, None)
+//!     .await?;
+//! runner.download(&resolution, python_version).await?;
+//! runner
+//!     .install(&resolution, PathBuf::from(".venv"), python_version)
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::download::download_distribution_cached;
+use crate::environment::Environment;
+use crate::http_cache::HttpCache;
+use crate::install::{filter_installed, install_all, InstalledPackage};
+use crate::interpreter::Interpreter;
+use crate::monotrail::{LaunchType, PythonContext};
+use crate::package_index::PYPI_HOST;
+use crate::poetry_integration::lock::poetry_resolve;
+use crate::poetry_integration::read_dependencies::requirements_for_poetry;
+use crate::read_poetry_specs;
+use crate::snapshot::venv_site_packages;
+use crate::spec::{FileOrUrl, RequestedSpec};
+use anyhow::Context;
+use install_wheel_rs::{CompatibleTags, InstallLocation, NoProgress};
+use monotrail_utils::transport::UreqTransport;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+/// The pinned packages of [AsyncRunner::resolve], to be passed to [AsyncRunner::download] and
+/// [AsyncRunner::install]
+#[derive(Debug, Clone)]
+pub struct Resolution {
+    /// The poetry.lock, pass it to the next [AsyncRunner::resolve] to keep the versions stable
+    pub lockfile: String,
+    pub(crate) specs: Vec<RequestedSpec>,
+}
+
+impl Resolution {
+    /// Name and version of each package, sorted by name
+    pub fn packages(&self) -> Vec<(String, String)> {
+        let mut packages: Vec<(String, String)> = self
+            .specs
+            .iter()
+            .map(|spec| {
+                (
+                    spec.normalized_name(),
+                    spec.get_unique_version().unwrap_or_default(),
+                )
+            })
+            .collect();
+        packages.sort();
+        packages
+    }
+}
+
+/// Runs the blocking operations on tokio's blocking pool, at most `max_jobs` at a time. Cloning
+/// shares the limit
+#[derive(Debug, Clone)]
+pub struct AsyncRunner {
+    permits: Arc<Semaphore>,
+}
+
+impl AsyncRunner {
+    /// At most `max_jobs` (but at least one) operations run at the same time
+    pub fn new(max_jobs: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_jobs.max(1))),
+        }
+    }
+
+    /// Waits for a free slot, then runs `job` on the blocking pool
+    async fn run<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> anyhow::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        // We never close the semaphore
+        let _permit = self.permits.clone().acquire_owned().await?;
+        tokio::task::spawn_blocking(job)
+            .await
+            .context("The blocking job failed")?
+    }
+
+    /// Resolves PEP 508 requirements such as `tqdm>=4,<5` for `interpreter`, with the
+    /// `lockfile` of a previous resolution as preference. Uses the same resolver as the cli
+    pub async fn resolve(
+        &self,
+        requirements: Vec<String>,
+        interpreter: Interpreter,
+        lockfile: Option<String>,
+    ) -> anyhow::Result<Resolution> {
+        self.run(move || {
+            let python_context = PythonContext {
+                sys_executable: interpreter.sys_executable.clone(),
+                version: interpreter.python_version(),
+                pep508_env: interpreter.markers,
+                launch_type: LaunchType::Binary,
+            };
+            let dependencies = requirements_for_poetry(&requirements)?;
+            let (poetry_section, poetry_lock, lockfile) =
+                poetry_resolve(&dependencies, lockfile.as_deref(), &python_context)
+                    .context("Failed to resolve the requirements")?;
+            let specs = read_poetry_specs(
+                &poetry_section,
+                poetry_lock,
+                &env::current_dir()?,
+                false,
+                &[],
+                &python_context.pep508_env,
+            )?;
+            Ok(Resolution { lockfile, specs })
+        })
+        .await
+    }
+
+    /// Downloads the distributions of the resolved packages into the cache, each as its own job,
+    /// and returns their paths. Git dependencies are skipped, they are checked out and built on
+    /// install
+    pub async fn download(
+        &self,
+        resolution: &Resolution,
+        python_version: (u8, u8),
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        // Detecting the libc runs a subprocess
+        let compatible_tags = Arc::new(
+            self.run(move || Ok(CompatibleTags::current(python_version)?))
+                .await?,
+        );
+        let mut jobs = JoinSet::new();
+        for spec in &resolution.specs {
+            let spec = spec.clone();
+            let runner = self.clone();
+            let compatible_tags = compatible_tags.clone();
+            jobs.spawn(async move {
+                runner
+                    .run(move || download_spec(&spec, &compatible_tags))
+                    .await
+            });
+        }
+        let mut paths = Vec::new();
+        while let Some(downloaded) = jobs.join_next().await {
+            if let Some(path) = downloaded.context("The download job failed")?? {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Installs the resolved packages into the venv and updates its
+    /// `monotrail-environment.json`, skipping the packages that are already installed in the
+    /// resolved version. Returns all packages of the resolution
+    pub async fn install(
+        &self,
+        resolution: &Resolution,
+        venv: PathBuf,
+        python_version: (u8, u8),
+    ) -> anyhow::Result<Vec<InstalledPackage>> {
+        let specs = resolution.specs.clone();
+        self.run(move || install_venv(&specs, &venv, python_version))
+            .await
+    }
+}
+
+/// The local path or the cached download, `None` for git dependencies
+fn download_spec(
+    spec: &RequestedSpec,
+    compatible_tags: &CompatibleTags,
+) -> anyhow::Result<Option<PathBuf>> {
+    let transport = HttpCache::new(&UreqTransport)?;
+    let resolved = spec.resolve(PYPI_HOST, &transport, compatible_tags)?;
+    match resolved.location {
+        FileOrUrl::File(path) => Ok(Some(path)),
+        FileOrUrl::Url { url, filename } => {
+            let path = download_distribution_cached(
+                &transport,
+                &resolved.name,
+                &resolved.unique_version,
+                &filename,
+                &url,
+                resolved.sha256.as_deref(),
+            )
+            .with_context(|| format!("Failed to download {}", resolved.requested))?;
+            Ok(Some(path))
+        }
+        FileOrUrl::Git { .. } => Ok(None),
+    }
+}
+
+/// Same as `monotrail poetry install` without the monotrail mode
+fn install_venv(
+    specs: &[RequestedSpec],
+    venv: &Path,
+    python_version: (u8, u8),
+) -> anyhow::Result<Vec<InstalledPackage>> {
+    let venv_base = venv
+        .canonicalize()
+        .with_context(|| format!("Couldn't canonicalize venv location {}", venv.display()))?;
+    let location = InstallLocation::venv(venv_base, python_version);
+    let compatible_tags = location.compatible_tags()?;
+    let location = location.acquire_lock()?;
+    let (to_install, mut installed) = filter_installed(&location, specs, &compatible_tags)?;
+    installed.extend(install_all(
+        &to_install,
+        &location,
+        &compatible_tags,
+        false,
+        false,
+        false,
+        false,
+        false,
+        &HttpCache::new(&UreqTransport)?,
+        &NoProgress,
+    )?);
+    Environment::update(
+        venv,
+        &venv_site_packages(venv, python_version),
+        python_version,
+        specs,
+        &installed,
+        SystemTime::now(),
+    )?;
+    installed.sort_by(|left, right| left.name.cmp(&right.name));
+    Ok(installed)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::asynchronous::{AsyncRunner, Resolution};
+    use crate::spec::RequestedSpec;
+    use crate::{query_interpreter, Environment};
+    use std::path::Path;
+    use std::process::Command;
+    use tempfile::TempDir;
+    use tokio::task::JoinSet;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_install_concurrently() {
+        let python = query_interpreter(Path::new("python3")).unwrap();
+        let python_version = python.python_version();
+        let wheel = Path::new("../../test-data/pip-test-packages")
+            .join("has.script-1.0-py2.py3-none-any.whl")
+            .canonicalize()
+            .unwrap();
+        let resolution = Resolution {
+            lockfile: String::new(),
+            specs: vec![RequestedSpec::from_requested(wheel.to_str().unwrap(), &[]).unwrap()],
+        };
+        assert_eq!(
+            resolution.packages(),
+            [("has-script".to_string(), "1.0".to_string())]
+        );
+
+        let temp_dir = TempDir::new().unwrap();
+        let venvs: Vec<_> = (0..3)
+            .map(|i| {
+                let venv = temp_dir.path().join(format!("venv{}", i));
+                let status = Command::new(&python.sys_executable)
+                    .args(["-m", "venv", "--without-pip"])
+                    .arg(&venv)
+                    .status()
+                    .unwrap();
+                assert!(status.success());
+                venv
+            })
+            .collect();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        // Fewer slots than jobs, so some have to wait
+        let runner = AsyncRunner::new(2);
+        runtime.block_on(async {
+            assert_eq!(
+                runner.download(&resolution, python_version).await.unwrap(),
+                vec![wheel.clone()]
+            );
+
+            let mut jobs = JoinSet::new();
+            for venv in &venvs {
+                let runner = runner.clone();
+                let resolution = resolution.clone();
+                let venv = venv.clone();
+                jobs.spawn(async move { runner.install(&resolution, venv, python_version).await });
+            }
+            while let Some(installed) = jobs.join_next().await {
+                let installed = installed.unwrap().unwrap();
+                assert_eq!(installed.len(), 1);
+                assert_eq!(installed[0].python_version, "1.0");
+            }
+        });
+
+        for venv in &venvs {
+            let environment = Environment::load(venv).unwrap().unwrap();
+            assert_eq!(environment.packages.len(), 1);
+            assert_eq!(environment.packages[0].name, "has-script");
+        }
+    }
+}
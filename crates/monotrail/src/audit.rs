@@ -0,0 +1,513 @@
+//! `monotrail audit`: Checks the resolved packages against the known vulnerabilities of the
+//! [PyPA advisory database](https://github.com/pypa/advisory-database) and OSV.
+//!
+//! Online, we ask pypi's json api, which lists the vulnerabilities of a release, and fetch the
+//! severity of each finding from <https://api.osv.dev>. Offline, we read a directory of OSV
+//! json files instead, e.g. the unpacked
+//! <https://osv-vulnerabilities.storage.googleapis.com/PyPI/all.zip>, set with
+//! `--advisory-db` or `MONOTRAIL_ADVISORY_DB`.
+
+use crate::spec::{same_version, RequestedSpec};
+use anyhow::Context;
+use fs_err as fs;
+use install_wheel_rs::normalize_name;
+use monotrail_utils::transport::{get_json, is_not_found, Transport};
+use pep440_rs::Version;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::{debug, warn};
+
+/// Where the vulnerability details (e.g. the severity) come from
+const OSV_API: &str = "https://api.osv.dev/v1/vulns";
+
+/// A known vulnerability of a package
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Advisory {
+    /// e.g. `PYSEC-2023-74` or `GHSA-j8r2-6x86-q33q`
+    pub id: String,
+    /// The same vulnerability in other databases, e.g. the CVE
+    pub aliases: Vec<String>,
+    /// One line description, or the start of the details
+    pub summary: String,
+    /// The versions that fix the vulnerability
+    pub fixed_in: Vec<String>,
+    /// e.g. `HIGH` from the GitHub advisory or a CVSS vector
+    pub severity: Option<String>,
+}
+
+/// A resolved package that has a known vulnerability
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    /// The package name
+    pub name: String,
+    /// The resolved version
+    pub version: String,
+    /// The vulnerability
+    pub advisory: Advisory,
+}
+
+/// Where to look up the vulnerabilities
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdvisorySource {
+    /// pypi's json api on this host and the OSV api for the severity
+    Online {
+        /// e.g. `https://pypi.org`
+        pypi_host: String,
+    },
+    /// A directory of OSV json files
+    Database(PathBuf),
+}
+
+impl AdvisorySource {
+    /// The directory from `--advisory-db` or `MONOTRAIL_ADVISORY_DB`, otherwise pypi
+    pub fn from_args(advisory_db: Option<PathBuf>, pypi_host: &str) -> Self {
+        let name = format!("{}_ADVISORY_DB", env!("CARGO_PKG_NAME").to_uppercase());
+        match advisory_db.or_else(|| env::var_os(name).map(PathBuf::from)) {
+            Some(dir) => Self::Database(dir),
+            None => Self::Online {
+                pypi_host: pypi_host.to_string(),
+            },
+        }
+    }
+}
+
+/// <https://ossf.github.io/osv-schema/>
+#[derive(Deserialize, Debug, Clone)]
+struct OsvVulnerability {
+    id: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+    #[serde(default)]
+    summary: String,
+    #[serde(default)]
+    details: String,
+    withdrawn: Option<String>,
+    #[serde(default)]
+    affected: Vec<OsvAffected>,
+    #[serde(default)]
+    severity: Vec<OsvSeverity>,
+    database_specific: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct OsvAffected {
+    package: Option<OsvPackage>,
+    #[serde(default)]
+    ranges: Vec<OsvRange>,
+    #[serde(default)]
+    versions: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct OsvPackage {
+    ecosystem: String,
+    name: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct OsvRange {
+    #[serde(rename = "type")]
+    range_type: String,
+    #[serde(default)]
+    events: Vec<OsvEvent>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct OsvEvent {
+    introduced: Option<String>,
+    fixed: Option<String>,
+    last_affected: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct OsvSeverity {
+    score: String,
+}
+
+/// An entry of `vulnerabilities` in <https://pypi.org/pypi/{name}/{version}/json>
+#[derive(Deserialize, Debug, Clone)]
+struct PypiVulnerability {
+    id: String,
+    #[serde(default)]
+    aliases: Vec<String>,
+    summary: Option<String>,
+    #[serde(default)]
+    details: String,
+    #[serde(default)]
+    fixed_in: Vec<String>,
+    withdrawn: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct PypiRelease {
+    #[serde(default)]
+    vulnerabilities: Vec<PypiVulnerability>,
+}
+
+/// The summary, or the first line of the details if there is none
+fn summary(summary: &str, details: &str) -> String {
+    if summary.is_empty() {
+        details
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string()
+    } else {
+        summary.to_string()
+    }
+}
+
+/// Compares two versions as PEP 440, where OSV's `0` means "since the beginning"
+fn compare(left: &Version, right: &str) -> Option<Ordering> {
+    Some(left.cmp(&Version::from_str(right).ok()?))
+}
+
+impl OsvVulnerability {
+    /// The github advisory severity (e.g. `HIGH`), otherwise the first CVSS vector
+    fn severity(&self) -> Option<String> {
+        self.database_specific
+            .as_ref()
+            .and_then(|specific| specific.get("severity"))
+            .and_then(|severity| severity.as_str())
+            .map(ToString::to_string)
+            .or_else(|| self.severity.first().map(|severity| severity.score.clone()))
+    }
+
+    /// The entries for the pypi package `name` (normalized)
+    fn affected_for<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a OsvAffected> {
+        self.affected.iter().filter(move |affected| {
+            affected.package.as_ref().is_some_and(|package| {
+                package.ecosystem == "PyPI" && normalize_name(&package.name) == name
+            })
+        })
+    }
+
+    /// Whether `version` of the pypi package `name` (normalized) is affected
+    fn affects(&self, name: &str, version: &str) -> bool {
+        if self.withdrawn.is_some() {
+            return false;
+        }
+        let parsed = Version::from_str(version).ok();
+        self.affected_for(name).any(|affected| {
+            if affected
+                .versions
+                .iter()
+                .any(|affected_version| same_version(affected_version, version))
+            {
+                return true;
+            }
+            let Some(parsed) = &parsed else {
+                return false;
+            };
+            affected
+                .ranges
+                .iter()
+                .filter(|range| range.range_type == "ECOSYSTEM")
+                .any(|range| in_range(&range.events, parsed))
+        })
+    }
+
+    fn fixed_in(&self, name: &str) -> Vec<String> {
+        self.affected_for(name)
+            .flat_map(|affected| &affected.ranges)
+            .filter(|range| range.range_type == "ECOSYSTEM")
+            .flat_map(|range| &range.events)
+            .filter_map(|event| event.fixed.clone())
+            .collect()
+    }
+
+    fn to_advisory(&self, name: &str) -> Advisory {
+        Advisory {
+            id: self.id.clone(),
+            aliases: self.aliases.clone(),
+            summary: summary(&self.summary, &self.details),
+            fixed_in: self.fixed_in(name),
+            severity: self.severity(),
+        }
+    }
+}
+
+/// The events of a range are evaluated in version order: `introduced` starts an affected
+/// interval, `fixed` ends it before and `last_affected` after the version
+fn in_range(events: &[OsvEvent], version: &Version) -> bool {
+    let mut affected = false;
+    for event in events {
+        if let Some(introduced) = &event.introduced {
+            if introduced == "0" || compare(version, introduced) != Some(Ordering::Less) {
+                affected = true;
+            }
+        }
+        if let Some(fixed) = &event.fixed {
+            if compare(version, fixed) != Some(Ordering::Less) {
+                affected = false;
+            }
+        }
+        if let Some(last_affected) = &event.last_affected {
+            if compare(version, last_affected) == Some(Ordering::Greater) {
+                affected = false;
+            }
+        }
+    }
+    affected
+}
+
+/// Reads all OSV json files in `dir`, skipping those that aren't valid
+fn read_database(dir: &Path) -> anyhow::Result<Vec<OsvVulnerability>> {
+    let mut vulnerabilities = Vec::new();
+    for entry in fs::read_dir(dir)
+        .with_context(|| format!("Failed to read the advisory database {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+            continue;
+        }
+        match serde_json::from_str(&fs::read_to_string(&path)?) {
+            Ok(vulnerability) => vulnerabilities.push(vulnerability),
+            Err(err) => warn!("Skipping invalid advisory {}: {}", path.display(), err),
+        }
+    }
+    debug!(
+        "Read {} advisories from {}",
+        vulnerabilities.len(),
+        dir.display()
+    );
+    Ok(vulnerabilities)
+}
+
+/// Asks pypi for the vulnerabilities of one release and OSV for their severity
+fn query_online(
+    pypi_host: &str,
+    transport: &dyn Transport,
+    name: &str,
+    version: &str,
+) -> anyhow::Result<Vec<Advisory>> {
+    let url = format!("{}/pypi/{}/{}/json", pypi_host, name, version);
+    let release: PypiRelease = match get_json(transport, &url) {
+        Ok(release) => release,
+        // Not from pypi, e.g. from an internal index
+        Err(err) if is_not_found(&err) => {
+            debug!("{} {} is not on {}", name, version, pypi_host);
+            return Ok(Vec::new());
+        }
+        Err(err) => return Err(err),
+    };
+    let mut advisories = Vec::new();
+    for vulnerability in release.vulnerabilities {
+        if vulnerability.withdrawn.is_some() {
+            continue;
+        }
+        let severity = match get_json::<OsvVulnerability>(
+            transport,
+            &format!("{}/{}", OSV_API, vulnerability.id),
+        ) {
+            Ok(osv) => osv.severity(),
+            Err(err) => {
+                debug!("No severity for {}: {:#}", vulnerability.id, err);
+                None
+            }
+        };
+        advisories.push(Advisory {
+            summary: summary(
+                vulnerability.summary.as_deref().unwrap_or_default(),
+                &vulnerability.details,
+            ),
+            id: vulnerability.id,
+            aliases: vulnerability.aliases,
+            fixed_in: vulnerability.fixed_in,
+            severity,
+        });
+    }
+    Ok(advisories)
+}
+
+/// Checks each resolved spec for known vulnerabilities. Advisories whose id or alias is in
+/// `ignore` are skipped. Git, url and path dependencies can't be checked
+pub fn audit(
+    specs: &[RequestedSpec],
+    source: &AdvisorySource,
+    transport: &dyn Transport,
+    ignore: &[String],
+) -> anyhow::Result<Vec<Finding>> {
+    let database = match source {
+        AdvisorySource::Database(dir) => Some(read_database(dir)?),
+        AdvisorySource::Online { .. } => None,
+    };
+    let mut findings = Vec::new();
+    for spec in specs {
+        if spec.source.is_some() || spec.url.is_some() || spec.file_path.is_some() {
+            continue;
+        }
+        let Some(version) = &spec.python_version else {
+            continue;
+        };
+        let name = spec.normalized_name();
+        let advisories = match (&database, source) {
+            (Some(database), _) => database
+                .iter()
+                .filter(|vulnerability| vulnerability.affects(&name, version))
+                .map(|vulnerability| vulnerability.to_advisory(&name))
+                .collect(),
+            (None, AdvisorySource::Online { pypi_host }) => {
+                query_online(pypi_host, transport, &name, version).with_context(|| {
+                    format!("Failed to get the vulnerabilities of {} {}", name, version)
+                })?
+            }
+            (None, AdvisorySource::Database(_)) => unreachable!(),
+        };
+        for advisory in advisories {
+            if ignore
+                .iter()
+                .any(|ignored| *ignored == advisory.id || advisory.aliases.contains(ignored))
+            {
+                debug!("Ignoring {} for {} {}", advisory.id, name, version);
+                continue;
+            }
+            findings.push(Finding {
+                name: spec.name.clone(),
+                version: version.clone(),
+                advisory,
+            });
+        }
+    }
+    findings.sort_by(|a, b| {
+        (a.name.to_lowercase(), &a.advisory.id).cmp(&(b.name.to_lowercase(), &b.advisory.id))
+    });
+    Ok(findings)
+}
+
+/// One paragraph per finding
+pub fn format_findings(findings: &[Finding]) -> String {
+    let mut report = String::new();
+    for finding in findings {
+        let advisory = &finding.advisory;
+        let aliases = if advisory.aliases.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", advisory.aliases.join(", "))
+        };
+        report += &format!(
+            "{} {}: {}{}\n",
+            finding.name, finding.version, advisory.id, aliases
+        );
+        if !advisory.summary.is_empty() {
+            report += &format!("  {}\n", advisory.summary);
+        }
+        report += &format!(
+            "  Severity: {}\n",
+            advisory.severity.as_deref().unwrap_or("unknown")
+        );
+        if advisory.fixed_in.is_empty() {
+            report += "  No fixed version\n";
+        } else {
+            report += &format!("  Fixed in: {}\n", advisory.fixed_in.join(", "));
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use crate::audit::{audit, format_findings, AdvisorySource};
+    use crate::spec::RequestedSpec;
+    use indoc::indoc;
+    use monotrail_utils::transport::MockTransport;
+    use std::path::PathBuf;
+
+    fn specs(requested: &[&str]) -> Vec<RequestedSpec> {
+        requested
+            .iter()
+            .map(|requested| RequestedSpec::from_requested(requested, &[]).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_audit_database() {
+        let source = AdvisorySource::Database(PathBuf::from("../../test-data/advisories"));
+        let findings = audit(
+            &specs(&["jinja2==2.11.2", "urllib3==1.26.17", "Flask==2.2.5"]),
+            &source,
+            &MockTransport::default(),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            format_findings(&findings),
+            indoc! {"
+                jinja2 2.11.2: GHSA-g3rq-g295-4j3m (CVE-2020-28493, PYSEC-2021-66)
+                  Regular Expression Denial of Service (ReDoS) in Jinja2
+                  Severity: MODERATE
+                  Fixed in: 2.11.3
+                urllib3 1.26.17: PYSEC-2023-192 (CVE-2023-45803)
+                  urllib3 doesn't strip the body on a 303 redirect
+                  Severity: CVSS:3.1/AV:A/AC:H/PR:H/UI:R/S:U/C:H/I:N/A:N
+                  Fixed in: 1.26.18, 2.0.7
+            "}
+        );
+
+        let findings = audit(
+            &specs(&["jinja2==2.11.3", "urllib3==1.26.18", "urllib3==2.0.6"]),
+            &source,
+            &MockTransport::default(),
+            &["CVE-2023-45803".to_string()],
+        )
+        .unwrap();
+        assert!(findings.is_empty(), "{:?}", findings);
+
+        let findings = audit(
+            &specs(&["urllib3==2.0.6"]),
+            &source,
+            &MockTransport::default(),
+            &[],
+        )
+        .unwrap();
+        assert_eq!(findings.len(), 1);
+    }
+
+    #[test]
+    fn test_audit_online() {
+        let transport = MockTransport::default()
+            .with_response(
+                "https://pypi.org/pypi/jinja2/2.11.2/json",
+                indoc! {r#"
+                    {"info": {}, "vulnerabilities": [{
+                        "id": "PYSEC-2021-66",
+                        "aliases": ["CVE-2020-28493", "GHSA-g3rq-g295-4j3m"],
+                        "details": "This affects the package jinja2 from 0.0.0 and before 2.11.3.\nMore",
+                        "fixed_in": ["2.11.3"],
+                        "withdrawn": null
+                    }]}
+                "#},
+            )
+            .with_response(
+                "https://api.osv.dev/v1/vulns/PYSEC-2021-66",
+                r#"{"id": "PYSEC-2021-66", "severity": [{"type": "CVSS_V3", "score": "CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:N/I:N/A:L"}]}"#,
+            )
+            .with_response(
+                "https://pypi.org/pypi/flask/2.2.5/json",
+                r#"{"info": {}, "vulnerabilities": []}"#,
+            );
+        let source = AdvisorySource::Online {
+            pypi_host: "https://pypi.org".to_string(),
+        };
+        let findings = audit(
+            &specs(&["jinja2==2.11.2", "flask==2.2.5", "internal==1.0"]),
+            &source,
+            &transport,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(
+            format_findings(&findings),
+            indoc! {"
+                jinja2 2.11.2: PYSEC-2021-66 (CVE-2020-28493, GHSA-g3rq-g295-4j3m)
+                  This affects the package jinja2 from 0.0.0 and before 2.11.3.
+                  Severity: CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:N/I:N/A:L
+                  Fixed in: 2.11.3
+            "}
+        );
+    }
+}
@@ -0,0 +1,376 @@
+//! Wheels built from source distributions, shared between machines through
+//! `MONOTRAIL_BUILD_CACHE`, so only one CI machine ever compiles a given version of a heavy
+//! package. Set it to a directory (e.g. a mounted volume) or an http(s) url that supports GET
+//! and PUT.
+//!
+//! A build is identified by the hash of the source distribution, the python tag, the abi tag,
+//! the platform tag, a fingerprint of the C compiler, if there are any a hash of the
+//! `config_settings` and whether the wheel was repaired. The layout is the same for both
+//! backends, so a directory cache can also be served over http:
+//!
+//! ```text
+//! <cache>/<sdist sha256>/<python>-<abi>-<platform>-<compiler>[-<settings>][-repaired]/filename  # the wheel filename
+//! <cache>/<sdist sha256>/<python>-<abi>-<platform>-<compiler>[-<settings>][-repaired]/<wheel filename>
+//! ```
+
+use crate::config_settings::{settings_key, ConfigSettings};
+use anyhow::{bail, Context};
+use fs_err as fs;
+use install_wheel_rs::{
+    hash_backend, integrity_hash, to_hex, CompatibleTags, HashPurpose, WheelFilename,
+};
+use monotrail_utils::transport::Transport;
+use std::env;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use tracing::debug;
+
+/// Where built wheels are shared
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum BuildCache {
+    Directory(PathBuf),
+    Http(String),
+}
+
+impl BuildCache {
+    /// The cache configured through `MONOTRAIL_BUILD_CACHE`, if any
+    pub(crate) fn from_env() -> Option<Self> {
+        let value = env::var(format!(
+            "{}_BUILD_CACHE",
+            env!("CARGO_PKG_NAME").to_uppercase()
+        ))
+        .ok()?;
+        Self::parse(&value)
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        if value.is_empty() {
+            None
+        } else if value.starts_with("http://") || value.starts_with("https://") {
+            Some(Self::Http(value.trim_end_matches('/').to_string()))
+        } else {
+            Some(Self::Directory(PathBuf::from(value)))
+        }
+    }
+
+    /// Copies the wheel built for `key` into `target_dir`, `None` if nobody built it yet
+    pub(crate) fn fetch(
+        &self,
+        key: &BuildKey,
+        target_dir: &Path,
+        transport: &dyn Transport,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        let Some(filename) = self.read(&format!("{}/filename", key), transport)? else {
+            return Ok(None);
+        };
+        // The filename comes from the cache, it must not point somewhere else
+        let filename = String::from_utf8(filename)
+            .ok()
+            .filter(|filename| !filename.contains(['/', '\\']) && !filename.contains(".."))
+            .filter(|filename| WheelFilename::from_str(filename).is_ok())
+            .with_context(|| format!("Invalid wheel filename in the build cache for {}", key))?;
+        // Download to a temp file and rename, so concurrent readers and interrupted runs never see
+        // a truncated wheel
+        let mut temp_file = tempfile::NamedTempFile::new_in(target_dir)?;
+        if !self.download(
+            &format!("{}/{}", key, filename),
+            temp_file.as_file_mut(),
+            transport,
+        )? {
+            bail!("The build cache has a filename but no wheel for {}", key);
+        }
+        let target = target_dir.join(&filename);
+        temp_file.persist(&target)?;
+        Ok(Some(target))
+    }
+
+    /// Shares the wheel built for `key`
+    pub(crate) fn store(
+        &self,
+        key: &BuildKey,
+        wheel: &Path,
+        transport: &dyn Transport,
+    ) -> anyhow::Result<()> {
+        let filename = wheel
+            .file_name()
+            .context("Expected a file")?
+            .to_string_lossy()
+            .to_string();
+        // Upload the wheel first, so readers never see a filename without a wheel
+        self.write(
+            &format!("{}/{}", key, filename),
+            &fs::read(wheel)?,
+            transport,
+        )?;
+        self.write(&format!("{}/filename", key), filename.as_bytes(), transport)
+    }
+
+    fn read(&self, path: &str, transport: &dyn Transport) -> anyhow::Result<Option<Vec<u8>>> {
+        match self {
+            BuildCache::Directory(dir) => match fs::read(dir.join(path)) {
+                Ok(content) => Ok(Some(content)),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(err) => Err(err.into()),
+            },
+            BuildCache::Http(base) => {
+                // Transport doesn't distinguish a 404 from other failures, a cache miss is never
+                // fatal anyway
+                let url = format!("{}/{}", base, path);
+                match transport.get(&url) {
+                    Ok(mut reader) => {
+                        let mut content = Vec::new();
+                        reader.read_to_end(&mut content)?;
+                        Ok(Some(content))
+                    }
+                    Err(err) => {
+                        debug!("Build cache miss for {}: {}", url, err);
+                        Ok(None)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Like [BuildCache::read], but streams into `writer` instead of reading the whole wheel into
+    /// memory. Over http, this goes around the [crate::http_cache::HttpCache], which would store a
+    /// second copy of the wheel
+    fn download(
+        &self,
+        path: &str,
+        writer: &mut impl Write,
+        transport: &dyn Transport,
+    ) -> anyhow::Result<bool> {
+        let mut reader: Box<dyn Read> = match self {
+            BuildCache::Directory(dir) => match fs::File::open(dir.join(path)) {
+                Ok(file) => Box::new(file),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+                Err(err) => return Err(err.into()),
+            },
+            BuildCache::Http(base) => {
+                let url = format!("{}/{}", base, path);
+                match transport.get_range(&url, 0) {
+                    Ok(reader) => reader,
+                    Err(err) => {
+                        debug!("Build cache miss for {}: {}", url, err);
+                        return Ok(false);
+                    }
+                }
+            }
+        };
+        io::copy(&mut reader, writer).with_context(|| format!("Failed to download {}", path))?;
+        Ok(true)
+    }
+
+    fn write(&self, path: &str, content: &[u8], transport: &dyn Transport) -> anyhow::Result<()> {
+        match self {
+            BuildCache::Directory(dir) => {
+                let target = dir.join(path);
+                fs::create_dir_all(target.parent().unwrap_or(dir))?;
+                // Write to a temp file and rename, so concurrent readers never see a partial file
+                let temp_file = tempfile::NamedTempFile::new_in(target.parent().unwrap_or(dir))?;
+                fs::write(temp_file.path(), content)?;
+                temp_file.persist(&target)?;
+                Ok(())
+            }
+            BuildCache::Http(base) => transport.put(&format!("{}/{}", base, path), content),
+        }
+    }
+}
+
+/// Everything that determines the wheel we get from building a source distribution
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) struct BuildKey {
+    pub sdist_sha256: String,
+    /// The most specific compatible tag, e.g. `cp38-cp38-manylinux_2_17_x86_64`
+    pub tag: String,
+    pub compiler: String,
+    /// See [settings_key]
+    pub config_settings: Option<String>,
+    /// Whether the shared libraries were copied into the wheel, see `MONOTRAIL_REPAIR_WHEELS`
+    pub repaired: bool,
+}
+
+impl BuildKey {
+    pub(crate) fn new(
+        sdist: &Path,
+        compatible_tags: &CompatibleTags,
+        config_settings: &ConfigSettings,
+        repaired: bool,
+    ) -> anyhow::Result<Self> {
+        let (_, sdist_hash) = integrity_hash(&mut fs::File::open(sdist)?)?;
+        let (python_tag, abi_tag, platform_tag) = compatible_tags
+            .first()
+            .context("No compatible tags for this platform")?;
+        Ok(Self {
+            sdist_sha256: to_hex(&sdist_hash),
+            tag: format!("{}-{}-{}", python_tag, abi_tag, platform_tag),
+            compiler: compiler_fingerprint(),
+            config_settings: settings_key(config_settings)?,
+            repaired,
+        })
+    }
+}
+
+impl std::fmt::Display for BuildKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}-{}", self.sdist_sha256, self.tag, self.compiler)?;
+        if let Some(config_settings) = &self.config_settings {
+            write!(f, "-{}", config_settings)?;
+        }
+        if self.repaired {
+            write!(f, "-repaired")?;
+        }
+        Ok(())
+    }
+}
+
+/// A short hash of `$CC --version`, `CFLAGS` and `CXXFLAGS`, `nocc` if there is no C compiler
+fn compiler_fingerprint() -> String {
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let Ok(output) = Command::new(&cc).arg("--version").output() else {
+        return "nocc".to_string();
+    };
+    if !output.status.success() {
+        return "nocc".to_string();
+    }
+    let mut hasher = hash_backend(HashPurpose::CacheKey).hasher();
+    hasher.update(&output.stdout);
+    for flags in ["CFLAGS", "CXXFLAGS"] {
+        hasher.update(env::var(flags).unwrap_or_default().as_bytes());
+        hasher.update(b"\0");
+    }
+    to_hex(&hasher.finalize())[..16].to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::build_cache::{BuildCache, BuildKey};
+    use fs_err as fs;
+    use monotrail_utils::transport::MockTransport;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    fn key() -> BuildKey {
+        BuildKey {
+            sdist_sha256: "ab12".to_string(),
+            tag: "cp38-cp38-manylinux_2_17_x86_64".to_string(),
+            compiler: "0123456789abcdef".to_string(),
+            config_settings: None,
+            repaired: false,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(BuildCache::parse(""), None);
+        assert_eq!(
+            BuildCache::parse("https://cache.example.org/wheels/"),
+            Some(BuildCache::Http(
+                "https://cache.example.org/wheels".to_string()
+            ))
+        );
+        assert_eq!(
+            BuildCache::parse("/mnt/ci-cache"),
+            Some(BuildCache::Directory(PathBuf::from("/mnt/ci-cache")))
+        );
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let temp_dir = tempdir().unwrap();
+        let wheel = temp_dir
+            .path()
+            .join("tqdm-4.62.3-cp38-cp38-linux_x86_64.whl");
+        fs::write(&wheel, "wheel content").unwrap();
+        let target_dir = tempdir().unwrap();
+        let transport = MockTransport::default();
+
+        let directory = BuildCache::Directory(temp_dir.path().join("cache"));
+        let http = BuildCache::Http("https://cache.example.org".to_string());
+        for cache in [directory, http] {
+            assert_eq!(
+                cache.fetch(&key(), target_dir.path(), &transport).unwrap(),
+                None
+            );
+            cache.store(&key(), &wheel, &transport).unwrap();
+            let fetched = cache
+                .fetch(&key(), target_dir.path(), &transport)
+                .unwrap()
+                .unwrap();
+            assert_eq!(fetched.file_name(), wheel.file_name());
+            assert_eq!(fs::read_to_string(fetched).unwrap(), "wheel content");
+        }
+        assert_eq!(
+            transport.uploads(),
+            [
+                "https://cache.example.org/ab12/cp38-cp38-manylinux_2_17_x86_64-0123456789abcdef/filename",
+                "https://cache.example.org/ab12/cp38-cp38-manylinux_2_17_x86_64-0123456789abcdef/tqdm-4.62.3-cp38-cp38-linux_x86_64.whl"
+            ]
+        );
+
+        // Builds with config settings don't share the wheel with those without
+        let with_settings = BuildKey {
+            config_settings: Some("fedcba9876543210".to_string()),
+            ..key()
+        };
+        assert_eq!(
+            with_settings.to_string(),
+            "ab12/cp38-cp38-manylinux_2_17_x86_64-0123456789abcdef-fedcba9876543210"
+        );
+        // Nor do repaired wheels with unrepaired ones
+        let repaired = BuildKey {
+            repaired: true,
+            ..key()
+        };
+        assert_eq!(
+            repaired.to_string(),
+            "ab12/cp38-cp38-manylinux_2_17_x86_64-0123456789abcdef-repaired"
+        );
+    }
+
+    /// An interrupted download must not leave a truncated wheel behind
+    #[test]
+    fn test_fetch_interrupted() {
+        let target_dir = tempdir().unwrap();
+        let base = format!("https://cache.example.org/{}", key());
+        let transport = MockTransport::default()
+            .with_response(format!("{}/filename", base), "tqdm-4.62.3-py3-none-any.whl")
+            .with_response(
+                format!("{}/tqdm-4.62.3-py3-none-any.whl", base),
+                "wheel content",
+            )
+            .with_interruption(format!("{}/tqdm-4.62.3-py3-none-any.whl", base), 5);
+        let cache = BuildCache::Http("https://cache.example.org".to_string());
+        cache
+            .fetch(&key(), target_dir.path(), &transport)
+            .unwrap_err();
+        assert_eq!(fs::read_dir(target_dir.path()).unwrap().count(), 0);
+    }
+
+    /// The cache must not make us write outside of the target dir
+    #[test]
+    fn test_fetch_rejects_paths() {
+        let cache_dir = tempdir().unwrap();
+        let target_dir = tempdir().unwrap();
+        let cache = BuildCache::Directory(cache_dir.path().to_path_buf());
+        let key_dir = cache_dir.path().join(key().to_string());
+        fs::create_dir_all(&key_dir).unwrap();
+        fs::write(
+            cache_dir.path().join("tqdm-4.62.3-py3-none-any.whl"),
+            "wheel content",
+        )
+        .unwrap();
+        for filename in [
+            "../../tqdm-4.62.3-py3-none-any.whl",
+            "/tmp/tqdm-4.62.3-py3-none-any.whl",
+        ] {
+            fs::write(key_dir.join("filename"), filename).unwrap();
+            let err = cache
+                .fetch(&key(), target_dir.path(), &MockTransport::default())
+                .unwrap_err();
+            assert!(err.to_string().starts_with("Invalid wheel filename"));
+        }
+    }
+}
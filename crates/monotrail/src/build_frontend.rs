@@ -0,0 +1,677 @@
+//! PEP 517 build frontend: Builds wheels and source distributions from a source tree by calling
+//! the hooks of its build backend in a subprocess.
+//!
+//! Each build gets an isolated environment, into which we resolve and install the
+//! `[build-system] requires` (and whatever `get_requires_for_build_*` asks for) with our own
+//! resolver and installer. The backend only sees that environment and the standard library, not
+//! the user's packages. The environments are cached by interpreter and requirements, so ten
+//! source distributions that all need `setuptools>=61` share one environment. A cached
+//! environment never changes, the extra requirements of `get_requires_for_build_*` get another
+//! environment with both sets. Its output is captured, logged at debug level and included in
+//! the error when the build fails.
+//!
+//! <https://peps.python.org/pep-0517/>
+
+use crate::config_settings::{hook_argument, ConfigSettings};
+use crate::http_cache::HttpCache;
+use crate::install::install_all;
+use crate::interpreter::query_interpreter;
+use crate::monotrail::{LaunchType, PythonContext};
+use crate::poetry_integration::lock::poetry_resolve;
+use crate::poetry_integration::read_dependencies::{read_poetry_specs, requirements_for_poetry};
+use crate::utils::cache_dir;
+use anyhow::{bail, format_err, Context, Result};
+use flate2::read::GzDecoder;
+use fs2::FileExt;
+use fs_err as fs;
+use fs_err::File;
+use install_wheel_rs::{
+    cache_key, read_wheel_metadata, InstallLocation, NoProgress, SchemePaths, WheelFilename,
+};
+use monotrail_utils::transport::UreqTransport;
+use serde::Deserialize;
+use serde_json::Value;
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use tar::Archive;
+use tempfile::TempDir;
+use tracing::debug;
+
+/// Calls a single hook of the backend and writes the result to a json file
+static PEP517_HOOK: &str = include_str!("pep517_hook.py");
+
+/// The fallback for source trees without `[build-system]`, as specified by PEP 517
+const LEGACY_REQUIRES: [&str; 2] = ["setuptools>=40.8.0", "wheel"];
+const LEGACY_BACKEND: &str = "setuptools.build_meta:__legacy__";
+
+/// The cached build environments, `<cache>/build-envs/<key>`
+pub(crate) const BUILD_ENVS: &str = "build-envs";
+/// Written once all requirements are installed, with the requirements
+const COMPLETE_MARKER: &str = "build-requirements.txt";
+
+#[derive(Deserialize, Debug)]
+struct PyprojectToml {
+    #[serde(rename = "build-system")]
+    build_system: Option<BuildSystem>,
+}
+
+/// The `[build-system]` table of a pyproject.toml
+///
+/// <https://peps.python.org/pep-0518/#build-system-table>
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct BuildSystem {
+    /// PEP 508 requirements of the build environment
+    pub requires: Vec<String>,
+    /// `module` or `module:object`, `None` means the legacy setuptools backend
+    pub build_backend: Option<String>,
+    /// Directories in the source tree that contain an in-tree backend
+    #[serde(default)]
+    pub backend_path: Vec<String>,
+}
+
+impl BuildSystem {
+    /// Reads `[build-system]` from the pyproject.toml in the source tree, falling back to
+    /// setuptools if there is no pyproject.toml or no `[build-system]`
+    pub fn from_source_tree(source_tree: &Path) -> Result<Self> {
+        let pyproject_toml = match fs::read_to_string(source_tree.join("pyproject.toml")) {
+            Ok(pyproject_toml) => pyproject_toml,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::legacy()),
+            Err(err) => return Err(err.into()),
+        };
+        let pyproject_toml: PyprojectToml = toml::from_str(&pyproject_toml).with_context(|| {
+            format!(
+                "Invalid [build-system] in {}",
+                source_tree.join("pyproject.toml").display()
+            )
+        })?;
+        Ok(pyproject_toml.build_system.unwrap_or_else(Self::legacy))
+    }
+
+    fn legacy() -> Self {
+        Self {
+            requires: LEGACY_REQUIRES.iter().map(ToString::to_string).collect(),
+            build_backend: None,
+            backend_path: Vec::new(),
+        }
+    }
+
+    /// The backend to import
+    pub fn backend(&self) -> &str {
+        self.build_backend.as_deref().unwrap_or(LEGACY_BACKEND)
+    }
+}
+
+/// A directory in the cache with the build requirements, which are installed into
+/// `site-packages` with `bin/python` pointing to the actual interpreter, so scripts in the build
+/// environment get a working shebang
+pub struct BuildEnvironment {
+    root: PathBuf,
+    python_context: PythonContext,
+    /// Shared lock on the environment, held for as long as the build uses it so it doesn't get
+    /// recreated underneath us
+    _lock: File,
+}
+
+impl BuildEnvironment {
+    /// The build environment with `requirements` for the given python, which is created and
+    /// installed if it isn't in the cache yet
+    pub fn cached(python_context: &PythonContext, requirements: &[String]) -> Result<Self> {
+        Self::cached_in(&cache_dir()?.join(BUILD_ENVS), python_context, requirements)
+    }
+
+    fn cached_in(
+        envs_dir: &Path,
+        python_context: &PythonContext,
+        requirements: &[String],
+    ) -> Result<Self> {
+        let mut requirements = requirements.to_vec();
+        requirements.sort();
+        requirements.dedup();
+        let key = cache_key(format!(
+            "{}\n{}.{}\n{}",
+            python_context.sys_executable.display(),
+            python_context.version.0,
+            python_context.version.1,
+            requirements.join("\n")
+        ));
+        fs::create_dir_all(envs_dir).context("Failed to create the build environment")?;
+        let environment = Self {
+            root: envs_dir.join(&key),
+            python_context: python_context.clone(),
+            _lock: File::create(envs_dir.join(format!("{}.lock", key)))?,
+        };
+        let complete = environment.root.join(COMPLETE_MARKER);
+        // Builds using a complete environment share it, creating it needs it exclusively
+        environment._lock.file().lock_shared()?;
+        if complete.is_file() {
+            debug!(
+                "Reusing the build environment {} with {}",
+                key,
+                requirements.join(", ")
+            );
+            return Ok(environment);
+        }
+        environment._lock.file().unlock()?;
+        if environment._lock.file().try_lock_exclusive().is_err() {
+            debug!(
+                "Waiting for another process using the build environment {}",
+                key
+            );
+            environment._lock.file().lock_exclusive()?;
+        }
+        // Another process may have created it while we were waiting
+        if !complete.is_file() {
+            // Left over from an aborted or failed attempt
+            if environment.root.is_dir() {
+                fs::remove_dir_all(&environment.root)?;
+            }
+            fs::create_dir_all(environment.scripts())?;
+            fs::create_dir_all(environment.site_packages())?;
+            #[cfg(unix)]
+            fs_err::os::unix::fs::symlink(
+                &python_context.sys_executable,
+                environment.scripts().join("python"),
+            )?;
+            environment.install(&requirements)?;
+            fs::write(&complete, requirements.join("\n"))?;
+        }
+        environment._lock.file().lock_shared()?;
+        Ok(environment)
+    }
+
+    fn scheme() -> SchemePaths {
+        SchemePaths {
+            purelib: PathBuf::from("site-packages"),
+            platlib: PathBuf::from("site-packages"),
+            scripts: PathBuf::from("bin"),
+            data: PathBuf::from("."),
+            include: PathBuf::from("include"),
+        }
+    }
+
+    fn site_packages(&self) -> PathBuf {
+        self.root.join(Self::scheme().purelib)
+    }
+
+    fn scripts(&self) -> PathBuf {
+        self.root.join(Self::scheme().scripts)
+    }
+
+    fn python(&self) -> PathBuf {
+        if cfg!(unix) {
+            self.scripts().join("python")
+        } else {
+            self.python_context.sys_executable.clone()
+        }
+    }
+
+    /// Resolves the requirements and installs them into the build environment
+    fn install(&self, requirements: &[String]) -> Result<()> {
+        if requirements.is_empty() {
+            return Ok(());
+        }
+        debug!("Installing build requirements {}", requirements.join(", "));
+        let dependencies = requirements_for_poetry(requirements)?;
+        let (poetry_section, poetry_lock, _lockfile) =
+            poetry_resolve(&dependencies, None, &self.python_context).with_context(|| {
+                format!(
+                    "Failed to resolve the build requirements {}",
+                    requirements.join(", ")
+                )
+            })?;
+        let specs = read_poetry_specs(
+            &poetry_section,
+            poetry_lock,
+            &self.root,
+            true,
+            &[],
+            &self.python_context.pep508_env,
+        )?;
+        let location = InstallLocation::venv(self.root.clone(), self.python_context.version)
+            .with_scheme(Self::scheme())
+            .acquire_lock()?;
+        install_all(
+            &specs,
+            &location,
+            &location.compatible_tags()?,
+            false,
+            false,
+            false,
+            false,
+            false,
+            &HttpCache::new(&UreqTransport)?,
+            &NoProgress,
+        )
+        .context("Failed to install the build requirements")?;
+        Ok(())
+    }
+
+    /// Calls `hook` of the backend in a subprocess in `source_tree` and returns its return value
+    pub fn call_hook(
+        &self,
+        source_tree: &Path,
+        build_system: &BuildSystem,
+        hook: &str,
+        args: &[Value],
+    ) -> Result<Value> {
+        let hook_dir = TempDir::new()?;
+        let hook_script = hook_dir.path().join("pep517_hook.py");
+        fs::write(&hook_script, PEP517_HOOK)?;
+        let result_file = hook_dir.path().join("result.json");
+
+        let path = env::var_os("PATH").unwrap_or_default();
+        let path = env::join_paths([self.scripts()].into_iter().chain(env::split_paths(&path)))?;
+        debug!("Calling {} of {}", hook, build_system.backend());
+        let output = Command::new(self.python())
+            .current_dir(source_tree)
+            // No user site-packages, only the build environment
+            .arg("-s")
+            .arg(&hook_script)
+            .arg(&result_file)
+            .arg(build_system.backend())
+            .arg(serde_json::to_string(&build_system.backend_path)?)
+            .arg(hook)
+            .arg(serde_json::to_string(args)?)
+            .env("PYTHONPATH", self.site_packages())
+            .env("PYTHONNOUSERSITE", "1")
+            .env("PATH", path)
+            .env_remove("PYTHONHOME")
+            .output()
+            .with_context(|| format!("Failed to run {}", self.python().display()))?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            bail!(
+                "{} of {} failed in {}: {}\n---stdout:\n{}\n---stderr:\n{}\n---",
+                hook,
+                build_system.backend(),
+                source_tree.display(),
+                output.status,
+                stdout.trim(),
+                stderr.trim()
+            );
+        }
+        debug!(
+            "{} of {}\n---stdout:\n{}\n---stderr:\n{}\n---",
+            hook,
+            build_system.backend(),
+            stdout.trim(),
+            stderr.trim()
+        );
+
+        let result: Value = serde_json::from_str(&fs::read_to_string(&result_file)?)
+            .with_context(|| format!("{} returned invalid json", hook))?;
+        Ok(result["result"].clone())
+    }
+}
+
+/// Builds a wheel from the source tree into `out_dir` and returns its path
+pub fn build_wheel(
+    source_tree: &Path,
+    out_dir: &Path,
+    python_context: &PythonContext,
+    config_settings: &ConfigSettings,
+) -> Result<PathBuf> {
+    build(
+        source_tree,
+        out_dir,
+        python_context,
+        config_settings,
+        "wheel",
+    )
+}
+
+/// Builds a source distribution from the source tree into `out_dir` and returns its path
+#[allow(dead_code)] // Only the wheel is needed for installing
+pub fn build_sdist(
+    source_tree: &Path,
+    out_dir: &Path,
+    python_context: &PythonContext,
+    config_settings: &ConfigSettings,
+) -> Result<PathBuf> {
+    build(
+        source_tree,
+        out_dir,
+        python_context,
+        config_settings,
+        "sdist",
+    )
+}
+
+/// The `METADATA` of the wheel the source tree would build. It comes from
+/// `prepare_metadata_for_build_wheel` if the backend has it, otherwise we build the wheel
+pub fn wheel_metadata(
+    source_tree: &Path,
+    python_context: &PythonContext,
+    config_settings: &ConfigSettings,
+) -> Result<String> {
+    let (source_tree, build_system, environment) =
+        build_environment(source_tree, python_context, config_settings, "wheel")?;
+    let out_dir = TempDir::new()?;
+    let args = [
+        Value::String(out_dir.path().to_string_lossy().to_string()),
+        hook_argument(config_settings)?,
+    ];
+    let dist_info = environment.call_hook(
+        &source_tree,
+        &build_system,
+        "prepare_metadata_for_build_wheel",
+        &args,
+    )?;
+    if let Some(dist_info) = dist_info.as_str() {
+        let metadata = out_dir.path().join(dist_info).join("METADATA");
+        return fs::read_to_string(&metadata).with_context(|| {
+            format!(
+                "prepare_metadata_for_build_wheel of {} didn't write {}",
+                build_system.backend(),
+                metadata.display()
+            )
+        });
+    }
+    let wheel = call_build_hook(
+        &source_tree,
+        out_dir.path(),
+        &build_system,
+        &environment,
+        config_settings,
+        "wheel",
+    )?;
+    let filename =
+        WheelFilename::from_str(&wheel.file_name().unwrap_or_default().to_string_lossy())?;
+    Ok(read_wheel_metadata(&filename, File::open(&wheel)?)?)
+}
+
+/// The source tree and its build system with the build requirements installed. `kind` is either
+/// `wheel` or `sdist`, which selects the hooks
+fn build_environment(
+    source_tree: &Path,
+    python_context: &PythonContext,
+    config_settings: &ConfigSettings,
+    kind: &str,
+) -> Result<(PathBuf, BuildSystem, BuildEnvironment)> {
+    let source_tree = source_tree
+        .canonicalize()
+        .with_context(|| format!("Can't build {}", source_tree.display()))?;
+    let build_system = BuildSystem::from_source_tree(&source_tree)?;
+    let environment = BuildEnvironment::cached(python_context, &build_system.requires)?;
+
+    let requires = environment.call_hook(
+        &source_tree,
+        &build_system,
+        &format!("get_requires_for_build_{}", kind),
+        &[hook_argument(config_settings)?],
+    )?;
+    let requires: Vec<String> = serde_json::from_value(requires).with_context(|| {
+        format!(
+            "get_requires_for_build_{} must return a list of strings",
+            kind
+        )
+    })?;
+    let environment = if requires.is_empty() {
+        environment
+    } else {
+        let all_requires = [build_system.requires.clone(), requires].concat();
+        BuildEnvironment::cached(python_context, &all_requires)?
+    };
+    Ok((source_tree, build_system, environment))
+}
+
+fn build(
+    source_tree: &Path,
+    out_dir: &Path,
+    python_context: &PythonContext,
+    config_settings: &ConfigSettings,
+    kind: &str,
+) -> Result<PathBuf> {
+    let (source_tree, build_system, environment) =
+        build_environment(source_tree, python_context, config_settings, kind)?;
+    fs::create_dir_all(out_dir)?;
+    call_build_hook(
+        &source_tree,
+        &out_dir.canonicalize()?,
+        &build_system,
+        &environment,
+        config_settings,
+        kind,
+    )
+}
+
+/// Calls `build_wheel` or `build_sdist` and returns the path of what it built
+fn call_build_hook(
+    source_tree: &Path,
+    out_dir: &Path,
+    build_system: &BuildSystem,
+    environment: &BuildEnvironment,
+    config_settings: &ConfigSettings,
+    kind: &str,
+) -> Result<PathBuf> {
+    let filename = environment.call_hook(
+        source_tree,
+        build_system,
+        &format!("build_{}", kind),
+        &[
+            Value::String(out_dir.to_string_lossy().to_string()),
+            hook_argument(config_settings)?,
+        ],
+    )?;
+    let filename = filename
+        .as_str()
+        .ok_or_else(|| format_err!("build_{} must return the filename, not {}", kind, filename))?;
+    let built = out_dir.join(filename);
+    if !built.is_file() {
+        bail!(
+            "The build backend {} didn't write {}",
+            build_system.backend(),
+            built.display()
+        );
+    }
+    Ok(built)
+}
+
+/// The python context of the interpreter used for building, queried from the interpreter
+pub fn python_context_for(python: &Path) -> Result<PythonContext> {
+    let interpreter = query_interpreter(python)?;
+    Ok(PythonContext {
+        sys_executable: python.to_path_buf(),
+        version: interpreter.python_version(),
+        pep508_env: interpreter.markers,
+        launch_type: LaunchType::Binary,
+    })
+}
+
+/// Unpacks a `.tar.gz` source distribution into `target` and returns the source tree, i.e. the
+/// single top level directory such as `tqdm-4.66.1`
+pub fn unpack_sdist(sdist: &Path, target: &Path) -> Result<PathBuf> {
+    let reader = GzDecoder::new(File::open(sdist)?);
+    // `unpack` skips entries that would end up outside of target
+    Archive::new(reader)
+        .unpack(target)
+        .with_context(|| format!("Failed to unpack {}", sdist.display()))?;
+    let mut entries = fs::read_dir(target)?.collect::<io::Result<Vec<_>>>()?;
+    match entries.as_mut_slice() {
+        [entry] if entry.file_type()?.is_dir() => Ok(entry.path()),
+        _ => bail!(
+            "Expected a single top level directory in {}",
+            sdist.display()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::build_frontend::{
+        build_sdist, python_context_for, unpack_sdist, BuildEnvironment, BuildSystem,
+    };
+    use crate::config_settings::{ConfigSetting, ConfigSettings};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use fs_err as fs;
+    use fs_err::File;
+    use indoc::indoc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_build_system() {
+        let source_tree = TempDir::new().unwrap();
+        // No pyproject.toml
+        let legacy = BuildSystem::from_source_tree(source_tree.path()).unwrap();
+        assert_eq!(legacy.requires, ["setuptools>=40.8.0", "wheel"]);
+        assert_eq!(legacy.backend(), "setuptools.build_meta:__legacy__");
+
+        // No [build-system]
+        fs::write(
+            source_tree.path().join("pyproject.toml"),
+            "[tool.black]\nline-length = 100\n",
+        )
+        .unwrap();
+        assert_eq!(
+            BuildSystem::from_source_tree(source_tree.path()).unwrap(),
+            legacy
+        );
+
+        fs::write(
+            source_tree.path().join("pyproject.toml"),
+            indoc! {r#"
+                [build-system]
+                requires = ["maturin>=1.0,<2.0"]
+                build-backend = "backend"
+                backend-path = ["_build"]
+            "#},
+        )
+        .unwrap();
+        let build_system = BuildSystem::from_source_tree(source_tree.path()).unwrap();
+        assert_eq!(build_system.requires, ["maturin>=1.0,<2.0"]);
+        assert_eq!(build_system.backend(), "backend");
+        assert_eq!(build_system.backend_path, ["_build"]);
+    }
+
+    #[test]
+    fn test_unpack_sdist() {
+        let temp_dir = TempDir::new().unwrap();
+        let sdist = temp_dir.path().join("foo-1.0.tar.gz");
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            File::create(&sdist).unwrap(),
+            Compression::default(),
+        ));
+        let content = b"[build-system]\nrequires = []\n";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "foo-1.0/pyproject.toml", &content[..])
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let target = temp_dir.path().join("unpacked");
+        fs::create_dir(&target).unwrap();
+        let source_tree = unpack_sdist(&sdist, &target).unwrap();
+        assert_eq!(source_tree, target.join("foo-1.0"));
+        assert_eq!(
+            BuildSystem::from_source_tree(&source_tree)
+                .unwrap()
+                .requires
+                .len(),
+            0
+        );
+    }
+
+    /// Environments with the same requirements are shared, broken ones are recreated
+    #[test]
+    fn test_cached_build_environment() {
+        let python_context = python_context_for(&which::which("python3").unwrap()).unwrap();
+        let envs_dir = TempDir::new().unwrap();
+        let first = BuildEnvironment::cached_in(envs_dir.path(), &python_context, &[]).unwrap();
+        // Reusing a complete environment only needs the shared lock
+        let second = BuildEnvironment::cached_in(envs_dir.path(), &python_context, &[]).unwrap();
+        assert_eq!(first.root, second.root);
+        assert!(first.site_packages().is_dir());
+        let root = first.root.clone();
+        drop((first, second));
+
+        // A leftover from an aborted attempt
+        fs::remove_file(root.join("build-requirements.txt")).unwrap();
+        fs::write(root.join("site-packages").join("partial.py"), "").unwrap();
+        let third = BuildEnvironment::cached_in(envs_dir.path(), &python_context, &[]).unwrap();
+        assert_eq!(third.root, root);
+        assert!(!third.site_packages().join("partial.py").exists());
+    }
+
+    /// An in-tree backend without requirements, so we don't need the network
+    #[test]
+    fn test_build_in_tree_backend() {
+        let source_tree = TempDir::new().unwrap();
+        fs::write(
+            source_tree.path().join("pyproject.toml"),
+            indoc! {r#"
+                [build-system]
+                requires = []
+                build-backend = "backend:Backend"
+                backend-path = ["_build"]
+            "#},
+        )
+        .unwrap();
+        fs::create_dir(source_tree.path().join("_build")).unwrap();
+        fs::write(
+            source_tree.path().join("_build").join("backend.py"),
+            indoc! {r#"
+                import json
+                import os
+                import sys
+
+                class Backend:
+                    @staticmethod
+                    def build_sdist(sdist_directory, config_settings=None):
+                        print("building the sdist")
+                        if "--fail" in os.listdir("."):
+                            sys.exit("failing as requested")
+                        with open(os.path.join(sdist_directory, "foo-1.0.tar.gz"), "w") as fp:
+                            json.dump(config_settings, fp)
+                        return "foo-1.0.tar.gz"
+            "#},
+        )
+        .unwrap();
+        let python_context = python_context_for(&which::which("python3").unwrap()).unwrap();
+        let out_dir = TempDir::new().unwrap();
+        let config_settings =
+            ConfigSettings::from([("flag".to_string(), ConfigSetting::Single("on".to_string()))]);
+        let sdist = build_sdist(
+            source_tree.path(),
+            out_dir.path(),
+            &python_context,
+            &config_settings,
+        )
+        .unwrap();
+        assert_eq!(
+            sdist,
+            out_dir
+                .path()
+                .canonicalize()
+                .unwrap()
+                .join("foo-1.0.tar.gz")
+        );
+        assert_eq!(fs::read_to_string(&sdist).unwrap(), r#"{"flag": "on"}"#);
+
+        fs::write(source_tree.path().join("--fail"), "").unwrap();
+        let err = build_sdist(
+            source_tree.path(),
+            out_dir.path(),
+            &python_context,
+            &ConfigSettings::new(),
+        )
+        .unwrap_err();
+        let err = format!("{:#}", err);
+        assert!(
+            err.contains("build_sdist of backend:Backend failed"),
+            "{}",
+            err
+        );
+        // The captured output of the backend
+        assert!(err.contains("building the sdist"), "{}", err);
+        assert!(err.contains("failing as requested"), "{}", err);
+    }
+}
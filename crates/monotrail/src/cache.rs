@@ -0,0 +1,504 @@
+//! `monotrail cache`: Inspects and cleans up the cache directory.
+//!
+//! Downloaded distributions are in `<cache>/artifacts/<name>/<version>/<filename>`. We bump the
+//! modification time of an artifact whenever we use it from the cache, so `cache prune
+//! --older-than` can remove what hasn't been used in a while.
+
+use crate::build_frontend::BUILD_ENVS;
+use crate::spec::same_version;
+use crate::utils::get_dir_content;
+use anyhow::Context;
+use fs2::FileExt;
+use fs_err as fs;
+use install_wheel_rs::{normalize_name, verify_wheel, WheelFilename};
+use serde::Serialize;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use tracing::{debug, warn};
+use walkdir::WalkDir;
+
+/// `monotrail cache dir|info|clean|prune|verify`
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum CacheCommand {
+    /// Print the cache directory
+    Dir,
+    /// Show the size of each part of the cache and of the downloads of each package
+    Info,
+    /// Remove the downloads and the index responses, or only the downloads of the given packages
+    Clean {
+        /// Only remove the downloaded distributions of those packages
+        packages: Vec<String>,
+    },
+    /// Remove the downloads that weren't used for some days or that aren't installed in the
+    /// monotrail store or the current venv, and leftovers of aborted downloads
+    Prune {
+        /// Remove downloads that weren't used in this many days
+        #[clap(long)]
+        older_than: Option<u64>,
+        /// Remove downloads of versions that aren't installed in the monotrail store or the
+        /// current venv
+        #[clap(long)]
+        unreferenced: bool,
+    },
+    /// Check the cached wheels against the hashes in their RECORD, removing corrupted ones
+    Verify {
+        /// Only report corrupted wheels instead of removing them
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+/// The downloads of one package
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PackageUsage {
+    /// The directory name, the name as it was requested
+    pub name: String,
+    /// The number of cached versions
+    pub versions: usize,
+    /// Bytes on disk
+    pub size: u64,
+}
+
+/// A downloaded distribution
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Artifact {
+    name: String,
+    version: String,
+    path: PathBuf,
+}
+
+/// The total size of the files below `path`, 0 if it doesn't exist
+pub fn dir_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// e.g. `1.5 MiB`
+pub fn format_size(size: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024. && unit + 1 < units.len() {
+        value /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", size)
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+/// The size of each top level directory of the cache, largest first
+pub fn cache_sections(cache_dir: &Path) -> anyhow::Result<Vec<(String, u64)>> {
+    let mut sections: Vec<(String, u64)> = get_dir_content(cache_dir)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|entry| {
+            (
+                entry.file_name().to_string_lossy().to_string(),
+                dir_size(&entry.path()),
+            )
+        })
+        .collect();
+    sections.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Ok(sections)
+}
+
+/// The size of the downloads of each package, largest first
+pub fn package_usage(cache_dir: &Path) -> anyhow::Result<Vec<PackageUsage>> {
+    let mut usage: Vec<PackageUsage> = get_dir_content(&cache_dir.join("artifacts"))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|entry| PackageUsage {
+            name: entry.file_name().to_string_lossy().to_string(),
+            versions: get_dir_content(&entry.path()).map_or(0, |versions| versions.len()),
+            size: dir_size(&entry.path()),
+        })
+        .collect();
+    usage.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+    Ok(usage)
+}
+
+/// An aligned table of the parts of the cache and the largest packages
+pub fn format_cache_info(
+    cache_dir: &Path,
+    sections: &[(String, u64)],
+    packages: &[PackageUsage],
+) -> String {
+    let total: u64 = sections.iter().map(|(_, size)| size).sum();
+    let mut info = format!("{} ({})\n", cache_dir.display(), format_size(total));
+    let width = sections
+        .iter()
+        .map(|(name, _)| name.len())
+        .max()
+        .unwrap_or(0);
+    for (name, size) in sections {
+        info += &format!("  {:width$}  {}\n", name, format_size(*size), width = width);
+    }
+    if !packages.is_empty() {
+        info += "Downloads by package:\n";
+        let width = packages
+            .iter()
+            .map(|package| package.name.len())
+            .max()
+            .unwrap_or(0);
+        for package in packages {
+            info += &format!(
+                "  {:width$}  {} ({} {})\n",
+                package.name,
+                format_size(package.size),
+                package.versions,
+                if package.versions == 1 {
+                    "version"
+                } else {
+                    "versions"
+                },
+                width = width
+            );
+        }
+    }
+    info
+}
+
+fn list_artifacts(cache_dir: &Path) -> anyhow::Result<Vec<Artifact>> {
+    let mut artifacts = Vec::new();
+    for name_dir in get_dir_content(&cache_dir.join("artifacts")).unwrap_or_default() {
+        for version_dir in get_dir_content(&name_dir.path())? {
+            for entry in fs::read_dir(version_dir.path())? {
+                let path = entry?.path();
+                if path.is_file() {
+                    artifacts.push(Artifact {
+                        name: name_dir.file_name().to_string_lossy().to_string(),
+                        version: version_dir.file_name().to_string_lossy().to_string(),
+                        path,
+                    });
+                }
+            }
+        }
+    }
+    artifacts.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(artifacts)
+}
+
+/// Removes the file and then its version and package directories if they became empty.
+/// Returns the freed bytes
+fn remove_artifact(artifacts_dir: &Path, path: &Path) -> anyhow::Result<u64> {
+    let size = fs::metadata(path)?.len();
+    fs::remove_file(path)?;
+    for dir in path.ancestors().skip(1).take(2) {
+        if dir == artifacts_dir || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+    Ok(size)
+}
+
+/// Removes the downloads, the http cache and the build environments, or only the downloads of
+/// `packages`. Returns the freed bytes
+pub fn clean_cache(cache_dir: &Path, packages: &[String]) -> anyhow::Result<u64> {
+    let targets: Vec<PathBuf> = if packages.is_empty() {
+        vec![
+            cache_dir.join("artifacts"),
+            cache_dir.join("http"),
+            cache_dir.join(BUILD_ENVS),
+        ]
+    } else {
+        let packages: Vec<String> = packages.iter().map(|name| normalize_name(name)).collect();
+        get_dir_content(&cache_dir.join("artifacts"))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|entry| {
+                packages.contains(&normalize_name(&entry.file_name().to_string_lossy()))
+            })
+            .map(|entry| entry.path())
+            .collect()
+    };
+    let mut freed = 0;
+    for target in targets {
+        let size = dir_size(&target);
+        match fs::remove_dir_all(&target) {
+            Ok(()) => freed += size,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).context("Failed to clean the cache"),
+        }
+    }
+    Ok(freed)
+}
+
+/// What `cache prune` removes besides partial downloads
+#[derive(Debug, Default)]
+pub struct PruneOptions {
+    /// Downloads whose modification time is older
+    pub older_than: Option<Duration>,
+    /// If set, downloads of other name and version pairs (normalized names)
+    pub referenced: Option<Vec<(String, String)>>,
+}
+
+/// Removes `.part` files of aborted downloads and the downloads that match `options`. Returns
+/// the number of removed files and the freed bytes. `.part` files that a running download holds
+/// the lock on stay
+pub fn prune_cache(
+    cache_dir: &Path,
+    options: &PruneOptions,
+    now: SystemTime,
+) -> anyhow::Result<(usize, u64)> {
+    let artifacts_dir = cache_dir.join("artifacts");
+    let mut removed = 0;
+    let mut freed = 0;
+    for artifact in list_artifacts(cache_dir)? {
+        let partial = artifact.path.extension().is_some_and(|ext| ext == "part");
+        let unused = options.older_than.is_some_and(|older_than| {
+            fs::metadata(&artifact.path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .is_some_and(|age| age > older_than)
+        });
+        let unreferenced = options.referenced.as_ref().is_some_and(|referenced| {
+            let name = normalize_name(&artifact.name);
+            !referenced
+                .iter()
+                .any(|(other, version)| *other == name && same_version(version, &artifact.version))
+        });
+        if !(partial || unused || unreferenced) {
+            continue;
+        }
+        // Held until the file is gone, so a download can't start writing to it in between
+        let _lock = if partial {
+            match lock_partial_download(&artifact.path)? {
+                Some(lock) => Some(lock),
+                None => {
+                    debug!(
+                        "Skipping {}, it's being downloaded",
+                        artifact.path.display()
+                    );
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        debug!("Removing {}", artifact.path.display());
+        freed += remove_artifact(&artifacts_dir, &artifact.path)?;
+        removed += 1;
+    }
+    Ok((removed, freed))
+}
+
+/// Takes the lock a download holds on its `.part` file, `None` if a download is running
+fn lock_partial_download(path: &Path) -> anyhow::Result<Option<fs::File>> {
+    let file = fs::File::open(path)?;
+    if file.file().try_lock_exclusive().is_err() {
+        return Ok(None);
+    }
+    Ok(Some(file))
+}
+
+/// Checks the cached wheels against their RECORD. Returns the corrupted wheels with the reason
+pub fn verify_cache(cache_dir: &Path) -> anyhow::Result<Vec<(PathBuf, String)>> {
+    let mut corrupted = Vec::new();
+    for artifact in list_artifacts(cache_dir)? {
+        let Some(filename) = artifact
+            .path
+            .file_name()
+            .map(|filename| filename.to_string_lossy().to_string())
+            .filter(|filename| filename.ends_with(".whl"))
+        else {
+            continue;
+        };
+        let filename = match WheelFilename::from_str(&filename) {
+            Ok(filename) => filename,
+            Err(err) => {
+                warn!("Skipping {}: {}", artifact.path.display(), err);
+                continue;
+            }
+        };
+        match verify_wheel(&filename, fs::File::open(&artifact.path)?) {
+            Ok(failing) if failing.is_empty() => {}
+            Ok(failing) => corrupted.push((
+                artifact.path,
+                format!("Doesn't match its RECORD: {}", failing.join(", ")),
+            )),
+            Err(err) => corrupted.push((artifact.path, err.to_string())),
+        }
+    }
+    Ok(corrupted)
+}
+
+/// Removes corrupted wheels found by [verify_cache]
+pub fn remove_corrupted(cache_dir: &Path, corrupted: &[(PathBuf, String)]) -> anyhow::Result<()> {
+    let artifacts_dir = cache_dir.join("artifacts");
+    for (path, _reason) in corrupted {
+        remove_artifact(&artifacts_dir, path)?;
+    }
+    Ok(())
+}
+
+/// Marks a cached artifact as used for `cache prune --older-than`. Artifacts of other users in a
+/// shared cache may not be writable, which is fine
+pub(crate) fn touch_artifact(path: &Path) {
+    let result = std::fs::File::options()
+        .append(true)
+        .open(path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(err) = result {
+        debug!("Couldn't update the mtime of {}: {}", path.display(), err);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cache::{
+        clean_cache, format_size, package_usage, prune_cache, remove_corrupted, verify_cache,
+        PruneOptions,
+    };
+    use fs2::FileExt;
+    use fs_err as fs;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+    use tempfile::TempDir;
+
+    fn add_artifact(cache_dir: &Path, name: &str, version: &str, filename: &str) {
+        let dir = cache_dir.join("artifacts").join(name).join(version);
+        fs::create_dir_all(&dir).unwrap();
+        let wheel = Path::new("../../test-data/wheels").join(filename);
+        if wheel.is_file() {
+            fs::copy(wheel, dir.join(filename)).unwrap();
+        } else {
+            fs::write(dir.join(filename), "not a zip").unwrap();
+        }
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_prune_and_clean() {
+        let cache_dir = TempDir::new().unwrap();
+        let cache_dir = cache_dir.path();
+        add_artifact(
+            cache_dir,
+            "miniblack",
+            "23.1.0",
+            "miniblack-23.1.0-py3-none-any.whl",
+        );
+        add_artifact(
+            cache_dir,
+            "colander",
+            "0.9.9",
+            "colander-0.9.9-py2.py3-none-any.whl",
+        );
+        add_artifact(cache_dir, "colander", "1.0", "colander-1.0.tar.gz.part");
+        add_artifact(cache_dir, "colander", "1.1", "colander-1.1.tar.gz.part");
+        // A running download
+        let downloading =
+            fs::File::open(cache_dir.join("artifacts/colander/1.1/colander-1.1.tar.gz.part"))
+                .unwrap();
+        downloading.file().lock_exclusive().unwrap();
+
+        let usage = package_usage(cache_dir).unwrap();
+        let names: Vec<_> = usage.iter().map(|package| package.name.as_str()).collect();
+        assert_eq!(names, ["colander", "miniblack"]);
+        assert_eq!(usage[0].versions, 3);
+
+        // Only the aborted partial download
+        let (removed, _) =
+            prune_cache(cache_dir, &PruneOptions::default(), SystemTime::now()).unwrap();
+        assert_eq!(removed, 1);
+        assert!(!cache_dir.join("artifacts/colander/1.0").exists());
+        assert!(cache_dir.join("artifacts/colander/1.1").exists());
+        drop(downloading);
+        prune_cache(cache_dir, &PruneOptions::default(), SystemTime::now()).unwrap();
+        assert!(!cache_dir.join("artifacts/colander/1.1").exists());
+
+        // Nothing was used in the last second from the point of view of tomorrow
+        let tomorrow = SystemTime::now() + Duration::from_secs(24 * 60 * 60);
+        let options = PruneOptions {
+            older_than: Some(Duration::from_secs(2 * 24 * 60 * 60)),
+            referenced: Some(vec![("miniblack".to_string(), "23.1".to_string())]),
+        };
+        let (removed, freed) = prune_cache(cache_dir, &options, tomorrow).unwrap();
+        assert_eq!(removed, 1);
+        assert!(freed > 0);
+        assert!(!cache_dir.join("artifacts/colander").exists());
+        assert!(cache_dir.join("artifacts/miniblack/23.1.0").is_dir());
+
+        let options = PruneOptions {
+            older_than: Some(Duration::from_secs(60 * 60)),
+            referenced: None,
+        };
+        prune_cache(cache_dir, &options, tomorrow).unwrap();
+        assert!(!cache_dir.join("artifacts/miniblack").exists());
+
+        add_artifact(
+            cache_dir,
+            "miniblack",
+            "23.1.0",
+            "miniblack-23.1.0-py3-none-any.whl",
+        );
+        fs::create_dir_all(cache_dir.join("http")).unwrap();
+        fs::create_dir_all(cache_dir.join("build-envs").join("0123")).unwrap();
+        assert_eq!(clean_cache(cache_dir, &["other".to_string()]).unwrap(), 0);
+        assert!(clean_cache(cache_dir, &[]).unwrap() > 0);
+        assert!(!cache_dir.join("artifacts").exists());
+        assert!(!cache_dir.join("http").exists());
+        assert!(!cache_dir.join("build-envs").exists());
+    }
+
+    #[test]
+    fn test_verify_cache() {
+        let cache_dir = TempDir::new().unwrap();
+        let cache_dir = cache_dir.path();
+        add_artifact(
+            cache_dir,
+            "colander",
+            "0.9.9",
+            "colander-0.9.9-py2.py3-none-any.whl",
+        );
+        // The RECORD of this test wheel lists a file that was removed from the wheel
+        add_artifact(
+            cache_dir,
+            "miniblack",
+            "23.1.0",
+            "miniblack-23.1.0-py3-none-any.whl",
+        );
+        add_artifact(cache_dir, "broken", "1.0", "broken-1.0-py3-none-any.whl");
+        let corrupted = verify_cache(cache_dir).unwrap();
+        let reasons: Vec<(String, &str)> = corrupted
+            .iter()
+            .map(|(path, reason)| {
+                let filename = path.file_name().unwrap().to_string_lossy().to_string();
+                (filename, reason.as_str())
+            })
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                (
+                    "broken-1.0-py3-none-any.whl".to_string(),
+                    "Failed to read the wheel file (index)"
+                ),
+                (
+                    "miniblack-23.1.0-py3-none-any.whl".to_string(),
+                    "Doesn't match its RECORD: blackd/middlewares.py"
+                )
+            ]
+        );
+        remove_corrupted(cache_dir, &corrupted).unwrap();
+        assert!(!cache_dir.join("artifacts/broken").exists());
+        assert!(cache_dir.join("artifacts/colander/0.9.9").is_dir());
+        assert!(verify_cache(cache_dir).unwrap().is_empty());
+    }
+}
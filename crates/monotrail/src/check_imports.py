@@ -0,0 +1,17 @@
+"""
+Imports the modules given as arguments and prints `<module>\t<error>` for each one that fails
+
+Runs in its own process per package so a crashing extension module only takes down its own check
+"""
+import importlib
+import sys
+
+failed = False
+for module in sys.argv[1:]:
+    try:
+        importlib.import_module(module)
+    except BaseException as err:  # noqa: B036 SystemExit and KeyboardInterrupt are failures too
+        message = f"{type(err).__name__}: {err}".replace("\n", " ")
+        print(f"{module}\t{message}")
+        failed = True
+sys.exit(1 if failed else 0)
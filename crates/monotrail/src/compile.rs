@@ -0,0 +1,180 @@
+//! pip-tools style `compile`: Resolves a requirements.in or the dependencies in a pyproject.toml
+//! with poetry and writes a fully pinned requirements.txt with hashes, so users can adopt
+//! monotrail without switching their lockfile format
+
+use crate::monotrail::PythonContext;
+use crate::path_dependency::{absolute_path_dependencies, is_path_source};
+use crate::pep621::read_pep621_project;
+use crate::poetry_integration::lock::poetry_resolve;
+use crate::poetry_integration::poetry_lock::PoetryLock;
+use crate::poetry_integration::poetry_toml::{self, PoetryPyprojectToml};
+use crate::poetry_integration::read_dependencies::read_requirements_for_poetry;
+use crate::read_poetry_specs;
+use crate::spec::RequestedSpec;
+use anyhow::{bail, Context};
+use fs_err as fs;
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::warn;
+use url::Url;
+
+/// Reads the top level requirements from a `pyproject.toml` (`[project.dependencies]` or
+/// `[tool.poetry.dependencies]`) or a requirements.in. Also returns the poetry.lock next to a
+/// pyproject.toml, so poetry keeps the locked versions where possible
+fn read_input(
+    input: &Path,
+    groups: &[String],
+    python_context: &PythonContext,
+) -> anyhow::Result<(BTreeMap<String, poetry_toml::Dependency>, Option<String>)> {
+    let working_dir = input.parent().unwrap_or(Path::new("."));
+    if input.file_name().and_then(|name| name.to_str()) != Some("pyproject.toml") {
+        if !groups.is_empty() {
+            bail!("Dependency groups can only be used with a pyproject.toml");
+        }
+        let dependencies =
+            read_requirements_for_poetry(input, working_dir, &python_context.pep508_env)?;
+        return Ok((dependencies, None));
+    }
+
+    let dependencies = if let Some(project) = read_pep621_project(working_dir)? {
+        project.requirements_for_poetry(&[], groups, &python_context.pep508_env)?
+    } else {
+        let poetry: PoetryPyprojectToml = toml::from_str(&fs::read_to_string(input)?)
+            .with_context(|| format!("Invalid pyproject.toml at {}", input.display()))?;
+        let poetry_section = poetry.tool.and_then(|tool| tool.poetry).with_context(|| {
+            format!("Neither [project] nor [tool.poetry] in {}", input.display())
+        })?;
+        let dependencies = poetry_section
+            .dependencies
+            .into_iter()
+            // Optional dependencies are only for extras
+            .filter(|(name, dependency)| name != "python" && !dependency.is_optional())
+            .collect();
+        absolute_path_dependencies(dependencies, working_dir)
+    };
+    let lockfile = fs::read_to_string(working_dir.join("poetry.lock")).ok();
+    Ok((dependencies, lockfile))
+}
+
+/// Resolves `input` for the given python and returns the content of the pinned requirements.txt
+pub fn compile_requirements(
+    input: &Path,
+    groups: &[String],
+    python_context: &PythonContext,
+) -> anyhow::Result<String> {
+    let (dependencies, lockfile) = read_input(input, groups, python_context)?;
+    let working_dir = input.parent().unwrap_or(Path::new("."));
+    let (poetry_section, poetry_lock, _lockfile) =
+        poetry_resolve(&dependencies, lockfile.as_deref(), python_context)
+            .with_context(|| format!("Failed to resolve {}", input.display()))?;
+    let specs = read_poetry_specs(
+        &poetry_section,
+        poetry_lock.clone(),
+        working_dir,
+        true,
+        &[],
+        &python_context.pep508_env,
+    )?;
+    let header = format!(
+        "# This file was generated by monotrail for python {}.{} with:\n#\n#    monotrail compile {}{}\n#\n",
+        python_context.version.0,
+        python_context.version.1,
+        input.display(),
+        groups
+            .iter()
+            .map(|group| format!(" --group {}", group))
+            .collect::<String>()
+    );
+    Ok(header + &pinned_requirements(&specs, &poetry_lock))
+}
+
+/// One `name==version` line per spec, with `--hash` for every file in the lock, sorted by name
+fn pinned_requirements(specs: &[RequestedSpec], poetry_lock: &PoetryLock) -> String {
+    let mut specs = specs.to_vec();
+    specs.sort_by_key(|spec| spec.normalized_name());
+    let mut requirements = String::new();
+    for spec in specs {
+        let extras = if spec.extras.is_empty() {
+            String::new()
+        } else {
+            let mut extras = spec.extras.clone();
+            extras.sort();
+            format!("[{}]", extras.join(","))
+        };
+        if let Some(source) = &spec.source {
+            // pip can't check hashes of git checkouts or local paths
+            if is_path_source(&source.source_type) {
+                let url = Url::from_file_path(&source.url)
+                    .map_or_else(|()| source.url.clone(), |url| url.to_string());
+                requirements += &format!("{}{} @ {}\n", spec.name, extras, url);
+                continue;
+            }
+            requirements += &format!(
+                "{}{} @ git+{}@{}\n",
+                spec.name, extras, source.url, source.resolved_reference
+            );
+            continue;
+        }
+        let version = spec.python_version.as_deref().unwrap_or_default();
+        requirements += &format!("{}{}=={}", spec.name, extras, version);
+        let files = poetry_lock
+            .get_filenames(&spec.name)
+            .cloned()
+            .unwrap_or_default();
+        if files.is_empty() {
+            warn!("No hashes in the lock for {} {}", spec.name, version);
+        }
+        for file in files {
+            requirements += &format!(" \\\n    --hash={}", file.hash);
+        }
+        requirements += "\n";
+    }
+    requirements
+}
+
+#[cfg(test)]
+mod test {
+    use crate::compile::pinned_requirements;
+    use crate::poetry_integration::read_dependencies::read_toml_files;
+    use crate::read_poetry_specs;
+    use crate::test_utils::test_pep508_env;
+    use indoc::indoc;
+    use std::path::Path;
+
+    #[test]
+    fn test_pinned_requirements() {
+        let pep508_env = test_pep508_env();
+        let (poetry_section, poetry_lock, _lockfile) =
+            read_toml_files(Path::new("../../test-data/poetry-1.3-django")).unwrap();
+        let specs = read_poetry_specs(
+            &poetry_section,
+            poetry_lock.clone(),
+            Path::new("../../test-data/poetry-1.3-django"),
+            true,
+            &[],
+            &pep508_env,
+        )
+        .unwrap();
+        let requirements = pinned_requirements(&specs, &poetry_lock);
+        // tzdata is windows only
+        let names: Vec<&str> = requirements
+            .lines()
+            .filter(|line| !line.starts_with(' '))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "asgiref==3.5.2 \\",
+                "backports-zoneinfo==0.2.1 \\",
+                "django==4.1.2 \\",
+                "sqlparse==0.4.3 \\"
+            ]
+        );
+        assert!(requirements.starts_with(indoc! {r"
+            asgiref==3.5.2 \
+                --hash=sha256:1d2880b792ae8757289136f1db2b7b99100ce959b2aa57fd69dab783d05afac4 \
+                --hash=sha256:4a29362a6acebe09bf1d6640db38c1dc3d9217c68e6f9f6204d72667fc19a424
+            backports-zoneinfo==0.2.1 \
+        "}));
+    }
+}
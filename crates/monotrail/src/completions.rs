@@ -0,0 +1,335 @@
+//! `monotrail completions <shell>`: Shell completions for bash, zsh, fish, powershell and elvish.
+//!
+//! The subcommands and options come from clap_complete. On top of that, bash, zsh, fish and
+//! powershell complete the console scripts of the current venv after `run` and the installed
+//! packages after `remove`, `tree --invert` and `lock --upgrade-package`, by calling the hidden
+//! `monotrail completions --list scripts|packages` while completing.
+
+use crate::cli::Cli;
+use crate::output::OutputOptions;
+use crate::progress::ProgressOptions;
+use crate::snapshot::venv_site_packages;
+use crate::venv_parser::get_venv_python_version;
+use crate::{GlobalOptions, Verbosity};
+use clap::{Args, CommandFactory};
+use clap_complete::Shell;
+use install_wheel_rs::{installed_dist_infos, EntryPoints};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// What `monotrail completions --list` prints for the completion scripts
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CompletionList {
+    /// The names of the packages installed in the current venv
+    Packages,
+    /// The console scripts of the packages installed in the current venv
+    Scripts,
+}
+
+/// The options whose value is an installed package
+const PACKAGE_OPTIONS: [&str; 4] = ["--invert", "-i", "--upgrade-package", "-P"];
+
+/// The command line as main.rs parses it, i.e. with the global options
+fn cli_command() -> clap::Command {
+    let command = Cli::command().name("monotrail");
+    let command = Verbosity::augment_args(command);
+    let command = ProgressOptions::augment_args(command);
+    let command = GlobalOptions::augment_args(command);
+    OutputOptions::augment_args(command)
+}
+
+/// The options of the top level command, `run` and `remove` that take a value, which the shell
+/// functions skip when walking back to the subcommand
+fn value_options(command: &clap::Command) -> Vec<String> {
+    let subcommands = command
+        .get_subcommands()
+        .filter(|subcommand| ["run", "remove"].contains(&subcommand.get_name()));
+    let mut options = BTreeSet::new();
+    for command in std::iter::once(command).chain(subcommands) {
+        for arg in command.get_arguments() {
+            if arg.is_positional() || !arg.get_action().takes_values() {
+                continue;
+            }
+            options.extend(arg.get_long().map(|long| format!("--{}", long)));
+            options.extend(arg.get_short().map(|short| format!("-{}", short)));
+        }
+    }
+    options.into_iter().collect()
+}
+
+/// The completion script for `shell`, to be sourced by the shell
+pub fn completion_script(shell: Shell) -> String {
+    let mut command = cli_command();
+    let value_options = value_options(&command).join("|");
+    let mut script = Vec::new();
+    clap_complete::generate(shell, &mut command, "monotrail", &mut script);
+    // clap_complete only writes utf-8
+    let script = String::from_utf8(script).unwrap();
+    let package_options = PACKAGE_OPTIONS.join(" ");
+    match shell {
+        Shell::Bash => script + &bash_dynamic(&package_options, &value_options),
+        Shell::Zsh => script + &zsh_dynamic(&package_options, &value_options),
+        Shell::Fish => script + FISH_DYNAMIC,
+        Shell::PowerShell => powershell_dynamic(&script, &package_options),
+        _ => script,
+    }
+}
+
+/// Wraps the generated `_monotrail` function. We walk back to the subcommand, so e.g.
+/// `run -p 3.11 <TAB>` and `run command <TAB>` complete the scripts, too
+fn bash_dynamic(package_options: &str, value_options: &str) -> String {
+    let package_cases = package_options.replace(' ', "|");
+    format!(
+        r#"
+_monotrail_dynamic() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    case "${{COMP_WORDS[COMP_CWORD-1]}}" in
+        {package_cases})
+            COMPREPLY=($(compgen -W "$(monotrail completions --list packages 2>/dev/null)" -- "$cur"))
+            return
+            ;;
+    esac
+    local i word subcommand="" positionals=""
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        word="${{COMP_WORDS[i]}}"
+        case "$word" in
+            {value_cases})
+                ((i++))
+                ;;
+            -*)
+                ;;
+            *)
+                if [[ -z "$subcommand" ]]; then
+                    subcommand="$word"
+                else
+                    positionals="$positionals $word"
+                fi
+                ;;
+        esac
+    done
+    if [[ "$cur" != -* ]]; then
+        case "$subcommand:$positionals" in
+            run:|exec:)
+                COMPREPLY=($(compgen -W "python command $(monotrail completions --list scripts 2>/dev/null)" -- "$cur"))
+                return
+                ;;
+            "run: command"|"exec: command")
+                COMPREPLY=($(compgen -W "$(monotrail completions --list scripts 2>/dev/null)" -- "$cur"))
+                return
+                ;;
+            remove:*)
+                COMPREPLY=($(compgen -W "$(monotrail completions --list packages 2>/dev/null)" -- "$cur"))
+                return
+                ;;
+        esac
+    fi
+    _monotrail "$@"
+}}
+
+complete -F _monotrail_dynamic -o nosort -o bashdefault -o default monotrail
+"#,
+        package_cases = package_cases,
+        value_cases = value_options
+    )
+}
+
+/// Wraps the generated `_monotrail` function, so this needs to be sourced (e.g.
+/// `source <(monotrail completions zsh)`) instead of being autoloaded from `fpath`. Like for bash,
+/// we walk back to the subcommand
+fn zsh_dynamic(package_options: &str, value_options: &str) -> String {
+    let package_cases = package_options.replace(' ', "|");
+    format!(
+        r#"
+_monotrail_dynamic() {{
+    case "${{words[CURRENT-1]}}" in
+        {package_cases})
+            compadd -- ${{(f)"$(monotrail completions --list packages 2>/dev/null)"}}
+            return
+            ;;
+    esac
+    local i word subcommand="" positionals=""
+    for ((i = 2; i < CURRENT; i++)); do
+        word="${{words[i]}}"
+        case "$word" in
+            {value_cases})
+                ((i++))
+                ;;
+            -*)
+                ;;
+            *)
+                if [[ -z "$subcommand" ]]; then
+                    subcommand="$word"
+                else
+                    positionals="$positionals $word"
+                fi
+                ;;
+        esac
+    done
+    if [[ "${{words[CURRENT]}}" != -* ]]; then
+        case "$subcommand:$positionals" in
+            run:|exec:)
+                compadd -- python command ${{(f)"$(monotrail completions --list scripts 2>/dev/null)"}}
+                return
+                ;;
+            "run: command"|"exec: command")
+                compadd -- ${{(f)"$(monotrail completions --list scripts 2>/dev/null)"}}
+                return
+                ;;
+            remove:*)
+                compadd -- ${{(f)"$(monotrail completions --list packages 2>/dev/null)"}}
+                return
+                ;;
+        esac
+    fi
+    _monotrail "$@"
+}}
+
+compdef _monotrail_dynamic monotrail
+"#,
+        package_cases = package_cases,
+        value_cases = value_options
+    )
+}
+
+/// fish merges multiple `complete` definitions, so these just add to the generated ones
+const FISH_DYNAMIC: &str = r#"
+complete -c monotrail -n "__fish_seen_subcommand_from run exec; and test (count (commandline -opc)) -eq 2" -f -a "python command (monotrail completions --list scripts 2>/dev/null)"
+complete -c monotrail -n "__fish_seen_subcommand_from run exec; and __fish_seen_subcommand_from command; and test (count (commandline -opc)) -eq 3" -f -a "(monotrail completions --list scripts 2>/dev/null)"
+complete -c monotrail -n "__fish_seen_subcommand_from remove" -f -a "(monotrail completions --list packages 2>/dev/null)"
+complete -c monotrail -n "__fish_seen_subcommand_from tree" -s i -l invert -r -f -a "(monotrail completions --list packages 2>/dev/null)"
+complete -c monotrail -n "__fish_seen_subcommand_from lock" -s P -l upgrade-package -r -f -a "(monotrail completions --list packages 2>/dev/null)"
+"#;
+
+/// Registering a completer replaces the previous one, so we keep the generated one as fallback
+fn powershell_dynamic(script: &str, package_options: &str) -> String {
+    let package_options: Vec<String> = package_options
+        .split(' ')
+        .map(|option| format!("'{}'", option))
+        .collect();
+    let script = script.replacen(
+        "Register-ArgumentCompleter -Native -CommandName 'monotrail' -ScriptBlock {",
+        "$global:__monotrailStatic = {",
+        1,
+    );
+    format!(
+        r#"{script}
+Register-ArgumentCompleter -Native -CommandName 'monotrail' -ScriptBlock {{
+    param($wordToComplete, $commandAst, $cursorPosition)
+    $previous = $commandAst.CommandElements |
+        Where-Object {{ $_.Extent.EndOffset -lt ($cursorPosition - $wordToComplete.Length) }} |
+        Select-Object -Last 1
+    $names = switch -CaseSensitive ("$previous") {{
+        {{ $_ -cin 'run', 'exec' }} {{ @('python', 'command') + @(monotrail completions --list scripts 2>$null) }}
+        {{ $_ -cin {package_options} }} {{ @(monotrail completions --list packages 2>$null) }}
+    }}
+    if ($null -eq $names -and "$($commandAst.CommandElements[1])" -ceq 'remove' -and -not $wordToComplete.StartsWith('-')) {{
+        $names = @(monotrail completions --list packages 2>$null)
+    }}
+    if ($null -eq $names) {{
+        return & $global:__monotrailStatic $wordToComplete $commandAst $cursorPosition
+    }}
+    $names | Where-Object {{ $_ -like "$wordToComplete*" }} | ForEach-Object {{
+        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)
+    }}
+}}
+"#,
+        script = script,
+        package_options = package_options.join(", ")
+    )
+}
+
+/// The installed packages or their console scripts, sorted. Reading the venv must be fast and
+/// must not fail loudly since it runs on every tab press
+pub fn complete_names(list: CompletionList, venv: &Path) -> anyhow::Result<Vec<String>> {
+    let site_packages = venv_site_packages(venv, get_venv_python_version(venv)?);
+    let mut names = BTreeSet::new();
+    for (name, _version, dist_info) in installed_dist_infos(&site_packages)? {
+        match list {
+            CompletionList::Packages => {
+                names.insert(name);
+            }
+            CompletionList::Scripts => {
+                // A broken entry_points.txt shouldn't hide the scripts of the other packages
+                let Ok(entry_points) = EntryPoints::from_dist_info(&site_packages.join(dist_info))
+                else {
+                    continue;
+                };
+                names.extend(
+                    entry_points
+                        .group("console_scripts")
+                        .map(|entry_point| entry_point.name.clone()),
+                );
+            }
+        }
+    }
+    Ok(names.into_iter().collect())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::completions::{cli_command, complete_names, completion_script, CompletionList};
+    use clap_complete::Shell;
+    use fs_err as fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_cli_command() {
+        cli_command().debug_assert();
+        let matches = cli_command()
+            .try_get_matches_from(["monotrail", "--format", "json", "tree", "-i", "tqdm"])
+            .unwrap();
+        assert_eq!(matches.subcommand_name(), Some("tree"));
+    }
+
+    #[test]
+    fn test_completion_scripts() {
+        for shell in [Shell::Bash, Shell::Zsh, Shell::Fish, Shell::PowerShell] {
+            let script = completion_script(shell);
+            assert!(script.contains("upgrade-package"), "{}", shell);
+            assert!(
+                script.contains("monotrail completions --list packages"),
+                "{}",
+                shell
+            );
+        }
+        let script = completion_script(Shell::PowerShell);
+        assert_eq!(script.matches("Register-ArgumentCompleter").count(), 1);
+        // `run -p 3.11 <TAB>` skips the value of `-p` to find the subcommand
+        let script = completion_script(Shell::Bash);
+        assert!(script.contains("|--python-version|"));
+        assert!(!script.contains("|--isolated|"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_complete_names() {
+        let temp_dir = TempDir::new().unwrap();
+        let venv = temp_dir.path();
+        fs::write(venv.join("pyvenv.cfg"), "version_info = 3.11.4\n").unwrap();
+        let site_packages = venv.join("lib").join("python3.11").join("site-packages");
+        for (name, scripts) in [
+            ("black", "black = black:main"),
+            ("tqdm", "tqdm = tqdm.cli:main"),
+        ] {
+            let dist_info = site_packages.join(format!("{}-1.0.dist-info", name));
+            fs::create_dir_all(&dist_info).unwrap();
+            fs::write(
+                dist_info.join("entry_points.txt"),
+                format!("[console_scripts]\n{}\n", scripts),
+            )
+            .unwrap();
+        }
+        let broken = site_packages.join("attrs-23.1.0.dist-info");
+        fs::create_dir_all(&broken).unwrap();
+        fs::write(broken.join("entry_points.txt"), "[console_scripts\n").unwrap();
+
+        assert_eq!(
+            complete_names(CompletionList::Packages, venv).unwrap(),
+            ["attrs", "black", "tqdm"]
+        );
+        assert_eq!(
+            complete_names(CompletionList::Scripts, venv).unwrap(),
+            ["black", "tqdm"]
+        );
+    }
+}
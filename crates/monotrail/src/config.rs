@@ -0,0 +1,299 @@
+//! Settings from config files, so they don't have to be passed on every invocation. From lowest
+//! to highest precedence:
+//!
+//!  * the user config file, `MONOTRAIL_CONFIG` or `~/.config/monotrail/config.toml`, which also
+//!    has the `[network]` table (see [monotrail_utils::network])
+//!  * `monotrail.toml` in the project directory or the closest parent directory that has one
+//!  * the environment variables, e.g. `MONOTRAIL_INDEX_URL`
+//!  * the command line options, e.g. `--offline`
+//!
+//! ```toml
+//! index-url = "https://pypi.corp/simple"
+//! extra-index-urls = ["https://download.pytorch.org/whl/cpu"]
+//! find-links = ["./wheelhouse"]
+//! cache-dir = "/var/cache/monotrail"
+//! default-python = "3.11"
+//! offline = false
+//!
+//! [licenses]
+//! deny = ["GPL-3.0"]
+//!
+//! [config-settings.numpy]
+//! setup-args = ["-Dblas=openblas"]
+//! ```
+//!
+//! The rest of monotrail reads the environment variables, so [Config::apply] exports the
+//! settings as variables that aren't set yet. This also makes them visible to monotrail
+//! subprocesses.
+
+use crate::config_settings::PackageConfigSettings;
+use crate::index::find_links_var;
+use crate::interpreter::ProjectPython;
+use crate::licenses::LicensePolicy;
+use crate::DEFAULT_PYTHON_VERSION;
+use anyhow::{bail, Context};
+use fs_err as fs;
+use monotrail_utils::network::config_path;
+use monotrail_utils::parse_cpython_args::parse_major_minor;
+use serde::Deserialize;
+use std::env;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// The project config file
+pub const PROJECT_CONFIG: &str = "monotrail.toml";
+
+/// The settings of the config files, see the module documentation
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    /// Replaces pypi as main index, `MONOTRAIL_INDEX_URL`
+    pub index_url: Option<String>,
+    /// Searched before the main index, `MONOTRAIL_EXTRA_INDEX_URL`
+    #[serde(default)]
+    pub extra_index_urls: Vec<String>,
+    /// Directories and html pages with distributions, `MONOTRAIL_FIND_LINKS`. Relative paths are
+    /// relative to the config file
+    #[serde(default)]
+    pub find_links: Vec<String>,
+    /// Where the wheels, pythons and the other caches go, `MONOTRAIL_CACHE_DIR`. Relative to
+    /// the config file
+    pub cache_dir: Option<PathBuf>,
+    /// The python version x.y when none is given, `MONOTRAIL_DEFAULT_PYTHON`
+    pub default_python: Option<String>,
+    /// Only use cached index responses and downloads, `MONOTRAIL_OFFLINE`
+    pub offline: Option<bool>,
+    /// The license policy of `monotrail licenses`, see [crate::licenses]
+    pub licenses: Option<LicensePolicy>,
+    /// PEP 517 `config_settings` per package, `MONOTRAIL_CONFIG_SETTINGS`, see
+    /// [crate::config_settings]
+    #[serde(default)]
+    pub config_settings: PackageConfigSettings,
+    /// Read by [monotrail_utils::network], only allowed in the user config
+    #[serde(default)]
+    network: Option<toml::Table>,
+}
+
+impl Config {
+    /// Reads a config file, resolving relative paths against its directory
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let mut config: Self = toml::from_str(&fs::read_to_string(path)?)
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        if let Some(cache_dir) = &config.cache_dir {
+            config.cache_dir = Some(base.join(cache_dir));
+        }
+        for find_links in &mut config.find_links {
+            if !find_links.contains("://") {
+                *find_links = base.join(&*find_links).to_string_lossy().to_string();
+            }
+        }
+        config.config_settings = config.config_settings.normalized();
+        Ok(config)
+    }
+
+    /// Merges the user config and the `monotrail.toml` of the project that contains `dir`
+    pub fn load(dir: &Path) -> anyhow::Result<Self> {
+        Self::load_from(config_path().as_deref(), dir)
+    }
+
+    fn load_from(user_config: Option<&Path>, dir: &Path) -> anyhow::Result<Self> {
+        let mut config = match user_config {
+            Some(path) if path.is_file() => Self::from_file(path)?,
+            _ => Self::default(),
+        };
+        if let Some(project_config) = dir
+            .ancestors()
+            .map(|ancestor| ancestor.join(PROJECT_CONFIG))
+            .find(|path| path.is_file())
+        {
+            debug!("Using {}", project_config.display());
+            let project = Self::from_file(&project_config)?;
+            if project.network.is_some() {
+                bail!(
+                    "[network] is only allowed in the user config, not in {}",
+                    project_config.display()
+                );
+            }
+            config = config.merge(project);
+        }
+        Ok(config)
+    }
+
+    /// The settings of `other` replace those of `self`. Lists are replaced too, not extended
+    fn merge(self, other: Self) -> Self {
+        Self {
+            index_url: other.index_url.or(self.index_url),
+            extra_index_urls: if other.extra_index_urls.is_empty() {
+                self.extra_index_urls
+            } else {
+                other.extra_index_urls
+            },
+            find_links: if other.find_links.is_empty() {
+                self.find_links
+            } else {
+                other.find_links
+            },
+            cache_dir: other.cache_dir.or(self.cache_dir),
+            default_python: other.default_python.or(self.default_python),
+            offline: other.offline.or(self.offline),
+            licenses: other.licenses.or(self.licenses),
+            config_settings: self.config_settings.merge(other.config_settings),
+            network: other.network.or(self.network),
+        }
+    }
+
+    /// The environment variables for the settings, `MONOTRAIL_` prefixed
+    fn env_vars(&self) -> Vec<(&'static str, String)> {
+        let mut vars = Vec::new();
+        if let Some(index_url) = &self.index_url {
+            vars.push(("INDEX_URL", index_url.clone()));
+        }
+        if !self.extra_index_urls.is_empty() {
+            vars.push(("EXTRA_INDEX_URL", self.extra_index_urls.join(" ")));
+        }
+        if !self.find_links.is_empty() {
+            vars.push(("FIND_LINKS", find_links_var(&self.find_links)));
+        }
+        if let Some(cache_dir) = &self.cache_dir {
+            vars.push(("CACHE_DIR", cache_dir.to_string_lossy().to_string()));
+        }
+        if let Some(default_python) = &self.default_python {
+            vars.push(("DEFAULT_PYTHON", default_python.clone()));
+        }
+        if let Some(offline) = self.offline {
+            vars.push(("OFFLINE", if offline { "1" } else { "0" }.to_string()));
+        }
+        if !self.config_settings.is_empty() {
+            if let Ok(json) = serde_json::to_string(&self.config_settings) {
+                vars.push(("CONFIG_SETTINGS", json));
+            }
+        }
+        vars
+    }
+
+    /// Exports the settings as environment variables, unless the variable is already set,
+    /// since the environment takes precedence over the config files. Call this before applying
+    /// the command line options
+    pub fn apply(&self) -> anyhow::Result<()> {
+        if let Some(default_python) = &self.default_python {
+            parse_major_minor(default_python).context("Invalid default-python in config")?;
+        }
+        let prefix = env!("CARGO_PKG_NAME").to_uppercase();
+        for (name, value) in self.env_vars() {
+            let name = format!("{}_{}", prefix, name);
+            if env::var_os(&name).is_none() {
+                env::set_var(name, value);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The python version to use if none was given: `default-python` from the config or
+/// `MONOTRAIL_DEFAULT_PYTHON`, otherwise 3.8. This doesn't look at any project, see
+/// [project_python_version] for that
+pub(crate) fn default_python_version() -> anyhow::Result<(u8, u8)> {
+    let name = format!("{}_DEFAULT_PYTHON", env!("CARGO_PKG_NAME").to_uppercase());
+    match env::var(&name) {
+        Ok(version) if !version.is_empty() => {
+            Ok(parse_major_minor(&version).with_context(|| format!("Invalid {}", name))?)
+        }
+        _ => Ok(DEFAULT_PYTHON_VERSION),
+    }
+}
+
+/// The python version to use if none was given for the project in `root` or the current
+/// directory: the version in the closest `.python-version` of the project, otherwise
+/// [default_python_version] if it matches `requires-python` or otherwise the newest python we can
+/// download that does
+pub(crate) fn project_python_version(root: Option<&Path>) -> anyhow::Result<(u8, u8)> {
+    let dir = match root {
+        // The relative root has no parents to search
+        Some(root) => fs::canonicalize(root)?,
+        None => env::current_dir().context("Couldn't get current directory")?,
+    };
+    ProjectPython::find(&dir)?.python_version(default_python_version()?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Config;
+    use fs_err as fs;
+    use indoc::indoc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_layered_config() {
+        let temp_dir = TempDir::new().unwrap();
+        let user_config = temp_dir.path().join("config.toml");
+        fs::write(
+            &user_config,
+            indoc! {r#"
+                index-url = "https://pypi.corp/simple"
+                default-python = "3.10"
+                offline = true
+
+                [network]
+                https-proxy = "http://proxy.corp:3128"
+            "#},
+        )
+        .unwrap();
+        let project = temp_dir.path().join("project");
+        fs::create_dir_all(project.join("src")).unwrap();
+        fs::write(
+            project.join("monotrail.toml"),
+            indoc! {r#"
+                default-python = "3.11"
+                find-links = ["wheelhouse", "https://example.org/wheels.html"]
+
+                [config-settings.NumPy]
+                setup-args = "-Dblas=openblas"
+            "#},
+        )
+        .unwrap();
+
+        let config = Config::load_from(Some(&user_config), &project.join("src")).unwrap();
+        assert_eq!(
+            config.index_url.as_deref(),
+            Some("https://pypi.corp/simple")
+        );
+        assert_eq!(config.default_python.as_deref(), Some("3.11"));
+        assert_eq!(config.offline, Some(true));
+        assert_eq!(
+            config.find_links,
+            [
+                project.join("wheelhouse").to_string_lossy().to_string(),
+                "https://example.org/wheels.html".to_string()
+            ]
+        );
+        let vars: Vec<_> = config
+            .env_vars()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(
+            vars,
+            [
+                "INDEX_URL",
+                "FIND_LINKS",
+                "DEFAULT_PYTHON",
+                "OFFLINE",
+                "CONFIG_SETTINGS"
+            ]
+        );
+        assert_eq!(
+            config.config_settings.for_package("numpy")["setup-args"].values(),
+            ["-Dblas=openblas"]
+        );
+
+        // Only the user may configure proxies and certificates
+        fs::write(
+            project.join("monotrail.toml"),
+            "[network]\nhttps-proxy = \"http://evil:3128\"\n",
+        )
+        .unwrap();
+        let err = Config::load_from(Some(&user_config), &project).unwrap_err();
+        assert!(err.to_string().starts_with("[network] is only allowed"));
+    }
+}
@@ -0,0 +1,210 @@
+//! PEP 517 `config_settings`, which pass options to the build backend, e.g. meson-python's
+//! `setup-args` or setuptools' `--build-option`. Many native extensions need them for linking
+//! against the right libraries.
+//!
+//! They come from `--config-settings key=value` for all builds and from monotrail.toml, where
+//! `"*"` applies to all packages. A key on the command line replaces that key in the settings of
+//! every package:
+//!
+//! ```toml
+//! [config-settings."*"]
+//! editable_mode = "compat"
+//!
+//! [config-settings.numpy]
+//! setup-args = ["-Dblas=openblas", "-Dlapack=openblas"]
+//! ```
+//!
+//! Like the other settings, they are passed on as json in `MONOTRAIL_CONFIG_SETTINGS`.
+//!
+//! <https://peps.python.org/pep-0517/#config-settings>
+
+use anyhow::{bail, Context};
+use install_wheel_rs::{cache_key, normalize_name};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::env;
+
+/// The key for the settings of all packages
+const ALL_PACKAGES: &str = "*";
+
+/// A value is a string, or a list if the key is given multiple times
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ConfigSetting {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl ConfigSetting {
+    /// The values in the order they were given
+    pub fn values(&self) -> Vec<&str> {
+        match self {
+            Self::Single(value) => vec![value.as_str()],
+            Self::Multiple(values) => values.iter().map(String::as_str).collect(),
+        }
+    }
+}
+
+/// The settings for a single build
+pub type ConfigSettings = BTreeMap<String, ConfigSetting>;
+
+/// The settings for a build as argument to the hooks, `None` if there are none
+pub(crate) fn hook_argument(config_settings: &ConfigSettings) -> anyhow::Result<Value> {
+    if config_settings.is_empty() {
+        Ok(Value::Null)
+    } else {
+        Ok(serde_json::to_value(config_settings)?)
+    }
+}
+
+/// Identifies the settings in the cache keys of the built wheels, `None` if there are none, so
+/// builds without settings keep their keys
+pub(crate) fn settings_key(config_settings: &ConfigSettings) -> anyhow::Result<Option<String>> {
+    if config_settings.is_empty() {
+        return Ok(None);
+    }
+    let json = serde_json::to_string(config_settings)?;
+    Ok(Some(cache_key(json)[..16].to_string()))
+}
+
+/// The settings of all packages, keyed by the normalized name or `*`
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PackageConfigSettings(BTreeMap<String, ConfigSettings>);
+
+impl PackageConfigSettings {
+    pub(crate) fn env_var() -> String {
+        format!("{}_CONFIG_SETTINGS", env!("CARGO_PKG_NAME").to_uppercase())
+    }
+
+    /// `MONOTRAIL_CONFIG_SETTINGS`, empty if it isn't set
+    pub fn from_env() -> anyhow::Result<Self> {
+        match env::var(Self::env_var()) {
+            Ok(json) if !json.is_empty() => {
+                serde_json::from_str(&json).with_context(|| format!("Invalid {}", Self::env_var()))
+            }
+            _ => Ok(Self::default()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.values().all(BTreeMap::is_empty)
+    }
+
+    /// The settings for building `name`: those for all packages, overridden by the ones for
+    /// this package
+    pub fn for_package(&self, name: &str) -> ConfigSettings {
+        let mut config_settings = self.0.get(ALL_PACKAGES).cloned().unwrap_or_default();
+        if let Some(package) = self.0.get(&normalize_name(name)) {
+            config_settings.extend(package.clone());
+        }
+        config_settings
+    }
+
+    /// Normalizes the package names, e.g. from the config file
+    pub(crate) fn normalized(self) -> Self {
+        Self(
+            self.0
+                .into_iter()
+                .map(|(name, config_settings)| {
+                    if name == ALL_PACKAGES {
+                        (name, config_settings)
+                    } else {
+                        (normalize_name(&name), config_settings)
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    /// The settings of `other` replace those of `self` key by key
+    pub(crate) fn merge(mut self, other: Self) -> Self {
+        for (name, config_settings) in other.0 {
+            self.0.entry(name).or_default().extend(config_settings);
+        }
+        self
+    }
+
+    /// Adds `key=value` options of the command line for all packages, replacing the key in the
+    /// settings of single packages too. A key given multiple times becomes a list
+    pub(crate) fn with_options(mut self, options: &[String]) -> anyhow::Result<Self> {
+        let mut from_options = ConfigSettings::new();
+        for option in options {
+            let Some((key, value)) = option.split_once('=') else {
+                bail!("Invalid --config-settings {}, expected `key=value`", option);
+            };
+            let value = value.to_string();
+            let setting = match from_options.remove(key) {
+                None => ConfigSetting::Single(value),
+                Some(ConfigSetting::Single(first)) => ConfigSetting::Multiple(vec![first, value]),
+                Some(ConfigSetting::Multiple(mut values)) => {
+                    values.push(value);
+                    ConfigSetting::Multiple(values)
+                }
+            };
+            from_options.insert(key.to_string(), setting);
+        }
+        self.0.entry(ALL_PACKAGES.to_string()).or_default();
+        for config_settings in self.0.values_mut() {
+            config_settings.extend(from_options.clone());
+        }
+        Ok(self)
+    }
+
+    /// Exports the settings for the builds of this run and of subprocesses
+    pub(crate) fn set(&self) -> anyhow::Result<()> {
+        env::set_var(Self::env_var(), serde_json::to_string(self)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config_settings::{ConfigSetting, PackageConfigSettings};
+    use indoc::indoc;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_for_package() {
+        let from_toml: BTreeMap<String, PackageConfigSettings> = toml::from_str(indoc! {r#"
+            [config-settings."*"]
+            editable_mode = "compat"
+            setup-args = "-Dfoo=bar"
+
+            [config-settings.NumPy]
+            setup-args = ["-Dblas=openblas", "-Dlapack=openblas"]
+        "#})
+        .unwrap();
+        let from_config = from_toml["config-settings"].clone().normalized();
+        assert_eq!(
+            from_config.for_package("numpy")["setup-args"].values(),
+            ["-Dblas=openblas", "-Dlapack=openblas"]
+        );
+        let settings = from_config
+            .with_options(&[
+                "--build-option=--a".to_string(),
+                "--build-option=--b".to_string(),
+                "setup-args=-Dblas=mkl".to_string(),
+            ])
+            .unwrap();
+
+        // The command line wins over monotrail.toml
+        let numpy = settings.for_package("numpy");
+        assert_eq!(
+            numpy["setup-args"],
+            ConfigSetting::Single("-Dblas=mkl".to_string())
+        );
+        assert_eq!(
+            numpy["editable_mode"],
+            ConfigSetting::Single("compat".to_string())
+        );
+        assert_eq!(numpy["--build-option"].values(), ["--a", "--b"]);
+        assert_eq!(
+            settings.for_package("tqdm")["setup-args"],
+            ConfigSetting::Single("-Dblas=mkl".to_string())
+        );
+        assert!(PackageConfigSettings::default()
+            .with_options(&["novalue".to_string()])
+            .is_err());
+    }
+}
@@ -0,0 +1,111 @@
+//! Collects the warnings that `--strict-warnings` can turn into failures, so we can show a summary
+//! at the end of the run
+
+use anyhow::bail;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use tracing::{info, warn};
+
+/// The classes of warnings `--strict-warnings` can turn into errors
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum WarningClass {
+    /// An installed release was yanked from the index
+    Yanked,
+    /// A requirement has no `--hash` while other requirements have one
+    HashMissing,
+    /// A marker expression can't be evaluated properly (e.g. an invalid version) and is ignored
+    MarkerIgnored,
+}
+
+impl WarningClass {
+    /// The name as in `--strict-warnings <name>`
+    fn name(&self) -> &'static str {
+        match self {
+            WarningClass::Yanked => "yanked",
+            WarningClass::HashMissing => "hash-missing",
+            WarningClass::MarkerIgnored => "marker-ignored",
+        }
+    }
+}
+
+/// The reported warnings of this run. Resolution runs in parallel, so this needs to be global
+static WARNINGS: Mutex<Vec<(WarningClass, String)>> = Mutex::new(Vec::new());
+
+/// Logs the warning and records it for [finish]
+pub(crate) fn report(class: WarningClass, message: String) {
+    warn!("{}", message);
+    WARNINGS.lock().unwrap().push((class, message));
+}
+
+/// Shows a summary of the warnings reported so far and fails if any of them is in `strict`
+pub(crate) fn finish(strict: &[WarningClass]) -> anyhow::Result<()> {
+    let warnings = std::mem::take(&mut *WARNINGS.lock().unwrap());
+    if warnings.is_empty() {
+        return Ok(());
+    }
+    info!("{}", summary_table(&warnings, strict));
+    check_strict(&warnings, strict)
+}
+
+/// One row per warning class with the number of warnings and whether it's an error
+fn summary_table(warnings: &[(WarningClass, String)], strict: &[WarningClass]) -> String {
+    let mut counts: BTreeMap<WarningClass, usize> = BTreeMap::new();
+    for (class, _) in warnings {
+        *counts.entry(*class).or_default() += 1;
+    }
+    let mut table = format!("{:<16} {:>5}  {}\n", "Warning", "Count", "Strict");
+    for (class, count) in counts {
+        let is_strict = if strict.contains(&class) { "yes" } else { "no" };
+        table += &format!("{:<16} {:>5}  {}\n", class.name(), count, is_strict);
+    }
+    table.trim_end().to_string()
+}
+
+fn check_strict(
+    warnings: &[(WarningClass, String)],
+    strict: &[WarningClass],
+) -> anyhow::Result<()> {
+    let failed: Vec<&str> = warnings
+        .iter()
+        .filter(|(class, _)| strict.contains(class))
+        .map(|(_, message)| message.as_str())
+        .collect();
+    if !failed.is_empty() {
+        bail!(
+            "{} warning(s) are errors due to --strict-warnings:\n  {}",
+            failed.len(),
+            failed.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::diagnostics::{check_strict, summary_table, WarningClass};
+    use indoc::indoc;
+
+    #[test]
+    fn test_summary_and_strict() {
+        let warnings = vec![
+            (WarningClass::MarkerIgnored, "a marker".to_string()),
+            (WarningClass::Yanked, "tqdm 4.0.0 was yanked".to_string()),
+            (WarningClass::MarkerIgnored, "b marker".to_string()),
+        ];
+        let strict = [WarningClass::Yanked, WarningClass::HashMissing];
+        assert_eq!(
+            summary_table(&warnings, &strict),
+            indoc! {"
+                Warning          Count  Strict
+                yanked               1  yes
+                marker-ignored       2  no"
+            }
+        );
+        let err = check_strict(&warnings, &strict).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1 warning(s) are errors due to --strict-warnings:\n  tqdm 4.0.0 was yanked"
+        );
+        check_strict(&warnings, &[WarningClass::HashMissing]).unwrap();
+    }
+}
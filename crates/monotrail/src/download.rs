@@ -0,0 +1,462 @@
+//! Downloads of wheels and source distributions into the artifact cache
+//! (`<cache>/artifacts/<name>/<version>/<filename>`).
+//!
+//! Downloads go to `<filename>.part` next to the target, which is locked while we write to it, so
+//! two processes never download the same file at once. If the connection breaks, we retry with
+//! exponential backoff and continue with a range request from where we stopped, also in a later
+//! run if the index gave us a hash. The hash is computed while writing and checked against the one
+//! from the index before the file is moved into place.
+//!
+//! At most `MONOTRAIL_CONCURRENT_DOWNLOADS` (default 8) downloads run at the same time, and
+//! `MONOTRAIL_DOWNLOAD_RETRIES` (default 3) sets how often a failed download is retried.
+
+use crate::cache::touch_artifact;
+use crate::http_cache::is_offline;
+use crate::progress::{self, Phase, ProgressReader};
+use crate::utils::{artifact_dirs, writable_artifact_dir};
+use anyhow::{bail, Context};
+use fs2::FileExt;
+use fs_err as fs;
+use fs_err::{File, OpenOptions};
+use install_wheel_rs::{hash_backend, to_hex, HashPurpose, IncrementalHash};
+use monotrail_utils::transport::{is_not_found, Transport};
+use std::env;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+use url::Url;
+
+/// The longest wait between two attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How many downloads run in parallel and how failed ones are retried
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct DownloadOptions {
+    pub(crate) concurrency: usize,
+    pub(crate) retries: u32,
+    /// The wait before the first retry, doubled for each further retry up to [MAX_BACKOFF]
+    pub(crate) backoff: Duration,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 8,
+            retries: 3,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl DownloadOptions {
+    /// The defaults, overridden by `MONOTRAIL_CONCURRENT_DOWNLOADS` and
+    /// `MONOTRAIL_DOWNLOAD_RETRIES`
+    pub(crate) fn from_env() -> Self {
+        let mut options = Self::default();
+        let prefix = env!("CARGO_PKG_NAME").to_uppercase();
+        if let Some(concurrency) = env_number::<usize>(&format!("{}_CONCURRENT_DOWNLOADS", prefix))
+        {
+            // 0 would block forever
+            options.concurrency = concurrency.max(1);
+        }
+        if let Some(retries) = env_number(&format!("{}_DOWNLOAD_RETRIES", prefix)) {
+            options.retries = retries;
+        }
+        options
+    }
+}
+
+fn env_number<T: FromStr>(name: &str) -> Option<T> {
+    let value = env::var(name).ok()?;
+    match T::from_str(value.trim()) {
+        Ok(number) => Some(number),
+        Err(_) => {
+            warn!("Invalid {}, expected a number, got `{}`", name, value);
+            None
+        }
+    }
+}
+
+/// The number of running downloads, for the concurrency limit
+static RUNNING: (Mutex<usize>, Condvar) = (Mutex::new(0), Condvar::new());
+
+/// A slot of the concurrency limit, freed on drop
+struct Permit;
+
+impl Permit {
+    fn acquire(limit: usize) -> Self {
+        let (running, condvar) = &RUNNING;
+        let mut running = condvar
+            .wait_while(running.lock().unwrap(), |running| *running >= limit)
+            .unwrap();
+        *running += 1;
+        Permit
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let (running, condvar) = &RUNNING;
+        *running.lock().unwrap() -= 1;
+        condvar.notify_one();
+    }
+}
+
+/// Hashes everything that is successfully written, so after an error the hash still matches the
+/// bytes in the file
+struct HashingWriter<W: Write> {
+    writer: W,
+    hasher: Box<dyn IncrementalHash>,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Returns the cached distribution or downloads it, checking the sha256 if we know it
+pub(crate) fn download_distribution_cached(
+    transport: &dyn Transport,
+    name: &str,
+    version: &str,
+    filename: &str,
+    url: &str,
+    sha256: Option<&str>,
+) -> anyhow::Result<PathBuf> {
+    // Distributions in a `--find-links` directory are used in place
+    if let Some(path) = Url::parse(url)
+        .ok()
+        .filter(|url| url.scheme() == "file")
+        .and_then(|url| url.to_file_path().ok())
+    {
+        return Ok(path);
+    }
+    // Entries of other users in a shared cache are fine as long as we can read them
+    for dir in artifact_dirs(name, version)? {
+        let cached = dir.join(filename);
+        if File::open(&cached).is_ok() {
+            debug!("Found {} {} cached at {}", name, version, cached.display());
+            touch_artifact(&cached);
+            return Ok(cached);
+        }
+    }
+
+    if is_offline() {
+        bail!(
+            "{} {} ({}) is not cached and we're offline",
+            name,
+            version,
+            filename
+        );
+    }
+    let target_dir = writable_artifact_dir(name, version)?;
+    let target_file = target_dir.join(filename);
+    debug!("Downloading {} {}", name, version);
+    download_distribution(
+        transport,
+        &DownloadOptions::from_env(),
+        name,
+        url,
+        &target_file,
+        sha256,
+    )?;
+    Ok(target_file)
+}
+
+/// Downloads `url` to `target_file` through `<target_file>.part`, resuming a previous partial
+/// download if there is one
+pub(crate) fn download_distribution(
+    transport: &dyn Transport,
+    options: &DownloadOptions,
+    name: &str,
+    url: &str,
+    target_file: &Path,
+    sha256: Option<&str>,
+) -> anyhow::Result<()> {
+    let target_dir = target_file
+        .parent()
+        .with_context(|| format!("Invalid download target {}", target_file.display()))?;
+    let filename = target_file
+        .file_name()
+        .with_context(|| format!("Invalid download target {}", target_file.display()))?
+        .to_string_lossy()
+        .to_string();
+    fs::create_dir_all(target_dir).context("Couldn't create cache dir")?;
+
+    let part_file = target_dir.join(format!("{}.part", filename));
+    let mut part = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&part_file)
+        .context("Couldn't create file for download")?;
+    if part.file().try_lock_exclusive().is_err() {
+        debug!("Waiting for another process downloading {}", filename);
+        part.file().lock_exclusive()?;
+    }
+    // The other process was faster
+    if target_file.is_file() {
+        let _ = fs::remove_file(&part_file);
+        return Ok(());
+    }
+    // Without a hash we couldn't tell if the file on the server changed since the previous run
+    if sha256.is_none() && part.metadata()?.len() > 0 {
+        debug!("Discarding the partial download of {}", filename);
+        part.set_len(0)?;
+    }
+
+    let _permit = Permit::acquire(options.concurrency);
+    progress::package_started(Phase::Download, name);
+    let start = Instant::now();
+    let result = download_with_retries(transport, options, name, url, &mut part, &filename);
+    progress::package_finished(
+        Phase::Download,
+        name,
+        start.elapsed(),
+        result.as_ref().err().map(|err| err as _),
+    );
+    // An interrupted download stays in the .part file, so the next run can resume it
+    let digest = result?;
+    if let Some(expected) = sha256 {
+        let actual = to_hex(&digest);
+        if !actual.eq_ignore_ascii_case(expected) {
+            // Nothing to resume from
+            part.set_len(0)?;
+            bail!(
+                "Hash mismatch for {}: expected sha256 {}, got {}",
+                filename,
+                expected,
+                actual
+            );
+        }
+    }
+
+    // Other users of a shared cache need to read the file regardless of our umask, but they still
+    // can't modify it
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        part.file()
+            .set_permissions(std::fs::Permissions::from_mode(0o644))
+            .context("Failed to make the downloaded file readable")?;
+    }
+    // Still holding the lock, so waiting processes see the finished file
+    fs::rename(&part_file, target_file).context("Failed to move download to target position")?;
+    Ok(())
+}
+
+/// `backoff` doubled `attempt` times, capped at [MAX_BACKOFF]
+fn retry_wait(backoff: Duration, attempt: u32) -> Duration {
+    let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+    backoff.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+/// Appends to `part` until the download is complete and returns the digest of the whole file
+fn download_with_retries(
+    transport: &dyn Transport,
+    options: &DownloadOptions,
+    name: &str,
+    url: &str,
+    part: &mut File,
+    filename: &str,
+) -> anyhow::Result<Vec<u8>> {
+    // Hash what we already have from a previous run
+    let mut hasher = hash_backend(HashPurpose::Integrity).hasher();
+    part.seek(SeekFrom::Start(0))?;
+    io::copy(part, &mut hasher).context("Failed to read partial download")?;
+
+    let mut attempt = 0;
+    loop {
+        let offset = part.seek(SeekFrom::End(0))?;
+        if offset > 0 {
+            debug!("Resuming download of {} at byte {}", filename, offset);
+        }
+        let mut writer = HashingWriter {
+            writer: &mut *part,
+            hasher,
+        };
+        let result = transport
+            .get_range(url, offset)
+            .with_context(|| format!("Failed to download {}", url))
+            .and_then(|reader| {
+                let mut reader = ProgressReader::new(reader, Phase::Download, name);
+                io::copy(&mut reader, &mut writer)
+                    .with_context(|| format!("Failed to download {}", url))?;
+                reader.finish();
+                Ok(())
+            });
+        writer.flush()?;
+        hasher = writer.hasher;
+        let err = match result {
+            Ok(()) => return Ok(hasher.finalize()),
+            Err(err) => err,
+        };
+        if is_not_found(&err) || attempt >= options.retries {
+            return Err(err);
+        }
+        // e.g. the server rejects the range because the partial file is broken, so start over
+        if offset > 0 && part.seek(SeekFrom::End(0))? == offset {
+            debug!("No progress resuming {}, starting over", filename);
+            part.set_len(0)?;
+            hasher = hash_backend(HashPurpose::Integrity).hasher();
+        }
+        let wait = retry_wait(options.backoff, attempt);
+        warn!(
+            "Download of {} failed, retrying in {:.1}s: {:#}",
+            filename,
+            wait.as_secs_f32(),
+            err
+        );
+        sleep(wait);
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::download::{download_distribution, retry_wait, DownloadOptions, MAX_BACKOFF};
+    use fs_err as fs;
+    use install_wheel_rs::{integrity_digest, to_hex};
+    use monotrail_utils::transport::MockTransport;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    const URL: &str = "https://files.example.org/foo-1.0.0-py3-none-any.whl";
+
+    fn options() -> DownloadOptions {
+        DownloadOptions {
+            concurrency: 2,
+            retries: 2,
+            backoff: Duration::ZERO,
+        }
+    }
+
+    fn sha256(data: &str) -> String {
+        to_hex(&integrity_digest(data.as_bytes()))
+    }
+
+    #[test]
+    fn test_resume_after_interruption() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("foo-1.0.0-py3-none-any.whl");
+        let body = "wheel content ".repeat(100);
+        let transport = MockTransport::default()
+            .with_response(URL, body.clone())
+            .with_interruption(URL, 500);
+        download_distribution(
+            &transport,
+            &options(),
+            "foo",
+            URL,
+            &target,
+            Some(&sha256(&body)),
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), body);
+        assert_eq!(transport.requests(), [URL, &format!("{} bytes=500-", URL)]);
+        assert!(!temp_dir
+            .path()
+            .join("foo-1.0.0-py3-none-any.whl.part")
+            .exists());
+    }
+
+    #[test]
+    fn test_resume_previous_run() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("foo-1.0.0-py3-none-any.whl");
+        let body = "0123456789".repeat(10);
+        fs::write(
+            temp_dir.path().join("foo-1.0.0-py3-none-any.whl.part"),
+            &body[..30],
+        )
+        .unwrap();
+        let transport = MockTransport::default().with_response(URL, body.clone());
+        download_distribution(
+            &transport,
+            &options(),
+            "foo",
+            URL,
+            &target,
+            Some(&sha256(&body)),
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), body);
+        assert_eq!(transport.requests(), [format!("{} bytes=30-", URL)]);
+    }
+
+    #[test]
+    fn test_discard_previous_run_without_hash() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("foo-1.0.0-py3-none-any.whl");
+        fs::write(
+            temp_dir.path().join("foo-1.0.0-py3-none-any.whl.part"),
+            "stale",
+        )
+        .unwrap();
+        let transport = MockTransport::default().with_response(URL, "current");
+        download_distribution(&transport, &options(), "foo", URL, &target, None).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "current");
+        assert_eq!(transport.requests(), [URL]);
+    }
+
+    #[test]
+    fn test_retry_wait() {
+        let backoff = Duration::from_secs(1);
+        assert_eq!(retry_wait(backoff, 0), backoff);
+        assert_eq!(retry_wait(backoff, 3), Duration::from_secs(8));
+        assert_eq!(retry_wait(backoff, 10), MAX_BACKOFF);
+        // 2^40 doesn't fit into u32
+        assert_eq!(retry_wait(backoff, 40), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_hash_mismatch() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("foo-1.0.0-py3-none-any.whl");
+        let transport = MockTransport::default().with_response(URL, "tampered");
+        let err = download_distribution(
+            &transport,
+            &options(),
+            "foo",
+            URL,
+            &target,
+            Some(&sha256("original")),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Hash mismatch for foo-1.0.0-py3-none-any.whl: expected sha256 {}, got {}",
+                sha256("original"),
+                sha256("tampered")
+            )
+        );
+        assert!(!target.exists());
+        // Not retried
+        assert_eq!(transport.requests(), [URL]);
+        // Nothing left to resume from
+        let part = temp_dir.path().join("foo-1.0.0-py3-none-any.whl.part");
+        assert_eq!(fs::metadata(part).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_not_found_not_retried() {
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("foo-1.0.0-py3-none-any.whl");
+        let transport = MockTransport::default();
+        download_distribution(&transport, &options(), "foo", URL, &target, None).unwrap_err();
+        assert_eq!(transport.requests(), [URL]);
+    }
+}
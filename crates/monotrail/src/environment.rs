@@ -0,0 +1,272 @@
+//! The environment manifest `monotrail-environment.json` we write into a venv after installing,
+//! with the name, version, tag, source, install time and a hash of the installed files of each
+//! package. Checking whether a venv matches a lockfile then means reading one json file and
+//! checking that the `.dist-info` directories still exist, instead of parsing the metadata of
+//! every package in site-packages.
+
+use crate::install::InstalledPackage;
+use crate::path_dependency::is_path_source;
+use crate::spec::{same_version, RequestedSpec};
+use anyhow::Context;
+use data_encoding::BASE64URL_NOPAD;
+use fs_err as fs;
+use fs_err::File;
+use install_wheel_rs::{installed_dist_infos, integrity_hash, normalize_name};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The file in the venv root
+pub const ENVIRONMENT_MANIFEST: &str = "monotrail-environment.json";
+
+/// The contents of `monotrail-environment.json`
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Environment {
+    /// The python version of the venv, e.g. `3.8`
+    pub python_version: String,
+    /// Sorted by name
+    pub packages: Vec<EnvironmentPackage>,
+}
+
+/// A package in the environment manifest
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct EnvironmentPackage {
+    /// The normalized name
+    pub name: String,
+    /// The version as python sees it
+    pub version: String,
+    /// The version or git revision
+    pub unique_version: String,
+    /// The compatibility tag of the wheel
+    pub tag: String,
+    /// `index`, the url or path of the distribution or `git+<url>@<revision>`
+    pub source: String,
+    /// The directory name in site-packages
+    pub dist_info: String,
+    /// The hash of the RECORD file, which has the hashes of all installed files
+    pub record_hash: Option<String>,
+    /// Seconds since the epoch
+    pub installed_at: u64,
+}
+
+/// How an environment differs from the requested packages
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EnvironmentDiff {
+    /// Requested but not installed
+    pub missing: Vec<String>,
+    /// Installed in a different version, as (name, installed, requested)
+    pub changed: Vec<(String, String, String)>,
+    /// Installed but not requested
+    pub extra: Vec<String>,
+}
+
+impl EnvironmentDiff {
+    /// Whether all requested packages are installed in the requested version. Extra packages
+    /// don't matter
+    pub fn is_up_to_date(&self) -> bool {
+        self.missing.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// `index`, the url or path of the distribution or `git+<url>@<revision>`
+fn spec_source(spec: &RequestedSpec) -> String {
+    if let Some(source) = spec
+        .source
+        .as_ref()
+        .filter(|source| is_path_source(&source.source_type))
+    {
+        source.url.clone()
+    } else if let Some(source) = &spec.source {
+        format!(
+            "{}+{}@{}",
+            source.source_type, source.url, source.resolved_reference
+        )
+    } else if let Some((url, ..)) = &spec.url {
+        url.clone()
+    } else if let Some((path, _)) = &spec.file_path {
+        path.display().to_string()
+    } else {
+        "index".to_string()
+    }
+}
+
+fn record_hash(record: &Path) -> anyhow::Result<Option<String>> {
+    let mut file = match File::open(record) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let (hash_name, digest) = integrity_hash(&mut file)?;
+    Ok(Some(format!(
+        "{}={}",
+        hash_name,
+        BASE64URL_NOPAD.encode(&digest)
+    )))
+}
+
+impl Environment {
+    /// Reads `monotrail-environment.json` from the venv, `None` if there is none
+    pub fn load(venv: &Path) -> anyhow::Result<Option<Self>> {
+        let path = venv.join(ENVIRONMENT_MANIFEST);
+        let data = match fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let environment =
+            serde_json::from_str(&data).with_context(|| format!("Invalid {}", path.display()))?;
+        Ok(Some(environment))
+    }
+
+    /// Adds the freshly installed packages to the manifest of the venv and drops the packages
+    /// whose `.dist-info` is gone
+    pub(crate) fn update(
+        venv: &Path,
+        site_packages: &Path,
+        python_version: (u8, u8),
+        specs: &[RequestedSpec],
+        installed: &[InstalledPackage],
+        now: SystemTime,
+    ) -> anyhow::Result<()> {
+        let mut environment = Self::load(venv).ok().flatten().unwrap_or_default();
+        environment.python_version = format!("{}.{}", python_version.0, python_version.1);
+        let dist_infos = installed_dist_infos(site_packages)?;
+        environment.packages.retain(|package| {
+            dist_infos
+                .iter()
+                .any(|(_, _, dist_info)| *dist_info == package.dist_info)
+        });
+        let installed_at = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        for package in installed {
+            let name = normalize_name(&package.name);
+            let Some((_, _, dist_info)) = dist_infos.iter().find(|(other, version, _)| {
+                *other == name && same_version(version, &package.python_version)
+            }) else {
+                continue;
+            };
+            let source = specs
+                .iter()
+                .find(|spec| spec.normalized_name() == name)
+                .map(spec_source)
+                .unwrap_or_else(|| "index".to_string());
+            environment.packages.retain(|other| other.name != name);
+            environment.packages.push(EnvironmentPackage {
+                name,
+                version: package.python_version.clone(),
+                unique_version: package.unique_version.clone(),
+                tag: package.tag.clone(),
+                source,
+                dist_info: dist_info.clone(),
+                record_hash: record_hash(&site_packages.join(dist_info).join("RECORD"))?,
+                installed_at,
+            });
+        }
+        environment
+            .packages
+            .sort_by(|left, right| left.name.cmp(&right.name));
+        let path = venv.join(ENVIRONMENT_MANIFEST);
+        fs::write(&path, serde_json::to_string_pretty(&environment)?)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Compares the manifest with the requested packages. A package whose `.dist-info` was
+    /// removed, e.g. with `pip uninstall`, counts as missing
+    pub(crate) fn diff(&self, site_packages: &Path, specs: &[RequestedSpec]) -> EnvironmentDiff {
+        let mut diff = EnvironmentDiff::default();
+        for spec in specs {
+            let name = spec.normalized_name();
+            let Some(package) = self.packages.iter().find(|package| package.name == name) else {
+                diff.missing.push(name);
+                continue;
+            };
+            if !site_packages.join(&package.dist_info).is_dir() {
+                diff.missing.push(name);
+                continue;
+            }
+            if let Some(requested) = spec.get_unique_version() {
+                if !same_version(&package.unique_version, &requested) {
+                    diff.changed
+                        .push((name, package.unique_version.clone(), requested));
+                }
+            }
+        }
+        for package in &self.packages {
+            if !specs
+                .iter()
+                .any(|spec| spec.normalized_name() == package.name)
+            {
+                diff.extra.push(package.name.clone());
+            }
+        }
+        diff
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::environment::{Environment, ENVIRONMENT_MANIFEST};
+    use crate::spec::RequestedSpec;
+    use crate::test_utils::install_fake;
+    use fs_err as fs;
+    use std::time::{Duration, UNIX_EPOCH};
+    use tempfile::TempDir;
+
+    fn spec(requested: &str) -> RequestedSpec {
+        RequestedSpec::from_requested(requested, &[]).unwrap()
+    }
+
+    #[test]
+    fn test_environment() {
+        let temp_dir = TempDir::new().unwrap();
+        let venv = temp_dir.path();
+        let site_packages = venv.join("lib/python3.8/site-packages");
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(Environment::load(venv).unwrap(), None);
+
+        let specs = [spec("tqdm==4.64.0"), spec("colorama==0.4.6")];
+        let installed = [
+            install_fake(&site_packages, "tqdm", "4.64.0"),
+            install_fake(&site_packages, "colorama", "0.4.6"),
+        ];
+        Environment::update(venv, &site_packages, (3, 8), &specs, &installed, now).unwrap();
+        let environment = Environment::load(venv).unwrap().unwrap();
+        assert_eq!(environment.python_version, "3.8");
+        let tqdm = &environment.packages[1];
+        assert_eq!(
+            (tqdm.name.as_str(), tqdm.source.as_str(), tqdm.installed_at),
+            ("tqdm", "index", 1_700_000_000)
+        );
+        assert!(tqdm.record_hash.as_ref().unwrap().starts_with("sha256="));
+        assert!(environment.diff(&site_packages, &specs).is_up_to_date());
+
+        // An upgrade, a new package and an uninstalled one
+        let specs = [spec("tqdm==4.65.0"), spec("attrs==23.1.0")];
+        let diff = environment.diff(&site_packages, &specs);
+        assert_eq!(diff.missing, ["attrs"]);
+        assert_eq!(
+            diff.changed,
+            [(
+                "tqdm".to_string(),
+                "4.64.0".to_string(),
+                "4.65.0".to_string()
+            )]
+        );
+        assert_eq!(diff.extra, ["colorama"]);
+
+        fs::remove_dir_all(site_packages.join("tqdm-4.64.0.dist-info")).unwrap();
+        let installed = [install_fake(&site_packages, "tqdm", "4.65.0")];
+        Environment::update(venv, &site_packages, (3, 8), &specs, &installed, now).unwrap();
+        let environment = Environment::load(venv).unwrap().unwrap();
+        let versions: Vec<_> = environment
+            .packages
+            .iter()
+            .map(|package| format!("{} {}", package.name, package.version))
+            .collect();
+        assert_eq!(versions, ["colorama 0.4.6", "tqdm 4.65.0"]);
+        assert!(venv.join(ENVIRONMENT_MANIFEST).is_file());
+    }
+}
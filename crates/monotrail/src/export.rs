@@ -0,0 +1,140 @@
+//! Export the locked environment as a constraints.txt and a find-links directory, so users stuck
+//! on pip can reproduce the exact environment with
+//! `pip install --no-index --find-links find-links -r constraints.txt`
+
+use crate::download::download_distribution_cached;
+use crate::package_index::PYPI_HOST;
+use crate::spec::{FileOrUrl, RequestedSpec};
+use anyhow::{bail, Context};
+use fs_err as fs;
+use install_wheel_rs::CompatibleTags;
+use monotrail_utils::transport::Transport;
+use std::path::Path;
+use tracing::{debug, info};
+
+/// The formats of `monotrail export`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ExportFormat {
+    /// A constraints.txt and a find-links directory for pip
+    #[default]
+    Constraints,
+    /// A PEP 751 pylock.toml with the urls and hashes of all files of each package
+    Pylock,
+    /// A CycloneDX 1.5 json SBOM
+    Cyclonedx,
+    /// An SPDX 2.3 json SBOM
+    Spdx,
+}
+
+/// Writes `constraints.txt` with the exact versions and `find-links/` with all distributions
+/// into `output`. The distributions are picked for the given tags, so the bundle is only valid
+/// for that platform and python version.
+pub fn export_constraints_bundle(
+    specs: &[RequestedSpec],
+    compatible_tags: &CompatibleTags,
+    transport: &dyn Transport,
+    output: &Path,
+) -> anyhow::Result<()> {
+    let find_links = output.join("find-links");
+    fs::create_dir_all(&find_links)?;
+
+    let mut specs = specs.to_vec();
+    specs.sort_by_key(|spec| spec.normalized_name());
+    let mut constraints = String::new();
+    for spec in specs {
+        let resolved = spec.resolve(PYPI_HOST, transport, compatible_tags)?;
+        let (distribution, filename) = match &resolved.location {
+            FileOrUrl::File(path) => {
+                let filename = path
+                    .file_name()
+                    .with_context(|| format!("Expected a file: {}", path.display()))?
+                    .to_string_lossy()
+                    .to_string();
+                (path.clone(), filename)
+            }
+            FileOrUrl::Url { url, filename } => {
+                let distribution = download_distribution_cached(
+                    transport,
+                    &resolved.name,
+                    &resolved.unique_version,
+                    filename,
+                    url,
+                    resolved.sha256.as_deref(),
+                )
+                .with_context(|| format!("Failed to download {}", resolved.requested))?;
+                (distribution, filename.clone())
+            }
+            FileOrUrl::Git { url, revision } => {
+                bail!(
+                    "Can't export {} from git ({} {}), pip would need to build it itself",
+                    resolved.name,
+                    url,
+                    revision
+                )
+            }
+        };
+        debug!("Adding {} to the bundle", filename);
+        fs::copy(&distribution, find_links.join(&filename))
+            .with_context(|| format!("Failed to copy {}", distribution.display()))?;
+        constraints.push_str(&format!("{}=={}\n", resolved.name, resolved.python_version));
+    }
+    fs::write(output.join("constraints.txt"), constraints)?;
+    info!(
+        "Exported constraints bundle to {}, install it with `pip install --no-index --find-links {} -r {}`",
+        output.display(),
+        find_links.display(),
+        output.join("constraints.txt").display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::export_constraints_bundle;
+    use crate::spec::RequestedSpec;
+    use fs_err as fs;
+    use install_wheel_rs::{Arch, CompatibleTags, Os};
+    use monotrail_utils::transport::MockTransport;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_export_wheel_files() {
+        let specs = [
+            RequestedSpec::from_requested(
+                "../../test-data/wheels/miniblack-23.1.0-py3-none-any.whl",
+                &[],
+            )
+            .unwrap(),
+            RequestedSpec::from_requested(
+                "../../test-data/wheels/colander-0.9.9-py2.py3-none-any.whl",
+                &[],
+            )
+            .unwrap(),
+        ];
+        let compatible_tags = CompatibleTags::new(
+            (3, 8),
+            Os::Manylinux {
+                major: 2,
+                minor: 27,
+            },
+            Arch::X86_64,
+        )
+        .unwrap();
+        let output = TempDir::new().unwrap();
+        export_constraints_bundle(
+            &specs,
+            &compatible_tags,
+            &MockTransport::default(),
+            output.path(),
+        )
+        .unwrap();
+
+        let constraints = fs::read_to_string(output.path().join("constraints.txt")).unwrap();
+        assert_eq!(constraints, "colander==0.9.9\nminiblack==23.1.0\n");
+        assert!(output
+            .path()
+            .join("find-links")
+            .join("miniblack-23.1.0-py3-none-any.whl")
+            .is_file());
+    }
+}
@@ -0,0 +1,27 @@
+"""
+Passes everything we need to know about an interpreter back to rust as json. Needs
+`get_pep508_env` from get_pep508_env.py, which rust prepends
+"""
+import json
+import struct
+import sys
+import sysconfig
+
+
+def get_interpreter_info() -> str:
+    info = {
+        "sys_executable": sys.executable,
+        "version": list(sys.version_info[:3]),
+        "implementation": sys.implementation.name,
+        "abiflags": getattr(sys, "abiflags", ""),
+        "soabi": sysconfig.get_config_var("SOABI"),
+        "platform": sysconfig.get_platform(),
+        "pointer_size": struct.calcsize("P") * 8,
+        "base_prefix": sys.base_prefix,
+        "paths": {
+            key: sysconfig.get_path(key)
+            for key in ["purelib", "platlib", "scripts", "data", "include"]
+        },
+        "markers": json.loads(get_pep508_env()),  # noqa: F821
+    }
+    return json.dumps(info)
@@ -0,0 +1,318 @@
+//! A persistent cache for index pages, metadata files and pypi json responses in
+//! `<cache>/http`, so repeated resolves only hit the network for projects that changed.
+//!
+//! Like cachecontrol, we follow `Cache-Control` (`max-age`, `no-cache` and `no-store`) and
+//! revalidate stale entries with `If-None-Match` and `If-Modified-Since`. With `--offline` (or
+//! `MONOTRAIL_OFFLINE=1`), responses are served exclusively from the cache, including stale ones.
+//!
+//! The cache wraps the transport where it's created, so the index code works with any
+//! [Transport] and tests can use a mock without a cache.
+
+use crate::utils::cache_dir;
+use anyhow::{bail, Context};
+use fs_err as fs;
+use fs_err::File;
+use install_wheel_rs::cache_key;
+use monotrail_utils::transport::{Response, Transport};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+fn offline_var() -> String {
+    format!("{}_OFFLINE", env!("CARGO_PKG_NAME").to_uppercase())
+}
+
+/// Never use the network for index requests and downloads, everything must be cached. This is
+/// an environment variable so that monotrail subprocesses are offline too
+pub(crate) fn set_offline() {
+    env::set_var(offline_var(), "1");
+}
+
+/// `--offline` or `MONOTRAIL_OFFLINE`
+pub(crate) fn is_offline() -> bool {
+    env::var_os(offline_var()).is_some_and(|value| !value.is_empty() && value != "0")
+}
+
+/// What we know about a cached response, stored next to the body as `<key>.json`
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+struct CachePolicy {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// Unix timestamp of when we got or revalidated the response
+    stored: u64,
+    max_age: Option<u64>,
+    /// `no-cache`: Always revalidate
+    no_cache: bool,
+}
+
+impl CachePolicy {
+    /// `None` if the response must not be stored
+    fn from_headers(url: &str, headers: &HashMap<String, String>, now: u64) -> Option<Self> {
+        let mut policy = Self {
+            url: url.to_string(),
+            etag: headers.get("etag").cloned(),
+            last_modified: headers.get("last-modified").cloned(),
+            stored: now,
+            max_age: None,
+            no_cache: false,
+        };
+        for directive in headers
+            .get("cache-control")
+            .map(String::as_str)
+            .unwrap_or_default()
+            .split(',')
+        {
+            let directive = directive.trim().to_lowercase();
+            if directive == "no-store" {
+                return None;
+            } else if directive == "no-cache" {
+                policy.no_cache = true;
+            } else if let Some(max_age) = directive.strip_prefix("max-age=") {
+                policy.max_age = max_age.trim_matches('"').parse().ok();
+            }
+        }
+        Some(policy)
+    }
+
+    fn is_fresh(&self, now: u64) -> bool {
+        match self.max_age {
+            Some(max_age) if !self.no_cache => now < self.stored.saturating_add(max_age),
+            _ => false,
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Writes through a temp file, so concurrent readers never see a partial file
+fn write_atomic(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let dir = path.parent().context("Cache file without parent")?;
+    let mut temp_file = tempfile::NamedTempFile::new_in(dir)?;
+    temp_file.write_all(data)?;
+    temp_file.persist(path)?;
+    Ok(())
+}
+
+/// Wraps a [Transport] and caches the responses to [Transport::get]. Range requests (downloads)
+/// and uploads are passed through
+pub(crate) struct HttpCache<'a> {
+    inner: &'a dyn Transport,
+    dir: PathBuf,
+    offline: bool,
+}
+
+impl<'a> HttpCache<'a> {
+    /// The cache in `<cache>/http`, offline if requested
+    pub(crate) fn new(inner: &'a dyn Transport) -> anyhow::Result<Self> {
+        Ok(Self::with_dir(
+            inner,
+            cache_dir()?.join("http"),
+            is_offline(),
+        ))
+    }
+
+    pub(crate) fn with_dir(inner: &'a dyn Transport, dir: PathBuf, offline: bool) -> Self {
+        Self {
+            inner,
+            dir,
+            offline,
+        }
+    }
+
+    fn paths(&self, url: &str) -> (PathBuf, PathBuf) {
+        let key = cache_key(url);
+        (
+            self.dir.join(format!("{}.json", key)),
+            self.dir.join(format!("{}.body", key)),
+        )
+    }
+
+    /// The cached policy and body file, if any
+    fn read_cached(&self, url: &str) -> Option<(CachePolicy, PathBuf)> {
+        let (policy_path, body_path) = self.paths(url);
+        let policy: CachePolicy = serde_json::from_slice(&fs::read(policy_path).ok()?).ok()?;
+        // Different urls with the same key are practically impossible, but be safe
+        (policy.url == url && body_path.is_file()).then_some((policy, body_path))
+    }
+
+    fn store(&self, policy: &CachePolicy, body: Option<&[u8]>) -> anyhow::Result<()> {
+        let (policy_path, body_path) = self.paths(&policy.url);
+        fs::create_dir_all(&self.dir)?;
+        // Body first, so the policy never points to a missing body
+        if let Some(body) = body {
+            write_atomic(&body_path, body)?;
+        }
+        write_atomic(&policy_path, &serde_json::to_vec(policy)?)
+    }
+
+    fn open(body_path: &Path) -> anyhow::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(File::open(body_path)?))
+    }
+
+    fn get_cached(&self, url: &str) -> anyhow::Result<Box<dyn Read + Send>> {
+        let cached = self.read_cached(url);
+        if self.offline {
+            return match cached {
+                Some((_policy, body_path)) => Self::open(&body_path),
+                None => bail!(
+                    "{} is not cached and we're offline (--offline or {}_OFFLINE)",
+                    url,
+                    env!("CARGO_PKG_NAME").to_uppercase()
+                ),
+            };
+        }
+
+        let now = now();
+        let mut headers = Vec::new();
+        if let Some((policy, body_path)) = &cached {
+            if policy.is_fresh(now) {
+                debug!("Fresh in http cache: {}", url);
+                return Self::open(body_path);
+            }
+            if let Some(etag) = &policy.etag {
+                headers.push(("If-None-Match", etag.as_str()));
+            }
+            if let Some(last_modified) = &policy.last_modified {
+                headers.push(("If-Modified-Since", last_modified.as_str()));
+            }
+        }
+
+        let Response {
+            status,
+            headers: response_headers,
+            mut body,
+        } = self.inner.get_response(url, &headers)?;
+        if status == 304 {
+            let Some((mut policy, body_path)) = cached else {
+                bail!("Got 304 Not Modified from {} without asking for it", url);
+            };
+            debug!("Unchanged, revalidated http cache: {}", url);
+            // The 304 may update the caching headers
+            if let Some(new) = CachePolicy::from_headers(url, &response_headers, now) {
+                policy.stored = now;
+                policy.max_age = new.max_age.or(policy.max_age);
+                policy.no_cache = new.no_cache;
+                policy.etag = new.etag.or(policy.etag);
+                self.store(&policy, None)?;
+            }
+            return Self::open(&body_path);
+        }
+
+        let Some(policy) = CachePolicy::from_headers(url, &response_headers, now) else {
+            return Ok(body);
+        };
+        let mut content = Vec::new();
+        body.read_to_end(&mut content)
+            .with_context(|| format!("Failed to read {}", url))?;
+        // A broken cache shouldn't break the resolution
+        if let Err(err) = self.store(&policy, Some(&content)) {
+            debug!("Failed to store {} in http cache: {:#}", url, err);
+        }
+        Ok(Box::new(Cursor::new(content)))
+    }
+}
+
+impl Transport for HttpCache<'_> {
+    fn get(&self, url: &str) -> anyhow::Result<Box<dyn Read + Send>> {
+        self.get_cached(url)
+    }
+
+    fn get_range(&self, url: &str, offset: u64) -> anyhow::Result<Box<dyn Read + Send>> {
+        if self.offline {
+            bail!("Can't download {}, we're offline", url);
+        }
+        self.inner.get_range(url, offset)
+    }
+
+    fn get_response(&self, url: &str, headers: &[(&str, &str)]) -> anyhow::Result<Response> {
+        if self.offline {
+            bail!("Can't request {}, we're offline", url);
+        }
+        self.inner.get_response(url, headers)
+    }
+
+    fn put(&self, url: &str, body: &[u8]) -> anyhow::Result<()> {
+        self.inner.put(url, body)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::http_cache::{CachePolicy, HttpCache};
+    use monotrail_utils::transport::{MockTransport, Transport};
+    use std::collections::HashMap;
+    use std::io::Read;
+    use tempfile::TempDir;
+
+    const URL: &str = "https://pypi.org/simple/tqdm/";
+
+    fn get(cache: &HttpCache, url: &str) -> String {
+        let mut body = String::new();
+        cache.get(url).unwrap().read_to_string(&mut body).unwrap();
+        body
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let headers = |value: &str| HashMap::from([("cache-control".to_string(), value.into())]);
+        let policy = CachePolicy::from_headers(URL, &headers("max-age=600, public"), 1000);
+        assert!(policy.as_ref().unwrap().is_fresh(1599));
+        assert!(!policy.unwrap().is_fresh(1600));
+        let policy = CachePolicy::from_headers(URL, &headers("max-age=600, no-cache"), 1000);
+        assert!(!policy.unwrap().is_fresh(1000));
+        assert_eq!(
+            CachePolicy::from_headers(URL, &headers("no-store"), 1000),
+            None
+        );
+    }
+
+    #[test]
+    fn test_etag_revalidation() {
+        let temp_dir = TempDir::new().unwrap();
+        let transport = MockTransport::default()
+            .with_response(URL, "<html>tqdm</html>")
+            .with_headers(URL, &[("ETag", "\"abc\""), ("Cache-Control", "max-age=0")]);
+        let cache = HttpCache::with_dir(&transport, temp_dir.path().to_path_buf(), false);
+        assert_eq!(get(&cache, URL), "<html>tqdm</html>");
+        // The mock answers with an empty 304, so the body must come from the cache
+        assert_eq!(get(&cache, URL), "<html>tqdm</html>");
+        assert_eq!(transport.requests(), [URL, URL]);
+
+        let offline = HttpCache::with_dir(&transport, temp_dir.path().to_path_buf(), true);
+        assert_eq!(get(&offline, URL), "<html>tqdm</html>");
+        assert_eq!(transport.requests().len(), 2);
+        let err = offline.get("https://pypi.org/simple/numpy/").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "https://pypi.org/simple/numpy/ is not cached and we're offline \
+            (--offline or MONOTRAIL_OFFLINE)"
+        );
+    }
+
+    #[test]
+    fn test_fresh_and_no_store() {
+        let temp_dir = TempDir::new().unwrap();
+        let metadata = "https://files.example.org/tqdm-4.65.0-py3-none-any.whl.metadata";
+        let transport = MockTransport::default()
+            .with_response(URL, "<html>tqdm</html>")
+            .with_headers(URL, &[("Cache-Control", "max-age=600")])
+            .with_response(metadata, "Name: tqdm")
+            .with_headers(metadata, &[("Cache-Control", "no-store")]);
+        let cache = HttpCache::with_dir(&transport, temp_dir.path().to_path_buf(), false);
+        for _ in 0..2 {
+            assert_eq!(get(&cache, URL), "<html>tqdm</html>");
+            assert_eq!(get(&cache, metadata), "Name: tqdm");
+        }
+        assert_eq!(transport.requests(), [URL, metadata, metadata]);
+    }
+}
@@ -0,0 +1,252 @@
+//! Imports the top level modules of installed packages in a subprocess, which catches wheels that
+//! install fine but can't be loaded, e.g. an extension module built for another python or a
+//! missing shared library.
+//!
+//! The modules come from `top_level.txt` if the package has one and otherwise from the RECORD: all
+//! single file modules and all packages whose parent isn't a package, so for
+//! `zope/interface/__init__.py` without `zope/__init__.py` we import `zope.interface`.
+
+use anyhow::Context;
+use fs_err as fs;
+use fs_err::File;
+use install_wheel_rs::read_record_file;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Imports the modules given as arguments
+static CHECK_IMPORTS: &str = include_str!("check_imports.py");
+
+/// A module that couldn't be imported
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportFailure {
+    /// The `.dist-info` directory name of the package
+    pub dist_info: String,
+    /// The dotted module name
+    pub module: String,
+    /// The exception, or the exit status if python crashed
+    pub error: String,
+}
+
+impl Display for ImportFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let package = self
+            .dist_info
+            .strip_suffix(".dist-info")
+            .unwrap_or(&self.dist_info);
+        write!(f, "{}: import {}: {}", package, self.module, self.error)
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_alphabetic() || first == '_')
+        && chars.all(|char| char.is_alphanumeric() || char == '_')
+}
+
+/// `foo.py`, `foo.cpython-38-x86_64-linux-gnu.so` or `foo.pyd` to `foo`
+fn file_module(filename: &str) -> Option<&str> {
+    let parts: Vec<&str> = filename.split('.').collect();
+    match *parts.as_slice() {
+        [stem, "py" | "so" | "pyd"] | [stem, _, "so" | "pyd"] => Some(stem),
+        _ => None,
+    }
+}
+
+/// The modules to import for a package, from `top_level.txt` or inferred from the RECORD
+pub(crate) fn top_level_modules(
+    site_packages: &Path,
+    dist_info: &str,
+) -> anyhow::Result<Vec<String>> {
+    let dist_info_dir = site_packages.join(dist_info);
+    match fs::read_to_string(dist_info_dir.join("top_level.txt")) {
+        Ok(top_level) => {
+            let modules: BTreeSet<String> = top_level
+                .lines()
+                .map(|line| line.trim().replace('/', "."))
+                .filter(|module| module.split('.').all(is_identifier))
+                .collect();
+            return Ok(modules.into_iter().collect());
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    let record = read_record_file(&mut File::open(dist_info_dir.join("RECORD"))?)
+        .with_context(|| format!("Invalid RECORD in {}", dist_info))?;
+    let packages: BTreeSet<Vec<&str>> = record
+        .iter()
+        .filter_map(|entry| {
+            let parts: Vec<&str> = entry.path.split('/').collect();
+            match parts.as_slice() {
+                [package @ .., "__init__.py"] if !package.is_empty() => Some(package.to_vec()),
+                _ => None,
+            }
+        })
+        .collect();
+    let mut modules = BTreeSet::new();
+    for package in &packages {
+        // The packages inside another package get imported with their parent
+        let nested = (1..package.len()).any(|len| packages.contains(&package[..len]));
+        if !nested && package.iter().all(|part| is_identifier(part)) {
+            modules.insert(package.join("."));
+        }
+    }
+    for entry in &record {
+        if entry.path.contains('/') {
+            continue;
+        }
+        if let Some(module) = file_module(&entry.path).filter(|module| is_identifier(module)) {
+            modules.insert(module.to_string());
+        }
+    }
+    Ok(modules.into_iter().collect())
+}
+
+/// Imports the modules of each package in its own python process, so one crashing extension
+/// module doesn't hide the others. `site_packages` is the only entry of `PYTHONPATH`, so we don't
+/// pick up the user's packages, and we don't write bytecode that isn't in the RECORD
+pub(crate) fn check_imports(
+    python: &Path,
+    site_packages: &Path,
+    dist_infos: &[String],
+) -> anyhow::Result<Vec<ImportFailure>> {
+    let failures = dist_infos
+        .par_iter()
+        .map(|dist_info| {
+            let modules = top_level_modules(site_packages, dist_info)?;
+            if modules.is_empty() {
+                return Ok(Vec::new());
+            }
+            let output = Command::new(python)
+                .arg("-c")
+                .arg(CHECK_IMPORTS)
+                .args(&modules)
+                .env("PYTHONPATH", site_packages)
+                .env("PYTHONDONTWRITEBYTECODE", "1")
+                .env("PYTHONIOENCODING", "utf-8")
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .output()
+                .with_context(|| format!("Failed to run {}", python.display()))?;
+            if output.status.success() {
+                return Ok(Vec::new());
+            }
+            let failures: Vec<ImportFailure> = String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .filter_map(|line| line.split_once('\t'))
+                .map(|(module, error)| ImportFailure {
+                    dist_info: dist_info.clone(),
+                    module: module.to_string(),
+                    error: error.to_string(),
+                })
+                .collect();
+            if !failures.is_empty() {
+                return Ok(failures);
+            }
+            // Python died before it could tell us, e.g. with a segfault in an extension module
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let last_line = stderr.trim().lines().last().unwrap_or_default();
+            Ok(vec![ImportFailure {
+                dist_info: dist_info.clone(),
+                module: modules.join(", "),
+                error: format!("python exited with {} {}", output.status, last_line)
+                    .trim_end()
+                    .to_string(),
+            }])
+        })
+        .collect::<anyhow::Result<Vec<Vec<ImportFailure>>>>()?;
+    Ok(failures.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::import_check::{check_imports, top_level_modules};
+    use crate::test_utils::install_files;
+    use fs_err as fs;
+    use tempfile::TempDir;
+    use which::which;
+
+    #[test]
+    fn test_top_level_modules() {
+        let temp_dir = TempDir::new().unwrap();
+        let site_packages = &temp_dir.path().join("lib/python3.8/site-packages");
+        install_files(
+            site_packages,
+            "zope.interface-5.4.0.dist-info",
+            &[
+                ("zope/interface/__init__.py", ""),
+                ("zope/interface/common/__init__.py", ""),
+                (
+                    "zope/interface/_zope_interface_coptimizations.cpython-38-x86_64-linux-gnu.so",
+                    "",
+                ),
+            ],
+        );
+        install_files(
+            site_packages,
+            "six-1.16.0.dist-info",
+            &[
+                ("six.py", ""),
+                ("__pycache__/six.cpython-38.pyc", ""),
+                ("../../bin/six-tool", ""),
+            ],
+        );
+        install_files(
+            site_packages,
+            "attrs-23.1.0.dist-info",
+            &[("attr/__init__.py", ""), ("attrs/__init__.py", "")],
+        );
+        fs::write(
+            site_packages.join("attrs-23.1.0.dist-info/top_level.txt"),
+            "attr\nattrs\n",
+        )
+        .unwrap();
+
+        let modules = |dist_info| top_level_modules(site_packages, dist_info).unwrap();
+        assert_eq!(
+            modules("zope.interface-5.4.0.dist-info"),
+            ["zope.interface"]
+        );
+        assert_eq!(modules("six-1.16.0.dist-info"), ["six"]);
+        assert_eq!(modules("attrs-23.1.0.dist-info"), ["attr", "attrs"]);
+    }
+
+    #[test]
+    fn test_check_imports() {
+        let python = which("python3").unwrap();
+        let temp_dir = TempDir::new().unwrap();
+        let site_packages = temp_dir.path();
+        install_files(
+            site_packages,
+            "good-1.0.0.dist-info",
+            &[("good/__init__.py", "")],
+        );
+        install_files(
+            site_packages,
+            "broken-1.0.0.dist-info",
+            &[
+                ("broken/__init__.py", "import does_not_exist_anywhere\n"),
+                ("broken_helper.py", ""),
+            ],
+        );
+        let dist_infos = [
+            "good-1.0.0.dist-info".to_string(),
+            "broken-1.0.0.dist-info".to_string(),
+        ];
+        let failures = check_imports(&python, site_packages, &dist_infos).unwrap();
+        let failures: Vec<String> = failures.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            failures,
+            ["broken-1.0.0: import broken: ModuleNotFoundError: No module named 'does_not_exist_anywhere'"]
+        );
+        // No bytecode that isn't in the RECORD
+        assert!(!site_packages.join("good/__pycache__").exists());
+    }
+}
@@ -0,0 +1,226 @@
+//! `monotrail init`: Scaffolds a new `[project]` with a src layout, either an application with a
+//! console script or a library with a `py.typed` marker
+
+use anyhow::{bail, Context};
+use fs_err as fs;
+use install_wheel_rs::normalize_name;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// What kind of project `monotrail init` creates
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum ProjectTemplate {
+    /// A package with a `main` function as console script named like the project
+    #[default]
+    App,
+    /// A typed package without scripts
+    Lib,
+}
+
+/// The project name must be a valid distribution name, since we don't want to guess what the user
+/// meant
+///
+/// <https://packaging.python.org/en/latest/specifications/name-normalization/>
+fn check_name(name: &str) -> anyhow::Result<()> {
+    let valid_chars = name
+        .chars()
+        .all(|char| char.is_ascii_alphanumeric() || matches!(char, '-' | '_' | '.'));
+    let valid_ends = name.starts_with(|char: char| char.is_ascii_alphanumeric())
+        && name.ends_with(|char: char| char.is_ascii_alphanumeric());
+    if !valid_chars || !valid_ends {
+        bail!(
+            "Invalid project name `{}`, use letters, digits, `-`, `_` and `.`, starting and \
+            ending with a letter or a digit",
+            name
+        );
+    }
+    Ok(())
+}
+
+/// The pyproject.toml for the template. hatchling finds the package in `src/` by itself
+fn pyproject_toml(name: &str, module: &str, template: ProjectTemplate, python: (u8, u8)) -> String {
+    let scripts = match template {
+        ProjectTemplate::App => format!(
+            "\n[project.scripts]\n{} = \"{}:main\"\n",
+            normalize_name(name),
+            module
+        ),
+        ProjectTemplate::Lib => String::new(),
+    };
+    format!(
+        r#"[project]
+name = "{name}"
+version = "0.1.0"
+description = ""
+readme = "README.md"
+requires-python = ">={major}.{minor}"
+dependencies = []
+{scripts}
+[build-system]
+requires = ["hatchling"]
+build-backend = "hatchling.build"
+"#,
+        name = name,
+        major = python.0,
+        minor = python.1,
+        scripts = scripts
+    )
+}
+
+/// Creates the pyproject.toml, the package in `src/` and a README.md in `dir` and, with
+/// `pin_python`, a `.python-version`. Existing files other than pyproject.toml are kept. Returns
+/// the created files
+pub fn init_project(
+    dir: &Path,
+    name: Option<&str>,
+    template: ProjectTemplate,
+    python_version: (u8, u8),
+    pin_python: bool,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let pyproject_path = dir.join("pyproject.toml");
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => dir
+            .canonicalize()
+            .ok()
+            .and_then(|dir| {
+                dir.file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+            })
+            .with_context(|| {
+                format!(
+                    "Can't take the project name from {}, please pass --name",
+                    dir.display()
+                )
+            })?,
+    };
+    check_name(&name)?;
+    let module = normalize_name(&name).replace('-', "_");
+
+    let init_py = match template {
+        ProjectTemplate::App => {
+            format!("def main() -> None:\n    print(\"Hello from {}!\")\n", name)
+        }
+        ProjectTemplate::Lib => {
+            format!("def hello() -> str:\n    return \"Hello from {}!\"\n", name)
+        }
+    };
+    let package = dir.join("src").join(&module);
+    let mut files = vec![
+        (dir.join("README.md"), format!("# {}\n", name)),
+        (package.join("__init__.py"), init_py),
+    ];
+    match template {
+        ProjectTemplate::App => files.push((
+            package.join("__main__.py"),
+            format!("from {} import main\n\nmain()\n", module),
+        )),
+        ProjectTemplate::Lib => files.push((package.join("py.typed"), String::new())),
+    }
+    if pin_python {
+        files.push((
+            dir.join(".python-version"),
+            format!("{}.{}\n", python_version.0, python_version.1),
+        ));
+    }
+
+    // Claims the directory first, so we don't race with someone else creating a project there
+    match fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&pyproject_path)
+    {
+        Ok(mut file) => {
+            file.write_all(pyproject_toml(&name, &module, template, python_version).as_bytes())?
+        }
+        Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+            bail!("{} already exists", pyproject_path.display())
+        }
+        Err(err) => return Err(err.into()),
+    }
+    fs::create_dir_all(&package)?;
+    let mut created = vec![pyproject_path];
+    for (path, contents) in files {
+        if path.exists() {
+            continue;
+        }
+        fs::write(&path, contents)?;
+        created.push(path);
+    }
+    Ok(created)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::init::{init_project, ProjectTemplate};
+    use crate::pep621::read_pep621_project;
+    use fs_err as fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_init_app() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.path().join("My.App");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("README.md"), "Keep me\n").unwrap();
+        let created = init_project(&dir, None, ProjectTemplate::App, (3, 10), true).unwrap();
+        let created: Vec<_> = created
+            .iter()
+            .map(|path| {
+                path.strip_prefix(&dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string()
+            })
+            .collect();
+        assert_eq!(
+            created,
+            [
+                "pyproject.toml",
+                "src/my_app/__init__.py",
+                "src/my_app/__main__.py",
+                ".python-version"
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("README.md")).unwrap(),
+            "Keep me\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join(".python-version")).unwrap(),
+            "3.10\n"
+        );
+        let project = read_pep621_project(&dir).unwrap().unwrap();
+        assert_eq!(project.name, "My.App");
+        assert!(project.dependencies.is_empty());
+        assert_eq!(project.scripts["my-app"], "my_app:main");
+
+        let err = init_project(&dir, None, ProjectTemplate::App, (3, 10), true).unwrap_err();
+        assert!(err.to_string().ends_with("pyproject.toml already exists"));
+    }
+
+    #[test]
+    fn test_init_lib() {
+        let temp_dir = TempDir::new().unwrap();
+        init_project(
+            temp_dir.path(),
+            Some("foo-bar"),
+            ProjectTemplate::Lib,
+            (3, 8),
+            false,
+        )
+        .unwrap();
+        let package = temp_dir.path().join("src").join("foo_bar");
+        assert!(package.join("py.typed").is_file());
+        assert!(!package.join("__main__.py").exists());
+        assert!(!temp_dir.path().join(".python-version").exists());
+        let project = read_pep621_project(temp_dir.path()).unwrap().unwrap();
+        assert!(project.scripts.is_empty());
+
+        let other = temp_dir.path().join("other");
+        fs::create_dir(&other).unwrap();
+        let err =
+            init_project(&other, Some("-foo"), ProjectTemplate::Lib, (3, 8), false).unwrap_err();
+        assert!(err.to_string().starts_with("Invalid project name `-foo`"));
+    }
+}
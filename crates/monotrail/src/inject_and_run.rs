@@ -1,8 +1,12 @@
 //! Communication with libpython
 
+use crate::config::project_python_version;
+use crate::inline_script::{find_inline_script, run_inline_script};
 use crate::monotrail::provision_python_env;
-use crate::monotrail::{find_scripts, install, load_specs, FinderData, InjectData, PythonContext};
-use crate::DEFAULT_PYTHON_VERSION;
+use crate::monotrail::{
+    find_scripts, install_layered, load_specs, specs_from_overlay, FinderData, InjectData,
+    PythonContext,
+};
 use anyhow::{bail, format_err, Context};
 use fs_err as fs;
 use install_wheel_rs::{get_script_launcher, Script, SHEBANG_PYTHON};
@@ -12,7 +16,7 @@ use monotrail_utils::parse_cpython_args::{determine_python_version, naive_python
 use std::collections::BTreeMap;
 use std::env;
 use std::env::current_exe;
-use std::ffi::CString;
+use std::ffi::{CString, OsStr};
 use std::mem::MaybeUninit;
 use std::path::{Path, PathBuf};
 use tempfile::TempDir;
@@ -61,11 +65,40 @@ pub struct PyStatus {
     pub exitcode: c_int,
 }
 
+fn isolated_var() -> String {
+    format!("{}_ISOLATED", env!("CARGO_PKG_NAME").to_uppercase())
+}
+
+/// `monotrail run --isolated`: Only the locked packages should be importable, so we remove the
+/// `PYTHON*` variables such as `PYTHONPATH` from our environment and mark it as isolated, so
+/// pythons we start later (also through execve) run like `python -I -S`
+pub(crate) fn isolate_environment() {
+    let removes: Vec<_> = env::vars_os()
+        .map(|(key, _value)| key)
+        .filter(|key| is_python_var(key))
+        .collect();
+    for key in removes {
+        debug!("Isolated: Removing {}", key.to_string_lossy());
+        env::remove_var(key);
+    }
+    env::set_var(isolated_var(), "1");
+}
+
+/// Whether python reads this variable, which we set ourselves
+fn is_python_var(key: &OsStr) -> bool {
+    key.to_string_lossy().starts_with("PYTHON") && key != "PYTHONNOUSERSITE"
+}
+
+/// Set by [isolate_environment]
+fn is_isolated() -> bool {
+    env::var_os(isolated_var()).is_some_and(|value| !value.is_empty() && value != "0")
+}
+
 //noinspection RsUnreachableCode
-/// Set utf-8 mode through pre-init
+/// Set utf-8 mode through pre-init, and with `isolated` ignore the environment
 ///
 /// <https://docs.python.org/3/c-api/init_config.html#preinitialize-python-with-pypreconfig>
-unsafe fn pre_init(lib: &Library) -> anyhow::Result<()> {
+unsafe fn pre_init(lib: &Library, isolated: bool) -> anyhow::Result<()> {
     trace!("libpython pre-init");
     let py_pre_config_init_python_config: libloading::Symbol<
         unsafe extern "C" fn(*mut PyPreConfig) -> c_void,
@@ -76,6 +109,10 @@ unsafe fn pre_init(lib: &Library) -> anyhow::Result<()> {
     let mut preconfig = preconfig.assume_init();
     // same as PYTHONUTF8=1
     preconfig.utf8_mode = 1;
+    if isolated {
+        preconfig.isolated = 1;
+        preconfig.use_environment = 0;
+    }
     trace!("preconfig: {:?}", preconfig);
 
     let py_pre_initialize: libloading::Symbol<unsafe extern "C" fn(*mut PyPreConfig) -> PyStatus> =
@@ -155,7 +192,23 @@ pub fn inject_and_run_python(
         // TODO: Do this via python c api instead
         env::set_var("PYTHONNOUSERSITE", "1");
 
-        pre_init(&lib)?;
+        let isolated = is_isolated();
+        pre_init(&lib, isolated)?;
+        if isolated {
+            // Like `python -I -S`: No PYTHON* variables, no user site-packages, no script
+            // directory in sys.path and no `site` run on startup, so neither sitecustomize nor
+            // the site-packages or .pth files of the interpreter. The finder imports `site` itself
+            // for the .pth files of the locked packages
+            for flag in [
+                &b"Py_IsolatedFlag"[..],
+                b"Py_IgnoreEnvironmentFlag",
+                b"Py_NoUserSiteDirectory",
+                b"Py_NoSiteFlag",
+            ] {
+                let flag: libloading::Symbol<*mut c_int> = lib.get(flag)?;
+                **flag = 1;
+            }
+        }
 
         trace!("Py_SetPythonHome {}", python_home.display());
         // https://docs.python.org/3/c-api/init.html#c.Py_SetPythonHome
@@ -283,9 +336,17 @@ pub fn run_python_args(
     python_version: Option<&str>,
     root: Option<&Path>,
     extras: &[String],
+    groups: &[String],
+    overlay: &[String],
 ) -> anyhow::Result<i32> {
+    // A script with inline metadata brings its own dependencies, unless we're told to use a project
+    if root.is_none() && extras.is_empty() && groups.is_empty() && overlay.is_empty() {
+        if let Some((script, metadata)) = find_inline_script(args)? {
+            return run_inline_script(args, python_version, &script, &metadata);
+        }
+    }
     let (args, python_version) =
-        determine_python_version(args, python_version, DEFAULT_PYTHON_VERSION)?;
+        determine_python_version(args, python_version, project_python_version(root)?)?;
     let (python_context, python_home) = provision_python_env(python_version)?;
 
     let script = if let Some(root) = root {
@@ -298,9 +359,11 @@ pub fn run_python_args(
     debug!("run_python_args: {:?}, `{}`", script, args.join(" "));
 
     let (specs, scripts, lockfile, project_dir) =
-        load_specs(script.as_deref(), extras, &python_context)?;
-    let finder_data = install(
-        &specs,
+        load_specs(script.as_deref(), extras, groups, &python_context)?;
+    // The project comes first so the overlay can't change the locked versions
+    let overlay_specs = specs_from_overlay(overlay, &python_context)?;
+    let finder_data = install_layered(
+        &[&specs, &overlay_specs],
         scripts,
         lockfile,
         Some(project_dir),
@@ -479,7 +542,7 @@ pub fn prepare_execve_environment(
 
 #[cfg(test)]
 mod tests {
-    use crate::inject_and_run::naive_python_arg_parser;
+    use crate::inject_and_run::{is_python_var, naive_python_arg_parser};
     use crate::run_python_args;
     use crate::utils::cache_dir;
     use anyhow::Context;
@@ -507,6 +570,15 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_is_python_var() {
+        assert!(is_python_var("PYTHONPATH".as_ref()));
+        assert!(is_python_var("PYTHONSTARTUP".as_ref()));
+        assert!(!is_python_var("PYTHONNOUSERSITE".as_ref()));
+        assert!(!is_python_var("MONOTRAIL_EXECVE_ROOT".as_ref()));
+        assert!(!is_python_var("PATH".as_ref()));
+    }
+
     #[test]
     fn no_deps_specs_file() {
         // Fake already installed python
@@ -557,7 +629,8 @@ mod tests {
             }
         }
 
-        let err = run_python_args(&[], Some("3.141"), Some(Path::new("/")), &[]).unwrap_err();
+        let err =
+            run_python_args(&[], Some("3.141"), Some(Path::new("/")), &[], &[], &[]).unwrap_err();
         let errors = err.chain().map(|e| e.to_string()).collect::<Vec<_>>();
         assert_eq!(errors, ["neither pyproject.toml nor requirements.txt not found next to / nor in any parent directory"]);
     }
@@ -0,0 +1,287 @@
+//! Scripts with inline metadata: A single `.py` file declares its dependencies in a
+//! `# /// script` comment block, so `monotrail run python script.py` (or `monotrail run script.py`)
+//! needs no pyproject.toml. The dependencies are resolved once and cached like the environments
+//! of `monotrail tool run`.
+//!
+//! ```python
+//! # /// script
+//! # requires-python = ">=3.11"
+//! # dependencies = ["requests<3", "rich"]
+//! # ///
+//! ```
+//!
+//! <https://peps.python.org/pep-0723/>
+
+use crate::config::default_python_version;
+use crate::inject_and_run::run_python_args_finder_data;
+use crate::interpreter::{allows_minor, ProjectPython};
+use crate::monotrail::{install, provision_python_env};
+use crate::ppipx::cached_environment;
+use crate::utils::data_local_dir;
+use anyhow::{bail, format_err, Context};
+use fs_err as fs;
+use install_wheel_rs::cache_key;
+use monotrail_utils::parse_cpython_args::{
+    determine_python_version, naive_python_arg_parser, parse_plus_arg,
+};
+use pep440_rs::VersionSpecifiers;
+use pep508_rs::Requirement;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::debug;
+
+/// The `script` metadata block. `[tool]` is for other tools, so we ignore it
+#[derive(Deserialize, Debug, Default, Clone, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ScriptMetadata {
+    /// PEP 508 requirements
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    /// PEP 440 specifiers for the python version
+    pub requires_python: Option<String>,
+}
+
+/// Reads the `script` block from the source of a python file. Other block types are ignored,
+/// more than one `script` block is an error
+pub fn parse_script_metadata(source: &str) -> anyhow::Result<Option<ScriptMetadata>> {
+    // This is the reference implementation's regex, which needs `\n` line endings
+    let block_re =
+        Regex::new(r"(?m)^# /// (?P<type>[a-zA-Z0-9-]+)$\s(?P<content>(^#(| .*)$\s)+)^# ///$")
+            .unwrap();
+    let source = source.replace("\r\n", "\n");
+    let mut blocks = block_re
+        .captures_iter(&source)
+        .filter(|captures| &captures["type"] == "script");
+    let Some(block) = blocks.next() else {
+        return Ok(None);
+    };
+    if blocks.next().is_some() {
+        bail!("There are multiple `# /// script` blocks, but only one is allowed");
+    }
+    let content: String = block["content"]
+        .lines()
+        .map(|line| line.strip_prefix("# ").unwrap_or(&line[1..]))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    let metadata: ScriptMetadata =
+        toml::from_str(&content).context("Invalid toml in the `# /// script` block")?;
+    for dependency in &metadata.dependencies {
+        Requirement::from_str(dependency)
+            .with_context(|| format!("Invalid dependency `{}`", dependency))?;
+    }
+    if let Some(requires_python) = &metadata.requires_python {
+        VersionSpecifiers::from_str(requires_python)
+            .map_err(|err| format_err!("Invalid requires-python: {}", err))?;
+    }
+    Ok(Some(metadata))
+}
+
+/// The script python would run with these args if it's a `.py` file with inline metadata
+pub fn find_inline_script(args: &[String]) -> anyhow::Result<Option<(PathBuf, ScriptMetadata)>> {
+    let (args, _python_version) = parse_plus_arg(args)?;
+    let Some(script) = naive_python_arg_parser(&args)
+        .map_err(|err| format_err!("Failed to parse python args: {}", err))?
+        .map(PathBuf::from)
+    else {
+        return Ok(None);
+    };
+    // `-` is stdin, and we don't read directories with a `__main__.py`
+    if !script.is_file() {
+        return Ok(None);
+    }
+    // A binary or otherwise non-utf8 file can't have a metadata block
+    let Ok(source) = fs::read_to_string(&script) else {
+        return Ok(None);
+    };
+    let metadata = parse_script_metadata(&source)
+        .with_context(|| format!("Invalid inline metadata in {}", script.display()))?;
+    Ok(metadata.map(|metadata| (script, metadata)))
+}
+
+/// The cached environment of a script. Like for `monotrail tool run`, the resolution only depends
+/// on the requirements and the python version, so scripts with the same dependencies share it
+fn script_env_dir(dependencies: &[String], python_version: (u8, u8)) -> anyhow::Result<PathBuf> {
+    // Normalizes the formatting and the order, the dependencies were validated when parsing
+    let mut dependencies: Vec<String> = dependencies
+        .iter()
+        .map(|dependency| Requirement::from_str(dependency).unwrap().to_string())
+        .collect();
+    dependencies.sort();
+    dependencies.dedup();
+    let key = cache_key(format!(
+        "{}\n{}.{}",
+        dependencies.join("\n"),
+        python_version.0,
+        python_version.1
+    ));
+    Ok(data_local_dir()?.join("scripts").join(key))
+}
+
+/// Installs the dependencies of the script into its cached environment and runs python with
+/// `args`. Without an explicit version, we pick one matching `requires-python`. A project in the
+/// current directory doesn't matter, the script only has its own metadata
+pub fn run_inline_script(
+    args: &[String],
+    python_version: Option<&str>,
+    script: &Path,
+    metadata: &ScriptMetadata,
+) -> anyhow::Result<i32> {
+    let requires_python = metadata
+        .requires_python
+        .as_deref()
+        .map(VersionSpecifiers::from_str)
+        .transpose()
+        .map_err(|err| format_err!("Invalid requires-python: {}", err))?;
+    let default = ProjectPython {
+        pinned: None,
+        requires_python: requires_python
+            .clone()
+            .map(|specifiers| (specifiers, script.to_path_buf())),
+    }
+    .python_version(default_python_version()?)?;
+    let (args, python_version) = determine_python_version(args, python_version, default)?;
+    if let Some(requires_python) = &requires_python {
+        if !allows_minor(requires_python, python_version) {
+            bail!(
+                "{} requires python {}, but python {}.{} was requested",
+                script.display(),
+                requires_python,
+                python_version.0,
+                python_version.1
+            );
+        }
+    }
+    let (python_context, python_home) = provision_python_env(python_version)?;
+
+    debug!(
+        "Running {} with the inline dependencies {:?}",
+        script.display(),
+        metadata.dependencies
+    );
+    let finder_data = if metadata.dependencies.is_empty() {
+        install(&[], BTreeMap::new(), String::new(), None, &python_context)?
+    } else {
+        let env_dir = script_env_dir(&metadata.dependencies, python_version)?;
+        cached_environment(&metadata.dependencies, &python_context, &env_dir).with_context(
+            || format!("Failed to install the dependencies of {}", script.display()),
+        )?
+    };
+    run_python_args_finder_data(None, args, &python_context, &python_home, &finder_data)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::inline_script::{
+        find_inline_script, parse_script_metadata, script_env_dir, ScriptMetadata,
+    };
+    use fs_err as fs;
+    use indoc::indoc;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_parse_script_metadata() {
+        let source = indoc! {r#"
+            #!/usr/bin/env python
+            # /// script
+            # requires-python = ">=3.11"
+            # dependencies = [
+            #   "requests<3",
+            #   "rich",
+            # ]
+            #
+            # [tool.some-tool]
+            # option = true
+            # ///
+
+            # /// other
+            # ignored = true
+            # ///
+
+            import requests
+        "#};
+        assert_eq!(
+            parse_script_metadata(source).unwrap(),
+            Some(ScriptMetadata {
+                dependencies: vec!["requests<3".to_string(), "rich".to_string()],
+                requires_python: Some(">=3.11".to_string()),
+            })
+        );
+        let windows = source.replace('\n', "\r\n");
+        assert!(parse_script_metadata(&windows).unwrap().is_some());
+        assert_eq!(parse_script_metadata("import os\n").unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_script_metadata_errors() {
+        let source = indoc! {r#"
+            # /// script
+            # dependencies = []
+            # ///
+            import os
+            # /// script
+            # dependencies = ["rich"]
+            # ///
+        "#};
+        let err = parse_script_metadata(source).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "There are multiple `# /// script` blocks, but only one is allowed"
+        );
+
+        let source = indoc! {r#"
+            # /// script
+            # dependencies = ["rich >"]
+            # ///
+        "#};
+        let err = parse_script_metadata(source).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid dependency `rich >`");
+    }
+
+    #[test]
+    fn test_find_inline_script() {
+        let temp_dir = TempDir::new().unwrap();
+        let script = temp_dir.path().join("script.py");
+        fs::write(
+            &script,
+            "# /// script\n# dependencies = [\"rich\"]\n# ///\nimport rich\n",
+        )
+        .unwrap();
+        let args = [
+            "+3.11".to_string(),
+            "-u".to_string(),
+            script.display().to_string(),
+            "--verbose".to_string(),
+        ];
+        let (found, metadata) = find_inline_script(&args).unwrap().unwrap();
+        assert_eq!(found, script);
+        assert_eq!(metadata.dependencies, ["rich"]);
+
+        let args = ["-m".to_string(), "http.server".to_string()];
+        assert_eq!(find_inline_script(&args).unwrap(), None);
+        fs::write(&script, "import os\n").unwrap();
+        let args = [script.display().to_string()];
+        assert_eq!(find_inline_script(&args).unwrap(), None);
+    }
+
+    #[test]
+    fn test_script_env_dir() {
+        let spaced = ["requests >= 2".to_string(), "rich".to_string()];
+        let compact = ["requests>=2".to_string(), "rich".to_string()];
+        let reordered = ["rich".to_string(), "requests>=2".to_string()];
+        assert_eq!(
+            script_env_dir(&spaced, (3, 11)).unwrap(),
+            script_env_dir(&compact, (3, 11)).unwrap()
+        );
+        assert_eq!(
+            script_env_dir(&reordered, (3, 11)).unwrap(),
+            script_env_dir(&compact, (3, 11)).unwrap()
+        );
+        assert_ne!(
+            script_env_dir(&compact, (3, 11)).unwrap(),
+            script_env_dir(&compact, (3, 12)).unwrap()
+        );
+    }
+}
@@ -1,21 +1,30 @@
 //! Filter and install python packages with install-wheel-rs
 
-use crate::cli::download_distribution_cached;
+use crate::download::download_distribution_cached;
 use crate::monotrail::filter_installed_monotrail;
 use crate::package_index::PYPI_HOST;
+use crate::progress::{self, Phase};
+use crate::snapshot::venv_site_packages;
 use crate::source_distribution::build_source_distribution_to_wheel_cached;
-use crate::spec::{DistributionType, FileOrUrl, RequestedSpec};
-use anyhow::{bail, Context};
+use crate::spec::{DistributionType, FileOrUrl, RequestedSpec, ResolvedSpec};
+use crate::utils::cache_dir;
+use anyhow::{bail, format_err, Context};
 use fs_err as fs;
 use fs_err::{DirEntry, File};
-use git2::{Direction, Repository};
-use indicatif::{ProgressBar, ProgressStyle};
+use git2::build::RepoBuilder;
+use git2::{FetchOptions, RemoteCallbacks, Repository};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use install_wheel_rs::{
-    install_wheel, normalize_name, parse_key_value_file, CompatibleTags, InstallLocation,
-    LockedDir, WheelFilename,
+    cache_key, check_disk_space_for, normalize_name, parse_key_value_file, wheel_install_paths,
+    ArchiveInfo, CompatibleTags, DirInfo, DirectUrl, InstallLocation, Installer, LockedDir,
+    ProgressReporter, WheelFilename,
+};
+use monotrail_utils::transport::{self, current_timeouts, TimeoutPhase, Transport};
+use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
 };
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
 use std::io;
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
@@ -25,13 +34,18 @@ use std::thread::sleep;
 use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use tracing::{debug, info, trace, warn};
+use url::Url;
+use walkdir::WalkDir;
 
 /// what we communicate back to python
 #[cfg_attr(feature = "python_bindings", pyo3::pyclass(get_all))]
 #[derive(Clone, Debug, Eq, PartialEq, Serialize)]
 pub struct InstalledPackage {
+    /// The normalized name
     pub name: String,
+    /// The version as python sees it
     pub python_version: String,
+    /// The version or git revision, which names the directory in the store
     pub unique_version: String,
     /// The compatibility tag like "py3-none-any" or
     /// "cp38-cp38-manylinux_2_12_x86_64.manylinux2010_x86_64"
@@ -40,7 +54,7 @@ pub struct InstalledPackage {
 
 #[cfg_attr(feature = "python_bindings", pyo3::pymethods)]
 impl InstalledPackage {
-    /// PathBuf for pyo3
+    /// The directory of this package in the store. PathBuf for pyo3
     pub fn monotrail_location(&self, sprawl_root: PathBuf) -> PathBuf {
         sprawl_root
             .join(&self.name)
@@ -48,6 +62,7 @@ impl InstalledPackage {
             .join(&self.tag)
     }
 
+    /// The directory with the modules, the one that goes on `sys.path`
     pub fn monotrail_site_packages(
         &self,
         sprawl_root: PathBuf,
@@ -75,14 +90,7 @@ pub fn filter_installed_venv(
     venv_base: &Path,
     python_version: (u8, u8),
 ) -> anyhow::Result<(Vec<RequestedSpec>, Vec<InstalledPackage>)> {
-    let site_packages = if cfg!(windows) {
-        venv_base.join("Lib").join("site-packages")
-    } else {
-        venv_base
-            .join("lib")
-            .join(format!("python{}.{}", python_version.0, python_version.1))
-            .join("site-packages")
-    };
+    let site_packages = venv_site_packages(venv_base, python_version);
     let entries: Vec<DirEntry> = match fs::read_dir(site_packages) {
         Ok(entries) => entries.collect::<io::Result<Vec<DirEntry>>>()?,
         Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
@@ -117,12 +125,7 @@ pub fn filter_installed_venv(
     let mut not_installed = Vec::new();
     for spec in specs {
         let matching_package = venv_packages.iter().find(|package| {
-            if let Some(spec_version) = &spec.python_version {
-                // TODO: use PEP440
-                package.name == spec.name && &package.python_version == spec_version
-            } else {
-                package.name == spec.name
-            }
+            package.name == spec.name && spec.matches_version(&package.python_version)
         });
         if let Some(package) = matching_package {
             installed.push(package.clone());
@@ -142,6 +145,7 @@ pub fn filter_installed(
         InstallLocation::Venv {
             venv_base,
             python_version,
+            ..
         } => filter_installed_venv(specs, venv_base, *python_version).context(format!(
             "Failed to filter packages installed in the venv at {}",
             venv_base.display()
@@ -153,7 +157,81 @@ pub fn filter_installed(
     }
 }
 
-/// Installs all given specs
+/// Which package of the current installation installs which file into the venv, so that two
+/// wheels writing the same file, e.g. a fork and the original or two distributions of a namespace
+/// package, don't silently clobber each other. Files with the same hash don't conflict
+#[derive(Debug)]
+pub(crate) struct FileClaims {
+    claims: HashMap<String, (String, Option<String>)>,
+    /// Warn and let the last package win instead of failing
+    allow_conflicts: bool,
+}
+
+impl FileClaims {
+    pub(crate) fn new(allow_conflicts: bool) -> Self {
+        Self {
+            claims: HashMap::new(),
+            allow_conflicts,
+        }
+    }
+
+    /// Records the files of `name`, fails if another package installs a different file to the
+    /// same path
+    pub(crate) fn claim(
+        &mut self,
+        name: &str,
+        paths: Vec<(String, Option<String>)>,
+    ) -> anyhow::Result<()> {
+        // other package -> conflicting paths
+        let mut conflicts: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for (path, hash) in &paths {
+            if let Some((other, other_hash)) = self.claims.get(path) {
+                if other != name && (hash.is_none() || hash != other_hash) {
+                    conflicts.entry(other).or_default().push(path);
+                }
+            }
+        }
+        if !conflicts.is_empty() {
+            let conflicts: Vec<String> = conflicts
+                .iter()
+                .map(|(other, paths)| {
+                    let more = if paths.len() > 1 {
+                        format!(" and {} more", paths.len() - 1)
+                    } else {
+                        String::new()
+                    };
+                    format!("{} and {} both install {}{}", other, name, paths[0], more)
+                })
+                .collect();
+            if !self.allow_conflicts {
+                bail!(
+                    "Two packages would write the same files:\n  {}\n\
+                    Remove one of them or pass --allow-file-conflicts to let the last one win",
+                    conflicts.join("\n  ")
+                );
+            }
+            for conflict in conflicts {
+                warn!("{}, overwriting", conflict);
+            }
+        }
+        for (path, hash) in paths {
+            self.claims.insert(path, (name.to_string(), hash));
+        }
+        Ok(())
+    }
+}
+
+/// Installs all given specs, passing the progress of each wheel to `reporter`.
+///
+/// With `keep_going`, a failing package doesn't stop the installation of the others and all
+/// failures are reported together at the end. In a venv, all wheels are downloaded or built first
+/// and their files are checked for conflicts in the order of `specs` before any is installed,
+/// `allow_file_conflicts` turns conflicts into warnings
+#[cfg_attr(
+    feature = "instrument",
+    tracing::instrument(level = "debug", skip_all, fields(count = specs.len()))
+)]
+#[allow(clippy::too_many_arguments)]
 pub fn install_all(
     specs: &[RequestedSpec],
     location: &InstallLocation<LockedDir>,
@@ -161,6 +239,10 @@ pub fn install_all(
     compile: bool,
     background: bool,
     no_parallel: bool,
+    keep_going: bool,
+    allow_file_conflicts: bool,
+    transport: &dyn Transport,
+    reporter: &dyn ProgressReporter,
 ) -> anyhow::Result<Vec<InstalledPackage>> {
     match specs {
         // If everything is already installed, return silently
@@ -174,14 +256,26 @@ pub fn install_all(
             } else {
                 info!("Installing {}", spec.requested);
             }
+            progress::phase_started(Phase::Install, Some(1));
+            progress::package_started(Phase::Install, &spec.name);
             let start = Instant::now();
-            let (python_version, unique_version, tag) = download_and_install(
+            let result = download_and_install(
                 spec,
                 &location,
                 compatible_tags,
                 compile,
                 &location.get_python(),
-            )?;
+                transport,
+                reporter,
+            );
+            progress::package_finished(
+                Phase::Install,
+                &spec.name,
+                start.elapsed(),
+                result.as_ref().err().map(|err| err as _),
+            );
+            progress::phase_finished(Phase::Install);
+            let (python_version, unique_version, tag) = result?;
             debug!(
                 "Installed {} {} in {:.1}s",
                 spec.name,
@@ -202,8 +296,13 @@ pub fn install_all(
                     .template("Installing {bar} {pos:>3}/{len:3} {wide_msg}")
                     .unwrap(), // We know the template, it's correct
             );
+            // The wrapping tool renders the progress
+            if progress::is_enabled() {
+                pb.set_draw_target(ProgressDrawTarget::hidden());
+            }
+            progress::phase_started(Phase::Install, Some(specs.len()));
             let current: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
-            let install_closure = |spec: &RequestedSpec| {
+            let fetch_closure = |spec: &RequestedSpec| {
                 current.lock().unwrap().push(spec.name.clone());
                 pb.set_message(current.lock().unwrap().join(","));
                 if pb.is_hidden() {
@@ -217,47 +316,110 @@ pub fn install_all(
                     }
                 }
 
+                progress::package_started(Phase::Install, &spec.name);
                 let start = Instant::now();
-                let (python_version, unique_version, tag) = download_and_install(
-                    spec,
-                    &location,
-                    compatible_tags,
-                    compile,
-                    &location.get_python(),
-                )?;
-                debug!(
-                    "Installed {} {} in {:.1}s",
-                    spec.name,
-                    unique_version,
-                    start.elapsed().as_secs_f32()
-                );
-                {
-                    let mut current = current.lock().unwrap();
-                    current.retain(|x| x != &spec.name);
-                    pb.set_message(current.join(", "));
-                    pb.inc(1);
-                }
+                let fetched = fetch_wheel(spec, compatible_tags, &location.get_python(), transport);
+                (start, fetched)
+            };
+            let finish =
+                |spec: &RequestedSpec,
+                 start: Instant,
+                 result: anyhow::Result<(String, String, String)>| {
+                    progress::package_finished(
+                        Phase::Install,
+                        &spec.name,
+                        start.elapsed(),
+                        result.as_ref().err().map(|err| err as _),
+                    );
+                    {
+                        let mut current = current.lock().unwrap();
+                        current.retain(|x| x != &spec.name);
+                        pb.set_message(current.join(", "));
+                        pb.inc(1);
+                    }
+                    let (python_version, unique_version, tag) = result?;
+                    debug!(
+                        "Installed {} {} in {:.1}s",
+                        spec.name,
+                        unique_version,
+                        start.elapsed().as_secs_f32()
+                    );
 
-                let installed_package = InstalledPackage {
-                    name: spec.normalized_name(),
-                    python_version,
-                    unique_version,
-                    tag,
+                    Ok(InstalledPackage {
+                        name: spec.normalized_name(),
+                        python_version,
+                        unique_version,
+                        tag,
+                    })
                 };
-                Ok(installed_package)
+
+            let mut fetched: Vec<(Instant, anyhow::Result<FetchedWheel>)> = if no_parallel {
+                specs.iter().map(fetch_closure).collect()
+            } else {
+                specs.par_iter().map(fetch_closure).collect()
             };
+            // In a venv, all packages share site-packages and bin. We claim the files one package
+            // after the other before installing anything, so the same packages always conflict
+            // and a conflict doesn't leave half-installed files behind
+            if matches!(location, InstallLocation::Venv { .. }) {
+                let mut claims = FileClaims::new(allow_file_conflicts);
+                for (_, result) in &mut fetched {
+                    if let Ok(wheel) = result {
+                        if let Err(err) = wheel.claim_files(&mut claims) {
+                            *result = Err(err);
+                        }
+                    }
+                }
+            }
+            // Once for all wheels, so we don't run out of space after installing half of them
+            let wheels = fetched
+                .iter()
+                .filter_map(|(_, result)| Some(&result.as_ref().ok()?.wheel));
+            if let Err(err) = check_disk_space_for(location, wheels) {
+                pb.finish_and_clear();
+                progress::phase_finished(Phase::Install);
+                return Err(err.into());
+            }
+            if !keep_going {
+                if let Some(index) = fetched.iter().position(|(_, result)| result.is_err()) {
+                    if let (start, Err(err)) = fetched.swap_remove(index) {
+                        let result = finish(&specs[index], start, Err(err));
+                        pb.finish_and_clear();
+                        progress::phase_finished(Phase::Install);
+                        return result.map(|installed| vec![installed]);
+                    }
+                }
+            }
 
-            let installed = if no_parallel {
-                specs
-                    .iter()
-                    .map(install_closure)
-                    .collect::<Result<Vec<InstalledPackage>, anyhow::Error>>()?
+            let install_closure = |(spec, (start, fetched)): (
+                &RequestedSpec,
+                (Instant, anyhow::Result<FetchedWheel>),
+            )| {
+                // Without --keep-going, we already returned the first fetch error above
+                let result = fetched
+                    .with_context(|| format!("Failed to install {}", spec.requested))
+                    .and_then(|wheel| {
+                        wheel.install(&location, compile, &location.get_python(), reporter)
+                    });
+                finish(spec, start, result)
+            };
+            let results: Vec<anyhow::Result<InstalledPackage>> = if no_parallel {
+                specs.iter().zip(fetched).map(install_closure).collect()
             } else {
                 specs
                     .par_iter()
+                    .zip(fetched.into_par_iter())
                     .map(install_closure)
-                    .collect::<Result<Vec<InstalledPackage>, anyhow::Error>>()?
+                    .collect()
             };
+            let installed = if keep_going {
+                pb.finish_and_clear();
+                collect_failures(specs, results)
+            } else {
+                results.into_iter().collect()
+            };
+            progress::phase_finished(Phase::Install);
+            let installed = installed?;
             pb.finish_and_clear();
             info!(
                 "Installed {} packages in {:.1}s",
@@ -271,7 +433,11 @@ pub fn install_all(
 
 /// <https://stackoverflow.com/a/67240436/3549270>
 fn checkout_revision(revision: &str, repo: Repository) -> Result<(), git2::Error> {
-    let (object, reference) = repo.revparse_ext(revision)?;
+    // Branches other than the default one only exist as remote branches, and the fetch only
+    // updates those
+    let (object, reference) = repo
+        .revparse_ext(&format!("origin/{}", revision))
+        .or_else(|_| repo.revparse_ext(revision))?;
 
     repo.checkout_tree(&object, None)?;
 
@@ -284,6 +450,33 @@ fn checkout_revision(revision: &str, repo: Repository) -> Result<(), git2::Error
     Ok(())
 }
 
+/// libgit2 has no timeout options, so we abort transfers that exceed the total timeout from
+/// [current_timeouts]. Returns the options and the deadline
+fn fetch_options_with_timeout<'a>() -> (FetchOptions<'a>, Option<Instant>) {
+    let deadline = current_timeouts().total.map(|total| Instant::now() + total);
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.transfer_progress(move |_progress| match deadline {
+        Some(deadline) => Instant::now() < deadline,
+        None => true,
+    });
+    let mut fetch_options = FetchOptions::new();
+    fetch_options.remote_callbacks(callbacks);
+    (fetch_options, deadline)
+}
+
+/// Reports an aborted transfer as timeout
+fn git_timeout(err: git2::Error, url: &str, deadline: Option<Instant>) -> anyhow::Error {
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        transport::Error::Timeout {
+            url: url.to_string(),
+            phase: TimeoutPhase::Total,
+        }
+        .into()
+    } else {
+        err.into()
+    }
+}
+
 /// If the folder does not yet exist, it clones the repo and checks out the revision, otherwise
 /// it fetches and checks out
 pub fn repo_at_revision(url: &str, revision: &str, repo_dir: &Path) -> anyhow::Result<()> {
@@ -303,18 +496,18 @@ pub fn repo_at_revision(url: &str, revision: &str, repo_dir: &Path) -> anyhow::R
         let mut origin = repo
             .find_remote("origin")
             .context("No remote origin in repository")?;
-        // required for default_branch
-        origin
-            .connect(Direction::Fetch)
-            .context("Couldn't connect to remote")?;
-        let default_branch = origin
-            .default_branch()
-            .context("Missing default branch")?
-            .as_str()
-            .context("Can't get default branch name")?
-            .to_string();
+        let (mut fetch_options, deadline) = fetch_options_with_timeout();
+        // All branches and tags, the revision might be any of them
         origin
-            .fetch(&[default_branch], None, None)
+            .fetch(
+                &[
+                    "+refs/heads/*:refs/remotes/origin/*",
+                    "+refs/tags/*:refs/tags/*",
+                ],
+                Some(&mut fetch_options),
+                None,
+            )
+            .map_err(|err| git_timeout(err, url, deadline))
             .context("Failed to fetch repository")?;
         drop(origin);
         repo
@@ -323,7 +516,11 @@ pub fn repo_at_revision(url: &str, revision: &str, repo_dir: &Path) -> anyhow::R
         // https://stackoverflow.com/q/3489173/3549270
         let mut tries = 1;
         loop {
-            let result = Repository::clone(url, repo_dir);
+            let (fetch_options, deadline) = fetch_options_with_timeout();
+            let result = RepoBuilder::new()
+                .fetch_options(fetch_options)
+                .clone(url, repo_dir)
+                .map_err(|err| git_timeout(err, url, deadline));
             let backoff = Duration::from_secs(1);
             tries -= 1;
             match result {
@@ -352,52 +549,182 @@ pub fn repo_at_revision(url: &str, revision: &str, repo_dir: &Path) -> anyhow::R
     Ok(())
 }
 
-/// Returns the python version, unique version
+/// Checks out `revision` (a branch, tag or commit, `HEAD` for the default branch) of the
+/// repository in the cache and returns the checkout and the full hash of the commit
+pub fn git_checkout(url: &str, revision: &str) -> anyhow::Result<(PathBuf, String)> {
+    let repo_dir = cache_dir()?
+        .join("checkouts")
+        .join(cache_key(format!("{}-{}", url, revision)));
+    repo_at_revision(url, revision, &repo_dir).context("Failed to checkout repository")?;
+    let commit = Repository::open(&repo_dir)?
+        .head()?
+        .peel_to_commit()?
+        .id()
+        .to_string();
+    Ok((repo_dir, commit))
+}
+
+/// Copies the checkout without `.git` to a temporary directory, so builds don't write
+/// `build/` or `*.egg-info` into the cached checkout that other builds reuse
+pub fn copy_checkout(repo_dir: &Path) -> anyhow::Result<TempDir> {
+    let copy = TempDir::new()?;
+    let walker = WalkDir::new(repo_dir)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| entry.depth() > 1 || entry.file_name() != ".git");
+    for entry in walker {
+        let entry = entry?;
+        let target = copy.path().join(entry.path().strip_prefix(repo_dir)?);
+        if entry.file_type().is_dir() {
+            fs::create_dir_all(&target)?;
+        } else if entry.file_type().is_symlink() {
+            #[cfg(unix)]
+            fs_err::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)?;
+            #[cfg(not(unix))]
+            fs::copy(entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(copy)
+}
+
+/// Returns the python version, unique version and tag
 fn download_and_install(
     requested_spec: &RequestedSpec,
     location: &InstallLocation<LockedDir>,
     compatible_tags: &CompatibleTags,
     compile: bool,
     sys_executable: &Path,
+    transport: &dyn Transport,
+    reporter: &dyn ProgressReporter,
 ) -> anyhow::Result<(String, String, String)> {
-    let spec = requested_spec.resolve(PYPI_HOST, compatible_tags)?;
+    let wheel = fetch_wheel(requested_spec, compatible_tags, sys_executable, transport)?;
+    check_disk_space_for(location, [&wheel.wheel])?;
+    wheel.install(location, compile, sys_executable, reporter)
+}
+
+/// A downloaded or built wheel that we haven't installed yet
+struct FetchedWheel {
+    spec: ResolvedSpec,
+    wheel: PathBuf,
+    filename: WheelFilename,
+    direct_url: Option<DirectUrl>,
+}
+
+impl FetchedWheel {
+    /// See [`FileClaims`]
+    fn claim_files(&self, claims: &mut FileClaims) -> anyhow::Result<()> {
+        let paths = wheel_install_paths(&self.filename, File::open(&self.wheel)?)
+            .with_context(|| format!("Failed to read {}", self.wheel.display()))?;
+        claims.claim(&self.spec.name, paths)
+    }
+
+    /// Returns the python version, unique version and tag
+    fn install(
+        self,
+        location: &InstallLocation<LockedDir>,
+        compile: bool,
+        sys_executable: &Path,
+        reporter: &dyn ProgressReporter,
+    ) -> anyhow::Result<(String, String, String)> {
+        let spec = self.spec;
+        debug!("Installing {} {}", spec.name, spec.unique_version);
+        // Local projects can change without a new version, so we always replace
+        let tag = Installer::new(location, sys_executable)
+            .compile(compile)
+            .unique_version(&spec.unique_version)
+            .direct_url(self.direct_url)
+            .force(true)
+            .reporter(reporter)
+            .install(File::open(self.wheel)?, self.filename)
+            .with_context(|| format!("Failed to install {}", spec.requested))?;
+        Ok((spec.python_version, spec.unique_version, tag))
+    }
+}
+
+/// Downloads the wheel of the spec, or downloads or checks out the source and builds it
+#[cfg_attr(
+    feature = "instrument",
+    tracing::instrument(level = "debug", skip_all, fields(name = requested_spec.name))
+)]
+fn fetch_wheel(
+    requested_spec: &RequestedSpec,
+    compatible_tags: &CompatibleTags,
+    sys_executable: &Path,
+    transport: &dyn Transport,
+) -> anyhow::Result<FetchedWheel> {
+    let spec = requested_spec.resolve(PYPI_HOST, transport, compatible_tags)?;
     trace!("requested: {:?}, resolved: {:?}", requested_spec, spec);
 
+    let mut direct_url = None;
     let (wheel, distribution_type) = match spec.location.clone() {
         FileOrUrl::File(file_path) => {
-            if file_path.as_os_str().to_string_lossy().ends_with(".whl") {
+            // A path dependency rather than a wheel given on the command line
+            if requested_spec.source.is_some() {
+                let url = Url::from_file_path(&file_path)
+                    .map_err(|()| format_err!("Invalid path {}", file_path.display()))?
+                    .to_string();
+                direct_url = Some(if file_path.is_dir() {
+                    DirectUrl::LocalDirectory {
+                        url,
+                        dir_info: DirInfo::default(),
+                    }
+                } else {
+                    DirectUrl::ArchiveUrl {
+                        url,
+                        archive_info: ArchiveInfo::default(),
+                    }
+                });
+            }
+            if file_path.is_dir() {
+                (file_path, DistributionType::SourceDistribution)
+            } else if file_path.as_os_str().to_string_lossy().ends_with(".whl") {
                 (file_path, DistributionType::Wheel)
             } else if file_path.as_os_str().to_string_lossy().ends_with(".tar.gz") {
                 (file_path, DistributionType::SourceDistribution)
             } else {
                 bail!(
-                    "Unknown filetype (neither .whl not .tar.gz): {}",
+                    "Unknown filetype (neither .whl nor .tar.gz nor a directory): {}",
                     file_path.display()
                 )
             }
         }
         FileOrUrl::Url { url, filename } => {
-            let wheel =
-                download_distribution_cached(&spec.name, &spec.unique_version, &filename, &url)
-                    .with_context(|| format!("Failed to download {} from pypi", spec.requested))?;
+            let wheel = download_distribution_cached(
+                transport,
+                &spec.name,
+                &spec.unique_version,
+                &filename,
+                &url,
+                spec.sha256.as_deref(),
+            )
+            .with_context(|| format!("Failed to download {} from pypi", spec.requested))?;
 
             (wheel, spec.distribution_type.clone())
         }
         FileOrUrl::Git { url, revision } => {
-            let temp_dir = TempDir::new()?;
-            let repo_dir = temp_dir.path().join(&spec.name);
-            repo_at_revision(&url, &revision, &repo_dir)?;
+            let (repo_dir, commit) = git_checkout(&url, &revision)?;
+            let source_tree = copy_checkout(&repo_dir)?;
+            // e.g. the branch from the requirement, the lock pins the commit
+            let requested_revision = requested_spec
+                .source
+                .as_ref()
+                .map(|source| source.reference.as_str())
+                .filter(|reference| !reference.is_empty() && *reference != commit);
+            direct_url = Some(DirectUrl::git(&url, &commit, requested_revision));
 
-            // If we got an sdist until now, build it into a wheel
             debug!(
-                "Building {} {} from source distribution to wheel",
-                spec.name, spec.unique_version
+                "Building {} {} from {} to wheel",
+                spec.name, spec.unique_version, url
             );
             let wheel = build_source_distribution_to_wheel_cached(
                 &spec.name,
                 &spec.unique_version,
-                &repo_dir,
+                source_tree.path(),
+                sys_executable,
                 compatible_tags,
+                transport,
             )
             .with_context(|| {
                 format!(
@@ -422,7 +749,9 @@ fn download_and_install(
             &spec.name,
             &spec.unique_version,
             &wheel,
+            sys_executable,
             compatible_tags,
+            transport,
         )
         .with_context(|| {
             format!(
@@ -431,22 +760,161 @@ fn download_and_install(
             )
         })?
     };
-    debug!("Installing {} {}", spec.name, spec.unique_version);
     let filename = wheel
         .file_name()
         .ok_or_else(|| install_wheel_rs::Error::InvalidWheel("Expected a file".to_string()))?
         .to_string_lossy();
     let filename = WheelFilename::from_str(&filename)?;
-    let tag = install_wheel(
-        location,
-        File::open(wheel)?,
+    Ok(FetchedWheel {
+        spec,
+        wheel,
         filename,
-        compile,
-        true,
-        &spec.extras,
-        &spec.unique_version,
-        sys_executable,
-    )
-    .with_context(|| format!("Failed to install {}", spec.requested))?;
-    Ok((spec.python_version, spec.unique_version, tag))
+        direct_url,
+    })
+}
+
+/// Returns the installed packages if all succeeded, otherwise one error listing every failure.
+/// The errors already start with `Failed to install <package>`
+fn collect_failures(
+    specs: &[RequestedSpec],
+    results: Vec<anyhow::Result<InstalledPackage>>,
+) -> anyhow::Result<Vec<InstalledPackage>> {
+    let mut installed = Vec::new();
+    let mut failures = Vec::new();
+    for result in results {
+        match result {
+            Ok(installed_package) => installed.push(installed_package),
+            Err(err) => failures.push(format!("{:#}", err)),
+        }
+    }
+    if !failures.is_empty() {
+        bail!(
+            "{} of {} packages failed to install, the others were installed:\n  {}",
+            failures.len(),
+            specs.len(),
+            failures.join("\n  ")
+        );
+    }
+    Ok(installed)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::install::{collect_failures, copy_checkout, FileClaims, InstalledPackage};
+    use crate::spec::RequestedSpec;
+    use anyhow::format_err;
+    use fs_err as fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_collect_failures() {
+        let specs = [
+            RequestedSpec::from_requested("tqdm==4.62.3", &[]).unwrap(),
+            RequestedSpec::from_requested("numpy==1.23.4", &[]).unwrap(),
+            RequestedSpec::from_requested("scipy==1.9.3", &[]).unwrap(),
+        ];
+        let tqdm = InstalledPackage {
+            name: "tqdm".to_string(),
+            python_version: "4.62.3".to_string(),
+            unique_version: "4.62.3".to_string(),
+            tag: "py2.py3-none-any".to_string(),
+        };
+        let results = vec![
+            Ok(tqdm.clone()),
+            Err(format_err!("Failed to build").context("Failed to install numpy==1.23.4")),
+            Err(format_err!("No compatible wheel").context("Failed to install scipy==1.9.3")),
+        ];
+        let err = collect_failures(&specs, results).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "2 of 3 packages failed to install, the others were installed:\n  \
+            Failed to install numpy==1.23.4: Failed to build\n  \
+            Failed to install scipy==1.9.3: No compatible wheel"
+        );
+        assert_eq!(
+            collect_failures(&specs[..1], vec![Ok(tqdm.clone())]).unwrap(),
+            [tqdm]
+        );
+    }
+
+    #[test]
+    fn test_file_claims() {
+        let mut claims = FileClaims::new(false);
+        let hash = |hash: &str| Some(hash.to_string());
+        claims
+            .claim(
+                "zope-interface",
+                vec![
+                    ("zope/__init__.py".to_string(), hash("sha256=a")),
+                    ("zope/interface/__init__.py".to_string(), hash("sha256=b")),
+                ],
+            )
+            .unwrap();
+        // The same namespace `__init__.py` is fine
+        claims
+            .claim(
+                "zope-event",
+                vec![
+                    ("zope/__init__.py".to_string(), hash("sha256=a")),
+                    ("zope/event/__init__.py".to_string(), hash("sha256=c")),
+                ],
+            )
+            .unwrap();
+        let err = claims
+            .claim(
+                "zope-interface-fork",
+                vec![
+                    ("zope/interface/__init__.py".to_string(), hash("sha256=d")),
+                    ("zope/event/__init__.py".to_string(), hash("sha256=e")),
+                    ("zope/event/base.py".to_string(), hash("sha256=f")),
+                ],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Two packages would write the same files:\n  \
+            zope-event and zope-interface-fork both install zope/event/__init__.py\n  \
+            zope-interface and zope-interface-fork both install zope/interface/__init__.py\n\
+            Remove one of them or pass --allow-file-conflicts to let the last one win"
+        );
+
+        // The last package wins
+        let mut claims = FileClaims::new(true);
+        for (name, hash) in [
+            ("zope-interface", "sha256=b"),
+            ("zope-interface-fork", "sha256=d"),
+        ] {
+            let paths = vec![(
+                "zope/interface/__init__.py".to_string(),
+                Some(hash.to_string()),
+            )];
+            claims.claim(name, paths).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_copy_checkout() {
+        let repo_dir = TempDir::new().unwrap();
+        fs::create_dir_all(repo_dir.path().join(".git").join("objects")).unwrap();
+        fs::create_dir_all(repo_dir.path().join("src").join("pkg")).unwrap();
+        fs::write(repo_dir.path().join("pyproject.toml"), "").unwrap();
+        fs::write(
+            repo_dir.path().join("src").join("pkg").join("__init__.py"),
+            "",
+        )
+        .unwrap();
+
+        let copy = copy_checkout(repo_dir.path()).unwrap();
+        assert!(!copy.path().join(".git").exists());
+        assert!(copy.path().join("pyproject.toml").is_file());
+        assert!(copy
+            .path()
+            .join("src")
+            .join("pkg")
+            .join("__init__.py")
+            .is_file());
+        // What the build writes stays out of the checkout
+        fs::create_dir(copy.path().join("build")).unwrap();
+        assert!(!repo_dir.path().join("build").exists());
+    }
 }
@@ -0,0 +1,937 @@
+//! Finds the pythons installed on this machine, so callers don't have to pass interpreter paths and
+//! versions around. We look in `PATH`, the pyenv versions, the pythons the windows `py` launcher
+//! knows about (which reads the PEP 514 registry entries), some common install dirs and the
+//! pythons we downloaded ourselves.
+//!
+//! Each interpreter is queried once with [INTERPRETER_QUERY], a single python process that returns
+//! version, implementation, ABI, platform, sysconfig paths and markers as json. The results are
+//! cached by path and modification time, since starting python is slow compared to everything else
+//! we do here.
+
+use crate::poetry_integration::constraint::poetry_to_pep440;
+use crate::utils::cache_dir;
+use crate::PEP508_QUERY_ENV;
+use anyhow::{bail, Context};
+use fs_err as fs;
+use install_wheel_rs::cache_key;
+use monotrail_utils::standalone_python::provision_python;
+use monotrail_utils::transport::UreqTransport;
+use pep440_rs::{Version, VersionSpecifiers};
+use pep508_rs::MarkerEnvironment;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::env;
+use std::ffi::OsStr;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+use tracing::{debug, info};
+
+/// The python script to return the [Interpreter] as json, needs [PEP508_QUERY_ENV]
+static INTERPRETER_QUERY: &str = include_str!("get_interpreter_info.py");
+
+/// The python-build-standalone versions we download when there's no matching python, newest
+/// first
+const MANAGED_PYTHON_VERSIONS: [(u8, u8); 5] = [(3, 12), (3, 11), (3, 10), (3, 9), (3, 8)];
+
+/// `monotrail python list|find|install`
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum PythonCommand {
+    /// Show all pythons we can find with their version and location
+    List,
+    /// Print the path of the first python matching the requires-python specifiers
+    Find {
+        /// PEP 440 specifiers such as `>=3.9,<3.12`. Defaults to the version in the
+        /// `.python-version` or the `requires-python` of the project in the current directory,
+        /// otherwise any version
+        requires_python: Option<String>,
+        /// Download a standalone python if none of the installed ones matches
+        #[clap(long)]
+        download: bool,
+    },
+    /// Download a standalone python build into the cache, where all projects share it
+    Install {
+        /// The python version x.y, e.g. `3.12`
+        version: String,
+    },
+}
+
+/// What we know about an installed python
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Interpreter {
+    /// `sys.executable`, which can differ from the path we found it at, e.g. for pyenv
+    pub sys_executable: PathBuf,
+    /// `sys.version_info` major, minor and micro
+    pub version: (u8, u8, u8),
+    /// `sys.implementation.name`, e.g. `cpython` or `pypy`
+    pub implementation: String,
+    /// `sys.abiflags`, e.g. `d` for a debug build, empty on windows
+    pub abiflags: String,
+    /// The `SOABI` config var, e.g. `cpython-311-x86_64-linux-gnu`
+    pub soabi: Option<String>,
+    /// `sysconfig.get_platform()`, e.g. `linux-x86_64` or `macosx-11.0-arm64`
+    pub platform: String,
+    /// 32 or 64
+    pub pointer_size: u8,
+    /// `sys.base_prefix`, the installation this interpreter or venv is based on
+    pub base_prefix: PathBuf,
+    /// Where this interpreter or venv installs packages
+    pub paths: SysconfigPaths,
+    /// The PEP 508 marker values
+    pub markers: MarkerEnvironment,
+}
+
+/// The install paths of the default `sysconfig` scheme, absolute
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SysconfigPaths {
+    /// Pure python packages, `site-packages`
+    pub purelib: PathBuf,
+    /// Packages with native extensions, the same as purelib except on some linux distributions
+    pub platlib: PathBuf,
+    /// Entrypoints and scripts, `bin` or `Scripts`
+    pub scripts: PathBuf,
+    /// The prefix for the `.data/data` files of a wheel
+    pub data: PathBuf,
+    /// The C headers of the interpreter
+    pub include: PathBuf,
+}
+
+impl Interpreter {
+    /// Major and minor version, the way most of monotrail takes python versions
+    pub fn python_version(&self) -> (u8, u8) {
+        (self.version.0, self.version.1)
+    }
+
+    /// Whether this is a free-threaded build (PEP 703) such as `python3.13t`: `t` in the
+    /// abiflags, or on windows, which has no abiflags, in the `SOABI` (`cp313t-win_amd64`)
+    pub fn is_free_threaded(&self) -> bool {
+        self.abiflags.contains('t')
+            || self.soabi.as_deref().is_some_and(|soabi| {
+                soabi.split('-').any(|part| {
+                    let abi = part.strip_prefix("cp").unwrap_or(part);
+                    abi.strip_suffix('t').is_some_and(|version| {
+                        !version.is_empty() && version.chars().all(|char| char.is_ascii_digit())
+                    })
+                })
+            })
+    }
+
+    /// The version for comparing with requires-python
+    pub fn full_version(&self) -> Version {
+        Version::from_str(&format!(
+            "{}.{}.{}",
+            self.version.0, self.version.1, self.version.2
+        ))
+        // Three numbers are always a valid version
+        .unwrap()
+    }
+}
+
+/// The query result saved in the cache dir, valid as long as the executable isn't modified
+#[derive(Serialize, Deserialize)]
+struct CachedInterpreter {
+    modified: SystemTime,
+    interpreter: Interpreter,
+}
+
+/// `python`, `python3`, `python3.x` and the free-threaded `python3.xt`, with `.exe` on windows
+fn is_python_executable_name(name: &str) -> bool {
+    static PYTHON_NAME: OnceLock<Regex> = OnceLock::new();
+    PYTHON_NAME
+        .get_or_init(|| Regex::new(r"^python(3(\.\d+t?)?)?(\.exe)?$").unwrap())
+        .is_match(name)
+}
+
+/// All pythons in the directories of a `PATH`-like variable, in order
+fn path_candidates(path_var: &OsStr) -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    for dir in env::split_paths(path_var) {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        let mut dir_candidates: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| is_python_executable_name(&entry.file_name().to_string_lossy()))
+            .map(|entry| entry.path())
+            .collect();
+        // Deterministic order, read_dir isn't
+        dir_candidates.sort();
+        candidates.extend(dir_candidates);
+    }
+    candidates
+}
+
+/// The versions installed by pyenv (or pyenv-win), without the shims, which depend on the
+/// working directory
+fn pyenv_candidates() -> Vec<PathBuf> {
+    let pyenv_root = env::var_os("PYENV_ROOT")
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".pyenv")));
+    let Some(Ok(versions)) = pyenv_root.map(|root| fs::read_dir(root.join("versions"))) else {
+        return Vec::new();
+    };
+    let mut candidates: Vec<PathBuf> = versions
+        .filter_map(|entry| entry.ok())
+        .map(|entry| {
+            if cfg!(windows) {
+                entry.path().join("python.exe")
+            } else {
+                entry.path().join("bin").join("python3")
+            }
+        })
+        .collect();
+    candidates.sort();
+    candidates
+}
+
+/// Parses the output of `py --list-paths`, e.g. ` -V:3.12 *        C:\Python312\python.exe` or
+/// ` -3.9-64        C:\Python39\python.exe` with older launchers
+fn parse_py_list_paths(output: &str) -> Vec<PathBuf> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            if !line.starts_with('-') {
+                return None;
+            }
+            // The path may contain spaces, so we take everything after the tag and the optional
+            // default marker
+            let (_tag, rest) = line.split_once(char::is_whitespace)?;
+            let path = rest.trim_start().trim_start_matches('*').trim();
+            (!path.is_empty()).then(|| PathBuf::from(path))
+        })
+        .collect()
+}
+
+/// The pythons registered on windows, through the `py` launcher
+fn py_launcher_candidates() -> Vec<PathBuf> {
+    if !cfg!(windows) {
+        return Vec::new();
+    }
+    match Command::new("py").arg("--list-paths").output() {
+        Ok(output) if output.status.success() => {
+            parse_py_list_paths(&String::from_utf8_lossy(&output.stdout))
+        }
+        Ok(output) => {
+            debug!("`py --list-paths` failed: {}", output.status);
+            Vec::new()
+        }
+        Err(err) => {
+            debug!("No py launcher: {}", err);
+            Vec::new()
+        }
+    }
+}
+
+/// Places where installers put python, also if they didn't add it to `PATH`
+fn common_dir_candidates() -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    if cfg!(windows) {
+        if let Some(local_app_data) = env::var_os("LOCALAPPDATA") {
+            let programs = PathBuf::from(local_app_data)
+                .join("Programs")
+                .join("Python");
+            if let Ok(entries) = fs::read_dir(programs) {
+                let mut installs: Vec<PathBuf> = entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path().join("python.exe"))
+                    .collect();
+                installs.sort();
+                candidates.extend(installs);
+            }
+        }
+    } else {
+        for dir in ["/usr/bin", "/usr/local/bin", "/opt/homebrew/bin"] {
+            candidates.extend(path_candidates(OsStr::new(dir)));
+        }
+        let frameworks = Path::new("/Library/Frameworks/Python.framework/Versions");
+        if let Ok(entries) = fs::read_dir(frameworks) {
+            let mut installs: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path().join("bin").join("python3"))
+                .collect();
+            installs.sort();
+            candidates.extend(installs);
+        }
+    }
+    candidates
+}
+
+/// The python-build-standalone pythons we installed, see
+/// [monotrail_utils::standalone_python::provision_python]
+fn managed_candidates() -> Vec<PathBuf> {
+    let Ok(cache_dir) = cache_dir() else {
+        return Vec::new();
+    };
+    let Ok(entries) = fs::read_dir(cache_dir.join("python-build-standalone")) else {
+        return Vec::new();
+    };
+    let mut candidates: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| {
+            let install = entry.path().join("python").join("install");
+            if cfg!(windows) {
+                install.join("python.exe")
+            } else {
+                install.join("bin").join("python3")
+            }
+        })
+        .collect();
+    candidates.sort();
+    candidates
+}
+
+/// All python executables we can find, in order of preference: `PATH` first, then pyenv, the
+/// windows launcher, the common install locations and last the ones we downloaded. Each
+/// executable is only listed once, even if it is linked from multiple places
+pub fn discover_interpreters() -> Vec<PathBuf> {
+    let path_var = env::var_os("PATH").unwrap_or_default();
+    let mut seen = HashSet::new();
+    path_candidates(&path_var)
+        .into_iter()
+        .chain(pyenv_candidates())
+        .chain(py_launcher_candidates())
+        .chain(common_dir_candidates())
+        .chain(managed_candidates())
+        .filter(|candidate| candidate.is_file())
+        .filter(|candidate| {
+            let canonical = candidate
+                .canonicalize()
+                .unwrap_or_else(|_| candidate.clone());
+            seen.insert(canonical)
+        })
+        .collect()
+}
+
+/// The first executable called `name` in `PATH`, trying `name.exe` on windows
+fn find_in_path(name: &Path) -> Option<PathBuf> {
+    let path_var = env::var_os("PATH")?;
+    env::split_paths(&path_var).find_map(|dir| {
+        let candidate = dir.join(name);
+        if candidate.is_file() {
+            return Some(candidate);
+        }
+        let candidate = candidate.with_extension("exe");
+        (cfg!(windows) && candidate.is_file()).then_some(candidate)
+    })
+}
+
+/// Runs the query script in `python`, without the cache
+fn query_interpreter_uncached(python: &Path) -> anyhow::Result<Interpreter> {
+    let mut child = Command::new(python)
+        // No user site-packages or PYTHON* variables. We still need `site`, it sets the prefix
+        // of a venv, which the paths depend on
+        .args(["-I", "-"])
+        .env("PYTHONIOENCODING", "utf-8")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {}", python.display()))?;
+    let script = format!(
+        "{}\n{}\nprint(get_interpreter_info())",
+        PEP508_QUERY_ENV, INTERPRETER_QUERY
+    );
+    child
+        .stdin
+        .as_mut()
+        .expect("piped stdin")
+        .write_all(script.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "Querying {} failed with {}: {}",
+            python.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Invalid interpreter info from {}", python.display()))
+}
+
+/// Returns version, ABI, platform, paths and markers of `python`, from the cache if the
+/// executable didn't change since the last query. A bare name such as `python3` is looked up in
+/// `PATH`
+pub fn query_interpreter(python: &Path) -> anyhow::Result<Interpreter> {
+    let python = if python.components().count() == 1 && !python.is_file() {
+        find_in_path(python).with_context(|| {
+            format!(
+                "Could not find {} on your PATH, are you sure you have python installed?",
+                python.display()
+            )
+        })?
+    } else {
+        std::path::absolute(python)?
+    };
+    let python = python.as_path();
+    let canonical = python
+        .canonicalize()
+        .with_context(|| format!("No python at {}", python.display()))?;
+    let modified = fs::metadata(&canonical)?.modified()?;
+    // A venv python is a link to the base python, but has its own prefix and paths
+    let cache_file = cache_dir()?.join("interpreters").join(format!(
+        "{}.json",
+        cache_key(format!("{}\n{}", python.display(), canonical.display()).as_bytes())
+    ));
+
+    if let Ok(cached) = fs::read(&cache_file) {
+        match serde_json::from_slice::<CachedInterpreter>(&cached) {
+            Ok(cached) if cached.modified == modified => return Ok(cached.interpreter),
+            Ok(_) => debug!("{} changed since it was queried", python.display()),
+            Err(err) => debug!(
+                "Ignoring broken cache entry for {}: {}",
+                python.display(),
+                err
+            ),
+        }
+    }
+
+    let interpreter = query_interpreter_uncached(python)?;
+    let cached = CachedInterpreter {
+        modified,
+        interpreter,
+    };
+    // Not being able to cache only makes the next run slower
+    let written = fs::create_dir_all(cache_file.parent().unwrap())
+        .and_then(|()| fs::write(&cache_file, serde_json::to_vec(&cached)?));
+    if let Err(err) = written {
+        debug!("Failed to cache interpreter info: {}", err);
+    }
+    Ok(cached.interpreter)
+}
+
+/// The first interpreter whose version matches `requires_python`
+fn select_interpreter(
+    interpreters: impl IntoIterator<Item = Interpreter>,
+    requires_python: Option<&VersionSpecifiers>,
+) -> Option<Interpreter> {
+    interpreters.into_iter().find(|interpreter| {
+        let Some(requires_python) = requires_python else {
+            return true;
+        };
+        requires_python.contains(&interpreter.full_version())
+    })
+}
+
+/// Queries all interpreters from [discover_interpreters], skipping those that can't be queried
+pub fn list_interpreters() -> Vec<Interpreter> {
+    discover_interpreters()
+        .into_iter()
+        .filter_map(|python| match query_interpreter(&python) {
+            Ok(interpreter) => Some(interpreter),
+            Err(err) => {
+                debug!("Skipping {}: {:#}", python.display(), err);
+                None
+            }
+        })
+        .collect()
+}
+
+/// The first interpreter in [discover_interpreters] order matching `requires_python`
+pub fn find_interpreter(
+    requires_python: Option<&VersionSpecifiers>,
+) -> anyhow::Result<Interpreter> {
+    select_interpreter(list_interpreters(), requires_python).with_context(|| {
+        if let Some(requires_python) = requires_python {
+            format!("Couldn't find a python matching {}", requires_python)
+        } else {
+            "Couldn't find any python".to_string()
+        }
+    })
+}
+
+/// Downloads and unpacks python-build-standalone for `python_version` if it isn't already in
+/// the cache. The archive is verified against the checksum of the release
+pub fn install_managed_python(python_version: (u8, u8)) -> anyhow::Result<Interpreter> {
+    let (python_binary, _python_home) =
+        provision_python(&UreqTransport, python_version, &cache_dir()?)?;
+    query_interpreter(&python_binary)
+}
+
+/// Whether any `x.y.*` can match `requires_python`, for when we don't know the patch version yet
+pub(crate) fn allows_minor(requires_python: &VersionSpecifiers, (major, minor): (u8, u8)) -> bool {
+    // Only the release segment of the specifiers matters here
+    [0, 99].iter().any(|patch| {
+        let version = Version::from_str(&format!("{}.{}.{}", major, minor, patch)).unwrap();
+        requires_python.contains(&version)
+    })
+}
+
+/// The newest managed python version that can match `requires_python`. Patch versions aren't
+/// known before downloading, so we check whether any `x.y.*` can match
+fn managed_python_version(requires_python: Option<&VersionSpecifiers>) -> Option<(u8, u8)> {
+    MANAGED_PYTHON_VERSIONS.into_iter().find(|python_version| {
+        let Some(requires_python) = requires_python else {
+            return true;
+        };
+        allows_minor(requires_python, *python_version)
+    })
+}
+
+/// Like [find_interpreter], but downloads a standalone python if none is installed
+pub fn find_or_install_interpreter(
+    requires_python: Option<&VersionSpecifiers>,
+) -> anyhow::Result<Interpreter> {
+    if let Some(interpreter) = select_interpreter(list_interpreters(), requires_python) {
+        return Ok(interpreter);
+    }
+    let python_version = managed_python_version(requires_python).with_context(|| {
+        format!(
+            "No installed python matches {}, and neither does any version we can download",
+            requires_python.map(ToString::to_string).unwrap_or_default()
+        )
+    })?;
+    info!(
+        "No matching python installed, downloading python {}.{}",
+        python_version.0, python_version.1
+    );
+    let interpreter = install_managed_python(python_version)?;
+    if let Some(requires_python) = requires_python {
+        if !requires_python.contains(&interpreter.full_version()) {
+            bail!(
+                "The downloaded python {} doesn't match {}",
+                interpreter.full_version(),
+                requires_python
+            );
+        }
+    }
+    Ok(interpreter)
+}
+
+/// The pyenv `.python-version` file
+pub const PYTHON_VERSION_FILE: &str = ".python-version";
+
+/// The first version in a `.python-version`, ignoring comments and the other versions pyenv would
+/// fall back to. `system` means no particular version. We only know cpython, so `pypy3.10` or
+/// `miniconda3-latest` are errors instead of being silently ignored
+fn parse_python_version_file(contents: &str) -> anyhow::Result<Option<Version>> {
+    let Some(line) = contents
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+    else {
+        return Ok(None);
+    };
+    if line == "system" {
+        return Ok(None);
+    }
+    let release = line.split('.').collect::<Vec<_>>();
+    if !(2..=3).contains(&release.len())
+        || !release
+            .iter()
+            .all(|part| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit()))
+    {
+        bail!(
+            "Unsupported python version `{}`, only cpython versions such as 3.11 or 3.11.4 are \
+            supported",
+            line
+        );
+    }
+    Ok(Some(Version::from_str(line).map_err(|err| {
+        anyhow::format_err!("Invalid python version `{}`: {}", line, err)
+    })?))
+}
+
+/// `requires-python` from `[project]` or the `python` constraint of poetry
+fn read_requires_python(pyproject_toml: &Path) -> anyhow::Result<Option<VersionSpecifiers>> {
+    let doc: toml::Value = toml::from_str(&fs::read_to_string(pyproject_toml)?)
+        .with_context(|| format!("Invalid {}", pyproject_toml.display()))?;
+    if let Some(requires_python) = doc
+        .get("project")
+        .and_then(|project| project.get("requires-python"))
+        .and_then(toml::Value::as_str)
+    {
+        return VersionSpecifiers::from_str(requires_python)
+            .map(Some)
+            .map_err(|err| {
+                anyhow::format_err!(
+                    "Invalid requires-python in {}: {}",
+                    pyproject_toml.display(),
+                    err
+                )
+            });
+    }
+    let poetry_python = doc
+        .get("tool")
+        .and_then(|tool| tool.get("poetry"))
+        .and_then(|poetry| poetry.get("dependencies"))
+        .and_then(|dependencies| dependencies.get("python"))
+        .and_then(toml::Value::as_str);
+    poetry_python
+        .map(poetry_to_pep440)
+        .transpose()
+        .with_context(|| format!("Invalid python constraint in {}", pyproject_toml.display()))
+}
+
+/// The x.y of a pinned version, which `parse_python_version_file` ensured has at least two parts
+fn release_minor(version: &Version, path: &Path) -> anyhow::Result<(u8, u8)> {
+    match (
+        u8::try_from(version.release[0]),
+        u8::try_from(version.release[1]),
+    ) {
+        (Ok(major), Ok(minor)) => Ok((major, minor)),
+        _ => bail!("Invalid python version {} in {}", version, path.display()),
+    }
+}
+
+/// The python a project asks for, with the `.python-version` and the `requires-python` of the
+/// closest pyproject.toml. The `.python-version` picks the version, `requires-python` the range
+/// of versions we may pick from
+#[derive(Debug, Clone, Default)]
+pub struct ProjectPython {
+    /// The version from the closest `.python-version` within the project and the file
+    pub pinned: Option<(Version, PathBuf)>,
+    /// `requires-python` of the closest pyproject.toml and the file
+    pub requires_python: Option<(VersionSpecifiers, PathBuf)>,
+}
+
+impl ProjectPython {
+    /// Reads the closest pyproject.toml in `dir` or its parents and the closest `.python-version`
+    /// up to the directory of that pyproject.toml, so a pin outside of the project doesn't apply.
+    /// Fails if the pinned version doesn't match `requires-python`
+    pub fn find(dir: &Path) -> anyhow::Result<Self> {
+        let requires_python = match dir
+            .ancestors()
+            .map(|ancestor| ancestor.join("pyproject.toml"))
+            .find(|path| path.is_file())
+        {
+            Some(path) => read_requires_python(&path)?.map(|specifiers| (specifiers, path)),
+            None => None,
+        };
+        let project_dir = requires_python.as_ref().and_then(|(_, path)| path.parent());
+        let mut pin_path = None;
+        for ancestor in dir.ancestors() {
+            if ancestor.join(PYTHON_VERSION_FILE).is_file() {
+                pin_path = Some(ancestor.join(PYTHON_VERSION_FILE));
+                break;
+            }
+            if Some(ancestor) == project_dir {
+                break;
+            }
+        }
+        let pinned = match pin_path {
+            Some(path) => parse_python_version_file(&fs::read_to_string(&path)?)
+                .with_context(|| format!("Invalid {}", path.display()))?
+                .map(|version| (version, path)),
+            None => None,
+        };
+        if let (Some((version, pin_path)), Some((specifiers, pyproject_path))) =
+            (&pinned, &requires_python)
+        {
+            let matches = if version.release.len() == 2 {
+                allows_minor(specifiers, release_minor(version, pin_path)?)
+            } else {
+                specifiers.contains(version)
+            };
+            if !matches {
+                bail!(
+                    "{} pins python {}, but {} requires python {}. Please change one of them",
+                    pin_path.display(),
+                    version,
+                    pyproject_path.display(),
+                    specifiers
+                );
+            }
+        }
+        Ok(Self {
+            pinned,
+            requires_python,
+        })
+    }
+
+    /// The specifiers an interpreter must match, `==x.y.*` for a pinned `x.y`
+    pub fn specifiers(&self) -> Option<VersionSpecifiers> {
+        if let Some((version, _)) = &self.pinned {
+            let specifier = if version.release.len() == 2 {
+                format!("=={}.*", version)
+            } else {
+                format!("=={}", version)
+            };
+            // A version with a prefix operator is always valid
+            return Some(VersionSpecifiers::from_str(&specifier).unwrap());
+        }
+        self.requires_python
+            .as_ref()
+            .map(|(specifiers, _)| specifiers.clone())
+    }
+
+    /// The python version x.y to use: the pinned version, `default` if it matches
+    /// `requires-python` or otherwise the newest python we can download that matches
+    pub fn python_version(&self, default: (u8, u8)) -> anyhow::Result<(u8, u8)> {
+        if let Some((version, path)) = &self.pinned {
+            return release_minor(version, path);
+        }
+        let Some((specifiers, path)) = &self.requires_python else {
+            return Ok(default);
+        };
+        if allows_minor(specifiers, default) {
+            return Ok(default);
+        }
+        let python_version = managed_python_version(Some(specifiers)).with_context(|| {
+            format!(
+                "{} requires python {}, but neither the default python {}.{} nor any python \
+                we can download matches",
+                path.display(),
+                specifiers,
+                default.0,
+                default.1
+            )
+        })?;
+        debug!(
+            "Using python {}.{} for requires-python {}",
+            python_version.0, python_version.1, specifiers
+        );
+        Ok(python_version)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::interpreter::{
+        is_python_executable_name, managed_python_version, parse_py_list_paths,
+        parse_python_version_file, query_interpreter, select_interpreter, Interpreter,
+        ProjectPython, SysconfigPaths,
+    };
+    use crate::markers::marker_environment_from_json_str;
+    use fs_err as fs;
+    use indoc::indoc;
+    use pep440_rs::{Version, VersionSpecifiers};
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::str::FromStr;
+    use tempfile::TempDir;
+
+    fn interpreter(version: (u8, u8, u8)) -> Interpreter {
+        let python_version = format!("{}.{}", version.0, version.1);
+        let python_full_version = format!("{}.{}", python_version, version.2);
+        let markers = marker_environment_from_json_str(
+            &serde_json::json!({
+                "implementation_name": "cpython",
+                "implementation_version": python_full_version,
+                "os_name": "posix",
+                "platform_machine": "x86_64",
+                "platform_python_implementation": "CPython",
+                "platform_release": "",
+                "platform_system": "Linux",
+                "platform_version": "",
+                "python_full_version": python_full_version,
+                "python_version": python_version,
+                "sys_platform": "linux",
+            })
+            .to_string(),
+        );
+        Interpreter {
+            sys_executable: PathBuf::from(format!("/usr/bin/python{}", python_version)),
+            version,
+            implementation: "cpython".to_string(),
+            abiflags: String::new(),
+            soabi: None,
+            platform: "linux-x86_64".to_string(),
+            pointer_size: 64,
+            base_prefix: PathBuf::from("/usr"),
+            paths: SysconfigPaths {
+                purelib: PathBuf::from(format!("/usr/lib/python{}/site-packages", python_version)),
+                platlib: PathBuf::from(format!("/usr/lib/python{}/site-packages", python_version)),
+                scripts: PathBuf::from("/usr/bin"),
+                data: PathBuf::from("/usr"),
+                include: PathBuf::from(format!("/usr/include/python{}", python_version)),
+            },
+            markers,
+        }
+    }
+
+    #[test]
+    fn test_python_executable_names() {
+        for name in [
+            "python",
+            "python3",
+            "python3.12",
+            "python3.13t",
+            "python3.exe",
+        ] {
+            assert!(is_python_executable_name(name), "{}", name);
+        }
+        for name in ["python2", "python3-config", "python3.12-config", "pythonw"] {
+            assert!(!is_python_executable_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_free_threaded() {
+        let mut python = interpreter((3, 13, 0));
+        assert!(!python.is_free_threaded());
+        python.soabi = Some("cpython-313-x86_64-linux-gnu".to_string());
+        assert!(!python.is_free_threaded());
+        python.soabi = Some("cpython-313t-x86_64-linux-gnu".to_string());
+        assert!(python.is_free_threaded());
+        python.soabi = Some("cp313t-win_amd64".to_string());
+        assert!(python.is_free_threaded());
+    }
+
+    #[test]
+    fn test_parse_py_list_paths() {
+        let output = indoc! {r"
+             -V:3.12 *        C:\Program Files\Python312\python.exe
+             -V:3.9           C:\Users\ferris\AppData\Local\Programs\Python\Python39\python.exe
+             -3.8-32          C:\Python38-32\python.exe
+        "};
+        assert_eq!(
+            parse_py_list_paths(output),
+            [
+                PathBuf::from(r"C:\Program Files\Python312\python.exe"),
+                PathBuf::from(r"C:\Users\ferris\AppData\Local\Programs\Python\Python39\python.exe"),
+                PathBuf::from(r"C:\Python38-32\python.exe"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_interpreter() {
+        let interpreters = [interpreter((3, 8, 18)), interpreter((3, 11, 4))];
+        let selected = select_interpreter(interpreters.clone(), None).unwrap();
+        assert_eq!(selected.version, (3, 8, 18));
+        let requires_python = VersionSpecifiers::from_str(">=3.9").unwrap();
+        let selected = select_interpreter(interpreters.clone(), Some(&requires_python)).unwrap();
+        assert_eq!(selected.python_version(), (3, 11));
+        let requires_python = VersionSpecifiers::from_str(">=3.12").unwrap();
+        assert!(select_interpreter(interpreters, Some(&requires_python)).is_none());
+    }
+
+    /// The venv shares the executable with its base python, but not the paths
+    #[test]
+    #[cfg(unix)]
+    fn test_query_interpreter() {
+        let python = query_interpreter(Path::new("python3")).unwrap();
+        assert_eq!(
+            python.markers.python_version.string,
+            format!("{}.{}", python.version.0, python.version.1)
+        );
+        assert!(python.paths.purelib.is_absolute());
+
+        let temp_dir = TempDir::new().unwrap();
+        let status = Command::new(&python.sys_executable)
+            .args(["-m", "venv", "--without-pip"])
+            .arg(temp_dir.path().join(".venv"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let venv_python = query_interpreter(&temp_dir.path().join(".venv/bin/python")).unwrap();
+        assert_eq!(venv_python.version, python.version);
+        assert_eq!(venv_python.base_prefix, python.base_prefix);
+        assert_ne!(venv_python.paths, python.paths);
+        assert!(venv_python
+            .paths
+            .purelib
+            .starts_with(temp_dir.path().canonicalize().unwrap()));
+    }
+
+    #[test]
+    fn test_managed_python_version() {
+        assert_eq!(managed_python_version(None), Some((3, 12)));
+        let requires_python = VersionSpecifiers::from_str(">=3.9,<3.11").unwrap();
+        assert_eq!(
+            managed_python_version(Some(&requires_python)),
+            Some((3, 10))
+        );
+        let requires_python = VersionSpecifiers::from_str("<3.9.5").unwrap();
+        assert_eq!(managed_python_version(Some(&requires_python)), Some((3, 9)));
+        let requires_python = VersionSpecifiers::from_str("<3.8").unwrap();
+        assert_eq!(managed_python_version(Some(&requires_python)), None);
+    }
+
+    #[test]
+    fn test_parse_python_version_file() {
+        let contents = "# pinned for the ci\n\n3.11.4\n3.10\n";
+        assert_eq!(
+            parse_python_version_file(contents).unwrap(),
+            Some(Version::from_str("3.11.4").unwrap())
+        );
+        assert_eq!(parse_python_version_file("system\n").unwrap(), None);
+        assert_eq!(parse_python_version_file("").unwrap(), None);
+        let err = parse_python_version_file("pypy3.10-7.3.12\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unsupported python version `pypy3.10-7.3.12`, only cpython versions such as 3.11 or \
+            3.11.4 are supported"
+        );
+    }
+
+    #[test]
+    fn test_project_python() {
+        let temp_dir = TempDir::new().unwrap();
+        let project = temp_dir.path().join("project");
+        let package = project.join("src").join("package");
+        fs::create_dir_all(&package).unwrap();
+        fs::write(
+            project.join("pyproject.toml"),
+            "[project]\nname = \"project\"\nrequires-python = \">=3.10\"\n",
+        )
+        .unwrap();
+
+        // requires-python picks the default if it matches, otherwise the newest download
+        let project_python = ProjectPython::find(&package).unwrap();
+        assert_eq!(project_python.python_version((3, 11)).unwrap(), (3, 11));
+        let specifiers = project_python.specifiers().unwrap();
+        assert_eq!(
+            project_python.python_version((3, 8)).unwrap(),
+            managed_python_version(Some(&specifiers)).unwrap()
+        );
+        assert_eq!(specifiers.to_string(), ">=3.10");
+
+        // A .python-version outside of the project doesn't apply
+        fs::write(temp_dir.path().join(".python-version"), "3.12\n").unwrap();
+        let project_python = ProjectPython::find(&package).unwrap();
+        assert_eq!(project_python.pinned, None);
+
+        // The closest .python-version wins
+        fs::write(project.join(".python-version"), "3.10\n").unwrap();
+        let project_python = ProjectPython::find(&package).unwrap();
+        assert_eq!(project_python.python_version((3, 8)).unwrap(), (3, 10));
+        let specifiers = project_python.specifiers().unwrap();
+        assert!(specifiers.contains(&Version::from_str("3.10.12").unwrap()));
+        assert!(!specifiers.contains(&Version::from_str("3.11.0").unwrap()));
+
+        fs::write(project.join(".python-version"), "3.9.18\n").unwrap();
+        let err = ProjectPython::find(&package).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} pins python 3.9.18, but {} requires python >=3.10. Please change one of them",
+                project.join(".python-version").display(),
+                project.join("pyproject.toml").display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_project_python_poetry() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(
+            temp_dir.path().join("pyproject.toml"),
+            "[tool.poetry.dependencies]\npython = \"~3.9\"\n",
+        )
+        .unwrap();
+        let project_python = ProjectPython::find(temp_dir.path()).unwrap();
+        assert_eq!(project_python.python_version((3, 8)).unwrap(), (3, 9));
+
+        fs::write(
+            temp_dir.path().join("pyproject.toml"),
+            "[project]\nname = \"foo\"\nrequires-python = \"<3.8\"\n",
+        )
+        .unwrap();
+        let err = ProjectPython::find(temp_dir.path())
+            .unwrap()
+            .python_version((3, 8))
+            .unwrap_err();
+        assert!(err.to_string().ends_with(
+            "requires python <3.8, but neither the default python 3.8 nor any python we can \
+            download matches"
+        ));
+    }
+}
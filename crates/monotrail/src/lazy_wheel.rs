@@ -0,0 +1,344 @@
+//! Reads the `METADATA` of a remote wheel without downloading it, for indexes that don't have
+//! PEP 658 metadata files.
+//!
+//! Like pip's `lazy_wheel`, we present the wheel as a seekable file backed by range requests. The
+//! zip central directory is at the end of the file, so we first fetch the last chunk with the end
+//! of central directory record, then the whole central directory in one request and then only
+//! the chunk with the `.dist-info/METADATA` entry. Resolving huge wheels such as torch or
+//! tensorflow that way only takes a few MB instead of GBs. Servers that don't support range requests
+//! send the whole file, which is slower but works the same.
+
+use crate::index::IndexFile;
+use anyhow::{bail, Context};
+use fs_err::File;
+use install_wheel_rs::{read_wheel_metadata, WheelFilename};
+use monotrail_utils::transport::Transport;
+use std::collections::BTreeMap;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::str::FromStr;
+use tracing::debug;
+use url::Url;
+
+/// Reads smaller than this are extended, so zip parsing doesn't make a request per header
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// A remote file as a seekable reader, downloading the chunks we read with range requests
+pub(crate) struct RangeReader<'a> {
+    transport: &'a dyn Transport,
+    url: String,
+    chunk_size: u64,
+    len: u64,
+    position: u64,
+    /// Start offset -> downloaded bytes, never overlapping
+    chunks: BTreeMap<u64, Vec<u8>>,
+}
+
+impl<'a> RangeReader<'a> {
+    /// Fetches the last `chunk_size` bytes, which tells us the length of the file
+    pub(crate) fn new(
+        transport: &'a dyn Transport,
+        url: &str,
+        chunk_size: u64,
+    ) -> anyhow::Result<Self> {
+        let mut reader = Self {
+            transport,
+            url: url.to_string(),
+            chunk_size,
+            len: 0,
+            position: 0,
+            chunks: BTreeMap::new(),
+        };
+        reader.fetch(&format!("bytes=-{}", chunk_size))?;
+        Ok(reader)
+    }
+
+    /// Requests a range and stores what we got. Returns `false` if the server sent nothing
+    fn fetch(&mut self, range: &str) -> anyhow::Result<bool> {
+        let response = self
+            .transport
+            .get_response(&self.url, &[("Range", range)])
+            .with_context(|| format!("Range request to {} failed", self.url))?;
+        let mut body = Vec::new();
+        let mut reader = response.body;
+        reader
+            .read_to_end(&mut body)
+            .with_context(|| format!("Failed to read {}", self.url))?;
+        if response.status != 206 {
+            debug!("{} doesn't support range requests", self.url);
+            self.len = body.len() as u64;
+            self.chunks = BTreeMap::from([(0, body)]);
+            return Ok(true);
+        }
+        // `bytes <start>-<end>/<len>`
+        let content_range = response
+            .headers
+            .get("content-range")
+            .map(String::as_str)
+            .unwrap_or_default();
+        let Some((start, len)) = content_range
+            .strip_prefix("bytes ")
+            .and_then(|range| range.split_once('/'))
+            .and_then(|(range, len)| Some((range.split_once('-')?.0.parse().ok()?, len)))
+        else {
+            bail!(
+                "Invalid Content-Range `{}` from {}",
+                content_range,
+                self.url
+            );
+        };
+        if let Ok(len) = len.parse() {
+            self.len = len;
+        }
+        if body.is_empty() {
+            return Ok(false);
+        }
+        self.chunks.insert(start, body);
+        Ok(true)
+    }
+
+    /// Downloads `start..end` with a single request, skipping what we already have
+    fn prefetch(&mut self, start: u64, end: u64) -> anyhow::Result<()> {
+        let end = end.min(self.len);
+        self.position = start;
+        while let Some(cached) = self.cached() {
+            self.position += cached.len() as u64;
+        }
+        // Chunks must not overlap
+        let next = self
+            .chunks
+            .range(self.position..)
+            .next()
+            .map_or(end, |(start, _)| *start);
+        let end = end.min(next);
+        if self.position < end {
+            self.fetch(&format!("bytes={}-{}", self.position, end - 1))?;
+        }
+        self.position = 0;
+        Ok(())
+    }
+
+    /// The start and end of the zip central directory according to the end of central directory
+    /// record in the last chunk, including zip64
+    ///
+    /// <https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT>, 4.3.14 to 4.3.16
+    fn central_directory(&self) -> Option<(u64, u64)> {
+        let (tail_start, tail) = self.chunks.iter().next_back()?;
+        let u32_at = |data: &[u8], at: usize| {
+            Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+        };
+        let u64_at = |data: &[u8], at: usize| {
+            Some(u64::from_le_bytes(data.get(at..at + 8)?.try_into().ok()?))
+        };
+        // The record is last, only followed by a comment
+        let eocd = tail
+            .windows(4)
+            .rposition(|window| window == b"PK\x05\x06")?;
+        let size = u32_at(tail, eocd + 12)?;
+        let offset = u32_at(tail, eocd + 16)?;
+        if size != u32::MAX && offset != u32::MAX {
+            return Some((offset.into(), u64::from(offset) + u64::from(size)));
+        }
+        // The zip64 locator is right before the record and points to the zip64 record
+        let locator = eocd.checked_sub(20)?;
+        if tail.get(locator..locator + 4)? != b"PK\x06\x07" {
+            return None;
+        }
+        let zip64 = usize::try_from(u64_at(tail, locator + 8)?.checked_sub(*tail_start)?).ok()?;
+        if tail.get(zip64..zip64 + 4)? != b"PK\x06\x06" {
+            return None;
+        }
+        let size = u64_at(tail, zip64 + 40)?;
+        let offset = u64_at(tail, zip64 + 48)?;
+        Some((offset, offset.checked_add(size)?))
+    }
+
+    /// The downloaded bytes from `position` on, if we have them
+    fn cached(&self) -> Option<&[u8]> {
+        let (start, chunk) = self.chunks.range(..=self.position).next_back()?;
+        chunk
+            .get((self.position - start) as usize..)
+            .filter(|rest| !rest.is_empty())
+    }
+}
+
+impl Read for RangeReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.cached().is_none() {
+            // Up to the next chunk we already have
+            let next = self
+                .chunks
+                .range(self.position..)
+                .next()
+                .map(|(start, _)| *start)
+                .unwrap_or(self.len);
+            let end = (self.position + self.chunk_size.max(buf.len() as u64))
+                .min(next)
+                .min(self.len);
+            let range = format!("bytes={}-{}", self.position, end - 1);
+            let fetched = self
+                .fetch(&range)
+                .map_err(|err| io::Error::other(format!("{:#}", err)))?;
+            if !fetched {
+                return Ok(0);
+            }
+        }
+        let Some(cached) = self.cached() else {
+            return Ok(0);
+        };
+        let read = cached.len().min(buf.len());
+        buf[..read].copy_from_slice(&cached[..read]);
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for RangeReader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        let Some(position) = position else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before the start of the file",
+            ));
+        };
+        self.position = position;
+        Ok(position)
+    }
+}
+
+/// The `METADATA` of a wheel from the index, read in place for `--find-links` directories and
+/// with range requests otherwise
+pub(crate) fn wheel_metadata(
+    transport: &dyn Transport,
+    file: &IndexFile,
+) -> anyhow::Result<String> {
+    let filename = WheelFilename::from_str(&file.filename)?;
+    let local = Url::parse(&file.url)
+        .ok()
+        .filter(|url| url.scheme() == "file")
+        .and_then(|url| url.to_file_path().ok());
+    let metadata = match local {
+        Some(path) => read_wheel_metadata(&filename, File::open(path)?),
+        None => {
+            debug!(
+                "Reading the metadata of {} with range requests",
+                file.filename
+            );
+            let mut reader = RangeReader::new(transport, &file.url, CHUNK_SIZE)?;
+            // Huge wheels such as torch have a central directory of several MB, which we get
+            // with one request instead of one per chunk
+            if let Some((start, end)) = reader.central_directory() {
+                reader.prefetch(start, end)?;
+            }
+            read_wheel_metadata(&filename, reader)
+        }
+    };
+    metadata.with_context(|| format!("Failed to read the metadata of {}", file.filename))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::index::IndexFile;
+    use crate::lazy_wheel::{wheel_metadata, RangeReader};
+    use fs_err as fs;
+    use install_wheel_rs::{read_wheel_metadata, WheelFilename};
+    use monotrail_utils::transport::MockTransport;
+    use std::collections::BTreeMap;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::str::FromStr;
+
+    const URL: &str = "https://files.example.org/mypy-0.782-py3-none-any.whl";
+
+    #[test]
+    fn test_range_reader() {
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let transport = MockTransport::default().with_response(URL, data.clone());
+        let mut reader = RangeReader::new(&transport, URL, 100).unwrap();
+        let mut buf = [0; 10];
+        reader.seek(SeekFrom::Start(950)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[950..960]);
+        reader.seek(SeekFrom::Start(10)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[10..20]);
+        // Stops at the chunk we already have
+        reader.seek(SeekFrom::Start(850)).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, data[850..]);
+        assert_eq!(
+            transport.requests(),
+            [
+                format!("{} bytes=-100", URL),
+                format!("{} bytes=10-109", URL),
+                format!("{} bytes=850-899", URL)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_zip64_central_directory() {
+        // Zip64 end of central directory record at 100 pointing to a central directory at 40..90
+        let mut data = vec![0; 100];
+        data.extend(b"PK\x06\x06");
+        data.extend([0; 36]);
+        data.extend(50u64.to_le_bytes());
+        data.extend(40u64.to_le_bytes());
+        // Locator and end of central directory record with the sizes moved to zip64
+        data.extend(b"PK\x06\x07");
+        data.extend([0; 4]);
+        data.extend(100u64.to_le_bytes());
+        data.extend(1u32.to_le_bytes());
+        data.extend(b"PK\x05\x06");
+        data.extend([0; 8]);
+        data.extend([0xff; 8]);
+        data.extend([0; 2]);
+        let transport = MockTransport::default().with_response(URL, data);
+        let reader = RangeReader::new(&transport, URL, 100).unwrap();
+        assert_eq!(reader.central_directory(), Some((40, 90)));
+    }
+
+    #[test]
+    fn test_remote_wheel_metadata() {
+        let wheel =
+            fs::read("../../test-data/pip-test-packages/mypy-0.782-py3-none-any.whl").unwrap();
+        let transport = MockTransport::default().with_response(URL, wheel.clone());
+        let file = IndexFile {
+            filename: "mypy-0.782-py3-none-any.whl".to_string(),
+            url: URL.to_string(),
+            hashes: BTreeMap::new(),
+            requires_python: None,
+            dist_info_metadata: false,
+            yanked: None,
+        };
+        let metadata = wheel_metadata(&transport, &file).unwrap();
+        assert!(
+            metadata.contains("Requires-Dist: typed-ast"),
+            "{}",
+            metadata
+        );
+        // The last chunk, the rest of the central directory (~140KB for mypy) and the metadata
+        // entry, not the whole 2.3MB
+        let requests = transport.requests();
+        assert_eq!(requests.len(), 3, "{:?}", requests);
+        assert_eq!(requests[0], format!("{} bytes=-65536", URL));
+        // Up to the last chunk we already have
+        let tail_start = wheel.len() - 65536;
+        assert!(
+            requests[1].ends_with(&format!("-{}", tail_start - 1)),
+            "{:?}",
+            requests
+        );
+        // Same as reading the whole file
+        let filename = WheelFilename::from_str(&file.filename).unwrap();
+        let full = read_wheel_metadata(&filename, std::io::Cursor::new(wheel)).unwrap();
+        assert_eq!(metadata, full);
+    }
+}
@@ -15,28 +15,93 @@
 //!    remains (which is in some case cleared up by the os) and we avoid half finished broken
 //!    installations.
 
-pub use cli::{run_cli, Cli};
+#[cfg(feature = "async")]
+pub use asynchronous::{AsyncRunner, Resolution};
+pub use cli::{run_cli, Cli, GlobalOptions};
+pub use config::Config;
+pub use environment::{Environment, EnvironmentPackage, ENVIRONMENT_MANIFEST};
 pub use inject_and_run::run_python_args;
+pub use install::InstalledPackage;
+pub use install_wheel_rs::{
+    set_hash_backend, HashBackend, HashPurpose, IncrementalHash, Sha256Backend, Xxh3Backend,
+};
+pub use interpreter::{
+    discover_interpreters, find_interpreter, find_or_install_interpreter, install_managed_python,
+    list_interpreters, query_interpreter, Interpreter,
+};
+pub use licenses::{package_licenses, LicensePolicy, LicenseViolation, PackageLicense};
 pub use monotrail_utils::parse_cpython_args::parse_major_minor;
+pub use output::{error_json, init_output, is_json_output, OutputFormat, OutputOptions};
+pub use poetry_integration::constraint::{pep440_to_poetry, poetry_to_pep440};
 use poetry_integration::read_dependencies::read_poetry_specs;
+pub use progress::{init_progress, ProgressFormat, ProgressOptions};
+pub use sbom::{installed_components, Provenance, SbomComponent};
+pub use site_injection::{injection_file, write_injection_file, InjectionFormat};
+pub use tree::{DependencyGraph, GraphEdge, GraphNode};
 #[doc(hidden)]
 pub use utils::assert_cli_error;
+pub use verbosity::{Subsystem, Verbosity};
+pub use workspace::{Workspace, WorkspaceMember, WORKSPACE_LOCK};
 
+#[cfg(feature = "async")]
+mod asynchronous;
+mod audit;
+mod build_cache;
+mod build_frontend;
+mod cache;
 mod cli;
+mod compile;
+mod completions;
+mod config;
+mod config_settings;
+mod diagnostics;
+mod download;
+mod environment;
+mod export;
+mod http_cache;
+mod import_check;
+mod index;
+mod init;
 mod inject_and_run;
+mod inline_script;
 mod install;
+mod interpreter;
+mod lazy_wheel;
+mod licenses;
+mod link_farm;
+mod lint;
+mod lockfile;
 mod markers;
 mod monotrail;
+mod outdated;
+mod output;
 mod package_index;
+mod path_dependency;
+mod pep621;
 mod poetry_integration;
 mod ppipx;
+mod progress;
+mod project_edit;
+mod project_lock;
+mod pylock;
 #[cfg(feature = "python_bindings")]
 mod python_bindings;
+mod resolver;
+mod sbom;
+mod site_injection;
+mod snapshot;
 mod source_distribution;
 mod spec;
+mod store;
+mod sync;
+#[cfg(test)]
+mod test_utils;
+mod tree;
 mod utils;
 mod venv_parser;
+mod verbosity;
 mod verify_installation;
+mod workspace;
 
 /// The python script to return the PEP 508 metadata as json string
 pub(crate) static PEP508_QUERY_ENV: &str = include_str!("get_pep508_env.py");
@@ -0,0 +1,374 @@
+//! `monotrail licenses`: The licenses of the installed packages, checked against the allow and
+//! deny lists of the `[licenses]` table in `monotrail.toml`:
+//!
+//! ```toml
+//! [licenses]
+//! allow = ["MIT", "BSD-3-Clause", "Apache-2.0"]
+//! deny = ["GPL-3.0", "AGPL-3.0"]
+//! # Internal packages without license metadata
+//! ignore = ["my-company-utils"]
+//! ```
+//!
+//! A policy entry also matches the variants of a license, so `GPL-3.0` denies `GPL-3.0-only`
+//! and `GPL-3.0-or-later`. With `License-Expression`, a package passes if one of the `OR`
+//! alternatives passes. Otherwise the license classifiers are mapped to SPDX identifiers where
+//! possible, and the package must pass with each of them and with the `License` field. Free-form
+//! text such as `MIT or GPL, see COPYING` can't be checked, so it fails any policy.
+
+use crate::sbom::SbomComponent;
+use install_wheel_rs::normalize_name;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt::{self, Display, Formatter};
+
+/// The `[licenses]` table in `monotrail.toml`
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct LicensePolicy {
+    /// If not empty, every package needs one of these licenses
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// No package may have only these licenses
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// Packages that aren't checked
+    #[serde(default)]
+    pub ignore: Vec<String>,
+}
+
+/// The licenses of an installed package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageLicense {
+    /// The name as in the metadata
+    pub name: String,
+    /// The installed version
+    pub version: String,
+    /// Each inner list is an `OR` alternative whose licenses all apply (`AND`). Empty if the
+    /// package has no license metadata
+    pub alternatives: Vec<Vec<String>>,
+}
+
+impl PackageLicense {
+    /// e.g. `MIT OR Apache-2.0` or `UNKNOWN`
+    pub fn license(&self) -> String {
+        if self.alternatives.is_empty() {
+            return "UNKNOWN".to_string();
+        }
+        self.alternatives
+            .iter()
+            .map(|alternative| alternative.join(" AND "))
+            .collect::<Vec<_>>()
+            .join(" OR ")
+    }
+}
+
+/// A package that doesn't pass the policy
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LicenseViolation {
+    /// The package name
+    pub name: String,
+    /// The installed version
+    pub version: String,
+    /// The licenses of the package
+    pub license: String,
+    /// Why it doesn't pass
+    pub reason: String,
+}
+
+impl Display for LicenseViolation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({}): {}",
+            self.name, self.version, self.license, self.reason
+        )
+    }
+}
+
+/// The SPDX identifier of the common `License :: OSI Approved :: ...` classifiers
+fn classifier_to_spdx(license: &str) -> &str {
+    match license {
+        "MIT License" => "MIT",
+        "MIT No Attribution License (MIT-0)" => "MIT-0",
+        "Apache Software License" => "Apache-2.0",
+        "BSD License" => "BSD",
+        "ISC License (ISCL)" => "ISC",
+        "Python Software Foundation License" => "PSF-2.0",
+        "Mozilla Public License 2.0 (MPL 2.0)" => "MPL-2.0",
+        "GNU General Public License v2 (GPLv2)" => "GPL-2.0",
+        "GNU General Public License v2 or later (GPLv2+)" => "GPL-2.0-or-later",
+        "GNU General Public License v3 (GPLv3)" => "GPL-3.0",
+        "GNU General Public License v3 or later (GPLv3+)" => "GPL-3.0-or-later",
+        "GNU Lesser General Public License v2 (LGPLv2)" => "LGPL-2.0",
+        "GNU Lesser General Public License v2 or later (LGPLv2+)" => "LGPL-2.0-or-later",
+        "GNU Lesser General Public License v3 (LGPLv3)" => "LGPL-3.0",
+        "GNU Lesser General Public License v3 or later (LGPLv3+)" => "LGPL-3.0-or-later",
+        "GNU Affero General Public License v3" => "AGPL-3.0",
+        "GNU Affero General Public License v3 or later (AGPLv3+)" => "AGPL-3.0-or-later",
+        "The Unlicense (Unlicense)" => "Unlicense",
+        "zlib/libpng License" => "Zlib",
+        other => other,
+    }
+}
+
+/// Splits a `License-Expression` into its `OR` alternatives of `AND`ed licenses. Exceptions
+/// (`WITH ...`) and parentheses are dropped, nested `OR`s inside `AND`s aren't expanded
+fn parse_expression(expression: &str) -> Vec<Vec<String>> {
+    let expression = expression.replace(['(', ')'], " ");
+    let words: Vec<&str> = expression.split_whitespace().collect();
+    let mut alternatives = vec![Vec::new()];
+    let mut words = words.iter();
+    while let Some(word) = words.next() {
+        match *word {
+            "OR" | "or" => alternatives.push(Vec::new()),
+            "AND" | "and" => {}
+            "WITH" | "with" => {
+                words.next();
+            }
+            license => alternatives.last_mut().unwrap().push(license.to_string()),
+        }
+    }
+    alternatives.retain(|alternative| !alternative.is_empty());
+    alternatives
+}
+
+/// Reads the license metadata of each package, see the module docs
+pub fn package_licenses(components: &[SbomComponent]) -> Vec<PackageLicense> {
+    components
+        .iter()
+        .map(|component| {
+            let alternatives = if let Some(expression) = &component.license_expression {
+                parse_expression(expression)
+            } else {
+                let mut licenses: Vec<String> = component
+                    .licenses
+                    .iter()
+                    .map(|license| classifier_to_spdx(license).to_string())
+                    .collect();
+                licenses.sort();
+                licenses.dedup();
+                // Every source applies, so they are a single alternative
+                if licenses.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![licenses]
+                }
+            };
+            PackageLicense {
+                name: component.name.clone(),
+                version: component.version.clone(),
+                alternatives,
+            }
+        })
+        .collect()
+}
+
+/// Whether this looks like an SPDX identifier such as `Apache-2.0` or `LicenseRef-Foo`, and not
+/// like free-form text
+fn is_identifier(license: &str) -> bool {
+    !license.is_empty()
+        && license
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || ".-+".contains(char))
+}
+
+/// `GPL-3.0` matches `gpl-3.0`, `GPL-3.0-only`, `GPL-3.0-or-later` and `GPL-3.0+`, but not
+/// `LGPL-3.0`
+fn matches(entry: &str, license: &str) -> bool {
+    let entry = entry.to_lowercase();
+    let license = license.to_lowercase();
+    let license = license.trim_end_matches('+');
+    license == entry || license.starts_with(&format!("{}-", entry))
+}
+
+impl LicensePolicy {
+    /// Whether there is anything to check
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Adds the lists of `other`, e.g. from the command line
+    pub fn extend(&mut self, other: LicensePolicy) {
+        self.allow.extend(other.allow);
+        self.deny.extend(other.deny);
+        self.ignore.extend(other.ignore);
+    }
+
+    /// Why the licenses of an alternative don't pass, if they don't
+    fn check_alternative(&self, alternative: &[String]) -> Option<String> {
+        for license in alternative {
+            if !is_identifier(license) {
+                return Some(format!("Can't check the free-form license {:?}", license));
+            }
+            if self.deny.iter().any(|entry| matches(entry, license)) {
+                return Some(format!("{} is denied", license));
+            }
+            if !self.allow.is_empty() && !self.allow.iter().any(|entry| matches(entry, license)) {
+                return Some(format!("{} is not allowed", license));
+            }
+        }
+        None
+    }
+
+    /// The packages that don't pass the policy, sorted by name
+    pub fn check(&self, packages: &[PackageLicense]) -> Vec<LicenseViolation> {
+        let ignore: Vec<String> = self
+            .ignore
+            .iter()
+            .map(|name| normalize_name(name))
+            .collect();
+        let mut violations = Vec::new();
+        for package in packages {
+            if ignore.contains(&normalize_name(&package.name)) {
+                continue;
+            }
+            let reason = if self.is_empty() {
+                None
+            } else if package.alternatives.is_empty() {
+                // We can't tell whether it's allowed
+                (!self.allow.is_empty()).then(|| "No license metadata".to_string())
+            } else {
+                let reasons: Vec<String> = package
+                    .alternatives
+                    .iter()
+                    .map(|alternative| self.check_alternative(alternative))
+                    .collect::<Option<_>>()
+                    .unwrap_or_default();
+                (!reasons.is_empty()).then(|| reasons.join(", "))
+            };
+            if let Some(reason) = reason {
+                violations.push(LicenseViolation {
+                    name: package.name.clone(),
+                    version: package.version.clone(),
+                    license: package.license(),
+                    reason,
+                });
+            }
+        }
+        violations.sort_by_key(|violation| violation.name.to_lowercase());
+        violations
+    }
+}
+
+/// The packages grouped by license, e.g. `MIT (2): attrs, tqdm`
+pub fn format_licenses(packages: &[PackageLicense]) -> String {
+    let mut by_license: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for package in packages {
+        by_license
+            .entry(package.license())
+            .or_default()
+            .push(&package.name);
+    }
+    let mut report = String::new();
+    for (license, mut names) in by_license {
+        names.sort_by_key(|name| name.to_lowercase());
+        report += &format!("{} ({}): {}\n", license, names.len(), names.join(", "));
+    }
+    report
+}
+
+/// `{"packages": [{"name", "version", "license"}], "violations": [...]}` for `--format json`
+pub fn licenses_json(
+    packages: &[PackageLicense],
+    violations: &[LicenseViolation],
+) -> serde_json::Value {
+    let packages: Vec<_> = packages
+        .iter()
+        .map(|package| {
+            json!({
+                "name": package.name,
+                "version": package.version,
+                "license": package.license(),
+            })
+        })
+        .collect();
+    json!({ "packages": packages, "violations": violations })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::licenses::{format_licenses, package_licenses, parse_expression, LicensePolicy};
+    use crate::sbom::SbomComponent;
+    use indoc::indoc;
+
+    fn component(name: &str, expression: Option<&str>, licenses: &[&str]) -> SbomComponent {
+        SbomComponent {
+            name: name.to_string(),
+            version: "1.0".to_string(),
+            license_expression: expression.map(ToString::to_string),
+            licenses: licenses.iter().map(ToString::to_string).collect(),
+            ..SbomComponent::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_expression() {
+        assert_eq!(
+            parse_expression("(MIT OR Apache-2.0) AND GPL-2.0-only WITH Classpath-exception-2.0"),
+            [vec!["MIT"], vec!["Apache-2.0", "GPL-2.0-only"]]
+        );
+    }
+
+    #[test]
+    fn test_license_policy() {
+        let packages = package_licenses(&[
+            component("attrs", Some("MIT"), &[]),
+            component("tqdm", None, &["MPLv2.0, MIT Licences", "MIT License"]),
+            component("classifiers", None, &["MIT License", "BSD License", "MIT"]),
+            component("dual", Some("GPL-3.0-or-later OR Apache-2.0"), &[]),
+            component("copyleft", None, &["GNU General Public License v3 (GPLv3)"]),
+            component("lesser", Some("LGPL-3.0-only"), &[]),
+            component("internal", None, &[]),
+        ]);
+        assert_eq!(
+            format_licenses(&packages),
+            indoc! {"
+                BSD AND MIT (1): classifiers
+                GPL-3.0 (1): copyleft
+                GPL-3.0-or-later OR Apache-2.0 (1): dual
+                LGPL-3.0-only (1): lesser
+                MIT (1): attrs
+                MIT AND MPLv2.0, MIT Licences (1): tqdm
+                UNKNOWN (1): internal
+            "}
+        );
+
+        let deny = LicensePolicy {
+            deny: vec!["GPL-3.0".to_string()],
+            ..LicensePolicy::default()
+        };
+        let violations: Vec<String> = deny
+            .check(&packages)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            violations,
+            [
+                "copyleft 1.0 (GPL-3.0): GPL-3.0 is denied",
+                "tqdm 1.0 (MIT AND MPLv2.0, MIT Licences): Can't check the free-form license \"MPLv2.0, MIT Licences\""
+            ]
+        );
+
+        let allow = LicensePolicy {
+            allow: vec!["MIT".to_string(), "apache-2.0".to_string()],
+            deny: vec!["GPL-3.0".to_string()],
+            ignore: vec!["Internal".to_string()],
+        };
+        let violations: Vec<String> = allow
+            .check(&packages)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            violations,
+            [
+                "classifiers 1.0 (BSD AND MIT): BSD is not allowed",
+                "copyleft 1.0 (GPL-3.0): GPL-3.0 is denied",
+                "lesser 1.0 (LGPL-3.0-only): LGPL-3.0-only is not allowed",
+                "tqdm 1.0 (MIT AND MPLv2.0, MIT Licences): Can't check the free-form license \"MPLv2.0, MIT Licences\""
+            ]
+        );
+    }
+}
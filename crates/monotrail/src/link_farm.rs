@@ -0,0 +1,465 @@
+//! `monotrail install --link`: Instead of copying the files of each package into the venv, we
+//! install the package once into the monotrail store and create symlinks from site-packages into
+//! the store, so a venv costs a few kilobytes and creating it takes milliseconds once all
+//! packages are in the store.
+//!
+//! Directories are created as real directories and only the files are symlinks, so python writes
+//! `__pycache__` into the venv instead of the shared store, and namespace packages that several
+//! distributions share just get the files of each. The `.dist-info` directory is copied, since tools
+//! like pip write to it, and its RECORD lists the symlinks instead of the files in the store, so
+//! uninstalling removes the links and never touches the store. Scripts with the generic monotrail
+//! shebang are copied with the venv python as shebang instead.
+
+use crate::install::InstalledPackage;
+use anyhow::Context;
+use fs_err as fs;
+use install_wheel_rs::{
+    installed_dist_infos, normalize_name, read_record_file, record_hash, relative_to,
+    remove_distribution, write_record_file, RecordEntry, SHEBANG_PYTHON,
+};
+use serde::Serialize;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// A problem with a package linked into a venv
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LinkProblem {
+    /// The path in the venv
+    pub path: PathBuf,
+    /// What's wrong with it
+    pub reason: String,
+}
+
+impl Display for LinkProblem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.reason)
+    }
+}
+
+#[cfg(unix)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    fs_err::os::unix::fs::symlink(original, link)
+}
+
+#[cfg(windows)]
+fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+    // Needs developer mode or admin rights
+    if original.is_dir() {
+        fs_err::os::windows::fs::symlink_dir(original, link)
+    } else {
+        fs_err::os::windows::fs::symlink_file(original, link)
+    }
+}
+
+/// The RECORD entry of a file we wrote ourselves
+fn hashed_entry(path: &Path, site_packages: &Path, data: &[u8]) -> anyhow::Result<RecordEntry> {
+    Ok(RecordEntry {
+        path: record_path(path, site_packages)?,
+        hash: Some(record_hash(data)),
+        size: Some(data.len()),
+    })
+}
+
+fn record_path(path: &Path, site_packages: &Path) -> anyhow::Result<String> {
+    let relative = relative_to(path, site_packages)?;
+    let relative = relative
+        .to_str()
+        .with_context(|| format!("Non-unicode path {}", relative.display()))?;
+    // RECORD uses forward slashes on all platforms
+    Ok(relative.replace('\\', "/"))
+}
+
+/// Creates the symlink `link` -> `original`, replacing whatever was there before
+fn place_link(
+    original: &Path,
+    link: &Path,
+    site_packages: &Path,
+    record: &mut Vec<RecordEntry>,
+) -> anyhow::Result<()> {
+    match fs::symlink_metadata(link) {
+        Ok(metadata) => {
+            if metadata.is_symlink() && fs::read_link(link)? == original {
+                debug!("{} is already linked", link.display());
+            } else {
+                warn!(
+                    "Replacing {}, which another package installed",
+                    link.display()
+                );
+                if metadata.is_dir() {
+                    fs::remove_dir_all(link)?;
+                } else {
+                    fs::remove_file(link)?;
+                }
+                symlink(original, link)?;
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => symlink(original, link)?,
+        Err(err) => return Err(err.into()),
+    }
+    record.push(RecordEntry {
+        path: record_path(link, site_packages)?,
+        hash: None,
+        size: None,
+    });
+    Ok(())
+}
+
+/// Links `original` to `link`, see the module docs
+fn link_tree(
+    original: &Path,
+    link: &Path,
+    site_packages: &Path,
+    record: &mut Vec<RecordEntry>,
+) -> anyhow::Result<()> {
+    if !original.is_dir() {
+        return place_link(original, link, site_packages, record);
+    }
+    if link.is_symlink() {
+        fs::remove_file(link)?;
+    }
+    fs::create_dir_all(link)?;
+    for entry in fs::read_dir(original)? {
+        let entry = entry?;
+        if entry.file_name() == "__pycache__" {
+            continue;
+        }
+        link_tree(
+            &entry.path(),
+            &link.join(entry.file_name()),
+            site_packages,
+            record,
+        )?;
+    }
+    Ok(())
+}
+
+/// Copies the `.dist-info` from the store, except for the RECORD and INSTALLER we write ourselves
+fn copy_dist_info(
+    original: &Path,
+    target: &Path,
+    site_packages: &Path,
+    record: &mut Vec<RecordEntry>,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(target)?;
+    for entry in fs::read_dir(original)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let path = target.join(&file_name);
+        if entry.file_type()?.is_dir() {
+            copy_dist_info(&entry.path(), &path, site_packages, record)?;
+        } else if target.parent() != Some(site_packages)
+            || !["RECORD", "INSTALLER"].contains(&file_name.to_string_lossy().as_ref())
+        {
+            let data = fs::read(entry.path())?;
+            fs::write(&path, &data)?;
+            record.push(hashed_entry(&path, site_packages, &data)?);
+        }
+    }
+    Ok(())
+}
+
+/// Scripts with the monotrail shebang get the venv python, the others are linked
+fn link_scripts(
+    store_scripts: &Path,
+    venv_scripts: &Path,
+    shebang: &str,
+    site_packages: &Path,
+    record: &mut Vec<RecordEntry>,
+) -> anyhow::Result<()> {
+    let entries = match fs::read_dir(store_scripts) {
+        Ok(entries) => entries.collect::<io::Result<Vec<_>>>()?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    fs::create_dir_all(venv_scripts)?;
+    for entry in entries {
+        let target = venv_scripts.join(entry.file_name());
+        let data = fs::read(entry.path())?;
+        let Some(rest) = data.strip_prefix(format!("{}\n", SHEBANG_PYTHON).as_bytes()) else {
+            place_link(&entry.path(), &target, site_packages, record)?;
+            continue;
+        };
+        let data = [format!("{}\n", shebang).as_bytes(), rest].concat();
+        if target.is_symlink() {
+            fs::remove_file(&target)?;
+        }
+        fs::write(&target, &data)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&target, std::fs::Permissions::from_mode(0o755))?;
+        }
+        record.push(hashed_entry(&target, site_packages, &data)?);
+    }
+    Ok(())
+}
+
+/// Links one package from the store into the venv
+fn link_package(
+    package_root: &Path,
+    store_site_packages: &Path,
+    site_packages: &Path,
+    venv_scripts: &Path,
+    shebang: &str,
+) -> anyhow::Result<()> {
+    let mut record = Vec::new();
+    let mut dist_info = None;
+    for entry in fs::read_dir(store_site_packages)? {
+        let entry = entry?;
+        let target = site_packages.join(entry.file_name());
+        if entry.file_name().to_string_lossy().ends_with(".dist-info") {
+            copy_dist_info(&entry.path(), &target, site_packages, &mut record)?;
+            dist_info = Some(target);
+        } else if entry.file_name() != "__pycache__" {
+            link_tree(&entry.path(), &target, site_packages, &mut record)?;
+        }
+    }
+    let dist_info =
+        dist_info.with_context(|| format!("No .dist-info in {}", store_site_packages.display()))?;
+    let store_scripts = if cfg!(windows) {
+        package_root.join("Scripts")
+    } else {
+        package_root.join("bin")
+    };
+    link_scripts(
+        &store_scripts,
+        venv_scripts,
+        shebang,
+        site_packages,
+        &mut record,
+    )?;
+
+    let installer = format!("{}\n", env!("CARGO_PKG_NAME"));
+    fs::write(dist_info.join("INSTALLER"), &installer)?;
+    record.push(hashed_entry(
+        &dist_info.join("INSTALLER"),
+        site_packages,
+        installer.as_bytes(),
+    )?);
+    record.push(RecordEntry {
+        path: record_path(&dist_info.join("RECORD"), site_packages)?,
+        hash: None,
+        size: None,
+    });
+    write_record_file(&dist_info.join("RECORD"), record)?;
+    Ok(())
+}
+
+/// Links the packages, which must be installed in `store`, into the venv. Other versions of the
+/// same packages in the venv are removed first. Returns the number of packages that weren't
+/// linked already
+pub(crate) fn link_packages(
+    store: &Path,
+    packages: &[InstalledPackage],
+    site_packages: &Path,
+    venv_scripts: &Path,
+    python: &Path,
+) -> anyhow::Result<usize> {
+    let shebang = format!("#!{}", python.display());
+    let installed = installed_dist_infos(site_packages)?;
+    let mut linked = 0;
+    for package in packages {
+        let name = normalize_name(&package.name);
+        let mut up_to_date = false;
+        for (_, version, dist_info) in installed.iter().filter(|(other, ..)| *other == name) {
+            if *version == package.python_version && site_packages.join(dist_info).is_dir() {
+                up_to_date = true;
+            } else {
+                debug!("Removing {} {}", name, version);
+                remove_distribution(site_packages, dist_info)?;
+            }
+        }
+        if up_to_date {
+            continue;
+        }
+        let package_root = package.monotrail_location(store.to_path_buf());
+        let store_site_packages = if cfg!(windows) {
+            package_root.join("Lib").join("site-packages")
+        } else {
+            package_root
+                .join("lib")
+                .join("python")
+                .join("site-packages")
+        };
+        link_package(
+            &package_root,
+            &store_site_packages,
+            site_packages,
+            venv_scripts,
+            &shebang,
+        )
+        .with_context(|| format!("Failed to link {} {}", package.name, package.python_version))?;
+        linked += 1;
+    }
+    Ok(linked)
+}
+
+/// Checks that the links in the venv match their RECORD and point to existing files in `store`
+pub fn check_links(site_packages: &Path, store: &Path) -> anyhow::Result<Vec<LinkProblem>> {
+    let mut problems = Vec::new();
+    for (_, _, dist_info) in installed_dist_infos(site_packages)? {
+        let record_file = site_packages.join(&dist_info).join("RECORD");
+        let record = match fs::read(&record_file) {
+            Ok(record) => record,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let record = read_record_file(&mut record.as_slice())
+            .with_context(|| format!("Invalid {}", record_file.display()))?;
+        for entry in record {
+            let path = site_packages.join(&entry.path);
+            let reason = match fs::symlink_metadata(&path) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Some("Missing".to_string()),
+                Err(err) => return Err(err.into()),
+                Ok(metadata) if metadata.is_symlink() => {
+                    let original = fs::read_link(&path)?;
+                    if !original.starts_with(store) {
+                        Some(format!(
+                            "Points outside the store to {}",
+                            original.display()
+                        ))
+                    } else if !original.exists() {
+                        Some(format!(
+                            "Points to {}, which was removed from the store",
+                            original.display()
+                        ))
+                    } else {
+                        None
+                    }
+                }
+                Ok(_) => None,
+            };
+            if let Some(reason) = reason {
+                problems.push(LinkProblem { path, reason });
+            }
+        }
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::install::InstalledPackage;
+    use crate::link_farm::{check_links, link_packages};
+    use crate::test_utils::install_files;
+    use fs_err as fs;
+    use install_wheel_rs::{read_record_file, SHEBANG_PYTHON};
+    use tempfile::TempDir;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_link_packages() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = temp_dir.path().join("store");
+        let venv = temp_dir.path().join(".venv");
+        let site_packages = venv.join("lib/python3.8/site-packages");
+        let scripts = venv.join("bin");
+        let python = scripts.join("python");
+        fs::create_dir_all(&site_packages).unwrap();
+        let mut packages = Vec::new();
+        for (name, module, version) in [
+            ("tqdm", "tqdm", "4.64.0"),
+            ("tqdm", "tqdm", "4.65.0"),
+            // Two distributions sharing the `zope` namespace package
+            ("zope-interface", "zope/interface", "6.0"),
+            ("zope-event", "zope/event", "5.0"),
+        ] {
+            let package = InstalledPackage {
+                name: name.to_string(),
+                python_version: version.to_string(),
+                unique_version: version.to_string(),
+                tag: "py3-none-any".to_string(),
+            };
+            // The package in the store as `install_wheel` would leave it
+            let dist_info = format!("{}-{}.dist-info", name.replace('-', "_"), version);
+            install_files(
+                &package
+                    .monotrail_location(store.clone())
+                    .join("lib/python/site-packages"),
+                &dist_info,
+                &[
+                    (&format!("{}/__init__.py", module), version),
+                    (&format!("{}/METADATA", dist_info), ""),
+                    (&format!("{}/licenses/LICENSE", dist_info), "MIT"),
+                    (
+                        &format!("../../../bin/{}", name),
+                        &format!("{}\nimport {}\n", SHEBANG_PYTHON, module),
+                    ),
+                ],
+            );
+            packages.push(package);
+        }
+        let [old, new, interface, event] = packages.try_into().unwrap();
+
+        let linked = link_packages(
+            &store,
+            &[old, interface, event.clone()],
+            &site_packages,
+            &scripts,
+            &python,
+        )
+        .unwrap();
+        assert_eq!(linked, 3);
+        for dir in ["tqdm", "zope", "zope/interface", "zope/event"] {
+            assert!(site_packages.join(dir).is_dir());
+            assert!(!site_packages.join(dir).is_symlink());
+        }
+        assert!(site_packages
+            .join("zope/interface/__init__.py")
+            .is_symlink());
+        assert!(site_packages.join("zope/event/__init__.py").is_symlink());
+        assert_eq!(
+            fs::read_to_string(site_packages.join("tqdm/__init__.py")).unwrap(),
+            "4.64.0"
+        );
+        assert_eq!(
+            fs::read_to_string(scripts.join("tqdm")).unwrap(),
+            format!("#!{}\nimport tqdm\n", python.display())
+        );
+        let record = fs::read(site_packages.join("tqdm-4.64.0.dist-info/RECORD")).unwrap();
+        let paths: Vec<String> = read_record_file(&mut record.as_slice())
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                "../../../bin/tqdm",
+                "tqdm-4.64.0.dist-info/INSTALLER",
+                "tqdm-4.64.0.dist-info/METADATA",
+                "tqdm-4.64.0.dist-info/RECORD",
+                "tqdm-4.64.0.dist-info/licenses/LICENSE",
+                "tqdm/__init__.py"
+            ]
+        );
+        assert_eq!(check_links(&site_packages, &store).unwrap(), []);
+
+        // Upgrading replaces the old links, linking again is a no-op
+        let linked =
+            link_packages(&store, &[new, event], &site_packages, &scripts, &python).unwrap();
+        assert_eq!(linked, 1);
+        assert!(!site_packages.join("tqdm-4.64.0.dist-info").exists());
+        assert_eq!(
+            fs::read_to_string(site_packages.join("tqdm/__init__.py")).unwrap(),
+            "4.65.0"
+        );
+        // The store is untouched, bytecode goes into the venv
+        assert!(store.join("tqdm/4.64.0").is_dir());
+        fs::create_dir(site_packages.join("tqdm/__pycache__")).unwrap();
+        assert!(!store
+            .join("tqdm/4.65.0/lib/python/site-packages/tqdm/__pycache__")
+            .exists());
+
+        fs::remove_dir_all(store.join("zope-event")).unwrap();
+        let problems: Vec<String> = check_links(&site_packages, &store)
+            .unwrap()
+            .iter()
+            .map(|problem| problem.reason.clone())
+            .collect();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].ends_with("which was removed from the store"));
+    }
+}
@@ -0,0 +1,491 @@
+//! `monotrail lint`: Checks requirements.txt files and the PEP 621 dependencies in a
+//! pyproject.toml for mistakes that pip silently accepts, such as unpinned requirements in a
+//! lockfile, a marker that is never true or a deprecated option. Duplicate extras in
+//! requirements.txt files can be fixed automatically, everything else needs a human decision.
+
+use anyhow::{bail, Context};
+use fs_err as fs;
+use monotrail_utils::{RequirementSource, RequirementsTxt};
+use pep440_rs::Operator;
+use pep508_rs::{
+    MarkerEnvironment, MarkerTree, MarkerValue, MarkerValueString, MarkerValueVersion, Requirement,
+    StringVersion, VersionOrUrl,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The python minor versions we consider when checking whether a marker can ever be true
+const PYTHON_MINORS: std::ops::RangeInclusive<u8> = 7..=13;
+
+/// The checks `monotrail lint` runs
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LintRule {
+    /// A requirement in a requirements.txt that isn't pinned with `==`
+    Unpinned,
+    /// Some requirements have hashes but this one doesn't, which makes pip fail
+    MissingHash,
+    /// A marker that is false on every platform and python version
+    ImpossibleMarker,
+    /// The same extra is requested twice
+    DuplicateExtra,
+    /// An option that pip deprecated
+    DeprecatedOption,
+}
+
+impl Display for LintRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            LintRule::Unpinned => "unpinned",
+            LintRule::MissingHash => "missing-hash",
+            LintRule::ImpossibleMarker => "impossible-marker",
+            LintRule::DuplicateExtra => "duplicate-extra",
+            LintRule::DeprecatedOption => "deprecated-option",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Replaces the bytes `start..end` of `file`, which must still be `original`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Fix {
+    pub start: usize,
+    pub end: usize,
+    pub original: String,
+    pub replacement: String,
+}
+
+/// One problem in a requirements file
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Finding {
+    pub rule: LintRule,
+    pub file: PathBuf,
+    /// 1-based line
+    pub line: usize,
+    pub message: String,
+    /// Set if the problem can be fixed automatically
+    pub fix: Option<Fix>,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}: [{}] {}",
+            self.file.display(),
+            self.line,
+            self.rule,
+            self.message
+        )
+    }
+}
+
+/// Lints a requirements.txt, a requirements.in or a pyproject.toml, depending on the filename
+pub fn lint_file(path: &Path) -> anyhow::Result<Vec<Finding>> {
+    if path.file_name().and_then(|name| name.to_str()) == Some("pyproject.toml") {
+        lint_pyproject(path)
+    } else {
+        lint_requirements_txt(path)
+    }
+}
+
+/// Lints a requirements file and all files it includes with `-r`
+fn lint_requirements_txt(path: &Path) -> anyhow::Result<Vec<Finding>> {
+    let working_dir = path.parent().unwrap_or(Path::new("."));
+    let requirements = RequirementsTxt::parse_lossless(path, working_dir)?;
+    // requirements.in files are the unpinned input for a lockfile
+    let is_input = path.extension().is_some_and(|extension| extension == "in");
+    // pip requires hashes for all requirements as soon as one has a hash
+    let hash_mode = requirements
+        .requirements
+        .iter()
+        .any(|entry| !entry.hashes.is_empty());
+
+    let mut findings = Vec::new();
+    for entry in &requirements.requirements {
+        let span = entry
+            .span
+            .as_ref()
+            .context("Missing span from lossless parsing")?;
+        let mut finding = |rule, message: String, fix| {
+            findings.push(Finding {
+                rule,
+                file: span.file.clone(),
+                line: span.line,
+                message,
+                fix,
+            })
+        };
+        let requirement = &entry.requirement;
+
+        if !is_input && entry.source == RequirementSource::Registry && !is_pinned(requirement) {
+            finding(
+                LintRule::Unpinned,
+                format!("{} is not pinned with `==`", requirement.name),
+                None,
+            );
+        }
+        if hash_mode && entry.hashes.is_empty() && entry.source != RequirementSource::Editable {
+            finding(
+                LintRule::MissingHash,
+                format!(
+                    "{} has no `--hash`, but other requirements do, so pip will refuse to install",
+                    requirement.name
+                ),
+                None,
+            );
+        }
+        if let Some(marker) = &requirement.marker {
+            // `extra` is always empty for requirements.txt entries
+            if marker_never_true(marker, &[]) {
+                finding(
+                    LintRule::ImpossibleMarker,
+                    format!(
+                        "The marker `{}` is never true, so {} is never installed",
+                        marker, requirement.name
+                    ),
+                    None,
+                );
+            }
+        }
+        if let Some(duplicates) = duplicate_extras(requirement) {
+            finding(
+                LintRule::DuplicateExtra,
+                format!(
+                    "{} requests the extras {} multiple times",
+                    requirement.name,
+                    duplicates.join(", ")
+                ),
+                dedup_extras_fix(&span.text, span.start),
+            );
+        }
+        if !entry.global_options.is_empty() {
+            finding(
+                LintRule::DeprecatedOption,
+                format!(
+                    "`--global-option` is deprecated by pip, use `--config-settings` for {} instead",
+                    requirement.name
+                ),
+                None,
+            );
+        }
+    }
+    Ok(findings)
+}
+
+#[derive(Deserialize, Debug)]
+struct LintPyprojectToml {
+    project: Option<LintProjectSection>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct LintProjectSection {
+    #[serde(default)]
+    dependencies: Vec<String>,
+    #[serde(default)]
+    optional_dependencies: BTreeMap<String, Vec<String>>,
+}
+
+/// Lints the `[project]` dependencies and optional dependencies of a pyproject.toml. Those are
+/// version ranges by design, so there is no pinning or hash check
+fn lint_pyproject(path: &Path) -> anyhow::Result<Vec<Finding>> {
+    let content = fs::read_to_string(path)?;
+    let pyproject_toml: LintPyprojectToml = toml::from_str(&content)
+        .with_context(|| format!("Invalid pyproject.toml at {}", path.display()))?;
+    let Some(project) = pyproject_toml.project else {
+        return Ok(Vec::new());
+    };
+    let groups = [(None, &project.dependencies)].into_iter().chain(
+        project
+            .optional_dependencies
+            .iter()
+            .map(|(extra, requirements)| (Some(extra.as_str()), requirements)),
+    );
+
+    let mut findings = Vec::new();
+    for (extra, requirements) in groups {
+        for requirement_str in requirements {
+            let requirement = Requirement::from_str(requirement_str)
+                .with_context(|| format!("Invalid requirement '{}'", requirement_str))?;
+            // toml offers no locations, so we search for the string
+            let line = content
+                .find(requirement_str.as_str())
+                .map_or(1, |offset| content[..offset].lines().count().max(1));
+            let mut finding = |rule, message| {
+                findings.push(Finding {
+                    rule,
+                    file: path.to_path_buf(),
+                    line,
+                    message,
+                    fix: None,
+                })
+            };
+            if let Some(marker) = &requirement.marker {
+                let extras: Vec<&str> = extra.into_iter().collect();
+                if marker_never_true(marker, &extras) {
+                    finding(
+                        LintRule::ImpossibleMarker,
+                        format!(
+                            "The marker `{}` is never true, so {} is never installed",
+                            marker, requirement.name
+                        ),
+                    );
+                }
+            }
+            if let Some(duplicates) = duplicate_extras(&requirement) {
+                finding(
+                    LintRule::DuplicateExtra,
+                    format!(
+                        "{} requests the extras {} multiple times",
+                        requirement.name,
+                        duplicates.join(", ")
+                    ),
+                );
+            }
+        }
+    }
+    Ok(findings)
+}
+
+/// Whether the requirement has exactly one `==` or `===` specifier
+fn is_pinned(requirement: &Requirement) -> bool {
+    match &requirement.version_or_url {
+        Some(VersionOrUrl::VersionSpecifier(specifiers)) => {
+            specifiers.len() == 1
+                && matches!(
+                    specifiers[0].operator(),
+                    Operator::Equal | Operator::ExactEqual
+                )
+        }
+        // A url is as pinned as it gets
+        Some(VersionOrUrl::Url(_)) => true,
+        None => false,
+    }
+}
+
+/// The extras that occur more than once, if any
+fn duplicate_extras(requirement: &Requirement) -> Option<Vec<String>> {
+    let mut seen = BTreeSet::new();
+    let duplicates: BTreeSet<String> = requirement
+        .extras
+        .iter()
+        .flatten()
+        .filter(|extra| !seen.insert(extra.as_str()))
+        .cloned()
+        .collect();
+    (!duplicates.is_empty()).then(|| duplicates.into_iter().collect())
+}
+
+/// Rewrites the first `[...]` of the entry with each extra once, keeping the order
+fn dedup_extras_fix(text: &str, offset: usize) -> Option<Fix> {
+    let open = text.find('[')?;
+    let close = open + text[open..].find(']')?;
+    let mut seen = BTreeSet::new();
+    let extras: Vec<&str> = text[open + 1..close]
+        .split(',')
+        .map(str::trim)
+        .filter(|extra| seen.insert(*extra))
+        .collect();
+    Some(Fix {
+        start: offset + open,
+        end: offset + close + 1,
+        original: text[open..=close].to_string(),
+        replacement: format!("[{}]", extras.join(",")),
+    })
+}
+
+/// Whether the marker only uses values we can enumerate. Markers on e.g. the exact python
+/// version or the kernel release could be true somewhere we don't check
+fn is_enumerable(marker: &MarkerTree) -> bool {
+    match marker {
+        MarkerTree::Expression(expression) => [&expression.l_value, &expression.r_value]
+            .into_iter()
+            .all(|value| match value {
+                MarkerValue::MarkerEnvVersion(version) => {
+                    *version == MarkerValueVersion::PythonVersion
+                }
+                MarkerValue::MarkerEnvString(string) => !matches!(
+                    string,
+                    MarkerValueString::PlatformRelease
+                        | MarkerValueString::PlatformVersion
+                        | MarkerValueString::PlatformVersionDeprecated
+                ),
+                MarkerValue::Extra | MarkerValue::QuotedString(_) => true,
+            }),
+        MarkerTree::And(markers) | MarkerTree::Or(markers) => markers.iter().all(is_enumerable),
+    }
+}
+
+/// The cpython and pypy environments on the common platforms for all supported python versions
+fn representative_environments() -> Vec<MarkerEnvironment> {
+    let platforms = [
+        (
+            "linux",
+            "Linux",
+            "posix",
+            &["x86_64", "aarch64", "i686"][..],
+        ),
+        ("darwin", "Darwin", "posix", &["x86_64", "arm64"][..]),
+        ("win32", "Windows", "nt", &["AMD64", "x86", "ARM64"][..]),
+    ];
+    let implementations = [("cpython", "CPython"), ("pypy", "PyPy")];
+    let mut environments = Vec::new();
+    for (sys_platform, platform_system, os_name, machines) in platforms {
+        for machine in machines {
+            for (implementation_name, platform_python_implementation) in implementations {
+                for minor in PYTHON_MINORS {
+                    let full_version = StringVersion::from_str(&format!("3.{}.0", minor))
+                        .expect("Invalid hardcoded version");
+                    environments.push(MarkerEnvironment {
+                        implementation_name: implementation_name.to_string(),
+                        implementation_version: full_version.clone(),
+                        os_name: os_name.to_string(),
+                        platform_machine: machine.to_string(),
+                        platform_python_implementation: platform_python_implementation.to_string(),
+                        platform_release: String::new(),
+                        platform_system: platform_system.to_string(),
+                        platform_version: String::new(),
+                        python_full_version: full_version,
+                        python_version: StringVersion::from_str(&format!("3.{}", minor))
+                            .expect("Invalid hardcoded version"),
+                        sys_platform: sys_platform.to_string(),
+                    });
+                }
+            }
+        }
+    }
+    environments
+}
+
+/// Whether the marker is false for every environment we know of
+fn marker_never_true(marker: &MarkerTree, extras: &[&str]) -> bool {
+    is_enumerable(marker)
+        && representative_environments()
+            .iter()
+            // The warnings are for users installing, not for the linter
+            .all(|env| !marker.evaluate_reporter(env, extras, &mut |_, _, _| {}))
+}
+
+/// Applies all fixes and returns the number of applied fixes. Fixes for text that changed since
+/// linting are skipped
+pub fn apply_fixes(findings: &[Finding]) -> anyhow::Result<usize> {
+    let mut by_file: BTreeMap<&Path, Vec<&Fix>> = BTreeMap::new();
+    for finding in findings {
+        if let Some(fix) = &finding.fix {
+            by_file.entry(&finding.file).or_default().push(fix);
+        }
+    }
+    let mut applied = 0;
+    for (file, mut fixes) in by_file {
+        let mut content = fs::read_to_string(file)?;
+        // Back to front so the earlier offsets stay valid
+        fixes.sort_by_key(|fix| std::cmp::Reverse(fix.start));
+        let mut last_start = usize::MAX;
+        for fix in fixes {
+            if fix.end > last_start || content.get(fix.start..fix.end) != Some(&fix.original) {
+                continue;
+            }
+            content.replace_range(fix.start..fix.end, &fix.replacement);
+            last_start = fix.start;
+            applied += 1;
+        }
+        fs::write(file, content)?;
+    }
+    Ok(applied)
+}
+
+/// The files to lint if none are given: requirements.txt and pyproject.toml in `dir`
+pub fn default_lint_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let files: Vec<PathBuf> = ["requirements.txt", "pyproject.toml"]
+        .into_iter()
+        .map(|name| dir.join(name))
+        .filter(|path| path.is_file())
+        .collect();
+    if files.is_empty() {
+        bail!(
+            "Neither requirements.txt nor pyproject.toml in {}, please pass the files to lint",
+            dir.display()
+        );
+    }
+    Ok(files)
+}
+
+/// `{"findings": [{"rule", "file", "line", "message", "fixable"}], "fixed": n}` for
+/// `--format json`
+pub fn findings_json(findings: &[Finding], fixed: usize) -> serde_json::Value {
+    let findings: Vec<_> = findings
+        .iter()
+        .map(|finding| {
+            json!({
+                "rule": finding.rule,
+                "file": finding.file,
+                "line": finding.line,
+                "message": finding.message,
+                "fixable": finding.fix.is_some(),
+            })
+        })
+        .collect();
+    json!({ "findings": findings, "fixed": fixed })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lint::{apply_fixes, lint_file, LintRule};
+    use fs_err as fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_lint_requirements_txt() {
+        let findings = lint_file(Path::new("../../test-data/lint/requirements.txt")).unwrap();
+        let findings: Vec<_> = findings
+            .iter()
+            .map(|finding| (finding.line, finding.rule, finding.fix.is_some()))
+            .collect();
+        assert_eq!(
+            findings,
+            [
+                (3, LintRule::Unpinned, false),
+                (4, LintRule::MissingHash, false),
+                (5, LintRule::ImpossibleMarker, false),
+                (6, LintRule::DuplicateExtra, true),
+                (7, LintRule::DeprecatedOption, false),
+                (8, LintRule::ImpossibleMarker, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lint_pyproject() {
+        let findings = lint_file(Path::new("../../test-data/lint/pyproject.toml")).unwrap();
+        let findings: Vec<_> = findings
+            .iter()
+            .map(|finding| (finding.line, finding.rule))
+            .collect();
+        assert_eq!(
+            findings,
+            [
+                (5, LintRule::DuplicateExtra),
+                (9, LintRule::ImpossibleMarker)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_apply_fixes() {
+        let temp_dir = tempdir().unwrap();
+        let requirements_txt = temp_dir.path().join("requirements.txt");
+        fs::copy("../../test-data/lint/requirements.txt", &requirements_txt).unwrap();
+        let findings = lint_file(&requirements_txt).unwrap();
+        assert_eq!(apply_fixes(&findings).unwrap(), 1);
+        let content = fs::read_to_string(&requirements_txt).unwrap();
+        assert!(content.contains("httpx[http2,cli]==0.24.1"));
+        let findings = lint_file(&requirements_txt).unwrap();
+        assert!(findings.iter().all(|finding| finding.fix.is_none()));
+    }
+}
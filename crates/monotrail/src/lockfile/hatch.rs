@@ -0,0 +1,100 @@
+//! Hatch has no lockfile of its own, but the hatch-pip-compile plugin pins each environment into
+//! a requirements.txt, `requirements.txt` for the default environment. Since those are already
+//! resolved, we use them as they are instead of resolving them again with poetry.
+
+use crate::markers::filter_requirements;
+use crate::spec::RequestedSpec;
+use anyhow::bail;
+use fs_err as fs;
+use monotrail_utils::RequirementsTxt;
+use pep440_rs::Operator;
+use pep508_rs::{MarkerEnvironment, VersionOrUrl};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// The header hatch-pip-compile writes into the lockfiles
+const HATCH_HEADER: &str = "hatch-pip-compile";
+
+/// Whether `path` is a requirements.txt written by hatch-pip-compile
+fn is_hatch_lock(path: &Path) -> bool {
+    let Ok(file) = fs::File::open(path) else {
+        return false;
+    };
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .take_while(|line| line.starts_with('#'))
+        .any(|line| line.contains(HATCH_HEADER))
+}
+
+/// The `requirements.txt` hatch-pip-compile writes for the default environment in `dir`, if any
+pub fn find_hatch_lock(dir: &Path) -> Option<PathBuf> {
+    let path = dir.join("requirements.txt");
+    is_hatch_lock(&path).then_some(path)
+}
+
+/// Reads the pinned requirements for the environment, returns the specs and the lockfile
+pub fn read_hatch_specs(
+    path: &Path,
+    pep508_env: &MarkerEnvironment,
+) -> anyhow::Result<(Vec<RequestedSpec>, String)> {
+    let lockfile = fs::read_to_string(path)?;
+    let working_dir = path.parent().unwrap_or(Path::new("."));
+    let requirements = RequirementsTxt::parse(path, working_dir)?;
+    let mut specs = Vec::new();
+    for entry in filter_requirements(requirements.requirements, pep508_env) {
+        let requirement = entry.requirement;
+        let version = match &requirement.version_or_url {
+            Some(VersionOrUrl::VersionSpecifier(specifiers))
+                if specifiers.len() == 1 && *specifiers[0].operator() == Operator::Equal =>
+            {
+                specifiers[0].version().to_string()
+            }
+            _ => bail!(
+                "Expected a pinned version (`==`) for {} in {}, is this a hatch-pip-compile lockfile?",
+                requirement.name,
+                path.display()
+            ),
+        };
+        specs.push(RequestedSpec {
+            requested: format!("{} {}", requirement.name, version),
+            name: requirement.name.clone(),
+            python_version: Some(version),
+            specifiers: None,
+            source: None,
+            extras: requirement.extras.clone().unwrap_or_default(),
+            file_path: None,
+            url: None,
+        });
+    }
+    Ok((specs, lockfile))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lockfile::hatch::{find_hatch_lock, is_hatch_lock, read_hatch_specs};
+    use crate::test_utils::test_pep508_env;
+    use std::path::Path;
+
+    #[test]
+    fn test_read_hatch_specs() {
+        let lockfile = find_hatch_lock(Path::new("../../test-data/hatch")).unwrap();
+        let (specs, _lockfile) = read_hatch_specs(&lockfile, &test_pep508_env()).unwrap();
+        let specs: Vec<_> = specs.iter().map(|spec| spec.requested.as_str()).collect();
+        // colorama is windows only
+        assert_eq!(
+            specs,
+            [
+                "click 8.1.7",
+                "markdown-it-py 3.0.0",
+                "mdurl 0.1.2",
+                "pygments 2.16.1",
+                "rich 13.5.2"
+            ]
+        );
+        // A requirements.txt from somebody else
+        assert!(!is_hatch_lock(Path::new(
+            "../../test-data/requirements-txt/basic.txt"
+        )));
+    }
+}
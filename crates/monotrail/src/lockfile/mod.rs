@@ -0,0 +1,4 @@
+//! Lockfiles of other tools, read into the same specs as poetry.lock
+
+pub mod hatch;
+pub mod pdm;
@@ -0,0 +1,309 @@
+//! Types for pdm.lock and the selection of the packages for one environment
+//!
+//! pdm.lock contains the packages for all platforms, so like with poetry.lock we walk the
+//! dependency tree from the pyproject.toml requirements and evaluate the markers on the way.
+//! Packages with extras have a separate entry with the dependencies of the extras.
+
+use crate::markers::evaluate_marker;
+use crate::pep621::read_pep621_project;
+use crate::spec::{RequestedSpec, SpecSource};
+use anyhow::{bail, Context};
+use fs_err as fs;
+use install_wheel_rs::{normalize_name, Script};
+use pep508_rs::{MarkerEnvironment, Requirement};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::path::Path;
+use std::str::FromStr;
+use tracing::debug;
+
+/// `pdm.lock`
+#[derive(Deserialize, Debug, Clone)]
+pub struct PdmLock {
+    #[serde(default)]
+    pub package: Vec<Package>,
+    pub metadata: Metadata,
+}
+
+impl PdmLock {
+    pub fn from_str(data: &str) -> anyhow::Result<Self> {
+        let lockfile: Self = toml::from_str(data)?;
+        if !lockfile.metadata.lock_version.starts_with("4.") {
+            bail!(
+                "Unsupported pdm.lock version {}",
+                lockfile.metadata.lock_version
+            )
+        }
+        Ok(lockfile)
+    }
+
+    /// Walks the dependency tree from `roots` and returns one spec per package
+    pub fn specs(
+        &self,
+        roots: &[Requirement],
+        pep508_env: &MarkerEnvironment,
+    ) -> anyhow::Result<Vec<RequestedSpec>> {
+        let mut packages: HashMap<String, Vec<&Package>> = HashMap::new();
+        for package in &self.package {
+            packages
+                .entry(normalize_name(&package.name))
+                .or_default()
+                .push(package);
+        }
+
+        // normalized name -> activated extras
+        let mut deps_with_extras: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        let mut queue: VecDeque<(String, BTreeSet<String>)> = roots
+            .iter()
+            .map(|root| {
+                (
+                    normalize_name(&root.name),
+                    root.extras.iter().flatten().cloned().collect(),
+                )
+            })
+            .collect();
+        while let Some((name, extras)) = queue.pop_front() {
+            let entries = packages.get(&name).with_context(|| {
+                format!("pdm.lock is outdated (run `pdm lock`): {} is missing", name)
+            })?;
+            let known_extras = deps_with_extras.get(&name);
+            if known_extras.is_some_and(|known| extras.is_subset(known)) {
+                continue;
+            }
+            let active_extras = deps_with_extras.entry(name.clone()).or_default();
+            active_extras.extend(extras);
+            let active_extras_vec: Vec<&str> = active_extras.iter().map(String::as_str).collect();
+            // Already visited entries are visited again, but they don't queue anything new
+            for entry in entries {
+                if !entry
+                    .extras
+                    .iter()
+                    .all(|extra| active_extras.contains(extra))
+                {
+                    continue;
+                }
+                for dependency in &entry.dependencies {
+                    let dependency = Requirement::from_str(dependency).with_context(|| {
+                        format!("Invalid dependency '{}' of {}", dependency, entry.name)
+                    })?;
+                    if let Some(marker) = &dependency.marker {
+                        if !evaluate_marker(marker, pep508_env, &active_extras_vec) {
+                            continue;
+                        }
+                    }
+                    let dependency_name = normalize_name(&dependency.name);
+                    // The extra entries depend on the package itself
+                    if dependency_name == name && dependency.extras.is_none() {
+                        continue;
+                    }
+                    queue.push_back((
+                        dependency_name,
+                        dependency.extras.into_iter().flatten().collect(),
+                    ));
+                }
+            }
+        }
+
+        let mut specs = Vec::new();
+        for (name, extras) in deps_with_extras {
+            let package = packages[&name][0];
+            if package.path.is_some() || package.url.is_some() {
+                bail!(
+                    "{} is a path or url dependency in pdm.lock, which is not supported",
+                    package.name
+                );
+            }
+            let source = match (&package.git, &package.revision) {
+                (Some(url), Some(revision)) => Some(SpecSource {
+                    source_type: "git".to_string(),
+                    url: url.clone(),
+                    reference: package
+                        .reference
+                        .clone()
+                        .unwrap_or_else(|| revision.clone()),
+                    resolved_reference: revision.clone(),
+                }),
+                (Some(url), None) => bail!("Missing revision for {} from {}", package.name, url),
+                _ => None,
+            };
+            specs.push(RequestedSpec {
+                requested: format!("{} {}", package.name, package.version),
+                name: package.name.clone(),
+                python_version: Some(package.version.clone()),
+                specifiers: None,
+                source,
+                extras: extras.into_iter().collect(),
+                file_path: None,
+                url: None,
+            });
+        }
+        Ok(specs)
+    }
+}
+
+/// `[[package]]`
+#[derive(Deserialize, Debug, Clone)]
+#[allow(dead_code)]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    /// This entry only carries the dependencies of these extras
+    #[serde(default)]
+    pub extras: Vec<String>,
+    /// PEP 508 requirements
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    pub git: Option<String>,
+    #[serde(rename = "ref")]
+    pub reference: Option<String>,
+    pub revision: Option<String>,
+    pub path: Option<String>,
+    pub url: Option<String>,
+}
+
+/// `[metadata]`
+#[derive(Deserialize, Debug, Clone)]
+#[allow(dead_code)]
+pub struct Metadata {
+    pub lock_version: String,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+/// `[tool.pdm.dev-dependencies]` of the pyproject.toml
+#[derive(Deserialize, Debug, Default)]
+struct PdmPyprojectToml {
+    tool: Option<ToolSection>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ToolSection {
+    pdm: Option<PdmSection>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+struct PdmSection {
+    #[serde(default)]
+    dev_dependencies: BTreeMap<String, Vec<String>>,
+}
+
+/// Reads pyproject.toml and pdm.lock from `dir` and returns the packages for the environment,
+/// the scripts and the lockfile. Like for poetry, the dev dependencies are included
+pub fn read_pdm_specs(
+    dir: &Path,
+    extras: &[String],
+    groups: &[String],
+    pep508_env: &MarkerEnvironment,
+) -> anyhow::Result<(Vec<RequestedSpec>, BTreeMap<String, Script>, String)> {
+    let lockfile = fs::read_to_string(dir.join("pdm.lock"))?;
+    let pdm_lock = PdmLock::from_str(&lockfile)
+        .with_context(|| format!("Invalid pdm.lock in {}", dir.display()))?;
+    let project = read_pep621_project(dir)?.with_context(|| {
+        format!(
+            "No [project] table in {}",
+            dir.join("pyproject.toml").display()
+        )
+    })?;
+    let mut roots = project.requirements(extras, groups, pep508_env)?;
+
+    let pyproject_toml: PdmPyprojectToml =
+        toml::from_str(&fs::read_to_string(dir.join("pyproject.toml"))?)?;
+    let dev_dependencies = pyproject_toml
+        .tool
+        .and_then(|tool| tool.pdm)
+        .unwrap_or_default()
+        .dev_dependencies;
+    for requirement in dev_dependencies.values().flatten() {
+        // Editable installs of local packages are out of scope
+        if requirement.starts_with("-e") {
+            debug!("Skipping editable dev dependency {}", requirement);
+            continue;
+        }
+        let requirement = Requirement::from_str(requirement)
+            .with_context(|| format!("Invalid dev dependency '{}'", requirement))?;
+        if let Some(marker) = &requirement.marker {
+            if !evaluate_marker(marker, pep508_env, &[]) {
+                continue;
+            }
+        }
+        roots.push(requirement);
+    }
+
+    let specs = pdm_lock.specs(&roots, pep508_env)?;
+    Ok((specs, project.scripts()?, lockfile))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::lockfile::pdm::read_pdm_specs;
+    use crate::test_utils::test_pep508_env;
+    use std::path::Path;
+
+    #[test]
+    fn test_read_pdm_specs() {
+        let (specs, scripts, _lockfile) = read_pdm_specs(
+            Path::new("../../test-data/pdm"),
+            &[],
+            &[],
+            &test_pep508_env(),
+        )
+        .unwrap();
+        let specs: Vec<_> = specs
+            .iter()
+            .map(|spec| {
+                (
+                    spec.requested.as_str(),
+                    spec.extras.join(","),
+                    spec.source
+                        .as_ref()
+                        .map(|source| source.resolved_reference.as_str()),
+                )
+            })
+            .collect();
+        // colorama is windows only, typing-extensions is only for python 3.7 and h2 and its
+        // dependencies are only for the http2 extra
+        assert_eq!(
+            specs,
+            [
+                ("anyio 3.7.1", "".to_string(), None),
+                ("certifi 2023.7.22", "".to_string(), None),
+                ("click 8.1.7", "".to_string(), None),
+                ("exceptiongroup 1.1.3", "".to_string(), None),
+                ("h11 0.14.0", "".to_string(), None),
+                ("httpcore 0.17.3", "".to_string(), None),
+                ("httpx 0.24.1", "".to_string(), None),
+                ("idna 3.4", "".to_string(), None),
+                ("iniconfig 2.0.0", "".to_string(), None),
+                (
+                    "mylib 0.1.0",
+                    "".to_string(),
+                    Some("b0bab0b0bab0b0bab0b0bab0b0bab0b0bab0b0ba")
+                ),
+                ("packaging 23.1", "".to_string(), None),
+                ("pluggy 1.3.0", "".to_string(), None),
+                ("pytest 7.4.2", "".to_string(), None),
+                ("sniffio 1.3.0", "".to_string(), None),
+                ("tomli 2.0.1", "".to_string(), None),
+            ]
+        );
+        assert_eq!(scripts.len(), 1);
+    }
+
+    #[test]
+    fn test_read_pdm_specs_extras() {
+        let (specs, _scripts, _lockfile) = read_pdm_specs(
+            Path::new("../../test-data/pdm"),
+            &["http2".to_string()],
+            &[],
+            &test_pep508_env(),
+        )
+        .unwrap();
+        let names: Vec<_> = specs.iter().map(|spec| spec.name.as_str()).collect();
+        assert!(names.contains(&"h2"));
+        assert!(names.contains(&"hpack"));
+        assert!(names.contains(&"hyperframe"));
+        let httpx = specs.iter().find(|spec| spec.name == "httpx").unwrap();
+        assert_eq!(httpx.extras, ["http2"]);
+    }
+}
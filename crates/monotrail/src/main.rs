@@ -2,12 +2,37 @@
 
 use anyhow::Context;
 use clap::Parser;
-use monotrail::{run_cli, run_python_args, Cli};
+use monotrail::{
+    error_json, init_output, init_progress, is_json_output, run_cli, run_python_args, Cli, Config,
+    GlobalOptions, OutputOptions, ProgressOptions, Verbosity,
+};
 use monotrail_utils::parse_cpython_args::parse_major_minor;
 use std::env;
 use std::env::args;
+use std::io;
 use std::path::{Path, PathBuf};
 use tracing::debug;
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+
+/// The main cli, with the global options before the subcommand
+#[derive(Parser, Debug)]
+#[clap(version)]
+struct Args {
+    #[clap(flatten)]
+    verbosity: Verbosity,
+    #[clap(flatten)]
+    progress: ProgressOptions,
+    #[clap(flatten)]
+    options: GlobalOptions,
+    #[clap(flatten)]
+    output: OutputOptions,
+    #[clap(subcommand)]
+    command: Cli,
+}
 
 /// Checks under what name we're running and if it's python, shortcuts to running as python,
 /// otherwise does the normal cli run
@@ -25,6 +50,7 @@ fn run() -> anyhow::Result<Option<i32>> {
         .with_context(|| format!("First argument filename isn't utf-8: {:?}", filename))?
         .to_string();
     if let Some(version) = name.strip_prefix("python") {
+        init_tracing(Verbosity::default().targets());
         let root = env::var_os(format!(
             "{}_EXECVE_ROOT",
             env!("CARGO_PKG_NAME").to_uppercase()
@@ -54,28 +80,65 @@ fn run() -> anyhow::Result<Option<i32>> {
             python_version,
             root.as_deref(),
             &[],
+            &[],
+            &[],
         )?))
     } else {
+        let cli_args = Args::parse();
+        init_output(&cli_args.output);
+        init_tracing(cli_args.verbosity.targets());
+        init_progress(&cli_args.progress)?;
+        // The command line options override the config files
+        Config::load(&env::current_dir()?)?.apply()?;
+        cli_args.options.apply()?;
         debug!("START: monotrail as '{}': `{}`", name, args.join(" "));
-        run_cli(Cli::parse(), None)
+        run_cli(cli_args.command, None)
     }
 }
 
-fn main() {
-    // Good enough for now
+/// `RUST_LOG` takes precedence over `targets` and shows more details for each message
+fn init_tracing(targets: Targets) {
+    // With `--format json`, stdout is reserved for the result
+    let writer = if is_json_output() {
+        BoxMakeWriter::new(io::stderr)
+    } else {
+        BoxMakeWriter::new(io::stdout)
+    };
     if env::var_os("RUST_LOG").is_some() {
-        tracing_subscriber::fmt::init();
+        // Unlike `-v`, this may also enable the debug output of dependencies
+        let filter = EnvFilter::builder()
+            .with_default_directive(LevelFilter::INFO.into())
+            .from_env_lossy();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(writer)
+            .with_env_filter(filter);
+        // Show the durations of the instrumented spans
+        #[cfg(feature = "instrument")]
+        let subscriber =
+            subscriber.with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE);
+        subscriber.init();
     } else {
         let format = tracing_subscriber::fmt::format()
             .with_level(false)
             .with_target(false)
             .without_time()
             .compact();
-        tracing_subscriber::fmt().event_format(format).init();
+        tracing_subscriber::fmt()
+            .event_format(format)
+            .with_writer(writer)
+            .with_max_level(tracing::Level::TRACE)
+            .finish()
+            .with(targets)
+            .init();
     }
+}
 
+fn main() {
     match run() {
         Err(e) => {
+            if is_json_output() {
+                println!("{:#}", error_json(&e));
+            }
             eprintln!("💥 {} failed", env!("CARGO_PKG_NAME"));
             for cause in e.chain().collect::<Vec<_>>().iter() {
                 eprintln!("  Caused by: {}", cause);
@@ -1,8 +1,12 @@
-use crate::PEP508_QUERY_ENV;
-use pep508_rs::MarkerEnvironment;
-use std::io;
+//! PEP 508 markers: Getting the environment of the target interpreter and evaluating markers of
+//! requirements and lockfile packages against it
+
+use crate::diagnostics::{report, WarningClass};
+use crate::interpreter::query_interpreter;
+use monotrail_utils::RequirementEntry;
+use pep508_rs::{MarkerEnvironment, MarkerTree};
 use std::path::Path;
-use std::process::{Command, Stdio};
+use tracing::debug;
 
 /// If we launch from python, we can call the python code from python with no overhead, but
 /// still need to parse into Self here
@@ -11,49 +15,43 @@ pub fn marker_environment_from_json_str(pep508_env_data: &str) -> MarkerEnvironm
     serde_json::from_str(pep508_env_data).unwrap()
 }
 
-/// Runs python to get the actual PEP 508 values
-///
-/// To be eventually replaced by something like the maturin solution where we construct this
-/// is in rust
-pub fn marker_environment_from_python(python: &Path) -> MarkerEnvironment {
-    let out = Command::new(python)
-        .args(["-S"])
-        .env("PYTHONIOENCODING", "utf-8")
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .and_then(|mut child| {
-            use std::io::Write;
-            // We only have the module definition in that file (because we also want to load
-            // it as a module in the python bindings), so we need to append the actual call
-            let pep508_query_script = format!("{}\nprint(get_pep508_env())", PEP508_QUERY_ENV);
-            child
-                .stdin
-                .as_mut()
-                .expect("piped stdin")
-                .write_all(pep508_query_script.as_bytes())?;
-            child.wait_with_output()
-        });
+/// The PEP 508 values of `python`, from the cached [query_interpreter], so we start python at
+/// most once per interpreter
+pub fn marker_environment_from_python(python: &Path) -> anyhow::Result<MarkerEnvironment> {
+    Ok(query_interpreter(python)?.markers)
+}
+
+/// Whether the marker applies to the target environment with the given extras. Expressions that
+/// can't be evaluated properly, such as comparisons with invalid versions, are reported as
+/// [WarningClass::MarkerIgnored]
+pub fn evaluate_marker(marker: &MarkerTree, env: &MarkerEnvironment, extras: &[&str]) -> bool {
+    marker.evaluate_reporter(env, extras, &mut |_kind, message, expression| {
+        report(
+            WarningClass::MarkerIgnored,
+            format!("{} (in `{}`)", message, expression),
+        );
+    })
+}
 
-    let returned = match out {
-        Err(err) => {
-            if err.kind() == io::ErrorKind::NotFound {
-                panic!(
-                    "Could not find any interpreter at {}, \
-                        are you sure you have Python installed on your PATH?",
-                    python.display()
-                )
-            } else {
-                panic!(
-                    "Failed to run the Python interpreter at {}: {}",
-                    python.display(),
-                    err
-                )
+/// Removes the requirements whose markers don't apply to the target environment
+pub fn filter_requirements(
+    requirements: Vec<RequirementEntry>,
+    env: &MarkerEnvironment,
+) -> Vec<RequirementEntry> {
+    requirements
+        .into_iter()
+        .filter(|entry| {
+            let Some(marker) = &entry.requirement.marker else {
+                return true;
+            };
+            let applies = evaluate_marker(marker, env, &[]);
+            if !applies {
+                debug!(
+                    "Skipping {}, its marker doesn't apply to the target environment",
+                    entry.requirement
+                );
             }
-        }
-        Ok(ok) if !ok.status.success() => panic!("Python script failed"),
-        Ok(ok) => ok.stdout,
-    };
-    serde_json::from_slice(&returned).unwrap()
+            applies
+        })
+        .collect()
 }
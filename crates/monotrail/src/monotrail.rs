@@ -1,30 +1,39 @@
+use crate::config::{default_python_version, project_python_version};
+use crate::http_cache::HttpCache;
 use crate::inject_and_run::{
     inject_and_run_python, prepare_execve_environment, run_python_args_finder_data,
 };
 use crate::install::{install_all, InstalledPackage};
+use crate::lockfile::hatch::{find_hatch_lock, read_hatch_specs};
+use crate::lockfile::pdm::read_pdm_specs;
 use crate::markers::marker_environment_from_python;
-use crate::poetry_integration::lock::poetry_resolve;
+use crate::pep621::read_pep621_project;
+use crate::poetry_integration::lock::{poetry_resolve, poetry_resolve_roots};
 use crate::poetry_integration::read_dependencies::{
-    poetry_spec_from_dir, read_requirements_for_poetry, specs_from_git,
+    poetry_spec_from_dir, read_requirements_for_poetry, requirements_for_poetry, specs_from_git,
 };
-use crate::spec::RequestedSpec;
-use crate::utils::{cache_dir, get_dir_content};
-use crate::{read_poetry_specs, DEFAULT_PYTHON_VERSION};
+use crate::read_poetry_specs;
+use crate::spec::{same_version, RequestedSpec};
+use crate::store::{record_usage, usage_dir};
+use crate::utils::{cache_dir, foreign_owner, get_dir_content, is_writable_dir};
+use crate::workspace::{Workspace, WORKSPACE_LOCK};
 use anyhow::{bail, Context};
 use fs_err as fs;
 use fs_err::{DirEntry, File};
-use install_wheel_rs::{CompatibleTags, InstallLocation, Script, SHEBANG_PYTHON};
+use install_wheel_rs::{CompatibleTags, InstallLocation, NoProgress, Script, SHEBANG_PYTHON};
 use monotrail_utils::parse_cpython_args::determine_python_version;
 use monotrail_utils::standalone_python::provision_python;
+use monotrail_utils::transport::UreqTransport;
 use pep508_rs::MarkerEnvironment;
 use serde::Serialize;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env::{current_dir, current_exe};
 #[cfg(unix)]
 use std::ffi::CString;
 use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::SystemTime;
 use std::{env, io};
 use tempfile::TempDir;
 use tracing::{debug, info, trace, warn};
@@ -35,6 +44,12 @@ enum LockfileType {
     PoetryLock,
     /// pyproject.toml, we assume it's one with poetry config
     PyprojectToml,
+    /// pyproject.toml with a PEP 621 `[project]` table and no poetry config
+    Pep621,
+    /// pdm.lock, with the roots in the `[project]` table of the pyproject.toml
+    PdmLock,
+    /// requirements.txt pinned by hatch-pip-compile, which we don't need to resolve again
+    HatchLock,
     /// requirements.txt, we parse a subset of it
     RequirementsTxt,
 }
@@ -115,13 +130,21 @@ pub fn monotrail_root() -> anyhow::Result<PathBuf> {
     }
 }
 
-/// Walks the directory tree up to find a pyproject.toml or a requirements.txt and returns
-/// the dir (poetry) or the file (requirements.txt)
+/// Walks the directory tree up to find a lockfile, a pyproject.toml or a requirements.txt and
+/// returns the dir (poetry, pdm and PEP 621) or the file (requirements.txt)
 fn find_dep_file(dir_running: &Path) -> Option<(PathBuf, LockfileType)> {
     for ancestor in dir_running.ancestors() {
         if ancestor.join("poetry.lock").exists() {
             return Some((ancestor.to_path_buf(), LockfileType::PoetryLock));
+        } else if ancestor.join("pdm.lock").exists() {
+            return Some((ancestor.to_path_buf(), LockfileType::PdmLock));
+        } else if let Some(hatch_lock) = find_hatch_lock(ancestor) {
+            return Some((hatch_lock, LockfileType::HatchLock));
         } else if ancestor.join("pyproject.toml").exists() {
+            // An invalid pyproject.toml fails later with a proper error message
+            if read_pep621_project(ancestor).is_ok_and(|project| project.is_some()) {
+                return Some((ancestor.to_path_buf(), LockfileType::Pep621));
+            }
             return Some((ancestor.to_path_buf(), LockfileType::PyprojectToml));
         } else if ancestor.join("requirements.txt").exists() {
             return Some((
@@ -183,6 +206,36 @@ pub fn list_installed(
     Ok(compatible)
 }
 
+/// Another user may own the directory of a package in a shared monotrail root. We check this for
+/// all packages before installing anything, so we don't fail halfway through
+pub(crate) fn check_installable(
+    monotrail_root: &Path,
+    specs: &[RequestedSpec],
+) -> anyhow::Result<()> {
+    for spec in specs {
+        let package_dir = monotrail_root.join(spec.normalized_name());
+        let dir = if package_dir.is_dir() {
+            package_dir
+        } else {
+            monotrail_root.to_path_buf()
+        };
+        if !is_writable_dir(&dir) {
+            let owner = foreign_owner(&dir)
+                .map(|uid| format!(" (owned by uid {})", uid))
+                .unwrap_or_default();
+            bail!(
+                "Can't install {} because {}{} is not writable. \
+                If the cache is shared with other users, set {}_CACHE_PER_USER=1",
+                spec.name,
+                dir.display(),
+                owner,
+                env!("CARGO_PKG_NAME").to_uppercase()
+            );
+        }
+    }
+    Ok(())
+}
+
 /// Splits the given spec set into installed and to-be-installed
 pub fn filter_installed_monotrail(
     specs: &[RequestedSpec],
@@ -203,7 +256,8 @@ pub fn filter_installed_monotrail(
         if let Some(unique_version) = unique_version {
             if let Some((name, installed_version, tag)) =
                 compatible.iter().find(|(name, installed_version, _tag)| {
-                    name == &spec.normalized_name() && installed_version == &unique_version
+                    name == &spec.normalized_name()
+                        && same_version(installed_version, &unique_version)
                 })
             {
                 installed.push(InstalledPackage {
@@ -219,19 +273,17 @@ pub fn filter_installed_monotrail(
                 not_installed.push(spec.clone());
             }
         } else {
-            // For now we just take any version there is
+            // For now we just take any version there is that matches the specifiers
             // This would take proper version resolution to make sense
-            if let Some((name, unique_version, _path)) = compatible
-                .iter()
-                .find(|(name, _version, _path)| name == &spec.normalized_name())
+            if let Some((name, unique_version, _path)) =
+                compatible.iter().find(|(name, version, _path)| {
+                    name == &spec.normalized_name() && spec.matches_version(version)
+                })
             {
                 installed.push(InstalledPackage {
                     // already normalized
                     name: name.clone(),
-                    python_version: spec
-                        .python_version
-                        .clone()
-                        .context("TODO: needs python version")?,
+                    python_version: unique_version.to_string(),
                     unique_version: unique_version.to_string(),
                     tag: "".to_string(),
                 });
@@ -269,6 +321,7 @@ pub fn install_missing(
 
     let (to_install_specs, installed_done) =
         filter_installed_monotrail(specs, Path::new(&monotrail_root), &compatible_tags)?;
+    check_installable(&monotrail_root, &to_install_specs)?;
 
     let mut installed = install_all(
         &to_install_specs,
@@ -277,6 +330,10 @@ pub fn install_missing(
         false,
         true,
         false,
+        false,
+        false,
+        &HttpCache::new(&UreqTransport)?,
+        &NoProgress,
     )?;
 
     installed.extend(installed_done);
@@ -364,6 +421,13 @@ pub fn spec_paths(
                         file_modules
                             .insert(stem.to_string(), (sprawl_package.clone(), entry.path()));
                     }
+                    // Legacy setuptools namespace packages (`namespace_packages`) install
+                    // `<name>-nspkg.pth`, which creates the namespace module from a single
+                    // site-packages. We already merge the namespace dirs of all packages like
+                    // PEP 420, so running them would hide the other packages' parts
+                    [.., "pth"] if filename.ends_with("-nspkg.pth") => {
+                        debug!("Skipping {}", entry.path().display());
+                    }
                     [.., "pth"] => pth_files.push(entry.path()),
                     _ => continue,
                 }
@@ -435,6 +499,7 @@ pub fn spec_paths(
 pub fn load_specs(
     script: Option<&Path>,
     extras: &[String],
+    groups: &[String],
     python_context: &PythonContext,
 ) -> anyhow::Result<(
     Vec<RequestedSpec>,
@@ -476,6 +541,21 @@ pub fn load_specs(
     };
     debug!("python project dir: {}", project_dir.display());
 
+    // The members of a workspace have their own pyproject.toml, but they are resolved together
+    if let Some(workspace) = Workspace::find(&project_dir)? {
+        debug!("Using the workspace at {}", workspace.root.display());
+        let (specs, root_scripts, lockfile) =
+            specs_from_workspace(&workspace, extras, groups, python_context).with_context(
+                || {
+                    format!(
+                        "Couldn't load specs from the workspace at {}",
+                        workspace.root.display()
+                    )
+                },
+            )?;
+        return Ok((specs, root_scripts, lockfile, project_dir));
+    }
+
     let (dep_file_location, lockfile_type) = find_dep_file(&project_dir).with_context(|| {
         format!(
             "neither pyproject.toml nor requirements.txt not found next to {} nor in any parent directory",
@@ -511,12 +591,65 @@ pub fn load_specs(
                     )
                 }
             }
+            // Poetry groups are selected like extras
+            let extras: Vec<String> = extras.iter().chain(groups).cloned().collect();
             let (specs, root_scripts, lockfile) =
-                poetry_spec_from_dir(&dep_file_location, extras, &python_context.pep508_env)
+                poetry_spec_from_dir(&dep_file_location, &extras, &python_context.pep508_env)
                     .context("Couldn't load specs from pyproject.toml/poetry.lock")?;
             Ok((specs, root_scripts, lockfile, project_dir))
         }
+        LockfileType::Pep621 => {
+            // Written by `monotrail lock`, the locked versions are kept where they still match
+            let lock_path = dep_file_location.join(WORKSPACE_LOCK);
+            let previous_lockfile = if lock_path.is_file() {
+                Some(fs::read_to_string(&lock_path)?)
+            } else {
+                None
+            };
+            let (specs, root_scripts, lockfile) = specs_from_pep621_resolved(
+                &dep_file_location,
+                extras,
+                groups,
+                previous_lockfile.as_deref(),
+                python_context,
+            )
+            .context("Couldn't load specs from the [project] table in pyproject.toml")?;
+            Ok((specs, root_scripts, lockfile, project_dir))
+        }
+        LockfileType::PdmLock => {
+            let (specs, root_scripts, lockfile) = read_pdm_specs(
+                &dep_file_location,
+                extras,
+                groups,
+                &python_context.pep508_env,
+            )
+            .context("Couldn't load specs from pyproject.toml/pdm.lock")?;
+            Ok((specs, root_scripts, lockfile, project_dir))
+        }
+        LockfileType::HatchLock => {
+            let (specs, lockfile) =
+                read_hatch_specs(&dep_file_location, &python_context.pep508_env).with_context(
+                    || format!("Couldn't load specs from {}", dep_file_location.display()),
+                )?;
+            // The lockfile is for one environment, which already includes the extras
+            if !extras.is_empty() || !groups.is_empty() {
+                warn!(
+                    "Ignoring extras and groups, hatch-pip-compile lockfiles have them already applied"
+                );
+            }
+            let project_scripts = match dep_file_location.parent().map(read_pep621_project) {
+                Some(Ok(Some(project))) => project.scripts()?,
+                _ => BTreeMap::new(),
+            };
+            Ok((specs, project_scripts, lockfile, project_dir))
+        }
         LockfileType::RequirementsTxt => {
+            if !groups.is_empty() {
+                bail!(
+                    "Dependency groups need a pyproject.toml, but {} was found",
+                    dep_file_location.display()
+                );
+            }
             let (specs, lockfile) = specs_from_requirements_txt_resolved(
                 &dep_file_location,
                 extras,
@@ -536,7 +669,11 @@ pub fn specs_from_requirements_txt_resolved(
     lockfile: Option<&str>,
     python_context: &PythonContext,
 ) -> anyhow::Result<(Vec<RequestedSpec>, String)> {
-    let requirements = read_requirements_for_poetry(&requirements_txt, &current_dir()?)?;
+    let requirements = read_requirements_for_poetry(
+        &requirements_txt,
+        &current_dir()?,
+        &python_context.pep508_env,
+    )?;
     // We don't know whether the requirements.txt is from `pip freeze` or just a list of
     // version, so we let it go through poetry resolve either way. For a frozen file
     // there will just be no change
@@ -546,6 +683,7 @@ pub fn specs_from_requirements_txt_resolved(
     let specs = read_poetry_specs(
         &poetry_section,
         poetry_lock,
+        &current_dir()?,
         false,
         extras,
         &python_context.pep508_env,
@@ -553,6 +691,146 @@ pub fn specs_from_requirements_txt_resolved(
     Ok((specs, lockfile))
 }
 
+/// Reads the PEP 621 `[project]` table of the pyproject.toml in `dir`, calls poetry to resolve
+/// the dependencies and returns the resolved specs, the `[project.scripts]` and the lockfile
+pub fn specs_from_pep621_resolved(
+    dir: &Path,
+    extras: &[String],
+    groups: &[String],
+    lockfile: Option<&str>,
+    python_context: &PythonContext,
+) -> anyhow::Result<(Vec<RequestedSpec>, BTreeMap<String, Script>, String)> {
+    let project = read_pep621_project(dir)?.with_context(|| {
+        format!(
+            "No [project] table in {}",
+            dir.join("pyproject.toml").display()
+        )
+    })?;
+    let requirements =
+        project.requirements_for_poetry(extras, groups, &python_context.pep508_env)?;
+    let (poetry_section, poetry_lock, lockfile) =
+        poetry_resolve(&requirements, lockfile, python_context)
+            .context("Failed to resolve dependencies with poetry")?;
+    // The extras are already part of the requirements
+    let specs = read_poetry_specs(
+        &poetry_section,
+        poetry_lock,
+        dir,
+        false,
+        &[],
+        &python_context.pep508_env,
+    )?;
+    Ok((specs, project.scripts()?, lockfile))
+}
+
+/// Resolves the requirements of all workspace members together, starting from the previous
+/// `monotrail.lock` to keep the locked versions, and updates the lock. Returns the specs, the
+/// scripts of all members and the lockfile
+pub fn specs_from_workspace(
+    workspace: &Workspace,
+    extras: &[String],
+    groups: &[String],
+    python_context: &PythonContext,
+) -> anyhow::Result<(Vec<RequestedSpec>, BTreeMap<String, Script>, String)> {
+    let requirements =
+        workspace.requirements_for_poetry(extras, groups, &python_context.pep508_env)?;
+    let lock_path = workspace.root.join(WORKSPACE_LOCK);
+    let previous_lockfile = if lock_path.is_file() {
+        Some(fs::read_to_string(&lock_path)?)
+    } else {
+        None
+    };
+    let (poetry_section, poetry_lock, lockfile) =
+        poetry_resolve(&requirements, previous_lockfile.as_deref(), python_context)
+            .context("Failed to resolve dependencies with poetry")?;
+    if previous_lockfile.as_deref() != Some(lockfile.as_str()) {
+        fs::write(&lock_path, &lockfile)?;
+    }
+    // The extras are already part of the requirements
+    let specs = read_poetry_specs(
+        &poetry_section,
+        poetry_lock,
+        &workspace.root,
+        false,
+        &[],
+        &python_context.pep508_env,
+    )?;
+    Ok((specs, workspace.scripts()?, lockfile))
+}
+
+/// Resolves each requirements file as its own root, independent of the project and of each
+/// other. Returns the specs and the lockfile of each root
+pub fn specs_from_tool_requirements(
+    requirements_files: &[PathBuf],
+    python_context: &PythonContext,
+) -> anyhow::Result<Vec<(Vec<RequestedSpec>, String)>> {
+    let working_dir = current_dir()?;
+    let roots = requirements_files
+        .iter()
+        .map(|requirements_txt| {
+            read_requirements_for_poetry(requirements_txt, &working_dir, &python_context.pep508_env)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    poetry_resolve_roots(&roots, python_context)?
+        .into_iter()
+        .map(|(poetry_section, poetry_lock, lockfile)| {
+            let specs = read_poetry_specs(
+                &poetry_section,
+                poetry_lock,
+                &working_dir,
+                false,
+                &[],
+                &python_context.pep508_env,
+            )?;
+            Ok((specs, lockfile))
+        })
+        .collect()
+}
+
+/// Resolves the extra packages of an overlay (e.g. `ipython` for a debugging session) on their
+/// own, so the project lockfile is neither changed nor re-resolved
+pub fn specs_from_overlay(
+    requirements: &[String],
+    python_context: &PythonContext,
+) -> anyhow::Result<Vec<RequestedSpec>> {
+    if requirements.is_empty() {
+        return Ok(Vec::new());
+    }
+    let dependencies = requirements_for_poetry(requirements)?;
+    let (poetry_section, poetry_lock, _lockfile) =
+        poetry_resolve(&dependencies, None, python_context)
+            .context("Failed to resolve the overlay packages")?;
+    read_poetry_specs(
+        &poetry_section,
+        poetry_lock,
+        &current_dir()?,
+        false,
+        &[],
+        &python_context.pep508_env,
+    )
+}
+
+/// Stacks independently resolved sets of packages on top of each other. If a package is in
+/// multiple layers, the one from the first layer is used and the others are shadowed.
+pub fn layer_packages(layers: Vec<Vec<InstalledPackage>>) -> Vec<InstalledPackage> {
+    let mut seen = HashSet::new();
+    let mut packages = Vec::new();
+    for layer in layers {
+        for package in layer {
+            if seen.insert(package.name.clone()) {
+                packages.push(package);
+            } else {
+                debug!(
+                    "{} {} is shadowed by a higher layer",
+                    package.name, package.python_version
+                );
+            }
+        }
+    }
+    packages.sort_by(|left, right| left.name.cmp(&right.name));
+    packages
+}
+
 /// Convenience wrapper around `install_requested` and `spec_paths`
 pub fn install(
     specs: &[RequestedSpec],
@@ -561,16 +839,67 @@ pub fn install(
     project_dir: Option<PathBuf>,
     python_context: &PythonContext,
 ) -> anyhow::Result<FinderData> {
-    let (sprawl_root, sprawl_packages) = install_missing(
-        specs,
-        &python_context.sys_executable,
-        python_context.version,
-    )?;
-    let (spec_paths, pth_files) = spec_paths(
+    install_layered(
+        &[specs],
+        root_scripts,
+        lockfile,
+        project_dir,
+        python_context,
+    )
+}
+
+/// Like [install], but for multiple independently resolved sets of specs that are layered with
+/// [layer_packages], the first layer taking precedence
+pub fn install_layered(
+    layers: &[&[RequestedSpec]],
+    root_scripts: BTreeMap<String, Script>,
+    lockfile: String,
+    project_dir: Option<PathBuf>,
+    python_context: &PythonContext,
+) -> anyhow::Result<FinderData> {
+    let mut installed_layers = Vec::new();
+    for specs in layers {
+        let (_sprawl_root, installed) = install_missing(
+            specs,
+            &python_context.sys_executable,
+            python_context.version,
+        )?;
+        installed_layers.push(installed);
+    }
+    let sprawl_root = monotrail_root()?
+        .to_str()
+        .with_context(|| format!("{} path is cursed", env!("CARGO_PKG_NAME")))?
+        .to_string();
+    let sprawl_packages = layer_packages(installed_layers);
+    // So `monotrail store gc` knows which packages are still in use
+    if let Some(project_dir) = &project_dir {
+        let recorded = usage_dir().and_then(|usage_dir| {
+            record_usage(
+                &usage_dir,
+                Path::new(&sprawl_root),
+                project_dir,
+                &sprawl_packages,
+                SystemTime::now(),
+            )
+        });
+        if let Err(err) = recorded {
+            warn!("Failed to record the store usage: {:#}", err);
+        }
+    }
+    let (spec_paths, mut pth_files) = spec_paths(
         sprawl_root.as_ref(),
         &sprawl_packages,
         python_context.version,
     )?;
+    // The workspace members aren't installed, instead they're importable from their sources
+    if let Some(workspace) = project_dir
+        .as_deref()
+        .map(Workspace::find)
+        .transpose()?
+        .flatten()
+    {
+        pth_files.push(workspace.write_pth()?);
+    }
 
     // ugly hack: jupyter otherwise tries to locate its kernel.json relative to the python
     // interpreter, while we're installing them relative to the jupyter package.
@@ -664,19 +993,71 @@ pub fn is_python_script(executable: &Path) -> anyhow::Result<bool> {
     Ok(is_python_script)
 }
 
+/// The project `monotrail run` installs: the directory with the pyproject.toml (the current
+/// directory if `None`) and the extras and dependency groups to include
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProjectSelection<'a> {
+    pub root: Option<&'a Path>,
+    pub extras: &'a [String],
+    pub groups: &'a [String],
+}
+
 /// Run an installed command
+///
+/// Each of the `tool_requirements` files is resolved on its own and installed as its own
+/// environment, like `tool run`. If one of the tools has the command, it runs with only that
+/// tool's packages, so it can use a different version of a package than the project. Otherwise,
+/// the command runs in the project with the `overlay` packages layered below it, so they can't
+/// change the locked versions.
 pub fn run_command(
-    extras: &[String],
+    project: ProjectSelection,
     python_version: Option<&str>,
-    root: Option<&Path>,
+    tool_requirements: &[PathBuf],
+    overlay: &[String],
     command: &str,
     args: &[String],
 ) -> anyhow::Result<i32> {
     let (args, python_version) =
-        determine_python_version(args, python_version, DEFAULT_PYTHON_VERSION)?;
+        determine_python_version(args, python_version, project_python_version(project.root)?)?;
     let (python_context, python_home) = provision_python_env(python_version)?;
-    let (specs, root_scripts, lockfile, root) = load_specs(root, extras, &python_context)?;
-    let finder_data = install(&specs, root_scripts, lockfile, Some(root), &python_context)?;
+    let (specs, root_scripts, lockfile, root) = load_specs(
+        project.root,
+        project.extras,
+        project.groups,
+        &python_context,
+    )?;
+    let tool_roots = specs_from_tool_requirements(tool_requirements, &python_context)?;
+    let overlay_specs = specs_from_overlay(overlay, &python_context)?;
+    let mut tool_finder_data = None;
+    for (tool_specs, tool_lockfile) in tool_roots {
+        let finder_data = install(
+            &tool_specs,
+            BTreeMap::new(),
+            tool_lockfile,
+            None,
+            &python_context,
+        )?;
+        let tool_scripts = find_scripts(
+            &finder_data.sprawl_packages,
+            Path::new(&finder_data.sprawl_root),
+        )?;
+        if tool_scripts.contains_key(command) {
+            debug!("Running {} in its tool environment", command);
+            tool_finder_data = Some(finder_data);
+            break;
+        }
+    }
+    let finder_data = match tool_finder_data {
+        Some(finder_data) => finder_data,
+        // The project comes first so the overlay can't change the locked versions
+        None => install_layered(
+            &[&specs, &overlay_specs],
+            root_scripts,
+            lockfile,
+            Some(root),
+            &python_context,
+        )?,
+    };
 
     // We need to resolve the command when we pass it to python so we need to remove the bare
     // command. TODO: Do we want to fake argv with the bare command? At least for code in tracebacks
@@ -811,7 +1192,7 @@ pub fn cli_from_git(
     let (trail_args, python_version) = determine_python_version(
         &trail_args,
         python_version.as_deref(),
-        DEFAULT_PYTHON_VERSION,
+        default_python_version()?,
     )?;
     let (python_context, python_home) = provision_python_env(python_version)?;
 
@@ -850,10 +1231,11 @@ pub fn cli_from_git(
 /// If a downloaded python version exists, return this, otherwise download and unpack a matching one
 /// from indygreg/python-build-standalone
 pub fn provision_python_env(python_version: (u8, u8)) -> anyhow::Result<(PythonContext, PathBuf)> {
-    let (python_binary, python_home) = provision_python(python_version, cache_dir()?.as_path())?;
+    let (python_binary, python_home) =
+        provision_python(&UreqTransport, python_version, cache_dir()?.as_path())?;
 
     // TODO: Already init and use libpython here
-    let pep508_env = marker_environment_from_python(&python_binary);
+    let pep508_env = marker_environment_from_python(&python_binary)?;
     let python_context = PythonContext {
         sys_executable: python_binary,
         version: python_version,
@@ -863,3 +1245,85 @@ pub fn provision_python_env(python_version: (u8, u8)) -> anyhow::Result<(PythonC
 
     Ok((python_context, python_home))
 }
+
+#[cfg(test)]
+mod test {
+    use super::{check_installable, layer_packages, spec_paths};
+    use crate::install::InstalledPackage;
+    use crate::spec::RequestedSpec;
+    use crate::utils::{foreign_owner, is_writable_dir};
+    use fs_err as fs;
+    use tempfile::TempDir;
+
+    fn package(name: &str, version: &str) -> InstalledPackage {
+        InstalledPackage {
+            name: name.to_string(),
+            python_version: version.to_string(),
+            unique_version: version.to_string(),
+            tag: "py3-none-any".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_spec_paths_namespace_packages() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path();
+        let interface = package("zope-interface", "6.0");
+        let event = package("zope-event", "5.0");
+        for (package, module) in [(&interface, "interface"), (&event, "event")] {
+            let site_packages = package.monotrail_site_packages(root.to_path_buf(), (3, 8));
+            fs::create_dir_all(site_packages.join("zope").join(module)).unwrap();
+            fs::write(
+                site_packages.join("zope").join(module).join("__init__.py"),
+                "",
+            )
+            .unwrap();
+            fs::write(
+                site_packages.join(format!("{}-6.0-py3.8-nspkg.pth", package.name)),
+                "import sys, types, os",
+            )
+            .unwrap();
+        }
+        let (spec_paths, pth_files) =
+            spec_paths(root, &[interface.clone(), event.clone()], (3, 8)).unwrap();
+        assert_eq!(pth_files, Vec::<std::path::PathBuf>::new());
+        let (init_py, locations) = &spec_paths["zope"];
+        assert_eq!(init_py, &None);
+        assert_eq!(
+            locations,
+            &[
+                event
+                    .monotrail_site_packages(root.to_path_buf(), (3, 8))
+                    .join("zope"),
+                interface
+                    .monotrail_site_packages(root.to_path_buf(), (3, 8))
+                    .join("zope")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_layer_packages() {
+        let overlay = vec![package("ipython", "8.10.0"), package("click", "8.1.3")];
+        let project = vec![package("click", "7.1.2"), package("flask", "1.1.4")];
+        let versions: Vec<_> = layer_packages(vec![project, overlay])
+            .into_iter()
+            .map(|package| format!("{} {}", package.name, package.python_version))
+            .collect();
+        // The project's pin wins over the overlay's version
+        assert_eq!(versions, ["click 7.1.2", "flask 1.1.4", "ipython 8.10.0"]);
+    }
+
+    #[test]
+    fn test_check_installable_own_dirs() {
+        let monotrail_root = TempDir::new().unwrap();
+        assert_eq!(foreign_owner(monotrail_root.path()), None);
+        assert!(is_writable_dir(&monotrail_root.path().join("tqdm")));
+        assert!(!monotrail_root.path().join("tqdm").exists());
+        let specs = [
+            RequestedSpec::from_requested("tqdm", &[]).unwrap(),
+            RequestedSpec::from_requested("black", &[]).unwrap(),
+        ];
+        check_installable(monotrail_root.path(), &specs).unwrap();
+    }
+}
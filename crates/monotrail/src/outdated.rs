@@ -0,0 +1,215 @@
+//! `monotrail outdated`: Which locked packages have newer releases on the indexes
+
+use crate::index::{project_files, IndexFile, IndexUrls};
+use crate::package_index::PreReleasePolicy;
+use crate::spec::RequestedSpec;
+use anyhow::Context;
+use install_wheel_rs::{CompatibleTags, WheelFilename};
+use monotrail_utils::transport::Transport;
+use pep440_rs::{Version, VersionSpecifiers};
+use pep508_rs::MarkerEnvironment;
+use serde_json::json;
+use std::str::FromStr;
+use tracing::debug;
+
+/// A package with a newer release than the locked one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutdatedPackage {
+    /// The name as in the lock
+    pub name: String,
+    /// The locked version
+    pub current: Version,
+    /// The newest release that works with this python and platform
+    pub latest_compatible: Version,
+    /// The newest release overall
+    pub latest: Version,
+}
+
+/// Whether the file can be installed: requires-python matches and it's either a compatible wheel
+/// or a source distribution
+fn is_compatible(file: &IndexFile, python_version: &Version, tags: &CompatibleTags) -> bool {
+    if let Some(requires_python) = &file.requires_python {
+        match VersionSpecifiers::from_str(requires_python) {
+            Ok(specifiers) if !specifiers.contains(python_version) => return false,
+            Ok(_) => {}
+            Err(err) => debug!(
+                "Ignoring invalid requires-python of {}: {}",
+                file.filename, err
+            ),
+        }
+    }
+    if file.filename.ends_with(".whl") {
+        WheelFilename::from_str(&file.filename)
+            .is_ok_and(|filename| filename.compatibility(tags).is_ok())
+    } else {
+        true
+    }
+}
+
+/// Compares the locked version of each spec with the releases on the indexes. Yanked releases
+/// are ignored, and so are pre-releases, unless the locked version is one or they are allowed
+/// for the package (`MONOTRAIL_PRE_RELEASES`). Git, url and path dependencies are skipped
+pub fn outdated_packages(
+    specs: &[RequestedSpec],
+    indexes: &IndexUrls,
+    transport: &dyn Transport,
+    pep508_env: &MarkerEnvironment,
+    tags: &CompatibleTags,
+) -> anyhow::Result<Vec<OutdatedPackage>> {
+    let python_version = &pep508_env.python_full_version.version;
+    let pre_releases = PreReleasePolicy::current();
+    let mut outdated = Vec::new();
+    for spec in specs {
+        if spec.source.is_some() || spec.url.is_some() || spec.file_path.is_some() {
+            continue;
+        }
+        let Some(current) = spec
+            .python_version
+            .as_deref()
+            .and_then(|version| Version::from_str(version).ok())
+        else {
+            continue;
+        };
+        let project = project_files(indexes, transport, &spec.name)
+            .with_context(|| format!("Failed to get the releases of {}", spec.name))?;
+        let allow_pre = current.any_prerelease() || pre_releases.allows(&spec.name);
+        let mut latest: Option<Version> = None;
+        let mut latest_compatible: Option<Version> = None;
+        for file in &project.files {
+            if file.yanked.is_some() {
+                continue;
+            }
+            let Some(version) = file.version() else {
+                continue;
+            };
+            if version.any_prerelease() && !allow_pre {
+                continue;
+            }
+            if !matches!(&latest, Some(latest) if version <= *latest) {
+                latest = Some(version.clone());
+            }
+            if is_compatible(file, python_version, tags)
+                && !matches!(&latest_compatible, Some(latest) if version <= *latest)
+            {
+                latest_compatible = Some(version);
+            }
+        }
+        let Some(latest) = latest else {
+            continue;
+        };
+        if latest <= current {
+            continue;
+        }
+        let latest_compatible = latest_compatible
+            .filter(|version| *version > current)
+            .unwrap_or_else(|| current.clone());
+        outdated.push(OutdatedPackage {
+            name: spec.name.clone(),
+            current,
+            latest_compatible,
+            latest,
+        });
+    }
+    outdated.sort_by_key(|package| package.name.to_lowercase());
+    Ok(outdated)
+}
+
+/// An aligned table with a header, like `pip list --outdated`
+pub fn format_outdated(outdated: &[OutdatedPackage]) -> String {
+    let rows: Vec<[String; 4]> = [[
+        "Package".to_string(),
+        "Current".to_string(),
+        "Compatible".to_string(),
+        "Latest".to_string(),
+    ]]
+    .into_iter()
+    .chain(outdated.iter().map(|package| {
+        [
+            package.name.clone(),
+            package.current.to_string(),
+            package.latest_compatible.to_string(),
+            package.latest.to_string(),
+        ]
+    }))
+    .collect();
+    let widths: Vec<usize> = (0..4)
+        .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or(0))
+        .collect();
+    let mut table = String::new();
+    for row in rows {
+        let line: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        table += line.join("  ").trim_end();
+        table += "\n";
+    }
+    table
+}
+
+/// `{"packages": [{"name", "current", "latest_compatible", "latest"}]}` for `--format json`
+pub fn outdated_json(outdated: &[OutdatedPackage]) -> serde_json::Value {
+    let packages: Vec<_> = outdated
+        .iter()
+        .map(|package| {
+            json!({
+                "name": package.name,
+                "current": package.current.to_string(),
+                "latest_compatible": package.latest_compatible.to_string(),
+                "latest": package.latest.to_string(),
+            })
+        })
+        .collect();
+    json!({ "packages": packages })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::index::IndexUrls;
+    use crate::outdated::{format_outdated, outdated_json, outdated_packages};
+    use crate::resolver::test::test_transport;
+    use crate::spec::RequestedSpec;
+    use crate::test_utils::test_pep508_env;
+    use indoc::indoc;
+    use install_wheel_rs::{Arch, CompatibleTags, Os};
+    use serde_json::json;
+
+    #[test]
+    fn test_outdated() {
+        let specs: Vec<RequestedSpec> = ["foo==1.0", "bar==1.0", "baz==1.0"]
+            .iter()
+            .map(|requested| RequestedSpec::from_requested(requested, &[]).unwrap())
+            .collect();
+        let tags = CompatibleTags::new(
+            (3, 8),
+            Os::Manylinux {
+                major: 2,
+                minor: 27,
+            },
+            Arch::X86_64,
+        )
+        .unwrap();
+        let outdated = outdated_packages(
+            &specs,
+            &IndexUrls::default(),
+            &test_transport(),
+            &test_pep508_env(),
+            &tags,
+        )
+        .unwrap();
+        // bar 1.1 is yanked, foo 2.1rc1 is a pre-release and baz 3.0 needs python 3.10
+        assert_eq!(
+            format_outdated(&outdated),
+            indoc! {"
+                Package  Current  Compatible  Latest
+                baz      1.0      2.0         3.0
+                foo      1.0      2.0         2.0
+            "}
+        );
+        assert_eq!(
+            outdated_json(&outdated)["packages"][0],
+            json!({"name": "baz", "current": "1.0", "latest_compatible": "2.0", "latest": "3.0"})
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! `--format json`: The result of a command as a single JSON document on stdout, with the logs on
+//! stderr, so CI scripts can use e.g. the outdated packages or the audit findings without parsing
+//! tables. Failures print `{"error": {"code", "message", "causes"}}`, `code` is the stable code of
+//! an installer or requirements.txt error if there is one.
+//!
+//! ```text
+//! $ monotrail --format json outdated
+//! {
+//!   "packages": [
+//!     {"name": "tqdm", "current": "4.64.0", "latest_compatible": "4.66.1", "latest": "4.66.1"}
+//!   ]
+//! }
+//! ```
+
+use crate::install::InstalledPackage;
+use monotrail_utils::RequirementsTxtFileError;
+use serde_json::json;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// How to print the results of a command
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum OutputFormat {
+    /// Tables and log messages on stdout
+    #[default]
+    Human,
+    /// A JSON document on stdout and the log messages on stderr
+    Json,
+}
+
+/// Global output options, which go before the subcommand, e.g.
+/// `monotrail --format json audit`
+#[derive(clap::Args, Debug, Default, Clone)]
+pub struct OutputOptions {
+    /// `json` prints the results of install, wheel-install, poetry-install, sync, tree, outdated,
+    /// audit, licenses, lint, verify-installation, python, cache and store as JSON on stdout and
+    /// the log messages on stderr. The other commands fail with `json`
+    #[clap(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+}
+
+/// Set by [init_output], read wherever a command prints its result
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Switches to JSON output if requested
+pub fn init_output(options: &OutputOptions) {
+    JSON.store(options.format == OutputFormat::Json, Ordering::Relaxed);
+}
+
+/// Whether the results are printed as JSON instead of for humans
+pub fn is_json_output() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// Prints the result of the command to stdout
+pub(crate) fn print_json(value: &serde_json::Value) {
+    // Serializing a Value can't fail
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
+
+/// `{"venv": ..., "installed": [...]}` with the packages that were installed in this run
+pub(crate) fn print_installed(venv: &Path, installed: &[InstalledPackage]) {
+    let mut installed = installed.to_vec();
+    installed.sort_by(|left, right| left.name.cmp(&right.name));
+    print_json(&json!({ "venv": venv, "installed": installed }));
+}
+
+/// The path of a python interpreter, as `{"python": ...}` for JSON
+pub(crate) fn print_python(python: &Path) {
+    if is_json_output() {
+        print_json(&json!({ "python": python }));
+    } else {
+        println!("{}", python.display());
+    }
+}
+
+/// `{"error": {"code": ..., "message": ..., "causes": [...]}}`
+pub fn error_json(err: &anyhow::Error) -> serde_json::Value {
+    let code = err.chain().find_map(|cause| {
+        if let Some(err) = cause.downcast_ref::<install_wheel_rs::Error>() {
+            Some(err.code())
+        } else {
+            cause
+                .downcast_ref::<RequirementsTxtFileError>()
+                .map(RequirementsTxtFileError::code)
+        }
+    });
+    let causes: Vec<String> = err.chain().skip(1).map(ToString::to_string).collect();
+    json!({
+        "error": {
+            "code": code,
+            "message": err.to_string(),
+            "causes": causes,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::output::error_json;
+    use anyhow::Context;
+    use install_wheel_rs::Error;
+    use serde_json::json;
+
+    #[test]
+    fn test_error_json() {
+        let err = Err::<(), _>(Error::InvalidWheel("No RECORD".to_string()))
+            .context("Failed to install tqdm")
+            .unwrap_err();
+        assert_eq!(
+            error_json(&err),
+            json!({
+                "error": {
+                    "code": "invalid-wheel",
+                    "message": "Failed to install tqdm",
+                    "causes": ["The wheel is invalid: No RECORD"],
+                }
+            })
+        );
+
+        let err = anyhow::format_err!("Missing version for requirement tqdm");
+        assert_eq!(error_json(&err)["error"]["code"], json!(null));
+    }
+}
@@ -1,15 +1,18 @@
 //! Basic downloading from pypi
 
+use crate::diagnostics::{report, WarningClass};
+use crate::index::{find_links_files, IndexFile};
 use crate::spec::DistributionType;
-use anyhow::{bail, Context, Result};
-use fs_err as fs;
-use install_wheel_rs::{CompatibleTags, Error, WheelFilename};
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::io;
-use std::path::Path;
+use anyhow::{bail, format_err, Context, Result};
+use install_wheel_rs::{normalize_name, CompatibleTags, Error, WheelFilename};
+use monotrail_utils::transport::Transport;
+use pep440_rs::{Version, VersionSpecifiers};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
 use std::str::FromStr;
-use tracing::debug;
+use std::sync::Mutex;
+use tracing::{debug, warn};
 
 pub(crate) const PYPI_HOST: &str = "https://pypi.org";
 
@@ -28,6 +31,13 @@ pub struct PypiRelease {
     pub python_version: String,
     pub size: usize,
     pub url: String,
+    /// Hash algorithm -> hex digest, e.g. `sha256`
+    #[serde(default)]
+    pub digests: HashMap<String, String>,
+    #[serde(default)]
+    pub yanked: bool,
+    #[serde(default)]
+    pub yanked_reason: Option<String>,
 }
 
 /// <https://github.com/pypa/warehouse/blob/4d4c7940063db51e8ee03de78afdff6d4e9140ae/warehouse/filters.py#L33-L41>
@@ -44,11 +54,257 @@ pub enum PackageType {
     Sdist,
 }
 
+/// Whether to install a package from a wheel or to build it from the source distribution. The
+/// native resolver only picks versions with a wheel for [SourcePreference::Wheel] and records
+/// the preferences in the lockfile
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourcePreference {
+    /// Use a compatible wheel if there is one, otherwise build the source distribution
+    Auto,
+    /// Build from the source distribution even if there is a wheel, e.g. to get CPU-optimized
+    /// builds of numpy. Falls back to wheels if there is no source distribution
+    Sdist,
+    /// Only install wheels, never build
+    Wheel,
+}
+
+impl SourcePreference {
+    /// Configured like pip's `--no-binary` and `--only-binary` with comma separated package names
+    /// or `:all:` in `MONOTRAIL_NO_BINARY` and `MONOTRAIL_ONLY_BINARY`
+    pub fn for_package(name: &str) -> Self {
+        SourcePreferences::from_env().for_package(name)
+    }
+
+    /// A package named explicitly takes precedence over `:all:`
+    fn from_lists(name: &str, no_binary: &str, only_binary: &str) -> Self {
+        let contains = |list: &str, entry: &str| {
+            list.split(',')
+                .map(str::trim)
+                .any(|item| normalize_name(item) == entry)
+        };
+        let name = normalize_name(name);
+        if contains(no_binary, &name) {
+            SourcePreference::Sdist
+        } else if contains(only_binary, &name) {
+            SourcePreference::Wheel
+        } else if contains(no_binary, ":all:") {
+            SourcePreference::Sdist
+        } else if contains(only_binary, ":all:") {
+            SourcePreference::Wheel
+        } else {
+            SourcePreference::Auto
+        }
+    }
+}
+
+/// The `MONOTRAIL_NO_BINARY` and `MONOTRAIL_ONLY_BINARY` lists, see [SourcePreference::for_package]
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub(crate) struct SourcePreferences {
+    pub(crate) no_binary: String,
+    pub(crate) only_binary: String,
+}
+
+impl SourcePreferences {
+    pub(crate) fn from_env() -> Self {
+        let read_var = |suffix: &str| {
+            env::var(format!(
+                "{}_{}",
+                env!("CARGO_PKG_NAME").to_uppercase(),
+                suffix
+            ))
+            .unwrap_or_default()
+        };
+        Self {
+            no_binary: read_var("NO_BINARY"),
+            only_binary: read_var("ONLY_BINARY"),
+        }
+    }
+
+    pub(crate) fn for_package(&self, name: &str) -> SourcePreference {
+        SourcePreference::from_lists(name, &self.no_binary, &self.only_binary)
+    }
+}
+
+/// Set by [set_pre_releases], takes precedence over `MONOTRAIL_PRE`
+static PRE_RELEASES: Mutex<Option<PreReleasePolicy>> = Mutex::new(None);
+
+/// Which packages may use pre-release versions (PEP 440), set with `--pre`, `--pre-package` or
+/// `MONOTRAIL_PRE`. Otherwise, we only use a pre-release if a requirement mentions one, e.g.
+/// `black>=23.1b0`, or if no final release matches
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub(crate) enum PreReleasePolicy {
+    /// Only if mentioned or if there is no final release
+    #[default]
+    IfNecessary,
+    /// For all packages
+    All,
+    /// Only for these packages (normalized names)
+    Packages(BTreeSet<String>),
+}
+
+impl PreReleasePolicy {
+    fn env_var() -> String {
+        format!("{}_PRE", env!("CARGO_PKG_NAME").to_uppercase())
+    }
+
+    /// Comma separated package names or `:all:` in `MONOTRAIL_PRE`, like `MONOTRAIL_NO_BINARY`
+    pub(crate) fn from_env() -> Self {
+        Self::from_list(&env::var(Self::env_var()).unwrap_or_default())
+    }
+
+    /// The policy from [set_pre_releases], otherwise from `MONOTRAIL_PRE`
+    pub(crate) fn current() -> Self {
+        PRE_RELEASES
+            .lock()
+            .unwrap()
+            .clone()
+            .unwrap_or_else(Self::from_env)
+    }
+
+    fn from_list(list: &str) -> Self {
+        let mut policy = Self::default();
+        for name in list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            policy.allow(name);
+        }
+        policy
+    }
+
+    /// Allows pre-releases of `name`, or of all packages for `:all:`
+    pub(crate) fn allow(&mut self, name: &str) {
+        match self {
+            PreReleasePolicy::All => {}
+            _ if name == ":all:" => *self = PreReleasePolicy::All,
+            PreReleasePolicy::IfNecessary => {
+                *self = PreReleasePolicy::Packages(BTreeSet::from([normalize_name(name)]))
+            }
+            PreReleasePolicy::Packages(packages) => {
+                packages.insert(normalize_name(name));
+            }
+        }
+    }
+
+    /// Whether pre-releases of `name` are allowed even if there is a matching final release
+    pub(crate) fn allows(&self, name: &str) -> bool {
+        match self {
+            PreReleasePolicy::IfNecessary => false,
+            PreReleasePolicy::All => true,
+            PreReleasePolicy::Packages(packages) => packages.contains(&normalize_name(name)),
+        }
+    }
+}
+
+/// Allows pre-releases of the packages (or `:all:`) for the rest of this run, in addition to
+/// `MONOTRAIL_PRE`
+pub(crate) fn set_pre_releases(names: &[String]) {
+    let mut policy = PreReleasePolicy::current();
+    for name in names {
+        policy.allow(name);
+    }
+    *PRE_RELEASES.lock().unwrap() = Some(policy);
+}
+
+/// What to do with yanked releases (PEP 592), set with `--yanked` or `MONOTRAIL_YANKED`
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum YankedPolicy {
+    /// Ignore yanked releases unless they are pinned with `==` or `===` or by the lockfile, and
+    /// warn when installing them
+    #[default]
+    Pinned,
+    /// Yanked releases are candidates like all others, but warn when installing them
+    Warn,
+    /// Never install a yanked release, not even a pinned one
+    Refuse,
+}
+
+impl YankedPolicy {
+    fn env_var() -> String {
+        format!("{}_YANKED", env!("CARGO_PKG_NAME").to_uppercase())
+    }
+
+    /// `MONOTRAIL_YANKED`, defaulting to [YankedPolicy::Pinned]
+    pub(crate) fn from_env() -> Self {
+        let env_var = Self::env_var();
+        match env::var(&env_var).as_deref() {
+            Err(_) | Ok("") => Self::default(),
+            Ok(value) => clap::ValueEnum::from_str(value, true).unwrap_or_else(|_| {
+                warn!(
+                    "Invalid {}={}, expected one of pinned, warn or refuse",
+                    env_var, value
+                );
+                Self::default()
+            }),
+        }
+    }
+
+    /// This is an environment variable so that monotrail subprocesses use the same policy
+    pub(crate) fn set(self) {
+        let value = match self {
+            YankedPolicy::Pinned => "pinned",
+            YankedPolicy::Warn => "warn",
+            YankedPolicy::Refuse => "refuse",
+        };
+        env::set_var(Self::env_var(), value);
+    }
+
+    /// Whether we may install a yanked release. `pinned` means the requirement allows only this
+    /// version
+    pub(crate) fn allows(self, pinned: bool) -> bool {
+        match self {
+            YankedPolicy::Pinned => pinned,
+            YankedPolicy::Warn => true,
+            YankedPolicy::Refuse => false,
+        }
+    }
+}
+
+/// Warns that we install a yanked release, with the reason from the index
+pub(crate) fn report_yanked(name: &str, version: &str, reason: Option<&str>) {
+    report(
+        WarningClass::Yanked,
+        format!(
+            "{} {} was yanked: {}",
+            name,
+            version,
+            reason.unwrap_or("no reason given")
+        ),
+    );
+}
+
+/// The error for a yanked release the policy doesn't allow
+pub(crate) fn yanked_error(name: &str, version: &str, reason: Option<&str>) -> anyhow::Error {
+    format_err!(
+        "{} {} was yanked ({}) and yanked releases are refused (--yanked refuse)",
+        name,
+        version,
+        reason.unwrap_or("no reason given")
+    )
+}
+
 fn matching_package_for_version(
     compatible_tags: &CompatibleTags,
     version: &str,
     pypi_releases: &[PypiRelease],
+    preference: SourcePreference,
 ) -> Result<Option<(PypiRelease, DistributionType, String)>> {
+    let sdist = pypi_releases
+        .iter()
+        .find(|release| release.packagetype == PackageType::Sdist)
+        .map(|sdist_release| {
+            (
+                sdist_release.clone(),
+                DistributionType::SourceDistribution,
+                version.to_string(),
+            )
+        });
+    if preference == SourcePreference::Sdist && sdist.is_some() {
+        return Ok(sdist);
+    }
+
     let wheel_releases = pypi_releases
         .iter()
         .filter(|release| release.packagetype == PackageType::BdistWheel)
@@ -72,80 +328,391 @@ fn matching_package_for_version(
         )));
     }
 
-    if let Some(sdist_release) = pypi_releases
-        .iter()
-        .find(|release| release.packagetype == PackageType::Sdist)
-    {
-        Ok(Some((
-            sdist_release.clone(),
-            DistributionType::SourceDistribution,
-            version.to_string(),
-        )))
-    } else {
+    if preference == SourcePreference::Wheel {
         Ok(None)
+    } else {
+        Ok(sdist)
     }
 }
 
-/// Finds a matching wheel from pages like <https://pypi.org/pypi/tqdm/json>
+/// Whether pre-releases may be picked: If allowed for the package, if a specifier names a
+/// pre-release (`>=2.0b1`) or if there is nothing else (PEP 440)
+fn allows_pre_releases<'a>(
+    name: &str,
+    specifiers: Option<&VersionSpecifiers>,
+    mut versions: impl Iterator<Item = &'a Version>,
+) -> bool {
+    PreReleasePolicy::current().allows(name)
+        || specifiers.is_some_and(|specifiers| {
+            specifiers
+                .iter()
+                .any(|specifier| specifier.any_prerelease())
+        })
+        || versions.all(|version| version.any_prerelease())
+}
+
+/// Finds a matching wheel from pages like <https://pypi.org/pypi/tqdm/json>, for the exact
+/// `version` or otherwise for the newest version matching `specifiers`
 ///
 /// <https://warehouse.pypa.io/api-reference/json.html>
 pub fn search_release(
     host: &str,
+    transport: &dyn Transport,
     name: &str,
     version: Option<String>,
+    specifiers: Option<&VersionSpecifiers>,
     compatible_tags: &CompatibleTags,
 ) -> Result<(PypiRelease, DistributionType, String)> {
     debug!("Getting Releases");
-    let url = format!("{}/pypi/{}/json", host, name);
-    let pypi_project: PypiProject = ureq::get(&url)
-        .set("User-Agent", "monotrail (konstin@mailbox.org)")
-        .call()
-        .context("Failed to contact pypi. Is your internet connection working?")?
-        .into_json()
-        .context("Invalid api response from pypi")?;
+    let preference = SourcePreference::for_package(name);
+    let yanked_policy = YankedPolicy::from_env();
+    let pypi_project = fetch_project(host, transport, name)?;
     if let Some(version) = version {
+        // The index may spell the version differently, e.g. `1.0` for `1.0.0`
+        let parsed = Version::from_str(&version).ok();
         let pypi_releases = pypi_project
             .releases
             .get(&version)
+            .or_else(|| {
+                let parsed = parsed.as_ref()?;
+                pypi_project
+                    .releases
+                    .iter()
+                    .find(|(key, _)| Version::from_str(key).is_ok_and(|key| &key == parsed))
+                    .map(|(_, releases)| releases)
+            })
             .with_context(|| format!("{} {} not found on pypi", name, version))?;
 
-        matching_package_for_version(compatible_tags, &version, pypi_releases)?
-            .with_context(|| format!("Couldn't find compatible release for {} {}", name, version))
+        let picked =
+            matching_package_for_version(compatible_tags, &version, pypi_releases, preference)?
+                .with_context(|| {
+                    if preference == SourcePreference::Wheel {
+                        format!(
+                    "Couldn't find compatible wheel for {} {} and building from source is disabled",
+                    name, version
+                )
+                    } else {
+                        format!("Couldn't find compatible release for {} {}", name, version)
+                    }
+                })?;
+        // Like pip, we still install yanked releases if they are pinned
+        if picked.0.yanked {
+            let reason = picked.0.yanked_reason.as_deref();
+            if !yanked_policy.allows(true) {
+                return Err(yanked_error(name, &version, reason));
+            }
+            report_yanked(name, &version, reason);
+        }
+        Ok(picked)
     } else {
-        let mut releases = pypi_project.releases.iter().collect::<Vec<_>>();
-        // TODO: Actually parse versions
-        releases.sort_by_key(|&(key, _)| key);
-        releases.reverse();
-        for (version, release) in releases {
+        // Versions that aren't PEP 440 compliant can't match specifiers and go last
+        let mut releases: Vec<(Option<Version>, &String, &Vec<PypiRelease>)> = pypi_project
+            .releases
+            .iter()
+            .map(|(version, release)| (Version::from_str(version).ok(), version, release))
+            .collect();
+        // Newest first
+        releases.sort_by(|left, right| right.0.cmp(&left.0).then(right.1.cmp(left.1)));
+        if let Some(specifiers) = specifiers {
+            releases.retain(|(parsed, _, _)| {
+                parsed
+                    .as_ref()
+                    .is_some_and(|parsed| specifiers.contains(parsed))
+            });
+        }
+        if !allows_pre_releases(
+            name,
+            specifiers,
+            releases.iter().filter_map(|(parsed, _, _)| parsed.as_ref()),
+        ) {
+            releases.retain(|(parsed, _, _)| {
+                !parsed
+                    .as_ref()
+                    .is_some_and(|parsed| parsed.any_prerelease())
+            });
+        }
+        for (_, version, release) in releases {
+            // Yanked files are ignored unless pinned (PEP 592)
+            let release: Vec<PypiRelease> = release
+                .iter()
+                .filter(|release| !release.yanked || yanked_policy.allows(false))
+                .cloned()
+                .collect();
             if let Some(matching_package) =
-                matching_package_for_version(compatible_tags, version, release)?
+                matching_package_for_version(compatible_tags, version, &release, preference)?
             {
+                if matching_package.0.yanked {
+                    report_yanked(name, version, matching_package.0.yanked_reason.as_deref());
+                }
                 return Ok(matching_package);
             }
         }
-        bail!("No matching version found for {}", name);
-    }
-}
-
-/// Just wraps ureq
-pub(crate) fn download_distribution(
-    url: &str,
-    target_dir: &Path,
-    target_file: &Path,
-) -> Result<()> {
-    debug!("Downloading wheel to {}", target_file.display());
-    fs::create_dir_all(target_dir).context("Couldn't create cache dir")?;
-    // temp file so we don't clash with other processes running in parallel
-    let mut temp_file =
-        tempfile::NamedTempFile::new_in(target_dir).context("Couldn't create file for download")?;
-    let request_for_file = ureq::get(url)
-        .set("User-Agent", "monotrail (konstin@mailbox.org)")
-        .call()
-        .context("Error during pypi request")?;
-    io::copy(&mut request_for_file.into_reader(), &mut temp_file)
-        .context("Failed to download wheel from pypi")?;
-    temp_file
-        .persist(target_file)
-        .context("Failed to moved wheel to target position")?;
-    Ok(())
+        match specifiers {
+            Some(specifiers) => bail!("No matching version found for {} {}", name, specifiers),
+            None => bail!("No matching version found for {}", name),
+        }
+    }
+}
+
+/// Like [search_release], but in the `--find-links` sources. `None` if they don't have the
+/// project, then we use pypi
+pub(crate) fn search_find_links(
+    find_links: &[String],
+    transport: &dyn Transport,
+    name: &str,
+    version: Option<&str>,
+    specifiers: Option<&VersionSpecifiers>,
+    compatible_tags: &CompatibleTags,
+) -> Result<Option<(PypiRelease, DistributionType, String)>> {
+    if find_links.is_empty() {
+        return Ok(None);
+    }
+    let files = find_links_files(find_links, transport, name)?;
+    if files.is_empty() {
+        return Ok(None);
+    }
+    let mut releases: BTreeMap<Version, Vec<PypiRelease>> = BTreeMap::new();
+    for file in files {
+        if let Some(file_version) = file.version() {
+            releases
+                .entry(file_version)
+                .or_default()
+                .push(PypiRelease::from(file));
+        }
+    }
+    let preference = SourcePreference::for_package(name);
+    if let Some(version) = version {
+        let parsed = Version::from_str(version)
+            .map_err(|err| format_err!("Invalid version {}: {}", version, err))?;
+        let releases = releases.get(&parsed).with_context(|| {
+            format!(
+                "{} {} not found in --find-links {}",
+                name,
+                version,
+                find_links.join(", ")
+            )
+        })?;
+        let picked = matching_package_for_version(compatible_tags, version, releases, preference)?
+            .with_context(|| {
+                format!(
+                    "Couldn't find compatible release for {} {} in --find-links",
+                    name, version
+                )
+            })?;
+        return Ok(Some(picked));
+    }
+    let candidates: Vec<(&Version, &Vec<PypiRelease>)> = releases
+        .iter()
+        .rev()
+        .filter(|(version, _)| match specifiers {
+            Some(specifiers) => specifiers.contains(version),
+            None => true,
+        })
+        .collect();
+    let allows_pre_releases = allows_pre_releases(
+        name,
+        specifiers,
+        candidates.iter().map(|(version, _)| *version),
+    );
+    for (version, releases) in candidates {
+        if version.any_prerelease() && !allows_pre_releases {
+            continue;
+        }
+        let version = version.to_string();
+        if let Some(picked) =
+            matching_package_for_version(compatible_tags, &version, releases, preference)?
+        {
+            return Ok(Some(picked));
+        }
+    }
+    bail!(
+        "No matching version found for {} in --find-links {}",
+        name,
+        find_links.join(", ")
+    );
+}
+
+/// The index file as if pypi had told us about it
+impl From<IndexFile> for PypiRelease {
+    fn from(file: IndexFile) -> Self {
+        let packagetype = if file.filename.ends_with(".whl") {
+            PackageType::BdistWheel
+        } else {
+            PackageType::Sdist
+        };
+        Self {
+            filename: file.filename,
+            packagetype,
+            python_version: String::new(),
+            size: 0,
+            url: file.url,
+            digests: file.hashes.into_iter().collect(),
+            yanked: file.yanked.is_some(),
+            yanked_reason: file.yanked.filter(|reason| !reason.is_empty()),
+        }
+    }
+}
+
+/// <https://pypi.org/pypi/tqdm/json>
+///
+/// The callers wrap their transport in an [crate::http_cache::HttpCache] to cache the responses
+fn fetch_project(host: &str, transport: &dyn Transport, name: &str) -> Result<PypiProject> {
+    let url = format!("{}/pypi/{}/json", host, name);
+    let response = transport
+        .get(&url)
+        .context("Failed to contact pypi. Is your internet connection working?")?;
+    serde_json::from_reader(response).context("Invalid api response from pypi")
+}
+
+/// All files (wheels and source distributions) of a release
+pub(crate) fn release_files(
+    host: &str,
+    transport: &dyn Transport,
+    name: &str,
+    version: &str,
+) -> Result<Vec<PypiRelease>> {
+    let mut pypi_project = fetch_project(host, transport, name)?;
+    pypi_project
+        .releases
+        .remove(version)
+        .with_context(|| format!("{} {} not found on pypi", name, version))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::package_index::{
+        matching_package_for_version, search_find_links, PackageType, PreReleasePolicy,
+        PypiRelease, SourcePreference,
+    };
+    use crate::spec::DistributionType;
+    use install_wheel_rs::{Arch, CompatibleTags, Os};
+    use monotrail_utils::transport::MockTransport;
+    use pep440_rs::VersionSpecifiers;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_source_preference_lists() {
+        let from_lists = SourcePreference::from_lists;
+        assert_eq!(from_lists("numpy", "", ""), SourcePreference::Auto);
+        assert_eq!(
+            from_lists("numpy", "scipy,NumPy", ""),
+            SourcePreference::Sdist
+        );
+        assert_eq!(
+            from_lists("numpy", ":all:", "numpy"),
+            SourcePreference::Wheel
+        );
+        assert_eq!(from_lists("numpy", "", ":all:"), SourcePreference::Wheel);
+    }
+
+    #[test]
+    fn test_pre_release_lists() {
+        let policy = PreReleasePolicy::from_list("Black, flake8");
+        assert!(policy.allows("black"));
+        assert!(!policy.allows("numpy"));
+        assert_eq!(
+            PreReleasePolicy::from_list(""),
+            PreReleasePolicy::IfNecessary
+        );
+        assert_eq!(
+            PreReleasePolicy::from_list("black,:all:"),
+            PreReleasePolicy::All
+        );
+    }
+
+    #[test]
+    fn test_source_preference_selection() {
+        let release = |filename: &str, packagetype| PypiRelease {
+            filename: filename.to_string(),
+            packagetype,
+            python_version: "cp38".to_string(),
+            size: 0,
+            url: format!("https://example.org/{}", filename),
+            digests: HashMap::new(),
+            yanked: false,
+            yanked_reason: None,
+        };
+        let wheel = release(
+            "numpy-1.24.2-cp38-cp38-manylinux_2_17_x86_64.manylinux2014_x86_64.whl",
+            PackageType::BdistWheel,
+        );
+        let sdist = release("numpy-1.24.2.tar.gz", PackageType::Sdist);
+        let os = Os::Manylinux {
+            major: 2,
+            minor: 27,
+        };
+        let compatible_tags = CompatibleTags::new((3, 8), os, Arch::X86_64).unwrap();
+
+        let pick = |releases: &[PypiRelease], preference| {
+            matching_package_for_version(&compatible_tags, "1.24.2", releases, preference)
+                .unwrap()
+                .map(|(_, distribution_type, _)| distribution_type)
+        };
+        let both = [wheel.clone(), sdist.clone()];
+        assert_eq!(
+            pick(&both, SourcePreference::Auto),
+            Some(DistributionType::Wheel)
+        );
+        assert_eq!(
+            pick(&both, SourcePreference::Sdist),
+            Some(DistributionType::SourceDistribution)
+        );
+        assert_eq!(
+            pick(&[wheel], SourcePreference::Sdist),
+            Some(DistributionType::Wheel)
+        );
+        assert_eq!(pick(&[sdist], SourcePreference::Wheel), None);
+    }
+
+    #[test]
+    fn test_search_find_links() {
+        let find_links = ["../../test-data/pip-test-packages".to_string()];
+        let os = Os::Manylinux {
+            major: 2,
+            minor: 27,
+        };
+        let compatible_tags = CompatibleTags::new((3, 8), os, Arch::X86_64).unwrap();
+        let transport = MockTransport::default();
+        let search = |name: &str, version: Option<&str>| {
+            search_find_links(
+                &find_links,
+                &transport,
+                name,
+                version,
+                None,
+                &compatible_tags,
+            )
+        };
+
+        let (release, distribution_type, version) = search("simplewheel", None).unwrap().unwrap();
+        assert_eq!(version, "2.0");
+        assert_eq!(distribution_type, DistributionType::Wheel);
+        assert!(release.url.starts_with("file://"), "{}", release.url);
+        assert_eq!(release.digests["sha256"].len(), 64);
+        let (release, _, _) = search("simplewheel", Some("1.0")).unwrap().unwrap();
+        assert_eq!(release.filename, "simplewheel-1.0-py2.py3-none-any.whl");
+        let (_, distribution_type, _) = search("Simple2", Some("2.0")).unwrap().unwrap();
+        assert_eq!(distribution_type, DistributionType::SourceDistribution);
+        assert_eq!(
+            search("simplewheel", Some("3.0")).unwrap_err().to_string(),
+            "simplewheel 3.0 not found in --find-links ../../test-data/pip-test-packages"
+        );
+        // Not in the wheelhouse, so we ask pypi
+        assert!(search("tqdm", None).unwrap().is_none());
+
+        let specifiers = VersionSpecifiers::from_str("<2").unwrap();
+        let (_, _, version) = search_find_links(
+            &find_links,
+            &transport,
+            "simplewheel",
+            None,
+            Some(&specifiers),
+            &compatible_tags,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(version, "1.0");
+        assert!(transport.requests().is_empty());
+    }
 }
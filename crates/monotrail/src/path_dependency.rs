@@ -0,0 +1,203 @@
+//! Dependencies on a local project directory, wheel or source distribution, either as
+//! `name @ file:///path` or as poetry's `path = "..."`.
+//!
+//! They are built on every install like a git checkout, but instead of a commit the unique version
+//! is a fingerprint of the source, so `sync` and the wheel cache notice when they need to rebuild.
+//! The fingerprint covers the relative path, size and modification time of each file, which is
+//! much cheaper than hashing a project with all its data files and good enough for edits.
+
+use crate::build_frontend::{unpack_sdist, wheel_metadata};
+use crate::config_settings::ConfigSettings;
+use crate::monotrail::PythonContext;
+use crate::poetry_integration::poetry_toml::Dependency;
+use anyhow::{Context, Result};
+use fs_err::File;
+use install_wheel_rs::{cache_key, read_wheel_metadata, WheelFilename};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+/// Build output and caches that don't change what we'd build
+const IGNORED_DIRS: [&str; 4] = ["__pycache__", "build", "dist", "node_modules"];
+
+/// Relative paths are relative to `base`, the directory of the pyproject.toml declaring them
+pub(crate) fn absolute_path(path: &str, base: &Path) -> PathBuf {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        base.join(path)
+    }
+}
+
+/// Makes poetry's `path = "..."` dependencies absolute against `project_dir`, since the resolver
+/// works on a dummy project in another directory
+pub(crate) fn absolute_path_dependencies(
+    dependencies: BTreeMap<String, Dependency>,
+    project_dir: &Path,
+) -> BTreeMap<String, Dependency> {
+    dependencies
+        .into_iter()
+        .map(|(name, mut dependency)| {
+            if let Dependency::Expanded {
+                path: Some(path), ..
+            } = &mut dependency
+            {
+                *path = absolute_path(path, project_dir)
+                    .to_string_lossy()
+                    .to_string();
+            }
+            (name, dependency)
+        })
+        .collect()
+}
+
+/// The `[package.source]` type, `directory` for a project and `file` for a wheel or source
+/// distribution, same as poetry
+pub(crate) fn source_type(path: &Path) -> &'static str {
+    if path.is_dir() {
+        "directory"
+    } else {
+        "file"
+    }
+}
+
+/// Whether the `[package.source]` type is one of ours
+pub(crate) fn is_path_source(source_type: &str) -> bool {
+    matches!(source_type, "directory" | "file")
+}
+
+fn is_ignored(name: &str) -> bool {
+    name.starts_with('.') || name.ends_with(".egg-info") || IGNORED_DIRS.contains(&name)
+}
+
+/// Changes whenever a file in the project (or the file itself) is added, removed or modified
+pub(crate) fn source_fingerprint(path: &Path) -> Result<String> {
+    let mut entries = Vec::new();
+    let walker = WalkDir::new(path)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0 || !is_ignored(&entry.file_name().to_string_lossy())
+        });
+    for entry in walker {
+        let entry =
+            entry.with_context(|| format!("Failed to read the source of {}", path.display()))?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let metadata = entry.metadata()?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let relative = entry.path().strip_prefix(path).unwrap_or(entry.path());
+        entries.push(format!(
+            "{}\t{}\t{}",
+            relative.display(),
+            metadata.len(),
+            modified.as_nanos()
+        ));
+    }
+    Ok(cache_key(entries.join("\n")))
+}
+
+/// The `METADATA` of what we would install from the path
+pub(crate) fn path_metadata(
+    path: &Path,
+    python_context: &PythonContext,
+    config_settings: &ConfigSettings,
+) -> Result<String> {
+    let filename = path.file_name().unwrap_or_default().to_string_lossy();
+    if path.is_dir() {
+        wheel_metadata(path, python_context, config_settings)
+    } else if filename.ends_with(".whl") {
+        let filename = WheelFilename::from_str(&filename)?;
+        Ok(read_wheel_metadata(&filename, File::open(path)?)?)
+    } else {
+        let unpacked = TempDir::new()?;
+        let source_tree = unpack_sdist(path, unpacked.path())?;
+        wheel_metadata(&source_tree, python_context, config_settings)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::path_dependency::{absolute_path_dependencies, source_fingerprint};
+    use crate::poetry_integration::poetry_toml::Dependency;
+    use fs_err as fs;
+    use std::collections::BTreeMap;
+    use std::fs::File;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_source_fingerprint() {
+        let project = TempDir::new().unwrap();
+        fs::create_dir(project.path().join("pkg")).unwrap();
+        fs::write(project.path().join("pkg").join("__init__.py"), "").unwrap();
+        fs::write(project.path().join("pyproject.toml"), "[project]\n").unwrap();
+        let fingerprint = source_fingerprint(project.path()).unwrap();
+        assert_eq!(source_fingerprint(project.path()).unwrap(), fingerprint);
+
+        // Build output and caches don't count
+        fs::create_dir_all(project.path().join("pkg").join("__pycache__")).unwrap();
+        fs::write(
+            project
+                .path()
+                .join("pkg")
+                .join("__pycache__")
+                .join("__init__.pyc"),
+            "",
+        )
+        .unwrap();
+        fs::write(project.path().join(".gitignore"), "dist\n").unwrap();
+        assert_eq!(source_fingerprint(project.path()).unwrap(), fingerprint);
+
+        // An edit with a new modification time
+        let init = project.path().join("pkg").join("__init__.py");
+        fs::write(&init, "VERSION = 1").unwrap();
+        File::options()
+            .write(true)
+            .open(&init)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(10))
+            .unwrap();
+        assert_ne!(source_fingerprint(project.path()).unwrap(), fingerprint);
+    }
+
+    #[test]
+    fn test_absolute_path_dependencies() {
+        let path_dependency = |path: &str| Dependency::Expanded {
+            version: None,
+            optional: None,
+            extras: None,
+            git: None,
+            branch: None,
+            rev: None,
+            path: Some(path.to_string()),
+            allow_prereleases: None,
+        };
+        let dependencies = BTreeMap::from([
+            ("relative".to_string(), path_dependency("../relative")),
+            ("absolute".to_string(), path_dependency("/opt/absolute")),
+            ("pinned".to_string(), Dependency::Compact("1.0".to_string())),
+        ]);
+        let project_dir = Path::new("/home/ferris/app");
+        let dependencies = absolute_path_dependencies(dependencies, project_dir);
+        let path = |name: &str| match &dependencies[name] {
+            Dependency::Expanded { path, .. } => path.clone(),
+            Dependency::Compact(_) => None,
+        };
+        assert_eq!(
+            path("relative").unwrap(),
+            project_dir.join("../relative").to_string_lossy()
+        );
+        assert_eq!(path("absolute").unwrap(), "/opt/absolute");
+        assert_eq!(path("pinned"), None);
+    }
+}
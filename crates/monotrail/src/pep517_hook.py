@@ -0,0 +1,47 @@
+"""
+Calls a PEP 517 hook of the build backend in the current directory
+
+Usage: python pep517_hook.py <result.json> <backend> <backend-path json> <hook> <args json>
+
+The return value is written as `{"result": ...}` to the result file, so the backend can print
+whatever it wants to stdout and stderr.
+
+https://peps.python.org/pep-0517/
+"""
+import importlib
+import json
+import os
+import sys
+
+
+def load_backend(backend, backend_path):
+    # In-tree backends take precedence over the installed build requirements
+    for path in reversed(backend_path):
+        sys.path.insert(0, os.path.abspath(path))
+    module_name, _, object_path = backend.partition(":")
+    obj = importlib.import_module(module_name)
+    for attr in filter(None, object_path.split(".")):
+        obj = getattr(obj, attr)
+    return obj
+
+
+def main():
+    result_file, backend, backend_path, hook_name, args = sys.argv[1:]
+    obj = load_backend(backend, json.loads(backend_path))
+    hook = getattr(obj, hook_name, None)
+    if hook is not None:
+        result = hook(*json.loads(args))
+    elif hook_name.startswith("get_requires_for_build_"):
+        # The optional hooks default to no extra requirements
+        result = []
+    elif hook_name == "prepare_metadata_for_build_wheel":
+        # The frontend builds the wheel instead
+        result = None
+    else:
+        sys.exit(f"The build backend {backend} has no {hook_name} hook")
+    with open(result_file, "w") as fp:
+        json.dump({"result": result}, fp)
+
+
+if __name__ == "__main__":
+    main()
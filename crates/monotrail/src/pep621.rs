@@ -0,0 +1,367 @@
+//! PEP 621 `[project]` tables in pyproject.toml as a source of requirements, for projects that
+//! don't (or no longer) use poetry. The requirements are resolved with poetry just like a
+//! requirements.txt
+//!
+//! <https://peps.python.org/pep-0621/>
+
+use crate::markers::evaluate_marker;
+use crate::poetry_integration::poetry_toml;
+use crate::poetry_integration::read_dependencies::requirement_to_poetry;
+use anyhow::{bail, Context};
+use fs_err as fs;
+use install_wheel_rs::{normalize_name, Script};
+use pep508_rs::{MarkerEnvironment, Requirement};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::path::Path;
+use std::str::FromStr;
+
+/// The parts of pyproject.toml we read, the `[tool.poetry]` presence decides whether this is a
+/// poetry project
+#[derive(Deserialize, Debug)]
+struct PyprojectToml {
+    project: Option<ProjectSection>,
+    tool: Option<ToolSection>,
+    #[serde(rename = "dependency-groups", default)]
+    dependency_groups: BTreeMap<String, Vec<DependencyGroupEntry>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ToolSection {
+    poetry: Option<toml::Value>,
+}
+
+/// An entry of a list in `[dependency-groups]`, either a requirement or
+/// `{ include-group = "test" }`
+///
+/// <https://peps.python.org/pep-0735/>
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(untagged)]
+pub enum DependencyGroupEntry {
+    Requirement(String),
+    #[serde(rename_all = "kebab-case")]
+    Include {
+        include_group: String,
+    },
+}
+
+/// `[project]`
+#[derive(Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProjectSection {
+    pub name: String,
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    #[serde(default)]
+    pub optional_dependencies: BTreeMap<String, Vec<String>>,
+    #[serde(default)]
+    pub scripts: BTreeMap<String, String>,
+    /// The top level `[dependency-groups]` table of the pyproject.toml, with normalized names
+    #[serde(skip)]
+    pub dependency_groups: BTreeMap<String, Vec<DependencyGroupEntry>>,
+}
+
+impl ProjectSection {
+    /// The requirements of the dependency groups, with the included groups expanded
+    pub fn group_requirements(&self, groups: &[String]) -> anyhow::Result<Vec<Requirement>> {
+        let mut requirements = Vec::new();
+        for group in groups {
+            self.expand_group(&normalize_name(group), &mut Vec::new(), &mut requirements)?;
+        }
+        Ok(requirements)
+    }
+
+    /// `parents` are the groups that include this group, to detect cycles
+    fn expand_group(
+        &self,
+        group: &str,
+        parents: &mut Vec<String>,
+        requirements: &mut Vec<Requirement>,
+    ) -> anyhow::Result<()> {
+        if parents.iter().any(|parent| parent == group) {
+            bail!(
+                "Dependency group {} includes itself: {} -> {}",
+                group,
+                parents.join(" -> "),
+                group
+            );
+        }
+        let entries = self
+            .dependency_groups
+            .get(group)
+            .with_context(|| format!("No such dependency group {}", group))?;
+        parents.push(group.to_string());
+        for entry in entries {
+            match entry {
+                DependencyGroupEntry::Requirement(requirement) => {
+                    requirements.push(Requirement::from_str(requirement).with_context(|| {
+                        format!("Invalid requirement '{}' in group {}", requirement, group)
+                    })?);
+                }
+                DependencyGroupEntry::Include { include_group } => {
+                    self.expand_group(&normalize_name(include_group), parents, requirements)?;
+                }
+            }
+        }
+        parents.pop();
+        Ok(())
+    }
+
+    /// The requirements for the given extras and dependency groups that apply to `pep508_env`.
+    ///
+    /// Extras that refer to the project itself (e.g. `all = ["myproject[cli,test]"]`) are
+    /// expanded, also when a dependency group refers to the project.
+    pub fn requirements(
+        &self,
+        extras: &[String],
+        groups: &[String],
+        pep508_env: &MarkerEnvironment,
+    ) -> anyhow::Result<Vec<Requirement>> {
+        let project_name = normalize_name(&self.name);
+        let mut requirements = Vec::new();
+        let mut seen_extras = BTreeSet::new();
+        let mut queue: VecDeque<Option<String>> = extras.iter().cloned().map(Some).collect();
+        queue.push_front(None);
+        for requirement in self.group_requirements(groups)? {
+            if let Some(marker) = &requirement.marker {
+                if !evaluate_marker(marker, pep508_env, &[]) {
+                    continue;
+                }
+            }
+            if normalize_name(&requirement.name) == project_name {
+                queue.extend(requirement.extras.unwrap_or_default().into_iter().map(Some));
+                continue;
+            }
+            requirements.push(requirement);
+        }
+        while let Some(extra) = queue.pop_front() {
+            let group = match &extra {
+                None => &self.dependencies,
+                Some(extra) => {
+                    if !seen_extras.insert(extra.clone()) {
+                        continue;
+                    }
+                    self.optional_dependencies
+                        .get(extra)
+                        .with_context(|| format!("No such extra {}", extra))?
+                }
+            };
+            for requirement in group {
+                let requirement = Requirement::from_str(requirement)
+                    .with_context(|| format!("Invalid requirement '{}'", requirement))?;
+                // The markers of optional dependencies may also test for the extra
+                let active_extras: Vec<&str> = extra.iter().map(String::as_str).collect();
+                if let Some(marker) = &requirement.marker {
+                    if !evaluate_marker(marker, pep508_env, &active_extras) {
+                        continue;
+                    }
+                }
+                if normalize_name(&requirement.name) == project_name {
+                    queue.extend(requirement.extras.unwrap_or_default().into_iter().map(Some));
+                    continue;
+                }
+                requirements.push(requirement);
+            }
+        }
+        Ok(requirements)
+    }
+
+    /// [Self::requirements] in the poetry format. A package that is required multiple times
+    /// gets all version specifiers and extras merged.
+    pub fn requirements_for_poetry(
+        &self,
+        extras: &[String],
+        groups: &[String],
+        pep508_env: &MarkerEnvironment,
+    ) -> anyhow::Result<BTreeMap<String, poetry_toml::Dependency>> {
+        let mut poetry_requirements: BTreeMap<String, poetry_toml::Dependency> = BTreeMap::new();
+        for requirement in self.requirements(extras, groups, pep508_env)? {
+            let dependency = requirement_to_poetry(&requirement)?;
+            let name = normalize_name(&requirement.name);
+            let dependency = match poetry_requirements.remove(&name) {
+                Some(existing) => merge_dependencies(existing, dependency),
+                None => dependency,
+            };
+            poetry_requirements.insert(name, dependency);
+        }
+        Ok(poetry_requirements)
+    }
+
+    /// `[project.scripts]` in the format for [crate::monotrail::load_specs]
+    pub fn scripts(&self) -> anyhow::Result<BTreeMap<String, Script>> {
+        let mut scripts = BTreeMap::new();
+        for (name, value) in &self.scripts {
+            if let Some(script) = Script::from_value(name, value, None)? {
+                scripts.insert(name.clone(), script);
+            }
+        }
+        Ok(scripts)
+    }
+}
+
+/// Combines the version specifiers and extras of two requirements for the same package
+pub(crate) fn merge_dependencies(
+    left: poetry_toml::Dependency,
+    right: poetry_toml::Dependency,
+) -> poetry_toml::Dependency {
+    let version = |dependency: &poetry_toml::Dependency| match dependency {
+        poetry_toml::Dependency::Compact(version) => Some(version.clone()),
+        poetry_toml::Dependency::Expanded { version, .. } => version.clone(),
+    };
+    let versions: Vec<String> = [version(&left), version(&right)]
+        .into_iter()
+        .flatten()
+        .filter(|version| version != "*")
+        .collect();
+    let extras: BTreeSet<String> = left
+        .get_extras()
+        .iter()
+        .chain(right.get_extras())
+        .cloned()
+        .collect();
+    poetry_toml::Dependency::Expanded {
+        version: Some(if versions.is_empty() {
+            "*".to_string()
+        } else {
+            versions.join(",")
+        }),
+        optional: Some(false),
+        extras: Some(extras.into_iter().collect()),
+        git: None,
+        branch: None,
+        rev: None,
+        path: None,
+        allow_prereleases: (left.allows_prereleases() || right.allows_prereleases())
+            .then_some(true),
+    }
+}
+
+/// Returns the `[project]` section if the pyproject.toml in `dir` has one and isn't a poetry
+/// project
+pub fn read_pep621_project(dir: &Path) -> anyhow::Result<Option<ProjectSection>> {
+    let path = dir.join("pyproject.toml");
+    let pyproject_toml: PyprojectToml = toml::from_str(&fs::read_to_string(&path)?)
+        .with_context(|| format!("Invalid pyproject.toml at {}", path.display()))?;
+    if pyproject_toml
+        .tool
+        .is_some_and(|tool| tool.poetry.is_some())
+    {
+        return Ok(None);
+    }
+    Ok(pyproject_toml.project.map(|project| ProjectSection {
+        dependency_groups: pyproject_toml
+            .dependency_groups
+            .into_iter()
+            .map(|(name, entries)| (normalize_name(&name), entries))
+            .collect(),
+        ..project
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pep621::{read_pep621_project, DependencyGroupEntry};
+    use crate::poetry_integration::poetry_toml::Dependency;
+    use crate::test_utils::test_pep508_env;
+    use std::path::Path;
+
+    fn version(dependency: &Dependency) -> (&str, Vec<String>) {
+        match dependency {
+            Dependency::Compact(version) => (version, vec![]),
+            Dependency::Expanded {
+                version, extras, ..
+            } => (
+                version.as_deref().unwrap(),
+                extras.clone().unwrap_or_default(),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_pep621() {
+        let project = read_pep621_project(Path::new("../../test-data/pep621"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(project.scripts().unwrap().len(), 1);
+
+        let requirements = project
+            .requirements_for_poetry(&[], &[], &test_pep508_env())
+            .unwrap();
+        let requirements: Vec<_> = requirements
+            .iter()
+            .map(|(name, dependency)| (name.as_str(), version(dependency)))
+            .collect();
+        // pywin32 is windows only
+        assert_eq!(
+            requirements,
+            [
+                ("httpx", (">=0.23", vec![])),
+                ("tomli", (">=1.1.0", vec![]))
+            ]
+        );
+
+        let requirements = project
+            .requirements_for_poetry(&["all".to_string()], &[], &test_pep508_env())
+            .unwrap();
+        let requirements: Vec<_> = requirements
+            .iter()
+            .map(|(name, dependency)| (name.as_str(), version(dependency)))
+            .collect();
+        assert_eq!(
+            requirements,
+            [
+                ("httpx", (">=0.23,<1", vec!["http2".to_string()])),
+                ("pytest", (">=7", vec![])),
+                ("rich", ("*", vec![])),
+                ("tomli", (">=1.1.0", vec![]))
+            ]
+        );
+
+        let err = project
+            .requirements_for_poetry(&["docs".to_string()], &[], &test_pep508_env())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "No such extra docs");
+    }
+
+    #[test]
+    fn test_dependency_groups() {
+        let mut project = read_pep621_project(Path::new("../../test-data/pep621"))
+            .unwrap()
+            .unwrap();
+        // `dev` includes test and lint, test requires the project's cli extra
+        let requirements = project
+            .requirements_for_poetry(&[], &["dev".to_string()], &test_pep508_env())
+            .unwrap();
+        assert_eq!(
+            requirements.keys().collect::<Vec<_>>(),
+            ["httpx", "pytest", "rich", "ruff", "tomli"]
+        );
+
+        let err = project
+            .requirements_for_poetry(&[], &["docs".to_string()], &test_pep508_env())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "No such dependency group docs");
+
+        project.dependency_groups.insert(
+            "lint".to_string(),
+            vec![DependencyGroupEntry::Include {
+                include_group: "dev".to_string(),
+            }],
+        );
+        let err = project
+            .requirements_for_poetry(&[], &["dev".to_string()], &test_pep508_env())
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Dependency group dev includes itself: dev -> lint -> dev"
+        );
+    }
+
+    #[test]
+    fn test_poetry_project_is_not_pep621() {
+        assert_eq!(
+            read_pep621_project(Path::new("../../test-data/poetry-1.3-django")).unwrap(),
+            None
+        );
+    }
+}
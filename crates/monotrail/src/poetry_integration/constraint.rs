@@ -0,0 +1,171 @@
+//! Poetry version constraints such as `^1.2.3` and `~1.2` as PEP 440 specifiers and back
+//!
+//! <https://python-poetry.org/docs/dependency-specification/#version-constraints>
+
+use anyhow::{bail, format_err};
+use pep440_rs::{Operator, Version, VersionSpecifiers};
+use regex::Regex;
+use std::str::FromStr;
+
+/// The exclusive upper bound of a caret or tilde constraint: The release part at `index` is
+/// incremented, the later parts are zeroed, e.g. `1.2.3` and 0 gives `2.0.0`
+fn bump_at(version: &Version, index: usize) -> Version {
+    let release = version
+        .release
+        .iter()
+        .enumerate()
+        .map(|(position, part)| match position.cmp(&index) {
+            std::cmp::Ordering::Less => *part,
+            std::cmp::Ordering::Equal => part + 1,
+            std::cmp::Ordering::Greater => 0,
+        })
+        .collect();
+    Version::from_release(release)
+}
+
+/// `^1.2.3` allows updates that don't change the leftmost non-zero part: `<2.0.0`, while
+/// `^0.2.3` gives `<0.3.0` and `^0.0.3` gives `<0.0.4`
+fn caret_upper(version: &Version) -> Version {
+    let index = version
+        .release
+        .iter()
+        .position(|part| *part != 0)
+        .unwrap_or(version.release.len() - 1);
+    bump_at(version, index)
+}
+
+/// `~1.2.3` allows patch updates: `<1.3.0`, while `~1` gives `<2`
+fn tilde_upper(version: &Version) -> Version {
+    bump_at(version, usize::from(version.release.len() > 1))
+}
+
+/// Converts a poetry constraint into PEP 440 specifiers. Besides the PEP 440 operators, this
+/// supports `*`, bare versions (`1.2.3` is `==1.2.3`), `^`, `~`, and constraints separated by
+/// whitespace instead of commas. `||` has no PEP 440 equivalent and is rejected.
+pub fn poetry_to_pep440(constraint: &str) -> anyhow::Result<VersionSpecifiers> {
+    if constraint.contains("||") || constraint.contains('|') {
+        bail!(
+            "`{}` can't be expressed as PEP 440 specifiers since it contains an or (`||`)",
+            constraint
+        );
+    }
+    let part_re = Regex::new(r"^(\^|~=|~|===|==|!=|>=|<=|>|<|=)?\s*(\S+)$").unwrap();
+    // `>= 1.2, < 1.5` and `>=1.2 <1.5` are both allowed, so we first split on commas and then
+    // on whitespace that isn't between an operator and its version
+    let operator_re = Regex::new(r"(\^|~=|~|===|==|!=|>=|<=|>|<|=)\s+").unwrap();
+    let mut specifiers = Vec::new();
+    for part in constraint.split(',').flat_map(|part| {
+        operator_re
+            .replace_all(part, "$1")
+            .split_whitespace()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+    }) {
+        let captures = part_re
+            .captures(&part)
+            .ok_or_else(|| format_err!("Invalid version constraint `{}`", constraint))?;
+        let operator = captures.get(1).map_or("", |operator| operator.as_str());
+        let version = &captures[2];
+        match operator {
+            "" | "=" if version == "*" => {}
+            "^" | "~" => {
+                let parsed = Version::from_str(version)
+                    .map_err(|err| format_err!("Invalid version `{}`: {}", version, err))?;
+                let upper = if operator == "^" {
+                    caret_upper(&parsed)
+                } else {
+                    tilde_upper(&parsed)
+                };
+                specifiers.push(format!(">={}", version));
+                specifiers.push(format!("<{}", upper));
+            }
+            "" | "=" => specifiers.push(format!("=={}", version)),
+            operator => specifiers.push(format!("{}{}", operator, version)),
+        }
+    }
+    VersionSpecifiers::from_str(&specifiers.join(", "))
+        .map_err(|err| format_err!("Invalid version constraint `{}`: {}", constraint, err))
+}
+
+/// Converts PEP 440 specifiers into the constraint poetry would write, e.g. `>=1.2.3, <2.0.0`
+/// becomes `^1.2.3` and `==1.2.3` becomes `1.2.3`. Everything else is already valid for poetry.
+pub fn pep440_to_poetry(specifiers: &VersionSpecifiers) -> String {
+    match &**specifiers {
+        [] => "*".to_string(),
+        [pin] if *pin.operator() == Operator::Equal => pin.version().to_string(),
+        [lower, upper]
+            if *lower.operator() == Operator::GreaterThanEqual
+                && *upper.operator() == Operator::LessThan
+                && lower.version().pre.is_none()
+                && lower.version().dev.is_none() =>
+        {
+            if upper.version() == &caret_upper(lower.version()) {
+                format!("^{}", lower.version())
+            } else if upper.version() == &tilde_upper(lower.version()) {
+                format!("~{}", lower.version())
+            } else {
+                specifiers.to_string()
+            }
+        }
+        _ => specifiers.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::poetry_integration::constraint::{pep440_to_poetry, poetry_to_pep440};
+
+    #[test]
+    fn test_poetry_to_pep440() {
+        let cases = [
+            ("*", ""),
+            ("1.2.3", "==1.2.3"),
+            ("^1.2.3", ">=1.2.3, <2.0.0"),
+            ("^1.2", ">=1.2, <2.0"),
+            ("^1", ">=1, <2"),
+            ("^0.2.3", ">=0.2.3, <0.3.0"),
+            ("^0.0.3", ">=0.0.3, <0.0.4"),
+            ("^0.0", ">=0.0, <0.1"),
+            ("^0", ">=0, <1"),
+            ("~1.2.3", ">=1.2.3, <1.3.0"),
+            ("~1.2", ">=1.2, <1.3"),
+            ("~1", ">=1, <2"),
+            ("~=1.2", "~=1.2"),
+            ("1.2.*", "==1.2.*"),
+            (">= 1.2, < 1.5", ">=1.2, <1.5"),
+            (">=1.2 <1.5 !=1.3", ">=1.2, <1.5, !=1.3"),
+            ("^2.0.0b1", ">=2.0.0b1, <3.0.0"),
+        ];
+        for (poetry, pep440) in cases {
+            assert_eq!(
+                poetry_to_pep440(poetry).unwrap().to_string(),
+                pep440,
+                "{}",
+                poetry
+            );
+        }
+        assert_eq!(
+            poetry_to_pep440("^1.2 || ^2.1").unwrap_err().to_string(),
+            "`^1.2 || ^2.1` can't be expressed as PEP 440 specifiers since it contains an or (`||`)"
+        );
+        assert!(poetry_to_pep440("^foo").is_err());
+    }
+
+    #[test]
+    fn test_pep440_to_poetry() {
+        let cases = [
+            ("*", "*"),
+            ("1.2.3", "1.2.3"),
+            ("^1.2.3", "^1.2.3"),
+            ("^0.2.3", "^0.2.3"),
+            ("~1.2.3", "~1.2.3"),
+            ("~1.2", "~1.2"),
+            (">=1.2, <1.5", ">=1.2, <1.5"),
+            ("^2.0.0b1", ">=2.0.0b1, <3.0.0"),
+        ];
+        for (poetry, roundtrip) in cases {
+            let specifiers = poetry_to_pep440(poetry).unwrap();
+            assert_eq!(pep440_to_poetry(&specifiers), roundtrip, "{}", poetry);
+        }
+    }
+}
@@ -1,21 +1,27 @@
 //! calls to poetry to resolve a set of requirements
 
+use crate::http_cache::{is_offline, HttpCache};
+use crate::index::IndexUrls;
 use crate::monotrail::{install_missing, LaunchType, PythonContext};
+use crate::package_index::PreReleasePolicy;
 use crate::poetry_integration::poetry_lock::PoetryLock;
 use crate::poetry_integration::poetry_toml;
 use crate::poetry_integration::poetry_toml::{PoetryPyprojectToml, PoetrySection};
 use crate::poetry_integration::read_dependencies::read_toml_files;
+use crate::progress::{self, Phase};
 use crate::read_poetry_specs;
+use crate::resolver::{native_resolve, native_resolve_roots, ResolverChoice, ResolverOptions};
 use crate::utils::cache_dir;
 use anyhow::{bail, format_err, Context};
 use fs_err as fs;
+use monotrail_utils::transport::UreqTransport;
 use std::collections::BTreeMap;
 use std::default::Default;
 use std::process::Command;
 use std::time::Instant;
 use std::{env, io};
 use tempfile::{tempdir, TempDir};
-use tracing::{debug, span, Level};
+use tracing::{debug, span, warn, Level};
 
 /// Minimal dummy pyproject.toml with the user requested deps for poetry to resolve
 pub fn dummy_poetry_pyproject_toml(
@@ -23,6 +29,14 @@ pub fn dummy_poetry_pyproject_toml(
     python_version: (u8, u8),
 ) -> PoetryPyprojectToml {
     let mut dependencies = dependencies.clone();
+    // Poetry only allows pre-releases per dependency, so for transitive dependencies it only
+    // uses them when there is no final release
+    let pre_releases = PreReleasePolicy::current();
+    for (name, dependency) in &mut dependencies {
+        if pre_releases.allows(name) {
+            dependency.allow_prereleases();
+        }
+    }
     // Add python entry with current version; resolving will otherwise fail with complaints
     dependencies.insert(
         "python".to_string(),
@@ -44,6 +58,7 @@ pub fn dummy_poetry_pyproject_toml(
                 authors: vec!["konstin <konstin@mailbox.org>".to_string()],
                 dependencies,
                 dev_dependencies: None,
+                group: None,
                 extras: Some(BTreeMap::new()),
                 scripts: None,
             }),
@@ -52,12 +67,48 @@ pub fn dummy_poetry_pyproject_toml(
     }
 }
 
-/// Calls poetry to resolve the user specified dependencies into a set of locked consistent
-/// dependencies. Produces a poetry.lock in the process
+/// Resolves the user specified dependencies into a set of locked consistent dependencies.
+/// Produces a poetry.lock in the process
+///
+/// Uses the native resolver and falls back to calling poetry if that fails, see
+/// [ResolverChoice]
 pub fn poetry_resolve(
     dependencies: &BTreeMap<String, poetry_toml::Dependency>,
     lockfile: Option<&str>,
     python_context: &PythonContext,
+) -> anyhow::Result<(PoetrySection, PoetryLock, String)> {
+    let choice = ResolverChoice::from_env()?;
+    if choice != ResolverChoice::Poetry {
+        progress::phase_started(Phase::Resolve, None);
+        let resolved = native_resolve(
+            dependencies,
+            lockfile,
+            python_context.version,
+            &python_context.pep508_env,
+            &python_context.sys_executable,
+            &IndexUrls::from_env(),
+            &HttpCache::new(&UreqTransport)?,
+            &ResolverOptions::current(),
+        );
+        progress::phase_finished(Phase::Resolve);
+        match resolved {
+            Ok(resolved) => return Ok(resolved),
+            // poetry would use the network
+            Err(err) if choice == ResolverChoice::Auto && !is_offline() => {
+                warn!("Native resolver failed, falling back to poetry: {:#}", err);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    poetry_resolve_subprocess(dependencies, lockfile, python_context)
+}
+
+/// Calls poetry to resolve the dependencies
+fn poetry_resolve_subprocess(
+    dependencies: &BTreeMap<String, poetry_toml::Dependency>,
+    lockfile: Option<&str>,
+    python_context: &PythonContext,
 ) -> anyhow::Result<(PoetrySection, PoetryLock, String)> {
     // Write a dummy poetry pyproject.toml with the requested dependencies
     let resolve_dir = tempdir()?;
@@ -76,7 +127,10 @@ pub fn poetry_resolve(
         fs::write(&poetry_lock_path, lockfile)?;
     }
 
-    poetry_resolve_from_dir(&resolve_dir, &python_context)?;
+    progress::phase_started(Phase::Resolve, None);
+    let resolved = poetry_resolve_from_dir(&resolve_dir, &python_context);
+    progress::phase_finished(Phase::Resolve);
+    resolved?;
     // read back the pyproject.toml and compare, just to be sure
     let pyproject_toml_reread = toml::from_str(&fs::read_to_string(pyproject_toml_path)?)?;
     if pyproject_toml_content != pyproject_toml_reread {
@@ -91,8 +145,59 @@ pub fn poetry_resolve(
     Ok((poetry_section, poetry_lock, lockfile))
 }
 
+/// Resolves multiple sets of requirements (e.g. the project, the linters and the build tools) in one
+/// pass, but each as its own root, so they don't constrain each other. This way black can use a
+/// different click than the one pinned by the project.
+///
+/// poetry can only resolve one root at a time, so if we fall back to it, it runs once per root
+pub fn poetry_resolve_roots(
+    roots: &[BTreeMap<String, poetry_toml::Dependency>],
+    python_context: &PythonContext,
+) -> anyhow::Result<Vec<(PoetrySection, PoetryLock, String)>> {
+    let choice = ResolverChoice::from_env()?;
+    if choice != ResolverChoice::Poetry {
+        let native_roots: Vec<_> = roots
+            .iter()
+            .map(|dependencies| (dependencies, None))
+            .collect();
+        progress::phase_started(Phase::Resolve, None);
+        let resolved = native_resolve_roots(
+            &native_roots,
+            python_context.version,
+            &python_context.pep508_env,
+            &python_context.sys_executable,
+            &IndexUrls::from_env(),
+            &HttpCache::new(&UreqTransport)?,
+            &ResolverOptions::current(),
+        );
+        progress::phase_finished(Phase::Resolve);
+        match resolved {
+            Ok(resolved) => return Ok(resolved),
+            Err(err) if choice == ResolverChoice::Auto && !is_offline() => {
+                warn!("Native resolver failed, falling back to poetry: {:#}", err);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    roots
+        .iter()
+        .enumerate()
+        .map(|(index, dependencies)| {
+            debug!(
+                "Resolving root {} of {} with poetry",
+                index + 1,
+                roots.len()
+            );
+            poetry_resolve_subprocess(dependencies, None, python_context)
+                .with_context(|| format!("Failed to resolve root {}", index + 1))
+        })
+        .collect()
+}
+
 /// Runs `poetry lock --no-update` in the given tempdir, which needs to contain a pyproject.toml
 /// and optionally a poetry.lock
+#[cfg_attr(feature = "instrument", tracing::instrument(level = "debug", skip_all))]
 pub fn poetry_resolve_from_dir(
     resolve_dir: &TempDir,
     python_context: &PythonContext,
@@ -121,6 +226,7 @@ pub fn poetry_resolve_from_dir(
     let specs = read_poetry_specs(
         &poetry_section,
         poetry_lock,
+        &poetry_boostrap_lock,
         false,
         &[],
         &python_context.pep508_env,
@@ -1,5 +1,6 @@
 //! Read poetry.toml/poetry.lock and run poetry to resolve dependencies
 
+pub mod constraint;
 pub mod lock;
 pub mod poetry_lock;
 pub mod poetry_toml;
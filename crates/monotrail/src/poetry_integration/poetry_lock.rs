@@ -1,11 +1,51 @@
 //! Types for poetry.lock
 
+use crate::config_settings::PackageConfigSettings;
+use crate::markers::evaluate_marker;
+use crate::package_index::SourcePreference;
 use anyhow::bail;
+use install_wheel_rs::{integrity_digest, to_hex};
 use pep508_rs::{MarkerEnvironment, MarkerTree};
 use regex::Regex;
 use serde::Deserialize;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
 use std::str::FromStr;
+use tracing::warn;
+
+/// The lock versions we know: 1.1 up to poetry 1.2, 2.0 since poetry 1.3 and 2.1 since poetry 2.0
+const LOCK_VERSIONS: [&str; 3] = ["1.1", "2.0", "2.1"];
+
+/// The keys of `[tool.poetry]` that go into the content-hash. The legacy keys are hashed as null
+/// if they're missing, the others are skipped
+const HASHED_POETRY_KEYS: [&str; 5] = [
+    "dependencies",
+    "source",
+    "extras",
+    "dev-dependencies",
+    "group",
+];
+const LEGACY_POETRY_KEYS: [&str; 4] = ["dependencies", "source", "extras", "dev-dependencies"];
+/// The keys of `[project]` that go into the content-hash since poetry 2.0
+const HASHED_PROJECT_KEYS: [&str; 3] = ["requires-python", "dependencies", "optional-dependencies"];
+
+/// poetry.lock doesn't match pyproject.toml anymore. Callers can downcast to this to lock again
+/// instead of installing stale pins
+#[derive(Debug, thiserror::Error)]
+pub enum LockOutdated {
+    #[error(
+        "poetry.lock is outdated (run `poetry lock`): pyproject.toml changed since it was locked \
+        (content-hash {locked} in poetry.lock, but {current} for pyproject.toml)"
+    )]
+    ContentHash { locked: String, current: String },
+    #[error("Lockfile outdated (run `poetry update`): {0} is missing")]
+    MissingPackage(String),
+    #[error(
+        "Lockfile outdated (lock again): {name} {version} is locked without a wheel, but \
+        MONOTRAIL_ONLY_BINARY forbids building it from source"
+    )]
+    OnlyBinary { name: String, version: String },
+}
 
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
@@ -17,15 +57,35 @@ pub struct PoetryLock {
 impl PoetryLock {
     pub fn from_str(data: &str) -> anyhow::Result<Self> {
         let lockfile: Self = toml::from_str(data)?;
-        if lockfile.metadata.lock_version != "1.1" && lockfile.metadata.lock_version != "2.0" {
-            bail!(
-                "Unsupported poetry.lock version {}",
-                lockfile.metadata.lock_version
-            )
+        let lock_version = &lockfile.metadata.lock_version;
+        if !LOCK_VERSIONS.contains(&lock_version.as_str()) {
+            // Like poetry, we try newer minor versions, they are meant to be compatible
+            if lock_version.starts_with("2.") {
+                warn!(
+                    "poetry.lock version {} is newer than the supported versions, \
+                    this might not work",
+                    lock_version
+                );
+            } else {
+                bail!("Unsupported poetry.lock version {}", lock_version)
+            }
         }
         Ok(lockfile)
     }
 
+    /// Checks that the lockfile was created from this pyproject.toml, i.e. that the
+    /// dependency declarations didn't change since
+    pub fn verify_content_hash(&self, pyproject_toml: &toml::Value) -> Result<(), LockOutdated> {
+        let current = content_hash(pyproject_toml);
+        if self.metadata.content_hash != current {
+            return Err(LockOutdated::ContentHash {
+                locked: self.metadata.content_hash.clone(),
+                current,
+            });
+        }
+        Ok(())
+    }
+
     /// Abstract over lock_version 1.1 and 2.0, which change in poetry 1.3
     ///
     /// In 1.1 the filenames and the hashes were separately in the metadata table, while in 2.0
@@ -44,6 +104,122 @@ impl PoetryLock {
     }
 }
 
+/// poetry's content-hash of a pyproject.toml: The sha256 of the dependency declarations as
+/// `json.dumps(relevant_content, sort_keys=True)`
+pub fn content_hash(pyproject_toml: &toml::Value) -> String {
+    let table = |value: Option<&toml::Value>| value.and_then(toml::Value::as_table).cloned();
+    let project = table(pyproject_toml.get("project")).unwrap_or_default();
+    let poetry = table(
+        pyproject_toml
+            .get("tool")
+            .and_then(|tool| tool.get("poetry")),
+    )
+    .unwrap_or_default();
+
+    let relevant_project: serde_json::Map<String, serde_json::Value> = HASHED_PROJECT_KEYS
+        .iter()
+        .filter_map(|key| Some((key.to_string(), toml_to_json(project.get(*key)?))))
+        .collect();
+    let mut relevant_poetry = serde_json::Map::new();
+    for key in HASHED_POETRY_KEYS {
+        match poetry.get(key) {
+            Some(value) => {
+                relevant_poetry.insert(key.to_string(), toml_to_json(value));
+            }
+            // With a `[project]` section, poetry 2 skips the missing legacy keys too
+            None if LEGACY_POETRY_KEYS.contains(&key) && relevant_project.is_empty() => {
+                relevant_poetry.insert(key.to_string(), serde_json::Value::Null);
+            }
+            None => {}
+        }
+    }
+    let relevant = if relevant_project.is_empty() {
+        serde_json::Value::Object(relevant_poetry)
+    } else {
+        serde_json::json!({
+            "project": relevant_project,
+            "tool": {"poetry": relevant_poetry},
+        })
+    };
+
+    let mut json = String::new();
+    python_json_dumps(&relevant, &mut json);
+    // poetry's content-hash is sha256, which the integrity backend computes
+    to_hex(&integrity_digest(json.as_bytes()))
+}
+
+fn toml_to_json(value: &toml::Value) -> serde_json::Value {
+    match value {
+        toml::Value::String(string) => serde_json::Value::String(string.clone()),
+        toml::Value::Integer(integer) => serde_json::Value::from(*integer),
+        toml::Value::Float(float) => serde_json::Value::from(*float),
+        toml::Value::Boolean(boolean) => serde_json::Value::Bool(*boolean),
+        toml::Value::Datetime(datetime) => serde_json::Value::String(datetime.to_string()),
+        toml::Value::Array(array) => array.iter().map(toml_to_json).collect(),
+        toml::Value::Table(table) => serde_json::Value::Object(
+            table
+                .iter()
+                .map(|(key, value)| (key.clone(), toml_to_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Python's `json.dumps(value, sort_keys=True)`, which separates with `, ` and `: ` and escapes
+/// everything that isn't ascii
+fn python_json_dumps(value: &serde_json::Value, out: &mut String) {
+    match value {
+        serde_json::Value::Null => out.push_str("null"),
+        serde_json::Value::Bool(boolean) => out.push_str(if *boolean { "true" } else { "false" }),
+        serde_json::Value::Number(number) => out.push_str(&number.to_string()),
+        serde_json::Value::String(string) => {
+            out.push('"');
+            for char in string.chars() {
+                match char {
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    '\n' => out.push_str("\\n"),
+                    '\r' => out.push_str("\\r"),
+                    '\t' => out.push_str("\\t"),
+                    '\u{8}' => out.push_str("\\b"),
+                    '\u{c}' => out.push_str("\\f"),
+                    ' '..='\u{7f}' => out.push(char),
+                    _ => {
+                        for unit in char.encode_utf16(&mut [0; 2]) {
+                            write!(out, "\\u{:04x}", unit).unwrap();
+                        }
+                    }
+                }
+            }
+            out.push('"');
+        }
+        serde_json::Value::Array(array) => {
+            out.push('[');
+            for (index, item) in array.iter().enumerate() {
+                if index > 0 {
+                    out.push_str(", ");
+                }
+                python_json_dumps(item, out);
+            }
+            out.push(']');
+        }
+        serde_json::Value::Object(object) => {
+            let mut entries: Vec<_> = object.iter().collect();
+            entries.sort_by_key(|(key, _)| *key);
+            out.push('{');
+            for (index, (key, item)) in entries.into_iter().enumerate() {
+                if index > 0 {
+                    out.push_str(", ");
+                }
+                python_json_dumps(&serde_json::Value::String(key.clone()), out);
+                out.push_str(": ");
+                python_json_dumps(item, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
 /// `[[package]]`
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
@@ -51,9 +227,13 @@ impl PoetryLock {
 pub struct Package {
     pub name: String,
     pub version: String,
+    #[serde(default)]
     pub description: String,
+    /// Only up to poetry 1.4
     pub category: Option<String>,
+    #[serde(default)]
     pub optional: bool,
+    #[serde(default = "any_python")]
     pub python_versions: String,
     #[serde(default)]
     pub extras: HashMap<String, Vec<String>>,
@@ -65,6 +245,10 @@ pub struct Package {
     pub files: Option<Vec<HashedFile>>,
 }
 
+fn any_python() -> String {
+    "*".to_string()
+}
+
 /// e.g. `{version = ">=1.21.0", markers = "python_version >= \"3.10\""}`
 #[derive(Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
@@ -105,78 +289,69 @@ impl Dependency {
         environment: &MarkerEnvironment,
         self_extras: &HashSet<String>,
     ) -> Result<Option<(String, Vec<String>)>, String> {
+        Ok(self
+            .matching_option(environment, self_extras)?
+            .map(|option| (option.version, option.extras.unwrap_or_default())))
+    }
+
+    /// Like [Dependency::get_version_and_extras], but returns the whole matching entry including
+    /// its markers
+    pub fn matching_option(
+        &self,
+        environment: &MarkerEnvironment,
+        self_extras: &HashSet<String>,
+    ) -> Result<Option<DependencyExpanded>, String> {
         let extra_re = Regex::new(r#"^extra == "([\w\d_-]+)"$"#).unwrap();
         let self_extras_vec: Vec<&str> = self_extras.iter().map(|str| str.as_str()).collect();
-
-        Ok(match self {
-            Dependency::Compact(version) => Some((version.to_string(), Vec::new())),
-            Dependency::Expanded(DependencyExpanded {
-                version,
-                markers,
-                extras,
-            }) => {
-                if let Some(markers) = markers {
-                    if let Some(captures) = extra_re.captures(markers) {
-                        if self_extras.contains(&captures[1].to_string()) {
-                            Some((version.to_string(), extras.clone().unwrap_or_default()))
-                        } else {
-                            None
-                        }
-                    } else if MarkerTree::from_str(markers)
-                        .unwrap()
-                        .evaluate(environment, &self_extras_vec)
-                    {
-                        Some((version.to_string(), extras.clone().unwrap_or_default()))
-                    } else {
-                        None
-                    }
-                } else {
-                    Some((version.to_string(), extras.clone().unwrap_or_default()))
-                }
+        let options = match self {
+            Dependency::Compact(version) => {
+                return Ok(Some(DependencyExpanded {
+                    version: version.to_string(),
+                    markers: None,
+                    extras: None,
+                }))
             }
-            Dependency::List(options) => {
-                for option in options {
-                    if let Some(markers) = &option.markers {
-                        if let Some(captures) = extra_re.captures(markers) {
-                            if self_extras.contains(&captures[1].to_string()) {
-                                return Ok(Some((
-                                    option.version.to_string(),
-                                    option.extras.clone().unwrap_or_default(),
-                                )));
-                            } else {
-                                continue;
-                            };
-                        }
-                        if MarkerTree::from_str(markers)
-                            .unwrap()
-                            .evaluate(environment, &self_extras_vec)
-                        {
-                            return Ok(Some((
-                                option.version.to_string(),
-                                option.extras.clone().unwrap_or_default(),
-                            )));
-                        }
+            Dependency::Expanded(option) => std::slice::from_ref(option),
+            Dependency::List(options) => options.as_slice(),
+        };
+        for option in options {
+            if let Some(markers) = &option.markers {
+                if let Some(captures) = extra_re.captures(markers) {
+                    if self_extras.contains(&captures[1].to_string()) {
+                        return Ok(Some(option.clone()));
                     } else {
-                        return Ok(Some((
-                            option.version.to_string(),
-                            option.extras.clone().unwrap_or_default(),
-                        )));
-                    }
+                        continue;
+                    };
                 }
-                None
+                if evaluate_marker(&parse_marker(markers)?, environment, &self_extras_vec) {
+                    return Ok(Some(option.clone()));
+                }
+            } else {
+                return Ok(Some(option.clone()));
             }
-        })
+        }
+        Ok(None)
     }
 }
 
+/// An invalid marker in the lockfile should be an error and not a panic
+fn parse_marker(markers: &str) -> Result<MarkerTree, String> {
+    MarkerTree::from_str(markers).map_err(|err| format!("Invalid marker `{}`: {}", markers, err))
+}
+
 /// `[[package]] [package.source]`
+///
+/// Besides git, this can be a `legacy` index, where `reference` is the name of the index and
+/// there is no `resolved_reference`, or a `url`, `file` or `directory`
 #[derive(Deserialize, Debug, Clone)]
 #[allow(dead_code)]
 pub struct Source {
     #[serde(rename = "type")]
     pub source_type: String,
     pub url: String,
+    #[serde(default)]
     pub reference: String,
+    #[serde(default)]
     pub resolved_reference: String,
 }
 
@@ -191,6 +366,12 @@ pub struct Metadata {
     /// `[metadata.files]`
     /// Only in lock_version 1.1, in version 2.0/poetry 1.3 it's in each package
     pub files: Option<HashMap<String, Vec<HashedFile>>>,
+    /// Only written by our resolver, see [crate::config_settings]
+    #[serde(default)]
+    pub config_settings: PackageConfigSettings,
+    /// Only written by our resolver, see [SourcePreference]
+    #[serde(default)]
+    pub source_preferences: BTreeMap<String, SourcePreference>,
 }
 
 /// e.g. `{file = "attrs-21.4.0-py2.py3-none-any.whl", hash = "sha256:2d27e3784d7a565d36ab851fe94887c5eccd6a463168875832a1be79c82828b4"}`
@@ -204,7 +385,8 @@ pub struct HashedFile {
 
 #[cfg(test)]
 mod test {
-    use crate::poetry_integration::poetry_lock::PoetryLock;
+    use crate::poetry_integration::poetry_lock::{content_hash, LockOutdated, PoetryLock};
+    use indoc::indoc;
     use std::fs;
     use std::path::Path;
 
@@ -231,4 +413,97 @@ mod test {
     fn poetry_1_3() {
         assert_eq!(get_filenames("poetry-1.3-django/poetry.lock", "django"), 2);
     }
+
+    #[test]
+    fn test_content_hash() {
+        for dir in [
+            "poetry-1.1.13",
+            "poetry-1.2.0b1",
+            "poetry-1.3-django",
+            "poetry/black",
+            "poetry/data-science",
+            "poetry/ibis",
+            "poetry/mst",
+        ] {
+            let dir = Path::new("../../test-data").join(dir);
+            let pyproject_toml =
+                toml::from_str(&fs::read_to_string(dir.join("pyproject.toml")).unwrap()).unwrap();
+            let lockfile =
+                PoetryLock::from_str(&fs::read_to_string(dir.join("poetry.lock")).unwrap())
+                    .unwrap();
+            lockfile
+                .verify_content_hash(&pyproject_toml)
+                .unwrap_or_else(|err| panic!("{}: {}", dir.display(), err));
+        }
+
+        let dir = Path::new("../../test-data/lockfile-outdated");
+        let pyproject_toml =
+            toml::from_str(&fs::read_to_string(dir.join("pyproject.toml")).unwrap()).unwrap();
+        let lockfile =
+            PoetryLock::from_str(&fs::read_to_string(dir.join("poetry.lock")).unwrap()).unwrap();
+        let err = lockfile.verify_content_hash(&pyproject_toml).unwrap_err();
+        assert!(matches!(err, LockOutdated::ContentHash { .. }));
+
+        // Non-ascii is escaped like in python's json
+        let poetry_1 = toml::from_str(indoc! {r#"
+            [tool.poetry.dependencies]
+            python = "^3.8"
+            "ümlaut" = "*"
+        "#})
+        .unwrap();
+        assert_eq!(
+            content_hash(&poetry_1),
+            "073f6cadef06592efb065b05a4a3c11601fa286ef54e8428e5150dfb706c782a"
+        );
+        // poetry 2 also hashes [project], and skips the missing legacy keys then
+        let poetry_2 = toml::from_str(indoc! {r#"
+            [project]
+            name = "foo"
+            requires-python = ">=3.9"
+            dependencies = ["tqdm>=4", "boltons (>=21,<22)"]
+
+            [tool.poetry.group.dev.dependencies]
+            pytest = "^8"
+        "#})
+        .unwrap();
+        assert_eq!(
+            content_hash(&poetry_2),
+            "f52c1c9f28284ff4dffa02d61bcdea8a1d51b6706e96e9d64f3765c75a4f67ef"
+        );
+    }
+
+    #[test]
+    fn test_lock_versions() {
+        // poetry 2 drops fields it doesn't need and legacy sources have no resolved_reference
+        let lock_2_1 = indoc! {r#"
+            [[package]]
+            name = "tqdm"
+            version = "4.66.1"
+            groups = ["main"]
+            files = []
+
+            [package.source]
+            type = "legacy"
+            url = "https://pypi.corp/simple"
+            reference = "corp"
+
+            [metadata]
+            lock-version = "2.1"
+            python-versions = ">=3.9"
+            content-hash = "0000"
+        "#};
+        let lockfile = PoetryLock::from_str(lock_2_1).unwrap();
+        assert_eq!(lockfile.package[0].python_versions, "*");
+        assert_eq!(
+            lockfile.package[0].source.as_ref().unwrap().reference,
+            "corp"
+        );
+        assert!(PoetryLock::from_str(&lock_2_1.replace("\"2.1\"", "\"2.2\"")).is_ok());
+        assert_eq!(
+            PoetryLock::from_str(&lock_2_1.replace("\"2.1\"", "\"3.0\""))
+                .unwrap_err()
+                .to_string(),
+            "Unsupported poetry.lock version 3.0"
+        );
+    }
 }
@@ -60,6 +60,12 @@ pub enum Dependency {
         extras: Option<Vec<String>>,
         git: Option<String>,
         branch: Option<String>,
+        /// A commit or tag of `git`
+        rev: Option<String>,
+        /// A local project directory, wheel or source distribution
+        path: Option<String>,
+        #[serde(rename = "allow-prereleases")]
+        allow_prereleases: Option<bool>,
     },
 }
 
@@ -77,11 +83,59 @@ impl Dependency {
             Dependency::Expanded { extras, .. } => extras.as_deref().unwrap_or_default(),
         }
     }
+
+    /// `allow-prereleases = true`
+    pub fn allows_prereleases(&self) -> bool {
+        match self {
+            Dependency::Compact(_) => false,
+            Dependency::Expanded {
+                allow_prereleases, ..
+            } => allow_prereleases.unwrap_or(false),
+        }
+    }
+
+    /// Sets `allow-prereleases = true`, expanding a compact dependency
+    pub fn allow_prereleases(&mut self) {
+        match self {
+            Dependency::Compact(version) => {
+                *self = Dependency::Expanded {
+                    version: Some(version.clone()),
+                    optional: None,
+                    extras: None,
+                    git: None,
+                    branch: None,
+                    rev: None,
+                    path: None,
+                    allow_prereleases: Some(true),
+                }
+            }
+            Dependency::Expanded {
+                allow_prereleases, ..
+            } => *allow_prereleases = Some(true),
+        }
+    }
+}
+
+/// ```toml
+/// [tool.poetry.group.test]
+/// optional = true
+///
+/// [tool.poetry.group.test.dependencies]
+/// pytest = "^7.0.0"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct DependencyGroup {
+    /// Optional groups are only installed when requested
+    pub optional: Option<bool>,
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, Dependency>,
 }
 
 /// ```toml
 /// [tool.poetry.dependencies]
 /// [tool.poetry.dev-dependencies]
+/// [tool.poetry.group.<name>.dependencies]
 /// [tool.poetry.extras]
 /// ``
 ///
@@ -96,6 +150,8 @@ pub struct PoetrySection {
     pub authors: Vec<String>,
     pub dependencies: BTreeMap<String, Dependency>,
     pub dev_dependencies: Option<BTreeMap<String, Dependency>>,
+    /// Dependency groups, poetry 1.2+ puts the dev dependencies in the `dev` group
+    pub group: Option<BTreeMap<String, DependencyGroup>>,
     pub extras: Option<BTreeMap<String, Vec<String>>>,
     pub scripts: Option<BTreeMap<String, String>>,
 }
@@ -1,24 +1,30 @@
 //! Parsing of pyproject.toml and poetry.lock
 
-use crate::install::repo_at_revision;
-use crate::monotrail::{specs_from_requirements_txt_resolved, PythonContext};
-use crate::poetry_integration::poetry_lock::PoetryLock;
+use crate::index::use_requirements_index_options;
+use crate::install::git_checkout;
+use crate::markers::filter_requirements;
+use crate::monotrail::{
+    specs_from_pep621_resolved, specs_from_requirements_txt_resolved, PythonContext,
+};
+use crate::package_index::{SourcePreference, SourcePreferences};
+use crate::path_dependency::{absolute_path, is_path_source, source_fingerprint};
+use crate::pep621::read_pep621_project;
+use crate::poetry_integration::poetry_lock::{LockOutdated, PoetryLock};
 use crate::poetry_integration::poetry_toml::{PoetryPyprojectToml, PoetrySection};
 use crate::poetry_integration::run::poetry_run;
 use crate::poetry_integration::{poetry_lock, poetry_toml};
 use crate::spec::{DistributionType, RequestedSpec, SpecSource};
-use crate::utils::cache_dir;
 use anyhow::{bail, Context};
 use fs_err as fs;
 use install_wheel_rs::{normalize_name, CompatibleTags, Error, Script, WheelFilename};
-use monotrail_utils::RequirementsTxt;
-use pep508_rs::{MarkerEnvironment, VersionOrUrl};
+use monotrail_utils::{RequirementEntry, RequirementsTxt};
+use pep440_rs::{Operator, Version, VersionSpecifier};
+use pep508_rs::{MarkerEnvironment, Requirement, VersionOrUrl};
 use regex::Regex;
-use sha2::{Digest, Sha256};
 use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use tracing::debug;
+use tracing::{debug, warn};
 
 /// The list is empty in poetry 1.2, but lockfiles may have been created by old versions
 ///
@@ -135,6 +141,7 @@ fn parse_dep_extra(dep_spec: &str) -> Result<(String, HashSet<String>, Option<St
 fn resolution_to_specs(
     packages: HashMap<String, poetry_lock::Package>,
     deps_with_extras: BTreeMap<String, HashSet<String>>,
+    project_dir: &Path,
 ) -> anyhow::Result<Vec<RequestedSpec>> {
     let mut specs = Vec::new();
     for (dep_name, dep_extras) in deps_with_extras {
@@ -145,21 +152,37 @@ fn resolution_to_specs(
             continue;
         } else {
             debug!("Packages: {:?}", packages.keys().collect::<Vec<_>>());
-            bail!(
-                "Lockfile outdated (run `poetry update`): {} is missing",
-                norm_name
-            )
+            return Err(LockOutdated::MissingPackage(norm_name).into());
         };
-        let spec = RequestedSpec {
-            requested: format!("{} {}", package.name, package.version),
-            name: package.name.clone(),
-            python_version: Some(package.version.clone()),
-            source: package.source.clone().map(|source| SpecSource {
+        // Packages from a `legacy` index are installed from the configured indexes
+        let source = match package.source.clone() {
+            Some(source) if source.source_type == "legacy" => None,
+            // The fingerprint of the source tells us when we need to rebuild
+            Some(source) if is_path_source(&source.source_type) => {
+                let path = absolute_path(&source.url, project_dir);
+                let fingerprint = source_fingerprint(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                Some(SpecSource {
+                    source_type: source.source_type,
+                    url: path.to_string_lossy().to_string(),
+                    reference: source.reference,
+                    resolved_reference: fingerprint,
+                })
+            }
+            Some(source) => Some(SpecSource {
                 source_type: source.source_type,
                 url: source.url,
                 reference: source.reference,
                 resolved_reference: source.resolved_reference,
             }),
+            None => None,
+        };
+        let spec = RequestedSpec {
+            requested: format!("{} {}", package.name, package.version),
+            name: package.name.clone(),
+            python_version: Some(package.version.clone()),
+            specifiers: None,
+            source,
             extras: dep_extras.into_iter().collect(),
             file_path: None,
             url: None,
@@ -171,30 +194,38 @@ fn resolution_to_specs(
 
 /// Get the root deps from pyproject.toml, already filtered by activated extras.
 /// The is no root package in poetry.lock that we could use so we also need to read pyproject.toml
+///
+/// Besides the extras, `extras` can name dependency groups, which also selects optional groups
+/// and groups when `no_dev` is set, e.g. `--extras docs` for `[tool.poetry.group.docs]`
 fn get_root_info(
     poetry_section: &PoetrySection,
     no_dev: bool,
     extras: &[String],
 ) -> anyhow::Result<HashMap<String, poetry_toml::Dependency>> {
-    let root_deps = if no_dev {
-        poetry_section.dependencies.clone()
-    } else {
-        poetry_section
-            .dependencies
-            .clone()
-            .into_iter()
-            .chain(poetry_section.dev_dependencies.clone().unwrap_or_default())
-            .collect()
-    };
+    let groups = poetry_section.group.clone().unwrap_or_default();
+    let mut root_deps: HashMap<String, poetry_toml::Dependency> =
+        poetry_section.dependencies.clone().into_iter().collect();
+    if !no_dev {
+        root_deps.extend(poetry_section.dev_dependencies.clone().unwrap_or_default());
+    }
+    for (group_name, group) in &groups {
+        let default_group = !no_dev && !group.optional.unwrap_or(false);
+        if default_group || extras.contains(group_name) {
+            root_deps.extend(group.dependencies.clone());
+        }
+    }
 
     let mut root_extra_deps: HashSet<String> = HashSet::new();
     for extra_name in extras {
         let packages = poetry_section
             .extras
             .as_ref()
-            .and_then(|extras| extras.get(extra_name).cloned())
-            .with_context(|| format!("No such extra {}", extra_name))?;
-        root_extra_deps.extend(packages);
+            .and_then(|extras| extras.get(extra_name).cloned());
+        match packages {
+            Some(packages) => root_extra_deps.extend(packages),
+            None if groups.contains_key(extra_name) => {}
+            None => bail!("No such extra or dependency group {}", extra_name),
+        }
     }
 
     let root_deps = root_deps
@@ -231,21 +262,39 @@ fn get_packages_from_lockfile(
 /// Reads pyproject.toml and poetry.lock, also returns poetry.lock as string
 pub fn read_toml_files(dir: &Path) -> anyhow::Result<(PoetrySection, PoetryLock, String)> {
     let path = dir.join("pyproject.toml").canonicalize()?;
-    let poetry_toml: PoetryPyprojectToml = toml::from_str(&fs::read_to_string(&path)?)
-        .with_context(|| format!("Invalid pyproject.toml in {}", path.display()))?;
+    let pyproject_toml = fs::read_to_string(&path)?;
+    let lockfile = fs::read_to_string(dir.join("poetry.lock"))?;
+    parse_toml_strs(&pyproject_toml, lockfile)
+        .with_context(|| format!("Invalid poetry files in {}", dir.display()))
+}
+
+/// Like [read_toml_files], but for pyproject.toml and poetry.lock content that is already in
+/// memory, e.g. generated by a wrapper
+pub fn parse_toml_strs(
+    pyproject_toml: &str,
+    lockfile: String,
+) -> anyhow::Result<(PoetrySection, PoetryLock, String)> {
+    let pyproject_value: toml::Value =
+        toml::from_str(pyproject_toml).context("Invalid pyproject.toml")?;
+    let poetry_toml: PoetryPyprojectToml = pyproject_value
+        .clone()
+        .try_into()
+        .context("Invalid pyproject.toml")?;
     let poetry_section = poetry_toml
         .tool
         .and_then(|tool| tool.poetry)
-        .with_context(|| format!("[tool.poetry] section missing in {}", path.display()))?;
-    let lockfile = fs::read_to_string(dir.join("poetry.lock"))?;
+        .context("[tool.poetry] section missing in pyproject.toml")?;
     let poetry_lock = PoetryLock::from_str(&lockfile).context("Invalid poetry.lock")?;
+    poetry_lock.verify_content_hash(&pyproject_value)?;
     Ok((poetry_section, poetry_lock, lockfile))
 }
 
-/// Parses pyproject.toml and poetry.lock and returns a list of packages to install
+/// Parses pyproject.toml and poetry.lock and returns a list of packages to install. Path
+/// dependencies in the lock are relative to `project_dir`, where the pyproject.toml is
 pub fn read_poetry_specs(
     poetry_section: &PoetrySection,
     poetry_lock: PoetryLock,
+    project_dir: &Path,
     no_dev: bool,
     extras: &[String],
     pep508_env: &MarkerEnvironment,
@@ -288,10 +337,7 @@ pub fn read_poetry_specs(
             continue;
         } else {
             debug!("Packages: {:?}", packages.keys().collect::<Vec<_>>());
-            bail!(
-                "Lockfile outdated (run `poetry update`): {} is missing",
-                norm_name
-            )
+            return Err(LockOutdated::MissingPackage(norm_name).into());
         };
         // descend one level into the dep tree
         for (new_dep_name, new_dep) in package.dependencies.clone().unwrap_or_default() {
@@ -326,7 +372,49 @@ pub fn read_poetry_specs(
         }
     }
 
-    resolution_to_specs(packages, deps_with_extras)
+    check_source_preferences(
+        &poetry_lock,
+        &packages,
+        deps_with_extras.keys(),
+        &SourcePreferences::from_env(),
+    )?;
+    resolution_to_specs(packages, deps_with_extras, project_dir)
+}
+
+/// The lock may predate `MONOTRAIL_ONLY_BINARY`, so it can pin a version we'd have to build
+fn check_source_preferences<'a>(
+    poetry_lock: &PoetryLock,
+    packages: &HashMap<String, poetry_lock::Package>,
+    names: impl Iterator<Item = &'a String>,
+    preferences: &SourcePreferences,
+) -> Result<(), LockOutdated> {
+    for name in names {
+        let Some(package) = packages.get(name) else {
+            continue;
+        };
+        // git and path dependencies are always built
+        if package
+            .source
+            .as_ref()
+            .is_some_and(|source| source.source_type != "legacy")
+        {
+            continue;
+        }
+        if preferences.for_package(name) != SourcePreference::Wheel {
+            continue;
+        }
+        let files = poetry_lock
+            .get_filenames(&package.name)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        if !files.is_empty() && !files.iter().any(|file| file.file.ends_with(".whl")) {
+            return Err(LockOutdated::OnlyBinary {
+                name: package.name.clone(),
+                version: package.version.clone(),
+            });
+        }
+    }
+    Ok(())
 }
 
 /// Checkouts the specified revision to the cache dir, if not present
@@ -338,11 +426,7 @@ pub fn specs_from_git(
     lockfile: Option<&str>,
     python_context: &PythonContext,
 ) -> anyhow::Result<(Vec<RequestedSpec>, PathBuf, String)> {
-    let reference_hash = Sha256::digest(format!("{}-{}", url, revision));
-    let repo_dir = cache_dir()?
-        .join("checkouts")
-        .join(format!("{:x}", reference_hash));
-    repo_at_revision(&url, &revision, &repo_dir).context("Failed to checkout repository")?;
+    let (repo_dir, _commit) = git_checkout(url, revision)?;
 
     if repo_dir.join("poetry.lock").is_file() {
         let (poetry_section, poetry_lock, lockfile) = read_toml_files(&repo_dir)
@@ -350,6 +434,7 @@ pub fn specs_from_git(
         let specs = read_poetry_specs(
             &poetry_section,
             poetry_lock,
+            &repo_dir,
             true,
             extras,
             &python_context.pep508_env,
@@ -382,14 +467,19 @@ pub fn specs_from_git(
             let specs = read_poetry_specs(
                 &poetry_section,
                 poetry_lock,
+                &repo_dir,
                 true,
                 extras,
                 &python_context.pep508_env,
             )?;
             return Ok((specs, repo_dir, lockfile));
+        } else if read_pep621_project(&repo_dir)?.is_some() {
+            let (specs, _scripts, lockfile) =
+                specs_from_pep621_resolved(&repo_dir, extras, &[], lockfile, python_context)?;
+            return Ok((specs, repo_dir, lockfile));
         } else {
             debug!(
-                "Found {} but [tool.poetry] section, ignoring",
+                "Found {} but neither [tool.poetry] nor [project] section, ignoring",
                 repo_dir.join("pyproject.toml").display()
             );
         }
@@ -404,7 +494,7 @@ pub fn specs_from_git(
         )?;
         return Ok((specs, repo_dir, lockfile));
     }
-    bail!("Neither poetry.lock nor pyproject.toml with [tool.poetry] section nor requirements.txt found");
+    bail!("Neither poetry.lock nor pyproject.toml with [tool.poetry] or [project] section nor requirements.txt found");
 }
 
 /// Reads `poetry.toml` and `poetry.lock` from `dep_file_location`, returns specs, scripts and
@@ -423,75 +513,276 @@ pub fn poetry_spec_from_dir(
             }
         }
     }
-    let specs = read_poetry_specs(&poetry_section, poetry_lock, false, extras, pep508_env)?;
+    let specs = read_poetry_specs(
+        &poetry_section,
+        poetry_lock,
+        dep_file_location,
+        false,
+        extras,
+        pep508_env,
+    )?;
     Ok((specs, scripts, lockfile))
 }
 
 /// Reads and parses requirements into poetry dependencies from a requirements file.
+///
+/// Requirements with markers that don't apply to `pep508_env` are skipped
 pub fn read_requirements_for_poetry(
     requirements_txt: &Path,
     working_dir: &Path,
+    pep508_env: &MarkerEnvironment,
 ) -> anyhow::Result<BTreeMap<String, poetry_toml::Dependency>> {
-    let data = RequirementsTxt::parse(requirements_txt, working_dir)?;
-    if !data.constraints.is_empty() {
-        bail!(
-            "Constraints (`-c`) from {} are not supported yet",
+    let mut data = RequirementsTxt::parse(requirements_txt, working_dir)?;
+    apply_constraints(&mut data.requirements, &data.constraints).with_context(|| {
+        format!(
+            "Failed to apply constraints (`-c`) from {}",
             requirements_txt.display()
-        );
-    }
+        )
+    })?;
+    use_requirements_index_options(&data.index_options, working_dir);
     let mut poetry_requirements: BTreeMap<String, poetry_toml::Dependency> = BTreeMap::new();
-    for requirement_entry in data.requirements {
-        let version = match requirement_entry.requirement.version_or_url {
-            None => "*".to_string(),
+    for requirement_entry in filter_requirements(data.requirements, pep508_env) {
+        let dep = requirement_to_poetry(&requirement_entry.requirement).with_context(|| {
+            format!("Unsupported requirement in {}", requirements_txt.display())
+        })?;
+        poetry_requirements.insert(requirement_entry.requirement.name, dep);
+    }
+    Ok(poetry_requirements)
+}
+
+/// Applies constraints (`-c`) by adding their version specifiers to every requirement for the same
+/// package, failing if they obviously can't both be fulfilled.
+///
+/// Like in pip, constraints don't add requirements. Since we don't have a way to pass constraints
+/// for transitive dependencies to the resolver, those are ignored with a warning.
+pub(crate) fn apply_constraints(
+    requirements: &mut [RequirementEntry],
+    constraints: &[Requirement],
+) -> anyhow::Result<()> {
+    for constraint in constraints {
+        if constraint.marker.is_some() {
+            bail!(
+                "Constraints with markers are not supported: '{}'",
+                constraint
+            );
+        }
+        let constraint_specifiers = match &constraint.version_or_url {
+            None => continue,
             Some(VersionOrUrl::Url(_)) => {
-                bail!(
-                    "Unsupported url requirement in {}: '{}'",
-                    requirements_txt.display(),
-                    requirement_entry.requirement,
-                )
+                bail!("Url constraints are not supported: '{}'", constraint)
             }
-            Some(VersionOrUrl::VersionSpecifier(specifiers)) => specifiers.to_string(),
+            Some(VersionOrUrl::VersionSpecifier(specifiers)) => specifiers,
         };
+        let mut constrained = requirements
+            .iter_mut()
+            .filter(|entry| {
+                normalize_name(&entry.requirement.name) == normalize_name(&constraint.name)
+            })
+            .peekable();
+        if constrained.peek().is_none() {
+            warn!(
+                "Ignoring constraint '{}' since {} is not a direct requirement",
+                constraint, constraint.name
+            );
+            continue;
+        }
+        // e.g. the same package with different markers or extras
+        for entry in constrained {
+            let mut merged: Vec<VersionSpecifier> = match &entry.requirement.version_or_url {
+                None => Vec::new(),
+                Some(VersionOrUrl::Url(_)) => {
+                    bail!(
+                        "Can't apply constraint '{}' to url requirement '{}'",
+                        constraint,
+                        entry.requirement
+                    )
+                }
+                Some(VersionOrUrl::VersionSpecifier(specifiers)) => specifiers.to_vec(),
+            };
+            for specifier in constraint_specifiers.iter() {
+                if !merged.contains(specifier) {
+                    merged.push(specifier.clone());
+                }
+            }
+            if !is_satisfiable(&merged) {
+                bail!(
+                    "Constraint '{}' conflicts with requirement '{}'",
+                    constraint,
+                    entry.requirement
+                );
+            }
+            entry.requirement.version_or_url =
+                Some(VersionOrUrl::VersionSpecifier(merged.into_iter().collect()));
+        }
+    }
+    Ok(())
+}
+
+/// Checks that pinned versions fulfill all specifiers and that the lower bounds are below the
+/// upper bounds. This catches conflicts such as `==1.0` and `>=2` or `>=2` and `<1`, but not all
+/// (e.g. `==1.*` and `!=1.*`), which the resolver will report instead
+fn is_satisfiable(specifiers: &[VersionSpecifier]) -> bool {
+    let pinned = specifiers
+        .iter()
+        .filter(|specifier| *specifier.operator() == Operator::Equal);
+    for pin in pinned {
+        if !specifiers
+            .iter()
+            .all(|specifier| specifier.contains(pin.version()))
+        {
+            return false;
+        }
+    }
 
-        let dep = poetry_toml::Dependency::Expanded {
-            version: Some(version),
-            optional: Some(false),
-            extras: requirement_entry.requirement.extras.clone(),
-            git: None,
-            branch: None,
+    // (version, inclusive)
+    let mut lower: Option<(&Version, bool)> = None;
+    let mut upper: Option<(&Version, bool)> = None;
+    for specifier in specifiers {
+        let version = specifier.version();
+        let (bound, inclusive) = match specifier.operator() {
+            Operator::GreaterThan => (&mut lower, false),
+            Operator::GreaterThanEqual => (&mut lower, true),
+            Operator::LessThan => (&mut upper, false),
+            Operator::LessThanEqual => (&mut upper, true),
+            _ => continue,
         };
-        poetry_requirements.insert(requirement_entry.requirement.name, dep);
+        let is_lower = matches!(
+            specifier.operator(),
+            Operator::GreaterThan | Operator::GreaterThanEqual
+        );
+        *bound = match *bound {
+            Some((current, current_inclusive)) if current == version => {
+                Some((current, current_inclusive && inclusive))
+            }
+            // Keep the tighter bound
+            Some((current, current_inclusive)) if (current > version) == is_lower => {
+                Some((current, current_inclusive))
+            }
+            _ => Some((version, inclusive)),
+        };
+    }
+    match (lower, upper) {
+        (Some((lower, lower_inclusive)), Some((upper, upper_inclusive))) => {
+            lower < upper || (lower == upper && lower_inclusive && upper_inclusive)
+        }
+        _ => true,
+    }
+}
+
+/// Converts PEP 508 requirements given e.g. on the command line into the poetry format
+pub fn requirements_for_poetry(
+    requirements: &[String],
+) -> anyhow::Result<BTreeMap<String, poetry_toml::Dependency>> {
+    let mut poetry_requirements: BTreeMap<String, poetry_toml::Dependency> = BTreeMap::new();
+    for requirement in requirements {
+        let requirement = Requirement::from_str(requirement)
+            .with_context(|| format!("Invalid requirement '{}'", requirement))?;
+        let dep = requirement_to_poetry(&requirement)?;
+        poetry_requirements.insert(requirement.name, dep);
     }
     Ok(poetry_requirements)
 }
 
+/// Only version specifiers, extras, git urls and `file://` urls are supported
+pub(crate) fn requirement_to_poetry(
+    requirement: &Requirement,
+) -> anyhow::Result<poetry_toml::Dependency> {
+    let version = match &requirement.version_or_url {
+        None => "*".to_string(),
+        Some(VersionOrUrl::Url(url)) if url.scheme().starts_with("git+") => {
+            let (git, rev) = parse_git_url(url.as_str())
+                .with_context(|| format!("Unsupported url requirement: '{}'", requirement))?;
+            return Ok(poetry_toml::Dependency::Expanded {
+                version: None,
+                optional: Some(false),
+                extras: requirement.extras.clone(),
+                git: Some(git),
+                branch: None,
+                rev,
+                path: None,
+                allow_prereleases: None,
+            });
+        }
+        Some(VersionOrUrl::Url(url)) if url.scheme() == "file" => {
+            let path = url
+                .to_file_path()
+                .map_err(|()| anyhow::format_err!("Invalid file url in '{}'", requirement))?;
+            return Ok(poetry_toml::Dependency::Expanded {
+                version: None,
+                optional: Some(false),
+                extras: requirement.extras.clone(),
+                git: None,
+                branch: None,
+                rev: None,
+                path: Some(path.to_string_lossy().to_string()),
+                allow_prereleases: None,
+            });
+        }
+        Some(VersionOrUrl::Url(_)) => {
+            bail!("Unsupported url requirement: '{}'", requirement)
+        }
+        Some(VersionOrUrl::VersionSpecifier(specifiers)) => specifiers.to_string(),
+    };
+
+    Ok(poetry_toml::Dependency::Expanded {
+        version: Some(version),
+        optional: Some(false),
+        extras: requirement.extras.clone(),
+        git: None,
+        branch: None,
+        rev: None,
+        path: None,
+        allow_prereleases: None,
+    })
+}
+
+/// Splits `git+https://github.com/org/repo.git@v1.0#egg=name` into the repository url and the
+/// revision (branch, tag or commit), if any
+pub(crate) fn parse_git_url(url: &str) -> anyhow::Result<(String, Option<String>)> {
+    let url = url
+        .strip_prefix("git+")
+        .with_context(|| format!("Not a git url: {}", url))?;
+    let (url, fragment) = url.split_once('#').unwrap_or((url, ""));
+    if fragment
+        .split('&')
+        .any(|part| part.starts_with("subdirectory="))
+    {
+        bail!("Packages in a subdirectory of a repository are not supported");
+    }
+    // The revision is after an `@` in the path, the one in `ssh://git@github.com` is the user
+    let host_start = url.find("://").map_or(0, |pos| pos + 3);
+    let path_start = url[host_start..]
+        .find('/')
+        .map_or(url.len(), |pos| host_start + pos);
+    match url[path_start..].rsplit_once('@') {
+        Some((path, revision)) if !revision.is_empty() => Ok((
+            format!("{}{}", &url[..path_start], path),
+            Some(revision.to_string()),
+        )),
+        _ => Ok((url.to_string(), None)),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::{
-        parse_dep_extra, poetry_spec_from_dir, read_requirements_for_poetry, read_toml_files,
+        apply_constraints, check_source_preferences, get_packages_from_lockfile, parse_dep_extra,
+        parse_git_url, parse_toml_strs, poetry_spec_from_dir, read_requirements_for_poetry,
+        read_toml_files, requirements_for_poetry,
     };
+    use crate::package_index::SourcePreferences;
+    use crate::poetry_integration::poetry_lock::{content_hash, LockOutdated, PoetryLock};
+    use crate::poetry_integration::poetry_toml::PoetryPyprojectToml;
     use crate::read_poetry_specs;
+    use crate::test_utils::test_pep508_env;
+    use fs_err as fs;
     use indoc::indoc;
-    use pep508_rs::{MarkerEnvironment, StringVersion};
+    use monotrail_utils::{RequirementEntry, RequirementSource};
+    use pep508_rs::{Requirement, VersionOrUrl};
     use std::collections::HashSet;
     use std::path::Path;
     use std::str::FromStr;
-
-    fn test_pep508_env() -> MarkerEnvironment {
-        MarkerEnvironment {
-            implementation_name: "cpython".to_string(),
-            implementation_version: StringVersion::from_str("3.8.10").unwrap(),
-            os_name: "posix".to_string(),
-            platform_machine: "x86_64".to_string(),
-            platform_python_implementation: "CPython".to_string(),
-            platform_release: "5.13.0-39-generic".to_string(),
-            platform_system: "Linux".to_string(),
-            platform_version: "#44~20.04.1-Ubuntu SMP Thu Mar 24 16:43:35 UTC 2022".to_string(),
-            python_full_version: StringVersion::from_str("3.8.10").unwrap(),
-            python_version: StringVersion::from_str("3.8").unwrap(),
-            sys_platform: "linux".to_string(),
-        }
-    }
+    use tempfile::TempDir;
 
     #[test]
     fn test_parse_extra_deps() {
@@ -541,6 +832,41 @@ mod test {
             let specs = read_poetry_specs(
                 &poetry_section,
                 poetry_lock,
+                toml_dir,
+                no_dev,
+                &extras,
+                &test_pep508_env(),
+            )
+            .unwrap();
+            assert_eq!(specs.len(), specs_count);
+        }
+    }
+
+    #[test]
+    fn test_poetry_groups() {
+        let ibis = Path::new("../../test-data/poetry/ibis");
+        let (mut poetry_section, poetry_lock, _lockfile) = read_toml_files(ibis).unwrap();
+        // The dev and test groups are installed by default, docs is optional
+        poetry_section
+            .group
+            .as_mut()
+            .unwrap()
+            .get_mut("docs")
+            .unwrap()
+            .optional = Some(true);
+        let expected = [
+            (true, vec![], 28),
+            (false, vec![], 108),
+            (true, vec!["test"], 67),
+            (false, vec!["docs"], 162),
+            (false, vec!["docs", "duckdb"], 166),
+        ];
+        for (no_dev, extras, specs_count) in expected {
+            let extras: Vec<String> = extras.into_iter().map(ToString::to_string).collect();
+            let specs = read_poetry_specs(
+                &poetry_section,
+                poetry_lock.clone(),
+                ibis,
                 no_dev,
                 &extras,
                 &test_pep508_env(),
@@ -548,6 +874,42 @@ mod test {
             .unwrap();
             assert_eq!(specs.len(), specs_count);
         }
+
+        let err = read_poetry_specs(
+            &poetry_section,
+            poetry_lock,
+            ibis,
+            false,
+            &["lint".to_string()],
+            &test_pep508_env(),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "No such extra or dependency group lint");
+    }
+
+    #[test]
+    fn test_parse_toml_strs() {
+        let data_science = Path::new("../../test-data/poetry/data-science");
+        let pyproject_toml = fs::read_to_string(data_science.join("pyproject.toml")).unwrap();
+        let lockfile = fs::read_to_string(data_science.join("poetry.lock")).unwrap();
+        let (poetry_section, poetry_lock, _lockfile) =
+            parse_toml_strs(&pyproject_toml, lockfile).unwrap();
+        let specs = read_poetry_specs(
+            &poetry_section,
+            poetry_lock,
+            data_science,
+            true,
+            &[],
+            &test_pep508_env(),
+        )
+        .unwrap();
+        assert_eq!(specs.len(), 15);
+
+        let err = parse_toml_strs("[project]\nname = \"foo\"\n", String::new()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[tool.poetry] section missing in pyproject.toml"
+        );
     }
 
     #[test]
@@ -573,11 +935,159 @@ mod test {
 
         let working_dir = Path::new("../../test-data").join("requirements-txt");
         let path = working_dir.join("for-poetry.txt");
-        let reqs = read_requirements_for_poetry(&path, &working_dir).unwrap();
+        let reqs = read_requirements_for_poetry(&path, &working_dir, &test_pep508_env()).unwrap();
+        let poetry_toml = toml::to_string(&reqs).unwrap();
+        assert_eq!(poetry_toml, expected);
+    }
+
+    #[test]
+    fn test_constraints_for_poetry() {
+        let expected = indoc! {r#"
+            [numpy]
+            version = "==1.24.2"
+            optional = false
+
+            [pandas]
+            version = ">=1, <2"
+            optional = false
+        "#};
+
+        let working_dir = Path::new("../../test-data").join("requirements-txt");
+        let path = working_dir.join("constraints-for-poetry");
+        let reqs = read_requirements_for_poetry(&path, &working_dir, &test_pep508_env()).unwrap();
+        let poetry_toml = toml::to_string(&reqs).unwrap();
+        assert_eq!(poetry_toml, expected);
+    }
+
+    #[test]
+    fn test_markers_for_poetry() {
+        let working_dir = Path::new("../../test-data").join("requirements-txt");
+        let path = working_dir.join("markers-for-poetry");
+        let reqs = read_requirements_for_poetry(&path, &working_dir, &test_pep508_env()).unwrap();
+        assert_eq!(reqs.keys().collect::<Vec<_>>(), ["numpy", "tomli"]);
+    }
+
+    #[test]
+    fn test_constraint_conflicts() {
+        let cases = [
+            ("tqdm==4.65.0", "tqdm==4.64.0", false),
+            ("tqdm>=4", "tqdm==3.1", false),
+            ("tqdm>=4", "tqdm<3", false),
+            ("tqdm>=4", "tqdm<4", false),
+            ("tqdm>=4", "tqdm<=4", true),
+            ("tqdm>4,>=3", "tqdm<=4", false),
+            ("Django>=2", "django<3", true),
+            ("tqdm", "tqdm!=4.0", true),
+        ];
+        for (requirement, constraint, ok) in cases {
+            let mut requirements = vec![RequirementEntry {
+                requirement: Requirement::from_str(requirement).unwrap(),
+                hashes: vec![],
+                source: RequirementSource::Registry,
+                global_options: vec![],
+                config_settings: vec![],
+                span: None,
+            }];
+            let constraints = [Requirement::from_str(constraint).unwrap()];
+            let result = apply_constraints(&mut requirements, &constraints);
+            assert_eq!(result.is_ok(), ok, "{} -c {}", requirement, constraint);
+        }
+    }
+
+    #[test]
+    fn test_constraint_duplicated_name() {
+        let entry = |requirement: &str| RequirementEntry {
+            requirement: Requirement::from_str(requirement).unwrap(),
+            hashes: vec![],
+            source: RequirementSource::Registry,
+            global_options: vec![],
+            config_settings: vec![],
+            span: None,
+        };
+        let mut requirements = vec![
+            entry("numpy>=1.20; python_version < '3.10'"),
+            entry("numpy[dev]; python_version >= '3.10'"),
+        ];
+        let constraints = [Requirement::from_str("numpy<1.25").unwrap()];
+        apply_constraints(&mut requirements, &constraints).unwrap();
+        let specifiers: Vec<String> = requirements
+            .iter()
+            .map(|entry| match &entry.requirement.version_or_url {
+                Some(VersionOrUrl::VersionSpecifier(specifiers)) => specifiers.to_string(),
+                _ => String::new(),
+            })
+            .collect();
+        assert_eq!(specifiers, [">=1.20, <1.25", "<1.25"]);
+    }
+
+    #[test]
+    fn test_requirements_for_poetry() {
+        let expected = indoc! {r#"
+            [ipython]
+            version = "*"
+            optional = false
+
+            [pandas]
+            version = ">=1, <2"
+            optional = false
+            extras = ["tabulate"]
+
+            [tqdm]
+            optional = false
+            git = "https://github.com/tqdm/tqdm"
+            rev = "v4.66.1"
+
+            [upsidedown]
+            optional = false
+            path = "/data/upsidedown"
+        "#};
+
+        let reqs = requirements_for_poetry(&[
+            "ipython".to_string(),
+            "pandas[tabulate] >=1, <2".to_string(),
+            "tqdm @ git+https://github.com/tqdm/tqdm@v4.66.1".to_string(),
+            "upsidedown @ file:///data/upsidedown".to_string(),
+        ])
+        .unwrap();
         let poetry_toml = toml::to_string(&reqs).unwrap();
         assert_eq!(poetry_toml, expected);
     }
 
+    #[test]
+    fn test_parse_git_url() {
+        let cases = [
+            (
+                "git+https://github.com/org/repo",
+                "https://github.com/org/repo",
+                None,
+            ),
+            (
+                "git+https://github.com/org/repo.git@v1.0#egg=repo",
+                "https://github.com/org/repo.git",
+                Some("v1.0"),
+            ),
+            (
+                "git+ssh://git@github.com/org/repo@0123abc",
+                "ssh://git@github.com/org/repo",
+                Some("0123abc"),
+            ),
+            (
+                "git+ssh://git@github.com/org/repo",
+                "ssh://git@github.com/org/repo",
+                None,
+            ),
+        ];
+        for (url, repository, revision) in cases {
+            assert_eq!(
+                parse_git_url(url).unwrap(),
+                (repository.to_string(), revision.map(ToString::to_string)),
+                "{}",
+                url
+            );
+        }
+        assert!(parse_git_url("git+https://github.com/org/repo#subdirectory=lib").is_err());
+    }
+
     #[test]
     fn test_outdated_lockfile() {
         let err = poetry_spec_from_dir(
@@ -586,10 +1096,133 @@ mod test {
             &test_pep508_env(),
         )
         .unwrap_err();
-        let errors = err.chain().map(|e| e.to_string()).collect::<Vec<_>>();
+        // boltons was added to pyproject.toml without locking again
+        assert!(matches!(
+            err.downcast_ref::<LockOutdated>(),
+            Some(LockOutdated::ContentHash { .. })
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Invalid poetry files in ../../test-data/lockfile-outdated"
+        );
+
+        // Without the content-hash, we only notice when the package is missing
+        let dir = Path::new("../../test-data/lockfile-outdated");
+        let pyproject_toml = fs::read_to_string(dir.join("pyproject.toml")).unwrap();
+        let lockfile = fs::read_to_string(dir.join("poetry.lock")).unwrap();
+        let locked = PoetryLock::from_str(&lockfile)
+            .unwrap()
+            .metadata
+            .content_hash;
+        let current = content_hash(&toml::from_str(&pyproject_toml).unwrap());
+        let (poetry_section, poetry_lock, _) =
+            parse_toml_strs(&pyproject_toml, lockfile.replace(&locked, &current)).unwrap();
+        let err = read_poetry_specs(
+            &poetry_section,
+            poetry_lock,
+            dir,
+            false,
+            &[],
+            &test_pep508_env(),
+        )
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<LockOutdated>(),
+            Some(LockOutdated::MissingPackage(name)) if name == "boltons"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Lockfile outdated (run `poetry update`): boltons is missing"
+        );
+    }
+
+    #[test]
+    fn test_check_source_preferences() {
+        let poetry_lock = PoetryLock::from_str(indoc! {r#"
+            [[package]]
+            name = "numpy"
+            version = "1.24.0"
+            description = ""
+            optional = false
+            python-versions = ">=3.8"
+            files = [
+                {file = "numpy-1.24.0.tar.gz", hash = "sha256:c4ab7c9711fe6b235e86487ca74c1b092a6dd59a3cb45b63241ea0a148501853"},
+            ]
+
+            [metadata]
+            lock-version = "2.0"
+            python-versions = "^3.8"
+            content-hash = "0"
+        "#})
+        .unwrap();
+        let packages = get_packages_from_lockfile(&poetry_lock).unwrap();
+        let names = ["numpy".to_string()];
+        let preferences = |only_binary: &str| SourcePreferences {
+            no_binary: String::new(),
+            only_binary: only_binary.to_string(),
+        };
+        check_source_preferences(&poetry_lock, &packages, names.iter(), &preferences("")).unwrap();
+        let err =
+            check_source_preferences(&poetry_lock, &packages, names.iter(), &preferences(":all:"))
+                .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Lockfile outdated (lock again): numpy 1.24.0 is locked without a wheel, but \
+            MONOTRAIL_ONLY_BINARY forbids building it from source"
+        );
+    }
+
+    /// poetry writes the path as declared, relative to the pyproject.toml and not to where we
+    /// happen to run
+    #[test]
+    fn test_relative_path_dependency() {
+        let workspace = TempDir::new().unwrap();
+        let project_dir = workspace.path().join("app");
+        fs::create_dir_all(workspace.path().join("localpkg")).unwrap();
+        fs::create_dir_all(&project_dir).unwrap();
+        let poetry_section: PoetryPyprojectToml = toml::from_str(indoc! {r#"
+            [tool.poetry]
+            name = "app"
+            version = "1.0.0"
+            description = ""
+            authors = []
+
+            [tool.poetry.dependencies]
+            localpkg = { path = "../localpkg" }
+        "#})
+        .unwrap();
+        let poetry_lock = PoetryLock::from_str(indoc! {r#"
+            [[package]]
+            name = "localpkg"
+            version = "1.0"
+            description = ""
+            optional = false
+            python-versions = "*"
+            files = []
+
+            [package.source]
+            type = "directory"
+            url = "../localpkg"
+
+            [metadata]
+            lock-version = "2.0"
+            python-versions = "*"
+            content-hash = ""
+        "#})
+        .unwrap();
+        let specs = read_poetry_specs(
+            &poetry_section.tool.unwrap().poetry.unwrap(),
+            poetry_lock,
+            &project_dir,
+            false,
+            &[],
+            &test_pep508_env(),
+        )
+        .unwrap();
+        let source = specs[0].source.as_ref().unwrap();
         assert_eq!(
-            errors,
-            vec!["Lockfile outdated (run `poetry update`): boltons is missing"]
+            Path::new(&source.url),
+            project_dir.join("..").join("localpkg")
         );
     }
 }
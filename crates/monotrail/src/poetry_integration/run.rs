@@ -1,21 +1,23 @@
 //! Runs poetry after installing it from a bundle lockfile
 
+use crate::config::default_python_version;
 use crate::inject_and_run::inject_and_run_python;
 use crate::monotrail::install;
 use crate::monotrail::provision_python_env;
 use crate::poetry_integration::poetry_lock::PoetryLock;
 use crate::poetry_integration::poetry_toml::PoetryPyprojectToml;
-use crate::{read_poetry_specs, DEFAULT_PYTHON_VERSION};
+use crate::read_poetry_specs;
 use anyhow::Context;
 use monotrail_utils::parse_cpython_args::determine_python_version;
 use std::collections::BTreeMap;
+use std::env::current_dir;
 use std::path::PathBuf;
 
 /// Use the libpython.so to run a poetry command on python 3.8, unless you give +x.y as first
 /// argument
 pub fn poetry_run(args: &[String], python_version: Option<&str>) -> anyhow::Result<i32> {
     let (args, python_version) =
-        determine_python_version(&args, python_version, DEFAULT_PYTHON_VERSION)?;
+        determine_python_version(&args, python_version, default_python_version()?)?;
     let (python_context, python_home) = provision_python_env(python_version)?;
 
     let pyproject_toml = include_str!("../../../../resources/poetry_boostrap_lock/pyproject.toml");
@@ -27,6 +29,7 @@ pub fn poetry_run(args: &[String], python_version: Option<&str>) -> anyhow::Resu
     let specs = read_poetry_specs(
         &poetry_section,
         poetry_lock,
+        &current_dir()?,
         true,
         &[],
         &python_context.pep508_env,
@@ -1,19 +1,46 @@
+use crate::config::default_python_version;
 use crate::monotrail::provision_python_env;
-use crate::monotrail::{install, run_command_finder_data, PythonContext};
-use crate::poetry_integration::lock::poetry_resolve_from_dir;
+use crate::monotrail::{install, run_command_finder_data, FinderData, PythonContext};
+use crate::poetry_integration::lock::{poetry_resolve, poetry_resolve_from_dir};
+use crate::poetry_integration::poetry_lock::LockOutdated;
 use crate::poetry_integration::poetry_toml;
 use crate::poetry_integration::poetry_toml::PoetryPyprojectToml;
-use crate::poetry_integration::read_dependencies::read_toml_files;
+use crate::poetry_integration::read_dependencies::{read_toml_files, requirements_for_poetry};
+use crate::read_poetry_specs;
 use crate::utils::data_local_dir;
-use crate::{read_poetry_specs, DEFAULT_PYTHON_VERSION};
 use anyhow::Context;
 use fs_err as fs;
+use install_wheel_rs::cache_key;
 use monotrail_utils::parse_cpython_args::parse_major_minor;
+use pep508_rs::Requirement;
 use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use tempfile::TempDir;
 use tracing::{debug, info};
 
+/// `monotrail tool run`
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum ToolCommand {
+    /// Installs a package and its dependencies into an environment of its own and runs a command
+    /// from it, e.g. `monotrail tool run "black[jupyter]>=23" -- --check .`. The environment is
+    /// cached, so the next run with the same requirement starts without resolving
+    #[clap(trailing_var_arg = true)]
+    Run {
+        /// The PEP 508 requirement, e.g. `black`, `black==23.1.0` or `black[jupyter]`
+        spec: String,
+        /// The command to run, defaults to the package name
+        #[clap(long)]
+        command: Option<String>,
+        /// Run this python version x.y
+        #[clap(long, short)]
+        python_version: Option<String>,
+        /// The arguments passed verbatim to the command
+        #[clap(allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+}
+
 /// Simple pipx reimplementation
 ///
 /// Resolves one package, saving it in .local and runs one command from it
@@ -25,10 +52,10 @@ pub fn ppipx(
     command: &str,
     args: &[String],
 ) -> anyhow::Result<i32> {
-    let python_version = python_version
-        .map(parse_major_minor)
-        .transpose()?
-        .unwrap_or(DEFAULT_PYTHON_VERSION);
+    let python_version = match python_version {
+        Some(python_version) => parse_major_minor(python_version)?,
+        None => default_python_version()?,
+    };
 
     let (python_context, python_home) = provision_python_env(python_version)?;
     let package = package.unwrap_or(command);
@@ -66,6 +93,7 @@ pub fn ppipx(
     let specs = read_poetry_specs(
         &poetry_section,
         poetry_lock,
+        &resolution_dir,
         true,
         &[],
         &python_context.pep508_env,
@@ -113,6 +141,9 @@ fn generate_ppipx_entry(
                 extras: Some(extras.to_vec()),
                 git: None,
                 branch: None,
+                rev: None,
+                path: None,
+                allow_prereleases: None,
             },
         );
     }
@@ -125,6 +156,7 @@ fn generate_ppipx_entry(
                 authors: vec!["monotrail".to_string()],
                 dependencies,
                 dev_dependencies: Default::default(),
+                group: Default::default(),
                 extras: None,
                 scripts: None,
             }),
@@ -152,3 +184,163 @@ fn generate_ppipx_entry(
 
     Ok(())
 }
+
+/// The cached environment of `monotrail tool run`. The resolution depends on the requirement and
+/// the python version, so both go into the key
+fn tool_dir(requirement: &Requirement, python_version: (u8, u8)) -> anyhow::Result<PathBuf> {
+    let key = cache_key(format!(
+        "{}\n{}.{}",
+        requirement, python_version.0, python_version.1
+    ));
+    Ok(data_local_dir()?.join("tools").join(key))
+}
+
+/// Resolves the requirements of a tool or a script and saves the pyproject.toml and the
+/// poetry.lock to `env_dir`. The poetry.lock is written last since it marks the entry as complete
+fn generate_tool_entry(
+    requirements: &[String],
+    python_context: &PythonContext,
+    env_dir: &Path,
+) -> anyhow::Result<()> {
+    let dependencies = requirements_for_poetry(requirements)?;
+    let (poetry_section, _poetry_lock, lockfile) =
+        poetry_resolve(&dependencies, None, python_context)
+            .with_context(|| format!("Failed to resolve {}", requirements.join(", ")))?;
+    let pyproject_toml = PoetryPyprojectToml {
+        tool: Some(poetry_toml::ToolSection {
+            poetry: Some(poetry_section),
+        }),
+        build_system: None,
+    };
+    fs::create_dir_all(env_dir).context("Failed to create tool dir")?;
+    fs::write(
+        env_dir.join("pyproject.toml"),
+        toml::to_string(&pyproject_toml).context("Failed to serialize pyproject.toml for tool")?,
+    )?;
+    fs::write(env_dir.join("poetry.lock"), lockfile)?;
+    Ok(())
+}
+
+/// Resolves the requirements to `env_dir` unless that was done before and installs them, for
+/// `monotrail tool run` and scripts with inline metadata
+pub(crate) fn cached_environment(
+    requirements: &[String],
+    python_context: &PythonContext,
+    env_dir: &Path,
+) -> anyhow::Result<FinderData> {
+    if !env_dir.join("poetry.lock").is_file() {
+        info!(
+            "Resolving {} for python {}.{}",
+            requirements.join(", "),
+            python_context.version.0,
+            python_context.version.1
+        );
+        generate_tool_entry(requirements, python_context, env_dir)?;
+    } else {
+        debug!("Using the cached environment in {}", env_dir.display());
+    }
+
+    let (poetry_section, poetry_lock, lockfile) = match read_toml_files(env_dir) {
+        // e.g. written by an older monotrail version with a different content-hash
+        Err(err) if err.downcast_ref::<LockOutdated>().is_some() => {
+            debug!("Resolving the cached environment again: {:#}", err);
+            generate_tool_entry(requirements, python_context, env_dir)?;
+            read_toml_files(env_dir)
+        }
+        result => result,
+    }
+    .with_context(|| format!("Invalid tool environment at {}", env_dir.display()))?;
+    let specs = read_poetry_specs(
+        &poetry_section,
+        poetry_lock,
+        env_dir,
+        true,
+        &[],
+        &python_context.pep508_env,
+    )?;
+    install(&specs, BTreeMap::new(), lockfile, None, python_context)
+        .context("Couldn't install packages")
+}
+
+/// `monotrail tool run`: Installs `spec` with its dependencies into an isolated environment and
+/// runs `command`, or the command named like the package, from it. Nothing of the current
+/// project is read or changed
+pub fn tool_run(
+    spec: &str,
+    command: Option<&str>,
+    python_version: Option<&str>,
+    args: &[String],
+) -> anyhow::Result<i32> {
+    let requirement =
+        Requirement::from_str(spec).with_context(|| format!("Invalid requirement '{}'", spec))?;
+    let python_version = match python_version {
+        Some(python_version) => parse_major_minor(python_version)?,
+        None => default_python_version()?,
+    };
+    let (python_context, python_home) = provision_python_env(python_version)?;
+
+    let tool_dir = tool_dir(&requirement, python_version)?;
+    let finder_data = cached_environment(&[requirement.to_string()], &python_context, &tool_dir)?;
+
+    let command = command.unwrap_or(&requirement.name);
+    run_command_finder_data(command, args, &python_context, &python_home, &finder_data)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::ppipx::{tool_dir, ToolCommand};
+    use crate::Cli;
+    use clap::Parser;
+    use pep508_rs::Requirement;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_tool_run_args() {
+        let cli = Cli::try_parse_from([
+            "monotrail",
+            "tool",
+            "run",
+            "-p",
+            "3.9",
+            "black>=23",
+            "--",
+            "--check",
+            ".",
+        ])
+        .unwrap();
+        let Cli::Tool {
+            command:
+                ToolCommand::Run {
+                    spec,
+                    python_version,
+                    args,
+                    ..
+                },
+        } = cli
+        else {
+            panic!("{:?}", cli)
+        };
+        assert_eq!(spec, "black>=23");
+        assert_eq!(python_version.as_deref(), Some("3.9"));
+        assert_eq!(args, ["--check", "."]);
+    }
+
+    #[test]
+    fn test_tool_dir() {
+        let black = Requirement::from_str("black[jupyter]>=23").unwrap();
+        let black_spaced = Requirement::from_str("black [jupyter] >= 23").unwrap();
+        assert_eq!(
+            tool_dir(&black, (3, 8)).unwrap(),
+            tool_dir(&black_spaced, (3, 8)).unwrap()
+        );
+        assert_ne!(
+            tool_dir(&black, (3, 8)).unwrap(),
+            tool_dir(&black, (3, 9)).unwrap()
+        );
+        let ruff = Requirement::from_str("ruff").unwrap();
+        assert_ne!(
+            tool_dir(&black, (3, 8)).unwrap(),
+            tool_dir(&ruff, (3, 8)).unwrap()
+        );
+    }
+}
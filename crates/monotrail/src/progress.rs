@@ -0,0 +1,373 @@
+//! `--progress json-lines`: Progress as one JSON object per line, so build systems and IDEs that
+//! wrap monotrail can render their own UI and attribute the time to single dependencies. The
+//! events go to stderr, or to `--progress-fd` to keep them apart from the log output.
+//!
+//! ```text
+//! {"time":0.012,"event":"phase-started","phase":"install","total":2}
+//! {"time":0.013,"event":"package-started","phase":"download","package":"tqdm"}
+//! {"time":0.210,"event":"bytes","phase":"download","package":"tqdm","bytes":78470}
+//! {"time":0.211,"event":"package-finished","phase":"download","package":"tqdm","elapsed":0.198}
+//! {"time":0.305,"event":"package-finished","phase":"install","package":"tqdm","elapsed":0.292,"eta":0.292}
+//! {"time":0.598,"event":"phase-finished","phase":"install","elapsed":0.586}
+//! ```
+
+use anyhow::bail;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// How to show progress
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum ProgressFormat {
+    /// Progress bars and log messages
+    #[default]
+    Human,
+    /// One JSON event per line, see the module documentation
+    JsonLines,
+}
+
+/// Global progress options, which go before the subcommand, e.g.
+/// `monotrail --progress-fd 3 install ... 3>progress.jsonl`
+#[derive(clap::Args, Debug, Default, Clone)]
+pub struct ProgressOptions {
+    /// `json-lines` writes machine readable progress events to stderr instead of progress bars
+    #[clap(long, value_enum, default_value_t)]
+    pub progress: ProgressFormat,
+    /// Write the json-lines progress events to this (already open) file descriptor instead of
+    /// stderr. Implies `--progress json-lines`
+    #[clap(long)]
+    pub progress_fd: Option<i32>,
+}
+
+/// The steps of a run that report progress
+#[derive(Serialize, Debug, Clone, Copy, Eq, PartialEq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Phase {
+    /// Resolving requirements
+    Resolve,
+    /// Downloading a distribution, reported per package
+    Download,
+    /// Building a wheel from a source distribution, reported per package
+    Build,
+    /// Installing wheels, with a total
+    Install,
+}
+
+/// Times are in seconds, `time` is since the start of the run
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+enum ProgressEvent<'a> {
+    PhaseStarted {
+        phase: Phase,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        total: Option<usize>,
+    },
+    PackageStarted {
+        phase: Phase,
+        package: &'a str,
+    },
+    /// The bytes processed so far
+    Bytes {
+        phase: Phase,
+        package: &'a str,
+        bytes: u64,
+    },
+    PackageFinished {
+        phase: Phase,
+        package: &'a str,
+        elapsed: f64,
+        /// The estimated time until the phase is finished, if it has a total
+        #[serde(skip_serializing_if = "Option::is_none")]
+        eta: Option<f64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    PhaseFinished {
+        phase: Phase,
+        elapsed: f64,
+    },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: f64,
+    #[serde(flatten)]
+    event: ProgressEvent<'a>,
+}
+
+/// Milliseconds are precise enough and keep the lines short
+fn seconds(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 1000.0).round() / 1000.0
+}
+
+/// Writes the events and keeps track of the phases for the ETA
+struct ProgressSink<W: Write> {
+    writer: W,
+    start: Instant,
+    /// phase -> (start, total, finished packages)
+    phases: HashMap<Phase, (Instant, Option<usize>, usize)>,
+}
+
+impl<W: Write> ProgressSink<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer,
+            start: Instant::now(),
+            phases: HashMap::new(),
+        }
+    }
+
+    fn emit(&mut self, event: ProgressEvent) {
+        let line = Line {
+            time: seconds(self.start.elapsed()),
+            event,
+        };
+        let result = serde_json::to_writer(&mut self.writer, &line)
+            .map_err(io::Error::from)
+            .and_then(|()| self.writer.write_all(b"\n"))
+            .and_then(|()| self.writer.flush());
+        // A wrapper that stopped reading shouldn't break the installation
+        if let Err(err) = result {
+            debug!("Failed to write progress event: {}", err);
+        }
+    }
+
+    fn phase_started(&mut self, phase: Phase, total: Option<usize>) {
+        self.phases.insert(phase, (Instant::now(), total, 0));
+        self.emit(ProgressEvent::PhaseStarted { phase, total });
+    }
+
+    fn package_finished(
+        &mut self,
+        phase: Phase,
+        package: &str,
+        elapsed: Duration,
+        error: Option<String>,
+    ) {
+        let eta = self
+            .phases
+            .get_mut(&phase)
+            .and_then(|(start, total, finished)| {
+                *finished += 1;
+                let remaining = total.as_ref()?.saturating_sub(*finished);
+                Some(seconds(
+                    start.elapsed().mul_f64(remaining as f64 / *finished as f64),
+                ))
+            });
+        self.emit(ProgressEvent::PackageFinished {
+            phase,
+            package,
+            elapsed: seconds(elapsed),
+            eta,
+            error,
+        });
+    }
+
+    fn phase_finished(&mut self, phase: Phase) {
+        let elapsed = self
+            .phases
+            .remove(&phase)
+            .map_or(0.0, |(start, _, _)| seconds(start.elapsed()));
+        self.emit(ProgressEvent::PhaseFinished { phase, elapsed });
+    }
+}
+
+/// Set by [init_progress], `None` for human output. Downloads and builds run in parallel, so this
+/// needs to be global
+static SINK: Mutex<Option<ProgressSink<Box<dyn Write + Send>>>> = Mutex::new(None);
+
+/// Enables the json-lines events if requested
+pub fn init_progress(options: &ProgressOptions) -> anyhow::Result<()> {
+    let writer: Box<dyn Write + Send> = match (options.progress_fd, options.progress) {
+        (Some(fd), _) => open_fd(fd)?,
+        (None, ProgressFormat::JsonLines) => Box::new(io::stderr()),
+        (None, ProgressFormat::Human) => return Ok(()),
+    };
+    *SINK.lock().unwrap() = Some(ProgressSink::new(writer));
+    Ok(())
+}
+
+#[cfg(unix)]
+fn open_fd(fd: i32) -> anyhow::Result<Box<dyn Write + Send>> {
+    use std::os::unix::io::FromRawFd;
+
+    // SAFETY: fcntl only checks that the fd is open, it doesn't modify it
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        bail!("--progress-fd {} is not an open file descriptor", fd);
+    }
+    // SAFETY: The user passed us the fd to write to, and we're the only ones using it
+    Ok(Box::new(unsafe { std::fs::File::from_raw_fd(fd) }))
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> anyhow::Result<Box<dyn Write + Send>> {
+    bail!("--progress-fd is only supported on unix, use `--progress json-lines` instead")
+}
+
+/// Whether json-lines events are written, so we don't draw progress bars in between
+pub(crate) fn is_enabled() -> bool {
+    SINK.lock().unwrap().is_some()
+}
+
+fn with_sink(f: impl FnOnce(&mut ProgressSink<Box<dyn Write + Send>>)) {
+    if let Some(sink) = SINK.lock().unwrap().as_mut() {
+        f(sink);
+    }
+}
+
+/// A phase with `total` packages started, the total is used for the ETA
+pub(crate) fn phase_started(phase: Phase, total: Option<usize>) {
+    with_sink(|sink| sink.phase_started(phase, total));
+}
+
+pub(crate) fn package_started(phase: Phase, package: &str) {
+    with_sink(|sink| sink.emit(ProgressEvent::PackageStarted { phase, package }));
+}
+
+/// `error` is set if processing the package failed
+pub(crate) fn package_finished(
+    phase: Phase,
+    package: &str,
+    elapsed: Duration,
+    error: Option<&dyn Display>,
+) {
+    with_sink(|sink| {
+        sink.package_finished(
+            phase,
+            package,
+            elapsed,
+            error.map(|error| error.to_string()),
+        )
+    });
+}
+
+pub(crate) fn phase_finished(phase: Phase) {
+    with_sink(|sink| sink.phase_finished(phase));
+}
+
+/// Emits [ProgressEvent::Bytes] every few hundred kilobytes read through it
+pub(crate) struct ProgressReader<'a, R: Read> {
+    reader: R,
+    phase: Phase,
+    package: &'a str,
+    bytes: u64,
+    last_reported: u64,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    /// Bytes between two events
+    const INTERVAL: u64 = 256 * 1024;
+
+    pub(crate) fn new(reader: R, phase: Phase, package: &'a str) -> Self {
+        Self {
+            reader,
+            phase,
+            package,
+            bytes: 0,
+            last_reported: 0,
+        }
+    }
+
+    /// Reports the final byte count
+    pub(crate) fn finish(self) {
+        let (phase, package, bytes) = (self.phase, self.package, self.bytes);
+        with_sink(|sink| {
+            sink.emit(ProgressEvent::Bytes {
+                phase,
+                package,
+                bytes,
+            })
+        });
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.bytes += read as u64;
+        if self.bytes - self.last_reported >= Self::INTERVAL {
+            self.last_reported = self.bytes;
+            let (phase, package, bytes) = (self.phase, self.package, self.bytes);
+            with_sink(|sink| {
+                sink.emit(ProgressEvent::Bytes {
+                    phase,
+                    package,
+                    bytes,
+                })
+            });
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::progress::{Phase, ProgressEvent, ProgressSink};
+    use serde_json::Value;
+    use std::time::Duration;
+
+    #[test]
+    fn test_events() {
+        let mut sink = ProgressSink::new(Vec::new());
+        sink.phase_started(Phase::Install, Some(2));
+        sink.emit(ProgressEvent::PackageStarted {
+            phase: Phase::Download,
+            package: "tqdm",
+        });
+        sink.emit(ProgressEvent::Bytes {
+            phase: Phase::Download,
+            package: "tqdm",
+            bytes: 78470,
+        });
+        sink.package_finished(Phase::Download, "tqdm", Duration::from_millis(198), None);
+        sink.package_finished(Phase::Install, "tqdm", Duration::from_millis(292), None);
+        sink.package_finished(
+            Phase::Install,
+            "numpy",
+            Duration::from_millis(12),
+            Some("No matching wheel".to_string()),
+        );
+        sink.phase_finished(Phase::Install);
+
+        let lines: Vec<Value> = String::from_utf8(sink.writer)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let events: Vec<(&str, &str)> = lines
+            .iter()
+            .map(|line| {
+                assert!(line["time"].is_number());
+                (
+                    line["event"].as_str().unwrap(),
+                    line["phase"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            events,
+            [
+                ("phase-started", "install"),
+                ("package-started", "download"),
+                ("bytes", "download"),
+                ("package-finished", "download"),
+                ("package-finished", "install"),
+                ("package-finished", "install"),
+                ("phase-finished", "install")
+            ]
+        );
+        assert_eq!(lines[0]["total"], 2);
+        assert_eq!(lines[2]["bytes"], 78470);
+        assert_eq!(lines[3]["elapsed"], 0.198);
+        // Downloads have no total and so no ETA
+        assert_eq!(lines[3].get("eta"), None);
+        assert!(lines[4]["eta"].is_number());
+        // Nothing remaining
+        assert_eq!(lines[5]["eta"], 0.0);
+        assert_eq!(lines[5]["error"], "No matching wheel");
+    }
+}
@@ -0,0 +1,384 @@
+//! `monotrail add` and `monotrail remove`: Edit the dependencies in a pyproject.toml in place
+//! with toml_edit, so comments, key order and formatting stay as they are.
+//!
+//! `[project]` projects get PEP 508 strings in `dependencies`, `[project.optional-dependencies]`
+//! or `[dependency-groups]`, poetry projects get poetry constraints in
+//! `[tool.poetry.dependencies]` or `[tool.poetry.group.<group>.dependencies]`.
+
+use crate::poetry_integration::constraint::pep440_to_poetry;
+use anyhow::{bail, format_err, Context};
+use install_wheel_rs::normalize_name;
+use pep508_rs::{Requirement, VersionOrUrl};
+use std::str::FromStr;
+use toml_edit::{value, Array, Document, InlineTable, Item, Table, Value};
+
+/// Where in the pyproject.toml the dependencies go
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DependencyKind {
+    /// The dependencies of the project
+    Main,
+    /// An extra, i.e. `[project.optional-dependencies]`
+    Optional(String),
+    /// A dependency group, e.g. for the dev tools
+    Group(String),
+}
+
+/// Poetry 2 projects have both a `[project]` and `[tool.poetry]`, the dependencies go where they
+/// already are
+fn is_poetry(doc: &Document) -> bool {
+    let has_poetry_dependencies = doc
+        .get("tool")
+        .and_then(|tool| tool.get("poetry"))
+        .and_then(|poetry| poetry.get("dependencies"))
+        .is_some();
+    match doc.get("project") {
+        Some(project) => project.get("dependencies").is_none() && has_poetry_dependencies,
+        None => true,
+    }
+}
+
+/// The `--extras` and `--group` for locking and syncing that include the dependencies of `kind`.
+/// For poetry projects, the groups are passed as extras
+pub fn lock_selection(
+    pyproject_toml: &str,
+    kind: &DependencyKind,
+) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+    let doc = Document::from_str(pyproject_toml).context("Invalid pyproject.toml")?;
+    Ok(match kind {
+        DependencyKind::Main => (Vec::new(), Vec::new()),
+        DependencyKind::Optional(extra) => (vec![extra.clone()], Vec::new()),
+        DependencyKind::Group(group) if is_poetry(&doc) => (vec![group.clone()], Vec::new()),
+        DependencyKind::Group(group) => (Vec::new(), vec![group.clone()]),
+    })
+}
+
+/// Walks down `path` from `table`, creating missing tables. The intermediate tables are implicit,
+/// so we write `[tool.poetry.group.dev.dependencies]` and not also `[tool.poetry.group.dev]`
+fn table_mut<'a>(mut table: &'a mut Table, path: &[&str]) -> anyhow::Result<&'a mut Table> {
+    for (depth, key) in path.iter().enumerate() {
+        let item = table.entry(key).or_insert_with(|| {
+            let mut new = Table::new();
+            new.set_implicit(depth + 1 < path.len());
+            Item::Table(new)
+        });
+        table = item
+            .as_table_mut()
+            .ok_or_else(|| format_err!("Expected `{}` to be a table", path[..=depth].join(".")))?;
+    }
+    Ok(table)
+}
+
+/// The `[project]` array the requirements of `kind` go in
+fn pep621_array<'a>(doc: &'a mut Document, kind: &DependencyKind) -> anyhow::Result<&'a mut Array> {
+    let (table, key) = match kind {
+        DependencyKind::Main => (table_mut(doc.as_table_mut(), &["project"])?, "dependencies"),
+        DependencyKind::Optional(extra) => (
+            table_mut(doc.as_table_mut(), &["project", "optional-dependencies"])?,
+            extra.as_str(),
+        ),
+        DependencyKind::Group(group) => (
+            table_mut(doc.as_table_mut(), &["dependency-groups"])?,
+            group.as_str(),
+        ),
+    };
+    table
+        .entry(key)
+        .or_insert_with(|| value(Array::new()))
+        .as_array_mut()
+        .ok_or_else(|| format_err!("Expected `{}` to be an array", key))
+}
+
+/// The poetry table the requirements of `kind` go in
+fn poetry_table<'a>(doc: &'a mut Document, kind: &DependencyKind) -> anyhow::Result<&'a mut Table> {
+    match kind {
+        DependencyKind::Main => table_mut(doc.as_table_mut(), &["tool", "poetry", "dependencies"]),
+        DependencyKind::Optional(_) => {
+            bail!(
+                "Optional dependencies of poetry projects need `[tool.poetry.extras]`, please \
+                edit the pyproject.toml directly"
+            )
+        }
+        DependencyKind::Group(group) => table_mut(
+            doc.as_table_mut(),
+            &["tool", "poetry", "group", group, "dependencies"],
+        ),
+    }
+}
+
+/// The index of the requirement for `name` in a `[project]` array
+fn find_in_array(array: &Array, name: &str) -> Option<usize> {
+    array.iter().position(|entry| {
+        entry
+            .as_str()
+            .and_then(|entry| Requirement::from_str(entry).ok())
+            .is_some_and(|requirement| normalize_name(&requirement.name) == name)
+    })
+}
+
+/// The key of the dependency `name` in a poetry table, which may be spelled differently
+fn find_in_table(table: &Table, name: &str) -> Option<String> {
+    table
+        .iter()
+        .map(|(key, _)| key)
+        .find(|key| normalize_name(key) == name)
+        .map(ToString::to_string)
+}
+
+/// `"^2.31"` or `{ version = "^2.31", extras = ["socks"], markers = "..." }`
+fn poetry_value(requirement: &Requirement) -> anyhow::Result<Item> {
+    let version = match &requirement.version_or_url {
+        None => "*".to_string(),
+        Some(VersionOrUrl::VersionSpecifier(specifiers)) => pep440_to_poetry(specifiers),
+        Some(VersionOrUrl::Url(_)) => bail!(
+            "Adding url dependencies to poetry projects isn't supported, please edit the \
+            pyproject.toml directly: {}",
+            requirement
+        ),
+    };
+    let extras = requirement.extras.clone().unwrap_or_default();
+    if extras.is_empty() && requirement.marker.is_none() {
+        return Ok(value(version));
+    }
+    let mut table = InlineTable::new();
+    table.insert("version", version.into());
+    if !extras.is_empty() {
+        table.insert("extras", Array::from_iter(extras).into());
+    }
+    if let Some(marker) = &requirement.marker {
+        table.insert("markers", marker.to_string().into());
+    }
+    Ok(value(table))
+}
+
+/// Adds the requirements to the pyproject.toml, replacing the entries of packages that are
+/// already there, and returns the new pyproject.toml. `[project]` projects get the requirements
+/// as the user wrote them
+pub fn add_dependencies(
+    pyproject_toml: &str,
+    requirements: &[String],
+    kind: &DependencyKind,
+) -> anyhow::Result<String> {
+    let requirements = requirements
+        .iter()
+        .map(|requirement| {
+            Requirement::from_str(requirement)
+                .map(|parsed| (requirement.trim(), parsed))
+                .with_context(|| format!("Invalid requirement: {}", requirement))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let mut doc = Document::from_str(pyproject_toml).context("Invalid pyproject.toml")?;
+    if is_poetry(&doc) {
+        let table = poetry_table(&mut doc, kind)?;
+        for (_, requirement) in &requirements {
+            let mut item = poetry_value(requirement)?;
+            let key = match find_in_table(table, &normalize_name(&requirement.name)) {
+                Some(key) => {
+                    // Keep e.g. a trailing comment
+                    if let (Some(old), Some(new)) = (table[&key].as_value(), item.as_value_mut()) {
+                        *new.decor_mut() = old.decor().clone();
+                    }
+                    key
+                }
+                None => requirement.name.clone(),
+            };
+            table.insert(&key, item);
+        }
+    } else {
+        let array = pep621_array(&mut doc, kind)?;
+        for (entry, requirement) in &requirements {
+            if let Some(index) = find_in_array(array, &normalize_name(&requirement.name)) {
+                array.replace(index, *entry);
+            } else if let Some(last) = array.iter().last() {
+                // Multi-line arrays have the newline and the indentation in the prefix, after the
+                // comments of the previous line
+                let prefix = last.decor().prefix().and_then(|prefix| prefix.as_str());
+                let indent = prefix.map_or(" ", |prefix| {
+                    prefix.rfind('\n').map_or(prefix, |start| &prefix[start..])
+                });
+                let mut entry = Value::from(*entry);
+                entry.decor_mut().set_prefix(indent);
+                array.push_formatted(entry);
+            } else {
+                array.push(*entry);
+            }
+        }
+    }
+    Ok(doc.to_string())
+}
+
+/// Removes the packages from the pyproject.toml and returns the new pyproject.toml. Fails if a
+/// package isn't a dependency of this kind
+pub fn remove_dependencies(
+    pyproject_toml: &str,
+    names: &[String],
+    kind: &DependencyKind,
+) -> anyhow::Result<String> {
+    let mut doc = Document::from_str(pyproject_toml).context("Invalid pyproject.toml")?;
+    if is_poetry(&doc) {
+        let table = poetry_table(&mut doc, kind)?;
+        for name in names {
+            let key = find_in_table(table, &normalize_name(name))
+                .with_context(|| format!("{} is not a dependency of the project", name))?;
+            table.remove(&key);
+        }
+    } else {
+        let array = pep621_array(&mut doc, kind)?;
+        for name in names {
+            let index = find_in_array(array, &normalize_name(name))
+                .with_context(|| format!("{} is not a dependency of the project", name))?;
+            let removed = array.remove(index);
+            // The new first entry shouldn't start with the separating space
+            if index == 0 {
+                if let Some(first) = array.get_mut(0) {
+                    *first.decor_mut() = removed.decor().clone();
+                }
+            }
+        }
+    }
+    Ok(doc.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::project_edit::{add_dependencies, remove_dependencies, DependencyKind};
+    use indoc::indoc;
+
+    fn requirements(requirements: &[&str]) -> Vec<String> {
+        requirements
+            .iter()
+            .map(|requirement| requirement.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_add_remove_pep621() {
+        let pyproject_toml = indoc! {r#"
+            [project]
+            name = "foo"
+            # Keep this comment
+            dependencies = [
+                "tqdm>=4,<5",  # progress bars
+                "Requests>=2",
+            ]
+        "#};
+        let added = add_dependencies(
+            pyproject_toml,
+            &requirements(&["requests[socks]>=2.31", "black"]),
+            &DependencyKind::Main,
+        )
+        .unwrap();
+        assert_eq!(
+            added,
+            indoc! {r#"
+                [project]
+                name = "foo"
+                # Keep this comment
+                dependencies = [
+                    "tqdm>=4,<5",  # progress bars
+                    "requests[socks]>=2.31",
+                    "black",
+                ]
+            "#}
+        );
+        let removed =
+            remove_dependencies(&added, &["TQDM".to_string()], &DependencyKind::Main).unwrap();
+        assert_eq!(
+            removed,
+            indoc! {r#"
+                [project]
+                name = "foo"
+                # Keep this comment
+                dependencies = [
+                    "requests[socks]>=2.31",
+                    "black",
+                ]
+            "#}
+        );
+        let err = remove_dependencies(&removed, &["tqdm".to_string()], &DependencyKind::Main)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "tqdm is not a dependency of the project");
+    }
+
+    #[test]
+    fn test_add_pep621_groups() {
+        let pyproject_toml = indoc! {r#"
+            [project]
+            name = "foo"
+            dependencies = ["tqdm", "attrs"]
+        "#};
+        let added = add_dependencies(
+            pyproject_toml,
+            &requirements(&["pytest>=7"]),
+            &DependencyKind::Group("dev".to_string()),
+        )
+        .unwrap();
+        let added = add_dependencies(
+            &added,
+            &requirements(&["pysocks"]),
+            &DependencyKind::Optional("socks".to_string()),
+        )
+        .unwrap();
+        let removed =
+            remove_dependencies(&added, &["tqdm".to_string()], &DependencyKind::Main).unwrap();
+        assert_eq!(
+            removed,
+            indoc! {r#"
+                [project]
+                name = "foo"
+                dependencies = ["attrs"]
+
+                [project.optional-dependencies]
+                socks = ["pysocks"]
+
+                [dependency-groups]
+                dev = ["pytest>=7"]
+            "#}
+        );
+    }
+
+    #[test]
+    fn test_add_remove_poetry() {
+        let pyproject_toml = indoc! {r#"
+            [tool.poetry]
+            name = "foo"
+
+            [tool.poetry.dependencies]
+            python = "^3.8"
+            Django = "^4.1"  # the web framework
+        "#};
+        let added = add_dependencies(
+            pyproject_toml,
+            &requirements(&[
+                "django>=4.2,<5",
+                "requests[socks]>=2.31,<3 ; python_version >= '3.9'",
+            ]),
+            &DependencyKind::Main,
+        )
+        .unwrap();
+        let added = add_dependencies(
+            &added,
+            &requirements(&["pytest==7.4.0"]),
+            &DependencyKind::Group("dev".to_string()),
+        )
+        .unwrap();
+        assert_eq!(
+            added,
+            indoc! {r#"
+                [tool.poetry]
+                name = "foo"
+
+                [tool.poetry.dependencies]
+                python = "^3.8"
+                Django = "^4.2"  # the web framework
+                requests = { version = "^2.31", extras = ["socks"], markers = "python_version >= '3.9'" }
+
+                [tool.poetry.group.dev.dependencies]
+                pytest = "7.4.0"
+            "#}
+        );
+        let removed =
+            remove_dependencies(&added, &["django".to_string()], &DependencyKind::Main).unwrap();
+        assert!(!removed.contains("Django"));
+        assert!(removed.contains("python = \"^3.8\""));
+    }
+}
@@ -0,0 +1,238 @@
+//! `monotrail lock`: Resolves the project again while keeping the versions of the previous lock
+//! where possible, so only the packages that have to (or were asked to) change get new versions
+
+use crate::monotrail::PythonContext;
+use crate::pep621::read_pep621_project;
+use crate::poetry_integration::lock::poetry_resolve;
+use crate::poetry_integration::poetry_lock::PoetryLock;
+use crate::workspace::{Workspace, WORKSPACE_LOCK};
+use anyhow::{bail, Context};
+use fs_err as fs;
+use install_wheel_rs::normalize_name;
+use std::collections::BTreeMap;
+use std::env::current_exe;
+use std::fmt::{self, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::info;
+
+/// Which locked versions may change
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Upgrade {
+    /// Keep all locked versions that still match the requirements
+    #[default]
+    None,
+    /// Resolve from scratch, `--upgrade`
+    All,
+    /// Only these packages may move, `--upgrade-package`
+    Packages(Vec<String>),
+}
+
+impl Upgrade {
+    /// From `--upgrade` and `--upgrade-package`
+    pub fn from_args(upgrade: bool, upgrade_packages: &[String]) -> Self {
+        if upgrade {
+            Self::All
+        } else if upgrade_packages.is_empty() {
+            Self::None
+        } else {
+            Self::Packages(upgrade_packages.to_vec())
+        }
+    }
+}
+
+/// A package that was added, removed or changed its version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockChange {
+    /// The package name as in the lock
+    pub name: String,
+    /// The version in the previous lock
+    pub old: Option<String>,
+    /// The version in the new lock
+    pub new: Option<String>,
+}
+
+impl Display for LockChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => write!(f, "Updated {} {} -> {}", self.name, old, new),
+            (None, Some(new)) => write!(f, "Added {} {}", self.name, new),
+            (Some(old), None) => write!(f, "Removed {} {}", self.name, old),
+            (None, None) => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// Removes the packages that may change from the previous lockfile, so the resolver (ours or
+/// poetry) doesn't prefer their locked version. Returns `None` if nothing of the lockfile should
+/// be kept
+pub fn prune_lockfile(lockfile: &str, upgrade: &Upgrade) -> anyhow::Result<Option<String>> {
+    let names = match upgrade {
+        Upgrade::None => return Ok(Some(lockfile.to_string())),
+        Upgrade::All => return Ok(None),
+        Upgrade::Packages(names) => names,
+    };
+    let names: Vec<String> = names.iter().map(|name| normalize_name(name)).collect();
+    let mut lock: toml::Table = toml::from_str(lockfile).context("Invalid lockfile")?;
+    if let Some(toml::Value::Array(packages)) = lock.get_mut("package") {
+        packages.retain(|package| {
+            let name = package.get("name").and_then(toml::Value::as_str);
+            !name.is_some_and(|name| names.contains(&normalize_name(name)))
+        });
+    }
+    // Lock version 1.1 has the files in the metadata
+    if let Some(toml::Value::Table(files)) = lock
+        .get_mut("metadata")
+        .and_then(|metadata| metadata.get_mut("files"))
+    {
+        files.retain(|name, _| !names.contains(&normalize_name(name)));
+    }
+    Ok(Some(toml::to_string(&lock)?))
+}
+
+/// The version changes between two lockfiles, sorted by name
+pub fn lock_changes(old: Option<&str>, new: &str) -> anyhow::Result<Vec<LockChange>> {
+    let versions = |lockfile: &str| -> anyhow::Result<BTreeMap<String, (String, String)>> {
+        Ok(PoetryLock::from_str(lockfile)?
+            .package
+            .into_iter()
+            .map(|package| {
+                (
+                    normalize_name(&package.name),
+                    (package.name, package.version),
+                )
+            })
+            .collect())
+    };
+    let old = match old {
+        Some(old) => versions(old)?,
+        None => BTreeMap::new(),
+    };
+    let new = versions(new)?;
+    let mut names: Vec<&String> = old.keys().chain(new.keys()).collect();
+    names.sort();
+    names.dedup();
+    Ok(names
+        .into_iter()
+        .filter_map(|name| {
+            let old = old.get(name);
+            let new = new.get(name);
+            if old.map(|(_, version)| version) == new.map(|(_, version)| version) {
+                return None;
+            }
+            Some(LockChange {
+                name: new.or(old).unwrap().0.clone(),
+                old: old.map(|(_, version)| version.clone()),
+                new: new.map(|(_, version)| version.clone()),
+            })
+        })
+        .collect())
+}
+
+/// Locks the workspace or the `[project]` in `dir` to its `monotrail.lock`, starting from the
+/// previous lock. Returns the lockfile path and the changes. Poetry projects are delegated to
+/// the bundled poetry since poetry owns the poetry.lock
+pub fn lock_project(
+    dir: &Path,
+    extras: &[String],
+    groups: &[String],
+    upgrade: &Upgrade,
+    python_context: &PythonContext,
+) -> anyhow::Result<(PathBuf, Vec<LockChange>)> {
+    let (requirements, lock_path) = if let Some(workspace) = Workspace::find(dir)? {
+        let requirements =
+            workspace.requirements_for_poetry(extras, groups, &python_context.pep508_env)?;
+        (requirements, workspace.root.join(WORKSPACE_LOCK))
+    } else if let Some(project) = read_pep621_project(dir)? {
+        let requirements =
+            project.requirements_for_poetry(extras, groups, &python_context.pep508_env)?;
+        (requirements, dir.join(WORKSPACE_LOCK))
+    } else if dir.join("poetry.lock").is_file() || dir.join("pyproject.toml").is_file() {
+        let old = fs::read_to_string(dir.join("poetry.lock")).ok();
+        poetry_lock_subprocess(dir, upgrade)?;
+        let lock_path = dir.join("poetry.lock");
+        let changes = lock_changes(old.as_deref(), &fs::read_to_string(&lock_path)?)?;
+        return Ok((lock_path, changes));
+    } else {
+        bail!("No pyproject.toml in {}", dir.display());
+    };
+
+    let previous = if lock_path.is_file() {
+        Some(fs::read_to_string(&lock_path)?)
+    } else {
+        None
+    };
+    let preferences = match &previous {
+        Some(previous) => prune_lockfile(previous, upgrade)
+            .with_context(|| format!("Invalid lockfile {}", lock_path.display()))?,
+        None => None,
+    };
+    let (_poetry_section, _poetry_lock, lockfile) =
+        poetry_resolve(&requirements, preferences.as_deref(), python_context)
+            .context("Failed to resolve dependencies")?;
+    let changes = lock_changes(previous.as_deref(), &lockfile)?;
+    if previous.as_deref() != Some(lockfile.as_str()) {
+        fs::write(&lock_path, &lockfile)?;
+    }
+    Ok((lock_path, changes))
+}
+
+/// `poetry lock --no-update` or `poetry update --lock [packages]` with the bundled poetry, in a
+/// subprocess so as not to inject poetry's python packages into this process
+fn poetry_lock_subprocess(dir: &Path, upgrade: &Upgrade) -> anyhow::Result<()> {
+    let mut args = vec!["poetry".to_string()];
+    match upgrade {
+        Upgrade::None => args.extend(["lock".to_string(), "--no-update".to_string()]),
+        Upgrade::All => args.extend(["update".to_string(), "--lock".to_string()]),
+        Upgrade::Packages(names) => {
+            args.extend(["update".to_string(), "--lock".to_string()]);
+            args.extend(names.iter().cloned());
+        }
+    }
+    info!("Running `{} {}`", env!("CARGO_PKG_NAME"), args.join(" "));
+    let current_exe = current_exe().context("Couldn't determine currently running program 🤨")?;
+    let status = Command::new(&current_exe)
+        .args(&args)
+        .current_dir(dir)
+        .status()
+        .with_context(|| format!("Failed to run `{} {}`", current_exe.display(), args[1]))?;
+    if !status.success() {
+        bail!(
+            "Failed to run `{} {}`: {}",
+            current_exe.display(),
+            args.join(" "),
+            status
+        )
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::poetry_integration::poetry_lock::PoetryLock;
+    use crate::project_lock::{lock_changes, prune_lockfile, Upgrade};
+    use fs_err as fs;
+
+    #[test]
+    fn test_prune_lockfile() {
+        let lockfile = fs::read_to_string("../../test-data/poetry-1.1.13/poetry.lock").unwrap();
+        assert_eq!(
+            prune_lockfile(&lockfile, &Upgrade::None)
+                .unwrap()
+                .as_deref(),
+            Some(lockfile.as_str())
+        );
+        assert_eq!(prune_lockfile(&lockfile, &Upgrade::All).unwrap(), None);
+
+        let upgrade = Upgrade::from_args(false, &["Certifi".to_string()]);
+        let pruned = prune_lockfile(&lockfile, &upgrade).unwrap().unwrap();
+        let pruned_lock = PoetryLock::from_str(&pruned).unwrap();
+        assert!(pruned_lock.get_filenames("certifi").is_none());
+        let changes: Vec<String> = lock_changes(Some(&lockfile), &pruned)
+            .unwrap()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(changes, ["Removed certifi 2022.5.18.1"]);
+    }
+}
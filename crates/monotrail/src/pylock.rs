@@ -0,0 +1,358 @@
+//! Export the locked environment as a PEP 751 `pylock.toml`, so other installers can reproduce
+//! the environment without monotrail.
+//!
+//! The packages were selected for one environment (the markers are already evaluated), which is
+//! recorded in `environments`. Each package lists all files of its release with hashes, so
+//! installers can still pick the best wheel themselves. `MONOTRAIL_ONLY_BINARY` drops the source
+//! distribution and `MONOTRAIL_NO_BINARY` the wheels, so they pick what we would have picked.
+//!
+//! <https://peps.python.org/pep-0751/>
+
+use crate::download::download_distribution_cached;
+use crate::package_index::{release_files, PackageType, SourcePreference, SourcePreferences};
+use crate::path_dependency::is_path_source;
+use crate::spec::{DistributionType, RequestedSpec};
+use anyhow::{bail, Context};
+use fs_err as fs;
+use install_wheel_rs::{integrity_hash, to_hex};
+use monotrail_utils::transport::Transport;
+use pep508_rs::MarkerEnvironment;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The top level table
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct PyLock {
+    lock_version: String,
+    environments: Vec<String>,
+    requires_python: String,
+    created_by: String,
+    packages: Vec<Package>,
+}
+
+/// `[[packages]]`
+#[derive(Serialize, Debug, Default)]
+#[serde(rename_all = "kebab-case")]
+struct Package {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vcs: Option<Vcs>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    directory: Option<Directory>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sdist: Option<Archive>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    wheels: Vec<Archive>,
+}
+
+/// `[packages.vcs]`
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct Vcs {
+    #[serde(rename = "type")]
+    vcs_type: String,
+    url: String,
+    requested_revision: String,
+    commit_id: String,
+}
+
+/// `[packages.directory]`, a local project
+#[derive(Serialize, Debug)]
+struct Directory {
+    path: String,
+}
+
+/// `[packages.sdist]` and `[[packages.wheels]]`
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct Archive {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    hashes: BTreeMap<String, String>,
+}
+
+impl Archive {
+    /// A distribution we only have as file, hashed locally
+    fn from_file(name: &str, file: &Path, url: Option<String>) -> anyhow::Result<Self> {
+        let (hash_name, digest) = integrity_hash(&mut fs::File::open(file)?)?;
+        Ok(Self {
+            name: name.to_string(),
+            path: url.is_none().then(|| file.to_string_lossy().to_string()),
+            url,
+            size: Some(fs::metadata(file)?.len()),
+            hashes: BTreeMap::from([(hash_name.to_string(), to_hex(&digest))]),
+        })
+    }
+}
+
+/// The marker that describes the environment the specs were selected for
+fn environment_marker(pep508_env: &MarkerEnvironment) -> String {
+    format!(
+        "implementation_name == \"{}\" and python_version == \"{}\" and sys_platform == \"{}\" and platform_machine == \"{}\"",
+        pep508_env.implementation_name,
+        pep508_env.python_version.string,
+        pep508_env.sys_platform,
+        pep508_env.platform_machine
+    )
+}
+
+/// The entry for one package, with all files of the release for packages from the index
+fn package(
+    spec: &RequestedSpec,
+    host: &str,
+    transport: &dyn Transport,
+    preferences: &SourcePreferences,
+) -> anyhow::Result<Package> {
+    let version = spec
+        .python_version
+        .clone()
+        .with_context(|| format!("{} has no version, is the lock complete?", spec.name))?;
+    let mut package = Package {
+        name: spec.normalized_name(),
+        version: Some(version.clone()),
+        ..Package::default()
+    };
+    if let Some(source) = spec
+        .source
+        .as_ref()
+        .filter(|source| is_path_source(&source.source_type))
+    {
+        let path = Path::new(&source.url);
+        if path.is_dir() {
+            // Like a git checkout, the version is whatever the source tree says
+            package.version = None;
+            package.directory = Some(Directory {
+                path: source.url.clone(),
+            });
+        } else {
+            let name = path
+                .file_name()
+                .with_context(|| format!("Expected a file: {}", path.display()))?
+                .to_string_lossy();
+            let archive = Archive::from_file(&name, path, None)?;
+            if name.ends_with(".whl") {
+                package.wheels.push(archive);
+            } else {
+                package.sdist = Some(archive);
+            }
+        }
+    } else if let Some(source) = &spec.source {
+        // The version of a git dependency is whatever the checkout says
+        package.version = None;
+        package.vcs = Some(Vcs {
+            vcs_type: source.source_type.clone(),
+            url: source.url.clone(),
+            requested_revision: source.reference.clone(),
+            commit_id: source.resolved_reference.clone(),
+        });
+    } else if let Some((path, _filename)) = &spec.file_path {
+        let name = path
+            .file_name()
+            .with_context(|| format!("Expected a file: {}", path.display()))?
+            .to_string_lossy();
+        package.wheels.push(Archive::from_file(&name, path, None)?);
+    } else if let Some((url, filename, distribution_type)) = &spec.url {
+        // We need the file for the hash anyway and we'll probably install it later
+        let file =
+            download_distribution_cached(transport, &spec.name, &version, filename, url, None)
+                .with_context(|| format!("Failed to download {}", spec.requested))?;
+        let archive = Archive::from_file(filename, &file, Some(url.clone()))?;
+        match distribution_type {
+            DistributionType::Wheel => package.wheels.push(archive),
+            DistributionType::SourceDistribution => package.sdist = Some(archive),
+        }
+    } else {
+        package.index = Some(format!("{}/simple", host));
+        for file in release_files(host, transport, &spec.name, &version)? {
+            let Some(sha256) = file.digests.get("sha256") else {
+                bail!("pypi has no sha256 for {}", file.filename);
+            };
+            let archive = Archive {
+                name: file.filename,
+                url: Some(file.url),
+                path: None,
+                size: Some(file.size as u64),
+                hashes: BTreeMap::from([("sha256".to_string(), sha256.clone())]),
+            };
+            match file.packagetype {
+                PackageType::BdistWheel => package.wheels.push(archive),
+                PackageType::Sdist => package.sdist = Some(archive),
+                // PEP 751 only knows wheels and source distributions
+                _ => {}
+            }
+        }
+        match preferences.for_package(&spec.name) {
+            SourcePreference::Wheel if package.wheels.is_empty() => bail!(
+                "{} {} has no wheel, but MONOTRAIL_ONLY_BINARY forbids building it",
+                spec.name,
+                version
+            ),
+            SourcePreference::Wheel => package.sdist = None,
+            SourcePreference::Sdist if package.sdist.is_some() => package.wheels.clear(),
+            _ => {}
+        }
+    }
+    Ok(package)
+}
+
+/// Returns the `pylock.toml` for the specs, which were selected for `pep508_env`
+pub fn pylock_toml(
+    specs: &[RequestedSpec],
+    pep508_env: &MarkerEnvironment,
+    host: &str,
+    transport: &dyn Transport,
+) -> anyhow::Result<String> {
+    let mut specs = specs.to_vec();
+    specs.sort_by_key(|spec| spec.normalized_name());
+    let preferences = SourcePreferences::from_env();
+    let packages = specs
+        .iter()
+        .map(|spec| {
+            package(spec, host, transport, &preferences)
+                .with_context(|| format!("Failed to export {}", spec.requested))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let pylock = PyLock {
+        lock_version: "1.0".to_string(),
+        environments: vec![environment_marker(pep508_env)],
+        requires_python: format!("=={}.*", pep508_env.python_version.string),
+        created_by: env!("CARGO_PKG_NAME").to_string(),
+        packages,
+    };
+    toml::to_string(&pylock).context("Failed to serialize pylock.toml")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::package_index::SourcePreferences;
+    use crate::pylock::{package, pylock_toml};
+    use crate::spec::{RequestedSpec, SpecSource};
+    use crate::test_utils::test_pep508_env;
+    use monotrail_utils::transport::MockTransport;
+
+    #[test]
+    fn test_pylock_toml() {
+        let cffi_json = zstd::stream::decode_all(
+            fs_err::File::open("../../test-data/pypi/cffi.json.zstd").unwrap(),
+        )
+        .unwrap();
+        let transport =
+            MockTransport::default().with_response("https://pypi.org/pypi/cffi/json", cffi_json);
+        let specs = [
+            RequestedSpec {
+                requested: "cffi 1.15.1".to_string(),
+                name: "cffi".to_string(),
+                python_version: Some("1.15.1".to_string()),
+                specifiers: None,
+                source: None,
+                extras: vec![],
+                file_path: None,
+                url: None,
+            },
+            RequestedSpec::from_requested(
+                "../../test-data/wheels/miniblack-23.1.0-py3-none-any.whl",
+                &[],
+            )
+            .unwrap(),
+            RequestedSpec {
+                requested: "mylib 0.1.0".to_string(),
+                name: "mylib".to_string(),
+                python_version: Some("0.1.0".to_string()),
+                specifiers: None,
+                source: Some(SpecSource {
+                    source_type: "git".to_string(),
+                    url: "https://github.com/example/mylib".to_string(),
+                    reference: "main".to_string(),
+                    resolved_reference: "b0bab0b0bab0b0bab0b0bab0b0bab0b0bab0b0ba".to_string(),
+                }),
+                extras: vec![],
+                file_path: None,
+                url: None,
+            },
+        ];
+        let pylock =
+            pylock_toml(&specs, &test_pep508_env(), "https://pypi.org", &transport).unwrap();
+        let pylock: toml::Value = toml::from_str(&pylock).unwrap();
+        assert_eq!(pylock["lock-version"].as_str(), Some("1.0"));
+        assert_eq!(pylock["requires-python"].as_str(), Some("==3.8.*"));
+        let packages = pylock["packages"].as_array().unwrap();
+        let names: Vec<&str> = packages
+            .iter()
+            .map(|package| package["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["cffi", "miniblack", "mylib"]);
+
+        let cffi = &packages[0];
+        assert_eq!(cffi["index"].as_str(), Some("https://pypi.org/simple"));
+        assert_eq!(cffi["sdist"]["name"].as_str(), Some("cffi-1.15.1.tar.gz"));
+        // All wheels of the release, not only the one for this platform
+        assert_eq!(cffi["wheels"].as_array().unwrap().len(), 63);
+        assert_eq!(
+            cffi["wheels"][0]["hashes"]["sha256"].as_str(),
+            Some("a66d3508133af6e8548451b25058d5812812ec3798c886bf38ed24a98216fab2")
+        );
+
+        let miniblack = &packages[1];
+        assert!(miniblack["wheels"][0]["path"]
+            .as_str()
+            .unwrap()
+            .ends_with("miniblack-23.1.0-py3-none-any.whl"));
+        assert_eq!(
+            miniblack["wheels"][0]["hashes"]["sha256"]
+                .as_str()
+                .unwrap()
+                .len(),
+            64
+        );
+
+        let mylib = &packages[2];
+        assert_eq!(mylib.get("version"), None);
+        assert_eq!(mylib["vcs"]["type"].as_str(), Some("git"));
+        assert_eq!(mylib["vcs"]["requested-revision"].as_str(), Some("main"));
+    }
+
+    #[test]
+    fn test_pylock_source_preference() {
+        let cffi_json = zstd::stream::decode_all(
+            fs_err::File::open("../../test-data/pypi/cffi.json.zstd").unwrap(),
+        )
+        .unwrap();
+        let transport =
+            MockTransport::default().with_response("https://pypi.org/pypi/cffi/json", cffi_json);
+        let spec = RequestedSpec {
+            requested: "cffi 1.15.1".to_string(),
+            name: "cffi".to_string(),
+            python_version: Some("1.15.1".to_string()),
+            specifiers: None,
+            source: None,
+            extras: vec![],
+            file_path: None,
+            url: None,
+        };
+        let only_binary = SourcePreferences {
+            no_binary: String::new(),
+            only_binary: "cffi".to_string(),
+        };
+        let cffi = package(&spec, "https://pypi.org", &transport, &only_binary).unwrap();
+        assert!(cffi.sdist.is_none());
+        assert_eq!(cffi.wheels.len(), 63);
+        let no_binary = SourcePreferences {
+            no_binary: "cffi".to_string(),
+            only_binary: String::new(),
+        };
+        let cffi = package(&spec, "https://pypi.org", &transport, &no_binary).unwrap();
+        assert_eq!(cffi.sdist.unwrap().name, "cffi-1.15.1.tar.gz");
+        assert!(cffi.wheels.is_empty());
+    }
+}
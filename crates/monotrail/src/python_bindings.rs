@@ -17,9 +17,13 @@ use crate::{read_poetry_specs, PEP508_QUERY_ENV};
 use anyhow::{bail, Context};
 use install_wheel_rs::Script;
 use monotrail_utils::parse_cpython_args::naive_python_arg_parser;
-use pyo3::exceptions::PyRuntimeError;
+use monotrail_utils::{RequirementEntry, RequirementSource, RequirementsTxt};
+use pep508_rs::VersionOrUrl;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
 use pyo3::types::PyModule;
-use pyo3::{pyfunction, pymodule, wrap_pyfunction, Py, PyAny, PyErr, PyResult, Python};
+use pyo3::{
+    pyclass, pyfunction, pymethods, pymodule, wrap_pyfunction, Py, PyAny, PyErr, PyResult, Python,
+};
 use std::collections::BTreeMap;
 use std::env;
 use std::path::{Path, PathBuf};
@@ -106,7 +110,8 @@ pub fn monotrail_from_args(py: Python, args: Vec<String>) -> PyResult<InjectData
     debug!("extras: {:?}", extras);
 
     let (specs, scripts, lockfile, project_dir) =
-        load_specs(script.as_deref(), &extras, &python_context).map_err(format_monotrail_error)?;
+        load_specs(script.as_deref(), &extras, &[], &python_context)
+            .map_err(format_monotrail_error)?;
     let finder_data = install(
         &specs,
         scripts,
@@ -134,8 +139,15 @@ pub fn monotrail_from_requested(
         poetry_resolve(&requested, lockfile.as_deref(), &python_context)
             .context("Failed to resolve requested dependencies through poetry")
             .map_err(format_monotrail_error)?;
-    let specs = read_poetry_specs(&poetry_section, poetry_lock, false, &[], &pep508_env)
-        .map_err(format_monotrail_error)?;
+    let specs = read_poetry_specs(
+        &poetry_section,
+        poetry_lock,
+        &env::current_dir()?,
+        false,
+        &[],
+        &pep508_env,
+    )
+    .map_err(format_monotrail_error)?;
 
     let finder_data = install(&specs, BTreeMap::new(), lockfile, None, &python_context);
     injectable(finder_data, &python_context.sys_executable)
@@ -182,7 +194,7 @@ pub fn monotrail_from_dir(py: Python, dir: PathBuf, extras: Vec<String>) -> PyRe
     debug!("extras: {:?}", extras);
 
     let (specs, scripts, lockfile, project_dir) =
-        load_specs(Some(&dir), &extras, &python_context).map_err(format_monotrail_error)?;
+        load_specs(Some(&dir), &extras, &[], &python_context).map_err(format_monotrail_error)?;
     let finder_data = install(
         &specs,
         scripts,
@@ -221,6 +233,96 @@ pub fn monotrail_find_scripts(
     find_scripts(&sprawl_packages, &sprawl_root).map_err(format_monotrail_error)
 }
 
+/// A requirement from a requirements.txt, flattened for python
+#[pyclass(name = "Requirement", get_all)]
+#[derive(Clone)]
+pub struct PyRequirement {
+    /// The PEP 508 string, `<name> @ <url>` for bare urls and paths
+    requirement: String,
+    name: String,
+    /// The version specifiers such as `>=2.8.1,==2.8.*`, `None` for urls and without version
+    specifier: Option<String>,
+    /// For `<name> @ <url>`, bare urls and paths
+    url: Option<String>,
+    extras: Vec<String>,
+    marker: Option<String>,
+    hashes: Vec<String>,
+    /// `registry`, `url`, `path` or `editable`
+    source: String,
+}
+
+impl From<RequirementEntry> for PyRequirement {
+    fn from(entry: RequirementEntry) -> Self {
+        let (specifier, url) = match &entry.requirement.version_or_url {
+            None => (None, None),
+            Some(VersionOrUrl::VersionSpecifier(specifiers)) => {
+                (Some(specifiers.to_string()), None)
+            }
+            Some(VersionOrUrl::Url(url)) => (None, Some(url.to_string())),
+        };
+        let source = match entry.source {
+            RequirementSource::Registry => "registry",
+            RequirementSource::Url(_) => "url",
+            RequirementSource::Path(_) => "path",
+            RequirementSource::Editable => "editable",
+        };
+        Self {
+            requirement: entry.requirement.to_string(),
+            name: entry.requirement.name,
+            specifier,
+            url,
+            extras: entry.requirement.extras.unwrap_or_default(),
+            marker: entry.requirement.marker.map(|marker| marker.to_string()),
+            hashes: entry.hashes,
+            source: source.to_string(),
+        }
+    }
+}
+
+/// The strict requirements.txt parser of the cli, with `-r`/`-c` includes and `${VAR}`
+/// expansion
+#[pyclass(name = "RequirementsTxt", get_all)]
+pub struct PyRequirementsTxt {
+    requirements: Vec<PyRequirement>,
+    /// The `-c` constraints as PEP 508 strings
+    constraints: Vec<String>,
+}
+
+#[pymethods]
+impl PyRequirementsTxt {
+    /// Relative paths in the file are relative to `working_dir`, which defaults to the current
+    /// directory. Raises `ValueError` with the error code and the position for broken files
+    #[staticmethod]
+    #[pyo3(signature = (requirements_txt, working_dir=None))]
+    fn parse(
+        py: Python,
+        requirements_txt: PathBuf,
+        working_dir: Option<PathBuf>,
+    ) -> PyResult<Self> {
+        let working_dir = match working_dir {
+            Some(working_dir) => working_dir,
+            None => env::current_dir()?,
+        };
+        let parsed = py
+            .allow_threads(|| RequirementsTxt::parse(&requirements_txt, &working_dir))
+            .map_err(|err| {
+                let mut accumulator = format!("{} ({})", err, err.code());
+                for cause in anyhow::Error::new(err).chain().skip(1) {
+                    accumulator.push_str(&format!("\n  Caused by: {}", cause));
+                }
+                PyValueError::new_err(accumulator)
+            })?;
+        Ok(Self {
+            requirements: parsed
+                .requirements
+                .into_iter()
+                .map(PyRequirement::from)
+                .collect(),
+            constraints: parsed.constraints.iter().map(ToString::to_string).collect(),
+        })
+    }
+}
+
 fn parse_extras() -> anyhow::Result<Vec<String>> {
     let extras_env_var = format!("{}_EXTRAS", env!("CARGO_PKG_NAME").to_uppercase());
     let extras = if let Some(extras) = env::var_os(&extras_env_var) {
@@ -270,5 +372,7 @@ pub fn monotrail(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_class::<InstalledPackage>()?;
     m.add_class::<Script>()?;
     m.add_class::<FinderData>()?;
+    m.add_class::<PyRequirement>()?;
+    m.add_class::<PyRequirementsTxt>()?;
     Ok(())
 }
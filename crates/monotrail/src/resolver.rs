@@ -0,0 +1,1687 @@
+//! Resolves requirements with pubgrub directly against the index, so we don't need to bootstrap
+//! poetry and run it in a subprocess. The versions come from the simple api (see [crate::index])
+//! and the dependencies from the PEP 658 metadata files, or if the index doesn't have them, from
+//! the wheel through range requests (see [crate::lazy_wheel]), so we never download a whole
+//! distribution.
+//!
+//! Versions that don't support the target python are skipped, by the `requires-python` of the
+//! index files or, if the index doesn't have it, by the `Requires-Python` of the metadata.
+//!
+//! Extras are separate packages (`black[d]`) that depend on the base package with the same
+//! version. Requirements from git (`name @ git+https://...`) are checked out into the cache and
+//! have a single version, same as local paths (`name @ file:///...`, see
+//! [crate::path_dependency]). Their metadata comes from the build backend (see
+//! [crate::build_frontend]). The result is written as poetry.lock, so reading the specs and
+//! reusing the lockfile on the next run work the same for both resolvers. If the index doesn't
+//! have what we need (e.g. a release with only a source distribution) or the requirements use
+//! poetry syntax, [crate::poetry_integration::lock::poetry_resolve] falls back to poetry.
+//!
+//! <https://peps.python.org/pep-0658/>
+
+use crate::build_frontend::wheel_metadata;
+use crate::config_settings::{ConfigSettings, PackageConfigSettings};
+use crate::index::{project_files, IndexFile, IndexUrls};
+use crate::install::{copy_checkout, git_checkout};
+use crate::lazy_wheel;
+use crate::monotrail::{LaunchType, PythonContext};
+use crate::package_index::{
+    yanked_error, PreReleasePolicy, SourcePreference, SourcePreferences, YankedPolicy,
+};
+use crate::path_dependency::{absolute_path, path_metadata, source_type};
+use crate::poetry_integration::constraint::poetry_to_pep440;
+use crate::poetry_integration::lock::dummy_poetry_pyproject_toml;
+use crate::poetry_integration::poetry_lock::{self, content_hash, PoetryLock};
+use crate::poetry_integration::poetry_toml::{self, PoetrySection};
+use anyhow::{bail, format_err, Context};
+use install_wheel_rs::normalize_name;
+use monotrail_utils::transport::Transport;
+use pep440_rs::{Operator, Version, VersionSpecifier, VersionSpecifiers};
+use pep508_rs::{MarkerEnvironment, Requirement, VersionOrUrl};
+use pubgrub::{
+    DefaultStringReporter, Dependencies, DependencyConstraints, DependencyProvider,
+    PackageResolutionStatistics, PubGrubError, Ranges, Reporter,
+};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt::{self, Display, Formatter};
+use std::io::Read;
+use std::ops::Bound;
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
+use tracing::debug;
+
+/// Which resolver to use, set with `MONOTRAIL_RESOLVER`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum ResolverChoice {
+    /// The native resolver, falling back to poetry if it fails (default)
+    Auto,
+    /// Only the native resolver, e.g. to see why it failed
+    Native,
+    /// Only poetry
+    Poetry,
+}
+
+impl ResolverChoice {
+    pub(crate) fn from_env() -> anyhow::Result<Self> {
+        let env_var = format!("{}_RESOLVER", env!("CARGO_PKG_NAME").to_uppercase());
+        match env::var(&env_var).as_deref() {
+            Err(_) | Ok("") | Ok("auto") => Ok(Self::Auto),
+            Ok("native") => Ok(Self::Native),
+            Ok("poetry") => Ok(Self::Poetry),
+            Ok(other) => bail!(
+                "Invalid {}={}, expected one of auto, native or poetry",
+                env_var,
+                other
+            ),
+        }
+    }
+}
+
+/// How the resolver picks versions besides the requirements
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub(crate) struct ResolverOptions {
+    pub(crate) yanked: YankedPolicy,
+    pub(crate) pre_releases: PreReleasePolicy,
+    pub(crate) source_preferences: SourcePreferences,
+}
+
+impl ResolverOptions {
+    /// `--yanked` and `--pre` or `MONOTRAIL_YANKED` and `MONOTRAIL_PRE`, and
+    /// `MONOTRAIL_NO_BINARY` and `MONOTRAIL_ONLY_BINARY`
+    pub(crate) fn current() -> Self {
+        Self {
+            yanked: YankedPolicy::from_env(),
+            pre_releases: PreReleasePolicy::current(),
+            source_preferences: SourcePreferences::from_env(),
+        }
+    }
+}
+
+/// A node in the resolution, the requirements are the root. With multiple roots, each root is a
+/// virtual package and every package belongs to one root, so the roots don't constrain each other
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+enum PubGrubPackage {
+    Root,
+    /// The requirements of one of multiple roots, e.g. a tool next to the project
+    Requirements(usize),
+    /// `extra` is a virtual package with the dependencies of the extra
+    Package {
+        root: usize,
+        name: String,
+        extra: Option<String>,
+    },
+}
+
+impl PubGrubPackage {
+    fn new(root: usize, name: &str, extra: Option<&str>) -> Self {
+        Self::Package {
+            root,
+            name: normalize_name(name),
+            extra: extra.map(ToString::to_string),
+        }
+    }
+}
+
+impl Display for PubGrubPackage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PubGrubPackage::Root => f.write_str("the requirements"),
+            PubGrubPackage::Requirements(root) => {
+                write!(f, "the requirements of root {}", root + 1)
+            }
+            PubGrubPackage::Package {
+                name, extra: None, ..
+            } => f.write_str(name),
+            PubGrubPackage::Package {
+                name,
+                extra: Some(extra),
+                ..
+            } => write!(f, "{}[{}]", name, extra),
+        }
+    }
+}
+
+/// The installable files of each version
+type Releases = BTreeMap<Version, Vec<IndexFile>>;
+
+/// A version is yanked if all of its files are, see [IndexProvider::versions]
+fn is_yanked(files: &[IndexFile]) -> bool {
+    files.iter().all(|file| file.yanked.is_some())
+}
+
+/// The first reason the index gave for yanking
+fn yanked_reason(files: &[IndexFile]) -> Option<&str> {
+    files
+        .iter()
+        .filter_map(|file| file.yanked.as_deref())
+        .find(|reason| !reason.is_empty())
+}
+
+/// The fields of the core metadata we need
+#[derive(Debug, Default)]
+struct Metadata {
+    version: Option<Version>,
+    requires_dist: Vec<Requirement>,
+    requires_python: Option<VersionSpecifiers>,
+}
+
+/// Reads the headers of a METADATA file, the description after them is skipped
+fn parse_metadata(content: &str) -> anyhow::Result<Metadata> {
+    let mut metadata = Metadata::default();
+    for line in content.lines() {
+        if line.is_empty() {
+            break;
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            // Continuation of a multiline value
+            continue;
+        };
+        if key == "Version" {
+            metadata.version = Some(
+                Version::from_str(value.trim())
+                    .map_err(|err| format_err!("Invalid Version {}: {}", value.trim(), err))?,
+            );
+        } else if key == "Requires-Dist" {
+            let requirement = Requirement::from_str(value.trim())
+                .with_context(|| format!("Invalid Requires-Dist: {}", value.trim()))?;
+            metadata.requires_dist.push(requirement);
+        } else if key == "Requires-Python" {
+            match VersionSpecifiers::from_str(value.trim()) {
+                Ok(specifiers) => metadata.requires_python = Some(specifiers),
+                Err(err) => debug!("Ignoring invalid Requires-Python {}: {}", value.trim(), err),
+            }
+        }
+    }
+    Ok(metadata)
+}
+
+/// The version bounds of `==1.2.*` and `~=1.2.3`: the release with the last part incremented
+fn bump_release(release: &[u64]) -> Version {
+    let mut release = release.to_vec();
+    if let Some(last) = release.last_mut() {
+        *last += 1;
+    }
+    Version::from_release(release)
+}
+
+fn specifier_to_range(specifier: &VersionSpecifier) -> Ranges<Version> {
+    let version = specifier.version().clone();
+    match specifier.operator() {
+        Operator::Equal | Operator::ExactEqual => Ranges::singleton(version),
+        Operator::NotEqual => Ranges::singleton(version).complement(),
+        Operator::EqualStar => {
+            let upper = bump_release(&version.release);
+            Ranges::between(Version::from_release(version.release), upper)
+        }
+        Operator::NotEqualStar => {
+            let upper = bump_release(&version.release);
+            Ranges::between(Version::from_release(version.release), upper).complement()
+        }
+        Operator::TildeEqual if version.release.len() >= 2 => {
+            let upper = bump_release(&version.release[..version.release.len() - 1]);
+            Ranges::between(version, upper)
+        }
+        // `~=1` is invalid, we're lenient
+        Operator::TildeEqual | Operator::GreaterThanEqual => Ranges::higher_than(version),
+        Operator::GreaterThan => Ranges::strictly_higher_than(version),
+        Operator::LessThan => Ranges::strictly_lower_than(version),
+        Operator::LessThanEqual => Ranges::lower_than(version),
+    }
+}
+
+fn specifiers_to_range(specifiers: &VersionSpecifiers) -> Ranges<Version> {
+    specifiers.iter().fold(Ranges::full(), |range, specifier| {
+        range.intersection(&specifier_to_range(specifier))
+    })
+}
+
+/// Whether a bound of the range is a pre-release, e.g. from `>=2.0b1`. We check the range that
+/// combines all requirements on a package instead of the single requirements so the result
+/// doesn't depend on the order in which we see them
+fn mentions_pre_release(range: &Ranges<Version>) -> bool {
+    range.iter().any(|(lower, upper)| {
+        [lower, upper].into_iter().any(|bound| match bound {
+            Bound::Included(version) | Bound::Excluded(version) => version.any_prerelease(),
+            Bound::Unbounded => false,
+        })
+    })
+}
+
+/// The range of a version from a requirement or a dependency of the dummy pyproject.toml
+fn requirement_range(requirement: &Requirement) -> anyhow::Result<Ranges<Version>> {
+    match &requirement.version_or_url {
+        None => Ok(Ranges::full()),
+        Some(VersionOrUrl::VersionSpecifier(specifiers)) => Ok(specifiers_to_range(specifiers)),
+        Some(VersionOrUrl::Url(url)) => {
+            bail!(
+                "Url requirements are not supported: {} @ {}",
+                requirement.name,
+                url
+            )
+        }
+    }
+}
+
+/// Converts the poetry dependencies of the dummy pyproject.toml back to PEP 508 requirements
+fn roots_to_requirements(
+    dependencies: &BTreeMap<String, poetry_toml::Dependency>,
+) -> anyhow::Result<Vec<Requirement>> {
+    let mut requirements = Vec::new();
+    for (name, dependency) in dependencies {
+        // Checked per release with requires-python instead
+        if name == "python" || dependency.is_optional() {
+            continue;
+        }
+        let version = match dependency {
+            poetry_toml::Dependency::Compact(version) => version.as_str(),
+            // The checkout or the local project has exactly one version
+            poetry_toml::Dependency::Expanded { git: Some(_), .. }
+            | poetry_toml::Dependency::Expanded { path: Some(_), .. } => "*",
+            poetry_toml::Dependency::Expanded { version, .. } => version.as_deref().unwrap_or("*"),
+        };
+        let extras = dependency.get_extras();
+        let extras = if extras.is_empty() {
+            String::new()
+        } else {
+            format!("[{}]", extras.join(","))
+        };
+        let specifiers = poetry_to_pep440(version)
+            .with_context(|| format!("Unsupported version `{}` for {}", version, name))?;
+        let requirement = format!("{}{}{}", name, extras, specifiers);
+        requirements.push(Requirement::from_str(&requirement).map_err(|err| {
+            format_err!("Unsupported version `{}` for {}: {}", version, name, err)
+        })?);
+    }
+    Ok(requirements)
+}
+
+/// A requirement from a git repository checked out at one commit, or from a local path
+struct DirectPackage {
+    source: LockSource,
+    version: Version,
+    metadata: Rc<Metadata>,
+}
+
+impl DirectPackage {
+    /// Checks out the repository and reads the metadata through the build backend. A matching
+    /// entry in the previous lockfile pins the commit
+    fn checkout(
+        name: &str,
+        url: &str,
+        reference: Option<&str>,
+        locked: Option<&poetry_lock::Source>,
+        python_context: &PythonContext,
+        config_settings: &ConfigSettings,
+    ) -> anyhow::Result<Self> {
+        let reference = reference.unwrap_or("HEAD");
+        let revision = match locked {
+            Some(locked)
+                if locked.source_type == "git"
+                    && locked.url == url
+                    && locked.reference == reference =>
+            {
+                locked.resolved_reference.as_str()
+            }
+            _ => reference,
+        };
+        let (repo_dir, commit) = git_checkout(url, revision)
+            .with_context(|| format!("Failed to checkout {} from {}", name, url))?;
+        debug!("Checked out {} {} at {}", name, reference, commit);
+        let source_tree = copy_checkout(&repo_dir)?;
+        let content = wheel_metadata(source_tree.path(), python_context, config_settings)
+            .with_context(|| format!("Failed to get the metadata of {} from {}", name, url))?;
+        let metadata = parse_metadata(&content)
+            .with_context(|| format!("Invalid metadata for {} from {}", name, url))?;
+        let version = metadata
+            .version
+            .clone()
+            .with_context(|| format!("The metadata of {} from {} has no version", name, url))?;
+        Ok(Self {
+            source: LockSource {
+                source_type: "git".to_string(),
+                url: url.to_string(),
+                reference: reference.to_string(),
+                resolved_reference: commit,
+            },
+            version,
+            metadata: Rc::new(metadata),
+        })
+    }
+
+    /// Reads the metadata of a local project, wheel or source distribution. The lockfile gets
+    /// the path as written, the fingerprint for rebuilding is computed when installing.
+    ///
+    /// The dependencies are written to a dummy project elsewhere, so paths from a pyproject.toml
+    /// must already be absolute, see [crate::path_dependency::absolute_path_dependencies]
+    fn from_path(
+        name: &str,
+        path: &str,
+        python_context: &PythonContext,
+        config_settings: &ConfigSettings,
+    ) -> anyhow::Result<Self> {
+        let absolute = absolute_path(path, &env::current_dir()?);
+        let content = path_metadata(&absolute, python_context, config_settings)
+            .with_context(|| format!("Failed to get the metadata of {} from {}", name, path))?;
+        let metadata = parse_metadata(&content)
+            .with_context(|| format!("Invalid metadata for {} from {}", name, path))?;
+        let version = metadata
+            .version
+            .clone()
+            .with_context(|| format!("The metadata of {} from {} has no version", name, path))?;
+        Ok(Self {
+            source: LockSource {
+                source_type: source_type(&absolute).to_string(),
+                url: path.to_string(),
+                reference: String::new(),
+                resolved_reference: String::new(),
+            },
+            version,
+            metadata: Rc::new(metadata),
+        })
+    }
+}
+
+/// pubgrub wants a std error, it's only shown through anyhow again
+#[derive(Debug, thiserror::Error)]
+#[error("{0:#}")]
+struct ProviderError(anyhow::Error);
+
+impl From<anyhow::Error> for ProviderError {
+    fn from(err: anyhow::Error) -> Self {
+        Self(err)
+    }
+}
+
+/// One set of requirements with its own lockfile, e.g. the project or a tool
+struct ResolutionRoot {
+    requirements: Vec<Requirement>,
+    /// The versions of the previous lockfile, preferred if they still match
+    locked: HashMap<String, Version>,
+    options: ResolverOptions,
+    /// normalized name -> the checkout of a git requirement or a local path
+    direct: HashMap<String, DirectPackage>,
+}
+
+/// Answers pubgrub's questions from the index, caching the responses. The index responses are
+/// shared between the roots
+struct IndexProvider<'a> {
+    indexes: &'a IndexUrls,
+    transport: &'a dyn Transport,
+    pep508_env: &'a MarkerEnvironment,
+    roots: Vec<ResolutionRoot>,
+    /// normalized name -> the installable files for each version
+    versions: RefCell<HashMap<String, Rc<Releases>>>,
+    metadata: RefCell<HashMap<(String, Version), Rc<Metadata>>>,
+}
+
+impl<'a> IndexProvider<'a> {
+    /// Versions without a file for our python are dropped, and for [SourcePreference::Wheel] also
+    /// those without a wheel. Yanked files are only kept if all files of the version are yanked,
+    /// so [DependencyProvider::choose_version] can apply the [YankedPolicy]
+    fn versions(&self, root: usize, name: &str) -> anyhow::Result<Rc<Releases>> {
+        // The other roots may have the same package from the index
+        if let Some(direct) = self.roots[root].direct.get(name) {
+            // There are no files to pick from, we build the checkout
+            return Ok(Rc::new(BTreeMap::from([(
+                direct.version.clone(),
+                Vec::new(),
+            )])));
+        }
+        if let Some(versions) = self.versions.borrow().get(name) {
+            return Ok(versions.clone());
+        }
+        let project = project_files(self.indexes, self.transport, name)
+            .with_context(|| format!("Failed to get the versions of {}", name))?;
+        debug!(
+            "Found {} files for {} on {}",
+            project.files.len(),
+            name,
+            project.index
+        );
+        let python_version = &self.pep508_env.python_full_version.version;
+        // The source preferences are the same for all roots
+        let only_wheels = self.roots[root]
+            .options
+            .source_preferences
+            .for_package(name)
+            == SourcePreference::Wheel;
+        let mut versions: Releases = BTreeMap::new();
+        for file in project.files {
+            if only_wheels && !file.filename.ends_with(".whl") {
+                continue;
+            }
+            if let Some(requires_python) = &file.requires_python {
+                match VersionSpecifiers::from_str(requires_python) {
+                    Ok(specifiers) if !specifiers.contains(python_version) => continue,
+                    Ok(_) => {}
+                    Err(err) => debug!(
+                        "Ignoring invalid requires-python of {}: {}",
+                        file.filename, err
+                    ),
+                }
+            }
+            let Some(version) = file.version() else {
+                debug!("Ignoring {}, it has no valid version", file.filename);
+                continue;
+            };
+            versions.entry(version).or_default().push(file);
+        }
+        for files in versions.values_mut() {
+            if !is_yanked(files) {
+                files.retain(|file| file.yanked.is_none());
+            }
+        }
+        let versions = Rc::new(versions);
+        self.versions
+            .borrow_mut()
+            .insert(name.to_string(), versions.clone());
+        Ok(versions)
+    }
+
+    fn metadata(&self, root: usize, name: &str, version: &Version) -> anyhow::Result<Rc<Metadata>> {
+        if let Some(direct) = self.roots[root].direct.get(name) {
+            return Ok(direct.metadata.clone());
+        }
+        let key = (name.to_string(), version.clone());
+        if let Some(metadata) = self.metadata.borrow().get(&key) {
+            return Ok(metadata.clone());
+        }
+        let versions = self.versions(root, name)?;
+        let files = versions.get(version).map(Vec::as_slice).unwrap_or_default();
+        let content = match files.iter().find_map(IndexFile::metadata_url) {
+            Some(url) => {
+                let mut content = String::new();
+                self.transport
+                    .get(&url)?
+                    .read_to_string(&mut content)
+                    .with_context(|| format!("Failed to read {}", url))?;
+                content
+            }
+            // Without PEP 658, we read the metadata from a wheel
+            None => match files.iter().find(|file| file.filename.ends_with(".whl")) {
+                Some(wheel) => lazy_wheel::wheel_metadata(self.transport, wheel)?,
+                None => bail!(
+                    "The index has no metadata file (PEP 658) and no wheel for {} {}",
+                    name,
+                    version
+                ),
+            },
+        };
+        let metadata = Rc::new(
+            parse_metadata(&content)
+                .with_context(|| format!("Invalid metadata for {} {}", name, version))?,
+        );
+        self.metadata.borrow_mut().insert(key, metadata.clone());
+        Ok(metadata)
+    }
+
+    /// Adds the requirement and its extras, combining it with previous requirements on the same
+    /// package
+    fn add_requirement(
+        &self,
+        constraints: &mut DependencyConstraints<PubGrubPackage, Ranges<Version>>,
+        root: usize,
+        requirement: &Requirement,
+    ) -> anyhow::Result<()> {
+        let range = requirement_range(requirement)?;
+        let extras = requirement
+            .extras
+            .iter()
+            .flatten()
+            .map(|extra| Some(extra.as_str()));
+        for extra in [None].into_iter().chain(extras) {
+            let package = PubGrubPackage::new(root, &requirement.name, extra);
+            let combined = match constraints.get(&package) {
+                Some(existing) => existing.intersection(&range),
+                None => range.clone(),
+            };
+            constraints.insert(package, combined);
+        }
+        Ok(())
+    }
+
+    /// The requirements of a root for which the markers match
+    fn root_dependencies(
+        &self,
+        root: usize,
+    ) -> anyhow::Result<DependencyConstraints<PubGrubPackage, Ranges<Version>>> {
+        let mut constraints = DependencyConstraints::default();
+        for requirement in &self.roots[root].requirements {
+            if let Some(marker) = &requirement.marker {
+                if !crate::markers::evaluate_marker(marker, self.pep508_env, &[]) {
+                    continue;
+                }
+            }
+            self.add_requirement(&mut constraints, root, requirement)?;
+        }
+        Ok(constraints)
+    }
+
+    fn dependencies(
+        &self,
+        package: &PubGrubPackage,
+        version: &Version,
+    ) -> anyhow::Result<DependencyConstraints<PubGrubPackage, Ranges<Version>>> {
+        let mut constraints = DependencyConstraints::default();
+        let (root, name, extra) = match package {
+            PubGrubPackage::Root if self.roots.len() > 1 => {
+                for root in 0..self.roots.len() {
+                    constraints.insert(
+                        PubGrubPackage::Requirements(root),
+                        Ranges::singleton(Version::from_release(vec![0])),
+                    );
+                }
+                return Ok(constraints);
+            }
+            PubGrubPackage::Root => return self.root_dependencies(0),
+            PubGrubPackage::Requirements(root) => return self.root_dependencies(*root),
+            PubGrubPackage::Package { root, name, extra } => (*root, name, extra),
+        };
+        let extras: Vec<&str> = extra.iter().map(String::as_str).collect();
+        if extra.is_some() {
+            // The extra is only a set of additional dependencies of the package
+            constraints.insert(
+                PubGrubPackage::new(root, name, None),
+                Ranges::singleton(version.clone()),
+            );
+        }
+        let metadata = self.metadata(root, name, version)?;
+        for requirement in &metadata.requires_dist {
+            if let Some(marker) = &requirement.marker {
+                if !crate::markers::evaluate_marker(marker, self.pep508_env, &extras) {
+                    continue;
+                }
+            }
+            self.add_requirement(&mut constraints, root, requirement)?;
+        }
+        // e.g. `black[d]` activating `black[colorama]`, but not the base package depending on
+        // itself
+        constraints.remove(package);
+        Ok(constraints)
+    }
+}
+
+impl DependencyProvider for IndexProvider<'_> {
+    type P = PubGrubPackage;
+    type V = Version;
+    type VS = Ranges<Version>;
+    /// Extras pinned to their base package first, then the packages with the most conflicts
+    type Priority = (bool, u32);
+    type M = String;
+    type Err = ProviderError;
+
+    fn prioritize(
+        &self,
+        _package: &Self::P,
+        range: &Self::VS,
+        package_conflicts_counts: &PackageResolutionStatistics,
+    ) -> Self::Priority {
+        (
+            range.as_singleton().is_some(),
+            package_conflicts_counts.conflict_count(),
+        )
+    }
+
+    /// The locked version if it still matches, otherwise the newest version. Yanked versions are
+    /// skipped unless the [YankedPolicy] allows them, where the lockfile counts as pinned.
+    /// Pre-releases are only used if the [PreReleasePolicy] allows them, the range mentions one
+    /// or there is no matching final release (PEP 440)
+    fn choose_version(
+        &self,
+        package: &Self::P,
+        range: &Self::VS,
+    ) -> Result<Option<Self::V>, Self::Err> {
+        let PubGrubPackage::Package { root, name, .. } = package else {
+            return Ok(Some(Version::from_release(vec![0])));
+        };
+        let root_requirements = &self.roots[*root];
+        if let Some(direct) = root_requirements.direct.get(name) {
+            return Ok(Some(direct.version.clone()).filter(|version| range.contains(version)));
+        }
+        let versions = self.versions(*root, name)?;
+        let pinned = range.as_singleton();
+        if let Some((pinned, files)) =
+            pinned.and_then(|pinned| Some((pinned, versions.get(pinned)?)))
+        {
+            if is_yanked(files) && !root_requirements.options.yanked.allows(true) {
+                return Err(yanked_error(name, &pinned.to_string(), yanked_reason(files)).into());
+            }
+        }
+        let matching: Vec<&Version> = versions
+            .iter()
+            .rev()
+            .filter(|(version, files)| {
+                range.contains(version)
+                    && (!is_yanked(files)
+                        || root_requirements.options.yanked.allows(pinned.is_some()))
+            })
+            .map(|(version, _files)| version)
+            .collect();
+        let pre_releases = root_requirements.options.pre_releases.allows(name)
+            || mentions_pre_release(range)
+            || matching.iter().all(|version| version.any_prerelease());
+        if let Some(locked) = root_requirements.locked.get(name) {
+            if range.contains(locked)
+                && (pre_releases || !locked.any_prerelease())
+                && versions.get(locked).is_some_and(|files| {
+                    !is_yanked(files) || root_requirements.options.yanked.allows(true)
+                })
+            {
+                return Ok(Some(locked.clone()));
+            }
+        }
+        Ok(matching
+            .into_iter()
+            .find(|version| pre_releases || !version.any_prerelease())
+            .cloned())
+    }
+
+    fn get_dependencies(
+        &self,
+        package: &Self::P,
+        version: &Self::V,
+    ) -> Result<Dependencies<Self::P, Self::VS, Self::M>, Self::Err> {
+        // The index doesn't always tell us the requires-python (e.g. `--find-links` or older
+        // indexes), so we check it again in the metadata
+        if let PubGrubPackage::Package { root, name, .. } = package {
+            let python_version = &self.pep508_env.python_full_version.version;
+            if let Some(requires_python) = &self.metadata(*root, name, version)?.requires_python {
+                if !requires_python.contains(python_version) {
+                    return Ok(Dependencies::Unavailable(format!(
+                        "requires python {}, but the target python is {}",
+                        requires_python, python_version
+                    )));
+                }
+            }
+        }
+        Ok(Dependencies::Available(
+            self.dependencies(package, version)?,
+        ))
+    }
+}
+
+/// `[[package]]` of the poetry.lock we write, see [crate::poetry_integration::poetry_lock]
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "kebab-case")]
+struct LockPackage {
+    name: String,
+    version: String,
+    description: String,
+    optional: bool,
+    python_versions: String,
+    files: Vec<LockFile>,
+    dependencies: BTreeMap<String, Vec<LockDependency>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    source: Option<LockSource>,
+}
+
+/// `[package.source]` of a git requirement, with the exact commit as `resolved_reference`, or of a
+/// local path, which has neither reference
+#[derive(Serialize, Debug, Clone)]
+struct LockSource {
+    #[serde(rename = "type")]
+    source_type: String,
+    url: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    reference: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    resolved_reference: String,
+}
+
+#[derive(Serialize, Debug)]
+struct LockFile {
+    file: String,
+    hash: String,
+}
+
+#[derive(Serialize, Debug)]
+struct LockDependency {
+    version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    markers: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extras: Option<Vec<String>>,
+}
+
+#[derive(Serialize, Debug)]
+struct LockMetadata {
+    #[serde(rename = "lock-version")]
+    lock_version: String,
+    #[serde(rename = "python-versions")]
+    python_versions: String,
+    #[serde(rename = "content-hash")]
+    content_hash: String,
+    /// The `config_settings` the packages are built with, for reviewing the lock
+    #[serde(
+        rename = "config-settings",
+        skip_serializing_if = "PackageConfigSettings::is_empty"
+    )]
+    config_settings: PackageConfigSettings,
+    /// The packages that were resolved with `MONOTRAIL_NO_BINARY` or `MONOTRAIL_ONLY_BINARY`
+    #[serde(
+        rename = "source-preferences",
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    source_preferences: BTreeMap<String, SourcePreference>,
+}
+
+#[derive(Serialize, Debug)]
+struct Lock {
+    package: Vec<LockPackage>,
+    metadata: LockMetadata,
+}
+
+impl LockPackage {
+    fn new(
+        root: usize,
+        name: &str,
+        version: &Version,
+        provider: &IndexProvider,
+    ) -> anyhow::Result<Self> {
+        let files = provider.versions(root, name)?[version]
+            .iter()
+            .filter_map(|file| {
+                Some(LockFile {
+                    file: file.filename.clone(),
+                    hash: format!("sha256:{}", file.hashes.get("sha256")?),
+                })
+            })
+            .collect();
+        let metadata = provider.metadata(root, name, version)?;
+        let mut dependencies: BTreeMap<String, Vec<LockDependency>> = BTreeMap::new();
+        for requirement in &metadata.requires_dist {
+            let version = match &requirement.version_or_url {
+                Some(VersionOrUrl::VersionSpecifier(specifiers)) => specifiers.to_string(),
+                _ => "*".to_string(),
+            };
+            dependencies
+                .entry(requirement.name.clone())
+                .or_default()
+                .push(LockDependency {
+                    version,
+                    markers: requirement.marker.as_ref().map(ToString::to_string),
+                    extras: requirement.extras.clone(),
+                });
+        }
+        // Reading the lock takes the first option that matches, so the conditional ones (usually
+        // extras) need to come before the unconditional one
+        for options in dependencies.values_mut() {
+            options.sort_by_key(|option| option.markers.is_none());
+        }
+        Ok(Self {
+            name: name.to_string(),
+            version: version.to_string(),
+            description: String::new(),
+            optional: false,
+            python_versions: metadata
+                .requires_python
+                .as_ref()
+                .map_or_else(|| "*".to_string(), ToString::to_string),
+            files,
+            dependencies,
+            source: provider.roots[root]
+                .direct
+                .get(name)
+                .map(|direct| direct.source.clone()),
+        })
+    }
+}
+
+/// Resolves the dependencies of the dummy pyproject.toml against the indexes and returns
+/// the same as [crate::poetry_integration::lock::poetry_resolve]
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn native_resolve(
+    dependencies: &BTreeMap<String, poetry_toml::Dependency>,
+    lockfile: Option<&str>,
+    python_version: (u8, u8),
+    pep508_env: &MarkerEnvironment,
+    // Builds the metadata of git requirements and local paths
+    sys_executable: &Path,
+    indexes: &IndexUrls,
+    transport: &dyn Transport,
+    options: &ResolverOptions,
+) -> anyhow::Result<(PoetrySection, PoetryLock, String)> {
+    let mut resolved = native_resolve_roots(
+        &[(dependencies, lockfile)],
+        python_version,
+        pep508_env,
+        sys_executable,
+        indexes,
+        transport,
+        options,
+    )?;
+    Ok(resolved.remove(0))
+}
+
+/// Reads the previous lockfile and checks out the git requirements and local paths of a root
+fn resolution_root(
+    dependencies: &BTreeMap<String, poetry_toml::Dependency>,
+    lockfile: Option<&str>,
+    python_context: &PythonContext,
+    config_settings: &PackageConfigSettings,
+    options: &ResolverOptions,
+) -> anyhow::Result<ResolutionRoot> {
+    let locked_packages = match lockfile {
+        Some(lockfile) => PoetryLock::from_str(lockfile)?.package,
+        None => Vec::new(),
+    };
+    let locked = locked_packages
+        .iter()
+        .filter_map(|package| {
+            let version = Version::from_str(&package.version).ok()?;
+            Some((normalize_name(&package.name), version))
+        })
+        .collect();
+    let mut direct = HashMap::new();
+    for (name, dependency) in dependencies {
+        match dependency {
+            poetry_toml::Dependency::Expanded {
+                git: Some(url),
+                branch,
+                rev,
+                ..
+            } => {
+                let locked_source = locked_packages
+                    .iter()
+                    .find(|package| normalize_name(&package.name) == normalize_name(name))
+                    .and_then(|package| package.source.as_ref());
+                let reference = rev.as_deref().or(branch.as_deref());
+                let package = DirectPackage::checkout(
+                    name,
+                    url,
+                    reference,
+                    locked_source,
+                    python_context,
+                    &config_settings.for_package(name),
+                )?;
+                direct.insert(normalize_name(name), package);
+            }
+            poetry_toml::Dependency::Expanded {
+                path: Some(path), ..
+            } => {
+                let package = DirectPackage::from_path(
+                    name,
+                    path,
+                    python_context,
+                    &config_settings.for_package(name),
+                )?;
+                direct.insert(normalize_name(name), package);
+            }
+            _ => {}
+        }
+    }
+    // poetry's `allow-prereleases = true`
+    let mut options = options.clone();
+    for (name, dependency) in dependencies {
+        if dependency.allows_prereleases() {
+            options.pre_releases.allow(name);
+        }
+    }
+    Ok(ResolutionRoot {
+        requirements: roots_to_requirements(dependencies)?,
+        locked,
+        options,
+        direct,
+    })
+}
+
+/// Resolves multiple sets of dependencies, each with an optional previous lockfile, in a single
+/// resolution. Every root gets its own copy of each package, so the roots don't constrain each
+/// other, but the index is only queried once per package. Returns one lock per root.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn native_resolve_roots(
+    roots: &[(&BTreeMap<String, poetry_toml::Dependency>, Option<&str>)],
+    python_version: (u8, u8),
+    pep508_env: &MarkerEnvironment,
+    // Builds the metadata of git requirements and local paths
+    sys_executable: &Path,
+    indexes: &IndexUrls,
+    transport: &dyn Transport,
+    options: &ResolverOptions,
+) -> anyhow::Result<Vec<(PoetrySection, PoetryLock, String)>> {
+    let python_context = PythonContext {
+        sys_executable: sys_executable.to_path_buf(),
+        version: python_version,
+        pep508_env: pep508_env.clone(),
+        launch_type: LaunchType::Binary,
+    };
+    let config_settings = PackageConfigSettings::from_env()?;
+    let resolution_roots = roots
+        .iter()
+        .map(|(dependencies, lockfile)| {
+            resolution_root(
+                dependencies,
+                *lockfile,
+                &python_context,
+                &config_settings,
+                options,
+            )
+        })
+        .collect::<anyhow::Result<_>>()?;
+    let provider = IndexProvider {
+        indexes,
+        transport,
+        pep508_env,
+        roots: resolution_roots,
+        versions: RefCell::default(),
+        metadata: RefCell::default(),
+    };
+
+    let selected = match pubgrub::resolve(
+        &provider,
+        PubGrubPackage::Root,
+        Version::from_release(vec![0]),
+    ) {
+        Ok(selected) => selected,
+        Err(PubGrubError::NoSolution(mut derivation_tree)) => {
+            derivation_tree.collapse_no_versions();
+            bail!(
+                "The requirements can't be resolved:\n{}",
+                DefaultStringReporter::report(&derivation_tree)
+            )
+        }
+        Err(PubGrubError::ErrorRetrievingDependencies {
+            package,
+            version,
+            source,
+        }) => {
+            return Err(source.0.context(format!(
+                "Failed to get the dependencies of {} {}",
+                package, version
+            )))
+        }
+        Err(PubGrubError::ErrorChoosingVersion { package, source }) => {
+            return Err(source
+                .0
+                .context(format!("Failed to choose a version for {}", package)))
+        }
+        Err(PubGrubError::ErrorInShouldCancel(source)) => return Err(source.0),
+    };
+
+    let mut locks = Vec::new();
+    for (root, (dependencies, _lockfile)) in roots.iter().enumerate() {
+        let mut packages = Vec::new();
+        let mut source_preferences = BTreeMap::new();
+        for (package, version) in &selected {
+            let PubGrubPackage::Package {
+                root: package_root,
+                name,
+                extra: None,
+            } = package
+            else {
+                continue;
+            };
+            if *package_root != root {
+                continue;
+            }
+            packages.push(LockPackage::new(root, name, version, &provider)?);
+            let root_requirements = &provider.roots[root];
+            let preference = root_requirements
+                .options
+                .source_preferences
+                .for_package(name);
+            if preference != SourcePreference::Auto && !root_requirements.direct.contains_key(name)
+            {
+                source_preferences.insert(name.clone(), preference);
+            }
+        }
+        packages.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let pyproject_toml = dummy_poetry_pyproject_toml(dependencies, python_version);
+        // The same hash as poetry, so the lockfile also verifies against the pyproject.toml we
+        // write
+        let content_hash = content_hash(&toml::Value::try_from(&pyproject_toml)?);
+        let poetry_section = pyproject_toml.tool.unwrap().poetry.unwrap();
+        let lock = Lock {
+            package: packages,
+            metadata: LockMetadata {
+                lock_version: "2.0".to_string(),
+                python_versions: format!("~{}.{}", python_version.0, python_version.1),
+                content_hash,
+                config_settings: config_settings.clone(),
+                source_preferences,
+            },
+        };
+        let lockfile = toml::to_string(&lock).context("Failed to serialize poetry.lock")?;
+        let poetry_lock = PoetryLock::from_str(&lockfile)?;
+        locks.push((poetry_section, poetry_lock, lockfile));
+    }
+    Ok(locks)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::index::IndexUrls;
+    use crate::package_index::{PreReleasePolicy, SourcePreferences, YankedPolicy};
+    use crate::poetry_integration::poetry_toml::{Dependency, PoetryPyprojectToml, ToolSection};
+    use crate::poetry_integration::read_dependencies::parse_toml_strs;
+    use crate::project_lock::{prune_lockfile, Upgrade};
+    use crate::read_poetry_specs;
+    use crate::resolver::{
+        mentions_pre_release, native_resolve, native_resolve_roots, specifiers_to_range,
+        ResolverOptions,
+    };
+    use crate::test_utils::test_pep508_env;
+    use fs_err as fs;
+    use git2::{IndexAddOption, Repository, Signature};
+    use indoc::indoc;
+    use monotrail_utils::transport::MockTransport;
+    use pep440_rs::{Version, VersionSpecifiers};
+    use pep508_rs::StringVersion;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+    use std::str::FromStr;
+    use tempfile::TempDir;
+
+    /// Serves the simple api pages and metadata files from test-data/resolver
+    pub(crate) fn test_transport() -> MockTransport {
+        let mut transport = MockTransport::default();
+        for entry in fs::read_dir("../../test-data/resolver/simple").unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            transport = transport.with_response(
+                format!(
+                    "https://pypi.org/simple/{}/?format=application/vnd.pypi.simple.v1+json",
+                    name
+                ),
+                fs::read(&path).unwrap(),
+            );
+        }
+        for entry in fs::read_dir("../../test-data/resolver/metadata").unwrap() {
+            let path = entry.unwrap().path();
+            let filename = path.file_name().unwrap().to_string_lossy().to_string();
+            transport = transport.with_response(
+                format!("https://files.pythonhosted.org/packages/{}", filename),
+                fs::read(&path).unwrap(),
+            );
+        }
+        transport
+    }
+
+    /// The name, version and dependencies of each package, and the lockfile
+    type Resolution = (Vec<(String, String, Vec<String>)>, String);
+
+    fn resolve(dependencies: &[(&str, Dependency)], lockfile: Option<&str>) -> Resolution {
+        resolve_with(dependencies, lockfile, &ResolverOptions::default()).unwrap()
+    }
+
+    fn resolve_with(
+        dependencies: &[(&str, Dependency)],
+        lockfile: Option<&str>,
+        options: &ResolverOptions,
+    ) -> anyhow::Result<Resolution> {
+        let dependencies: BTreeMap<String, Dependency> = dependencies
+            .iter()
+            .map(|(name, dependency)| (name.to_string(), dependency.clone()))
+            .collect();
+        let (poetry_section, poetry_lock, lockfile) = native_resolve(
+            &dependencies,
+            lockfile,
+            (3, 8),
+            &test_pep508_env(),
+            Path::new("python3"),
+            &IndexUrls::default(),
+            &test_transport(),
+            options,
+        )?;
+        // Like a tool environment, the lockfile must verify against the pyproject.toml we write
+        let pyproject_toml = PoetryPyprojectToml {
+            tool: Some(ToolSection {
+                poetry: Some(poetry_section.clone()),
+            }),
+            build_system: None,
+        };
+        parse_toml_strs(&toml::to_string(&pyproject_toml).unwrap(), lockfile.clone()).unwrap();
+        let mut specs = read_poetry_specs(
+            &poetry_section,
+            poetry_lock,
+            Path::new("."),
+            false,
+            &[],
+            &test_pep508_env(),
+        )
+        .unwrap();
+        specs.sort_by(|a, b| a.name.cmp(&b.name));
+        let specs = specs
+            .into_iter()
+            .map(|spec| (spec.name, spec.python_version.unwrap(), spec.extras))
+            .collect();
+        Ok((specs, lockfile))
+    }
+
+    #[test]
+    fn test_native_resolve() {
+        let (specs, _lockfile) = resolve(
+            &[
+                ("foo", Dependency::Compact(">=1.0".to_string())),
+                (
+                    "bar",
+                    Dependency::Expanded {
+                        version: Some("*".to_string()),
+                        optional: None,
+                        extras: Some(vec!["extra1".to_string()]),
+                        git: None,
+                        branch: None,
+                        rev: None,
+                        path: None,
+                        allow_prereleases: None,
+                    },
+                ),
+            ],
+            None,
+        );
+        // bar 1.1 is yanked, bar[extra1] needs baz>=2 so we have to backtrack to foo 1.0,
+        // baz 3.0 is python>=3.10 only and qux is python<3.8 only
+        assert_eq!(
+            specs,
+            [
+                (
+                    "bar".to_string(),
+                    "1.0".to_string(),
+                    vec!["extra1".to_string()]
+                ),
+                ("baz".to_string(), "2.0".to_string(), vec![]),
+                ("foo".to_string(), "1.0".to_string(), vec![]),
+            ]
+        );
+    }
+
+    /// Both roots need baz, but in incompatible versions
+    #[test]
+    fn test_native_resolve_roots() {
+        let project = BTreeMap::from([("baz".to_string(), Dependency::Compact("<2".to_string()))]);
+        let tool = BTreeMap::from([("baz".to_string(), Dependency::Compact(">=2".to_string()))]);
+        let resolved = native_resolve_roots(
+            &[(&project, None), (&tool, None)],
+            (3, 8),
+            &test_pep508_env(),
+            Path::new("python3"),
+            &IndexUrls::default(),
+            &test_transport(),
+            &ResolverOptions::default(),
+        )
+        .unwrap();
+        let versions: Vec<Vec<String>> = resolved
+            .into_iter()
+            .map(|(_poetry_section, poetry_lock, _lockfile)| {
+                poetry_lock
+                    .package
+                    .into_iter()
+                    .map(|package| format!("{} {}", package.name, package.version))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(versions, [vec!["baz 1.0"], vec!["baz 2.0"]]);
+    }
+
+    #[test]
+    fn test_native_resolve_locked() {
+        let (specs, lockfile) = resolve(&[("baz", Dependency::Compact("<2".to_string()))], None);
+        assert_eq!(specs[0].1, "1.0");
+        // The locked version is kept as long as it matches
+        let (specs, _lockfile) = resolve(
+            &[("baz", Dependency::Compact("*".to_string()))],
+            Some(&lockfile),
+        );
+        assert_eq!(specs[0].1, "1.0");
+        let (specs, _lockfile) = resolve(&[("baz", Dependency::Compact("*".to_string()))], None);
+        assert_eq!(specs[0].1, "2.0");
+        // Poetry's caret constraints are translated to PEP 440
+        let (specs, _lockfile) = resolve(&[("baz", Dependency::Compact("^1".to_string()))], None);
+        assert_eq!(specs[0].1, "1.0");
+    }
+
+    #[test]
+    fn test_native_resolve_upgrade_package() {
+        let (specs, lockfile) = resolve(
+            &[
+                ("foo", Dependency::Compact("<2".to_string())),
+                ("baz", Dependency::Compact("<2".to_string())),
+            ],
+            None,
+        );
+        assert_eq!((specs[0].1.as_str(), specs[1].1.as_str()), ("1.0", "1.0"));
+        let any = [
+            ("foo", Dependency::Compact("*".to_string())),
+            ("baz", Dependency::Compact("*".to_string())),
+        ];
+        let upgrade = |names: &[&str]| {
+            let names: Vec<String> = names.iter().map(ToString::to_string).collect();
+            let pruned = prune_lockfile(&lockfile, &Upgrade::from_args(false, &names)).unwrap();
+            let (specs, _lockfile) = resolve(&any, pruned.as_deref());
+            (specs[0].1.clone(), specs[1].1.clone())
+        };
+        // (baz, foo)
+        assert_eq!(upgrade(&[]), ("1.0".to_string(), "1.0".to_string()));
+        assert_eq!(upgrade(&["baz"]), ("2.0".to_string(), "1.0".to_string()));
+        // foo 2.0 needs baz<2, which the locked baz 1.0 already is
+        assert_eq!(upgrade(&["foo"]), ("1.0".to_string(), "2.0".to_string()));
+    }
+
+    fn with_yanked(yanked: YankedPolicy) -> ResolverOptions {
+        ResolverOptions {
+            yanked,
+            ..ResolverOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_native_resolve_yanked() {
+        let any = [("bar", Dependency::Compact("*".to_string()))];
+        let pinned = [("bar", Dependency::Compact("==1.1".to_string()))];
+        // bar 1.1 is yanked, so we only get it if it's pinned
+        let (specs, lockfile) = resolve(&any, None);
+        assert_eq!(specs[0].1, "1.0");
+        let (specs, pinned_lockfile) = resolve(&pinned, None);
+        assert_eq!(specs[0].1, "1.1");
+        // A yanked version in the lockfile counts as pinned
+        let (specs, _lockfile) = resolve(&any, Some(&pinned_lockfile));
+        assert_eq!(specs[0].1, "1.1");
+        let (specs, _lockfile) =
+            resolve_with(&any, None, &with_yanked(YankedPolicy::Warn)).unwrap();
+        assert_eq!(specs[0].1, "1.1");
+        let err = resolve_with(&pinned, None, &with_yanked(YankedPolicy::Refuse)).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "Failed to choose a version for bar: bar 1.1 was yanked (no reason given) and \
+            yanked releases are refused (--yanked refuse)"
+        );
+        let (specs, _lockfile) = resolve_with(
+            &any,
+            Some(&pinned_lockfile),
+            &with_yanked(YankedPolicy::Refuse),
+        )
+        .unwrap();
+        assert_eq!(specs[0].1, "1.0");
+        assert_ne!(lockfile, pinned_lockfile);
+    }
+
+    #[test]
+    fn test_native_resolve_pre_releases() {
+        let any = [("foo", Dependency::Compact("*".to_string()))];
+        let pre_releases = |names: &[&str]| {
+            let mut policy = PreReleasePolicy::default();
+            for name in names {
+                policy.allow(name);
+            }
+            ResolverOptions {
+                pre_releases: policy,
+                ..ResolverOptions::default()
+            }
+        };
+        let foo_version = |specs: &[(String, String, Vec<String>)]| {
+            let foo = specs.iter().find(|(name, _, _)| name == "foo").unwrap();
+            foo.1.clone()
+        };
+        // foo 2.1rc1 is the newest version
+        let (specs, _lockfile) = resolve(&any, None);
+        assert_eq!(foo_version(&specs), "2.0");
+        let (specs, pre_lockfile) = resolve_with(&any, None, &pre_releases(&["foo"])).unwrap();
+        assert_eq!(foo_version(&specs), "2.1rc1");
+        let (specs, _lockfile) = resolve_with(&any, None, &pre_releases(&["bar"])).unwrap();
+        assert_eq!(foo_version(&specs), "2.0");
+        let (specs, _lockfile) = resolve_with(&any, None, &pre_releases(&[":all:"])).unwrap();
+        assert_eq!(foo_version(&specs), "2.1rc1");
+        // Mentioning a pre-release allows them
+        let (specs, _lockfile) =
+            resolve(&[("foo", Dependency::Compact(">=2.1a1".to_string()))], None);
+        assert_eq!(foo_version(&specs), "2.1rc1");
+        // Another requirement that doesn't mention one doesn't change that, in any order
+        for (first, second) in [(">=2.1a1", ">=1.0"), (">=1.0", ">=2.1a1")] {
+            let range = specifiers_to_range(&VersionSpecifiers::from_str(first).unwrap())
+                .intersection(&specifiers_to_range(
+                    &VersionSpecifiers::from_str(second).unwrap(),
+                ));
+            assert!(mentions_pre_release(&range));
+        }
+        assert!(!mentions_pre_release(&specifiers_to_range(
+            &VersionSpecifiers::from_str(">=1.0,<3").unwrap()
+        )));
+        // A locked pre-release is only kept while pre-releases are allowed
+        let (specs, _lockfile) = resolve(&any, Some(&pre_lockfile));
+        assert_eq!(foo_version(&specs), "2.0");
+    }
+
+    #[test]
+    fn test_native_resolve_source_preference() {
+        let any = [("spam", Dependency::Compact("*".to_string()))];
+        let only_binary = ResolverOptions {
+            source_preferences: SourcePreferences {
+                no_binary: String::new(),
+                only_binary: "spam".to_string(),
+            },
+            ..ResolverOptions::default()
+        };
+        // spam 2.0 only has a source distribution
+        let (specs, sdist_lockfile) = resolve(&any, None);
+        assert_eq!(specs[0].1, "2.0");
+        assert!(!sdist_lockfile.contains("source-preferences"));
+        let (specs, lockfile) = resolve_with(&any, None, &only_binary).unwrap();
+        assert_eq!(specs[0].1, "1.0");
+        let lock: toml::Value = toml::from_str(&lockfile).unwrap();
+        assert_eq!(
+            lock["metadata"]["source-preferences"]["spam"].as_str(),
+            Some("wheel")
+        );
+        let files = lock["package"][0]["files"].as_array().unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0]["file"].as_str(), Some("spam-1.0-py3-none-any.whl"));
+        // The locked version needs a build, so it's replaced
+        let (specs, _lockfile) = resolve_with(&any, Some(&sdist_lockfile), &only_binary).unwrap();
+        assert_eq!(specs[0].1, "1.0");
+    }
+
+    #[test]
+    fn test_native_resolve_requires_python_metadata() {
+        // Without data-requires-python from the index, we only see it in the metadata
+        let url = "https://pypi.org/simple/baz/?format=application/vnd.pypi.simple.v1+json";
+        let index_page = fs::read_to_string("../../test-data/resolver/simple/baz.json")
+            .unwrap()
+            .replace(
+                r#""requires-python": ">=3.10""#,
+                r#""requires-python": null"#,
+            );
+        let transport = test_transport().with_response(url, index_page);
+        let resolve_for = |requirement: &str, python_full_version: &str| {
+            let dependencies = BTreeMap::from([(
+                "baz".to_string(),
+                Dependency::Compact(requirement.to_string()),
+            )]);
+            let mut pep508_env = test_pep508_env();
+            pep508_env.python_full_version = StringVersion::from_str(python_full_version).unwrap();
+            native_resolve(
+                &dependencies,
+                None,
+                (3, 8),
+                &pep508_env,
+                Path::new("python3"),
+                &IndexUrls::default(),
+                &transport,
+                &ResolverOptions::default(),
+            )
+            .map(|(_poetry_section, poetry_lock, _lockfile)| poetry_lock.package[0].clone())
+        };
+        assert_eq!(resolve_for("*", "3.8.10").unwrap().version, "2.0");
+        // The target python, not the one we run with
+        let package = resolve_for("*", "3.11.4").unwrap();
+        assert_eq!(package.version, "3.0");
+        assert_eq!(package.python_versions, ">=3.10");
+        let err = resolve_for("==3.0", "3.8.10").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("requires python >=3.10, but the target python is 3.8.10"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_native_resolve_find_links() {
+        let dependencies = BTreeMap::from([(
+            "simplewheel".to_string(),
+            Dependency::Compact("*".to_string()),
+        )]);
+        let wheelhouse = TempDir::new().unwrap();
+        for filename in [
+            "simplewheel-1.0-py2.py3-none-any.whl",
+            "simplewheel-2.0-py2.py3-none-any.whl",
+        ] {
+            fs::copy(
+                Path::new("../../test-data/pip-test-packages").join(filename),
+                wheelhouse.path().join(filename),
+            )
+            .unwrap();
+        }
+        let indexes = IndexUrls {
+            find_links: vec![wheelhouse.path().to_string_lossy().to_string()],
+            indexes: Vec::new(),
+        };
+        // The metadata comes from the wheel itself, there's no index
+        let (_poetry_section, poetry_lock, lockfile) = native_resolve(
+            &dependencies,
+            None,
+            (3, 8),
+            &test_pep508_env(),
+            Path::new("python3"),
+            &indexes,
+            &MockTransport::default(),
+            &ResolverOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(poetry_lock.package[0].version, "2.0");
+        assert!(lockfile.contains("simplewheel-2.0-py2.py3-none-any.whl"));
+    }
+
+    /// Commits all files in the repository and returns the commit hash
+    fn commit_all(repo: &Repository, message: &str) -> String {
+        let mut index = repo.index().unwrap();
+        index.add_all(["*"], IndexAddOption::DEFAULT, None).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("monotrail", "monotrail@example.com").unwrap();
+        let parent = repo.head().ok().map(|head| head.peel_to_commit().unwrap());
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            message,
+            &tree,
+            parent.as_slice().iter().collect::<Vec<_>>().as_slice(),
+        )
+        .unwrap()
+        .to_string()
+    }
+
+    /// A git requirement with an in-tree backend, so we don't need the network
+    #[test]
+    fn test_native_resolve_git() {
+        let repo_dir = TempDir::new().unwrap();
+        fs::write(
+            repo_dir.path().join("pyproject.toml"),
+            indoc! {r#"
+                [build-system]
+                requires = []
+                build-backend = "backend"
+                backend-path = ["."]
+            "#},
+        )
+        .unwrap();
+        let backend = indoc! {r#"
+            import os
+
+            def prepare_metadata_for_build_wheel(metadata_directory, config_settings=None):
+                dist_info = os.path.join(metadata_directory, "gitpkg-VERSION.dist-info")
+                os.mkdir(dist_info)
+                with open(os.path.join(dist_info, "METADATA"), "w") as fp:
+                    fp.write("Metadata-Version: 2.1\nName: gitpkg\nVersion: VERSION\n")
+                    fp.write("Requires-Dist: baz<3\n")
+                return "gitpkg-VERSION.dist-info"
+        "#};
+        fs::write(
+            repo_dir.path().join("backend.py"),
+            backend.replace("VERSION", "0.1"),
+        )
+        .unwrap();
+        let repo = Repository::init(repo_dir.path()).unwrap();
+        let first_commit = commit_all(&repo, "0.1");
+
+        let dependencies = BTreeMap::from([(
+            "gitpkg".to_string(),
+            Dependency::Expanded {
+                version: None,
+                optional: Some(false),
+                extras: None,
+                git: Some(repo_dir.path().to_string_lossy().to_string()),
+                branch: None,
+                rev: None,
+                path: None,
+                allow_prereleases: None,
+            },
+        )]);
+        let python = which::which("python3").unwrap();
+        let resolve = |lockfile: Option<&str>| {
+            native_resolve(
+                &dependencies,
+                lockfile,
+                (3, 8),
+                &test_pep508_env(),
+                &python,
+                &IndexUrls::default(),
+                &test_transport(),
+                &ResolverOptions::default(),
+            )
+            .unwrap()
+        };
+        let (poetry_section, poetry_lock, lockfile) = resolve(None);
+        assert!(
+            lockfile.contains(&format!("resolved_reference = \"{}\"", first_commit)),
+            "{}",
+            lockfile
+        );
+        let mut specs = read_poetry_specs(
+            &poetry_section,
+            poetry_lock,
+            Path::new("."),
+            false,
+            &[],
+            &test_pep508_env(),
+        )
+        .unwrap();
+        specs.sort_by(|a, b| a.name.cmp(&b.name));
+        let versions: Vec<_> = specs
+            .iter()
+            .map(|spec| (spec.name.as_str(), spec.python_version.as_deref().unwrap()))
+            .collect();
+        assert_eq!(versions, [("baz", "2.0"), ("gitpkg", "0.1")]);
+        assert_eq!(
+            specs[1].source.as_ref().unwrap().resolved_reference,
+            first_commit
+        );
+
+        // The lockfile pins the commit, without it we get the new one
+        fs::write(
+            repo_dir.path().join("backend.py"),
+            backend.replace("VERSION", "0.2"),
+        )
+        .unwrap();
+        commit_all(&repo, "0.2");
+        let (_poetry_section, poetry_lock, _lockfile) = resolve(Some(&lockfile));
+        let gitpkg = poetry_lock
+            .package
+            .iter()
+            .find(|package| package.name == "gitpkg")
+            .unwrap();
+        assert_eq!(gitpkg.version, "0.1");
+        let (_poetry_section, poetry_lock, _lockfile) = resolve(None);
+        let gitpkg = poetry_lock
+            .package
+            .iter()
+            .find(|package| package.name == "gitpkg")
+            .unwrap();
+        assert_eq!(gitpkg.version, "0.2");
+    }
+
+    /// A local project with an in-tree backend, the fingerprint changes when the project does
+    #[test]
+    fn test_native_resolve_path() {
+        let project_dir = TempDir::new().unwrap();
+        fs::write(
+            project_dir.path().join("pyproject.toml"),
+            indoc! {r#"
+                [build-system]
+                requires = []
+                build-backend = "backend"
+                backend-path = ["."]
+            "#},
+        )
+        .unwrap();
+        fs::write(
+            project_dir.path().join("backend.py"),
+            indoc! {r#"
+                import os
+
+                def prepare_metadata_for_build_wheel(metadata_directory, config_settings=None):
+                    dist_info = os.path.join(metadata_directory, "localpkg-1.0.dist-info")
+                    os.mkdir(dist_info)
+                    with open(os.path.join(dist_info, "METADATA"), "w") as fp:
+                        fp.write("Metadata-Version: 2.1\nName: localpkg\nVersion: 1.0\n")
+                    return "localpkg-1.0.dist-info"
+            "#},
+        )
+        .unwrap();
+
+        let dependencies = BTreeMap::from([(
+            "localpkg".to_string(),
+            Dependency::Expanded {
+                version: None,
+                optional: Some(false),
+                extras: None,
+                git: None,
+                branch: None,
+                rev: None,
+                path: Some(project_dir.path().to_string_lossy().to_string()),
+                allow_prereleases: None,
+            },
+        )]);
+        let python = which::which("python3").unwrap();
+        let (poetry_section, poetry_lock, lockfile) = native_resolve(
+            &dependencies,
+            None,
+            (3, 8),
+            &test_pep508_env(),
+            &python,
+            &IndexUrls::default(),
+            &test_transport(),
+            &ResolverOptions::default(),
+        )
+        .unwrap();
+        assert!(lockfile.contains("type = \"directory\""), "{}", lockfile);
+        assert!(!lockfile.contains("resolved_reference"), "{}", lockfile);
+        let read_specs = || {
+            read_poetry_specs(
+                &poetry_section,
+                poetry_lock.clone(),
+                project_dir.path(),
+                false,
+                &[],
+                &test_pep508_env(),
+            )
+            .unwrap()
+        };
+        let specs = read_specs();
+        assert_eq!(specs.len(), 1);
+        assert_eq!(specs[0].python_version.as_deref(), Some("1.0"));
+        let fingerprint = specs[0].get_unique_version().unwrap();
+
+        fs::write(project_dir.path().join("localpkg.py"), "VERSION = 1\n").unwrap();
+        assert_ne!(read_specs()[0].get_unique_version().unwrap(), fingerprint);
+    }
+
+    #[test]
+    fn test_specifiers_to_range() {
+        let cases = [
+            ("~=1.4.5", "1.4.9", true),
+            ("~=1.4.5", "1.5", false),
+            ("~=1.4", "1.9", true),
+            ("==1.4.*", "1.4.2", true),
+            ("==1.4.*", "1.5.0", false),
+            ("!=1.4.*", "1.4.2", false),
+            (">=1.0,!=1.2,<2", "1.2", false),
+            (">=1.0,!=1.2,<2", "1.3", true),
+            ("==2", "2.0.0", true),
+        ];
+        for (specifiers, version, expected) in cases {
+            let range = specifiers_to_range(&VersionSpecifiers::from_str(specifiers).unwrap());
+            assert_eq!(
+                range.contains(&Version::from_str(version).unwrap()),
+                expected,
+                "{} {}",
+                specifiers,
+                version
+            );
+        }
+    }
+}
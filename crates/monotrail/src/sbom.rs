@@ -0,0 +1,587 @@
+//! Software bill of materials for compliance pipelines: The packages of an environment as
+//! [CycloneDX](https://cyclonedx.org/docs/1.5/json/) or [SPDX](https://spdx.github.io/spdx-spec/v2.3/)
+//! json, with versions, hashes, licenses and where each package came from.
+//!
+//! The packages either come from the `.dist-info` directories of a venv, which have the licenses
+//! and the `direct_url.json` provenance (PEP 610), or from the lock, which has the hashes of all
+//! files of each release but no licenses.
+
+use crate::path_dependency::is_path_source;
+use crate::poetry_integration::poetry_lock::PoetryLock;
+use crate::spec::RequestedSpec;
+use anyhow::Context;
+use fs_err as fs;
+use install_wheel_rs::{cache_key, installed_dist_infos};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tracing::debug;
+use url::Url;
+
+/// Where a package was installed from, if not from an index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Provenance {
+    /// A wheel or source distribution url, including `file://` urls
+    Archive(String),
+    /// A repository at a commit
+    Vcs {
+        /// e.g. `git`
+        vcs: String,
+        /// The repository url
+        url: String,
+        /// The exact commit
+        commit_id: String,
+    },
+    /// A local directory, e.g. an editable install
+    Directory(String),
+}
+
+impl Provenance {
+    fn url(&self) -> String {
+        match self {
+            Provenance::Archive(url) | Provenance::Directory(url) => url.clone(),
+            Provenance::Vcs {
+                vcs,
+                url,
+                commit_id,
+            } => format!("{}+{}@{}", vcs, url, commit_id),
+        }
+    }
+}
+
+/// A package in the SBOM
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SbomComponent {
+    /// The name as in the metadata or the lock
+    pub name: String,
+    /// The version as python sees it
+    pub version: String,
+    /// Algorithm (e.g. `sha256`) and hex digest
+    pub hashes: Vec<(String, String)>,
+    /// The SPDX expression from `License-Expression`
+    pub license_expression: Option<String>,
+    /// The free form `License` field and the names of the license classifiers
+    pub licenses: Vec<String>,
+    /// `None` for packages from an index
+    pub provenance: Option<Provenance>,
+}
+
+impl SbomComponent {
+    /// <https://github.com/package-url/purl-spec/blob/master/PURL-TYPES.rst#pypi>
+    pub fn purl(&self) -> String {
+        format!(
+            "pkg:pypi/{}@{}",
+            install_wheel_rs::normalize_name(&self.name),
+            self.version
+        )
+    }
+}
+
+/// The formats of the SBOM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbomFormat {
+    /// CycloneDX 1.5
+    CycloneDx,
+    /// SPDX 2.3
+    Spdx,
+}
+
+/// `direct_url.json`, <https://packaging.python.org/en/latest/specifications/direct-url/>
+#[derive(Deserialize, Debug)]
+struct DirectUrl {
+    url: String,
+    archive_info: Option<ArchiveInfo>,
+    vcs_info: Option<VcsInfo>,
+    dir_info: Option<Value>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ArchiveInfo {
+    /// Deprecated `<algorithm>=<hex>`
+    hash: Option<String>,
+    #[serde(default)]
+    hashes: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct VcsInfo {
+    vcs: String,
+    commit_id: String,
+}
+
+/// The license fields of the METADATA headers: `License-Expression`, the first line of
+/// `License` and the last part of each `Classifier: License :: ...`
+fn parse_metadata(content: &str) -> (Option<String>, Option<String>, SbomComponent) {
+    let mut name = None;
+    let mut version = None;
+    let mut component = SbomComponent::default();
+    for line in content.lines() {
+        if line.is_empty() {
+            break;
+        }
+        let Some((key, value)) = line.split_once(':') else {
+            // Continuation of a multiline value
+            continue;
+        };
+        let value = value.trim();
+        match key {
+            "Name" => name = Some(value.to_string()),
+            "Version" => version = Some(value.to_string()),
+            "License-Expression" => component.license_expression = Some(value.to_string()),
+            "License" if !value.is_empty() && value != "UNKNOWN" => {
+                component.licenses.push(value.to_string())
+            }
+            "Classifier" if value.starts_with("License ::") => {
+                if let Some(license) = value.rsplit(" :: ").next() {
+                    component.licenses.push(license.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    (name, version, component)
+}
+
+/// The packages installed in `site_packages`, from their `.dist-info` directories
+pub fn installed_components(site_packages: &Path) -> anyhow::Result<Vec<SbomComponent>> {
+    let mut components = Vec::new();
+    for (name, version, dist_info) in installed_dist_infos(site_packages)? {
+        let dist_info = site_packages.join(dist_info);
+        let metadata = fs::read_to_string(dist_info.join("METADATA"))?;
+        let (metadata_name, metadata_version, mut component) = parse_metadata(&metadata);
+        component.name = metadata_name.unwrap_or(name);
+        component.version = metadata_version.unwrap_or(version);
+        let direct_url_path = dist_info.join("direct_url.json");
+        if direct_url_path.is_file() {
+            let direct_url: DirectUrl =
+                serde_json::from_str(&fs::read_to_string(&direct_url_path)?)
+                    .with_context(|| format!("Invalid {}", direct_url_path.display()))?;
+            component.provenance = Some(if let Some(vcs_info) = direct_url.vcs_info {
+                Provenance::Vcs {
+                    vcs: vcs_info.vcs,
+                    url: direct_url.url,
+                    commit_id: vcs_info.commit_id,
+                }
+            } else if direct_url.dir_info.is_some() {
+                Provenance::Directory(direct_url.url)
+            } else {
+                if let Some(archive_info) = direct_url.archive_info {
+                    component.hashes = archive_info.hashes.into_iter().collect();
+                    if component.hashes.is_empty() {
+                        if let Some((algorithm, digest)) = archive_info
+                            .hash
+                            .as_deref()
+                            .and_then(|hash| hash.split_once('='))
+                        {
+                            component
+                                .hashes
+                                .push((algorithm.to_string(), digest.to_string()));
+                        }
+                    }
+                }
+                Provenance::Archive(direct_url.url)
+            });
+        }
+        components.push(component);
+    }
+    Ok(components)
+}
+
+/// The packages of the lock. The hashes are those of all files of the release from the
+/// poetry.lock or monotrail.lock, if there is one
+pub fn locked_components(specs: &[RequestedSpec], lock: Option<&PoetryLock>) -> Vec<SbomComponent> {
+    let mut components = Vec::new();
+    for spec in specs {
+        let Some(version) = &spec.python_version else {
+            debug!("Skipping {} without a locked version", spec.name);
+            continue;
+        };
+        let hashes = lock
+            .and_then(|lock| lock.get_filenames(&spec.normalized_name()))
+            .into_iter()
+            .flatten()
+            .filter_map(|file| file.hash.split_once(':'))
+            .map(|(algorithm, digest)| (algorithm.to_string(), digest.to_string()))
+            .collect();
+        let provenance = if let Some(source) = spec
+            .source
+            .as_ref()
+            .filter(|source| is_path_source(&source.source_type))
+        {
+            let path = Path::new(&source.url);
+            Url::from_file_path(path).ok().map(|url| {
+                if path.is_dir() {
+                    Provenance::Directory(url.to_string())
+                } else {
+                    Provenance::Archive(url.to_string())
+                }
+            })
+        } else if let Some(source) = &spec.source {
+            Some(Provenance::Vcs {
+                vcs: source.source_type.clone(),
+                url: source.url.clone(),
+                commit_id: source.resolved_reference.clone(),
+            })
+        } else if let Some((url, _filename, _distribution_type)) = &spec.url {
+            Some(Provenance::Archive(url.clone()))
+        } else if let Some((path, _filename)) = &spec.file_path {
+            Url::from_file_path(path)
+                .ok()
+                .map(|url| Provenance::Archive(url.to_string()))
+        } else {
+            None
+        };
+        components.push(SbomComponent {
+            name: spec.name.clone(),
+            version: version.clone(),
+            hashes,
+            license_expression: None,
+            licenses: Vec::new(),
+            provenance,
+        });
+    }
+    components.sort_by_key(|component| component.name.to_lowercase());
+    components
+}
+
+/// Seconds since the epoch as `2023-11-14T22:13:20Z`
+fn rfc3339_utc(timestamp: u64) -> String {
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (timestamp / 86400) as i64 + 719468;
+    let seconds = timestamp % 86400;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// CycloneDX names the algorithms `SHA-256`, SPDX `SHA256`
+fn algorithm_name(algorithm: &str, separator: &str) -> String {
+    let upper = algorithm.to_uppercase();
+    match upper.strip_prefix("SHA") {
+        Some(bits) if !bits.is_empty() && !bits.starts_with('3') => {
+            format!("SHA{}{}", separator, bits)
+        }
+        _ => upper,
+    }
+}
+
+fn cyclonedx(name: &str, components: &[SbomComponent], timestamp: u64) -> Value {
+    let components: Vec<Value> = components
+        .iter()
+        .map(|component| {
+            let mut entry = json!({
+                "type": "library",
+                "bom-ref": component.purl(),
+                "name": component.name,
+                "version": component.version,
+                "purl": component.purl(),
+            });
+            if !component.hashes.is_empty() {
+                entry["hashes"] = component
+                    .hashes
+                    .iter()
+                    .map(|(algorithm, digest)| {
+                        json!({"alg": algorithm_name(algorithm, "-"), "content": digest})
+                    })
+                    .collect();
+            }
+            let licenses: Vec<Value> = if let Some(expression) = &component.license_expression {
+                vec![json!({ "expression": expression })]
+            } else {
+                component
+                    .licenses
+                    .iter()
+                    .map(|license| json!({"license": {"name": license}}))
+                    .collect()
+            };
+            if !licenses.is_empty() {
+                entry["licenses"] = licenses.into();
+            }
+            if let Some(provenance) = &component.provenance {
+                let reference_type = match provenance {
+                    Provenance::Vcs { .. } => "vcs",
+                    Provenance::Archive(_) | Provenance::Directory(_) => "distribution",
+                };
+                entry["externalReferences"] =
+                    json!([{"type": reference_type, "url": provenance.url()}]);
+            }
+            entry
+        })
+        .collect();
+    json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "version": 1,
+        "metadata": {
+            "timestamp": rfc3339_utc(timestamp),
+            "tools": [{"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")}],
+            "component": {"type": "application", "bom-ref": name, "name": name},
+        },
+        "components": components,
+    })
+}
+
+/// SPDX ids may only contain letters, numbers, `.` and `-`
+fn spdx_id(component: &SbomComponent) -> String {
+    let id: String = format!("{}-{}", component.name, component.version)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("SPDXRef-Package-{}", id)
+}
+
+fn spdx(name: &str, components: &[SbomComponent], timestamp: u64) -> Value {
+    let packages: Vec<Value> = components
+        .iter()
+        .map(|component| {
+            let mut entry = json!({
+                "name": component.name,
+                "SPDXID": spdx_id(component),
+                "versionInfo": component.version,
+                "downloadLocation": component
+                    .provenance
+                    .as_ref()
+                    .map(Provenance::url)
+                    .unwrap_or_else(|| "NOASSERTION".to_string()),
+                "filesAnalyzed": false,
+                // The free form license fields aren't valid SPDX expressions
+                "licenseDeclared": component
+                    .license_expression
+                    .as_deref()
+                    .unwrap_or("NOASSERTION"),
+                "licenseConcluded": "NOASSERTION",
+                "copyrightText": "NOASSERTION",
+                "externalRefs": [{
+                    "referenceCategory": "PACKAGE-MANAGER",
+                    "referenceType": "purl",
+                    "referenceLocator": component.purl(),
+                }],
+            });
+            if !component.hashes.is_empty() {
+                entry["checksums"] = component
+                    .hashes
+                    .iter()
+                    .map(|(algorithm, digest)| {
+                        json!({"algorithm": algorithm_name(algorithm, ""), "checksumValue": digest})
+                    })
+                    .collect();
+            }
+            if component.license_expression.is_none() && !component.licenses.is_empty() {
+                entry["licenseComments"] = component.licenses.join(", ").into();
+            }
+            entry
+        })
+        .collect();
+    let relationships: Vec<Value> = components
+        .iter()
+        .map(|component| {
+            json!({
+                "spdxElementId": "SPDXRef-DOCUMENT",
+                "relationshipType": "DESCRIBES",
+                "relatedSpdxElement": spdx_id(component),
+            })
+        })
+        .collect();
+    // Unique per content, as the spec requires
+    let purls: Vec<String> = components.iter().map(SbomComponent::purl).collect();
+    let namespace = format!(
+        "https://spdx.org/spdxdocs/{}-{}",
+        name,
+        cache_key(format!("{}\n{}", timestamp, purls.join("\n")))
+    );
+    json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": name,
+        "documentNamespace": namespace,
+        "creationInfo": {
+            "created": rfc3339_utc(timestamp),
+            "creators": [format!("Tool: {}-{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))],
+        },
+        "packages": packages,
+        "relationships": relationships,
+    })
+}
+
+/// The SBOM document named `name` (e.g. the project) created at `timestamp` (unix seconds)
+pub fn sbom_json(
+    format: SbomFormat,
+    name: &str,
+    components: &[SbomComponent],
+    timestamp: u64,
+) -> anyhow::Result<String> {
+    let document = match format {
+        SbomFormat::CycloneDx => cyclonedx(name, components, timestamp),
+        SbomFormat::Spdx => spdx(name, components, timestamp),
+    };
+    Ok(serde_json::to_string_pretty(&document)? + "\n")
+}
+
+#[cfg(test)]
+mod test {
+    use crate::poetry_integration::poetry_lock::PoetryLock;
+    use crate::sbom::{
+        installed_components, locked_components, rfc3339_utc, sbom_json, Provenance, SbomComponent,
+        SbomFormat,
+    };
+    use crate::spec::RequestedSpec;
+    use fs_err as fs;
+    use serde_json::Value;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_rfc3339_utc() {
+        assert_eq!(rfc3339_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339_utc(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc3339_utc(1700000000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn test_installed_components() {
+        let site_packages = TempDir::new().unwrap();
+        let dist_info = site_packages.path().join("tqdm-4.65.0.dist-info");
+        fs::create_dir(&dist_info).unwrap();
+        fs::write(
+            dist_info.join("METADATA"),
+            "Metadata-Version: 2.1\nName: tqdm\nVersion: 4.65.0\nLicense: MPLv2.0, MIT Licences\n\
+             Classifier: License :: OSI Approved :: MIT License\n\
+             Classifier: Programming Language :: Python\n\nLicense: not a header\n",
+        )
+        .unwrap();
+        fs::write(
+            dist_info.join("direct_url.json"),
+            r#"{"url": "https://example.org/tqdm-4.65.0-py3-none-any.whl", "archive_info": {"hash": "sha256=abcd"}}"#,
+        )
+        .unwrap();
+        let dist_info = site_packages.path().join("My_Package-1.0.dist-info");
+        fs::create_dir(&dist_info).unwrap();
+        fs::write(
+            dist_info.join("METADATA"),
+            "Metadata-Version: 2.4\nName: My.Package\nVersion: 1.0\nLicense-Expression: MIT OR Apache-2.0\n",
+        )
+        .unwrap();
+        fs::write(
+            dist_info.join("direct_url.json"),
+            r#"{"url": "https://github.com/me/my-package", "vcs_info": {"vcs": "git", "commit_id": "0123abc"}}"#,
+        )
+        .unwrap();
+
+        let components = installed_components(site_packages.path()).unwrap();
+        assert_eq!(
+            components,
+            [
+                SbomComponent {
+                    name: "My.Package".to_string(),
+                    version: "1.0".to_string(),
+                    hashes: Vec::new(),
+                    license_expression: Some("MIT OR Apache-2.0".to_string()),
+                    licenses: Vec::new(),
+                    provenance: Some(Provenance::Vcs {
+                        vcs: "git".to_string(),
+                        url: "https://github.com/me/my-package".to_string(),
+                        commit_id: "0123abc".to_string()
+                    }),
+                },
+                SbomComponent {
+                    name: "tqdm".to_string(),
+                    version: "4.65.0".to_string(),
+                    hashes: vec![("sha256".to_string(), "abcd".to_string())],
+                    license_expression: None,
+                    licenses: vec![
+                        "MPLv2.0, MIT Licences".to_string(),
+                        "MIT License".to_string()
+                    ],
+                    provenance: Some(Provenance::Archive(
+                        "https://example.org/tqdm-4.65.0-py3-none-any.whl".to_string()
+                    )),
+                }
+            ]
+        );
+
+        let cyclonedx: Value = serde_json::from_str(
+            &sbom_json(SbomFormat::CycloneDx, "project", &components, 1700000000).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            cyclonedx["components"][0]["purl"],
+            "pkg:pypi/my-package@1.0"
+        );
+        assert_eq!(
+            cyclonedx["components"][0]["externalReferences"][0]["url"],
+            "git+https://github.com/me/my-package@0123abc"
+        );
+        assert_eq!(
+            cyclonedx["components"][0]["licenses"][0]["expression"],
+            "MIT OR Apache-2.0"
+        );
+        assert_eq!(
+            cyclonedx["components"][1]["hashes"][0]["alg"],
+            Value::from("SHA-256")
+        );
+        assert_eq!(
+            cyclonedx["components"][1]["licenses"][1]["license"]["name"],
+            "MIT License"
+        );
+
+        let spdx: Value = serde_json::from_str(
+            &sbom_json(SbomFormat::Spdx, "project", &components, 1700000000).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(spdx["creationInfo"]["created"], "2023-11-14T22:13:20Z");
+        assert_eq!(
+            spdx["packages"][0]["SPDXID"],
+            "SPDXRef-Package-My.Package-1.0"
+        );
+        assert_eq!(spdx["packages"][0]["licenseDeclared"], "MIT OR Apache-2.0");
+        assert_eq!(spdx["packages"][1]["licenseDeclared"], "NOASSERTION");
+        assert_eq!(spdx["packages"][1]["checksums"][0]["algorithm"], "SHA256");
+        assert_eq!(
+            spdx["relationships"][1]["relatedSpdxElement"],
+            "SPDXRef-Package-tqdm-4.65.0"
+        );
+    }
+
+    #[test]
+    fn test_locked_components() {
+        let lock = PoetryLock::from_str(
+            &fs::read_to_string("../../test-data/poetry-1.3-django/poetry.lock").unwrap(),
+        )
+        .unwrap();
+        let specs = [RequestedSpec::from_requested("sqlparse==0.4.3", &[]).unwrap()];
+        let components = locked_components(&specs, Some(&lock));
+        assert_eq!(components[0].version, "0.4.3");
+        assert_eq!(components[0].hashes.len(), 2);
+        assert!(components[0]
+            .hashes
+            .iter()
+            .all(|(algorithm, _)| algorithm == "sha256"));
+        assert_eq!(components[0].provenance, None);
+    }
+}
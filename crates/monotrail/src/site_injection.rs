@@ -0,0 +1,156 @@
+//! Makes installed monotrail packages importable from any interpreter, without our finder or a
+//! wrapper binary. We generate one of two files that python's `site` module picks up:
+//!
+//!  * `monotrail.pth` for the interpreter's `site-packages` (or a `--user` site), listing the
+//!    `site-packages` of every package. The `.pth` files inside those directories (e.g. of
+//!    matplotlib) are not run, python only processes the ones in site directories.
+//!  * `sitecustomize.py` for a directory on `sys.path` or `PYTHONPATH`, which adds every package
+//!    with `site.addsitedir`, so their `.pth` files run as well.
+
+use crate::install::InstalledPackage;
+use anyhow::{bail, Context};
+use fs_err as fs;
+use std::path::{Path, PathBuf};
+
+/// The kind of file that adds the packages to `sys.path`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum InjectionFormat {
+    /// `monotrail.pth`, one directory per line
+    Pth,
+    /// `sitecustomize.py` calling `site.addsitedir` for each directory
+    Sitecustomize,
+}
+
+impl InjectionFormat {
+    /// The name python looks for
+    pub fn filename(&self) -> &'static str {
+        match self {
+            InjectionFormat::Pth => "monotrail.pth",
+            InjectionFormat::Sitecustomize => "sitecustomize.py",
+        }
+    }
+}
+
+/// The `site-packages` of each package in `sprawl_root`, failing if one isn't installed
+fn package_dirs(
+    sprawl_root: &Path,
+    packages: &[InstalledPackage],
+    python_version: (u8, u8),
+) -> anyhow::Result<Vec<String>> {
+    let mut dirs = Vec::new();
+    for package in packages {
+        let dir = package.monotrail_site_packages(sprawl_root.to_path_buf(), python_version);
+        if !dir.is_dir() {
+            bail!(
+                "{} {} is not installed in {}",
+                package.name,
+                package.python_version,
+                sprawl_root.display()
+            );
+        }
+        // Python reads .pth files with the locale encoding, so we can't write other paths
+        let Some(dir) = dir.to_str() else {
+            bail!("Non-utf8 package path: {}", dir.display());
+        };
+        dirs.push(dir.to_string());
+    }
+    Ok(dirs)
+}
+
+/// Returns the content of the [InjectionFormat] file for the resolved and installed `packages`
+pub fn injection_file(
+    sprawl_root: &Path,
+    packages: &[InstalledPackage],
+    python_version: (u8, u8),
+    format: InjectionFormat,
+) -> anyhow::Result<String> {
+    let dirs = package_dirs(sprawl_root, packages, python_version)?;
+    let content = match format {
+        InjectionFormat::Pth => {
+            let mut content = "# Generated by monotrail\n".to_string();
+            for dir in dirs {
+                if dir.contains('\n') || dir.starts_with("import") {
+                    bail!("Can't add {} to a .pth file", dir);
+                }
+                content += &dir;
+                content += "\n";
+            }
+            content
+        }
+        InjectionFormat::Sitecustomize => {
+            let mut content =
+                "# Generated by monotrail\nimport site\n\nfor sitedir in [\n".to_string();
+            for dir in dirs {
+                // A json string is a valid python string literal
+                content += &format!("    {},\n", serde_json::to_string(&dir)?);
+            }
+            content += "]:\n    site.addsitedir(sitedir)\n";
+            content
+        }
+    };
+    Ok(content)
+}
+
+/// Writes the [InjectionFormat] file for `packages` to `target_dir` and returns its path. Put a
+/// `.pth` into the `site-packages` of the interpreter and a `sitecustomize.py` in a directory on
+/// `PYTHONPATH`
+pub fn write_injection_file(
+    target_dir: &Path,
+    sprawl_root: &Path,
+    packages: &[InstalledPackage],
+    python_version: (u8, u8),
+    format: InjectionFormat,
+) -> anyhow::Result<PathBuf> {
+    let content = injection_file(sprawl_root, packages, python_version, format)?;
+    let path = target_dir.join(format.filename());
+    fs::create_dir_all(target_dir)?;
+    fs::write(&path, content).with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::install::InstalledPackage;
+    use crate::site_injection::{injection_file, InjectionFormat};
+    use fs_err as fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_injection_file() {
+        let sprawl_root = TempDir::new().unwrap();
+        let packages =
+            [("tqdm", "4.65.0"), ("colorama", "0.4.6")].map(|(name, version)| InstalledPackage {
+                name: name.to_string(),
+                python_version: version.to_string(),
+                unique_version: version.to_string(),
+                tag: "py3-none-any".to_string(),
+            });
+        let err = injection_file(sprawl_root.path(), &packages, (3, 8), InjectionFormat::Pth)
+            .unwrap_err();
+        assert!(err.to_string().starts_with("tqdm 4.65.0 is not installed"));
+
+        let dirs: Vec<String> = packages
+            .iter()
+            .map(|package| {
+                let dir = package.monotrail_site_packages(sprawl_root.path().to_path_buf(), (3, 8));
+                fs::create_dir_all(&dir).unwrap();
+                dir.to_string_lossy().to_string()
+            })
+            .collect();
+        let pth =
+            injection_file(sprawl_root.path(), &packages, (3, 8), InjectionFormat::Pth).unwrap();
+        assert_eq!(
+            pth,
+            format!("# Generated by monotrail\n{}\n{}\n", dirs[0], dirs[1])
+        );
+        let sitecustomize = injection_file(
+            sprawl_root.path(),
+            &packages,
+            (3, 8),
+            InjectionFormat::Sitecustomize,
+        )
+        .unwrap();
+        assert!(sitecustomize.contains(&format!("    {:?},\n", dirs[1])));
+        assert!(sitecustomize.ends_with("    site.addsitedir(sitedir)\n"));
+    }
+}
@@ -0,0 +1,371 @@
+//! `monotrail snapshot`: Saves the exact set of distributions installed in a venv and puts it
+//! back later, e.g. before trying out a risky upgrade.
+//!
+//! The files of the distributions are stored once by content in `<cache>/snapshots/objects`, and
+//! each snapshot is a json manifest in `<cache>/snapshots` that references those objects. Restoring
+//! only removes and copies files, so it doesn't need the network or any builds, but it only works
+//! on the same machine.
+
+use crate::utils::cache_dir;
+use anyhow::{bail, Context};
+use fs_err as fs;
+use install_wheel_rs::{
+    cache_key, installed_dist_infos, is_free_threaded_venv, read_record_file, remove_distribution,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// `monotrail snapshot create|restore`
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum SnapshotCommand {
+    /// Save the distributions installed in the current venv
+    Create {
+        /// The name to restore the snapshot with, an existing snapshot is overwritten
+        name: String,
+    },
+    /// Put the venv back in the state of the snapshot, removing everything installed since
+    Restore {
+        /// The name given to `snapshot create`
+        name: String,
+    },
+}
+
+/// The manifest of a snapshot
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub(crate) struct Snapshot {
+    /// The venv the snapshot was taken of, informational only
+    pub(crate) venv: PathBuf,
+    /// Restoring into another python version would mix up the site-packages
+    pub(crate) python_version: (u8, u8),
+    pub(crate) distributions: Vec<SnapshotDistribution>,
+}
+
+/// One installed distribution, identified by its .dist-info directory
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub(crate) struct SnapshotDistribution {
+    pub(crate) name: String,
+    pub(crate) version: String,
+    pub(crate) dist_info: String,
+    /// The object of the RECORD, to skip distributions that didn't change when restoring
+    pub(crate) record: String,
+    pub(crate) files: Vec<SnapshotFile>,
+}
+
+/// A file from the RECORD
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub(crate) struct SnapshotFile {
+    /// Relative to site-packages like in the RECORD, so scripts are `../../../bin/<name>`
+    pub(crate) path: String,
+    pub(crate) object: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) executable: bool,
+}
+
+/// `<cache>/snapshots`
+pub(crate) fn snapshots_dir() -> anyhow::Result<PathBuf> {
+    Ok(cache_dir()?.join("snapshots"))
+}
+
+/// `lib/python3.x/site-packages`, or `lib/python3.xt/site-packages` for a free-threaded python
+pub(crate) fn venv_site_packages(venv: &Path, python_version: (u8, u8)) -> PathBuf {
+    if cfg!(windows) {
+        venv.join("Lib").join("site-packages")
+    } else {
+        let abiflags = if is_free_threaded_venv(venv, python_version) {
+            "t"
+        } else {
+            ""
+        };
+        venv.join("lib")
+            .join(format!(
+                "python{}.{}{}",
+                python_version.0, python_version.1, abiflags
+            ))
+            .join("site-packages")
+    }
+}
+
+fn manifest_path(snapshots_dir: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        bail!("Invalid snapshot name {:?}", name);
+    }
+    Ok(snapshots_dir.join(format!("{}.json", name)))
+}
+
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o111 != 0
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        false
+    }
+}
+
+/// Adds `data` to the object store unless it's already there and returns its key
+fn store_object(objects: &Path, data: &[u8]) -> anyhow::Result<String> {
+    let key = cache_key(data);
+    let target = objects.join(&key);
+    if !target.is_file() {
+        // Write to a temp file and rename, so an aborted snapshot never leaves a partial object
+        let mut temp_file = tempfile::NamedTempFile::new_in(objects)?;
+        io::Write::write_all(&mut temp_file, data)?;
+        temp_file.persist(&target)?;
+    }
+    Ok(key)
+}
+
+/// Stores the files of all distributions installed in the venv and writes the manifest
+pub(crate) fn create_snapshot(
+    venv: &Path,
+    python_version: (u8, u8),
+    snapshots_dir: &Path,
+    name: &str,
+) -> anyhow::Result<Snapshot> {
+    let manifest = manifest_path(snapshots_dir, name)?;
+    let objects = snapshots_dir.join("objects");
+    fs::create_dir_all(&objects)?;
+    let site_packages = venv_site_packages(venv, python_version);
+
+    let mut distributions = Vec::new();
+    for (name, version, dist_info) in installed_dist_infos(&site_packages)? {
+        let record_data = fs::read(site_packages.join(&dist_info).join("RECORD"))
+            .with_context(|| format!("{} has no RECORD, can't snapshot it", dist_info))?;
+        let record = read_record_file(&mut record_data.as_slice())
+            .with_context(|| format!("Invalid RECORD in {}", dist_info))?;
+        let mut files = Vec::new();
+        for entry in record {
+            let path = site_packages.join(&entry.path);
+            let data = match fs::read(&path) {
+                Ok(data) => data,
+                // e.g. a pyc file the RECORD lists but that python never wrote
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    debug!("Skipping missing {}", path.display());
+                    continue;
+                }
+                Err(err) => return Err(err.into()),
+            };
+            files.push(SnapshotFile {
+                object: store_object(&objects, &data)?,
+                executable: is_executable(&fs::metadata(&path)?),
+                path: entry.path,
+            });
+        }
+        distributions.push(SnapshotDistribution {
+            name,
+            version,
+            record: cache_key(&record_data),
+            dist_info,
+            files,
+        });
+    }
+
+    let snapshot = Snapshot {
+        venv: venv.to_path_buf(),
+        python_version,
+        distributions,
+    };
+    fs::write(&manifest, serde_json::to_string_pretty(&snapshot)?)?;
+    Ok(snapshot)
+}
+
+/// Copies the files of a distribution back from the object store
+fn restore_distribution(
+    site_packages: &Path,
+    objects: &Path,
+    distribution: &SnapshotDistribution,
+) -> anyhow::Result<()> {
+    for file in &distribution.files {
+        let target = site_packages.join(&file.path);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(objects.join(&file.object), &target)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = if file.executable { 0o755 } else { 0o644 };
+            fs::set_permissions(&target, std::fs::Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(())
+}
+
+/// Puts the venv back into the state of the snapshot. Distributions whose RECORD didn't change are
+/// left alone, all others are removed and the ones from the snapshot are copied back in.
+///
+/// Returns the number of removed and restored distributions
+pub(crate) fn restore_snapshot(
+    venv: &Path,
+    python_version: (u8, u8),
+    snapshots_dir: &Path,
+    name: &str,
+) -> anyhow::Result<(usize, usize)> {
+    let manifest = manifest_path(snapshots_dir, name)?;
+    if !manifest.is_file() {
+        bail!("There is no snapshot named {}", name);
+    }
+    let snapshot: Snapshot = serde_json::from_str(&fs::read_to_string(&manifest)?)
+        .with_context(|| format!("Invalid snapshot manifest {}", manifest.display()))?;
+    if snapshot.python_version != python_version {
+        bail!(
+            "The snapshot {} was taken with python {}.{}, but the venv has python {}.{}",
+            name,
+            snapshot.python_version.0,
+            snapshot.python_version.1,
+            python_version.0,
+            python_version.1
+        );
+    }
+    // Check before changing anything, the store may have been cleared in between
+    let objects = snapshots_dir.join("objects");
+    for distribution in &snapshot.distributions {
+        for file in &distribution.files {
+            if !objects.join(&file.object).is_file() {
+                bail!(
+                    "The stored files of {} {} for snapshot {} are missing, can't restore",
+                    distribution.name,
+                    distribution.version,
+                    name
+                );
+            }
+        }
+    }
+
+    let site_packages = venv_site_packages(venv, python_version);
+    let wanted: HashMap<&str, &SnapshotDistribution> = snapshot
+        .distributions
+        .iter()
+        .map(|distribution| (distribution.dist_info.as_str(), distribution))
+        .collect();
+    let mut unchanged = BTreeSet::new();
+    let mut removed = 0;
+    for (_name, _version, dist_info) in installed_dist_infos(&site_packages)? {
+        if let Some(distribution) = wanted.get(dist_info.as_str()) {
+            let record = fs::read(site_packages.join(&dist_info).join("RECORD")).ok();
+            if record.map(cache_key).as_ref() == Some(&distribution.record) {
+                unchanged.insert(dist_info);
+                continue;
+            }
+        }
+        debug!("Removing {}", dist_info);
+        remove_distribution(&site_packages, &dist_info)?;
+        removed += 1;
+    }
+    let mut restored = 0;
+    for distribution in &snapshot.distributions {
+        if unchanged.contains(&distribution.dist_info) {
+            continue;
+        }
+        debug!("Restoring {}", distribution.dist_info);
+        restore_distribution(&site_packages, &objects, distribution)
+            .with_context(|| format!("Failed to restore {}", distribution.dist_info))?;
+        restored += 1;
+    }
+    Ok((removed, restored))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::snapshot::{create_snapshot, restore_snapshot, venv_site_packages};
+    use crate::test_utils::install_files;
+    use fs_err as fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let venv = temp_dir.path().join(".venv");
+        let snapshots = temp_dir.path().join("snapshots");
+        let site_packages = venv.join("lib/python3.8/site-packages");
+        // Minimal installed distributions with a module and a script
+        install_files(
+            &site_packages,
+            "foo-1.0.0.dist-info",
+            &[
+                ("foo/__init__.py", "foo = 1"),
+                ("../../../bin/foo", "#!python"),
+                ("foo-1.0.0.dist-info/METADATA", ""),
+            ],
+        );
+        install_files(
+            &site_packages,
+            "bar-2.0.0.dist-info",
+            &[
+                ("bar/__init__.py", "bar = 2"),
+                ("../../../bin/bar", "#!python"),
+                ("bar-2.0.0.dist-info/METADATA", ""),
+            ],
+        );
+
+        let snapshot = create_snapshot(&venv, (3, 8), &snapshots, "before").unwrap();
+        let names: Vec<_> = snapshot
+            .distributions
+            .iter()
+            .map(|distribution| distribution.name.as_str())
+            .collect();
+        assert_eq!(names, ["bar", "foo"]);
+        assert_eq!(snapshot.distributions[1].files.len(), 4);
+
+        // Upgrade foo, add baz
+        fs::remove_dir_all(site_packages.join("foo-1.0.0.dist-info")).unwrap();
+        install_files(
+            &site_packages,
+            "foo-1.1.0.dist-info",
+            &[
+                ("foo/__init__.py", "foo = 1.1"),
+                ("../../../bin/foo", "#!python"),
+                ("foo-1.1.0.dist-info/METADATA", ""),
+            ],
+        );
+        install_files(
+            &site_packages,
+            "baz-0.1.0.dist-info",
+            &[
+                ("baz/__init__.py", "baz = 0"),
+                ("../../../bin/baz", "#!python"),
+                ("baz-0.1.0.dist-info/METADATA", ""),
+            ],
+        );
+
+        let (removed, restored) = restore_snapshot(&venv, (3, 8), &snapshots, "before").unwrap();
+        // bar is untouched
+        assert_eq!((removed, restored), (2, 1));
+        assert!(!site_packages.join("baz").exists());
+        assert!(!venv.join("bin/baz").exists());
+        assert!(!site_packages.join("foo-1.1.0.dist-info").exists());
+        assert_eq!(
+            fs::read_to_string(site_packages.join("foo/__init__.py")).unwrap(),
+            "foo = 1"
+        );
+        assert!(venv.join("bin/foo").is_file());
+        assert!(site_packages.join("bar-2.0.0.dist-info/RECORD").is_file());
+
+        let err = restore_snapshot(&venv, (3, 9), &snapshots, "before").unwrap_err();
+        assert!(err.to_string().contains("python 3.8"), "{}", err);
+        let err = restore_snapshot(&venv, (3, 8), &snapshots, "after").unwrap_err();
+        assert_eq!(err.to_string(), "There is no snapshot named after");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_venv_site_packages_free_threaded() {
+        let temp_dir = TempDir::new().unwrap();
+        let venv = temp_dir.path();
+        assert_eq!(
+            venv_site_packages(venv, (3, 13)),
+            venv.join("lib/python3.13/site-packages")
+        );
+        fs::create_dir_all(venv.join("lib/python3.13t/site-packages")).unwrap();
+        assert_eq!(
+            venv_site_packages(venv, (3, 13)),
+            venv.join("lib/python3.13t/site-packages")
+        );
+    }
+}
@@ -1,58 +1,199 @@
 //! Build a wheel from a source distribution
 
-use crate::utils::cache_dir;
+use crate::build_cache::{BuildCache, BuildKey};
+use crate::build_frontend::{build_wheel, python_context_for, unpack_sdist};
+use crate::config_settings::{settings_key, ConfigSettings, PackageConfigSettings};
+use crate::progress::{self, Phase};
+use crate::utils::{artifact_dirs, writable_artifact_dir};
 use anyhow::{bail, Context, Result};
 use fs_err as fs;
-use install_wheel_rs::{CompatibleTags, Error, WheelFilename};
+use install_wheel_rs::{library_search_path, repair_wheel, CompatibleTags, Error, WheelFilename};
+use monotrail_utils::transport::Transport;
+use std::env;
 use std::ffi::OsString;
 use std::io;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str::FromStr;
+use std::time::Instant;
 use tempfile::TempDir;
+use tracing::{debug, info, warn};
 
 /// Takes a source distribution, checks whether we have already built a matching wheel, and if
-/// not, builds a wheels from the source distribution by invoking `pip wheel --no-deps`
+/// not, builds a wheels from the source distribution with `python` (see [build_to_wheel]).
+///
+/// Built wheels of source distribution files are shared through the [BuildCache], if configured.
+/// Builds with `config_settings` (see [crate::config_settings]) are cached separately
 pub fn build_source_distribution_to_wheel_cached(
     name: &str,
     version: &str,
     sdist: &Path,
+    python: &Path,
     compatible_tags: &CompatibleTags,
+    transport: &dyn Transport,
 ) -> Result<PathBuf> {
-    let target_dir = cache_dir()?.join("artifacts").join(name).join(version);
-
-    if let Ok(target_dir) = fs::read_dir(&target_dir) {
-        for entry in target_dir.flatten() {
-            if !entry.path().to_string_lossy().ends_with(".whl") {
-                continue;
+    let config_settings = PackageConfigSettings::from_env()?.for_package(name);
+    let artifact_version = match settings_key(&config_settings)? {
+        Some(settings_key) => format!("{}-{}", version, settings_key),
+        None => version.to_string(),
+    };
+    for target_dir in artifact_dirs(name, &artifact_version)? {
+        if let Ok(target_dir) = fs::read_dir(&target_dir) {
+            for entry in target_dir.flatten() {
+                if !entry.path().to_string_lossy().ends_with(".whl") {
+                    continue;
+                }
+                if let Ok(true) =
+                    WheelFilename::from_str(entry.file_name().to_string_lossy().as_ref())
+                        .map(|filename| filename.compatibility(compatible_tags).is_ok())
+                {
+                    return Ok(entry.path());
+                }
             }
-            if let Ok(true) = WheelFilename::from_str(entry.file_name().to_string_lossy().as_ref())
-                .map(|filename| filename.compatibility(compatible_tags).is_ok())
-            {
-                return Ok(entry.path());
+        };
+    }
+
+    let repair = cfg!(target_os = "linux") && repair_enabled();
+    // Repository checkouts are directories and have no hash
+    let build_cache = BuildCache::from_env()
+        .filter(|_| sdist.is_file())
+        .map(|cache| {
+            let key = BuildKey::new(sdist, compatible_tags, &config_settings, repair)?;
+            anyhow::Ok((cache, key))
+        })
+        .transpose()?;
+    if let Some((cache, key)) = &build_cache {
+        let target_dir = writable_artifact_dir(name, &artifact_version)?;
+        match cache.fetch(key, &target_dir, transport) {
+            Ok(Some(wheel)) => {
+                debug!("Using {} {} from the build cache ({})", name, version, key);
+                return Ok(wheel);
             }
+            Ok(None) => {}
+            Err(err) => warn!(
+                "Failed to read {} {} from the build cache: {:?}",
+                name, version, err
+            ),
         }
-    };
+    }
 
     let build_dir = TempDir::new()?;
-    let wheel = build_to_wheel(sdist, build_dir.path(), compatible_tags)?;
-    fs::create_dir_all(&target_dir)?;
+    progress::package_started(Phase::Build, name);
+    let start = Instant::now();
+    let wheel = build_to_wheel(
+        sdist,
+        build_dir.path(),
+        python,
+        compatible_tags,
+        &config_settings,
+    );
+    progress::package_finished(
+        Phase::Build,
+        name,
+        start.elapsed(),
+        wheel.as_ref().err().map(|err| err as _),
+    );
+    let wheel = wheel?;
+    if repair {
+        let report = repair_wheel(&wheel, &library_search_path()).with_context(|| {
+            format!("Failed to repair the wheel built for {} {}", name, version)
+        })?;
+        for (soname, source) in report.grafted {
+            info!("Copied {} from {} into the wheel", soname, source.display());
+        }
+    }
+    let target_dir = writable_artifact_dir(name, &artifact_version)?;
     let wheel_in_cache = target_dir.join(wheel.file_name().unwrap_or(&OsString::new()));
     // rename only work on the same device :/
     fs::copy(wheel, &wheel_in_cache)?;
+    if let Some((cache, key)) = &build_cache {
+        // Not being able to share the wheel shouldn't fail the install
+        if let Err(err) = cache.store(key, &wheel_in_cache, transport) {
+            warn!(
+                "Failed to upload {} {} to the build cache: {:?}",
+                name, version, err
+            );
+        }
+    }
     Ok(wheel_in_cache)
 }
 
-/// Builds a wheel from an source distribution or a repo checkout using `pip wheel --no-deps`
+/// Whether to copy the shared libraries that locally built wheels link against into the wheel
+/// (like auditwheel), opt-in through `MONOTRAIL_REPAIR_WHEELS=1`
+fn repair_enabled() -> bool {
+    env::var_os(format!(
+        "{}_REPAIR_WHEELS",
+        env!("CARGO_PKG_NAME").to_uppercase()
+    ))
+    .is_some_and(|value| !value.is_empty() && value != "0")
+}
+
+/// Whether to build with `pip wheel --no-deps` instead of our own PEP 517 frontend, through
+/// `MONOTRAIL_BUILD_FRONTEND=pip`
+fn use_pip() -> Result<bool> {
+    let var = format!("{}_BUILD_FRONTEND", env!("CARGO_PKG_NAME").to_uppercase());
+    match env::var(&var).as_deref() {
+        Err(_) | Ok("") | Ok("native") => Ok(false),
+        Ok("pip") => Ok(true),
+        Ok(other) => bail!("Invalid {}={}, must be native or pip", var, other),
+    }
+}
+
+/// Builds a wheel from an source distribution or a repo checkout in an isolated build environment
+/// for `python`
 pub fn build_to_wheel(
     sdist_or_dir: &Path,
     // needs to be passed in or the tempdir will be deleted to early
     build_dir: &Path,
+    python: &Path,
     compatible_tags: &CompatibleTags,
+    config_settings: &ConfigSettings,
 ) -> Result<PathBuf> {
-    let output = Command::new("pip")
-        .current_dir(build_dir)
-        .args(["wheel", "--no-deps"])
+    if use_pip()? {
+        return build_to_wheel_pip(sdist_or_dir, build_dir, compatible_tags, config_settings);
+    }
+    let source_tree = if sdist_or_dir.is_dir() {
+        sdist_or_dir.to_path_buf()
+    } else {
+        let unpacked = build_dir.join("source");
+        fs::create_dir(&unpacked)?;
+        unpack_sdist(sdist_or_dir, &unpacked)?
+    };
+    let wheel = build_wheel(
+        &source_tree,
+        &build_dir.join("dist"),
+        &python_context_for(python)?,
+        config_settings,
+    )?;
+    let filename = wheel.file_name().unwrap_or_default().to_string_lossy();
+    if WheelFilename::from_str(&filename)?
+        .compatibility(compatible_tags)
+        .is_err()
+    {
+        bail!(
+            "The build backend wrote out an incompatible wheel {} for {}",
+            filename,
+            python.display()
+        )
+    }
+    Ok(wheel)
+}
+
+/// Builds a wheel from an source distribution or a repo checkout using `pip wheel --no-deps`
+fn build_to_wheel_pip(
+    sdist_or_dir: &Path,
+    build_dir: &Path,
+    compatible_tags: &CompatibleTags,
+    config_settings: &ConfigSettings,
+) -> Result<PathBuf> {
+    let mut command = Command::new("pip");
+    command.current_dir(build_dir).args(["wheel", "--no-deps"]);
+    for (key, setting) in config_settings {
+        for value in setting.values() {
+            command.arg(format!("--config-settings={}={}", key, value));
+        }
+    }
+    let output = command
         .arg(sdist_or_dir)
         .output()
         .context("Failed to invoke pip")?;
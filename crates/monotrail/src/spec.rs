@@ -1,9 +1,14 @@
 //! Descriptions of user requests ([RequestedSpec]) and a fully resolved installable
 //! ([ResolvedSpec]).
 
-use crate::package_index::search_release;
+use crate::index::IndexUrls;
+use crate::package_index::{search_find_links, search_release};
+use crate::path_dependency::is_path_source;
+use anyhow::bail;
 use install_wheel_rs::{normalize_name, CompatibleTags, Error, WheelFilename};
-use regex::Regex;
+use monotrail_utils::transport::Transport;
+use pep440_rs::{Operator, Version, VersionSpecifiers};
+use pep508_rs::{Requirement, VersionOrUrl};
 use std::path::PathBuf;
 use std::str::FromStr;
 
@@ -38,6 +43,8 @@ pub struct RequestedSpec {
     pub name: String,
     /// The version of the package
     pub python_version: Option<String>,
+    /// Instead of an exact version, the newest version matching these, e.g. `>=4.60,<5`
+    pub specifiers: Option<VersionSpecifiers>,
     pub source: Option<SpecSource>,
     /// The extras of the package to also be installed
     pub extras: Vec<String>,
@@ -60,7 +67,19 @@ impl RequestedSpec {
         }
     }
 
-    /// Parses "package_name", "package_name==version" and "some/path/tqdm-4.62.3-py2.py3-none-any.whl"
+    /// Whether an installed `version` fulfills the pin or the specifiers of this request
+    pub fn matches_version(&self, version: &str) -> bool {
+        if let Some(python_version) = &self.python_version {
+            same_version(python_version, version)
+        } else if let Some(specifiers) = &self.specifiers {
+            Version::from_str(version).is_ok_and(|version| specifiers.contains(&version))
+        } else {
+            true
+        }
+    }
+
+    /// Parses a PEP 508 requirement such as "package_name", "package_name==version" or
+    /// "package_name[extra]>=1.2,<2" or a path such as "some/path/tqdm-4.62.3-py2.py3-none-any.whl"
     pub fn from_requested(requested: impl AsRef<str>, extras: &[String]) -> Result<Self, Error> {
         if requested.as_ref().ends_with(".whl") {
             let file_path = PathBuf::from(requested.as_ref());
@@ -73,37 +92,50 @@ impl RequestedSpec {
                 requested: requested.as_ref().to_string(),
                 name: metadata.distribution.clone(),
                 python_version: Some(metadata.version.clone()),
+                specifiers: None,
                 source: None,
                 extras: extras.to_vec(),
                 file_path: Some((file_path, metadata)),
                 url: None,
             })
         } else {
-            // TODO: check actual naming rules
-            let valid_name = Regex::new(r"[-_a-zA-Z\d.]+").unwrap();
-            if let Some((name, version)) = requested.as_ref().split_once("==") {
-                Ok(Self {
-                    requested: requested.as_ref().to_string(),
-                    name: name.to_string(),
-                    python_version: Some(version.to_string()),
-                    source: None,
-                    extras: extras.to_vec(),
-                    file_path: None,
-                    url: None,
-                })
-            } else if valid_name.is_match(requested.as_ref()) {
-                Ok(Self {
-                    requested: requested.as_ref().to_string(),
-                    name: requested.as_ref().to_string(),
-                    python_version: None,
-                    source: None,
-                    extras: extras.to_vec(),
-                    file_path: None,
-                    url: None,
-                })
-            } else {
-                Err(Error::Pep440)
+            let requirement = Requirement::from_str(requested.as_ref())
+                .map_err(|err| Error::Pep440(err.to_string()))?;
+            if requirement.marker.is_some() {
+                return Err(Error::Pep440(format!(
+                    "Markers are not supported: {}",
+                    requested.as_ref()
+                )));
+            }
+            let (python_version, specifiers) = match requirement.version_or_url {
+                None => (None, None),
+                Some(VersionOrUrl::Url(url)) => {
+                    return Err(Error::Pep440(format!("Urls are not supported: {}", url)));
+                }
+                // A single `==` is a pin, everything else is a range
+                Some(VersionOrUrl::VersionSpecifier(specifiers)) => match &*specifiers {
+                    [specifier] if *specifier.operator() == Operator::Equal => {
+                        (Some(specifier.version().to_string()), None)
+                    }
+                    _ => (None, Some(specifiers)),
+                },
+            };
+            let mut all_extras = extras.to_vec();
+            for extra in requirement.extras.unwrap_or_default() {
+                if !all_extras.contains(&extra) {
+                    all_extras.push(extra);
+                }
             }
+            Ok(Self {
+                requested: requested.as_ref().to_string(),
+                name: requirement.name,
+                python_version,
+                specifiers,
+                source: None,
+                extras: all_extras,
+                file_path: None,
+                url: None,
+            })
         }
     }
 
@@ -112,6 +144,7 @@ impl RequestedSpec {
     pub fn resolve(
         &self,
         host: &str,
+        transport: &dyn Transport,
         compatible_tags: &CompatibleTags,
     ) -> anyhow::Result<ResolvedSpec> {
         if let Some(python_version) = self.python_version.clone() {
@@ -125,6 +158,7 @@ impl RequestedSpec {
                     extras: self.extras.clone(),
                     location: FileOrUrl::File(file_path),
                     distribution_type: DistributionType::Wheel,
+                    sha256: None,
                 });
             } else if let Some((url, filename, distribution_type)) = self.url.clone() {
                 return Ok(ResolvedSpec {
@@ -135,35 +169,71 @@ impl RequestedSpec {
                     extras: self.extras.clone(),
                     location: FileOrUrl::Url { url, filename },
                     distribution_type,
+                    sha256: None,
                 });
             } else if let Some(source) = self.source.clone() {
+                // A local path has the fingerprint of its source as `resolved_reference`
+                let (location, distribution_type) = if is_path_source(&source.source_type) {
+                    let distribution_type = if source.url.ends_with(".whl") {
+                        DistributionType::Wheel
+                    } else {
+                        DistributionType::SourceDistribution
+                    };
+                    (
+                        FileOrUrl::File(PathBuf::from(source.url)),
+                        distribution_type,
+                    )
+                } else {
+                    let location = FileOrUrl::Git {
+                        url: source.url,
+                        revision: source.resolved_reference.clone(),
+                    };
+                    (location, DistributionType::SourceDistribution)
+                };
                 return Ok(ResolvedSpec {
                     requested: self.requested.clone(),
                     name: self.name.clone(),
                     python_version,
-                    unique_version: source.resolved_reference.clone(),
+                    unique_version: source.resolved_reference,
                     extras: self.extras.clone(),
-                    location: FileOrUrl::Git {
-                        url: source.url,
-                        revision: source.resolved_reference,
-                    },
-                    distribution_type: DistributionType::SourceDistribution,
+                    location,
+                    distribution_type,
+                    sha256: None,
                 });
             }
         }
 
-        let (picked_release, distribution_type, version) = search_release(
-            host,
+        let indexes = IndexUrls::from_env();
+        let found = search_find_links(
+            &indexes.find_links,
+            transport,
             &self.name,
-            self.python_version.clone(),
+            self.python_version.as_deref(),
+            self.specifiers.as_ref(),
             compatible_tags,
         )?;
+        let (picked_release, distribution_type, version) = match found {
+            Some(found) => found,
+            None if indexes.indexes.is_empty() => bail!(
+                "Can't find {}, it's not in --find-links and no index is configured (--no-index)",
+                self.name
+            ),
+            None => search_release(
+                host,
+                transport,
+                &self.name,
+                self.python_version.clone(),
+                self.specifiers.as_ref(),
+                compatible_tags,
+            )?,
+        };
         Ok(ResolvedSpec {
             requested: self.requested.clone(),
             name: self.name.clone(),
             python_version: version.clone(),
             unique_version: version,
             extras: self.extras.clone(),
+            sha256: picked_release.digests.get("sha256").cloned(),
             location: FileOrUrl::Url {
                 url: picked_release.url,
                 filename: picked_release.filename,
@@ -173,8 +243,17 @@ impl RequestedSpec {
     }
 }
 
-/// The three places we can install something from: A file (wheel or sdist), a url which offers a
-/// file (i.e. the pypi servers) or a git repository.
+/// Whether both are the same PEP 440 version, e.g. `1.0` and `1.0.0`. What isn't a version, such
+/// as a git revision, is compared as string
+pub(crate) fn same_version(left: &str, right: &str) -> bool {
+    match (Version::from_str(left), Version::from_str(right)) {
+        (Ok(left), Ok(right)) => left == right,
+        _ => left == right,
+    }
+}
+
+/// The three places we can install something from: A file (wheel or sdist) or a project directory,
+/// a url which offers a file (i.e. the pypi servers) or a git repository.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum FileOrUrl {
     File(PathBuf),
@@ -199,19 +278,26 @@ pub struct ResolvedSpec {
     pub extras: Vec<String>,
     pub location: FileOrUrl,
     pub distribution_type: DistributionType,
+    /// The hex sha256 from the index, checked after downloading
+    pub sha256: Option<String>,
 }
 
 #[cfg(test)]
 mod test {
     use crate::markers::marker_environment_from_json_str;
     use crate::poetry_integration::read_dependencies::poetry_spec_from_dir;
-    use crate::spec::{FileOrUrl, ResolvedSpec};
+    use crate::spec::{FileOrUrl, RequestedSpec, ResolvedSpec};
     use crate::utils::zstd_json_mock;
     use install_wheel_rs::{Arch, CompatibleTags, Os};
     use mockito::Server;
+    use monotrail_utils::transport::{MockTransport, Transport, UreqTransport};
     use std::path::Path;
 
-    fn manylinux_url(host: &str, package: &str) -> anyhow::Result<ResolvedSpec> {
+    fn manylinux_url(
+        host: &str,
+        transport: &dyn Transport,
+        package: &str,
+    ) -> anyhow::Result<ResolvedSpec> {
         let os = Os::Manylinux {
             major: 2,
             minor: 27,
@@ -245,7 +331,35 @@ mod test {
             .iter()
             .find(|spec| spec.name == package)
             .unwrap()
-            .resolve(host, &compatible_tags)
+            .resolve(host, transport, &compatible_tags)
+    }
+
+    #[test]
+    fn test_from_requested() {
+        let spec = RequestedSpec::from_requested("tqdm==4.62.3", &[]).unwrap();
+        assert_eq!(spec.python_version.as_deref(), Some("4.62.3"));
+        assert_eq!(spec.specifiers, None);
+
+        let spec = RequestedSpec::from_requested("black[jupyter]>=22,<24", &[]).unwrap();
+        assert_eq!(spec.name, "black");
+        assert_eq!(spec.extras, ["jupyter"]);
+        assert_eq!(spec.python_version, None);
+        assert_eq!(spec.specifiers.as_ref().unwrap().to_string(), ">=22, <24");
+        assert!(spec.matches_version("23.1.0"));
+        assert!(!spec.matches_version("24.0"));
+
+        let spec = RequestedSpec::from_requested("numpy==1.24.*", &[]).unwrap();
+        assert!(spec.matches_version("1.24.2"));
+        assert!(RequestedSpec::from_requested("numpy==1.24", &[])
+            .unwrap()
+            .matches_version("1.24.0"));
+
+        let err = RequestedSpec::from_requested("tqdm>=", &[]).unwrap_err();
+        assert!(
+            err.to_string().starts_with("Invalid requirement: "),
+            "{}",
+            err
+        );
     }
 
     #[test]
@@ -253,7 +367,7 @@ mod test {
         let (server, _mock) =
             zstd_json_mock("/pypi/cffi/json", "../../test-data/pypi/cffi.json.zstd");
         assert_eq!(
-            manylinux_url(&server.url(), "cffi").unwrap().location,
+            manylinux_url(&server.url(), &UreqTransport, "cffi").unwrap().location,
             FileOrUrl::Url {
                 url: "https://files.pythonhosted.org/packages/93/d0/2e2b27ea2f69b0ec9e481647822f8f77f5fc23faca2dd00d1ff009940eb7/cffi-1.15.1-cp37-cp37m-manylinux_2_17_x86_64.manylinux2014_x86_64.whl".to_string(),
                 filename: "cffi-1.15.1-cp37-cp37m-manylinux_2_17_x86_64.manylinux2014_x86_64.whl".to_string()
@@ -261,11 +375,30 @@ mod test {
         )
     }
 
+    #[test]
+    fn test_manylinux_url_mock_transport() {
+        let cffi_json = zstd::stream::decode_all(
+            fs_err::File::open("../../test-data/pypi/cffi.json.zstd").unwrap(),
+        )
+        .unwrap();
+        let transport =
+            MockTransport::default().with_response("https://pypi.org/pypi/cffi/json", cffi_json);
+        let resolved = manylinux_url("https://pypi.org", &transport, "cffi").unwrap();
+        assert_eq!(
+            resolved.location,
+            FileOrUrl::Url {
+                url: "https://files.pythonhosted.org/packages/93/d0/2e2b27ea2f69b0ec9e481647822f8f77f5fc23faca2dd00d1ff009940eb7/cffi-1.15.1-cp37-cp37m-manylinux_2_17_x86_64.manylinux2014_x86_64.whl".to_string(),
+                filename: "cffi-1.15.1-cp37-cp37m-manylinux_2_17_x86_64.manylinux2014_x86_64.whl".to_string()
+            }
+        );
+        assert_eq!(transport.requests(), ["https://pypi.org/pypi/cffi/json"]);
+    }
+
     #[test]
     fn test_pypi_no_internet() {
         let server = Server::new();
         // We must use a different package here or we race with the other mock
-        let err = manylinux_url(&server.url(), "certifi").unwrap_err();
+        let err = manylinux_url(&server.url(), &UreqTransport, "certifi").unwrap_err();
         let errors = err.chain().map(|e| e.to_string()).collect::<Vec<_>>();
         // the second message has the mockito url in it
         assert_eq!(
@@ -0,0 +1,399 @@
+//! `monotrail store`: Maintenance of the monotrail store, the directory with one installation per
+//! package, version and tag (`<root>/<name>/<unique version>/<tag>`) that all projects share.
+//!
+//! Each time a project is installed from the store, we record which store entries it uses in
+//! `<cache>/store-usage/<hash of the project dir>.json`. `store gc` removes the entries that no
+//! project used recently, so the store doesn't grow without bounds. Entries that no usage record
+//! mentions at all, e.g. from installs without a project directory or from older versions, are
+//! kept, since we can't know whether they are in use.
+
+use crate::install::InstalledPackage;
+use crate::monotrail::list_installed;
+use crate::utils::cache_dir;
+use anyhow::Context;
+use fs_err as fs;
+use install_wheel_rs::{cache_key, read_record_file, LockedDir};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+/// `monotrail store info|verify|gc`
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum StoreCommand {
+    /// Show the size of the store and how much of it are files that several packages share
+    Info,
+    /// Hash all files in the store and compare them with the RECORD of their package
+    Verify {
+        /// Print all offending paths
+        #[clap(long, short)]
+        verbose: bool,
+    },
+    /// Remove the packages that no project used in the last days
+    Gc {
+        /// Keep the packages of projects that were installed in this many days
+        #[clap(long, default_value_t = 30)]
+        older_than: u64,
+        /// Only print what would be removed
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+/// A package version in the store, `<root>/<name>/<unique_version>/<tag>`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StoreEntry {
+    /// The normalized name
+    pub name: String,
+    /// The version or git revision
+    pub unique_version: String,
+    /// The compatibility tag, empty if the project used whichever tag was installed
+    pub tag: String,
+}
+
+/// The store entries a project used the last time it was installed
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct StoreUsage {
+    /// The project directory, informational only
+    project: PathBuf,
+    /// The store root, which `MONOTRAIL_ROOT` can change
+    store: PathBuf,
+    /// Unix timestamp
+    last_used: u64,
+    packages: Vec<StoreEntry>,
+}
+
+/// How much of the store is duplicated between packages
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct StoreStats {
+    /// Store entries
+    pub packages: usize,
+    /// Files with a hash in a RECORD
+    pub files: usize,
+    /// Their total size
+    pub size: u64,
+    /// Files whose content another file already has
+    pub duplicate_files: usize,
+    /// The size of those, which content-addressed storage would save
+    pub duplicate_size: u64,
+}
+
+/// `<cache>/store-usage`
+pub(crate) fn usage_dir() -> anyhow::Result<PathBuf> {
+    Ok(cache_dir()?.join("store-usage"))
+}
+
+fn now_secs(now: SystemTime) -> u64 {
+    now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Records that the project in `project_dir` uses these packages from `store`
+pub(crate) fn record_usage(
+    usage_dir: &Path,
+    store: &Path,
+    project_dir: &Path,
+    packages: &[InstalledPackage],
+    now: SystemTime,
+) -> anyhow::Result<()> {
+    let project = project_dir
+        .canonicalize()
+        .unwrap_or_else(|_| project_dir.to_path_buf());
+    let mut entries: Vec<StoreEntry> = packages
+        .iter()
+        .map(|package| StoreEntry {
+            name: package.name.clone(),
+            unique_version: package.unique_version.clone(),
+            tag: package.tag.clone(),
+        })
+        .collect();
+    entries.sort();
+    let usage = StoreUsage {
+        project: project.clone(),
+        store: store.to_path_buf(),
+        last_used: now_secs(now),
+        packages: entries,
+    };
+    fs::create_dir_all(usage_dir)?;
+    let key = cache_key(format!("{}\n{}", project.display(), store.display()));
+    // Write to a temp file and rename, so a concurrent gc never reads a partial file
+    let mut temp_file = tempfile::NamedTempFile::new_in(usage_dir)?;
+    serde_json::to_writer(&mut temp_file, &usage)?;
+    temp_file.persist(usage_dir.join(format!("{}.json", key)))?;
+    Ok(())
+}
+
+/// The usages of `store`, split into those within `max_age` and the stale ones with their path
+#[allow(clippy::type_complexity)]
+fn read_usages(
+    usage_dir: &Path,
+    store: &Path,
+    max_age: Duration,
+    now: SystemTime,
+) -> anyhow::Result<(Vec<StoreUsage>, Vec<(PathBuf, StoreUsage)>)> {
+    let entries = match fs::read_dir(usage_dir) {
+        Ok(entries) => entries.collect::<io::Result<Vec<_>>>()?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
+    let mut recent = Vec::new();
+    let mut stale = Vec::new();
+    for entry in entries {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        let usage: StoreUsage = match serde_json::from_str(&fs::read_to_string(&path)?) {
+            Ok(usage) => usage,
+            Err(err) => {
+                warn!("Ignoring invalid store usage {}: {}", path.display(), err);
+                continue;
+            }
+        };
+        if usage.store != store {
+            continue;
+        }
+        if now_secs(now).saturating_sub(usage.last_used) > max_age.as_secs() {
+            debug!("{} wasn't used recently", usage.project.display());
+            stale.push((path, usage));
+        } else {
+            recent.push(usage);
+        }
+    }
+    Ok((recent, stale))
+}
+
+/// Removes the store entries that projects used before, but none within `max_age`. Returns the
+/// removed entries and the freed bytes
+pub fn store_gc(
+    store: &Path,
+    usage_dir: &Path,
+    max_age: Duration,
+    now: SystemTime,
+    dry_run: bool,
+) -> anyhow::Result<(Vec<StoreEntry>, u64)> {
+    if !store.is_dir() {
+        return Ok((Vec::new(), 0));
+    }
+    // Don't remove packages that are being installed right now
+    let _lock = LockedDir::acquire(store)?;
+    let (recent, stale) = read_usages(usage_dir, store, max_age, now)?;
+    let referenced: BTreeSet<&StoreEntry> =
+        recent.iter().flat_map(|usage| &usage.packages).collect();
+    let outdated: BTreeSet<&StoreEntry> = stale
+        .iter()
+        .flat_map(|(_, usage)| &usage.packages)
+        .collect();
+    let mut removed = Vec::new();
+    let mut freed = 0;
+    for (name, unique_version, tag) in list_installed(store, None)? {
+        let matches = |entry: &&StoreEntry| {
+            entry.name == name
+                && entry.unique_version == unique_version
+                && (entry.tag.is_empty() || entry.tag == tag)
+        };
+        if referenced.iter().any(matches) {
+            continue;
+        }
+        if !outdated.iter().any(matches) {
+            debug!(
+                "Keeping {} {} {}, which has no usage record",
+                name, unique_version, tag
+            );
+            continue;
+        }
+        let dir = store.join(&name).join(&unique_version).join(&tag);
+        freed += crate::cache::dir_size(&dir);
+        if !dry_run {
+            fs::remove_dir_all(&dir)
+                .with_context(|| format!("Failed to remove {}", dir.display()))?;
+            // The version and the name dir if they are now empty
+            for parent in dir.ancestors().skip(1).take(2) {
+                if fs::remove_dir(parent).is_err() {
+                    break;
+                }
+            }
+        }
+        removed.push(StoreEntry {
+            name,
+            unique_version,
+            tag,
+        });
+    }
+    // Only now, otherwise we'd forget about the entries if removing them failed
+    if !dry_run {
+        for (path, _) in stale {
+            fs::remove_file(&path)?;
+        }
+    }
+    removed.sort();
+    Ok((removed, freed))
+}
+
+/// Counts the files that have the same content as another file in the store, using the hashes
+/// from the RECORD files
+pub fn store_stats(store: &Path) -> anyhow::Result<StoreStats> {
+    let installed = list_installed(store, None)?;
+    let mut stats = StoreStats {
+        packages: installed.len(),
+        ..StoreStats::default()
+    };
+    let mut seen: HashMap<String, u64> = HashMap::new();
+    for (name, unique_version, tag) in installed {
+        let package_dir = store.join(&name).join(&unique_version).join(&tag);
+        for entry in walkdir::WalkDir::new(&package_dir)
+            .into_iter()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_name() == "RECORD")
+        {
+            let record = fs::read_to_string(entry.path())?;
+            let record = read_record_file(&mut record.as_bytes())
+                .with_context(|| format!("Invalid {}", entry.path().display()))?;
+            for record_entry in record {
+                let (Some(hash), Some(size)) = (record_entry.hash, record_entry.size) else {
+                    continue;
+                };
+                let size = size as u64;
+                stats.files += 1;
+                stats.size += size;
+                if seen.insert(hash, size).is_some() {
+                    stats.duplicate_files += 1;
+                    stats.duplicate_size += size;
+                }
+            }
+        }
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::install::InstalledPackage;
+    use crate::store::{record_usage, store_gc, store_stats, StoreEntry};
+    use crate::test_utils::install_files;
+    use fs_err as fs;
+    use std::time::{Duration, SystemTime};
+    use tempfile::TempDir;
+
+    fn package(name: &str, version: &str, tag: &str) -> InstalledPackage {
+        InstalledPackage {
+            name: name.to_string(),
+            python_version: version.to_string(),
+            unique_version: version.to_string(),
+            tag: tag.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_store_gc() {
+        let temp_dir = TempDir::new().unwrap();
+        let store = temp_dir.path().join("store");
+        let usage_dir = temp_dir.path().join("usage");
+        install_files(
+            &store.join("tqdm/4.65.0/py3-none-any/lib/python/site-packages"),
+            "tqdm-4.65.0.dist-info",
+            &[("tqdm/__init__.py", "a")],
+        );
+        install_files(
+            &store.join("tqdm/4.64.0/py3-none-any/lib/python/site-packages"),
+            "tqdm-4.64.0.dist-info",
+            &[("tqdm/__init__.py", "b")],
+        );
+        install_files(
+            &store.join("colorama/0.4.6/py3-none-any/lib/python/site-packages"),
+            "colorama-0.4.6.dist-info",
+            &[("colorama/__init__.py", "a")],
+        );
+        install_files(
+            &store.join("old/1.0/py3-none-any/lib/python/site-packages"),
+            "old-1.0.dist-info",
+            &[("old/__init__.py", "c")],
+        );
+        // e.g. installed by an older version
+        install_files(
+            &store.join("unrecorded/1.0/py3-none-any/lib/python/site-packages"),
+            "unrecorded-1.0.dist-info",
+            &[("unrecorded/__init__.py", "d")],
+        );
+
+        let day = Duration::from_secs(24 * 60 * 60);
+        let now = SystemTime::now();
+        let project_a = temp_dir.path().join("a");
+        let project_b = temp_dir.path().join("b");
+        let project_old = temp_dir.path().join("old");
+        record_usage(
+            &usage_dir,
+            &store,
+            &project_a,
+            &[
+                package("tqdm", "4.65.0", "py3-none-any"),
+                package("colorama", "0.4.6", ""),
+            ],
+            now - day,
+        )
+        .unwrap();
+        record_usage(
+            &usage_dir,
+            &store,
+            &project_b,
+            &[package("tqdm", "4.64.0", "py3-none-any")],
+            now - 2 * day,
+        )
+        .unwrap();
+        record_usage(
+            &usage_dir,
+            &store,
+            &project_old,
+            &[package("old", "1.0", "py3-none-any")],
+            now - 40 * day,
+        )
+        .unwrap();
+        // Another store isn't our business
+        record_usage(
+            &usage_dir,
+            &temp_dir.path().join("other"),
+            &project_a,
+            &[package("old", "1.0", "py3-none-any")],
+            now,
+        )
+        .unwrap();
+
+        let stats = store_stats(&store).unwrap();
+        assert_eq!(
+            (stats.packages, stats.files, stats.duplicate_files),
+            (5, 5, 1)
+        );
+
+        let (removed, _) = store_gc(&store, &usage_dir, 30 * day, now, true).unwrap();
+        let names: Vec<&str> = removed.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["old"]);
+        assert!(store.join("old").is_dir());
+        assert_eq!(fs::read_dir(&usage_dir).unwrap().count(), 4);
+
+        let (removed, freed) = store_gc(&store, &usage_dir, day + day / 2, now, false).unwrap();
+        assert_eq!(
+            removed,
+            [
+                StoreEntry {
+                    name: "old".to_string(),
+                    unique_version: "1.0".to_string(),
+                    tag: "py3-none-any".to_string()
+                },
+                StoreEntry {
+                    name: "tqdm".to_string(),
+                    unique_version: "4.64.0".to_string(),
+                    tag: "py3-none-any".to_string()
+                }
+            ]
+        );
+        assert!(freed > 0);
+        assert!(!store.join("old").exists());
+        assert!(!store.join("tqdm").join("4.64.0").exists());
+        assert!(store.join("tqdm").join("4.65.0").is_dir());
+        assert!(store.join("colorama").join("0.4.6").is_dir());
+        assert!(store.join("unrecorded").join("1.0").is_dir());
+        // The usages of b and old are gone
+        assert_eq!(fs::read_dir(&usage_dir).unwrap().count(), 2);
+    }
+}
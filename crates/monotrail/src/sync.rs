@@ -0,0 +1,280 @@
+//! `monotrail sync`: Makes a venv match the lockfile by installing only the packages that are
+//! missing or have a different version, instead of reinstalling everything. With `--exact`, the
+//! packages that aren't in the lockfile are removed too.
+//!
+//! What is installed comes from the `.dist-info` directories, with the git revisions from the
+//! environment manifest (`monotrail-environment.json`), since the version of a package installed
+//! from git doesn't tell which commit it is.
+
+use crate::environment::Environment;
+use crate::pep621::read_pep621_project;
+use crate::poetry_integration::poetry_toml::PoetryPyprojectToml;
+use crate::spec::{same_version, RequestedSpec};
+use crate::workspace::Workspace;
+use anyhow::Context;
+use fs_err as fs;
+use install_wheel_rs::{installed_dist_infos, normalize_name, remove_distribution};
+use serde_json::json;
+use std::fmt::Write;
+use std::path::Path;
+
+/// The tools of the venv itself, which `--exact` keeps
+const VENV_TOOLS: [&str; 3] = ["pip", "setuptools", "wheel"];
+
+/// What `sync` changes
+#[derive(Debug, Default, Clone)]
+pub struct SyncPlan {
+    /// Not installed yet
+    pub add: Vec<RequestedSpec>,
+    /// Installed in another version, as (spec, installed version, `.dist-info`)
+    pub replace: Vec<(RequestedSpec, String, String)>,
+    /// Not in the lockfile, as (name, version, `.dist-info`), only with `--exact`
+    pub remove: Vec<(String, String, String)>,
+    /// Already installed in the locked version
+    pub unchanged: usize,
+}
+
+impl SyncPlan {
+    /// Whether there's nothing to do
+    pub fn is_empty(&self) -> bool {
+        self.add.is_empty() && self.replace.is_empty() && self.remove.is_empty()
+    }
+
+    /// The specs to install
+    pub fn to_install(&self) -> Vec<RequestedSpec> {
+        self.add
+            .iter()
+            .chain(self.replace.iter().map(|(spec, ..)| spec))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Diffs the locked specs against what is installed in `site_packages`. With `exact`, the
+/// packages that aren't locked are removed, except the tools of the venv and the given root
+/// projects
+pub(crate) fn plan_sync(
+    site_packages: &Path,
+    environment: Option<&Environment>,
+    specs: &[RequestedSpec],
+    exact: Option<&[String]>,
+) -> anyhow::Result<SyncPlan> {
+    let mut plan = SyncPlan::default();
+    let installed = installed_dist_infos(site_packages)?;
+    for spec in specs {
+        let name = spec.normalized_name();
+        let Some((_, version, dist_info)) = installed.iter().find(|(other, ..)| *other == name)
+        else {
+            plan.add.push(spec.clone());
+            continue;
+        };
+        // The manifest knows the git revision
+        let installed_version = environment
+            .and_then(|environment| {
+                environment
+                    .packages
+                    .iter()
+                    .find(|package| package.dist_info == *dist_info)
+            })
+            .map(|package| package.unique_version.clone())
+            .unwrap_or_else(|| version.clone());
+        match spec.get_unique_version() {
+            Some(locked) if !same_version(&installed_version, &locked) => {
+                plan.replace
+                    .push((spec.clone(), installed_version, dist_info.clone()));
+            }
+            _ => plan.unchanged += 1,
+        }
+    }
+    if let Some(root_projects) = exact {
+        for (name, version, dist_info) in installed {
+            if VENV_TOOLS.contains(&name.as_str())
+                || root_projects.contains(&name)
+                || specs.iter().any(|spec| spec.normalized_name() == name)
+            {
+                continue;
+            }
+            plan.remove.push((name, version, dist_info));
+        }
+    }
+    plan.add.sort_by_key(|spec| spec.normalized_name());
+    plan.replace
+        .sort_by_key(|(spec, ..)| spec.normalized_name());
+    Ok(plan)
+}
+
+/// The normalized names of the project in `project_dir` or of the members of its workspace,
+/// which aren't in their own lockfile but are usually installed editable into the venv
+pub(crate) fn root_projects(project_dir: &Path) -> anyhow::Result<Vec<String>> {
+    if let Some(workspace) = Workspace::find(project_dir)? {
+        return Ok(workspace
+            .members
+            .iter()
+            .map(|member| normalize_name(&member.project.name))
+            .collect());
+    }
+    let path = project_dir.join("pyproject.toml");
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    if let Some(project) = read_pep621_project(project_dir)? {
+        return Ok(vec![normalize_name(&project.name)]);
+    }
+    let pyproject_toml: PoetryPyprojectToml = toml::from_str(&fs::read_to_string(&path)?)
+        .with_context(|| format!("Invalid pyproject.toml at {}", path.display()))?;
+    Ok(pyproject_toml
+        .tool
+        .and_then(|tool| tool.poetry)
+        .map(|poetry| normalize_name(&poetry.name))
+        .into_iter()
+        .collect())
+}
+
+/// Removes the replaced and extraneous packages after the caller installed the new versions,
+/// which is up to the caller since it depends on whether we copy or link. Files that the new
+/// version installed too are kept, linking may have removed the old version already
+pub(crate) fn remove_outdated(site_packages: &Path, plan: &SyncPlan) -> anyhow::Result<()> {
+    let replaced = plan.replace.iter().map(|(_, _, dist_info)| dist_info);
+    let removed = plan.remove.iter().map(|(_, _, dist_info)| dist_info);
+    for dist_info in replaced.chain(removed) {
+        if site_packages.join(dist_info).is_dir() {
+            remove_distribution(site_packages, dist_info)?;
+        }
+    }
+    Ok(())
+}
+
+/// e.g. `+ attrs 23.1.0`, `~ tqdm 4.64.0 -> 4.65.0`, `- colorama 0.4.6`
+pub fn format_plan(plan: &SyncPlan) -> String {
+    let mut report = String::new();
+    for spec in &plan.add {
+        let version = spec.get_unique_version().unwrap_or_default();
+        writeln!(report, "+ {} {}", spec.name, version).unwrap();
+    }
+    for (spec, installed, _) in &plan.replace {
+        let version = spec.get_unique_version().unwrap_or_default();
+        writeln!(report, "~ {} {} -> {}", spec.name, installed, version).unwrap();
+    }
+    for (name, version, _) in &plan.remove {
+        writeln!(report, "- {} {}", name, version).unwrap();
+    }
+    report
+}
+
+/// `{"added": [...], "replaced": [...], "removed": [...], "unchanged": n}` for `--format json`
+pub fn plan_json(plan: &SyncPlan) -> serde_json::Value {
+    let added: Vec<_> = plan
+        .add
+        .iter()
+        .map(|spec| json!({"name": spec.name, "version": spec.get_unique_version()}))
+        .collect();
+    let replaced: Vec<_> = plan
+        .replace
+        .iter()
+        .map(|(spec, installed, _)| {
+            json!({"name": spec.name, "from": installed, "to": spec.get_unique_version()})
+        })
+        .collect();
+    let removed: Vec<_> = plan
+        .remove
+        .iter()
+        .map(|(name, version, _)| json!({"name": name, "version": version}))
+        .collect();
+    json!({
+        "added": added,
+        "replaced": replaced,
+        "removed": removed,
+        "unchanged": plan.unchanged,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::spec::RequestedSpec;
+    use crate::sync::{format_plan, plan_json, plan_sync, remove_outdated, root_projects};
+    use crate::test_utils::install_fake;
+    use fs_err as fs;
+    use indoc::indoc;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_plan_sync() {
+        let temp_dir = TempDir::new().unwrap();
+        let site_packages = temp_dir.path();
+        install_fake(site_packages, "tqdm", "4.64.0");
+        install_fake(site_packages, "colorama", "0.4.6");
+        install_fake(site_packages, "click", "8.1.3");
+        install_fake(site_packages, "pip", "23.1");
+        // The root project, installed editable
+        install_fake(site_packages, "my_app", "0.1.0");
+        let specs: Vec<RequestedSpec> = ["tqdm==4.65.0", "click==8.1.3", "attrs==23.1.0"]
+            .iter()
+            .map(|requested| RequestedSpec::from_requested(requested, &[]).unwrap())
+            .collect();
+
+        let plan = plan_sync(site_packages, None, &specs, None).unwrap();
+        assert_eq!(
+            format_plan(&plan),
+            indoc! {"
+                + attrs 23.1.0
+                ~ tqdm 4.64.0 -> 4.65.0
+            "}
+        );
+        assert_eq!(plan.unchanged, 1);
+
+        let root_projects = ["my-app".to_string()];
+        let plan = plan_sync(site_packages, None, &specs, Some(&root_projects)).unwrap();
+        assert_eq!(
+            format_plan(&plan),
+            indoc! {"
+                + attrs 23.1.0
+                ~ tqdm 4.64.0 -> 4.65.0
+                - colorama 0.4.6
+            "}
+        );
+        assert_eq!(
+            plan_json(&plan),
+            json!({
+                "added": [{"name": "attrs", "version": "23.1.0"}],
+                "replaced": [{"name": "tqdm", "from": "4.64.0", "to": "4.65.0"}],
+                "removed": [{"name": "colorama", "version": "0.4.6"}],
+                "unchanged": 1,
+            })
+        );
+        let names: Vec<String> = plan
+            .to_install()
+            .iter()
+            .map(|spec| spec.name.clone())
+            .collect();
+        assert_eq!(names, ["attrs", "tqdm"]);
+
+        // The new version is installed before the old one is removed
+        install_fake(site_packages, "attrs", "23.1.0");
+        install_fake(site_packages, "tqdm", "4.65.0");
+        remove_outdated(site_packages, &plan).unwrap();
+        assert!(!site_packages.join("tqdm-4.64.0.dist-info").exists());
+        assert!(site_packages.join("tqdm").join("__init__.py").is_file());
+        assert!(site_packages.join("tqdm-4.65.0.dist-info").is_dir());
+        assert!(!site_packages.join("colorama-0.4.6.dist-info").exists());
+        assert!(site_packages.join("click").is_dir());
+        assert!(site_packages.join("pip").is_dir());
+        assert!(site_packages.join("my_app").is_dir());
+    }
+
+    #[test]
+    fn test_root_projects() {
+        let project_dir = TempDir::new().unwrap();
+        assert!(root_projects(project_dir.path()).unwrap().is_empty());
+        fs::write(
+            project_dir.path().join("pyproject.toml"),
+            indoc! {r#"
+                [project]
+                name = "My.App"
+                version = "0.1.0"
+            "#},
+        )
+        .unwrap();
+        assert_eq!(root_projects(project_dir.path()).unwrap(), ["my-app"]);
+    }
+}
@@ -0,0 +1,63 @@
+//! Fixtures shared by the unit tests of multiple modules
+
+use crate::install::InstalledPackage;
+use data_encoding::BASE64URL_NOPAD;
+use fs_err as fs;
+use install_wheel_rs::integrity_hash;
+use pep508_rs::{MarkerEnvironment, StringVersion};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Writes `files` (relative to `site_packages`, e.g. `foo/__init__.py` or `../../../bin/foo`) and
+/// a RECORD with their hashes into `dist_info`, like installing a wheel would
+pub(crate) fn install_files(site_packages: &Path, dist_info: &str, files: &[(&str, &str)]) {
+    let mut record = String::new();
+    for (path, contents) in files {
+        let path_buf = site_packages.join(path);
+        fs::create_dir_all(path_buf.parent().unwrap()).unwrap();
+        fs::write(&path_buf, contents).unwrap();
+        let (name, digest) = integrity_hash(&mut contents.as_bytes()).unwrap();
+        record.push_str(&format!(
+            "{},{}={},{}\n",
+            path,
+            name,
+            BASE64URL_NOPAD.encode(&digest),
+            contents.len()
+        ));
+    }
+    fs::create_dir_all(site_packages.join(dist_info)).unwrap();
+    record.push_str(&format!("{}/RECORD,,\n", dist_info));
+    fs::write(site_packages.join(dist_info).join("RECORD"), record).unwrap();
+}
+
+/// An installed package that is only an empty `<name>/__init__.py`
+pub(crate) fn install_fake(site_packages: &Path, name: &str, version: &str) -> InstalledPackage {
+    install_files(
+        site_packages,
+        &format!("{}-{}.dist-info", name, version),
+        &[(&format!("{}/__init__.py", name), "")],
+    );
+    InstalledPackage {
+        name: name.to_string(),
+        python_version: version.to_string(),
+        unique_version: version.to_string(),
+        tag: "py3-none-any".to_string(),
+    }
+}
+
+/// Linux with python 3.8
+pub(crate) fn test_pep508_env() -> MarkerEnvironment {
+    MarkerEnvironment {
+        implementation_name: "cpython".to_string(),
+        implementation_version: StringVersion::from_str("3.8.10").unwrap(),
+        os_name: "posix".to_string(),
+        platform_machine: "x86_64".to_string(),
+        platform_python_implementation: "CPython".to_string(),
+        platform_release: "5.13.0-39-generic".to_string(),
+        platform_system: "Linux".to_string(),
+        platform_version: "#44~20.04.1-Ubuntu SMP Thu Mar 24 16:43:35 UTC 2022".to_string(),
+        python_full_version: StringVersion::from_str("3.8.10").unwrap(),
+        python_version: StringVersion::from_str("3.8").unwrap(),
+        sys_platform: "linux".to_string(),
+    }
+}
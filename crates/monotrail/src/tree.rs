@@ -0,0 +1,444 @@
+//! `monotrail tree`: The dependency graph of the locked packages, printed like `cargo tree`
+
+use crate::poetry_integration::poetry_lock::PoetryLock;
+use crate::spec::RequestedSpec;
+use anyhow::{bail, format_err};
+use install_wheel_rs::normalize_name;
+use pep508_rs::MarkerEnvironment;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::{BTreeMap, HashSet};
+
+/// A locked package with the dependencies that are active for this environment and its extras
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GraphNode {
+    /// The name as in the lock
+    pub name: String,
+    /// The locked version
+    pub version: String,
+    /// The extras selected on this package
+    pub extras: Vec<String>,
+    /// The dependencies that apply, sorted by name
+    pub dependencies: Vec<GraphEdge>,
+}
+
+/// A dependency of a package, as it was declared in the package's metadata
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct GraphEdge {
+    /// The normalized name of the dependency
+    pub name: String,
+    /// The version constraint, e.g. `>=3.5.2,<4` or `*`
+    pub version: String,
+    /// The markers under which the dependency is required
+    pub markers: Option<String>,
+}
+
+impl GraphEdge {
+    /// e.g. ` (>=2; python_version < "3.9")`, empty for an unconditional `*`
+    fn label(&self) -> String {
+        let parts: Vec<&str> = [Some(self.version.as_str()).filter(|v| *v != "*")]
+            .into_iter()
+            .chain([self.markers.as_deref()])
+            .flatten()
+            .collect();
+        if parts.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", parts.join("; "))
+        }
+    }
+}
+
+/// The packages of a lock that get installed, with the edges between them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DependencyGraph {
+    /// By normalized name
+    pub packages: BTreeMap<String, GraphNode>,
+}
+
+impl DependencyGraph {
+    /// Builds the graph of `specs`, the packages selected from `lock`. The edges come from the
+    /// lock and are evaluated for `pep508_env` and the extras of each spec
+    pub fn from_lock(
+        lock: &PoetryLock,
+        specs: &[RequestedSpec],
+        pep508_env: &MarkerEnvironment,
+    ) -> anyhow::Result<Self> {
+        let locked: BTreeMap<String, _> = lock
+            .package
+            .iter()
+            .map(|package| (normalize_name(&package.name), package))
+            .collect();
+        let selected: HashSet<String> = specs.iter().map(|spec| spec.normalized_name()).collect();
+        let mut packages = BTreeMap::new();
+        for spec in specs {
+            let name = spec.normalized_name();
+            let package = locked
+                .get(&name)
+                .ok_or_else(|| format_err!("{} is missing in the lock", spec.name))?;
+            let self_extras: HashSet<String> = spec.extras.iter().cloned().collect();
+            let mut dependencies = Vec::new();
+            for (dependency, declaration) in package.dependencies.iter().flatten() {
+                let dependency = normalize_name(dependency);
+                // e.g. setuptools, which isn't in the lock
+                if !selected.contains(&dependency) {
+                    continue;
+                }
+                let option = declaration
+                    .matching_option(pep508_env, &self_extras)
+                    .map_err(|err| format_err!("Invalid lock entry for {}: {}", name, err))?;
+                if let Some(option) = option {
+                    dependencies.push(GraphEdge {
+                        name: dependency,
+                        version: option.version,
+                        markers: option.markers,
+                    });
+                }
+            }
+            dependencies.sort_by(|a, b| a.name.cmp(&b.name));
+            let mut extras = spec.extras.clone();
+            extras.sort();
+            packages.insert(
+                name,
+                GraphNode {
+                    name: package.name.clone(),
+                    version: package.version.clone(),
+                    extras,
+                    dependencies,
+                },
+            );
+        }
+        Ok(Self { packages })
+    }
+
+    /// The packages that no other package depends on, i.e. what the project requires directly
+    pub fn roots(&self) -> Vec<&str> {
+        let required: HashSet<&str> = self
+            .packages
+            .values()
+            .flat_map(|node| &node.dependencies)
+            .map(|edge| edge.name.as_str())
+            .collect();
+        self.packages
+            .keys()
+            .map(String::as_str)
+            .filter(|name| !required.contains(name))
+            .collect()
+    }
+
+    /// The packages that depend on `name` with the edge to it, sorted by name
+    pub fn dependents(&self, name: &str) -> Vec<(&str, &GraphEdge)> {
+        let name = normalize_name(name);
+        self.packages
+            .iter()
+            .filter_map(|(dependent, node)| {
+                let edge = node.dependencies.iter().find(|edge| edge.name == name)?;
+                Some((dependent.as_str(), edge))
+            })
+            .collect()
+    }
+
+    /// Prints the tree below the roots. Packages that were already printed are marked with
+    /// `(*)` instead of repeating their dependencies, a dependency back onto a package further up
+    /// in the same branch is marked with `(cycle)`
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let mut seen = HashSet::new();
+        for root in self.roots() {
+            self.render_node(root, &mut seen, &mut out, false);
+        }
+        // Packages in a cycle that nothing outside of the cycle depends on aren't below any root
+        for name in self.packages.keys() {
+            if !seen.contains(name) {
+                self.render_node(name, &mut seen, &mut out, false);
+            }
+        }
+        out
+    }
+
+    /// Prints the packages that depend on `name` up to the roots, to find out why it's installed
+    pub fn render_inverted(&self, name: &str) -> anyhow::Result<String> {
+        let normalized = normalize_name(name);
+        if !self.packages.contains_key(&normalized) {
+            bail!("{} is not installed in this environment", name);
+        }
+        let mut out = String::new();
+        self.render_node(&normalized, &mut HashSet::new(), &mut out, true);
+        Ok(out)
+    }
+
+    /// `{"roots": [...], "packages": {...}}` with the nodes by normalized name, for
+    /// `--format json`
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({ "roots": self.roots(), "packages": self.packages })
+    }
+
+    /// `{"name": ..., "dependents": [...]}` with the packages that directly depend on `name`, for
+    /// `--format json`
+    pub fn inverted_json(&self, name: &str) -> anyhow::Result<serde_json::Value> {
+        let normalized = normalize_name(name);
+        if !self.packages.contains_key(&normalized) {
+            bail!("{} is not installed in this environment", name);
+        }
+        let dependents: Vec<_> = self
+            .dependents(&normalized)
+            .into_iter()
+            .map(|(dependent, edge)| {
+                json!({
+                    "name": dependent,
+                    "version": self.packages[dependent].version,
+                    "requires": edge.version,
+                    "markers": edge.markers,
+                })
+            })
+            .collect();
+        Ok(json!({ "name": normalized, "dependents": dependents }))
+    }
+
+    fn render_node(
+        &self,
+        name: &str,
+        seen: &mut HashSet<String>,
+        out: &mut String,
+        inverted: bool,
+    ) {
+        let marker = self.marker(name, &[], seen, inverted);
+        self.render_line(name, "", marker, out);
+        if !seen.insert(name.to_string()) {
+            return;
+        }
+        let mut ancestors = vec![name.to_string()];
+        self.render_children(name, "", &mut ancestors, seen, out, inverted);
+    }
+
+    /// `(cycle)` for a package that is already in the branch above, `(*)` for one whose
+    /// children were already printed elsewhere
+    fn marker(
+        &self,
+        name: &str,
+        ancestors: &[String],
+        seen: &HashSet<String>,
+        inverted: bool,
+    ) -> &'static str {
+        let has_children = if inverted {
+            !self.dependents(name).is_empty()
+        } else {
+            !self.packages[name].dependencies.is_empty()
+        };
+        if ancestors.iter().any(|ancestor| ancestor == name) {
+            " (cycle)"
+        } else if seen.contains(name) && has_children {
+            " (*)"
+        } else {
+            ""
+        }
+    }
+
+    fn render_line(&self, name: &str, label: &str, marker: &str, out: &mut String) {
+        let node = &self.packages[name];
+        let extras = if node.extras.is_empty() {
+            String::new()
+        } else {
+            format!("[{}]", node.extras.join(","))
+        };
+        out.push_str(&format!(
+            "{}{} {}{}{}\n",
+            node.name, extras, node.version, label, marker
+        ));
+    }
+
+    fn render_children(
+        &self,
+        name: &str,
+        prefix: &str,
+        ancestors: &mut Vec<String>,
+        seen: &mut HashSet<String>,
+        out: &mut String,
+        inverted: bool,
+    ) {
+        let children: Vec<(&str, &GraphEdge)> = if inverted {
+            self.dependents(name)
+        } else {
+            self.packages[name]
+                .dependencies
+                .iter()
+                .map(|edge| (edge.name.as_str(), edge))
+                .collect()
+        };
+        for (index, (child, edge)) in children.iter().enumerate() {
+            let last = index + 1 == children.len();
+            out.push_str(prefix);
+            out.push_str(if last { "└── " } else { "├── " });
+            let marker = self.marker(child, ancestors, seen, inverted);
+            self.render_line(child, &edge.label(), marker, out);
+            if seen.insert(child.to_string()) {
+                let prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+                ancestors.push(child.to_string());
+                self.render_children(child, &prefix, ancestors, seen, out, inverted);
+                ancestors.pop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::poetry_integration::read_dependencies::read_toml_files;
+    use crate::read_poetry_specs;
+    use crate::test_utils::test_pep508_env;
+    use crate::tree::{DependencyGraph, GraphEdge, GraphNode};
+    use indoc::indoc;
+    use serde_json::json;
+    use std::path::Path;
+
+    #[test]
+    fn test_tree() {
+        let (poetry_section, poetry_lock, _lockfile) =
+            read_toml_files(Path::new("../../test-data/poetry-1.3-django")).unwrap();
+        let specs = read_poetry_specs(
+            &poetry_section,
+            poetry_lock.clone(),
+            Path::new("../../test-data/poetry-1.3-django"),
+            false,
+            &[],
+            &test_pep508_env(),
+        )
+        .unwrap();
+        let graph = DependencyGraph::from_lock(&poetry_lock, &specs, &test_pep508_env()).unwrap();
+        assert_eq!(graph.roots(), ["django"]);
+        assert_eq!(
+            graph.render(),
+            indoc! {r#"
+                django 4.1.2
+                ├── asgiref 3.5.2 (>=3.5.2,<4)
+                ├── backports-zoneinfo 0.2.1 (python_version < "3.9")
+                └── sqlparse 0.4.3 (>=0.2.2)
+            "#}
+        );
+        assert_eq!(
+            graph.render_inverted("ASGIref").unwrap(),
+            indoc! {r#"
+                asgiref 3.5.2
+                └── django 4.1.2 (>=3.5.2,<4)
+            "#}
+        );
+        assert_eq!(
+            graph.inverted_json("ASGIref").unwrap(),
+            json!({
+                "name": "asgiref",
+                "dependents": [
+                    {"name": "django", "version": "4.1.2", "requires": ">=3.5.2,<4", "markers": null}
+                ]
+            })
+        );
+        let json = graph.to_json();
+        assert_eq!(json["roots"], json!(["django"]));
+        assert_eq!(json["packages"]["sqlparse"]["version"], "0.4.3");
+        assert_eq!(
+            graph.render_inverted("tzdata").unwrap_err().to_string(),
+            "tzdata is not installed in this environment"
+        );
+    }
+
+    #[test]
+    fn test_tree_duplicates() {
+        let node = |name: &str, dependencies: &[&str]| {
+            let node = GraphNode {
+                name: name.to_string(),
+                version: "1.0".to_string(),
+                extras: Vec::new(),
+                dependencies: dependencies
+                    .iter()
+                    .map(|dependency| GraphEdge {
+                        name: dependency.to_string(),
+                        version: "*".to_string(),
+                        markers: None,
+                    })
+                    .collect(),
+            };
+            (name.to_string(), node)
+        };
+        let graph = DependencyGraph {
+            packages: [
+                node("a", &["c"]),
+                node("b", &["c"]),
+                node("c", &["d"]),
+                node("d", &[]),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        assert_eq!(
+            graph.render(),
+            indoc! {"
+                a 1.0
+                └── c 1.0
+                    └── d 1.0
+                b 1.0
+                └── c 1.0 (*)
+            "}
+        );
+        assert_eq!(
+            graph.render_inverted("d").unwrap(),
+            indoc! {"
+                d 1.0
+                └── c 1.0
+                    ├── a 1.0
+                    └── b 1.0
+            "}
+        );
+    }
+
+    #[test]
+    fn test_tree_cycle() {
+        let node = |name: &str, dependencies: &[&str]| {
+            let node = GraphNode {
+                name: name.to_string(),
+                version: "1.0".to_string(),
+                extras: Vec::new(),
+                dependencies: dependencies
+                    .iter()
+                    .map(|dependency| GraphEdge {
+                        name: dependency.to_string(),
+                        version: "*".to_string(),
+                        markers: None,
+                    })
+                    .collect(),
+            };
+            (name.to_string(), node)
+        };
+        let graph = DependencyGraph {
+            packages: [
+                node("a", &["b"]),
+                node("b", &["c"]),
+                node("c", &["b"]),
+                node("x", &["y"]),
+                node("y", &["x"]),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        assert_eq!(graph.roots(), ["a"]);
+        assert_eq!(
+            graph.render(),
+            indoc! {"
+                a 1.0
+                └── b 1.0
+                    └── c 1.0
+                        └── b 1.0 (cycle)
+                x 1.0
+                └── y 1.0
+                    └── x 1.0 (cycle)
+            "}
+        );
+        assert_eq!(
+            graph.render_inverted("c").unwrap(),
+            indoc! {"
+                c 1.0
+                └── b 1.0
+                    ├── a 1.0
+                    └── c 1.0 (cycle)
+            "}
+        );
+    }
+}
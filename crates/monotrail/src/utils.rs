@@ -1,11 +1,13 @@
 use crate::cli::{run_cli, Cli};
+use anyhow::bail;
 use fs_err as fs;
 use fs_err::DirEntry;
 use install_wheel_rs::Error;
 #[cfg(test)]
 use mockito::{Mock, ServerGuard};
-use std::io;
 use std::path::{Path, PathBuf};
+use std::{env, io};
+use tracing::warn;
 
 /// Return all subdirs in a directory
 pub fn get_dir_content(dir: &Path) -> io::Result<Vec<DirEntry>> {
@@ -16,8 +18,29 @@ pub fn get_dir_content(dir: &Path) -> io::Result<Vec<DirEntry>> {
         .collect())
 }
 
-/// `~/.cache/monotrail`
+/// `~/.cache/monotrail`, or `MONOTRAIL_CACHE_DIR` if set, e.g. for a cache shared between the
+/// users of a CI runner. With `MONOTRAIL_CACHE_PER_USER=1` each user gets their own subdirectory
 pub(crate) fn cache_dir() -> Result<PathBuf, Error> {
+    let per_user = env::var_os(format!(
+        "{}_CACHE_PER_USER",
+        env!("CARGO_PKG_NAME").to_uppercase()
+    ))
+    .is_some_and(|value| !value.is_empty() && value != "0");
+    if per_user {
+        user_cache_dir()
+    } else {
+        shared_cache_dir()
+    }
+}
+
+/// The cache dir without per-user segmentation
+fn shared_cache_dir() -> Result<PathBuf, Error> {
+    if let Some(cache_dir) = env::var_os(format!(
+        "{}_CACHE_DIR",
+        env!("CARGO_PKG_NAME").to_uppercase()
+    )) {
+        return Ok(PathBuf::from(cache_dir));
+    }
     Ok(dirs::cache_dir()
         .ok_or_else(|| {
             Error::IO(io::Error::new(
@@ -28,6 +51,136 @@ pub(crate) fn cache_dir() -> Result<PathBuf, Error> {
         .join(env!("CARGO_PKG_NAME")))
 }
 
+/// The current user's segment of the shared cache
+fn user_cache_dir() -> Result<PathBuf, Error> {
+    let private = dirs::cache_dir().map(|dir| dir.join(env!("CARGO_PKG_NAME")));
+    Ok(user_cache_dir_in(&shared_cache_dir()?, private.as_deref()))
+}
+
+/// `<shared>/users/<user>`, where `users` is sticky and world-writable like `/tmp`, so every user
+/// can add their own segment no matter who came first. If we can't create `users`, or another
+/// user created it without that mode (e.g. an older monotrail), we use the user's own cache dir
+/// outside the shared one instead
+fn user_cache_dir_in(shared: &Path, private: Option<&Path>) -> PathBuf {
+    let users = shared.join("users");
+    match private {
+        Some(private) if private != shared && !prepare_users_dir(&users) => private.to_path_buf(),
+        _ => users.join(current_user()),
+    }
+}
+
+/// Creates `users` with mode 1777 or fixes the mode if we own it. Returns false if other users
+/// can't create their segment in it
+#[cfg(unix)]
+fn prepare_users_dir(users: &Path) -> bool {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    // Everybody can create entries, but only remove their own
+    const STICKY_WORLD_WRITABLE: u32 = 0o1777;
+
+    if !users.is_dir() && fs::create_dir_all(users).is_err() {
+        return false;
+    }
+    let Ok(metadata) = fs::metadata(users) else {
+        return false;
+    };
+    if metadata.mode() & STICKY_WORLD_WRITABLE == STICKY_WORLD_WRITABLE {
+        return true;
+    }
+    if metadata.uid() != current_uid() {
+        return false;
+    }
+    // Explicitly, since the mode at creation is subject to the umask
+    fs::set_permissions(
+        users,
+        std::fs::Permissions::from_mode(STICKY_WORLD_WRITABLE),
+    )
+    .is_ok()
+}
+
+#[cfg(not(unix))]
+fn prepare_users_dir(users: &Path) -> bool {
+    fs::create_dir_all(users).is_ok()
+}
+
+#[cfg(unix)]
+fn current_uid() -> u32 {
+    // SAFETY: getuid has no preconditions and always succeeds
+    unsafe { libc::getuid() }
+}
+
+#[cfg(unix)]
+fn current_user() -> String {
+    format!("uid-{}", current_uid())
+}
+
+#[cfg(not(unix))]
+fn current_user() -> String {
+    env::var("USERNAME").unwrap_or_else(|_| "default".to_string())
+}
+
+/// If `path` exists and belongs to another user, returns the uid of that user. Always `None` on
+/// windows
+pub(crate) fn foreign_owner(path: &Path) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let uid = fs::metadata(path).ok()?.uid();
+        (uid != current_uid()).then_some(uid)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        None
+    }
+}
+
+/// Whether we can create files in `dir`. A check must not leave anything behind, so for a missing
+/// `dir` we check whether we can create it in the closest existing parent
+pub(crate) fn is_writable_dir(dir: &Path) -> bool {
+    let Some(existing) = dir.ancestors().find(|ancestor| ancestor.is_dir()) else {
+        return false;
+    };
+    tempfile::tempfile_in(existing).is_ok()
+}
+
+/// The directories that may contain a cached artifact: The cache dir and, if it's a shared cache,
+/// the per-user directory we fall back to when another user owns the shared entry
+pub(crate) fn artifact_dirs(name: &str, version: &str) -> Result<Vec<PathBuf>, Error> {
+    let mut dirs = vec![cache_dir()?.join("artifacts").join(name).join(version)];
+    let user_dir = user_cache_dir()?.join("artifacts").join(name).join(version);
+    if dirs[0] != user_dir {
+        dirs.push(user_dir);
+    }
+    Ok(dirs)
+}
+
+/// The first of [artifact_dirs] we can write to, created if missing, so we never fail halfway
+/// through an install because another user created the shared entry
+pub(crate) fn writable_artifact_dir(name: &str, version: &str) -> anyhow::Result<PathBuf> {
+    let dirs = artifact_dirs(name, version)?;
+    for dir in &dirs {
+        if is_writable_dir(dir) && fs::create_dir_all(dir).is_ok() {
+            return Ok(dir.clone());
+        }
+        if let Some(uid) = foreign_owner(dir) {
+            warn!(
+                "{} is owned by uid {} and not writable for us, using the per-user cache",
+                dir.display(),
+                uid
+            );
+        }
+    }
+    bail!(
+        "None of the cache directories for {} {} is writable: {}",
+        name,
+        version,
+        dirs.iter()
+            .map(|dir| dir.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
 /// `~/.local/share/monotrail`
 pub(crate) fn data_local_dir() -> Result<PathBuf, Error> {
     Ok(dirs::data_local_dir()
@@ -68,3 +221,43 @@ pub fn zstd_json_mock(url: &str, fixture: impl Into<PathBuf>) -> (ServerGuard, M
         .create();
     (server, mock)
 }
+
+#[cfg(all(test, unix))]
+mod test {
+    use crate::utils::{current_user, user_cache_dir_in};
+    use fs_err as fs;
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_user_cache_dir_shared_users() {
+        let shared = TempDir::new().unwrap();
+        let private = TempDir::new().unwrap();
+        let user_dir = user_cache_dir_in(shared.path(), Some(private.path()));
+        assert_eq!(user_dir, shared.path().join("users").join(current_user()));
+        let mode = fs::metadata(shared.path().join("users")).unwrap().mode();
+        assert_eq!(mode & 0o7777, 0o1777);
+    }
+
+    /// Another user created `users` with their umask, we can't create our segment in there
+    #[test]
+    fn test_user_cache_dir_foreign_users() {
+        let shared = TempDir::new().unwrap();
+        let private = TempDir::new().unwrap();
+        let users = shared.path().join("users");
+        fs::create_dir(&users).unwrap();
+        fs::set_permissions(&users, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let users_c = CString::new(users.as_os_str().as_bytes()).unwrap();
+        // SAFETY: The path is a valid nul-terminated string
+        if unsafe { libc::chown(users_c.as_ptr(), 65534, 65534) } != 0 {
+            // Only root can give a directory to another user
+            return;
+        }
+        let user_dir = user_cache_dir_in(shared.path(), Some(private.path()));
+        assert_eq!(user_dir, private.path());
+        // We didn't touch the other user's directory
+        assert_eq!(fs::metadata(&users).unwrap().mode() & 0o7777, 0o755);
+    }
+}
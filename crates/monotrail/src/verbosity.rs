@@ -0,0 +1,175 @@
+//! `-v`/`-vv` and `--debug <subsystem>`, so you can get debug output for only the part you're
+//! interested in instead of everything at once
+
+use clap::ArgAction;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::filter::Targets;
+
+/// The parts of monotrail that can be made verbose on their own
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Subsystem {
+    /// Reading requirements and lockfiles and picking the distributions
+    Resolver,
+    /// Downloading distributions and python builds, building source distributions
+    Downloader,
+    /// Unpacking wheels into a venv or the monotrail cache
+    Installer,
+    /// Setting up the environment and launching python or commands
+    Runtime,
+}
+
+impl Subsystem {
+    const ALL: [Subsystem; 4] = [
+        Subsystem::Resolver,
+        Subsystem::Downloader,
+        Subsystem::Installer,
+        Subsystem::Runtime,
+    ];
+
+    /// The tracing targets (module path prefixes) that belong to this subsystem
+    fn targets(&self) -> &'static [&'static str] {
+        match self {
+            Subsystem::Resolver => &[
+                "monotrail::compile",
+                "monotrail::config_settings",
+                "monotrail::export",
+                "monotrail::lint",
+                "monotrail::lockfile",
+                "monotrail::markers",
+                "monotrail::outdated",
+                "monotrail::path_dependency",
+                "monotrail::pep621",
+                "monotrail::poetry_integration",
+                "monotrail::project_lock",
+                "monotrail::pylock",
+                "monotrail::resolver",
+                "monotrail::spec",
+                "monotrail::tree",
+                "monotrail::workspace",
+                "monotrail_utils::requirements_txt",
+            ],
+            Subsystem::Downloader => &[
+                "monotrail::audit",
+                "monotrail::build_cache",
+                "monotrail::build_frontend",
+                "monotrail::download",
+                "monotrail::http_cache",
+                "monotrail::index",
+                "monotrail::lazy_wheel",
+                "monotrail::package_index",
+                "monotrail::source_distribution",
+                "monotrail_utils::auth",
+                "monotrail_utils::network",
+                "monotrail_utils::standalone_python",
+                "monotrail_utils::transport",
+            ],
+            Subsystem::Installer => &[
+                "install_wheel_rs",
+                "monotrail::cache",
+                "monotrail::environment",
+                "monotrail::import_check",
+                "monotrail::install",
+                "monotrail::link_farm",
+                "monotrail::snapshot",
+                "monotrail::store",
+                "monotrail::sync",
+                "monotrail::verify_installation",
+            ],
+            Subsystem::Runtime => &[
+                "monotrail::cli",
+                "monotrail::init",
+                "monotrail::inject_and_run",
+                "monotrail::inline_script",
+                "monotrail::interpreter",
+                "monotrail::monotrail",
+                "monotrail::ppipx",
+                "monotrail::project_edit",
+                "monotrail::site_injection",
+                "monotrail::venv_parser",
+                "monotrail_utils::parse_cpython_args",
+            ],
+        }
+    }
+}
+
+/// Global verbosity options, which go before the subcommand, e.g. `monotrail -v install`.
+/// `RUST_LOG` overrides them
+#[derive(clap::Args, Debug, Default, Clone)]
+pub struct Verbosity {
+    /// Debug output for all subsystems, `-vv` also shows trace output and debug output of
+    /// dependencies
+    #[clap(short, long, action = ArgAction::Count)]
+    pub verbose: u8,
+    /// Debug output for only this subsystem. Can be passed multiple times
+    #[clap(long, value_enum)]
+    pub debug: Vec<Subsystem>,
+}
+
+impl Verbosity {
+    /// The filter for the tracing subscriber
+    pub fn targets(&self) -> Targets {
+        let (default, verbose) = match self.verbose {
+            0 => (LevelFilter::INFO, LevelFilter::INFO),
+            1 => (LevelFilter::INFO, LevelFilter::DEBUG),
+            _ => (LevelFilter::DEBUG, LevelFilter::TRACE),
+        };
+        // Modules that aren't part of a subsystem still get the `-v` level through their crate
+        let mut targets = Targets::new().with_default(default).with_targets(
+            ["monotrail", "monotrail_utils", "install_wheel_rs"].map(|krate| (krate, verbose)),
+        );
+        for subsystem in Subsystem::ALL {
+            let level = if self.debug.contains(&subsystem) {
+                verbose.max(LevelFilter::DEBUG)
+            } else {
+                verbose
+            };
+            for target in subsystem.targets() {
+                targets = targets.with_target(*target, level);
+            }
+        }
+        targets
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::verbosity::{Subsystem, Verbosity};
+    use tracing::Level;
+
+    #[test]
+    fn test_targets() {
+        let targets = Verbosity::default().targets();
+        assert!(targets.would_enable("monotrail::install", &Level::INFO));
+        assert!(!targets.would_enable("monotrail::install", &Level::DEBUG));
+
+        let targets = Verbosity {
+            verbose: 0,
+            debug: vec![Subsystem::Installer],
+        }
+        .targets();
+        assert!(targets.would_enable("monotrail::install", &Level::DEBUG));
+        assert!(targets.would_enable("install_wheel_rs::wheel", &Level::DEBUG));
+        assert!(!targets.would_enable("monotrail::spec", &Level::DEBUG));
+        assert!(!targets.would_enable("ureq::unit", &Level::DEBUG));
+
+        let targets = Verbosity {
+            verbose: 1,
+            debug: vec![],
+        }
+        .targets();
+        assert!(targets.would_enable("monotrail::resolver", &Level::DEBUG));
+        assert!(targets.would_enable("monotrail::utils", &Level::DEBUG));
+        assert!(targets.would_enable("monotrail_utils::transport", &Level::DEBUG));
+        assert!(!targets.would_enable("monotrail::resolver", &Level::TRACE));
+        assert!(!targets.would_enable("ureq::unit", &Level::DEBUG));
+
+        let targets = Verbosity {
+            verbose: 2,
+            debug: vec![],
+        }
+        .targets();
+        assert!(targets.would_enable("monotrail::spec", &Level::TRACE));
+        assert!(targets.would_enable("ureq::unit", &Level::DEBUG));
+        assert!(!targets.would_enable("ureq::unit", &Level::TRACE));
+    }
+}
@@ -0,0 +1,387 @@
+//! Workspaces are multiple local packages in one repository that depend on each other. They
+//! are resolved together into a single lock and share one set of installed packages, while the
+//! members themselves are importable from their source directories like editable installs.
+//!
+//! ```toml
+//! [tool.monotrail.workspace]
+//! members = ["packages/*", "tools/cli"]
+//! ```
+//!
+//! Every member needs a PEP 621 `[project]` table, the workspace root is a member if it has
+//! one. Requirements on other members are not resolved, instead the members' source
+//! directories are put on `sys.path` through a .pth file. The lock is stored as
+//! `monotrail.lock` in the workspace root.
+
+use crate::pep621::{merge_dependencies, read_pep621_project, ProjectSection};
+use crate::poetry_integration::poetry_toml;
+use crate::poetry_integration::read_dependencies::requirement_to_poetry;
+use crate::utils::cache_dir;
+use anyhow::{bail, Context};
+use fs_err as fs;
+use install_wheel_rs::{cache_key, normalize_name, Script};
+use pep508_rs::MarkerEnvironment;
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// The lockfile of the workspace, in the poetry.lock format
+pub const WORKSPACE_LOCK: &str = "monotrail.lock";
+
+#[derive(Deserialize, Debug)]
+struct PyprojectToml {
+    tool: Option<ToolSection>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ToolSection {
+    monotrail: Option<MonotrailSection>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MonotrailSection {
+    workspace: Option<WorkspaceSection>,
+}
+
+/// `[tool.monotrail.workspace]`
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct WorkspaceSection {
+    members: Vec<String>,
+}
+
+/// A local package in the workspace
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct WorkspaceMember {
+    /// The directory with the pyproject.toml
+    pub dir: PathBuf,
+    /// The `[project]` table of the pyproject.toml
+    pub project: ProjectSection,
+}
+
+impl WorkspaceMember {
+    /// The directory that goes on `sys.path`, `src` for the src layout and otherwise the
+    /// project directory
+    pub fn import_dir(&self) -> PathBuf {
+        let src = self.dir.join("src");
+        if src.is_dir() {
+            src
+        } else {
+            self.dir.clone()
+        }
+    }
+}
+
+/// A root pyproject.toml with `[tool.monotrail.workspace]` and its members
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Workspace {
+    /// The directory with the pyproject.toml with `[tool.monotrail.workspace]`
+    pub root: PathBuf,
+    /// The root (if it has a `[project]` table) and the members in the order of the patterns
+    pub members: Vec<WorkspaceMember>,
+}
+
+/// `dir/*` matches all direct subdirectories with a pyproject.toml, everything else must be a
+/// path to a member
+fn expand_member_pattern(root: &Path, pattern: &str) -> anyhow::Result<Vec<PathBuf>> {
+    if let Some(parent) = pattern.strip_suffix("/*") {
+        let mut dirs = Vec::new();
+        for entry in fs::read_dir(root.join(parent))? {
+            let path = entry?.path();
+            if path.join("pyproject.toml").is_file() {
+                dirs.push(path);
+            }
+        }
+        dirs.sort();
+        Ok(dirs)
+    } else if pattern.contains(['*', '?', '[']) {
+        bail!(
+            "Unsupported workspace member pattern {}, only `dir/*` is supported",
+            pattern
+        )
+    } else {
+        Ok(vec![root.join(pattern)])
+    }
+}
+
+impl Workspace {
+    /// Reads the workspace if the pyproject.toml in `root` has `[tool.monotrail.workspace]`
+    pub fn from_root(root: &Path) -> anyhow::Result<Option<Self>> {
+        let path = root.join("pyproject.toml");
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let pyproject_toml: PyprojectToml = toml::from_str(&fs::read_to_string(&path)?)
+            .with_context(|| format!("Invalid pyproject.toml at {}", path.display()))?;
+        let Some(section) = pyproject_toml
+            .tool
+            .and_then(|tool| tool.monotrail)
+            .and_then(|monotrail| monotrail.workspace)
+        else {
+            return Ok(None);
+        };
+
+        let mut members = Vec::new();
+        if let Some(project) = read_pep621_project(root)? {
+            members.push(WorkspaceMember {
+                dir: root.to_path_buf(),
+                project,
+            });
+        }
+        for pattern in &section.members {
+            for dir in expand_member_pattern(root, pattern)
+                .with_context(|| format!("Failed to find the workspace members {}", pattern))?
+            {
+                let project = read_pep621_project(&dir)?.with_context(|| {
+                    format!(
+                        "Workspace member {} has no PEP 621 [project] table",
+                        dir.display()
+                    )
+                })?;
+                members.push(WorkspaceMember { dir, project });
+            }
+        }
+
+        let mut names = BTreeSet::new();
+        for member in &members {
+            if !names.insert(normalize_name(&member.project.name)) {
+                bail!(
+                    "There are multiple workspace members named {}",
+                    member.project.name
+                );
+            }
+        }
+        Ok(Some(Self {
+            root: root.to_path_buf(),
+            members,
+        }))
+    }
+
+    /// Finds the workspace that `dir` is part of by searching the parent directories for a
+    /// workspace root. Returns `None` if there is none or `dir` isn't in a member
+    pub fn find(dir: &Path) -> anyhow::Result<Option<Self>> {
+        for ancestor in dir.ancestors() {
+            if let Some(workspace) = Self::from_root(ancestor)? {
+                if workspace.contains(dir) {
+                    return Ok(Some(workspace));
+                }
+                debug!(
+                    "{} is not a member of the workspace at {}",
+                    dir.display(),
+                    ancestor.display()
+                );
+                return Ok(None);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether `dir` is the workspace root or in a member
+    fn contains(&self, dir: &Path) -> bool {
+        dir == self.root
+            || self
+                .members
+                .iter()
+                .any(|member| dir.starts_with(&member.dir))
+    }
+
+    fn member_index(&self, name: &str) -> Option<usize> {
+        let name = normalize_name(name);
+        self.members
+            .iter()
+            .position(|member| normalize_name(&member.project.name) == name)
+    }
+
+    /// The requirements of all members for the given extras in the poetry format, without the
+    /// requirements on other members. Each extra applies to the members that define it.
+    /// Extras that members request from each other (`core[fast]`) are activated too. The
+    /// same goes for the dependency groups.
+    pub fn requirements_for_poetry(
+        &self,
+        extras: &[String],
+        groups: &[String],
+        pep508_env: &MarkerEnvironment,
+    ) -> anyhow::Result<BTreeMap<String, poetry_toml::Dependency>> {
+        for extra in extras {
+            if !self
+                .members
+                .iter()
+                .any(|member| member.project.optional_dependencies.contains_key(extra))
+            {
+                bail!("No workspace member has the extra {}", extra);
+            }
+        }
+        let groups: Vec<String> = groups.iter().map(|group| normalize_name(group)).collect();
+        for group in &groups {
+            if !self
+                .members
+                .iter()
+                .any(|member| member.project.dependency_groups.contains_key(group))
+            {
+                bail!("No workspace member has the dependency group {}", group);
+            }
+        }
+        let mut active_extras: Vec<BTreeSet<String>> = self
+            .members
+            .iter()
+            .map(|member| {
+                extras
+                    .iter()
+                    .filter(|extra| member.project.optional_dependencies.contains_key(*extra))
+                    .cloned()
+                    .collect()
+            })
+            .collect();
+
+        // Activating an extra of a member can add requirements that activate more extras, so we
+        // repeat until nothing changes
+        loop {
+            let mut poetry_requirements: BTreeMap<String, poetry_toml::Dependency> =
+                BTreeMap::new();
+            let mut requested_extras = Vec::new();
+            for (member, extras) in self.members.iter().zip(&active_extras) {
+                let extras: Vec<String> = extras.iter().cloned().collect();
+                let member_groups: Vec<String> = groups
+                    .iter()
+                    .filter(|group| member.project.dependency_groups.contains_key(*group))
+                    .cloned()
+                    .collect();
+                let requirements = member
+                    .project
+                    .requirements(&extras, &member_groups, pep508_env)
+                    .with_context(|| {
+                        format!("Invalid workspace member {}", member.dir.display())
+                    })?;
+                for requirement in requirements {
+                    if let Some(index) = self.member_index(&requirement.name) {
+                        for extra in requirement.extras.unwrap_or_default() {
+                            requested_extras.push((index, extra));
+                        }
+                        continue;
+                    }
+                    let dependency = requirement_to_poetry(&requirement)?;
+                    let name = normalize_name(&requirement.name);
+                    let dependency = match poetry_requirements.remove(&name) {
+                        Some(existing) => merge_dependencies(existing, dependency),
+                        None => dependency,
+                    };
+                    poetry_requirements.insert(name, dependency);
+                }
+            }
+
+            let mut changed = false;
+            for (index, extra) in requested_extras {
+                changed |= active_extras[index].insert(extra);
+            }
+            if !changed {
+                return Ok(poetry_requirements);
+            }
+        }
+    }
+
+    /// The `[project.scripts]` of all members
+    pub fn scripts(&self) -> anyhow::Result<BTreeMap<String, Script>> {
+        let mut scripts = BTreeMap::new();
+        for member in &self.members {
+            for (name, script) in member.project.scripts()? {
+                if scripts.contains_key(&name) {
+                    warn!(
+                        "Script {} is defined by multiple workspace members, using the first",
+                        name
+                    );
+                    continue;
+                }
+                scripts.insert(name, script);
+            }
+        }
+        Ok(scripts)
+    }
+
+    /// Writes a .pth file with the source directories of all members, which makes them
+    /// importable the same way an editable install does
+    pub fn write_pth(&self) -> anyhow::Result<PathBuf> {
+        let pth_dir = cache_dir()?
+            .join("workspaces")
+            .join(cache_key(self.root.to_string_lossy().as_bytes()));
+        fs::create_dir_all(&pth_dir)?;
+        let pth_file = pth_dir.join(format!("{}-workspace.pth", env!("CARGO_PKG_NAME")));
+        let mut content = String::new();
+        for member in &self.members {
+            content.push_str(&member.import_dir().to_string_lossy());
+            content.push('\n');
+        }
+        fs::write(&pth_file, content)?;
+        Ok(pth_file)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::poetry_integration::poetry_toml::Dependency;
+    use crate::test_utils::test_pep508_env;
+    use crate::workspace::Workspace;
+    use std::path::Path;
+
+    #[test]
+    fn test_workspace() {
+        let root = Path::new("../../test-data/workspace");
+        let workspace = Workspace::find(&root.join("packages").join("cli").join("cli"))
+            .unwrap()
+            .unwrap();
+        let members: Vec<_> = workspace
+            .members
+            .iter()
+            .map(|member| member.project.name.as_str())
+            .collect();
+        assert_eq!(members, ["workspace-root", "cli", "Core"]);
+        assert_eq!(
+            workspace.members[2].import_dir(),
+            root.join("packages").join("core").join("src")
+        );
+        assert_eq!(
+            workspace.members[1].import_dir(),
+            root.join("packages").join("cli")
+        );
+        assert_eq!(
+            workspace.scripts().unwrap().keys().collect::<Vec<_>>(),
+            ["workspace-cli"]
+        );
+
+        // `core[fast]` from cli activates orjson, the members themselves aren't resolved
+        let requirements = workspace
+            .requirements_for_poetry(&[], &[], &test_pep508_env())
+            .unwrap();
+        let requirements: Vec<_> = requirements
+            .iter()
+            .map(|(name, dependency)| {
+                let version = match dependency {
+                    Dependency::Compact(version) => version.as_str(),
+                    Dependency::Expanded { version, .. } => version.as_deref().unwrap(),
+                };
+                (name.as_str(), version)
+            })
+            .collect();
+        assert_eq!(
+            requirements,
+            [
+                ("attrs", ">=22"),
+                ("httpx", "<1,>=0.23"),
+                ("orjson", "*"),
+                ("rich", "*")
+            ]
+        );
+
+        let err = workspace
+            .requirements_for_poetry(&["docs".to_string()], &[], &test_pep508_env())
+            .unwrap_err();
+        assert_eq!(err.to_string(), "No workspace member has the extra docs");
+    }
+
+    #[test]
+    fn test_not_in_workspace() {
+        assert_eq!(
+            Workspace::find(Path::new("../../test-data/pep621")).unwrap(),
+            None
+        );
+    }
+}
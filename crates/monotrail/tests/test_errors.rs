@@ -84,8 +84,11 @@ fn test_cli_python_hyphen() {
 }
 
 #[test]
-fn test_neither_command_nor_python() {
-    let cli = Cli::try_parse_from([BIN, "run", "bogus"]).unwrap();
-    let expected = &["invalid command `bogus`, must be 'python' or 'command'"];
+fn test_run_nothing() {
+    let cli = Cli::try_parse_from([BIN, "run", "-p", "3.8"]).unwrap();
+    let expected = &[
+        "Missing what to run, use `python ...`, `command ...`, `-m <module> ...` \
+        or the name of an installed script",
+    ];
     assert_cli_error(cli, None, expected);
 }
@@ -56,6 +56,29 @@ class InjectData:
     # `sys.executable` manually
     sys_executable: str
 
+class Requirement:
+    # The PEP 508 string, `<name> @ <url>` for bare urls and paths
+    requirement: str
+    name: str
+    # The version specifiers such as `>=2.8.1,==2.8.*`
+    specifier: Optional[str]
+    url: Optional[str]
+    extras: List[str]
+    marker: Optional[str]
+    hashes: List[str]
+    # `registry`, `url`, `path` or `editable`
+    source: str
+
+class RequirementsTxt:
+    requirements: List[Requirement]
+    # The `-c` constraints as PEP 508 strings
+    constraints: List[str]
+
+    @staticmethod
+    def parse(
+        requirements_txt: Union[str, Path], working_dir: Union[str, Path, None] = None
+    ) -> "RequirementsTxt": ...
+
 def monotrail_from_args(args: List[str]) -> InjectData: ...
 def monotrail_from_requested(requested: str, lockfile: Optional[str]) -> InjectData: ...
 def monotrail_from_git(
@@ -0,0 +1,29 @@
+from pathlib import Path
+
+import pytest
+
+test_data = Path(__file__).parent.parent.parent.joinpath("test-data/requirements-txt")
+
+
+def test_parse_requirements_txt():
+    from monotrail.monotrail import RequirementsTxt
+
+    parsed = RequirementsTxt.parse(test_data.joinpath("poetry-with-hashes.txt"))
+    werkzeug = parsed.requirements[0]
+    assert werkzeug.name == "werkzeug"
+    assert werkzeug.specifier == "==2.2.3"
+    assert werkzeug.url is None
+    assert werkzeug.extras == []
+    assert werkzeug.marker == "python_version >= '3.8' and python_version < '4.0'"
+    assert werkzeug.hashes == [
+        "sha256:2e1ccc9417d4da358b9de6f174e3ac094391ea1d4fbef2d667865d819dfd0afe"
+    ]
+    assert werkzeug.source == "registry"
+    assert len(parsed.requirements[3].hashes) == 2
+
+
+def test_parse_requirements_txt_invalid():
+    from monotrail.monotrail import RequirementsTxt
+
+    with pytest.raises(ValueError, match="invalid-requirement"):
+        RequirementsTxt.parse(test_data.joinpath("invalid-requirement"), test_data)